@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+// The buffer pool against the plain allocator, on the orchestrator's
+// pattern: reader threads take a buffer per frame and fill it, as
+// `get_frame` does, and hand it to another thread, which drops it or gives
+// it back to the pool once delivered.
+//
+//   cargo bench --bench pool
+//
+// Prints the frames per second of each, per payload size and number of
+// reader/deliverer pairs.
+
+use std::hint::black_box;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use qpipe::pool::Pool;
+
+/// Payload bytes each reader fills per run.
+const BYTES: usize = 512 << 20;
+
+/// Frames in flight between a reader and its deliverer, like a queue's.
+const IN_FLIGHT: usize = 16;
+
+fn run(pool: Option<&'static Pool>, size: usize, pairs: usize) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..pairs {
+            let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(IN_FLIGHT);
+            s.spawn(move || {
+                for i in 0..BYTES / size {
+                    let mut buf = match pool {
+                        Some(p) => p.take(size),
+                        None => vec![0u8; size],
+                    };
+                    // What read_exact does to it.
+                    buf.fill(i as u8);
+                    tx.send(buf).unwrap();
+                }
+            });
+            s.spawn(move || {
+                for buf in rx {
+                    black_box(buf[0]);
+                    match pool {
+                        Some(p) => p.recycle(buf),
+                        None => drop(buf),
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn main() {
+    static POOL: Pool = Pool::new();
+    println!("{:>9} {:>5} {:>14} {:>14} {:>7}", "size", "pairs", "alloc frames/s", "pool frames/s", "speedup");
+    for size in [16 << 10, 64 << 10, 256 << 10, 1 << 20] {
+        for pairs in [1, 4] {
+            // Warm the pool and the allocator alike.
+            run(Some(&POOL), size, pairs);
+            run(None, size, pairs);
+            let rate = |t: Duration| (BYTES / size * pairs) as f64 / t.as_secs_f64();
+            let alloc = rate(run(None, size, pairs));
+            let pooled = rate(run(Some(&POOL), size, pairs));
+            println!("{:>9} {:>5} {:>14.0} {:>14.0} {:>6.2}x", size, pairs, alloc, pooled, pooled / alloc);
+        }
+    }
+}
@@ -27,18 +27,19 @@ from typing import Any, Self
 from ._qpipe import (
     Producer as _Producer, Consumer as _Consumer, QpipeError,
     healthcheck, wait_until_healthy, request_drain, request_shutdown,
+    wait_for_idle,
     MAX_FRAME_SIZE, CHUNK_HEADER_LEN, MAX_CHUNK_PAYLOAD,
     MAX_CHUNKS, MAX_MESSAGE_SIZE, FRAME_FLAG_CHUNK,
 )
 
 __all__ = [
     "Producer", "Consumer", "QpipeError", "healthcheck", "wait_until_healthy",
-    "request_drain", "request_shutdown",
+    "request_drain", "request_shutdown", "wait_for_idle",
     "MAX_FRAME_SIZE", "CHUNK_HEADER_LEN", "MAX_CHUNK_PAYLOAD",
     "MAX_CHUNKS", "MAX_MESSAGE_SIZE", "FRAME_FLAG_CHUNK",
     "__version__",
 ]
-__version__ = "1.6.0"
+__version__ = "2.0.0"
 
 
 # ----- codecs -----
@@ -130,7 +131,12 @@ class Producer:
         return self
 
     def __exit__(self, *exc: object) -> bool:
-        self.close()
+        try:
+            self.close()
+        except QpipeError:
+            # Don't hide the exception already on its way out.
+            if exc[0] is None:
+                raise
         return False
 
 
@@ -151,7 +157,8 @@ class Consumer:
         return cls(_Consumer.connect(addr), _resolve_codec(codec))
 
     def recv(self) -> Any:
-        """Receive one complete message and decode it with the codec."""
+        """Receive one complete message and decode it with the codec. On an
+        at-least-once queue it is acked by the next receive, or on close."""
         return self._codec.decode(self._inner.recv())
 
     def recv_bytes(self) -> bytes:
@@ -181,7 +188,12 @@ class Consumer:
         return self
 
     def __exit__(self, *exc: object) -> bool:
-        self.close()
+        try:
+            self.close()
+        except QpipeError:
+            # Don't hide the exception already on its way out.
+            if exc[0] is None:
+                raise
         return False
 
     def __iter__(self) -> Self:
@@ -62,9 +62,15 @@ impl Producer {
         Ok(())
     }
 
-    /// Drop the underlying connection. Subsequent calls raise RuntimeError.
-    fn close(&mut self) {
-        self.inner = None;
+    /// Wait until everything sent is ACKed, say goodbye and drop the
+    /// connection. Subsequent calls raise RuntimeError; closing again does
+    /// nothing.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        match self.inner.take() {
+            Some(inner) => py.detach(|| inner.close())
+                .map_err(|e| QpipeError::new_err(format!("close failed: {e}"))),
+            None => Ok(()),
+        }
     }
 
     /// Context-manager support: `with Producer.connect(...) as p: ...`
@@ -74,12 +80,17 @@ impl Producer {
 
     fn __exit__(
         &mut self,
-        _exc_type: &Bound<'_, PyAny>,
+        py: Python<'_>,
+        exc_type: &Bound<'_, PyAny>,
         _exc_val: &Bound<'_, PyAny>,
         _exc_tb: &Bound<'_, PyAny>,
-    ) -> bool {
-        self.close();
-        false // don't suppress exceptions
+    ) -> PyResult<bool> {
+        let closed = self.close(py);
+        // A failed close doesn't hide the exception already on its way.
+        if exc_type.is_none() {
+            closed?;
+        }
+        Ok(false) // don't suppress exceptions
     }
 
     fn __repr__(&self) -> String {
@@ -109,14 +120,15 @@ impl Consumer {
 
     /// Receive one complete message. Blocks until one is available; chunks
     /// of multi-frame messages are buffered internally, so messages are
-    /// returned in COMPLETION order. Returns `bytes`.
+    /// returned in COMPLETION order. Returns `bytes`. On an at-least-once
+    /// queue the message is acked by the next `recv`, or on close.
     fn recv<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
         let inner = self
             .inner
             .as_mut()
             .ok_or_else(|| PyRuntimeError::new_err("consumer is closed"))?;
         let data = py
-            .detach(|| inner.recv())
+            .detach(|| inner.recv().map(|m| m.payload))
             .map_err(|e| QpipeError::new_err(format!("recv failed: {e}")))?;
         Ok(PyBytes::new(py, &data))
     }
@@ -147,8 +159,14 @@ impl Consumer {
         Ok(inner.pending_partials())
     }
 
-    fn close(&mut self) {
-        self.inner = None;
+    /// Leave the session; see `qpipe::Consumer::close`. Subsequent calls
+    /// raise RuntimeError; closing again does nothing.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        match self.inner.take() {
+            Some(inner) => py.detach(|| inner.close())
+                .map_err(|e| QpipeError::new_err(format!("close failed: {e}"))),
+            None => Ok(()),
+        }
     }
 
     fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
@@ -157,12 +175,16 @@ impl Consumer {
 
     fn __exit__(
         &mut self,
-        _exc_type: &Bound<'_, PyAny>,
+        py: Python<'_>,
+        exc_type: &Bound<'_, PyAny>,
         _exc_val: &Bound<'_, PyAny>,
         _exc_tb: &Bound<'_, PyAny>,
-    ) -> bool {
-        self.close();
-        false
+    ) -> PyResult<bool> {
+        let closed = self.close(py);
+        if exc_type.is_none() {
+            closed?;
+        }
+        Ok(false)
     }
 
     /// `for frame in consumer: ...` — runs until the connection drops.
@@ -228,6 +250,22 @@ fn request_shutdown(py: Python<'_>, addr: &str) -> PyResult<()> {
         .map_err(|e| QpipeError::new_err(format!("shutdown request failed: {e}")))
 }
 
+/// Block until the queue is idle: every accepted message delivered and no
+/// producer connected. ACK-ON-COMPLETION, unlike drain/shutdown; the
+/// orchestrator keeps running. `queue` names one queue (None = every queue),
+/// `timeout` in seconds (None = forever).
+#[pyfunction]
+#[pyo3(signature = (addr, timeout=None, queue=None))]
+fn wait_for_idle(py: Python<'_>, addr: &str, timeout: Option<f64>, queue: Option<String>) -> PyResult<()> {
+    let a = addr.to_owned();
+    let t = timeout
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|e| PyValueError::new_err(format!("invalid timeout: {e}")))?;
+    py.detach(move || qpipe::wait_for_idle(&a, queue.as_deref(), t))
+        .map_err(|e| QpipeError::new_err(format!("wait_for_idle failed: {e}")))
+}
+
 // ---------- module ----------
 
 #[pymodule]
@@ -241,6 +279,7 @@ fn _qpipe(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(wait_until_healthy, m)?)?;
     m.add_function(wrap_pyfunction!(request_drain, m)?)?;
     m.add_function(wrap_pyfunction!(request_shutdown, m)?)?;
+    m.add_function(wrap_pyfunction!(wait_for_idle, m)?)?;
     // Framing constants (mirror qpipe's Rust consts) so Python code — tests,
     // payload size pre-checks — never hardcodes them.
     m.add("MAX_FRAME_SIZE", qpipe::MAX_FRAME_SIZE)?;
@@ -25,7 +25,7 @@ def _wait_until_listening(host, port, timeout=5.0):
             time.sleep(0.02)
     raise RuntimeError(f"orchestrator never came up on {host}:{port} ({last})")
 
-def _start_orchestrator():
+def _start_orchestrator(*args):
     # Binary name is `orchestrator` (per README). Override via env if it's not
     # on PATH, e.g. QPIPE_ORCHESTRATOR_BIN=../../target/debug/orchestrator
     binary = os.environ.get("QPIPE_ORCHESTRATOR_BIN", "orchestrator")
@@ -33,7 +33,7 @@ def _start_orchestrator():
     addr = f"127.0.0.1:{port}"
     # Positional arg = LISTEN_ADDR (orchestrator [LISTEN_ADDR] [CAPACITY] [STATS]).
     proc = subprocess.Popen(
-        [binary, addr],
+        [binary, *args, addr],
         env={**os.environ, "RUST_LOG": "warn"},
     )
     try:
@@ -75,3 +75,14 @@ def fresh_orchestrator():
         yield addr
     finally:
         _stop_orchestrator(proc)
+
+
+@pytest.fixture
+def at_least_once_orchestrator():
+    # Function-scoped, with `--delivery at-least-once`: every consumer is put
+    # in ack mode, and what one held unacked is redelivered.
+    proc, addr = _start_orchestrator("--delivery", "at-least-once")
+    try:
+        yield addr
+    finally:
+        _stop_orchestrator(proc)
@@ -173,3 +173,21 @@ def test_mpmc_no_loss_no_duplication(fresh_orchestrator):
     # everything is a legal (if unlucky) outcome, so asserting a spread would
     # test a property qpipe doesn't guarantee and would flake. Per-consumer
     # counts are available in received_slots for debugging if a run looks off.
+
+
+def test_recv_acks_on_an_at_least_once_queue(at_least_once_orchestrator):
+    # The queue puts consumers in ack mode; recv acks a message once the next
+    # is asked for, or on close.
+    addr = at_least_once_orchestrator
+    with qpipe.Producer.connect(addr) as p:
+        p.send(b"one")
+        p.send(b"two")
+        c = qpipe.Consumer.connect(addr)
+        assert c.recv() == b"one"
+        assert c.recv() == b"two"
+        del c  # gone without closing, so "two" was never acked
+        with qpipe.Consumer.connect(addr) as c:
+            assert c.recv() == b"two"
+        p.send(b"three")
+        with qpipe.Consumer.connect(addr) as c:
+            assert c.recv() == b"three"
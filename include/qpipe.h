@@ -0,0 +1,166 @@
+/* SPDX-License-Identifier: AGPL-3.0-or-later */
+
+#ifndef QPIPE_H
+#define QPIPE_H
+
+/*
+ * The C interface of the `capi` feature (src/capi.rs), kept by hand: a
+ * test there checks that the declarations here match its exports.
+ */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/*
+ * Success.
+ */
+#define QPIPE_OK 0
+
+/*
+ * A null or malformed argument, or a request the library refuses
+ * (`InvalidInput`), such as a message over the size limit.
+ */
+#define QPIPE_ERR_INVALID_ARGUMENT -1
+
+/*
+ * The connection could not be made, or was lost.
+ */
+#define QPIPE_ERR_CONNECTION -2
+
+/*
+ * The orchestrator ended the session, as when it drains.
+ */
+#define QPIPE_ERR_CLOSED -3
+
+/*
+ * Nothing arrived in time.
+ */
+#define QPIPE_ERR_TIMED_OUT -4
+
+/*
+ * Authentication failed, or the session lacks the role.
+ */
+#define QPIPE_ERR_PERMISSION_DENIED -5
+
+/*
+ * The peer sent something that isn't the protocol.
+ */
+#define QPIPE_ERR_INVALID_DATA -6
+
+/*
+ * An option or feature the orchestrator or this build doesn't support.
+ */
+#define QPIPE_ERR_UNSUPPORTED -7
+
+/*
+ * A rate limit refused the message.
+ */
+#define QPIPE_ERR_LIMIT -8
+
+/*
+ * Anything else.
+ */
+#define QPIPE_ERR_OTHER -99
+
+typedef struct qpipe_consumer qpipe_consumer;
+
+typedef struct qpipe_producer qpipe_producer;
+
+#ifdef __cplusplus
+extern "C" {
+#endif  // __cplusplus
+
+/*
+ * The message of the last call on this thread that failed, or an empty
+ * string. It stays valid until the next failure on the same thread.
+ */
+const char *qpipe_last_error(void);
+
+/*
+ * Connect a producer to `orchestrator`, on `queue` or, if it is null, the
+ * default queue. On success `*out` is the producer, to be released with
+ * `qpipe_producer_free`; on failure it is set to null.
+ *
+ * # Safety
+ * `orchestrator` and any `queue` are NUL-terminated strings; `out` points
+ * to writable storage for a pointer.
+ */
+int qpipe_producer_connect(const char *orchestrator, const char *queue, qpipe_producer **out);
+
+/*
+ * Send `len` bytes from `data` as one message; see `Producer::send`. It
+ * returns once the orchestrator has taken them. `data` may be null when
+ * `len` is 0.
+ *
+ * # Safety
+ * `producer` came from `qpipe_producer_connect` and hasn't been freed;
+ * `data` points to `len` readable bytes.
+ */
+int qpipe_producer_send(qpipe_producer *producer, const uint8_t *data, size_t len);
+
+/*
+ * Close a producer as `Producer::close` does, and release it. Errors on
+ * the way out are discarded. Null is ignored.
+ *
+ * # Safety
+ * `producer` came from `qpipe_producer_connect` and hasn't been freed.
+ */
+void qpipe_producer_free(qpipe_producer *producer);
+
+/*
+ * Connect a consumer to `orchestrator`, on `queue` or, if it is null, the
+ * default queue. On success `*out` is the consumer, to be released with
+ * `qpipe_consumer_free`; on failure it is set to null.
+ *
+ * # Safety
+ * As for `qpipe_producer_connect`.
+ */
+int qpipe_consumer_connect(const char *orchestrator, const char *queue, qpipe_consumer **out);
+
+/*
+ * Block until the next message; see `Consumer::recv`. On success `*data`
+ * and `*len` are the payload, to be released with `qpipe_message_free`.
+ * On an at-least-once queue it is acked by the next receive, or by
+ * `qpipe_consumer_free`.
+ *
+ * # Safety
+ * `consumer` came from `qpipe_consumer_connect` and hasn't been freed;
+ * `data` and `len` point to writable storage.
+ */
+int qpipe_consumer_recv(qpipe_consumer *consumer, uint8_t **data, size_t *len);
+
+/*
+ * Like `qpipe_consumer_recv`, but `QPIPE_ERR_TIMED_OUT` if no message
+ * came within `timeout_ms`; see `Consumer::recv_timeout`.
+ *
+ * # Safety
+ * As for `qpipe_consumer_recv`.
+ */
+int qpipe_consumer_recv_timeout(qpipe_consumer *consumer,
+                                uint64_t timeout_ms,
+                                uint8_t **data,
+                                size_t *len);
+
+/*
+ * Release a payload from `qpipe_consumer_recv`. Null is ignored.
+ *
+ * # Safety
+ * `data` and `len` are as a receive gave them, and haven't been freed.
+ */
+void qpipe_message_free(uint8_t *data, size_t len);
+
+/*
+ * Close a consumer as `Consumer::close` does, and release it: what it
+ * holds goes back to the queue. Errors on the way out are discarded. Null
+ * is ignored.
+ *
+ * # Safety
+ * `consumer` came from `qpipe_consumer_connect` and hasn't been freed.
+ */
+void qpipe_consumer_free(qpipe_consumer *consumer);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* QPIPE_H */
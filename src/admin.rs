@@ -0,0 +1,315 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Admin sessions: inspect and manage a running orchestrator — its
+//! connected sessions, per-client counters, its queues' depth, pausing,
+//! refusing and resuming intake, and purging a queue. `qpipe-admin` is a CLI over this module.
+//!
+//! A client opens a control session with `ROLE_ADMIN` (authenticating as
+//! `admin` where users are configured); the orchestrator answers
+//! `[ACK_ADMIN]`. Then any number of requests follow, one at a time:
+//!
+//!   request: [u8 op][u16 BE len][argument, UTF-8]
+//!   reply:   [u8 ACK_ADMIN | ADMIN_ERROR][u32 BE len][body, UTF-8]
+//!
+//! An error body is the message. Listings are one line per entry, fields
+//! separated by tabs; the default queue is named by the empty string.
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::transport::{Addr, Stream};
+use crate::{check_queue_name, open_control, ACK_ADMIN, ROLE_ADMIN};
+
+/// Reply status of a request that failed; the body says why.
+pub const ADMIN_ERROR: u8    = b'E';
+
+/// Request ops.
+pub const ADMIN_SESSIONS: u8 = b'L'; // no argument; `SessionInfo` lines
+pub const ADMIN_QUEUES: u8   = b'Q'; // no argument; `QueueInfo` lines
+pub const ADMIN_PAUSE: u8    = b'P'; // queue name, or empty for all queues
+pub const ADMIN_RESUME: u8   = b'R'; // queue name, or empty for all queues
+pub const ADMIN_PURGE: u8    = b'X'; // queue name; body: frames purged
+pub const ADMIN_CLIENTS: u8  = b'C'; // no argument; `ClientInfo` lines
+pub const ADMIN_REFUSE: u8   = b'F'; // queue name, or empty for all queues
+
+/// Longest reply body a client accepts.
+const MAX_REPLY: usize = 64 << 20;
+
+/// A connected producer or consumer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// `producer` or `consumer`.
+    pub role:      String,
+    /// The data connection's peer address (`unix` for Unix sockets).
+    pub peer:      String,
+    /// The queue it is attached to; empty for the default queue.
+    pub queue:     String,
+    /// How long it has been connected.
+    pub connected: Duration,
+    /// The name the client gave (`ProducerOptions::name`); empty if none.
+    pub name:      String,
+}
+
+impl SessionInfo {
+    pub(crate) fn line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.role, self.peer, self.queue, self.connected.as_millis(), self.name,
+        )
+    }
+
+    fn parse(line: &str) -> io::Result<Self> {
+        // Orchestrators from before client names send four fields.
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields[..] {
+            [role, peer, queue, ms] | [role, peer, queue, ms, _] => Ok(Self {
+                role:      role.to_string(),
+                peer:      peer.to_string(),
+                queue:     queue.to_string(),
+                connected: Duration::from_millis(number(ms)?),
+                name:      fields.get(4).unwrap_or(&"").to_string(),
+            }),
+            _ => Err(bad_line(line)),
+        }
+    }
+}
+
+/// What the sessions under one client name have done since the
+/// orchestrator started.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub name:             String,
+    /// Sessions connected now.
+    pub producers:        u64,
+    pub consumers:        u64,
+    /// Frames accepted from its producers, and their payload bytes.
+    pub posted_frames:    u64,
+    pub posted_bytes:     u64,
+    /// Frames delivered to its consumers.
+    pub collected_frames: u64,
+    pub collected_bytes:  u64,
+}
+
+impl ClientInfo {
+    pub(crate) fn line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.name, self.producers, self.consumers, self.posted_frames, self.posted_bytes,
+            self.collected_frames, self.collected_bytes,
+        )
+    }
+
+    fn parse(line: &str) -> io::Result<Self> {
+        match line.split('\t').collect::<Vec<_>>()[..] {
+            [name, producers, consumers, posted, posted_bytes, collected, collected_bytes] => Ok(Self {
+                name:             name.to_string(),
+                producers:        number(producers)?,
+                consumers:        number(consumers)?,
+                posted_frames:    number(posted)?,
+                posted_bytes:     number(posted_bytes)?,
+                collected_frames: number(collected)?,
+                collected_bytes:  number(collected_bytes)?,
+            }),
+            _ => Err(bad_line(line)),
+        }
+    }
+}
+
+/// One queue's state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueInfo {
+    /// Empty for the default queue.
+    pub name:        String,
+    /// Frames waiting, in memory or in the disk overflow.
+    pub depth:       u64,
+    /// Data frames accepted but not yet settled.
+    pub outstanding: u64,
+    /// How long the longest-waiting frame has waited.
+    pub oldest_wait: Duration,
+    /// Whether intake is paused: producers wait as if the queue were full.
+    pub paused:      bool,
+    /// Whether paused intake refuses new messages from producers that
+    /// accept `ACK_THROTTLED`, rather than holding them.
+    pub refusing:    bool,
+}
+
+impl QueueInfo {
+    pub(crate) fn line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.name, self.depth, self.outstanding, self.oldest_wait.as_millis(), self.intake(),
+        )
+    }
+
+    /// The last field: 0 open, 1 paused, 2 refusing. Clients from before
+    /// refusal read anything but 0 as paused.
+    fn intake(&self) -> u8 {
+        match (self.paused, self.refusing) {
+            (_, true) => 2,
+            (paused, false) => u8::from(paused),
+        }
+    }
+
+    fn parse(line: &str) -> io::Result<Self> {
+        match line.split('\t').collect::<Vec<_>>()[..] {
+            [name, depth, outstanding, wait, paused] => Ok(Self {
+                name:        name.to_string(),
+                depth:       number(depth)?,
+                outstanding: number(outstanding)?,
+                oldest_wait: Duration::from_millis(number(wait)?),
+                paused:      number(paused)? != 0,
+                refusing:    number(paused)? == 2,
+            }),
+            _ => Err(bad_line(line)),
+        }
+    }
+}
+
+fn number(field: &str) -> io::Result<u64> {
+    field.parse().map_err(|_| io::Error::new(
+        io::ErrorKind::InvalidData, format!("bad number {field:?} in admin reply"),
+    ))
+}
+
+fn bad_line(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad admin reply line {line:?}"))
+}
+
+/// An open admin session.
+pub struct Admin {
+    s: Stream,
+}
+
+impl Admin {
+    pub fn connect(orchestrator: &str) -> io::Result<Self> {
+        let mut s = Addr::resolve(orchestrator)?.connect(Some(Duration::from_secs(5)))?;
+        s.set_read_timeout(Some(Duration::from_secs(30))).ok();
+        s.set_write_timeout(Some(Duration::from_secs(5))).ok();
+
+        open_control(&mut s, ROLE_ADMIN, &[], None, None)?;
+
+        let mut ack = [0u8; 1];
+        s.read_exact(&mut ack)?;
+        if ack[0] != ACK_ADMIN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected admin ack: 0x{:02x}", ack[0]),
+            ));
+        }
+        Ok(Self { s })
+    }
+
+    /// Send one request and return the body of its reply. A refusal comes
+    /// back as `io::ErrorKind::Other` with the orchestrator's reason.
+    pub fn request(&mut self, op: u8, arg: &str) -> io::Result<String> {
+        let len = u16::try_from(arg.len()).map_err(|_| io::Error::new(
+            io::ErrorKind::InvalidInput, "admin argument too long",
+        ))?;
+        let mut req = Vec::with_capacity(3 + arg.len());
+        req.push(op);
+        req.extend_from_slice(&len.to_be_bytes());
+        req.extend_from_slice(arg.as_bytes());
+        self.s.write_all(&req)?;
+        self.s.flush()?;
+
+        let mut head = [0u8; 5];
+        self.s.read_exact(&mut head)?;
+        let len = u32::from_be_bytes(head[1..].try_into().unwrap()) as usize;
+        if len > MAX_REPLY {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "admin reply too large"));
+        }
+        let mut body = vec![0u8; len];
+        self.s.read_exact(&mut body)?;
+        let body = String::from_utf8(body).map_err(|_| io::Error::new(
+            io::ErrorKind::InvalidData, "admin reply is not UTF-8",
+        ))?;
+        match head[0] {
+            ACK_ADMIN => Ok(body),
+            ADMIN_ERROR => Err(io::Error::other(body)),
+            b => Err(io::Error::new(
+                io::ErrorKind::InvalidData, format!("unexpected admin reply status 0x{b:02x}"),
+            )),
+        }
+    }
+
+    /// Every connected producer and consumer.
+    pub fn sessions(&mut self) -> io::Result<Vec<SessionInfo>> {
+        self.request(ADMIN_SESSIONS, "")?.lines().map(SessionInfo::parse).collect()
+    }
+
+    /// Counters for every client name sessions have given, by name.
+    /// Sessions without a name are not counted here.
+    pub fn clients(&mut self) -> io::Result<Vec<ClientInfo>> {
+        self.request(ADMIN_CLIENTS, "")?.lines().map(ClientInfo::parse).collect()
+    }
+
+    /// Every queue, the default one first.
+    pub fn queues(&mut self) -> io::Result<Vec<QueueInfo>> {
+        self.request(ADMIN_QUEUES, "")?.lines().map(QueueInfo::parse).collect()
+    }
+
+    /// Stop taking frames from producers into `queue`, or into every queue
+    /// (including ones created later) with `None`. Producers wait, as they
+    /// would for a full queue; consumers carry on with what is queued.
+    pub fn pause(&mut self, queue: Option<&str>) -> io::Result<()> {
+        self.request(ADMIN_PAUSE, &queue_arg(queue)?).map(drop)
+    }
+
+    /// Like `pause`, but producers that opted in to `ACK_THROTTLED`
+    /// (`ProducerOptions::throttle_errors`) have new messages refused
+    /// instead of waiting; others still wait.
+    pub fn refuse(&mut self, queue: Option<&str>) -> io::Result<()> {
+        self.request(ADMIN_REFUSE, &queue_arg(queue)?).map(drop)
+    }
+
+    /// Undo `pause` or `refuse`, for `queue` or every queue.
+    pub fn resume(&mut self, queue: Option<&str>) -> io::Result<()> {
+        self.request(ADMIN_RESUME, &queue_arg(queue)?).map(drop)
+    }
+
+    /// Drop every frame waiting in `queue` ("" for the default queue), in
+    /// memory and in its overflow, and return how many went. Frames already
+    /// handed to consumers, and EOS markers, stay.
+    pub fn purge(&mut self, queue: &str) -> io::Result<u64> {
+        let arg = queue_arg(Some(queue).filter(|q| !q.is_empty()))?;
+        number(self.request(ADMIN_PURGE, &arg)?.trim())
+    }
+}
+
+fn queue_arg(queue: Option<&str>) -> io::Result<String> {
+    match queue {
+        Some(name) => check_queue_name(name).map(|()| name.to_string()),
+        None => Ok(String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_lines_round_trip() {
+        let s = SessionInfo {
+            role: "producer".into(), peer: "127.0.0.1:5000".into(),
+            queue: String::new(), connected: Duration::from_millis(1500), name: "ingest-3".into(),
+        };
+        assert_eq!(s.line(), "producer\t127.0.0.1:5000\t\t1500\tingest-3");
+        assert_eq!(SessionInfo::parse(&s.line()).unwrap(), s);
+        let old = SessionInfo::parse("consumer\tunix\tjobs\t20").unwrap();
+        assert_eq!((old.queue.as_str(), old.name.as_str()), ("jobs", ""));
+        let c = ClientInfo {
+            name: "ingest 3".into(), producers: 2, posted_frames: 9, posted_bytes: 900,
+            ..ClientInfo::default()
+        };
+        assert_eq!(ClientInfo::parse(&c.line()).unwrap(), c);
+        let q = QueueInfo {
+            name: "jobs/gpu".into(), depth: 7, outstanding: 9,
+            oldest_wait: Duration::from_millis(20), paused: true, refusing: false,
+        };
+        assert_eq!(QueueInfo::parse(&q.line()).unwrap(), q);
+        let refusing = QueueInfo { refusing: true, ..q };
+        assert!(refusing.line().ends_with("\t2"));
+        assert_eq!(QueueInfo::parse(&refusing.line()).unwrap(), refusing);
+        assert!(QueueInfo::parse("jobs\t1\t2\t3").is_err());
+        assert!(SessionInfo::parse("producer\tpeer\t\tsoon").is_err());
+    }
+}
@@ -0,0 +1,542 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Encryption at rest for anything qpipe writes to disk (producer spools,
+//! dead-letter files), so queue contents never land on shared scratch in
+//! plaintext.
+//!
+//! Cipher: ChaCha20-Poly1305 (RFC 8439) with a fresh random 96-bit nonce per
+//! sealed record. No AEAD crate is vendored, so both primitives are
+//! implemented here and pinned to the RFC's test vectors.
+//!
+//! Sealed record on disk: `[u32 BE len][12-byte nonce][ciphertext][16-byte tag]`
+//! where `len` covers nonce, ciphertext and tag. `SealedWriter` /
+//! `SealedReader` turn a byte stream into such records and back, binding
+//! each record's index and a last-record marker into its tag, so records
+//! can't be reordered, dropped or cut off the end unnoticed.
+//!
+//! Keys are 32 bytes, loaded from a spec (see `Key::load`):
+//!   `file:<path>`   32 raw bytes, or 64 hex digits
+//!   `env:<VAR>`     64 hex digits in the variable
+//!   `cmd:<command>` 64 hex digits on the stdout of `sh -c <command>`,
+//!                   e.g. a KMS client
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process::Command;
+
+use rand::{rngs::SysRng, TryRng};
+
+pub const KEY_LEN: usize   = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize   = 16;
+
+/// Largest sealed record `SealedReader` accepts (a bound on allocation).
+const MAX_SEALED_LEN: usize = 1 << 30;
+
+/// A 256-bit at-rest key. `Debug` never prints the key material.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; KEY_LEN]);
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(<redacted>)")
+    }
+}
+
+impl Key {
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    /// Parse 64 hex digits (surrounding whitespace ignored).
+    pub fn from_hex(s: &str) -> io::Result<Self> {
+        let s = s.trim();
+        let bad = || io::Error::new(
+            io::ErrorKind::InvalidData, "at-rest key must be 64 hex digits",
+        );
+        if s.len() != 2 * KEY_LEN || !s.is_ascii() {
+            return Err(bad());
+        }
+        let mut key = [0u8; KEY_LEN];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| bad())?;
+        }
+        Ok(Self(key))
+    }
+
+    /// Load a key from a `file:`, `env:` or `cmd:` spec.
+    pub fn load(spec: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        if let Some(path) = spec.strip_prefix("file:") {
+            let raw = fs::read(path)?;
+            return match <[u8; KEY_LEN]>::try_from(raw.as_slice()) {
+                Ok(bytes) => Ok(Self(bytes)),
+                Err(_) => Self::from_hex(&String::from_utf8_lossy(&raw)),
+            };
+        }
+        if let Some(var) = spec.strip_prefix("env:") {
+            let v = std::env::var(var)
+                .map_err(|e| invalid(format!("key variable {var}: {e}")))?;
+            return Self::from_hex(&v);
+        }
+        if let Some(cmd) = spec.strip_prefix("cmd:") {
+            let out = Command::new("sh").arg("-c").arg(cmd).output()?;
+            if !out.status.success() {
+                return Err(io::Error::other(format!("key command failed: {}", out.status)));
+            }
+            return Self::from_hex(&String::from_utf8_lossy(&out.stdout));
+        }
+        Err(invalid(format!(
+            "key spec must start with file:, env: or cmd: (got {spec:?})"
+        )))
+    }
+}
+
+// ── ChaCha20 (RFC 8439 §2.3) ───────────────────────────────────────────────
+
+fn le32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn chacha20_block(key: &[u8; KEY_LEN], counter: u32, nonce: &[u8; NONCE_LEN]) -> [u8; 64] {
+    let mut init = [0u32; 16];
+    init[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for i in 0..8 {
+        init[4 + i] = le32(&key[4 * i..]);
+    }
+    init[12] = counter;
+    for i in 0..3 {
+        init[13 + i] = le32(&nonce[4 * i..]);
+    }
+
+    let mut s = init;
+    fn qr(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(7);
+    }
+    for _ in 0..10 {
+        qr(&mut s, 0, 4, 8, 12);
+        qr(&mut s, 1, 5, 9, 13);
+        qr(&mut s, 2, 6, 10, 14);
+        qr(&mut s, 3, 7, 11, 15);
+        qr(&mut s, 0, 5, 10, 15);
+        qr(&mut s, 1, 6, 11, 12);
+        qr(&mut s, 2, 7, 8, 13);
+        qr(&mut s, 3, 4, 9, 14);
+    }
+
+    let mut out = [0u8; 64];
+    for i in 0..16 {
+        out[4 * i..4 * i + 4].copy_from_slice(&s[i].wrapping_add(init[i]).to_le_bytes());
+    }
+    out
+}
+
+fn chacha20_xor(key: &[u8; KEY_LEN], counter: u32, nonce: &[u8; NONCE_LEN], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let ks = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (b, k) in chunk.iter_mut().zip(ks) {
+            *b ^= k;
+        }
+    }
+}
+
+// ── Poly1305 (RFC 8439 §2.5), 26-bit limbs ─────────────────────────────────
+
+fn poly1305(key: &[u8; 32], msg: &[u8]) -> [u8; TAG_LEN] {
+    const M: u32 = 0x3ff_ffff;
+    let r0 = le32(&key[0..]) & 0x3ff_ffff;
+    let r1 = (le32(&key[3..]) >> 2) & 0x3ff_ff03;
+    let r2 = (le32(&key[6..]) >> 4) & 0x3ff_c0ff;
+    let r3 = (le32(&key[9..]) >> 6) & 0x3f0_3fff;
+    let r4 = (le32(&key[12..]) >> 8) & 0x00f_ffff;
+    let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+    let (mut h0, mut h1, mut h2, mut h3, mut h4) = (0u32, 0u32, 0u32, 0u32, 0u32);
+
+    for chunk in msg.chunks(16) {
+        // Append the 0x01 byte; for a full block it lands on bit 128.
+        let mut b = [0u8; 17];
+        b[..chunk.len()].copy_from_slice(chunk);
+        b[chunk.len()] = 1;
+        h0 += le32(&b[0..]) & M;
+        h1 += (le32(&b[3..]) >> 2) & M;
+        h2 += (le32(&b[6..]) >> 4) & M;
+        h3 += (le32(&b[9..]) >> 6) & M;
+        h4 += (le32(&b[12..]) >> 8) | ((b[16] as u32) << 24);
+
+        let m = |a: u32, b: u32| a as u64 * b as u64;
+        let d0 = m(h0, r0) + m(h1, s4) + m(h2, s3) + m(h3, s2) + m(h4, s1);
+        let mut d1 = m(h0, r1) + m(h1, r0) + m(h2, s4) + m(h3, s3) + m(h4, s2);
+        let mut d2 = m(h0, r2) + m(h1, r1) + m(h2, r0) + m(h3, s4) + m(h4, s3);
+        let mut d3 = m(h0, r3) + m(h1, r2) + m(h2, r1) + m(h3, r0) + m(h4, s4);
+        let mut d4 = m(h0, r4) + m(h1, r3) + m(h2, r2) + m(h3, r1) + m(h4, r0);
+
+        d1 += d0 >> 26; h0 = d0 as u32 & M;
+        d2 += d1 >> 26; h1 = d1 as u32 & M;
+        d3 += d2 >> 26; h2 = d2 as u32 & M;
+        d4 += d3 >> 26; h3 = d3 as u32 & M;
+        h4 = d4 as u32 & M;
+        h0 += (d4 >> 26) as u32 * 5;
+        h1 += h0 >> 26; h0 &= M;
+    }
+
+    // Fully carry, then reduce mod 2^130 - 5 in constant time.
+    let mut c;
+    c = h1 >> 26; h1 &= M; h2 += c;
+    c = h2 >> 26; h2 &= M; h3 += c;
+    c = h3 >> 26; h3 &= M; h4 += c;
+    c = h4 >> 26; h4 &= M; h0 += c * 5;
+    c = h0 >> 26; h0 &= M; h1 += c;
+
+    let mut g0 = h0.wrapping_add(5); c = g0 >> 26; g0 &= M;
+    let mut g1 = h1.wrapping_add(c); c = g1 >> 26; g1 &= M;
+    let mut g2 = h2.wrapping_add(c); c = g2 >> 26; g2 &= M;
+    let mut g3 = h3.wrapping_add(c); c = g3 >> 26; g3 &= M;
+    let g4 = h4.wrapping_add(c).wrapping_sub(1 << 26);
+    let use_g = (g4 >> 31).wrapping_sub(1); // all ones iff h >= p
+    h0 = (h0 & !use_g) | (g0 & use_g);
+    h1 = (h1 & !use_g) | (g1 & use_g);
+    h2 = (h2 & !use_g) | (g2 & use_g);
+    h3 = (h3 & !use_g) | (g3 & use_g);
+    h4 = (h4 & !use_g) | (g4 & use_g);
+
+    let w = [
+        h0 | (h1 << 26),
+        (h1 >> 6) | (h2 << 20),
+        (h2 >> 12) | (h3 << 14),
+        (h3 >> 18) | (h4 << 8),
+    ];
+    let mut tag = [0u8; TAG_LEN];
+    let mut carry = 0u64;
+    for i in 0..4 {
+        let f = w[i] as u64 + le32(&key[16 + 4 * i..]) as u64 + carry;
+        tag[4 * i..4 * i + 4].copy_from_slice(&(f as u32).to_le_bytes());
+        carry = f >> 32;
+    }
+    tag
+}
+
+// ── AEAD (RFC 8439 §2.8) ───────────────────────────────────────────────────
+
+fn aead_tag(key: &Key, nonce: &[u8; NONCE_LEN], aad: &[u8], ct: &[u8]) -> [u8; TAG_LEN] {
+    let otk: [u8; 32] = chacha20_block(&key.0, 0, nonce)[..32].try_into().unwrap();
+    let pad = |n: usize| vec![0u8; (16 - n % 16) % 16];
+    let mut mac = Vec::with_capacity(aad.len() + ct.len() + 48);
+    mac.extend_from_slice(aad);
+    mac.extend_from_slice(&pad(aad.len()));
+    mac.extend_from_slice(ct);
+    mac.extend_from_slice(&pad(ct.len()));
+    mac.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+    mac.extend_from_slice(&(ct.len() as u64).to_le_bytes());
+    poly1305(&otk, &mac)
+}
+
+fn seal_with_nonce(key: &Key, nonce: [u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(NONCE_LEN + plaintext.len() + TAG_LEN);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(plaintext);
+    chacha20_xor(&key.0, 1, &nonce, &mut out[NONCE_LEN..]);
+    let tag = aead_tag(key, &nonce, aad, &out[NONCE_LEN..]);
+    out.extend_from_slice(&tag);
+    out
+}
+
+/// Encrypt and authenticate `plaintext` (binding `aad` too):
+/// `[nonce][ciphertext][tag]`.
+pub fn seal(key: &Key, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SysRng.try_fill_bytes(&mut nonce).map_err(io::Error::other)?;
+    Ok(seal_with_nonce(key, nonce, aad, plaintext))
+}
+
+/// Reverse `seal`. A wrong key, wrong `aad` or any tampering is
+/// `InvalidData`.
+pub fn open(key: &Key, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+    let bad = || io::Error::new(
+        io::ErrorKind::InvalidData, "sealed record failed authentication",
+    );
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(bad());
+    }
+    let nonce: [u8; NONCE_LEN] = sealed[..NONCE_LEN].try_into().unwrap();
+    let (ct, tag) = sealed[NONCE_LEN..].split_at(sealed.len() - NONCE_LEN - TAG_LEN);
+    let want = aead_tag(key, &nonce, aad, ct);
+    // Constant-time comparison.
+    if want.iter().zip(tag).fold(0u8, |acc, (a, b)| acc | (a ^ b)) != 0 {
+        return Err(bad());
+    }
+    let mut pt = ct.to_vec();
+    chacha20_xor(&key.0, 1, &nonce, &mut pt);
+    Ok(pt)
+}
+
+/// Associated data for `SealedWriter` streams, followed in each record's
+/// AAD by its index (u64 BE) and whether it is the last one (u8).
+const STREAM_AAD: &[u8] = b"qpipe-at-rest-v2";
+
+fn stream_aad(index: u64, last: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(STREAM_AAD.len() + 9);
+    aad.extend_from_slice(STREAM_AAD);
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+/// A `Write` adapter that buffers plaintext and, on every `flush`, writes
+/// what it has as one sealed record to the inner writer. Callers should
+/// flush at their natural record boundaries, and `finish` (or drop) the
+/// writer to seal the last record, without which the stream reads as
+/// truncated.
+pub struct SealedWriter<W: Write> {
+    inner:    W,
+    key:      Key,
+    buf:      Vec<u8>,
+    next:     u64,
+    finished: bool,
+}
+
+impl<W: Write> SealedWriter<W> {
+    pub fn new(inner: W, key: Key) -> Self {
+        Self { inner, key, buf: Vec::new(), next: 0, finished: false }
+    }
+
+    /// Seal what is buffered, even nothing, as the stream's last record.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.seal_last()
+    }
+
+    fn write_record(&mut self, last: bool) -> io::Result<()> {
+        let sealed = seal(&self.key, &stream_aad(self.next, last), &self.buf)?;
+        let mut rec = Vec::with_capacity(4 + sealed.len());
+        rec.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+        rec.extend_from_slice(&sealed);
+        self.inner.write_all(&rec)?;
+        self.buf.clear();
+        self.next += 1;
+        Ok(())
+    }
+
+    fn seal_last(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.write_record(true)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for SealedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("sealed stream already finished"));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() && !self.finished {
+            self.write_record(false)?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for SealedWriter<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.seal_last();
+        }
+    }
+}
+
+/// A `Read` adapter over a stream written by `SealedWriter`: yields the
+/// plaintext, verifying each record and its place in the stream. Records
+/// out of order are `InvalidData`; a stream cut off inside a record, or
+/// before its last one, is `UnexpectedEof`. Streams appended one after
+/// another (a dead-letter file across restarts) read as one.
+pub struct SealedReader<R: Read> {
+    inner: R,
+    key:   Key,
+    buf:   Vec<u8>,
+    pos:   usize,
+    /// Index of the next record; 0 between streams.
+    next:  u64,
+}
+
+impl<R: Read> SealedReader<R> {
+    pub fn new(inner: R, key: Key) -> Self {
+        Self { inner, key, buf: Vec::new(), pos: 0, next: 0 }
+    }
+
+    /// Load the next record; false at end of stream.
+    fn refill(&mut self) -> io::Result<bool> {
+        let cut = |what: &str| io::Error::new(
+            io::ErrorKind::UnexpectedEof, format!("sealed stream cut off {what}"),
+        );
+        let mut len = Vec::with_capacity(4);
+        match (&mut self.inner).take(4).read_to_end(&mut len)? {
+            0 if self.next == 0 => return Ok(false),
+            0 => return Err(cut("before its last record")),
+            4 => {}
+            _ => return Err(cut("inside a record")),
+        }
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        if len > MAX_SEALED_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "sealed record too large",
+            ));
+        }
+        let mut sealed = Vec::new();
+        if (&mut self.inner).take(len as u64).read_to_end(&mut sealed)? < len {
+            return Err(cut("inside a record"));
+        }
+        let (buf, last) = match open(&self.key, &stream_aad(self.next, false), &sealed) {
+            Ok(buf) => (buf, false),
+            Err(_) => (open(&self.key, &stream_aad(self.next, true), &sealed)?, true),
+        };
+        self.buf = buf;
+        self.pos = 0;
+        self.next = if last { 0 } else { self.next + 1 };
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for SealedReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if !self.refill()? {
+                return Ok(0);
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn chacha20_block_matches_rfc8439() {
+        // §2.3.2
+        let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+        let nonce: [u8; 12] = hex("000000090000004a00000000").try_into().unwrap();
+        let out = chacha20_block(&key, 1, &nonce);
+        assert_eq!(out[..16], hex("10f1e7e4d13b5915500fdd1fa32071c4")[..]);
+    }
+
+    #[test]
+    fn poly1305_matches_rfc8439() {
+        // §2.5.2
+        let key: [u8; 32] = hex(
+            "85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b",
+        ).try_into().unwrap();
+        let tag = poly1305(&key, b"Cryptographic Forum Research Group");
+        assert_eq!(tag[..], hex("a8061dc1305136c6c22b8baf0c0127a9")[..]);
+    }
+
+    #[test]
+    fn aead_matches_rfc8439() {
+        // §2.8.2
+        let key = Key::from_bytes(std::array::from_fn(|i| 0x80 + i as u8));
+        let nonce: [u8; 12] = hex("070000004041424344454647").try_into().unwrap();
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let pt = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+                   only one tip for the future, sunscreen would be it.";
+        let sealed = seal_with_nonce(&key, nonce, &aad, pt);
+        let ct = &sealed[NONCE_LEN..sealed.len() - TAG_LEN];
+        assert_eq!(ct[..16], hex("d31a8d34648e60db7b86afbc53ef7ec2")[..]);
+        assert_eq!(sealed[sealed.len() - TAG_LEN..], hex("1ae10b594f09e26a7e902ecbd0600691")[..]);
+        assert_eq!(open(&key, &aad, &sealed).unwrap(), pt);
+    }
+
+    #[test]
+    fn tampering_and_wrong_keys_are_rejected() {
+        let key = Key::from_bytes([7; 32]);
+        let mut sealed = seal(&key, b"aad", b"secret").unwrap();
+        assert!(open(&Key::from_bytes([8; 32]), b"aad", &sealed).is_err());
+        assert!(open(&key, b"other", &sealed).is_err());
+        sealed[NONCE_LEN] ^= 1;
+        assert_eq!(open(&key, b"aad", &sealed).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// A sealed stream of `parts`, one record each, and the record offsets.
+    fn sealed_stream(key: &Key, parts: &[&[u8]]) -> (Vec<u8>, Vec<usize>) {
+        let mut disk = Vec::new();
+        let mut w = SealedWriter::new(&mut disk, key.clone());
+        for part in parts {
+            w.write_all(part).unwrap();
+            w.flush().unwrap();
+        }
+        w.finish().unwrap();
+        let mut offsets = vec![0];
+        while *offsets.last().unwrap() < disk.len() {
+            let at = *offsets.last().unwrap();
+            offsets.push(at + 4 + u32::from_be_bytes(disk[at..at + 4].try_into().unwrap()) as usize);
+        }
+        offsets.pop();
+        (disk, offsets)
+    }
+
+    fn read_all(key: &Key, disk: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        SealedReader::new(disk, key.clone()).read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn sealed_streams_roundtrip_and_hide_plaintext() {
+        let key = Key::from_bytes([1; 32]);
+        let (disk, offsets) = sealed_stream(&key, &[b"export-controlled ", b"payload"]);
+        assert_eq!(offsets.len(), 3, "two records and the last, empty one");
+        assert!(!disk.windows(7).any(|win| win == b"payload"));
+        assert_eq!(read_all(&key, &disk).unwrap(), b"export-controlled payload");
+
+        // Streams appended to the same file read as one.
+        let (more, _) = sealed_stream(&key, &[b" and more"]);
+        let both = [disk, more].concat();
+        assert_eq!(read_all(&key, &both).unwrap(), b"export-controlled payload and more");
+        assert_eq!(read_all(&key, &[]).unwrap(), b"");
+    }
+
+    #[test]
+    fn reordered_dropped_and_cut_off_records_are_errors() {
+        let key = Key::from_bytes([2; 32]);
+        let (disk, at) = sealed_stream(&key, &[b"one", b"two", b"three"]);
+        let record = |i: usize| &disk[at[i]..*at.get(i + 1).unwrap_or(&disk.len())];
+        let kind = |disk: &[u8]| read_all(&key, disk).unwrap_err().kind();
+
+        let swapped = [record(1), record(0), record(2), record(3)].concat();
+        assert_eq!(kind(&swapped), io::ErrorKind::InvalidData);
+        let dropped = [record(0), record(2), record(3)].concat();
+        assert_eq!(kind(&dropped), io::ErrorKind::InvalidData);
+        // Cut at a record boundary before the last record...
+        assert_eq!(kind(&disk[..at[3]]), io::ErrorKind::UnexpectedEof);
+        // ...inside a length prefix, or inside a record.
+        assert_eq!(kind(&disk[..at[1] + 2]), io::ErrorKind::UnexpectedEof);
+        assert_eq!(kind(&disk[..at[1] + 9]), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn keys_load_from_specs() {
+        let hex_key = "00".repeat(31) + "ff";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("k");
+        fs::write(&path, format!("{hex_key}\n")).unwrap();
+        let want = Key::from_hex(&hex_key).unwrap();
+        assert_eq!(Key::load(&format!("file:{}", path.display())).unwrap(), want);
+        assert_eq!(Key::load(&format!("cmd:echo {hex_key}")).unwrap(), want);
+        assert!(Key::load("vault:whatever").is_err());
+        assert!(Key::from_hex("abc").is_err());
+        assert_eq!(format!("{want:?}"), "Key(<redacted>)");
+    }
+}
@@ -1,18 +1,31 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
+//
+//   consumer [ORCHESTRATOR_ADDR] [MODE]
+//
+// The stdout modes (--log, --jsonl, --base64, --raw, --binary) take each
+// message as it arrives; `nu_consumer` has the MessagePack and JSON batch
+// output for Nushell. The sink modes (--dir, --exec) consume in ack
+// mode: a message is acked once it is safely on disk or its command
+// succeeded, so the orchestrator's retry policy covers whatever the sink
+// did not finish. On an at-least-once queue the stdout modes are in ack
+// mode too, acking each message once it is written out.
 use std::env;
+use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
 use base64::{engine::general_purpose::STANDARD, Engine as _};
-use qpipe::Consumer;
+use qpipe::{Consumer, ConnectOptions, DeliveryPolicy, Filter};
 
-use log::info;
+use log::{info, warn};
 
-#[derive(Copy, Clone)]
 enum Mode {
     /// Human-readable: log each message (utf-8 if valid, else hex preview)
     /// to the env_logger sink. This is the original `consumer` behavior.
     Log,
-    /// One UTF-8 line per message on stdout (NDJSON-friendly).
+    /// One UTF-8 line per message on stdout (NDJSON-friendly). A JSON
+    /// payload spread over several lines is compacted onto one.
     Jsonl,
     /// One base64-encoded line per message on stdout.
     Base64,
@@ -22,6 +35,13 @@ enum Mode {
     ///   consumer ADDR --raw | from msgpack --objects
     /// streams typed records into Nushell.
     Raw,
+    /// Length-prefixed payloads on stdout, [u32 BE len][bytes]: binary
+    /// safe, and what `producer --binary` reads back.
+    Binary,
+    /// Each message to its own numbered file in a directory.
+    Dir(PathBuf),
+    /// Each message on the stdin of a fresh `sh -c CMD`.
+    Exec(String),
 }
 
 impl Mode {
@@ -31,12 +51,101 @@ impl Mode {
             "--jsonl"  => Ok(Mode::Jsonl),
             "--base64" => Ok(Mode::Base64),
             "--raw"    => Ok(Mode::Raw),
+            "--binary" => Ok(Mode::Binary),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                format!("mode must be --log, --jsonl, --base64, or --raw (got {s:?})"),
+                format!(
+                    "mode must be --log, --jsonl, --base64, --raw, --binary, --dir DIR, \
+                     or --exec CMD (got {s:?})"
+                ),
             )),
         }
     }
+
+    /// Whether the mode settles messages itself, in ack mode.
+    fn is_sink(&self) -> bool {
+        matches!(self, Mode::Dir(_) | Mode::Exec(_))
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> io::Result<(String, Mode)> {
+    let bad = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let mut orchestrator = None;
+    let mut mode = None;
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let mut value = |what: &str| args.next().ok_or_else(|| bad(format!("{arg} needs {what}")));
+        let next = match arg.as_str() {
+            "--dir"  => Mode::Dir(PathBuf::from(value("a DIR")?)),
+            "--exec" => Mode::Exec(value("a CMD")?),
+            s if s.starts_with("--") => Mode::parse(s)?,
+            _ if orchestrator.is_none() => {
+                orchestrator = Some(arg);
+                continue;
+            }
+            _ => return Err(bad(format!("unexpected argument {arg:?}"))),
+        };
+        if mode.replace(next).is_some() {
+            return Err(bad("only one mode may be given".into()));
+        }
+    }
+    Ok((orchestrator.unwrap_or_else(|| "127.0.0.1:7000".to_string()), mode.unwrap_or(Mode::Log)))
+}
+
+/// Writes messages to `dir` as 0000000001, 0000000002, ..., carrying on
+/// after the highest number already there so a restart never overwrites.
+struct DirSink {
+    dir:  PathBuf,
+    next: u64,
+}
+
+impl DirSink {
+    fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut last = 0;
+        for entry in fs::read_dir(dir)? {
+            if let Some(n) = entry?.file_name().to_str().and_then(|n| n.parse::<u64>().ok()) {
+                last = last.max(n);
+            }
+        }
+        Ok(Self { dir: dir.to_path_buf(), next: last + 1 })
+    }
+
+    /// Write under a hidden name, sync, then rename: whoever watches the
+    /// directory only ever sees whole files.
+    fn write(&mut self, payload: &[u8]) -> io::Result<PathBuf> {
+        let name = format!("{:010}", self.next);
+        let tmp = self.dir.join(format!(".{name}.tmp"));
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(payload)?;
+        f.sync_all()?;
+        let path = self.dir.join(name);
+        fs::rename(&tmp, &path)?;
+        self.next += 1;
+        Ok(path)
+    }
+}
+
+/// Runs `cmd` with `payload` on its stdin; true if it exited 0. The
+/// command's stdout and stderr are the consumer's.
+fn exec(cmd: &str, payload: &[u8], attempt: u32) -> io::Result<bool> {
+    let mut child = Command::new("sh")
+        .args(["-c", cmd])
+        .env("QPIPE_ATTEMPT", attempt.to_string())
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    match stdin.write_all(payload) {
+        // A command may well not read all of its input.
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+        _ => {}
+    }
+    drop(stdin);
+    let status = child.wait()?;
+    if !status.success() {
+        warn!("{cmd:?} failed ({status})");
+    }
+    Ok(status.success())
 }
 
 fn hex_preview(bytes: &[u8], max: usize) -> String {
@@ -53,28 +162,90 @@ fn hex_preview(bytes: &[u8], max: usize) -> String {
     out
 }
 
+/// `payload` as one line of JSON: as is if it has no newline, compacted if
+/// it is JSON that does.
+fn json_line(payload: &[u8]) -> io::Result<String> {
+    // Validate UTF-8 so Nu isn't fed broken text.
+    let s = std::str::from_utf8(payload).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "payload not valid UTF-8")
+    })?;
+    if !s.contains('\n') {
+        return Ok(s.to_string());
+    }
+    match serde_json::from_str::<serde_json::Value>(s) {
+        Ok(value) => Ok(value.to_string()),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "payload contains newline and is not JSON; not valid for --jsonl (use --base64)",
+        )),
+    }
+}
+
 fn main() -> io::Result<()> {
     // By default emit warnings
     env_logger::Builder::from_env(
         env_logger::Env::default().default_filter_or("warn")
     ).init();
 
-    let mut args = env::args().skip(1);
-    let orchestrator = args
-        .next()
-        .unwrap_or_else(|| "127.0.0.1:7000".to_string());
-    let mode = match args.next() {
-        Some(s) => Mode::parse(&s)?,
-        None    => Mode::Log,
-    };
+    let (orchestrator, mode) = parse_args(env::args().skip(1))?;
 
-    let mut c = Consumer::connect(&orchestrator)?;
+    // QPIPE_QUEUE picks a named queue; unset means the default one.
+    // QPIPE_NAME names this client in the orchestrator's logs and stats.
+    // QPIPE_FILTER takes only messages whose headers match, e.g. run^=sim-.
+    // QPIPE_CHECKSUM=1 has every frame carry a CRC-32C.
+    // QPIPE_SINGLE_PORT=1 keeps the session on the orchestrator's port.
+    let mut opts = ConnectOptions::new()
+        .ack_mode(mode.is_sink())
+        .checksum(env::var("QPIPE_CHECKSUM").is_ok_and(|v| v == "1"))
+        .single_port(env::var("QPIPE_SINGLE_PORT").is_ok_and(|v| v == "1"));
+    if let Ok(queue) = env::var("QPIPE_QUEUE")
+        && !queue.is_empty()
+    {
+        opts = opts.queue(queue);
+    }
+    if let Ok(name) = env::var("QPIPE_NAME")
+        && !name.is_empty()
+    {
+        opts = opts.name(name);
+    }
+    if let Ok(spec) = env::var("QPIPE_FILTER")
+        && !spec.is_empty()
+    {
+        opts = opts.filter(Filter::parse(&spec)?);
+    }
+    let mut c = Consumer::connect_with(&orchestrator, &opts)?;
     info!("consumer connected via {}", orchestrator);
+    // An at-least-once queue puts even the stdout modes in ack mode: they
+    // ack each message once it is written.
+    let acks = c.delivery_policy() == DeliveryPolicy::AtLeastOnce;
+
+    match &mode {
+        Mode::Dir(dir) => {
+            let mut sink = DirSink::open(dir)?;
+            loop {
+                let msg = c.recv_ack()?;
+                // A failed write leaves the message unacked: it goes back
+                // to the queue when the consumer exits with the error.
+                let path = sink.write(&msg.payload)?;
+                info!("wrote {} ({} bytes)", path.display(), msg.payload.len());
+                c.ack(msg.tag.unwrap())?;
+            }
+        }
+        Mode::Exec(cmd) => loop {
+            let msg = c.recv_ack()?;
+            if exec(cmd, &msg.payload, msg.attempt)? {
+                c.ack(msg.tag.unwrap())?;
+            } else {
+                c.nack(msg.tag.unwrap())?;
+            }
+        },
+        _ => {}
+    }
 
     let mut out = io::stdout().lock();
 
     loop {
-        let msg = c.recv()?;
+        let (msg, tag) = next(&mut c, acks)?;
 
         match mode {
             Mode::Log => {
@@ -88,26 +259,9 @@ fn main() -> io::Result<()> {
                 }
             }
             Mode::Jsonl => {
-                // Validate UTF-8 so Nu isn't fed broken text.
-                let s = std::str::from_utf8(&msg).map_err(|_| {
-                    io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "payload not valid UTF-8",
-                    )
-                })?;
-
-                // Ensure exactly one line per message (NDJSON style). If your
-                // producers might send pretty-printed JSON with newlines,
-                // either compact it before sending, or switch to --base64.
-                if s.contains('\n') {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "payload contains newline; not valid for --jsonl \
-                         (use compact JSON or --base64)",
-                    ));
-                }
-
-                out.write_all(s.as_bytes())?;
+                // Exactly one line per message (NDJSON style).
+                let line = json_line(&msg)?;
+                out.write_all(line.as_bytes())?;
                 out.write_all(b"\n")?;
                 out.flush()?;
             }
@@ -124,6 +278,26 @@ fn main() -> io::Result<()> {
                 out.write_all(&msg)?;
                 out.flush()?;
             }
+            Mode::Binary => {
+                let len = u32::try_from(msg.len()).map_err(|_| io::Error::new(
+                    io::ErrorKind::InvalidData, "payload too long for a u32 length prefix",
+                ))?;
+                out.write_all(&len.to_be_bytes())?;
+                out.write_all(&msg)?;
+                out.flush()?;
+            }
+            Mode::Dir(_) | Mode::Exec(_) => unreachable!("served above"),
+        }
+        if let Some(tag) = tag {
+            c.ack(tag)?;
         }
     }
 }
+
+/// The next message, with the tag to ack it by if `acks`.
+fn next(c: &mut Consumer, acks: bool) -> io::Result<(Vec<u8>, Option<u64>)> {
+    match acks {
+        true => c.recv_ack().map(|m| (m.payload, m.tag)),
+        false => c.recv().map(|m| (m.payload, None)),
+    }
+}
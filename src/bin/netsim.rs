@@ -0,0 +1,333 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+// Network-condition simulator: a TCP proxy to put between clients and an
+// orchestrator, so timeout and flow-control settings can be tried against
+// WAN-like links before going live.
+//
+//   netsim LISTEN_ADDR ORCHESTRATOR_ADDR [SPEC]
+//   SPEC = "latency=80ms,jitter=20ms,rate=1M,reset=0.001,seed=7"
+//
+// Clients connect to LISTEN_ADDR as if it were the orchestrator. Producer
+// and consumer handshakes are rewritten so their data connections run
+// through the proxy too: the upstream reply's data port is swapped for one
+// the proxy listens on. Single-port sessions, whose reply has port 0, stay
+// on the control connection. Admin roles (health, drain, ...) pass through.
+//
+// Every key is optional; each applies per connection and per direction:
+//   latency  one-way delay added to every chunk of bytes
+//   jitter   extra random delay, uniform in [0, jitter]; bytes still arrive
+//            in order, as TCP would deliver them
+//   rate     bandwidth cap in bytes/s (k, M, G suffixes; powers of 1000)
+//   reset    probability per chunk that the connection is cut abruptly
+//   seed     seed for jitter and resets, for reproducible runs
+// Durations take `us`, `ms` or `s`; a bare number means milliseconds.
+//
+// Packet loss and reordering inside a TCP stream never reach the
+// application — TCP hides them as delay — so they are modelled as jitter;
+// what the application can see, a dropped connection, is `reset`.
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use rand::{rngs::SysRng, TryRng};
+
+use qpipe::{HELLO_MAGIC, ROLE_CONSUMER, ROLE_FLAG_OPTS, ROLE_PRODUCER};
+
+const CHUNK: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Conditions {
+    latency: Duration,
+    jitter:  Duration,
+    rate:    Option<u64>,
+    reset:   f64,
+    seed:    Option<u64>,
+}
+
+impl Conditions {
+    fn parse(spec: &str) -> io::Result<Self> {
+        let bad = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let mut c = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, val) = part.split_once('=')
+                .ok_or_else(|| bad(format!("expected key=value, got {part:?}")))?;
+            let val = val.trim();
+            match key.trim() {
+                "latency" => c.latency = parse_duration(val).map_err(bad)?,
+                "jitter"  => c.jitter = parse_duration(val).map_err(bad)?,
+                "rate"    => c.rate = Some(parse_rate(val).map_err(bad)?),
+                "reset"   => {
+                    c.reset = val.parse().ok()
+                        .filter(|p: &f64| (0.0..=1.0).contains(p))
+                        .ok_or_else(|| bad(format!("reset must be in 0..1, got {val:?}")))?;
+                }
+                "seed" => {
+                    c.seed = Some(val.parse()
+                        .map_err(|_| bad(format!("bad seed {val:?}")))?);
+                }
+                k => return Err(bad(format!("unknown key {k:?}"))),
+            }
+        }
+        Ok(c)
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("bad duration {s:?}"))?;
+    match unit {
+        "us"      => Ok(Duration::from_micros(n)),
+        "" | "ms" => Ok(Duration::from_millis(n)),
+        "s"       => Ok(Duration::from_secs(n)),
+        _ => Err(format!("bad duration unit in {s:?}")),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<u64, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("bad rate {s:?}"))?;
+    let scale = match unit.trim_end_matches("/s").trim_end_matches('B') {
+        ""        => 1,
+        "k" | "K" => 1_000,
+        "M"       => 1_000_000,
+        "G"       => 1_000_000_000,
+        _ => return Err(format!("bad rate unit in {s:?}")),
+    };
+    n.checked_mul(scale).filter(|r| *r > 0)
+        .ok_or_else(|| format!("rate {s:?} must be positive"))
+}
+
+/// xorshift64*: plenty for jitter and coin flips, and seedable.
+struct Rng(u64);
+
+impl Rng {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Hands each pump its own generator: from `seed` if given, else random.
+struct Seeds {
+    base: u64,
+    next: AtomicU64,
+}
+
+impl Seeds {
+    fn new(seed: Option<u64>) -> io::Result<Self> {
+        let base = match seed {
+            Some(s) => s,
+            None => {
+                let mut b = [0u8; 8];
+                SysRng.try_fill_bytes(&mut b).map_err(io::Error::other)?;
+                u64::from_le_bytes(b)
+            }
+        };
+        Ok(Self { base, next: AtomicU64::new(0) })
+    }
+
+    fn rng(&self) -> Rng {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        // splitmix64 step, so neighbouring pumps don't get related streams
+        let mut z = self.base.wrapping_add(n.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        Rng((z ^ (z >> 31)) | 1)
+    }
+}
+
+/// Copy `src` to `dst` under `cond`, after first sending `prefix`. A reader
+/// thread timestamps chunks as they arrive; this thread releases each one
+/// when its delay has passed and the bandwidth cap allows. Returns when
+/// `src` closes (half-closing `dst`) or the connection is reset.
+fn pump(
+            src: TcpStream,
+            mut dst: TcpStream,
+            prefix: Vec<u8>,
+            cond: Conditions,
+            mut rng: Rng,
+        ) {
+    let (tx, rx) = mpsc::channel::<(Instant, Vec<u8>)>();
+    let reader = {
+        let (src_r, dst_r) = (src.try_clone(), dst.try_clone());
+        thread::spawn(move || {
+            let (Ok(mut src_r), Ok(dst_r)) = (src_r, dst_r) else { return };
+            let mut last = Instant::now();
+            let mut buf = vec![0u8; CHUNK];
+            loop {
+                let n = match src_r.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => n,
+                };
+                if rng.next_f64() < cond.reset {
+                    warn!("netsim: resetting connection");
+                    src_r.shutdown(Shutdown::Both).ok();
+                    dst_r.shutdown(Shutdown::Both).ok();
+                    return;
+                }
+                let jitter = cond.jitter.mul_f64(rng.next_f64());
+                // Never earlier than the previous chunk: a stream stays in order.
+                last = last.max(Instant::now() + cond.latency + jitter);
+                if tx.send((last, buf[..n].to_vec())).is_err() {
+                    return;
+                }
+            }
+        })
+    };
+
+    if dst.write_all(&prefix).is_err() {
+        src.shutdown(Shutdown::Both).ok();
+    }
+    let mut budget_at = Instant::now();
+    for (due, bytes) in rx {
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        if let Some(rate) = cond.rate {
+            // Serialize chunks at the capped rate.
+            budget_at = budget_at.max(Instant::now())
+                + Duration::from_secs_f64(bytes.len() as f64 / rate as f64);
+            thread::sleep(budget_at.saturating_duration_since(Instant::now()));
+        }
+        if dst.write_all(&bytes).is_err() {
+            src.shutdown(Shutdown::Both).ok();
+            break;
+        }
+    }
+    dst.shutdown(Shutdown::Write).ok();
+    let _ = reader.join();
+}
+
+/// Run both directions of a proxied connection, each on its own thread.
+fn relay(
+            client: TcpStream,
+            upstream: TcpStream,
+            to_client_prefix: Vec<u8>,
+            cond: Conditions,
+            seeds: &Seeds,
+        ) -> io::Result<()> {
+    let (c2, u2) = (client.try_clone()?, upstream.try_clone()?);
+    let (rng_up, rng_down) = (seeds.rng(), seeds.rng());
+    thread::spawn(move || pump(c2, u2, Vec::new(), cond, rng_up));
+    thread::spawn(move || pump(upstream, client, to_client_prefix, cond, rng_down));
+    Ok(())
+}
+
+/// One client control connection. Producer and consumer handshakes get
+/// their data port rewritten to a proxy listener; everything else is
+/// relayed as is.
+fn handle_control(
+            mut client: TcpStream,
+            upstream_addr: SocketAddr,
+            cond: Conditions,
+            seeds: Arc<Seeds>,
+        ) -> io::Result<()> {
+    client.set_nodelay(true).ok();
+    let mut upstream = TcpStream::connect(upstream_addr)?;
+    upstream.set_nodelay(true).ok();
+
+    let mut role = [0u8; 1];
+    client.read_exact(&mut role)?;
+    if role[0] == HELLO_MAGIC[0] {
+        // Relay the version hello untouched, then the real role byte.
+        let mut hello = [0u8; 6];
+        hello[0] = role[0];
+        client.read_exact(&mut hello[1..])?;
+        upstream.write_all(&hello)?;
+        let mut answer = [0u8; 5];
+        upstream.read_exact(&mut answer)?;
+        client.write_all(&answer)?;
+        client.read_exact(&mut role)?;
+    }
+    upstream.write_all(&role)?;
+    let session = matches!(role[0] & !ROLE_FLAG_OPTS, ROLE_PRODUCER | ROLE_CONSUMER);
+    if !session {
+        return relay(client, upstream, Vec::new(), cond, &seeds);
+    }
+
+    // Client -> upstream carries any handshake options; the reply starts
+    // with the data port, which we swap for our own.
+    let (c2, u2) = (client.try_clone()?, upstream.try_clone()?);
+    let rng = seeds.rng();
+    thread::spawn(move || pump(c2, u2, Vec::new(), cond, rng));
+    let mut port = [0u8; 2];
+    upstream.read_exact(&mut port)?;
+    if port == [0, 0] {
+        let rng = seeds.rng();
+        pump(upstream, client, port.to_vec(), cond, rng);
+        return Ok(());
+    }
+    let data_upstream = SocketAddr::new(upstream_addr.ip(), u16::from_be_bytes(port));
+    let data_listener = TcpListener::bind(SocketAddr::new(client.local_addr()?.ip(), 0))?;
+    let our_port = data_listener.local_addr()?.port();
+    debug!("netsim: data port {} -> {}", our_port, data_upstream);
+
+    let rng = seeds.rng();
+    thread::spawn(move || pump(upstream, client, our_port.to_be_bytes().to_vec(), cond, rng));
+
+    // The client connects once, right after the handshake.
+    let (data_client, _) = data_listener.accept()?;
+    data_client.set_nodelay(true).ok();
+    let data_up = TcpStream::connect(data_upstream)?;
+    data_up.set_nodelay(true).ok();
+    relay(data_client, data_up, Vec::new(), cond, &seeds)
+}
+
+fn run(args: &[String]) -> io::Result<()> {
+    let usage = || io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "usage: netsim LISTEN_ADDR ORCHESTRATOR_ADDR [SPEC]",
+    );
+    let listen = args.first().ok_or_else(usage)?;
+    let upstream = args.get(1).ok_or_else(usage)?
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(usage)?;
+    let cond = Conditions::parse(args.get(2).map_or("", String::as_str))?;
+    let seeds = Arc::new(Seeds::new(cond.seed)?);
+
+    let listener = TcpListener::bind(listen)?;
+    info!(
+        "netsim on {} -> {} with {:?}", listener.local_addr()?, upstream, cond
+    );
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("netsim: accept failed: {}", e);
+                continue;
+            }
+        };
+        let seeds = seeds.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_control(client, upstream, cond, seeds) {
+                debug!("netsim: connection ended: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn")
+    ).init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("netsim: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,267 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+//   nu_consumer [ORCHESTRATOR_ADDR] [--jsonl | --msgpack | --batch N]
+//               [--input-format auto|json|msgpack]
+//
+// A consumer for Nushell and polars: every message comes out as a record
+// `from json` or `from msgpack` can read, one at a time.
+//   --jsonl    : one JSON line per message (default). A JSON payload spread
+//                over several lines is compacted onto one.
+//   --msgpack  : one MessagePack value per message, for
+//                  nu_consumer ADDR --msgpack | from msgpack --objects
+//   --batch N  : up to N JSON messages per line, as one JSON array; a
+//                partial batch goes out once the stream goes quiet.
+//
+// --input-format says what --msgpack gets from producers. `auto` (the
+// default) converts a payload that is JSON, passes one that is a
+// MessagePack value through, and wraps anything else as a binary value.
+// JSON wins where a payload is both — `1` is the number 1, not the
+// MessagePack fixint 0x31 — so producers of bare MessagePack scalars
+// should say `msgpack`.
+//
+// Sessions take the consumer's QPIPE_QUEUE, QPIPE_NAME, QPIPE_FILTER,
+// QPIPE_CHECKSUM and QPIPE_SINGLE_PORT. On an at-least-once queue each
+// message (or batch) is acked once it is written out.
+use std::env;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use qpipe::{Consumer, ConnectOptions, Delivery, DeliveryPolicy, Filter};
+use rmpv::decode::read_value;
+use rmpv::encode::write_value;
+use rmpv::Value;
+
+use log::info;
+
+enum Mode {
+    Jsonl,
+    Msgpack,
+    Batch(usize),
+}
+
+/// What `--msgpack` takes payloads for.
+#[derive(Clone, Copy, PartialEq)]
+enum Input {
+    Auto,
+    Json,
+    Msgpack,
+}
+
+/// How long `--batch` holds a partial batch for want of messages.
+const BATCH_LINGER: Duration = Duration::from_secs(1);
+
+fn parse_args(args: impl Iterator<Item = String>) -> io::Result<(String, Mode, Input)> {
+    let bad = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let mut orchestrator = None;
+    let mut mode = None;
+    let mut input = None;
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let mut value = |what: &str| args.next().ok_or_else(|| bad(format!("{arg} needs {what}")));
+        let next = match arg.as_str() {
+            "--jsonl" => Mode::Jsonl,
+            "--msgpack" => Mode::Msgpack,
+            "--batch" => {
+                let n = value("a size")?;
+                let size = n.parse().ok().filter(|n| *n > 0);
+                Mode::Batch(size.ok_or_else(|| bad(format!("bad --batch {n:?}")))?)
+            }
+            "--input-format" => {
+                input = Some(match value("a format")?.as_str() {
+                    "auto" => Input::Auto,
+                    "json" => Input::Json,
+                    "msgpack" => Input::Msgpack,
+                    f => return Err(bad(format!("--input-format must be auto, json or msgpack (got {f:?})"))),
+                });
+                continue;
+            }
+            s if s.starts_with("--") => {
+                return Err(bad(format!("mode must be --jsonl, --msgpack or --batch N (got {s:?})")));
+            }
+            _ if orchestrator.is_none() => {
+                orchestrator = Some(arg);
+                continue;
+            }
+            _ => return Err(bad(format!("unexpected argument {arg:?}"))),
+        };
+        if mode.replace(next).is_some() {
+            return Err(bad("only one mode may be given".into()));
+        }
+    }
+    let mode = mode.unwrap_or(Mode::Jsonl);
+    if input.is_some() && !matches!(mode, Mode::Msgpack) {
+        return Err(bad("--input-format only applies to --msgpack".into()));
+    }
+    Ok((
+        orchestrator.unwrap_or_else(|| "127.0.0.1:7000".to_string()),
+        mode,
+        input.unwrap_or(Input::Auto),
+    ))
+}
+
+/// `payload` as exactly one MessagePack value; see `--input-format`.
+fn to_msgpack(payload: &[u8], input: Input, out: &mut Vec<u8>) -> io::Result<()> {
+    let parsed = match input {
+        Input::Msgpack => None,
+        _ => serde_json::from_slice(payload).ok().map(from_json),
+    };
+    let value = match parsed {
+        Some(value) => value,
+        None => {
+            let mut rest = payload;
+            if input != Input::Json && read_value(&mut rest).is_ok() && rest.is_empty() {
+                out.extend_from_slice(payload);
+                return Ok(());
+            }
+            Value::Binary(payload.to_vec())
+        }
+    };
+    write_value(out, &value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// `payload` as one line of JSON: as is if it has no newline, compacted if
+/// it is JSON that does.
+fn json_line(payload: &[u8]) -> io::Result<String> {
+    // Validate UTF-8 so Nu isn't fed broken text.
+    let s = std::str::from_utf8(payload).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "payload not valid UTF-8")
+    })?;
+    if !s.contains('\n') {
+        return Ok(s.to_string());
+    }
+    match serde_json::from_str::<serde_json::Value>(s) {
+        Ok(value) => Ok(value.to_string()),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "payload contains newline and is not JSON; not valid for --jsonl (use --base64)",
+        )),
+    }
+}
+
+/// A JSON value as MessagePack, object keys in their order. Integers
+/// that fit 64 bits stay integers; other numbers become floats.
+fn from_json(json: serde_json::Value) -> Value {
+    use serde_json::Value as Json;
+
+    match json {
+        Json::Null => Value::Nil,
+        Json::Bool(b) => Value::Boolean(b),
+        Json::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(u), _) => Value::from(u),
+            (_, Some(i)) => Value::from(i),
+            _ => n.as_f64().map_or_else(|| Value::from(n.to_string()), Value::from),
+        },
+        Json::String(s) => Value::from(s),
+        Json::Array(items) => Value::Array(items.into_iter().map(from_json).collect()),
+        Json::Object(entries) => Value::Map(
+            entries.into_iter().map(|(k, v)| (Value::from(k), from_json(v))).collect(),
+        ),
+    }
+}
+
+fn main() -> io::Result<()> {
+    // By default emit warnings
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn")
+    ).init();
+
+    let (orchestrator, mode, input) = parse_args(env::args().skip(1))?;
+
+    let mut opts = ConnectOptions::new()
+        .checksum(env::var("QPIPE_CHECKSUM").is_ok_and(|v| v == "1"))
+        .single_port(env::var("QPIPE_SINGLE_PORT").is_ok_and(|v| v == "1"));
+    if let Ok(queue) = env::var("QPIPE_QUEUE")
+        && !queue.is_empty()
+    {
+        opts = opts.queue(queue);
+    }
+    if let Ok(name) = env::var("QPIPE_NAME")
+        && !name.is_empty()
+    {
+        opts = opts.name(name);
+    }
+    if let Ok(spec) = env::var("QPIPE_FILTER")
+        && !spec.is_empty()
+    {
+        opts = opts.filter(Filter::parse(&spec)?);
+    }
+    let mut c = Consumer::connect_with(&orchestrator, &opts)?;
+    info!("nu_consumer connected via {}", orchestrator);
+    let acks = c.delivery_policy() == DeliveryPolicy::AtLeastOnce;
+
+    let mut out = io::stdout().lock();
+    let mut packed = Vec::new();
+    let mut batch = Vec::new();
+    let mut tags = Vec::new();
+    loop {
+        // Wait as long as it takes for a batch's first message, then only
+        // so long for the rest.
+        let msg = match mode {
+            Mode::Batch(_) if !batch.is_empty() => next_timeout(&mut c, acks, BATCH_LINGER)?,
+            _ => Some(next(&mut c, acks)?),
+        };
+        if let Some((_, tag)) = &msg {
+            tags.extend(*tag);
+        }
+        match (&mode, msg) {
+            (Mode::Jsonl, Some((msg, _))) => {
+                // Exactly one line per message (NDJSON style).
+                let line = json_line(&msg)?;
+                out.write_all(line.as_bytes())?;
+                out.write_all(b"\n")?;
+            }
+            (Mode::Msgpack, Some((msg, _))) => {
+                packed.clear();
+                to_msgpack(&msg, input, &mut packed)?;
+                out.write_all(&packed)?;
+            }
+            (Mode::Batch(n), msg) => {
+                // Only a partial batch waits with a timeout.
+                let quiet = msg.is_none();
+                if let Some((msg, _)) = msg {
+                    let line = json_line(&msg)?;
+                    if serde_json::from_str::<serde_json::Value>(&line).is_err() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData, "payload is not JSON; not valid for --batch",
+                        ));
+                    }
+                    batch.push(line);
+                }
+                if batch.len() < *n && !quiet {
+                    continue;
+                }
+                writeln!(out, "[{}]", batch.join(","))?;
+                batch.clear();
+            }
+            (_, None) => unreachable!("only batches wait with a timeout"),
+        }
+        out.flush()?;
+        for tag in tags.drain(..) {
+            c.ack(tag)?;
+        }
+    }
+}
+
+/// The next message, with the tag to ack it by if `acks`.
+fn next(c: &mut Consumer, acks: bool) -> io::Result<(Vec<u8>, Option<u64>)> {
+    match acks {
+        true => c.recv_ack().map(|m| (m.payload, m.tag)),
+        false => c.recv().map(|m| (m.payload, None)),
+    }
+}
+
+/// `next`, giving up after `timeout`.
+fn next_timeout(c: &mut Consumer, acks: bool, timeout: Duration) -> io::Result<Option<(Vec<u8>, Option<u64>)>> {
+    if !acks {
+        return Ok(c.recv_timeout(timeout)?.map(|m| (m.payload, None)));
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match c.recv_ext_timeout(left)? {
+            Some(Delivery::Message(m)) => return Ok(Some((m.payload, m.tag))),
+            Some(Delivery::Eos(_)) => continue,
+            None => return Ok(None),
+        }
+    }
+}
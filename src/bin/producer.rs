@@ -1,64 +1,204 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 //
+//   producer [ORCHESTRATOR_ADDR] [MODE] [--rate N]
+//
 // Modes (mirror the consumer's framing options):
 //   --lines    : one stdin line = one frame, raw UTF-8 bytes (default; the
-//                original `producer` behavior; works for NDJSON input).
+//                original `producer` behavior).
+//   --ndjson   : one JSON value per stdin line = one frame; blank lines are
+//                skipped and anything else is refused. For replaying what
+//                `consumer --jsonl` recorded.
 //   --base64   : one base64-encoded stdin line = one binary frame.
+//   --binary   : length-prefixed messages on stdin, [u32 BE len][bytes],
+//                as `qpipe-load -` reads them.
 //   --msgpack  : read concatenated MessagePack values from stdin, send each
 //                as one frame. Pairs with the consumer's
 //                  consumer ADDR --raw | from msgpack --objects
 //                for typed end-to-end Nushell pipelines.
+//   --file PATTERN : each matching file = one frame, in name order. `*` and
+//                `?` match within one path component, for patterns the shell
+//                didn't expand (quoted, or too many files for argv). May be
+//                given more than once.
+//
+// --rate N (messages per second, fractions allowed) paces the sends evenly
+// for demos and soak tests; unthrottled by default.
+//
+// QPIPE_PRIORITY=<0-255> sends every frame at that priority, e.g. for a
+// control message that must overtake queued bulk data.
+// QPIPE_TTL=<duration> ("500ms", "30s", "5m") has the orchestrator drop
+// frames no consumer has taken that long after they were queued.
 
 use std::env;
-use std::io::{self, BufRead};
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use base64::{engine::general_purpose::STANDARD, Engine as _};
-use qpipe::Producer;
+use qpipe::{Meta, Producer, ProducerOptions, MAX_MESSAGE_SIZE};
 use rmpv::decode::{read_value, Error as DecodeError};
 use rmpv::encode::write_value;
 
 use log::info;
 
-#[derive(Copy, Clone)]
 enum Mode {
     Lines,
+    Ndjson,
     Base64,
+    Binary,
     Msgpack,
+    /// Glob patterns, in the order given.
+    Files(Vec<String>),
 }
 
 impl Mode {
     fn parse(s: &str) -> io::Result<Self> {
         match s {
             "--lines"   => Ok(Mode::Lines),
+            "--ndjson"  => Ok(Mode::Ndjson),
             "--base64"  => Ok(Mode::Base64),
+            "--binary"  => Ok(Mode::Binary),
             "--msgpack" => Ok(Mode::Msgpack),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 format!(
-                    "mode must be --lines, --base64, or --msgpack (got {s:?})"
+                    "mode must be --lines, --ndjson, --base64, --binary, --msgpack, \
+                     or --file PATTERN (got {s:?})"
                 ),
             )),
         }
     }
 }
 
+struct Args {
+    orchestrator: String,
+    mode:         Mode,
+    rate:         Option<f64>,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> io::Result<Self> {
+        let bad = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let mut orchestrator = None;
+        let mut mode = None;
+        let mut rate = None;
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            let next = match arg.as_str() {
+                "--file" => {
+                    let pattern = args.next().ok_or_else(|| bad("--file needs a PATTERN".into()))?;
+                    match &mut mode {
+                        None => Mode::Files(vec![pattern]),
+                        Some(Mode::Files(patterns)) => {
+                            patterns.push(pattern);
+                            continue;
+                        }
+                        Some(_) => return Err(bad("--file cannot be combined with another mode".into())),
+                    }
+                }
+                "--rate" => {
+                    let val = args.next().ok_or_else(|| bad("--rate needs a value".into()))?;
+                    rate = Some(val.parse::<f64>().ok()
+                        .filter(|r| r.is_finite() && *r > 0.0)
+                        .ok_or_else(|| bad(format!("bad --rate {val:?} (messages per second)")))?);
+                    continue;
+                }
+                s if s.starts_with("--") => Mode::parse(s)?,
+                _ if orchestrator.is_none() => {
+                    orchestrator = Some(arg);
+                    continue;
+                }
+                _ => return Err(bad(format!("unexpected argument {arg:?}"))),
+            };
+            if mode.replace(next).is_some() {
+                return Err(bad("only one mode may be given".into()));
+            }
+        }
+        Ok(Self {
+            orchestrator: orchestrator.unwrap_or_else(|| "127.0.0.1:7000".to_string()),
+            mode: mode.unwrap_or(Mode::Lines),
+            rate,
+        })
+    }
+}
+
+/// Spaces sends `1/rate` seconds apart. A stall (a full queue) isn't made
+/// up for with a burst afterwards.
+struct Throttle {
+    interval: Duration,
+    next:     Instant,
+}
+
+impl Throttle {
+    fn new(rate: f64) -> Self {
+        Self { interval: Duration::from_secs_f64(1.0 / rate), next: Instant::now() }
+    }
+
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        }
+        self.next = self.next.max(now) + self.interval;
+    }
+}
+
 fn main() -> io::Result<()> {
     // By default emit warnings
     env_logger::Builder::from_env(
         env_logger::Env::default().default_filter_or("warn")
     ).init();
 
-    let mut args = env::args().skip(1);
-    let orchestrator = args
-        .next()
-        .unwrap_or_else(|| "127.0.0.1:7000".to_string());
-    let mode = match args.next() {
-        Some(s) => Mode::parse(&s)?,
-        None    => Mode::Lines,
+    let Args { orchestrator, mode, rate } = Args::parse(env::args().skip(1))?;
+    // Expand the patterns before connecting, so a typo fails fast.
+    let files = match &mode {
+        Mode::Files(patterns) => patterns.iter()
+            .map(|p| expand(p))
+            .collect::<io::Result<Vec<_>>>()?
+            .concat(),
+        _ => Vec::new(),
     };
 
-    let mut p = Producer::connect(&orchestrator)?;
+    // QPIPE_QUEUE picks a named queue; unset means the default one.
+    // QPIPE_NAME names this client in the orchestrator's logs and stats.
+    // QPIPE_CHECKSUM=1 has every frame carry a CRC-32C.
+    // QPIPE_SINGLE_PORT=1 keeps the session on the orchestrator's port.
+    let mut opts = ProducerOptions::new()
+        .checksum(env::var("QPIPE_CHECKSUM").is_ok_and(|v| v == "1"))
+        .single_port(env::var("QPIPE_SINGLE_PORT").is_ok_and(|v| v == "1"));
+    if let Ok(queue) = env::var("QPIPE_QUEUE")
+        && !queue.is_empty()
+    {
+        opts = opts.queue(queue);
+    }
+    if let Ok(name) = env::var("QPIPE_NAME")
+        && !name.is_empty()
+    {
+        opts = opts.name(name);
+    }
+    let mut p = Producer::connect_with(&orchestrator, &opts)?;
     info!("producer connected via {}", orchestrator);
+    let priority: u8 = match env::var("QPIPE_PRIORITY") {
+        Ok(s) if !s.is_empty() => s.parse().map_err(|e| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("QPIPE_PRIORITY={s:?}: {e}"),
+        ))?,
+        _ => 0,
+    };
+    let ttl = match env::var("QPIPE_TTL") {
+        Ok(s) if !s.is_empty() => Some(parse_duration(&s).map_err(|e| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("QPIPE_TTL: {e}"),
+        ))?),
+        _ => None,
+    };
+    let meta = Meta { priority: (priority > 0).then_some(priority), ttl, ..Meta::default() };
+    let mut throttle = rate.map(Throttle::new);
+    let mut send = |payload: &[u8]| {
+        if let Some(t) = &mut throttle {
+            t.wait();
+        }
+        p.send_with_meta(payload, &meta)
+    };
 
     let stdin = io::stdin();
     let mut stdin = stdin.lock();
@@ -72,7 +212,26 @@ fn main() -> io::Result<()> {
                 let n = stdin.read_line(&mut line)?;
                 if n == 0 { break; }
                 let payload = line.trim_end_matches(&['\n', '\r'][..]);
-                p.send(payload.as_bytes())?;
+                send(payload.as_bytes())?;
+            }
+        }
+        Mode::Ndjson => {
+            info!("reading NDJSON from stdin; each line's value becomes one frame");
+            let mut line = String::new();
+            let mut n = 0;
+            loop {
+                line.clear();
+                if stdin.read_line(&mut line)? == 0 { break; }
+                n += 1;
+                let trimmed = line.trim();
+                if trimmed.is_empty() { continue; }
+                if !is_json(trimmed.as_bytes()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {n} is not a JSON value"),
+                    ));
+                }
+                send(trimmed.as_bytes())?;
             }
         }
         Mode::Base64 => {
@@ -87,7 +246,38 @@ fn main() -> io::Result<()> {
                 let bytes = STANDARD.decode(trimmed).map_err(|e| {
                     io::Error::new(io::ErrorKind::InvalidData, e.to_string())
                 })?;
-                p.send(&bytes)?;
+                send(&bytes)?;
+            }
+        }
+        Mode::Binary => {
+            info!("reading length-prefixed messages from stdin");
+            let mut len = [0u8; 4];
+            let mut msg = Vec::new();
+            loop {
+                match stdin.read_exact(&mut len) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+                let len = u32::from_be_bytes(len) as usize;
+                if len > MAX_MESSAGE_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("length prefix {len} exceeds MAX_MESSAGE_SIZE"),
+                    ));
+                }
+                msg.resize(len, 0);
+                stdin.read_exact(&mut msg)?;
+                send(&msg)?;
+            }
+        }
+        Mode::Files(_) => {
+            info!("sending {} files, one frame each", files.len());
+            for path in &files {
+                let bytes = fs::read(path).map_err(|e| io::Error::new(
+                    e.kind(), format!("{}: {e}", path.display()),
+                ))?;
+                send(&bytes)?;
             }
         }
         Mode::Msgpack => {
@@ -100,7 +290,7 @@ fn main() -> io::Result<()> {
                         write_value(&mut buf, &val).map_err(|e| {
                             io::Error::new(io::ErrorKind::InvalidData, e.to_string())
                         })?;
-                        p.send(&buf)?;
+                        send(&buf)?;
                     }
                     // Clean EOF between values — nothing more to read.
                     Err(DecodeError::InvalidMarkerRead(e))
@@ -121,3 +311,192 @@ fn main() -> io::Result<()> {
 
     Ok(())
 }
+
+/// The regular files `pattern` names, sorted. `*` and `?` match within a
+/// path component, not across `/`, and not a leading `.` unless the
+/// pattern spells it out. A pattern that matches nothing is an error.
+fn expand(pattern: &str) -> io::Result<Vec<PathBuf>> {
+    let wild = |c: &str| c.contains(['*', '?']);
+    let mut paths = vec![PathBuf::new()];
+    for comp in pattern.split('/') {
+        if comp.is_empty() && paths == [PathBuf::new()] {
+            paths = vec![PathBuf::from("/")];
+            continue;
+        }
+        if !wild(comp) {
+            paths.iter_mut().for_each(|p| p.push(comp));
+            continue;
+        }
+        let mut next = Vec::new();
+        for dir in &paths {
+            let listed = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            let entries = match fs::read_dir(listed) {
+                Ok(entries) => entries,
+                // Not there, or not a directory: nothing below it matches.
+                Err(_) if !listed.is_dir() => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                let name = entry?.file_name();
+                let bytes = name.as_encoded_bytes();
+                let hidden = bytes.first() == Some(&b'.') && !comp.starts_with('.');
+                if !hidden && glob_match(comp.as_bytes(), bytes) {
+                    next.push(dir.join(&name));
+                }
+            }
+        }
+        paths = next;
+    }
+    let mut files: Vec<_> = paths.into_iter().filter(|p| p.is_file()).collect();
+    files.sort();
+    if files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound, format!("--file {pattern:?} matches no files"),
+        ));
+    }
+    Ok(files)
+}
+
+/// Whether `name` matches `pat`, where `*` is any run of bytes and `?`
+/// any one byte.
+fn glob_match(pat: &[u8], name: &[u8]) -> bool {
+    match (pat.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob_match(rest, name) || (!name.is_empty() && glob_match(pat, &name[1..]))
+        }
+        (Some((p, prest)), Some((n, nrest))) if *p == b'?' || p == n => glob_match(prest, nrest),
+        _ => false,
+    }
+}
+
+/// Whether `s` is exactly one JSON value (RFC 8259), surrounding
+/// whitespace aside.
+fn is_json(s: &[u8]) -> bool {
+    fn skip_ws(s: &[u8], i: &mut usize) {
+        while s.get(*i).is_some_and(|c| matches!(c, b' ' | b'\t' | b'\n' | b'\r')) {
+            *i += 1;
+        }
+    }
+    fn digits(s: &[u8], i: &mut usize) -> bool {
+        let start = *i;
+        while s.get(*i).is_some_and(u8::is_ascii_digit) {
+            *i += 1;
+        }
+        *i > start
+    }
+    fn string(s: &[u8], i: &mut usize) -> bool {
+        *i += 1; // the opening quote
+        while let Some(&c) = s.get(*i) {
+            *i += 1;
+            match c {
+                b'"' => return true,
+                b'\\' => match s.get(*i) {
+                    Some(b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => *i += 1,
+                    Some(b'u') if s.len() >= *i + 5
+                        && s[*i + 1..*i + 5].iter().all(u8::is_ascii_hexdigit) => *i += 5,
+                    _ => return false,
+                },
+                0..0x20 => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+    fn value(s: &[u8], i: &mut usize, depth: usize) -> bool {
+        skip_ws(s, i);
+        match s.get(*i) {
+            Some(b'"') => string(s, i),
+            Some(&open @ (b'[' | b'{')) => {
+                let close = if open == b'[' { b']' } else { b'}' };
+                if depth == 128 {
+                    return false;
+                }
+                *i += 1;
+                skip_ws(s, i);
+                if s.get(*i) == Some(&close) {
+                    *i += 1;
+                    return true;
+                }
+                loop {
+                    if open == b'{' {
+                        skip_ws(s, i);
+                        if s.get(*i) != Some(&b'"') || !string(s, i) {
+                            return false;
+                        }
+                        skip_ws(s, i);
+                        if s.get(*i) != Some(&b':') {
+                            return false;
+                        }
+                        *i += 1;
+                    }
+                    if !value(s, i, depth + 1) {
+                        return false;
+                    }
+                    skip_ws(s, i);
+                    match s.get(*i) {
+                        Some(b',') => *i += 1,
+                        Some(&c) if c == close => {
+                            *i += 1;
+                            break true;
+                        }
+                        _ => return false,
+                    }
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                if s[*i] == b'-' {
+                    *i += 1;
+                }
+                let lead_zero = s.get(*i) == Some(&b'0');
+                let start = *i;
+                if !digits(s, i) || (lead_zero && *i - start > 1) {
+                    return false;
+                }
+                if s.get(*i) == Some(&b'.') {
+                    *i += 1;
+                    if !digits(s, i) {
+                        return false;
+                    }
+                }
+                if matches!(s.get(*i), Some(b'e' | b'E')) {
+                    *i += 1;
+                    if matches!(s.get(*i), Some(b'+' | b'-')) {
+                        *i += 1;
+                    }
+                    if !digits(s, i) {
+                        return false;
+                    }
+                }
+                true
+            }
+            _ => ["true", "false", "null"].iter().any(|lit| {
+                let hit = s[*i..].starts_with(lit.as_bytes());
+                if hit {
+                    *i += lit.len();
+                }
+                hit
+            }),
+        }
+    }
+    let mut i = 0;
+    if !value(s, &mut i, 0) {
+        return false;
+    }
+    skip_ws(s, &mut i);
+    i == s.len()
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("bad duration {s:?}"))?;
+    match unit {
+        "ms"     => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m"      => Ok(Duration::from_secs(n * 60)),
+        "h"      => Ok(Duration::from_secs(n * 3600)),
+        _ => Err(format!("bad duration unit in {s:?}")),
+    }
+}
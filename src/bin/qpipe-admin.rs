@@ -0,0 +1,181 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+// Inspect and manage a running orchestrator over an admin session
+// (`qpipe::admin`).
+//
+//   qpipe-admin [ORCHESTRATOR_ADDR] sessions
+//   qpipe-admin [ORCHESTRATOR_ADDR] clients
+//   qpipe-admin [ORCHESTRATOR_ADDR] queues
+//   qpipe-admin [ORCHESTRATOR_ADDR] pause [--refuse] [QUEUE]
+//   qpipe-admin [ORCHESTRATOR_ADDR] resume [QUEUE]
+//   qpipe-admin [ORCHESTRATOR_ADDR] purge [QUEUE]
+//   qpipe-admin [ORCHESTRATOR_ADDR] snapshot FILE
+//   qpipe-admin [ORCHESTRATOR_ADDR] restore FILE
+//   qpipe-admin [ORCHESTRATOR_ADDR] wait-idle [QUEUE]
+//
+// `clients` lists the counters of every client name sessions have given.
+// `pause` and `resume` without a queue act on every queue. Paused
+// producers wait; with `--refuse`, those that can be told have new
+// messages refused (`ACK_THROTTLED`) instead. `purge` drops
+// everything waiting in one queue, the default one unless named.
+// `snapshot` copies what every queue holds to a recording, each frame with
+// its queue's name, and leaves the queues alone; `restore` queues a
+// recording's frames again, each in its queue. Both seal and open
+// recordings with QPIPE_AT_REST_KEY when it is set, like `qpipe-dump`,
+// whose recordings they share. With users configured, authenticate as an
+// `admin` through QPIPE_USER and QPIPE_PASSWORD. `wait-idle` returns once
+// the queue, or every queue unless one is named, is idle
+// (`qpipe::wait_for_idle`).
+
+use std::env;
+use std::io;
+use std::path::Path;
+use std::process::ExitCode;
+
+use qpipe::admin::Admin;
+use qpipe::at_rest::Key;
+use qpipe::{export_queue, import_queue, read_recording, write_recording};
+
+const COMMANDS: [&str; 9] = [
+    "sessions", "clients", "queues", "pause", "resume", "purge", "snapshot", "restore", "wait-idle",
+];
+
+const USAGE: &str = "usage: qpipe-admin [ORCHESTRATOR_ADDR] \
+    sessions|clients|queues|pause [--refuse] [QUEUE]|resume [QUEUE]|purge [QUEUE]|snapshot FILE|restore FILE|wait-idle [QUEUE]";
+
+/// The default queue's name in listings.
+fn queue_name(name: &str) -> &str {
+    if name.is_empty() { "(default)" } else { name }
+}
+
+fn run(args: &[String]) -> io::Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (addr, rest) = match args.first() {
+        Some(first) if !COMMANDS.contains(first) => (*first, &args[1..]),
+        _ => ("127.0.0.1:7000", &args[..]),
+    };
+    let usage = || io::Error::new(io::ErrorKind::InvalidInput, USAGE);
+    let mut rest = rest.to_vec();
+    let refuse = match rest.iter().position(|a| *a == "--refuse") {
+        Some(i) if rest.first() == Some(&"pause") => {
+            rest.remove(i);
+            true
+        }
+        Some(_) => return Err(usage()),
+        None => false,
+    };
+    let (command, queue) = match rest[..] {
+        [command] if !["snapshot", "restore"].contains(&command) => (command, None),
+        [command, queue] if ["pause", "resume", "purge", "wait-idle"].contains(&command) => (command, Some(queue)),
+        ["snapshot", file] => return snapshot(addr, Path::new(file)),
+        ["restore", file] => return restore(addr, Path::new(file)),
+        _ => return Err(usage()),
+    };
+    if command == "wait-idle" {
+        qpipe::wait_for_idle(addr, queue, None)?;
+        println!("{} idle", queue.map_or("all queues", queue_name));
+        return Ok(());
+    }
+
+    let mut admin = Admin::connect(addr)?;
+    match command {
+        "sessions" => {
+            println!("{:<9} {:<24} {:<16} {:>10} NAME", "ROLE", "PEER", "QUEUE", "CONNECTED");
+            for s in admin.sessions()? {
+                println!(
+                    "{:<9} {:<24} {:<16} {:>9.1}s {}",
+                    s.role, s.peer, queue_name(&s.queue), s.connected.as_secs_f64(), s.name,
+                );
+            }
+        }
+        "clients" => {
+            println!(
+                "{:<24} {:>9} {:>9} {:>12} {:>14} {:>12} {:>15}",
+                "NAME", "PRODUCERS", "CONSUMERS", "POSTED", "POSTED_BYTES", "COLLECTED", "COLLECTED_BYTES",
+            );
+            for c in admin.clients()? {
+                println!(
+                    "{:<24} {:>9} {:>9} {:>12} {:>14} {:>12} {:>15}",
+                    c.name, c.producers, c.consumers, c.posted_frames, c.posted_bytes,
+                    c.collected_frames, c.collected_bytes,
+                );
+            }
+        }
+        "queues" => {
+            println!("{:<16} {:>10} {:>12} {:>12} STATE", "QUEUE", "DEPTH", "OUTSTANDING", "OLDEST");
+            for q in admin.queues()? {
+                println!(
+                    "{:<16} {:>10} {:>12} {:>11.1}s {}",
+                    queue_name(&q.name), q.depth, q.outstanding, q.oldest_wait.as_secs_f64(),
+                    match (q.paused, q.refusing) {
+                        (_, true) => "refusing",
+                        (true, false) => "paused",
+                        (false, false) => "running",
+                    },
+                );
+            }
+        }
+        "pause" if refuse => {
+            admin.refuse(queue)?;
+            println!("intake refused for {}", queue.map_or("all queues", queue_name));
+        }
+        "pause" => {
+            admin.pause(queue)?;
+            println!("intake paused for {}", queue.map_or("all queues", queue_name));
+        }
+        "resume" => {
+            admin.resume(queue)?;
+            println!("intake resumed for {}", queue.map_or("all queues", queue_name));
+        }
+        "purge" => {
+            let purged = admin.purge(queue.unwrap_or(""))?;
+            println!("purged {purged} frames from {}", queue_name(queue.unwrap_or("")));
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+/// The key recordings are sealed with, if QPIPE_AT_REST_KEY names one.
+fn at_rest_key() -> io::Result<Option<Key>> {
+    match env::var("QPIPE_AT_REST_KEY") {
+        Ok(spec) => Key::load(&spec).map(Some).map_err(|e| io::Error::new(
+            e.kind(), format!("QPIPE_AT_REST_KEY: {e}"),
+        )),
+        Err(_) => Ok(None),
+    }
+}
+
+fn snapshot(addr: &str, path: &Path) -> io::Result<()> {
+    let key = at_rest_key()?;
+    let mut queues = std::collections::BTreeSet::new();
+    let frames = export_queue(addr, false, |frames| {
+        queues.extend(frames.iter().map(|(_, meta)| meta.queue.clone()));
+        write_recording(path, frames, key.as_ref())
+    })?;
+    println!("copied {frames} frames from {} queue(s) to {}", queues.len(), path.display());
+    Ok(())
+}
+
+fn restore(addr: &str, path: &Path) -> io::Result<()> {
+    let frames = read_recording(path, at_rest_key()?.as_ref())
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+    let n = import_queue(addr, &frames)?;
+    println!("restored {n} frames from {}", path.display());
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn")
+    ).init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("qpipe-admin: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
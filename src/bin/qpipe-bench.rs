@@ -0,0 +1,360 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+// Load generator: drives a live orchestrator with N producers and M
+// consumers for a fixed time and reports the throughput it sustained, the
+// end-to-end latency of its messages and how many never arrived.
+//
+//   qpipe-bench [ORCHESTRATOR_ADDR] [--producers 1] [--consumers 1]
+//               [--size 256] [--rate 0] [--duration 10s] [--report 1s]
+//               [--grace 5s]
+//
+// Every message carries the run it belongs to, its producer and sequence
+// number, and the time it was due to be sent, measured from the start of
+// the run on this process's monotonic clock. Producers and consumers are
+// threads of this one process, so latency is consumer receipt minus that
+// timestamp, with no clock synchronisation involved.
+//
+// `--rate` is messages per second over all producers; 0 sends as fast as
+// the orchestrator accepts them. A paced producer stamps each message with
+// its scheduled time, not the time it got round to sending it, so a stall
+// in the orchestrator shows up as latency instead of silently lowering the
+// offered load.
+//
+// When `--duration` is up the producers stop; the consumers keep going
+// until everything sent has arrived or nothing has for `--grace`. What is
+// still missing then counts as dropped (expired, purged, dead-lettered or
+// lost). Exits 1 if anything was dropped or a client failed. Run it
+// against a scratch orchestrator: it consumes whatever is in the queue, and
+// skips messages from other runs.
+
+use std::env;
+use std::io;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use qpipe::{Consumer, Delivery, Producer};
+
+const MAGIC: u8 = b'B';
+/// MAGIC, run id, producer, sequence number, due time in ns.
+const HEADER_LEN: usize = 1 + 4 + 4 + 8 + 8;
+
+struct Config {
+    addr:      String,
+    producers: u32,
+    consumers: u32,
+    size:      usize,
+    rate:      u64,
+    duration:  Duration,
+    report:    Duration,
+    grace:     Duration,
+}
+
+impl Config {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut c = Config {
+            addr:      "127.0.0.1:7000".into(),
+            producers: 1,
+            consumers: 1,
+            size:      256,
+            rate:      0,
+            duration:  Duration::from_secs(10),
+            report:    Duration::from_secs(1),
+            grace:     Duration::from_secs(5),
+        };
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            if !arg.starts_with("--") {
+                c.addr = arg.clone();
+                continue;
+            }
+            let val = it.next().ok_or_else(|| format!("{arg} needs a value"))?;
+            let num = || val.parse::<u64>().map_err(|_| format!("bad {arg} {val:?}"));
+            match arg.as_str() {
+                "--producers" => c.producers = num()?.max(1) as u32,
+                "--consumers" => c.consumers = num()?.max(1) as u32,
+                "--size"      => c.size = (num()? as usize).max(HEADER_LEN),
+                "--rate"      => c.rate = num()?,
+                "--duration"  => c.duration = parse_duration(val)?,
+                "--report"    => c.report = parse_duration(val)?.max(Duration::from_millis(10)),
+                "--grace"     => c.grace = parse_duration(val)?,
+                _ => return Err(format!("unknown option {arg}")),
+            }
+        }
+        Ok(c)
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("bad duration {s:?}"))?;
+    match unit {
+        "ms"     => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m"      => Ok(Duration::from_secs(n * 60)),
+        "h"      => Ok(Duration::from_secs(n * 3600)),
+        _ => Err(format!("bad duration unit in {s:?}")),
+    }
+}
+
+struct Shared {
+    run:        u32,
+    start:      Instant,
+    stop:       AtomicBool,
+    /// Set once the drain is over: consumers leave.
+    done:       AtomicBool,
+    sent:       AtomicU64,
+    received:   AtomicU64,
+    bytes:      AtomicU64,
+    /// When the last message of the run arrived, in ns.
+    last:       AtomicU64,
+    foreign:    AtomicU64,
+    errors:     AtomicU64,
+    /// Latencies in ns, one entry per message received: 8 bytes a message,
+    /// kept whole so the percentiles are exact.
+    latencies:  Mutex<Vec<u64>>,
+}
+
+impl Shared {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
+
+fn encode(run: u32, producer: u32, seq: u64, due: u64, size: usize) -> Vec<u8> {
+    let mut m = Vec::with_capacity(size);
+    m.push(MAGIC);
+    m.extend_from_slice(&run.to_be_bytes());
+    m.extend_from_slice(&producer.to_be_bytes());
+    m.extend_from_slice(&seq.to_be_bytes());
+    m.extend_from_slice(&due.to_be_bytes());
+    m.resize(size, 0);
+    m
+}
+
+/// (run id, due time) of a benchmark message.
+fn decode(m: &[u8]) -> Option<(u32, u64)> {
+    if m.len() < HEADER_LEN || m[0] != MAGIC {
+        return None;
+    }
+    let run = u32::from_be_bytes(m[1..5].try_into().ok()?);
+    let due = u64::from_be_bytes(m[17..25].try_into().ok()?);
+    Some((run, due))
+}
+
+fn producer_loop(mut p: Producer, cfg: Arc<Config>, sh: Arc<Shared>, id: u32) {
+    // Each producer offers its share of the rate, the first ones staggered
+    // so the sends don't all land at once.
+    let interval = (cfg.rate > 0).then(|| 1e9 * cfg.producers as f64 / cfg.rate as f64);
+    let mut due = interval.map_or(0.0, |i| i * id as f64 / cfg.producers as f64);
+    let mut seq = 0u64;
+    while !sh.stop.load(Ordering::Relaxed) {
+        let stamp = match interval {
+            Some(i) => {
+                let wait = (due as u64).saturating_sub(sh.now());
+                if wait > 0 {
+                    thread::sleep(Duration::from_nanos(wait));
+                }
+                due += i;
+                (due - i) as u64
+            }
+            None => sh.now(),
+        };
+        if let Err(e) = p.send(&encode(sh.run, id, seq, stamp, cfg.size)) {
+            warn!("producer {id}: send failed: {e}");
+            sh.errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        sh.sent.fetch_add(1, Ordering::Relaxed);
+        seq += 1;
+    }
+}
+
+fn consumer_loop(mut c: Consumer, sh: Arc<Shared>, id: u32) {
+    let mut latencies = Vec::new();
+    while !sh.done.load(Ordering::Relaxed) {
+        // On an at-least-once queue each message is acked once counted.
+        let (m, tag) = match c.recv_ext_timeout(Duration::from_millis(100)) {
+            Ok(Some(Delivery::Message(m))) => (m.payload, m.tag),
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("consumer {id}: recv failed: {e}");
+                sh.errors.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+        };
+        let now = sh.now();
+        match decode(&m) {
+            Some((run, due)) if run == sh.run => {
+                latencies.push(now.saturating_sub(due));
+                sh.last.fetch_max(now, Ordering::Relaxed);
+                sh.received.fetch_add(1, Ordering::Relaxed);
+                sh.bytes.fetch_add(m.len() as u64, Ordering::Relaxed);
+            }
+            _ => {
+                sh.foreign.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let Some(tag) = tag
+            && let Err(e) = c.ack(tag)
+        {
+            warn!("consumer {id}: ack failed: {e}");
+            sh.errors.fetch_add(1, Ordering::Relaxed);
+            break;
+        }
+    }
+    sh.latencies.lock().unwrap().append(&mut latencies);
+}
+
+fn report(sh: &Shared, last: &mut (u64, Instant)) {
+    let received = sh.received.load(Ordering::Relaxed);
+    let now = Instant::now();
+    let rate = (received - last.0) as f64 / now.duration_since(last.1).as_secs_f64().max(1e-3);
+    *last = (received, now);
+    println!(
+        "[bench {:6.1}s] sent={} received={} rate={:.0} msg/s errors={}",
+        sh.start.elapsed().as_secs_f64(), sh.sent.load(Ordering::Relaxed), received, rate,
+        sh.errors.load(Ordering::Relaxed),
+    );
+}
+
+/// The latency below which `p` percent of `sorted` fall, in ms.
+fn percentile(sorted: &[u64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((p / 100.0 * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1] as f64 / 1e6
+}
+
+fn run(cfg: Config) -> io::Result<bool> {
+    qpipe::wait_until_healthy(&cfg.addr, Some(Duration::from_secs(10)))?;
+    // Every client is connected before the clock starts, so session setup
+    // doesn't count as latency.
+    let consumers = (0..cfg.consumers)
+        .map(|_| Consumer::connect(&cfg.addr))
+        .collect::<io::Result<Vec<_>>>()?;
+    let producers = (0..cfg.producers)
+        .map(|_| Producer::connect(&cfg.addr))
+        .collect::<io::Result<Vec<_>>>()?;
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+    let sh = Arc::new(Shared {
+        run:       std::process::id().rotate_left(16) ^ nanos,
+        start:     Instant::now(),
+        stop:      AtomicBool::new(false),
+        done:      AtomicBool::new(false),
+        sent:      AtomicU64::new(0),
+        received:  AtomicU64::new(0),
+        bytes:     AtomicU64::new(0),
+        last:      AtomicU64::new(0),
+        foreign:   AtomicU64::new(0),
+        errors:    AtomicU64::new(0),
+        latencies: Mutex::new(Vec::new()),
+    });
+    let cfg = Arc::new(cfg);
+
+    let consumers: Vec<_> = consumers.into_iter().zip(0..)
+        .map(|(c, id)| {
+            let sh = sh.clone();
+            thread::spawn(move || consumer_loop(c, sh, id))
+        })
+        .collect();
+    let producers: Vec<_> = producers.into_iter().zip(0..)
+        .map(|(p, id)| {
+            let (cfg, sh) = (cfg.clone(), sh.clone());
+            thread::spawn(move || producer_loop(p, cfg, sh, id))
+        })
+        .collect();
+
+    let mut last = (0, Instant::now());
+    let mut next = sh.start + cfg.report;
+    let end = sh.start + cfg.duration;
+    while Instant::now() < end {
+        thread::sleep(next.min(end).saturating_duration_since(Instant::now()));
+        if Instant::now() >= next {
+            report(&sh, &mut last);
+            next += cfg.report;
+        }
+    }
+    sh.stop.store(true, Ordering::Relaxed);
+    for p in producers {
+        let _ = p.join();
+    }
+
+    // Drain: wait for the stragglers while they keep coming.
+    let mut seen = (sh.received.load(Ordering::Relaxed), Instant::now());
+    while sh.received.load(Ordering::Relaxed) < sh.sent.load(Ordering::Relaxed)
+        && seen.1.elapsed() < cfg.grace
+        && consumers.iter().any(|c| !c.is_finished())
+    {
+        thread::sleep(Duration::from_millis(10));
+        let received = sh.received.load(Ordering::Relaxed);
+        if received != seen.0 {
+            seen = (received, Instant::now());
+        }
+    }
+    sh.done.store(true, Ordering::Relaxed);
+    for c in consumers {
+        let _ = c.join();
+    }
+    report(&sh, &mut last);
+
+    let sent = sh.sent.load(Ordering::Relaxed);
+    let received = sh.received.load(Ordering::Relaxed);
+    let dropped = sent.saturating_sub(received);
+    let errors = sh.errors.load(Ordering::Relaxed);
+    // Throughput over the time it took to get everything across.
+    let secs = (sh.last.load(Ordering::Relaxed) as f64 / 1e9).max(1e-3);
+    let mut latencies = std::mem::take(&mut *sh.latencies.lock().unwrap());
+    latencies.sort_unstable();
+
+    println!(
+        "sent {sent}, received {received}, dropped {dropped} ({:.2}%)",
+        100.0 * dropped as f64 / sent.max(1) as f64,
+    );
+    println!(
+        "throughput {:.0} msg/s, {:.2} MB/s ({} producers, {} consumers, {} B messages)",
+        received as f64 / secs, sh.bytes.load(Ordering::Relaxed) as f64 / secs / 1e6,
+        cfg.producers, cfg.consumers, cfg.size,
+    );
+    println!(
+        "latency p50 {:.3} ms, p99 {:.3} ms, max {:.3} ms",
+        percentile(&latencies, 50.0), percentile(&latencies, 99.0), percentile(&latencies, 100.0),
+    );
+    let foreign = sh.foreign.load(Ordering::Relaxed);
+    if foreign > 0 {
+        println!("skipped {foreign} message(s) from other runs");
+    }
+    if errors > 0 {
+        println!("{errors} client error(s) during the run");
+    }
+    Ok(dropped == 0 && errors == 0)
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn")
+    ).init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cfg = match Config::parse(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("qpipe-bench: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(cfg) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("qpipe-bench: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,278 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+// Protocol conformance and robustness suite: plays adversarial clients
+// against a live orchestrator and reports pass/fail per case.
+//
+//   qpipe-conformance [ORCHESTRATOR_ADDR]
+//
+// Each case misbehaves in one way — malformed role bytes and option blocks,
+// wrong or truncated tokens, oversized or contradictory frame headers,
+// slow-loris handshakes, abrupt resets — and passes when the orchestrator
+// refuses the bad input without replying to it AND still answers a
+// healthcheck afterwards. Exits 0 when every case passed, 1 otherwise.
+//
+// Run it against a scratch orchestrator. No case enqueues a message, but
+// the slow-loris cases hold connections (and so orchestrator threads) open
+// for a few seconds.
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use qpipe::{
+    healthcheck, FRAME_FLAG_CHUNK, FRAME_FLAG_CTRL, FRAME_FLAG_META, MAX_FRAME_SIZE,
+    ROLE_CONSUMER, ROLE_FLAG_OPTS, ROLE_HEALTHCHECK, ROLE_PRODUCER, TOKEN_LEN,
+};
+
+/// How long the orchestrator gets to hang up on bad input.
+const CLOSE_WITHIN: Duration = Duration::from_secs(3);
+
+type Outcome = Result<(), String>;
+
+struct Case {
+    name: &'static str,
+    run:  fn(SocketAddr) -> Outcome,
+}
+
+const CASES: &[Case] = &[
+    Case { name: "unknown-role-byte", run: unknown_role_byte },
+    Case { name: "unknown-role-with-options", run: unknown_role_with_options },
+    Case { name: "truncated-options-block", run: truncated_options_block },
+    Case { name: "malformed-option-tlv", run: malformed_option_tlv },
+    Case { name: "wrong-token", run: wrong_token },
+    Case { name: "truncated-token", run: truncated_token },
+    Case { name: "oversized-length-prefix", run: oversized_length_prefix },
+    Case { name: "chunk-and-ctrl-flags", run: chunk_and_ctrl_flags },
+    Case { name: "metadata-overruns-frame", run: metadata_overruns_frame },
+    Case { name: "control-frame-without-kind", run: control_frame_without_kind },
+    Case { name: "reset-mid-frame", run: reset_mid_frame },
+    Case { name: "slow-loris-handshakes", run: slow_loris_handshakes },
+    Case { name: "slow-loris-frame", run: slow_loris_frame },
+];
+
+fn err(context: &str) -> impl Fn(io::Error) -> String + '_ {
+    move |e| format!("{context}: {e}")
+}
+
+fn connect(addr: SocketAddr) -> Result<TcpStream, String> {
+    let s = TcpStream::connect_timeout(&addr, Duration::from_secs(5))
+        .map_err(err("connect"))?;
+    s.set_nodelay(true).ok();
+    Ok(s)
+}
+
+/// Pass iff the peer closes `s` within CLOSE_WITHIN without sending anything.
+fn expect_closed(s: &mut TcpStream) -> Outcome {
+    s.set_read_timeout(Some(CLOSE_WITHIN)).ok();
+    let mut b = [0u8; 64];
+    match s.read(&mut b) {
+        Ok(0) => Ok(()),
+        Ok(n) => Err(format!("orchestrator replied with {n} byte(s) instead of closing")),
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            Err(format!("connection still open after {CLOSE_WITHIN:?}"))
+        }
+        Err(_) => Ok(()), // reset: closed, just less politely
+    }
+}
+
+/// Pass iff nothing arrives on `s` for `quiet`; EOF also counts as silence.
+fn expect_silence(s: &mut TcpStream, quiet: Duration) -> Outcome {
+    s.set_read_timeout(Some(quiet)).ok();
+    let mut b = [0u8; 1];
+    match s.read(&mut b) {
+        Ok(n) if n > 0 => Err(format!("unexpected reply byte 0x{:02x}", b[0])),
+        _ => Ok(()),
+    }
+}
+
+/// Control-port half of a producer/consumer handshake: the data port's
+/// address and the token. The caller decides what to do with them.
+fn open_session(addr: SocketAddr, role: u8) -> Result<(SocketAddr, [u8; TOKEN_LEN]), String> {
+    let mut ctrl = connect(addr)?;
+    ctrl.write_all(&[role]).map_err(err("send role"))?;
+    ctrl.set_read_timeout(Some(Duration::from_secs(5))).ok();
+    let mut port = [0u8; 2];
+    ctrl.read_exact(&mut port).map_err(err("read data port"))?;
+    let mut token = [0u8; TOKEN_LEN];
+    ctrl.read_exact(&mut token).map_err(err("read token"))?;
+    Ok((SocketAddr::new(addr.ip(), u16::from_be_bytes(port)), token))
+}
+
+/// A fully authenticated producer data connection.
+fn producer_session(addr: SocketAddr) -> Result<TcpStream, String> {
+    let (data, token) = open_session(addr, ROLE_PRODUCER)?;
+    let mut s = connect(data)?;
+    s.write_all(&token).map_err(err("send token"))?;
+    Ok(s)
+}
+
+/// Send a frame header (and `body`) on a fresh producer session; the
+/// orchestrator must hang up without ACKing.
+fn bad_frame(addr: SocketAddr, prefix: u32, body: &[u8]) -> Outcome {
+    let mut s = producer_session(addr)?;
+    s.write_all(&prefix.to_be_bytes()).map_err(err("send prefix"))?;
+    s.write_all(body).map_err(err("send body"))?;
+    expect_closed(&mut s)
+}
+
+fn unknown_role_byte(addr: SocketAddr) -> Outcome {
+    let mut s = connect(addr)?;
+    s.write_all(&[0x7f]).map_err(err("send role"))?;
+    expect_closed(&mut s)
+}
+
+fn unknown_role_with_options(addr: SocketAddr) -> Outcome {
+    let mut s = connect(addr)?;
+    s.write_all(&[b'Z' | ROLE_FLAG_OPTS, 0, 0]).map_err(err("send role"))?;
+    expect_closed(&mut s)
+}
+
+fn truncated_options_block(addr: SocketAddr) -> Outcome {
+    let mut s = connect(addr)?;
+    // Announces 100 bytes of options, delivers 3, then hangs up.
+    s.write_all(&[ROLE_PRODUCER | ROLE_FLAG_OPTS, 0, 100, 1, 0, 0])
+        .map_err(err("send options"))?;
+    s.shutdown(Shutdown::Write).ok();
+    expect_closed(&mut s)
+}
+
+fn malformed_option_tlv(addr: SocketAddr) -> Outcome {
+    let mut s = connect(addr)?;
+    // A 3-byte block whose only entry claims 200 bytes of value.
+    s.write_all(&[ROLE_CONSUMER | ROLE_FLAG_OPTS, 0, 3, 1, 0, 200])
+        .map_err(err("send options"))?;
+    expect_closed(&mut s)
+}
+
+fn wrong_token(addr: SocketAddr) -> Outcome {
+    let (data, token) = open_session(addr, ROLE_PRODUCER)?;
+    let mut s = connect(data)?;
+    let mut bad = token;
+    bad[0] ^= 0xff;
+    s.write_all(&bad).map_err(err("send token"))?;
+    // An unauthenticated connection must not get frame ACKs.
+    s.write_all(&[0, 0, 0, 1, b'x']).map_err(err("send frame"))?;
+    expect_silence(&mut s, Duration::from_secs(1))?;
+    // ... while the session itself is still there for the real client.
+    let mut ok = connect(data)?;
+    ok.write_all(&token).map_err(err("send token"))
+}
+
+fn truncated_token(addr: SocketAddr) -> Outcome {
+    let (data, token) = open_session(addr, ROLE_PRODUCER)?;
+    let mut s = connect(data)?;
+    s.write_all(&token[..TOKEN_LEN / 2]).map_err(err("send token"))?;
+    drop(s);
+    let mut ok = connect(data)?;
+    ok.write_all(&token).map_err(err("send token after a truncated one"))
+}
+
+fn oversized_length_prefix(addr: SocketAddr) -> Outcome {
+    bad_frame(addr, MAX_FRAME_SIZE as u32 + 1, &[])
+}
+
+fn chunk_and_ctrl_flags(addr: SocketAddr) -> Outcome {
+    bad_frame(addr, FRAME_FLAG_CHUNK | FRAME_FLAG_CTRL | 1, b"x")
+}
+
+fn metadata_overruns_frame(addr: SocketAddr) -> Outcome {
+    // 4-byte body whose metadata block claims 1000 bytes.
+    bad_frame(addr, FRAME_FLAG_META | 4, &[0x03, 0xe8, 0, 0])
+}
+
+fn control_frame_without_kind(addr: SocketAddr) -> Outcome {
+    bad_frame(addr, FRAME_FLAG_CTRL, &[])
+}
+
+fn reset_mid_frame(addr: SocketAddr) -> Outcome {
+    let mut s = producer_session(addr)?;
+    s.write_all(&[0, 0, 0, 100]).map_err(err("send prefix"))?;
+    s.write_all(&[0u8; 10]).map_err(err("send partial body"))?;
+    s.shutdown(Shutdown::Both).ok();
+    // The orchestrator must still run complete handshakes.
+    producer_session(addr).map(drop)
+}
+
+fn slow_loris_handshakes(addr: SocketAddr) -> Outcome {
+    // Connections that never finish their role byte or options block.
+    let mut stalled = Vec::new();
+    for i in 0..32 {
+        let mut s = connect(addr)?;
+        if i % 2 == 1 {
+            s.write_all(&[ROLE_CONSUMER | ROLE_FLAG_OPTS, 0]).map_err(err("stall"))?;
+        }
+        stalled.push(s);
+    }
+    let start = Instant::now();
+    healthcheck_raw(addr)?;
+    let took = start.elapsed();
+    if took > Duration::from_secs(2) {
+        return Err(format!("healthcheck took {took:?} next to stalled handshakes"));
+    }
+    Ok(())
+}
+
+fn slow_loris_frame(addr: SocketAddr) -> Outcome {
+    let mut s = producer_session(addr)?;
+    let trickle = thread::spawn(move || {
+        // One byte of a 4-byte prefix every 500ms, never finishing the frame.
+        for b in [0u8, 0, 0] {
+            if s.write_all(&[b]).is_err() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+    });
+    thread::sleep(Duration::from_millis(200));
+    let res = healthcheck_raw(addr);
+    let _ = trickle.join();
+    res
+}
+
+/// One healthcheck with the suite's own deadline.
+fn healthcheck_raw(addr: SocketAddr) -> Outcome {
+    let mut s = connect(addr)?;
+    s.set_read_timeout(Some(Duration::from_secs(2))).ok();
+    s.write_all(&[ROLE_HEALTHCHECK]).map_err(err("healthcheck"))?;
+    let mut b = [0u8; 1];
+    s.read_exact(&mut b).map_err(err("healthcheck"))
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn")
+    ).init();
+
+    let target = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:7000".to_string());
+    let addr = match target.to_socket_addrs().map(|mut a| a.next()) {
+        Ok(Some(a)) => a,
+        _ => {
+            eprintln!("qpipe-conformance: cannot resolve {target}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = healthcheck(&target) {
+        eprintln!("qpipe-conformance: {target} is not healthy to begin with: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    let mut failed = 0;
+    for case in CASES {
+        // Every case must leave the orchestrator serving.
+        let res = (case.run)(addr).and_then(|()| {
+            healthcheck(&target).map_err(|e| format!("unhealthy afterwards: {e}"))
+        });
+        match res {
+            Ok(()) => println!("PASS  {}", case.name),
+            Err(why) => {
+                failed += 1;
+                println!("FAIL  {}: {}", case.name, why);
+            }
+        }
+    }
+    println!("{} passed, {} failed", CASES.len() - failed, failed);
+    if failed == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
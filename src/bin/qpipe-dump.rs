@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+// Queue export: writes what an orchestrator has queued to a recording, to
+// move a backlog to another facility (`qpipe-load` publishes it again) or
+// to archive it before decommissioning an orchestrator. `--import` puts a
+// recording back, e.g. after maintenance.
+//
+//   qpipe-dump [ORCHESTRATOR_ADDR] OUTPUT [--copy]
+//   qpipe-dump [ORCHESTRATOR_ADDR] --import INPUT
+//
+// By default the queues are drained: the frames leave the orchestrator, but
+// only after OUTPUT is complete and synced to disk — until then they stay
+// held, and any failure puts them back. `--copy` takes a snapshot and
+// leaves the queues alone.
+//
+// OUTPUT is a recording: frames in wire format without ACKs, each with its
+// metadata (priority, capability tags, resource hints, attempt count, the
+// queue it was in), as `qpipe::get_frame` reads them. Every queue but the
+// reply queues is exported. With QPIPE_AT_REST_KEY set it is written
+// as sealed records. Frames already handed to consumers are not exported.
+//
+// An import queues the recording's frames in order, all at once, each
+// behind whatever its queue holds (`qpipe::import_queue`).
+
+use std::env;
+use std::io;
+use std::path::Path;
+use std::process::ExitCode;
+
+use qpipe::at_rest::Key;
+use qpipe::{export_queue, import_queue, read_recording, write_recording};
+
+fn run(args: &[String]) -> io::Result<()> {
+    let copy = args.iter().any(|a| a == "--copy");
+    let import = args.iter().any(|a| a == "--import");
+    let positional: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+    let (addr, file) = match positional.as_slice() {
+        [file] => ("127.0.0.1:7000", file.as_str()),
+        [addr, file] => (addr.as_str(), file.as_str()),
+        _ => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: qpipe-dump [ORCHESTRATOR_ADDR] OUTPUT [--copy] | [ORCHESTRATOR_ADDR] --import INPUT",
+        )),
+    };
+    if let Some(bad) = args.iter().find(|a| a.starts_with("--") && *a != "--copy" && *a != "--import") {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown option {bad}")));
+    }
+    if copy && import {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--copy and --import don't go together"));
+    }
+    let key = match env::var("QPIPE_AT_REST_KEY") {
+        Ok(spec) => Some(Key::load(&spec).map_err(|e| io::Error::new(
+            e.kind(), format!("QPIPE_AT_REST_KEY: {e}"),
+        ))?),
+        Err(_) => None,
+    };
+
+    let path = Path::new(file);
+    if import {
+        let frames = read_recording(path, key.as_ref())
+            .map_err(|e| io::Error::new(e.kind(), format!("{file}: {e}")))?;
+        let bytes = frames.iter().map(|(f, _)| f.payload_len()).sum::<usize>();
+        let n = import_queue(addr, &frames)?;
+        println!("imported {n} frames ({bytes} bytes) from {file}");
+        return Ok(());
+    }
+    let mut bytes = 0;
+    let frames = export_queue(addr, !copy, |frames| {
+        bytes = frames.iter().map(|(f, _)| f.payload_len()).sum::<usize>();
+        write_recording(path, frames, key.as_ref())
+    })?;
+    println!(
+        "{} {frames} frames ({bytes} bytes) to {file}",
+        if copy { "copied" } else { "drained" },
+    );
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn")
+    ).init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("qpipe-dump: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
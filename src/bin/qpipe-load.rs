@@ -0,0 +1,424 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+// Bulk backfill: publishes everything in a recording, a directory or stdin
+// to a queue over several producer connections, reporting progress and
+// checkpointing so an interrupted load resumes where it stopped.
+//
+//   qpipe-load [ORCHESTRATOR_ADDR] SOURCE [--concurrency 4]
+//              [--checkpoint PATH] [--progress 5s]
+//
+// SOURCE is one of:
+//   a file      a recording in wire format without ACKs (`qpipe::get_frame`
+//               reads it; dead-letter files are recordings). Frame metadata
+//               is kept, chunked messages are reassembled, EOS markers are
+//               forwarded once everything before them is in. With
+//               QPIPE_AT_REST_KEY set the file is read as sealed records.
+//   a directory every regular file in it becomes one message, in name order
+//   `-`         stdin as length-prefixed messages: [u32 BE len][bytes]
+//
+// The checkpoint records how many leading items of SOURCE are known to be
+// accepted by the orchestrator. It is rewritten every progress interval and
+// on exit; a rerun with the same checkpoint skips that prefix. Items that
+// were in flight when the load stopped are sent again (at-least-once).
+
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use qpipe::at_rest::{Key, SealedReader};
+use qpipe::{get_frame, Frame, Meta, Producer, Reassembler, MAX_MESSAGE_SIZE};
+
+struct Config {
+    addr:        String,
+    source:      String,
+    concurrency: usize,
+    checkpoint:  Option<PathBuf>,
+    progress:    Duration,
+}
+
+impl Config {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut positional = Vec::new();
+        let mut concurrency = 4;
+        let mut checkpoint = None;
+        let mut progress = Duration::from_secs(5);
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            if !arg.starts_with("--") {
+                positional.push(arg.clone());
+                continue;
+            }
+            let val = it.next().ok_or_else(|| format!("{arg} needs a value"))?;
+            match arg.as_str() {
+                "--concurrency" => {
+                    concurrency = val.parse::<usize>()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| format!("bad --concurrency {val:?}"))?;
+                }
+                "--checkpoint" => checkpoint = Some(PathBuf::from(val)),
+                "--progress"   => progress = parse_duration(val)?,
+                _ => return Err(format!("unknown option {arg}")),
+            }
+        }
+        let (addr, source) = match positional.as_slice() {
+            [source] => ("127.0.0.1:7000".to_string(), source.clone()),
+            [addr, source] => (addr.clone(), source.clone()),
+            _ => return Err("expected [ORCHESTRATOR_ADDR] SOURCE".into()),
+        };
+        Ok(Self { addr, source, concurrency, checkpoint, progress })
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("bad duration {s:?}"))?;
+    match unit {
+        "ms"     => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m"      => Ok(Duration::from_secs(n * 60)),
+        _ => Err(format!("bad duration unit in {s:?}")),
+    }
+}
+
+/// One unit of the load: a message, or an EOS marker to forward.
+enum Item {
+    Msg(Vec<u8>, Box<Meta>),
+    Eos(Vec<u8>),
+}
+
+/// Where items come from, in a stable order so checkpoints mean something.
+enum Source {
+    Recording {
+        frames:   Box<dyn Read + Send>,
+        partials: Reassembler,
+        metas:    HashMap<u128, Meta>,
+    },
+    Dir(std::vec::IntoIter<PathBuf>),
+    Stdin(io::Stdin),
+}
+
+impl Source {
+    fn open(spec: &str) -> io::Result<Self> {
+        if spec == "-" {
+            return Ok(Source::Stdin(io::stdin()));
+        }
+        let path = Path::new(spec);
+        if path.is_dir() {
+            let mut files: Vec<PathBuf> = fs::read_dir(path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<io::Result<_>>()?;
+            files.retain(|p| p.is_file());
+            files.sort();
+            return Ok(Source::Dir(files.into_iter()));
+        }
+        let file = BufReader::new(File::open(path)?);
+        let frames: Box<dyn Read + Send> = match env::var("QPIPE_AT_REST_KEY") {
+            Ok(spec) => {
+                let key = Key::load(&spec).map_err(|e| io::Error::new(
+                    e.kind(), format!("QPIPE_AT_REST_KEY: {e}"),
+                ))?;
+                Box::new(SealedReader::new(file, key))
+            }
+            Err(_) => Box::new(file),
+        };
+        Ok(Source::Recording { frames, partials: Reassembler::new(), metas: HashMap::new() })
+    }
+
+    fn next_item(&mut self) -> io::Result<Option<Item>> {
+        match self {
+            Source::Recording { frames, partials, metas } => loop {
+                let Some((frame, mut meta)) = get_frame(frames)? else {
+                    if partials.pending().0 > 0 {
+                        warn!("recording ends inside a chunked message; skipping it");
+                    }
+                    return Ok(None);
+                };
+                // Delivery tags and attempt counts belong to the old queue.
+                meta.delivery = None;
+                meta.attempt = None;
+                match frame {
+                    Frame::Msg(p) => return Ok(Some(Item::Msg(p, Box::new(meta)))),
+                    Frame::Eos(group) => return Ok(Some(Item::Eos(group))),
+                    Frame::Chunk { id, idx, count, payload } => {
+                        metas.entry(id).or_insert(meta);
+                        if let Some(msg) = partials.absorb(id, idx, count, payload)? {
+                            let meta = metas.remove(&id).unwrap_or_default();
+                            return Ok(Some(Item::Msg(msg, Box::new(meta))));
+                        }
+                    }
+                    _ => {} // pings, goodbyes: not a recorded message
+                }
+            },
+            Source::Dir(files) => match files.next() {
+                Some(path) => Ok(Some(Item::Msg(fs::read(path)?, Box::default()))),
+                None => Ok(None),
+            },
+            Source::Stdin(stdin) => {
+                let mut stdin = stdin.lock();
+                let mut len = [0u8; 4];
+                match stdin.read_exact(&mut len) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+                let len = u32::from_be_bytes(len) as usize;
+                if len > MAX_MESSAGE_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("length prefix {len} exceeds MAX_MESSAGE_SIZE"),
+                    ));
+                }
+                let mut msg = vec![0u8; len];
+                stdin.read_exact(&mut msg)?;
+                Ok(Some(Item::Msg(msg, Box::default())))
+            }
+        }
+    }
+}
+
+/// Which items are in: everything below `watermark`, plus `ahead` (done
+/// out of order by concurrent workers).
+#[derive(Default)]
+struct Progress {
+    watermark: u64,
+    ahead:     BTreeSet<u64>,
+    messages:  u64,
+    bytes:     u64,
+}
+
+struct Shared {
+    progress: Mutex<Progress>,
+    advanced: Condvar,
+    failed:   AtomicBool,
+    error:    Mutex<Option<io::Error>>,
+}
+
+impl Shared {
+    fn done(&self, index: u64, bytes: usize) {
+        let mut p = self.progress.lock().unwrap();
+        p.messages += 1;
+        p.bytes += bytes as u64;
+        p.ahead.insert(index);
+        while p.ahead.first() == Some(&p.watermark) {
+            p.ahead.pop_first();
+            p.watermark += 1;
+        }
+        self.advanced.notify_all();
+    }
+
+    fn fail(&self, e: io::Error) {
+        let mut slot = self.error.lock().unwrap();
+        if slot.is_none() {
+            *slot = Some(e);
+        }
+        self.failed.store(true, Ordering::SeqCst);
+        self.advanced.notify_all();
+    }
+
+    /// Block until every item before `index` is in (or something failed).
+    fn wait_for(&self, index: u64) {
+        let mut p = self.progress.lock().unwrap();
+        while p.watermark < index && !self.failed.load(Ordering::SeqCst) {
+            p = self.advanced.wait(p).unwrap();
+        }
+    }
+}
+
+/// Checkpoint file: the source it belongs to, then the item count.
+fn read_checkpoint(path: &Path, source: &str) -> io::Result<u64> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let bad = |msg: String| io::Error::new(
+        io::ErrorKind::InvalidData, format!("checkpoint {}: {msg}", path.display()),
+    );
+    let mut lines = text.lines();
+    match (lines.next(), lines.next().map(str::parse::<u64>)) {
+        (Some(s), Some(Ok(n))) if s == source => Ok(n),
+        (Some(s), Some(Ok(_))) => Err(bad(format!("belongs to {s:?}, not {source:?}"))),
+        _ => Err(bad("malformed".into())),
+    }
+}
+
+/// Replace the checkpoint atomically, so a crash mid-write can't lose it.
+fn write_checkpoint(path: &Path, source: &str, count: u64) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format!("{source}\n{count}\n"))?;
+    fs::rename(&tmp, path)
+}
+
+/// A message to publish and its position in the source.
+type Job = (u64, Vec<u8>, Meta);
+
+fn worker(addr: String, jobs: Arc<Mutex<mpsc::Receiver<Job>>>, sh: Arc<Shared>) {
+    let mut producer = match Producer::connect(&addr) {
+        Ok(p) => p,
+        Err(e) => return sh.fail(e),
+    };
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        let Ok((index, payload, meta)) = job else { return };
+        if let Err(e) = producer.send_with_meta(&payload, &meta) {
+            return sh.fail(e);
+        }
+        sh.done(index, payload.len());
+    }
+}
+
+fn run(cfg: &Config) -> io::Result<()> {
+    let resume = match &cfg.checkpoint {
+        Some(path) => read_checkpoint(path, &cfg.source)?,
+        None => 0,
+    };
+    let mut source = Source::open(&cfg.source)?;
+    for skipped in 0..resume {
+        if source.next_item()?.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("checkpoint says {resume} items are in, source has only {skipped}"),
+            ));
+        }
+    }
+    if resume > 0 {
+        info!("resuming after {} items", resume);
+    }
+
+    let sh = Arc::new(Shared {
+        progress: Mutex::new(Progress { watermark: resume, ..Progress::default() }),
+        advanced: Condvar::new(),
+        failed:   AtomicBool::new(false),
+        error:    Mutex::new(None),
+    });
+    let (tx, rx) = mpsc::sync_channel(cfg.concurrency * 2);
+    let rx = Arc::new(Mutex::new(rx));
+    let workers: Vec<_> = (0..cfg.concurrency)
+        .map(|_| {
+            let (addr, rx, sh) = (cfg.addr.clone(), rx.clone(), sh.clone());
+            thread::spawn(move || worker(addr, rx, sh))
+        })
+        .collect();
+
+    // Progress lines and checkpoints from a side thread.
+    let stop = Arc::new(AtomicBool::new(false));
+    let reporter = {
+        let (sh, stop) = (sh.clone(), stop.clone());
+        let (every, checkpoint, source) = (cfg.progress, cfg.checkpoint.clone(), cfg.source.clone());
+        thread::spawn(move || {
+            let start = Instant::now();
+            let mut next = start + every;
+            while !stop.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(50).min(every));
+                if Instant::now() < next {
+                    continue;
+                }
+                next += every;
+                let (watermark, messages, bytes) = {
+                    let p = sh.progress.lock().unwrap();
+                    (p.watermark, p.messages, p.bytes)
+                };
+                let rate = messages as f64 / start.elapsed().as_secs_f64().max(1e-3);
+                eprintln!("qpipe-load: {messages} messages, {bytes} bytes, {rate:.0} msg/s");
+                if let Some(path) = &checkpoint
+                    && let Err(e) = write_checkpoint(path, &source, watermark)
+                {
+                    warn!("cannot write checkpoint: {}", e);
+                }
+            }
+        })
+    };
+
+    let mut eos_producer: Option<Producer> = None;
+    let mut index = resume;
+    let read_result = loop {
+        if sh.failed.load(Ordering::SeqCst) {
+            break Ok(());
+        }
+        match source.next_item() {
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+            Ok(Some(Item::Msg(payload, meta))) => {
+                if tx.send((index, payload, *meta)).is_err() {
+                    break Ok(()); // every worker is gone; they recorded why
+                }
+            }
+            Ok(Some(Item::Eos(group))) => {
+                // Consumers must see everything before the marker first.
+                sh.wait_for(index);
+                if sh.failed.load(Ordering::SeqCst) {
+                    break Ok(());
+                }
+                let res = match &mut eos_producer {
+                    Some(p) => p.send_eos(&group),
+                    None => Producer::connect(&cfg.addr).and_then(|mut p| {
+                        p.send_eos(&group)?;
+                        eos_producer = Some(p);
+                        Ok(())
+                    }),
+                };
+                match res {
+                    Ok(()) => sh.done(index, 0),
+                    Err(e) => sh.fail(e),
+                }
+            }
+        }
+        index += 1;
+    };
+    drop(tx);
+    for w in workers {
+        let _ = w.join();
+    }
+    stop.store(true, Ordering::SeqCst);
+    let _ = reporter.join();
+
+    let (watermark, messages, bytes) = {
+        let p = sh.progress.lock().unwrap();
+        (p.watermark, p.messages, p.bytes)
+    };
+    if let Some(path) = &cfg.checkpoint {
+        write_checkpoint(path, &cfg.source, watermark)?;
+    }
+    let err = sh.error.lock().unwrap().take();
+    if let Some(e) = read_result.err().or(err) {
+        return Err(io::Error::new(
+            e.kind(),
+            format!("{e} (stopped after {watermark} items; rerun with the checkpoint to resume)"),
+        ));
+    }
+    println!("loaded {messages} messages ({bytes} bytes) from {}", cfg.source);
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn")
+    ).init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cfg = match Config::parse(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("qpipe-load: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(&cfg) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("qpipe-load: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,388 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+// Long-running soak test: churns producers and consumers against a live
+// orchestrator for as long as asked, checking that nothing is lost,
+// duplicated or corrupted, and watching the orchestrator process for fd and
+// memory leaks.
+//
+//   qpipe-soak [ORCHESTRATOR_ADDR] [--duration 1h] [--producers 4]
+//              [--consumers 4] [--session 200] [--size 256] [--report 10s]
+//              [--pid PID]
+//
+// Each producer thread connects, sends `--session` messages, disconnects,
+// and starts over; each consumer thread likewise receives `--session`
+// messages per connection. Every message carries its producer and sequence
+// number plus a payload derived from them, so consumers can verify it.
+//
+// Invariants, printed every `--report` interval:
+//   received <= sent, and backlog = sent - received stays bounded
+//   (client-side view of posted == collected + dropped + queued)
+// and checked at the end, once producers stop and the queue goes idle:
+//   every producer's messages arrived exactly once (count and seq sum),
+//   and with --pid, the orchestrator's fd count is back near its baseline.
+// Exits 1 if any check fails. Run it against a scratch orchestrator.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use qpipe::{Consumer, Delivery, Producer};
+
+const DATA: u8 = b'D';
+/// Tells one consumer to leave; used to wind the run down.
+const STOP: u8 = b'S';
+const HEADER_LEN: usize = 1 + 4 + 8;
+
+/// fds the orchestrator may keep above its baseline at the end without the
+/// run counting as a leak (log files, lazily opened sockets, ...).
+const FD_SLACK: usize = 16;
+
+struct Config {
+    addr:      String,
+    duration:  Duration,
+    producers: u32,
+    consumers: u32,
+    session:   u64,
+    size:      usize,
+    report:    Duration,
+    pid:       Option<u32>,
+}
+
+impl Config {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut c = Config {
+            addr:      "127.0.0.1:7000".into(),
+            duration:  Duration::from_secs(3600),
+            producers: 4,
+            consumers: 4,
+            session:   200,
+            size:      256,
+            report:    Duration::from_secs(10),
+            pid:       None,
+        };
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            if !arg.starts_with("--") {
+                c.addr = arg.clone();
+                continue;
+            }
+            let val = it.next().ok_or_else(|| format!("{arg} needs a value"))?;
+            let num = || val.parse::<u64>().map_err(|_| format!("bad {arg} {val:?}"));
+            match arg.as_str() {
+                "--duration"  => c.duration = parse_duration(val)?,
+                "--report"    => c.report = parse_duration(val)?,
+                "--producers" => c.producers = num()? as u32,
+                "--consumers" => c.consumers = num()?.max(1) as u32,
+                "--session"   => c.session = num()?.max(1),
+                "--size"      => c.size = (num()? as usize).max(HEADER_LEN),
+                "--pid"       => c.pid = Some(num()? as u32),
+                _ => return Err(format!("unknown option {arg}")),
+            }
+        }
+        Ok(c)
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("bad duration {s:?}"))?;
+    match unit {
+        "ms"     => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m"      => Ok(Duration::from_secs(n * 60)),
+        "h"      => Ok(Duration::from_secs(n * 3600)),
+        _ => Err(format!("bad duration unit in {s:?}")),
+    }
+}
+
+/// Per producer: how many messages and what sum of sequence numbers. Equal
+/// tallies on both sides mean no loss and no duplicates (short of a loss
+/// and a duplicate cancelling out exactly).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Tally {
+    count:   u64,
+    seq_sum: u128,
+}
+
+impl Tally {
+    fn add(&mut self, seq: u64) {
+        self.count += 1;
+        self.seq_sum += seq as u128;
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    stop:       AtomicBool,
+    sent:       Mutex<HashMap<u32, Tally>>,
+    received:   Mutex<HashMap<u32, Tally>>,
+    sent_n:     AtomicU64,
+    received_n: AtomicU64,
+    corrupt:    AtomicU64,
+    errors:     AtomicU64,
+    live_prod:  AtomicUsize,
+    live_cons:  AtomicUsize,
+}
+
+/// Payload byte `i` of message (`producer`, `seq`): cheap to generate and
+/// to check, and different for every message.
+fn pattern(producer: u32, seq: u64, i: usize) -> u8 {
+    (seq as usize ^ (producer as usize).rotate_left(8) ^ i.wrapping_mul(31)) as u8
+}
+
+fn encode(producer: u32, seq: u64, size: usize) -> Vec<u8> {
+    let mut m = Vec::with_capacity(size);
+    m.push(DATA);
+    m.extend_from_slice(&producer.to_be_bytes());
+    m.extend_from_slice(&seq.to_be_bytes());
+    m.extend((HEADER_LEN..size).map(|i| pattern(producer, seq, i)));
+    m
+}
+
+/// (producer, seq) of a data message whose payload checks out.
+fn decode(m: &[u8]) -> Option<(u32, u64)> {
+    if m.len() < HEADER_LEN || m[0] != DATA {
+        return None;
+    }
+    let producer = u32::from_be_bytes(m[1..5].try_into().ok()?);
+    let seq = u64::from_be_bytes(m[5..13].try_into().ok()?);
+    m[HEADER_LEN..].iter().enumerate()
+        .all(|(i, b)| *b == pattern(producer, seq, HEADER_LEN + i))
+        .then_some((producer, seq))
+}
+
+fn producer_loop(cfg: Arc<Config>, sh: Arc<Shared>, id: u32) {
+    let mut seq = 0u64;
+    while !sh.stop.load(Ordering::Relaxed) {
+        let mut p = match Producer::connect(&cfg.addr) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("producer {id}: connect failed: {e}");
+                sh.errors.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(200));
+                continue;
+            }
+        };
+        sh.live_prod.fetch_add(1, Ordering::Relaxed);
+        for _ in 0..cfg.session {
+            if sh.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Err(e) = p.send(&encode(id, seq, cfg.size)) {
+                // Unknown whether it was enqueued: a hard failure for a
+                // soak run against a healthy orchestrator.
+                warn!("producer {id}: send failed: {e}");
+                sh.errors.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+            sh.sent.lock().unwrap().entry(id).or_default().add(seq);
+            sh.sent_n.fetch_add(1, Ordering::Relaxed);
+            seq += 1;
+        }
+        sh.live_prod.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn consumer_loop(cfg: Arc<Config>, sh: Arc<Shared>, id: u32) {
+    loop {
+        let mut c = match Consumer::connect(&cfg.addr) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("consumer {id}: connect failed: {e}");
+                sh.errors.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(200));
+                continue;
+            }
+        };
+        sh.live_cons.fetch_add(1, Ordering::Relaxed);
+        for _ in 0..cfg.session {
+            // On an at-least-once queue each message is acked once counted,
+            // before the session ends.
+            let (m, tag) = match c.recv_ext() {
+                Ok(Delivery::Message(m)) => (m.payload, m.tag),
+                Ok(Delivery::Eos(_)) => continue,
+                Err(e) => {
+                    warn!("consumer {id}: recv failed: {e}");
+                    sh.errors.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+            };
+            let stop = m.first() == Some(&STOP);
+            if !stop {
+                match decode(&m) {
+                    Some((producer, seq)) => {
+                        sh.received.lock().unwrap().entry(producer).or_default().add(seq);
+                        sh.received_n.fetch_add(1, Ordering::Relaxed);
+                    }
+                    None => {
+                        sh.corrupt.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            if let Some(tag) = tag
+                && let Err(e) = c.ack(tag)
+            {
+                warn!("consumer {id}: ack failed: {e}");
+                sh.errors.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+            if stop {
+                sh.live_cons.fetch_sub(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        sh.live_cons.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// (open fds, resident KiB) of process `pid`, from /proc.
+fn proc_usage(pid: u32) -> Option<(usize, u64)> {
+    let fds = fs::read_dir(format!("/proc/{pid}/fd")).ok()?.count();
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let rss = status.lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))
+        .and_then(|v| v.split_whitespace().next()?.parse().ok())?;
+    Some((fds, rss))
+}
+
+fn report(cfg: &Config, sh: &Shared, start: Instant) -> bool {
+    let sent = sh.sent_n.load(Ordering::Relaxed);
+    let received = sh.received_n.load(Ordering::Relaxed);
+    let t = start.elapsed().as_secs();
+    let usage = cfg.pid.and_then(proc_usage)
+        .map(|(fds, rss)| format!(" fds={fds} rss={rss}KiB"))
+        .unwrap_or_default();
+    println!(
+        "[soak {:02}:{:02}:{:02}] sent={} received={} backlog={} corrupt={} errors={} \
+         producers={} consumers={}{}",
+        t / 3600, t / 60 % 60, t % 60, sent, received, sent.saturating_sub(received),
+        sh.corrupt.load(Ordering::Relaxed), sh.errors.load(Ordering::Relaxed),
+        sh.live_prod.load(Ordering::Relaxed), sh.live_cons.load(Ordering::Relaxed), usage,
+    );
+    // A consumer counts a message before the producer thread records it
+    // as sent only in a narrow race, so allow the in-flight sends.
+    received <= sent + cfg.producers as u64
+}
+
+fn run(cfg: Config) -> io::Result<bool> {
+    qpipe::wait_until_healthy(&cfg.addr, Some(Duration::from_secs(10)))?;
+    let baseline = cfg.pid.and_then(proc_usage);
+    let cfg = Arc::new(cfg);
+    let sh = Arc::new(Shared::default());
+
+    let producers: Vec<_> = (0..cfg.producers)
+        .map(|id| {
+            let (cfg, sh) = (cfg.clone(), sh.clone());
+            thread::spawn(move || producer_loop(cfg, sh, id))
+        })
+        .collect();
+    let consumers: Vec<_> = (0..cfg.consumers)
+        .map(|id| {
+            let (cfg, sh) = (cfg.clone(), sh.clone());
+            thread::spawn(move || consumer_loop(cfg, sh, id))
+        })
+        .collect();
+
+    let start = Instant::now();
+    let mut ok = true;
+    while start.elapsed() < cfg.duration {
+        thread::sleep(cfg.report.min(cfg.duration.saturating_sub(start.elapsed())));
+        ok &= report(&cfg, &sh, start);
+    }
+
+    // Wind down: stop the producers, let the consumers empty the queue,
+    // then send each consumer a STOP.
+    sh.stop.store(true, Ordering::Relaxed);
+    for p in producers {
+        let _ = p.join();
+    }
+    qpipe::wait_for_idle(&cfg.addr, None, Some(Duration::from_secs(60)))?;
+    let mut stopper = Producer::connect(&cfg.addr)?;
+    while consumers.iter().any(|c| !c.is_finished()) {
+        stopper.send(&[STOP])?;
+        thread::sleep(Duration::from_millis(20));
+    }
+    drop(stopper);
+    for c in consumers {
+        let _ = c.join();
+    }
+    ok &= report(&cfg, &sh, start);
+
+    let sent = sh.sent.lock().unwrap();
+    let received = sh.received.lock().unwrap();
+    let mut lost_or_duplicated = 0;
+    for (id, s) in sent.iter() {
+        let r = received.get(id).copied().unwrap_or_default();
+        if r != *s {
+            lost_or_duplicated += 1;
+            println!("producer {id}: sent {s:?}, received {r:?}");
+        }
+    }
+    lost_or_duplicated += received.keys().filter(|id| !sent.contains_key(id)).count();
+    let corrupt = sh.corrupt.load(Ordering::Relaxed);
+    let errors = sh.errors.load(Ordering::Relaxed);
+    if lost_or_duplicated == 0 && corrupt == 0 {
+        println!(
+            "conservation OK: {} messages sent, all received exactly once",
+            sh.sent_n.load(Ordering::Relaxed),
+        );
+    } else {
+        println!(
+            "conservation FAILED: {lost_or_duplicated} producer(s) mismatched, \
+             {corrupt} corrupt message(s)"
+        );
+        ok = false;
+    }
+    if errors > 0 {
+        println!("{errors} client error(s) during the run");
+        ok = false;
+    }
+
+    if let (Some((fds0, rss0)), Some(pid)) = (baseline, cfg.pid) {
+        // Give the orchestrator a moment to reap the last sessions.
+        thread::sleep(Duration::from_millis(500));
+        match proc_usage(pid) {
+            Some((fds, rss)) => {
+                println!("orchestrator fds {fds0} -> {fds}, rss {rss0}KiB -> {rss}KiB");
+                if fds > fds0 + FD_SLACK {
+                    println!("fd leak: {} more open than at the start", fds - fds0);
+                    ok = false;
+                }
+            }
+            None => println!("orchestrator process {pid} is gone"),
+        }
+    }
+    Ok(ok)
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn")
+    ).init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cfg = match Config::parse(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("qpipe-soak: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(cfg) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("qpipe-soak: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
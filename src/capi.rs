@@ -0,0 +1,495 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! A C interface to producers and consumers (`capi` feature), for feeding
+//! qpipe from C and C++ programs. The declarations are in
+//! `include/qpipe.h`, kept by hand alongside this module; a test checks
+//! that the two agree. The crate builds as a static and a shared library
+//! too, so
+//!
+//!   cargo build --release --lib --features capi
+//!
+//! leaves `target/release/libqpipe.a` (and `libqpipe.so`) to link.
+//!
+//!   qpipe_producer *p;
+//!   if (qpipe_producer_connect("orchestrator:7000", "daq", &p) != QPIPE_OK)
+//!       fprintf(stderr, "connect: %s\n", qpipe_last_error());
+//!   qpipe_producer_send(p, buf, len);
+//!   qpipe_producer_free(p);
+//!
+//!   qpipe_consumer *c;
+//!   qpipe_consumer_connect("orchestrator:7000", "daq", &c);
+//!   uint8_t *data; size_t len;
+//!   while (qpipe_consumer_recv(c, &data, &len) == QPIPE_OK) {
+//!       process(data, len);
+//!       qpipe_message_free(data, len);
+//!   }
+//!   qpipe_consumer_free(c);
+//!
+//! Every call but the `free`s returns `QPIPE_OK` or a negative `QPIPE_ERR_*`
+//! code, mapped from the `io::ErrorKind` of the Rust error; the message of
+//! the last failure on the calling thread is `qpipe_last_error`. A null
+//! pointer where one is required, or an address or queue name that isn't
+//! UTF-8, is `QPIPE_ERR_INVALID_ARGUMENT`. A panic inside the library is
+//! caught and reported as `QPIPE_ERR_OTHER`, never unwound into C.
+//!
+//! A producer or consumer may be used from any one thread at a time.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::io;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::time::Duration;
+
+use crate::{Consumer, Producer};
+
+/// Success.
+pub const QPIPE_OK: c_int = 0;
+/// A null or malformed argument, or a request the library refuses
+/// (`InvalidInput`), such as a message over the size limit.
+pub const QPIPE_ERR_INVALID_ARGUMENT: c_int = -1;
+/// The connection could not be made, or was lost.
+pub const QPIPE_ERR_CONNECTION: c_int = -2;
+/// The orchestrator ended the session, as when it drains.
+pub const QPIPE_ERR_CLOSED: c_int = -3;
+/// Nothing arrived in time.
+pub const QPIPE_ERR_TIMED_OUT: c_int = -4;
+/// Authentication failed, or the session lacks the role.
+pub const QPIPE_ERR_PERMISSION_DENIED: c_int = -5;
+/// The peer sent something that isn't the protocol.
+pub const QPIPE_ERR_INVALID_DATA: c_int = -6;
+/// An option or feature the orchestrator or this build doesn't support.
+pub const QPIPE_ERR_UNSUPPORTED: c_int = -7;
+/// A rate limit refused the message.
+pub const QPIPE_ERR_LIMIT: c_int = -8;
+/// Anything else.
+pub const QPIPE_ERR_OTHER: c_int = -99;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// The `QPIPE_ERR_*` code for an error.
+fn code(e: &io::Error) -> c_int {
+    use io::ErrorKind::*;
+    match e.kind() {
+        InvalidInput => QPIPE_ERR_INVALID_ARGUMENT,
+        ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected | BrokenPipe | WriteZero
+        | AddrInUse | AddrNotAvailable | HostUnreachable | NetworkUnreachable | NetworkDown => QPIPE_ERR_CONNECTION,
+        UnexpectedEof => QPIPE_ERR_CLOSED,
+        TimedOut | WouldBlock => QPIPE_ERR_TIMED_OUT,
+        PermissionDenied => QPIPE_ERR_PERMISSION_DENIED,
+        InvalidData => QPIPE_ERR_INVALID_DATA,
+        Unsupported => QPIPE_ERR_UNSUPPORTED,
+        QuotaExceeded => QPIPE_ERR_LIMIT,
+        _ => QPIPE_ERR_OTHER,
+    }
+}
+
+/// Run `f`, keeping the message of any failure for `qpipe_last_error`,
+/// and return its code.
+fn call(f: impl FnOnce() -> io::Result<()>) -> c_int {
+    let result = catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(io::Error::other("panic inside qpipe")));
+    match result {
+        Ok(()) => QPIPE_OK,
+        Err(e) => {
+            // Interior NULs can't be in a C string; cut the message there.
+            let msg = e.to_string();
+            let msg = msg.split('\0').next().unwrap_or_default();
+            LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(msg).unwrap_or_default());
+            code(&e)
+        }
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{what} is null or not UTF-8"))
+}
+
+/// A required C string argument.
+///
+/// # Safety
+/// `s` is null or points to a NUL-terminated string.
+unsafe fn string<'a>(s: *const c_char, what: &str) -> io::Result<&'a str> {
+    if s.is_null() {
+        return Err(invalid(what));
+    }
+    // SAFETY: non-null, and NUL-terminated per the caller's contract.
+    unsafe { CStr::from_ptr(s) }.to_str().map_err(|_| invalid(what))
+}
+
+/// The message of the last call on this thread that failed, or an empty
+/// string. It stays valid until the next failure on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn qpipe_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Connect a producer to `orchestrator`, on `queue` or, if it is null, the
+/// default queue. On success `*out` is the producer, to be released with
+/// `qpipe_producer_free`; on failure it is set to null.
+///
+/// # Safety
+/// `orchestrator` and any `queue` are NUL-terminated strings; `out` points
+/// to writable storage for a pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qpipe_producer_connect(
+    orchestrator: *const c_char,
+    queue: *const c_char,
+    out: *mut *mut Producer,
+) -> c_int {
+    call(|| {
+        if out.is_null() {
+            return Err(invalid("out"));
+        }
+        // SAFETY: checked non-null; writable per the caller's contract.
+        unsafe { *out = ptr::null_mut() };
+        // SAFETY: per the caller's contract.
+        let addr = unsafe { string(orchestrator, "orchestrator") }?;
+        let p = match queue.is_null() {
+            true => Producer::connect(addr)?,
+            // SAFETY: per the caller's contract.
+            false => Producer::connect_to(addr, unsafe { string(queue, "queue") }?)?,
+        };
+        // SAFETY: as above.
+        unsafe { *out = Box::into_raw(Box::new(p)) };
+        Ok(())
+    })
+}
+
+/// Send `len` bytes from `data` as one message; see `Producer::send`. It
+/// returns once the orchestrator has taken them. `data` may be null when
+/// `len` is 0.
+///
+/// # Safety
+/// `producer` came from `qpipe_producer_connect` and hasn't been freed;
+/// `data` points to `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qpipe_producer_send(producer: *mut Producer, data: *const u8, len: usize) -> c_int {
+    call(|| {
+        // SAFETY: a live producer per the caller's contract, or null.
+        let p = unsafe { producer.as_mut() }.ok_or_else(|| invalid("producer"))?;
+        let payload: &[u8] = match len {
+            0 => &[],
+            _ if data.is_null() => return Err(invalid("data")),
+            // SAFETY: `len` readable bytes per the caller's contract.
+            _ => unsafe { std::slice::from_raw_parts(data, len) },
+        };
+        p.send(payload)
+    })
+}
+
+/// Close a producer as `Producer::close` does, and release it. Errors on
+/// the way out are discarded. Null is ignored.
+///
+/// # Safety
+/// `producer` came from `qpipe_producer_connect` and hasn't been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qpipe_producer_free(producer: *mut Producer) {
+    if !producer.is_null() {
+        // SAFETY: made by `Box::into_raw` in `qpipe_producer_connect`.
+        let p = unsafe { Box::from_raw(producer) };
+        call(|| p.close());
+    }
+}
+
+/// Connect a consumer to `orchestrator`, on `queue` or, if it is null, the
+/// default queue. On success `*out` is the consumer, to be released with
+/// `qpipe_consumer_free`; on failure it is set to null.
+///
+/// # Safety
+/// As for `qpipe_producer_connect`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qpipe_consumer_connect(
+    orchestrator: *const c_char,
+    queue: *const c_char,
+    out: *mut *mut Consumer,
+) -> c_int {
+    call(|| {
+        if out.is_null() {
+            return Err(invalid("out"));
+        }
+        // SAFETY: checked non-null; writable per the caller's contract.
+        unsafe { *out = ptr::null_mut() };
+        // SAFETY: per the caller's contract.
+        let addr = unsafe { string(orchestrator, "orchestrator") }?;
+        let c = match queue.is_null() {
+            true => Consumer::connect(addr)?,
+            // SAFETY: per the caller's contract.
+            false => Consumer::connect_to(addr, unsafe { string(queue, "queue") }?)?,
+        };
+        // SAFETY: as above.
+        unsafe { *out = Box::into_raw(Box::new(c)) };
+        Ok(())
+    })
+}
+
+/// Hand a payload over to C.
+///
+/// # Safety
+/// `data` and `len` point to writable storage.
+unsafe fn give(payload: Vec<u8>, data: *mut *mut u8, len: *mut usize) {
+    let payload = payload.into_boxed_slice();
+    // SAFETY: per the caller's contract.
+    unsafe {
+        *len = payload.len();
+        *data = Box::into_raw(payload) as *mut u8;
+    }
+}
+
+/// Block until the next message; see `Consumer::recv`. On success `*data`
+/// and `*len` are the payload, to be released with `qpipe_message_free`.
+/// On an at-least-once queue it is acked by the next receive, or by
+/// `qpipe_consumer_free`.
+///
+/// # Safety
+/// `consumer` came from `qpipe_consumer_connect` and hasn't been freed;
+/// `data` and `len` point to writable storage.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qpipe_consumer_recv(consumer: *mut Consumer, data: *mut *mut u8, len: *mut usize) -> c_int {
+    call(|| {
+        // SAFETY: a live consumer per the caller's contract, or null.
+        let c = unsafe { consumer.as_mut() }.ok_or_else(|| invalid("consumer"))?;
+        if data.is_null() || len.is_null() {
+            return Err(invalid("data or len"));
+        }
+        let payload = c.recv()?.payload;
+        // SAFETY: checked non-null; writable per the caller's contract.
+        unsafe { give(payload, data, len) };
+        Ok(())
+    })
+}
+
+/// Like `qpipe_consumer_recv`, but `QPIPE_ERR_TIMED_OUT` if no message
+/// came within `timeout_ms`; see `Consumer::recv_timeout`.
+///
+/// # Safety
+/// As for `qpipe_consumer_recv`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qpipe_consumer_recv_timeout(
+    consumer: *mut Consumer,
+    timeout_ms: u64,
+    data: *mut *mut u8,
+    len: *mut usize,
+) -> c_int {
+    call(|| {
+        // SAFETY: a live consumer per the caller's contract, or null.
+        let c = unsafe { consumer.as_mut() }.ok_or_else(|| invalid("consumer"))?;
+        if data.is_null() || len.is_null() {
+            return Err(invalid("data or len"));
+        }
+        let timeout = Duration::from_millis(timeout_ms);
+        let payload = c.recv_timeout(timeout)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::TimedOut, format!("no message within {timeout:?}"))
+        })?.payload;
+        // SAFETY: checked non-null; writable per the caller's contract.
+        unsafe { give(payload, data, len) };
+        Ok(())
+    })
+}
+
+/// Release a payload from `qpipe_consumer_recv`. Null is ignored.
+///
+/// # Safety
+/// `data` and `len` are as a receive gave them, and haven't been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qpipe_message_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        // SAFETY: made by `Box::into_raw` on a boxed slice of `len` bytes.
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)) });
+    }
+}
+
+/// Close a consumer as `Consumer::close` does, and release it: what it
+/// holds goes back to the queue. Errors on the way out are discarded. Null
+/// is ignored.
+///
+/// # Safety
+/// `consumer` came from `qpipe_consumer_connect` and hasn't been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn qpipe_consumer_free(consumer: *mut Consumer) {
+    if !consumer.is_null() {
+        // SAFETY: made by `Box::into_raw` in `qpipe_consumer_connect`.
+        let c = unsafe { Box::from_raw(consumer) };
+        call(|| c.close());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem;
+
+    fn last_error() -> String {
+        // SAFETY: a NUL-terminated string owned by this thread.
+        unsafe { CStr::from_ptr(qpipe_last_error()) }.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn messages_go_through_the_c_calls() {
+        let orch = mem::Orchestrator::start().unwrap();
+        let addr = CString::new(orch.addr()).unwrap();
+        let queue = CString::new("daq").unwrap();
+        let (mut p, mut c) = (ptr::null_mut(), ptr::null_mut());
+        unsafe {
+            assert_eq!(qpipe_consumer_connect(addr.as_ptr(), queue.as_ptr(), &mut c), QPIPE_OK);
+            assert_eq!(qpipe_producer_connect(addr.as_ptr(), queue.as_ptr(), &mut p), QPIPE_OK);
+            assert_eq!(qpipe_producer_send(p, b"event 1".as_ptr(), 7), QPIPE_OK);
+            assert_eq!(qpipe_producer_send(p, ptr::null(), 0), QPIPE_OK);
+
+            let (mut data, mut len) = (ptr::null_mut(), 0);
+            assert_eq!(qpipe_consumer_recv(c, &mut data, &mut len), QPIPE_OK);
+            assert_eq!(std::slice::from_raw_parts(data, len), b"event 1");
+            qpipe_message_free(data, len);
+            assert_eq!(qpipe_consumer_recv_timeout(c, 5000, &mut data, &mut len), QPIPE_OK);
+            assert_eq!(len, 0);
+            qpipe_message_free(data, len);
+            assert_eq!(qpipe_consumer_recv_timeout(c, 10, &mut data, &mut len), QPIPE_ERR_TIMED_OUT);
+            assert!(last_error().starts_with("no message within"), "{}", last_error());
+
+            qpipe_producer_free(p);
+            qpipe_consumer_free(c);
+            qpipe_producer_free(ptr::null_mut());
+        }
+        orch.stop().unwrap();
+    }
+
+    #[test]
+    fn receives_ack_on_an_at_least_once_queue() {
+        use crate::orchestrator::OrchestratorOptions;
+        use crate::DeliveryPolicy;
+
+        let opts = OrchestratorOptions::new().delivery(DeliveryPolicy::AtLeastOnce);
+        let orch = mem::Orchestrator::start_with(&opts).unwrap();
+        let addr = CString::new(orch.addr()).unwrap();
+        let (mut p, mut c) = (ptr::null_mut(), ptr::null_mut());
+        let (mut data, mut len) = (ptr::null_mut(), 0);
+        unsafe {
+            assert_eq!(qpipe_producer_connect(addr.as_ptr(), ptr::null(), &mut p), QPIPE_OK);
+            for m in [&b"one"[..], b"two"] {
+                assert_eq!(qpipe_producer_send(p, m.as_ptr(), m.len()), QPIPE_OK);
+            }
+            assert_eq!(qpipe_consumer_connect(addr.as_ptr(), ptr::null(), &mut c), QPIPE_OK);
+            assert_eq!(qpipe_consumer_recv(c, &mut data, &mut len), QPIPE_OK, "{}", last_error());
+            assert_eq!(std::slice::from_raw_parts(data, len), b"one");
+            qpipe_message_free(data, len);
+            assert_eq!(qpipe_consumer_recv_timeout(c, 5000, &mut data, &mut len), QPIPE_OK);
+            assert_eq!(std::slice::from_raw_parts(data, len), b"two");
+            qpipe_message_free(data, len);
+            qpipe_consumer_free(c);
+
+            // Both were acked, so nothing comes back.
+            assert_eq!(qpipe_consumer_connect(addr.as_ptr(), ptr::null(), &mut c), QPIPE_OK);
+            assert_eq!(qpipe_consumer_recv_timeout(c, 300, &mut data, &mut len), QPIPE_ERR_TIMED_OUT);
+            qpipe_consumer_free(c);
+            qpipe_producer_free(p);
+        }
+        orch.stop().unwrap();
+    }
+
+    #[test]
+    fn failures_come_back_as_codes_with_a_message() {
+        let mut p = ptr::null_mut();
+        unsafe {
+            assert_eq!(qpipe_producer_connect(ptr::null(), ptr::null(), &mut p), QPIPE_ERR_INVALID_ARGUMENT);
+            assert_eq!(last_error(), "orchestrator is null or not UTF-8");
+            let bad = CString::new(vec![0xff, b'x']).unwrap();
+            assert_eq!(qpipe_producer_connect(bad.as_ptr(), ptr::null(), &mut p), QPIPE_ERR_INVALID_ARGUMENT);
+            let nobody = CString::new("mem://nobody-listens").unwrap();
+            assert_eq!(qpipe_producer_connect(nobody.as_ptr(), ptr::null(), &mut p), QPIPE_ERR_CONNECTION);
+            assert!(p.is_null());
+            assert_eq!(qpipe_producer_send(ptr::null_mut(), ptr::null(), 0), QPIPE_ERR_INVALID_ARGUMENT);
+        }
+        assert_eq!(code(&io::Error::from(io::ErrorKind::UnexpectedEof)), QPIPE_ERR_CLOSED);
+        assert_eq!(code(&io::Error::from(io::ErrorKind::QuotaExceeded)), QPIPE_ERR_LIMIT);
+        assert_eq!(code(&io::Error::other("?")), QPIPE_ERR_OTHER);
+    }
+
+    /// The C spelling of a type in this module's signatures.
+    fn c_type(rust: &str) -> String {
+        if let Some(t) = rust.strip_prefix("*const ") {
+            return format!("const {} *", c_type(t));
+        }
+        if let Some(t) = rust.strip_prefix("*mut ") {
+            return format!("{} *", c_type(t));
+        }
+        match rust {
+            "c_char" => "char",
+            "c_int" => "int",
+            "u8" => "uint8_t",
+            "u64" => "uint64_t",
+            "usize" => "size_t",
+            "Producer" => "qpipe_producer",
+            "Consumer" => "qpipe_consumer",
+            t => panic!("no C spelling for {t}"),
+        }.to_string()
+    }
+
+    /// `c` with single spaces, and none around punctuation, so that
+    /// `uint8_t **data` and `uint8_t * *data` compare equal.
+    fn squeeze(c: &str) -> String {
+        let words = c.split_whitespace().collect::<Vec<_>>().join(" ");
+        let mut out = String::new();
+        for ch in words.chars() {
+            if ch == ' ' && out.ends_with(['*', '(', ',']) {
+                continue;
+            }
+            if matches!(ch, '*' | '(' | ')' | ',' | ';') && out.ends_with(' ') {
+                out.pop();
+            }
+            out.push(ch);
+        }
+        out
+    }
+
+    #[test]
+    fn the_header_declares_what_this_module_exports() {
+        let header = include_str!("../include/qpipe.h");
+        let source = include_str!("capi.rs");
+        let source = &source[..source.find("#[cfg(test)]").unwrap()];
+        // The header without its comments, one declaration per `;`.
+        let mut code = String::new();
+        let mut rest = header;
+        while let Some(start) = rest.find("/*") {
+            code.push_str(&rest[..start]);
+            rest = &rest[start + rest[start..].find("*/").unwrap() + 2..];
+        }
+        code.push_str(rest);
+        let code: String = code.lines().map(|l| l.split("//").next().unwrap()).collect::<Vec<_>>().join("\n");
+        let declared = squeeze(&code);
+
+        let mut exported = Vec::new();
+        for def in source.split("extern \"C\" fn ").skip(1) {
+            let (name, def) = def.split_once('(').unwrap();
+            let (params, def) = def.split_once(')').unwrap();
+            let ret = def[..def.find('{').unwrap()].trim().strip_prefix("-> ").map_or("void".into(), c_type);
+            let params: Vec<String> = params.split(',')
+                .filter(|p| !p.trim().is_empty())
+                .map(|p| {
+                    let (name, ty) = p.split_once(':').unwrap();
+                    format!("{} {}", c_type(ty.trim()), name.trim())
+                })
+                .collect();
+            let params = if params.is_empty() { "void".into() } else { params.join(", ") };
+            let decl = squeeze(&format!("{ret} {name}({params});"));
+            assert!(declared.contains(&decl), "include/qpipe.h lacks {decl}");
+            exported.push(name.to_string());
+        }
+        let mut in_header: Vec<String> = declared.split(';')
+            .filter_map(|d| Some(d[..d.find('(')?].rsplit(['*', ' ']).next()?.to_string()))
+            .filter(|name| name.starts_with("qpipe_"))
+            .collect();
+        in_header.sort();
+        exported.sort();
+        assert_eq!(in_header, exported, "functions the header declares but this module lacks");
+
+        let mut codes = Vec::new();
+        for line in source.lines().filter_map(|l| l.strip_prefix("pub const QPIPE_")) {
+            let (name, value) = line.split_once(": c_int = ").unwrap();
+            let define = format!("#define QPIPE_{name} {}", value.trim_end_matches(';'));
+            assert!(header.lines().any(|l| l == define), "include/qpipe.h lacks {define}");
+            codes.push(name);
+        }
+        assert_eq!(header.matches("#define QPIPE_ERR_").count() + 1, codes.len());
+    }
+}
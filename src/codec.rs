@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Sans-IO framing, for event loops of your own: `FrameEncoder` appends
+//! frames to a buffer, `FrameDecoder` takes bytes in whatever pieces a
+//! non-blocking socket hands over and gives back each frame once all of it
+//! has arrived. Neither touches a socket, so both are easy to drive from
+//! mio, an async runtime or a fuzzer.
+//!
+//!   let mut enc = FrameEncoder::new();
+//!   let mut out = Vec::new();
+//!   enc.encode(&Frame::Msg(b"job".to_vec()), &Meta::default(), &mut out)?;
+//!
+//!   let mut dec = FrameDecoder::new();
+//!   dec.feed(&out[..2]);
+//!   assert!(dec.decode()?.is_none());       // not all there yet
+//!   dec.feed(&out[2..]);
+//!   let (frame, meta) = dec.decode()?.unwrap();
+//!
+//! The wire format is the one `put_frame` and `get_frame` speak (see the
+//! crate docs), parsed by the same code: a frame decodes here exactly as it
+//! would from a blocking stream. Acknowledgements are not framed: a peer
+//! that expects them gets one `ACK_PAYLOAD` byte per frame, written by the
+//! caller.
+
+use std::io;
+
+use crate::{
+    get_frame_as, put_frame_as, Frame, Meta, FRAME_FLAGS, FRAME_FLAG_CRC, MAX_FRAME_SIZE, MAX_META_LEN,
+};
+
+/// Writes frames into a byte buffer.
+#[derive(Debug, Clone, Default)]
+pub struct FrameEncoder {
+    checksum: bool,
+}
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// End every frame with a CRC-32C trailer (`FRAME_FLAG_CRC`), for
+    /// sessions that negotiated `OPT_CHECKSUM`.
+    pub fn checksum(mut self, on: bool) -> Self {
+        self.checksum = on;
+        self
+    }
+
+    /// Append `frame` and its metadata to `dst`. `InvalidInput`, with
+    /// nothing appended, for what `put_frame` refuses.
+    pub fn encode(&self, frame: &Frame, meta: &Meta, dst: &mut Vec<u8>) -> io::Result<()> {
+        let start = dst.len();
+        put_frame_as(dst, frame, meta, self.checksum).inspect_err(|_| dst.truncate(start))
+    }
+}
+
+/// Collects bytes until they make up whole frames.
+///
+/// After an error the stream is out of step and the session is lost, as
+/// with a blocking reader; the decoder should be dropped.
+#[derive(Debug, Clone)]
+pub struct FrameDecoder {
+    buf:      Vec<u8>,
+    /// Where the first undecoded byte of `buf` is.
+    start:    usize,
+    checksum: bool,
+    limit:    usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self { buf: Vec::new(), start: 0, checksum: false, limit: MAX_FRAME_SIZE }
+    }
+
+    /// Require a checksum trailer on every frame, like
+    /// `get_checked_frame`.
+    pub fn checksum(mut self, on: bool) -> Self {
+        self.checksum = on;
+        self
+    }
+
+    /// Refuse frames whose body (past any metadata block) is longer than
+    /// `bytes`; MAX_FRAME_SIZE by default.
+    pub fn limit(mut self, bytes: usize) -> Self {
+        self.limit = bytes;
+        self
+    }
+
+    /// Take in bytes as they arrive.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        // Move what is left to the front once the decoded part dominates,
+        // so the buffer doesn't grow with the stream.
+        if self.start > 0 && self.start >= self.buf.len() / 2 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The next complete frame, or `None` until more bytes are fed. Errors
+    /// are `get_frame`'s; a length prefix that no frame within the limit
+    /// could carry fails at once, before its body is buffered.
+    pub fn decode(&mut self) -> io::Result<Option<(Frame, Meta)>> {
+        let pending = &self.buf[self.start..];
+        let Some(prefix) = pending.first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let raw = u32::from_be_bytes(*prefix);
+        let body = (raw & !FRAME_FLAGS) as usize;
+        if body > self.limit + 2 + MAX_META_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "incoming frame too large"));
+        }
+        let len = 4 + body + if raw & FRAME_FLAG_CRC != 0 { 4 } else { 0 };
+        if pending.len() < len {
+            return Ok(None);
+        }
+        let mut frame = &pending[..len];
+        let got = get_frame_as(&mut frame, self.checksum, self.limit)?;
+        self.start += len;
+        if self.start == self.buf.len() {
+            self.buf.clear();
+            self.start = 0;
+        }
+        Ok(got)
+    }
+
+    /// Bytes fed but not yet decoded.
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    /// The end of the stream: an error if it cut a frame short, as for a
+    /// blocking reader.
+    pub fn finish(&self) -> io::Result<()> {
+        match self.buffered() {
+            0 => Ok(()),
+            n => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof, format!("connection closed mid-frame ({n} bytes buffered)"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_frame, FRAME_FLAG_CHUNK};
+
+    #[test]
+    fn frames_come_out_whole_however_the_bytes_arrive() {
+        let enc = FrameEncoder::new();
+        let meta = Meta { priority: Some(3), ..Meta::default() };
+        let frames = [
+            Frame::Msg(b"hello".to_vec()),
+            Frame::Chunk { id: 7, idx: 1, count: 2, payload: vec![9; 1000] },
+            Frame::Eos(b"group".to_vec()),
+            Frame::Ping,
+            Frame::Bye,
+        ];
+        let mut wire = Vec::new();
+        for f in &frames {
+            enc.encode(f, &meta, &mut wire).unwrap();
+        }
+        // The blocking reader sees the same frames.
+        let mut r = &wire[..];
+        assert_eq!(get_frame(&mut r).unwrap(), Some((frames[0].clone(), meta.clone())));
+
+        let mut dec = FrameDecoder::new();
+        let mut got = Vec::new();
+        for byte in &wire {
+            dec.feed(std::slice::from_ref(byte));
+            while let Some((f, m)) = dec.decode().unwrap() {
+                assert_eq!(m, meta);
+                got.push(f);
+            }
+        }
+        assert_eq!(got, frames);
+        assert_eq!(dec.buffered(), 0);
+        dec.finish().unwrap();
+
+        dec.feed(&wire[..3]);
+        assert_eq!(dec.finish().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn checksums_and_limits_apply_as_on_blocking_reads() {
+        let mut wire = Vec::new();
+        FrameEncoder::new().checksum(true).encode(&Frame::Msg(vec![1; 100]), &Meta::default(), &mut wire).unwrap();
+        let mut dec = FrameDecoder::new().checksum(true);
+        dec.feed(&wire);
+        assert_eq!(dec.decode().unwrap().unwrap().0, Frame::Msg(vec![1; 100]));
+
+        // A flipped payload bit.
+        wire[10] ^= 1;
+        let mut dec = FrameDecoder::new();
+        dec.feed(&wire);
+        assert_eq!(dec.decode().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Too long for the limit: refused from the prefix alone.
+        let mut dec = FrameDecoder::new().limit(1024);
+        dec.feed(&(FRAME_FLAG_CHUNK | 1_000_000).to_be_bytes());
+        assert_eq!(dec.decode().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Refused frames leave nothing behind in the buffer.
+        let mut out = vec![0xAA];
+        let big = Frame::Msg(vec![0; MAX_FRAME_SIZE + 1]);
+        assert!(FrameEncoder::new().encode(&big, &Meta::default(), &mut out).is_err());
+        assert_eq!(out, [0xAA]);
+    }
+}
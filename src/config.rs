@@ -0,0 +1,484 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Orchestrator configuration files, as `orchestrator --config qpipe.toml`
+//! reads them (see `OrchestratorOptions::config`):
+//!
+//!   listen = "0.0.0.0:7000"
+//!   capacity = 100000
+//!   log_level = "info"
+//!   delivery = "at-least-once"             # every queue's, by default
+//!   dedup_window = 100000                  # message ids per queue
+//!   when_full = "block"                    # or drop-oldest, reject-newest
+//!   wal_dir = "/var/lib/qpipe"
+//!   standby_of = "qpipe-a:7000"            # this one stands by for qpipe-a
+//!   failover_after = "10s"
+//!
+//!   [limits]
+//!   producer = "msgs=1000/s,mode=reject"   # QPIPE_PRODUCER_LIMIT syntax
+//!   max_sessions = 500
+//!   timeouts = "read=30s,idle=10m"         # QPIPE_SESSION_TIMEOUTS syntax
+//!
+//!   [queues.ingest]
+//!   capacity = 500000
+//!
+//!   [queues.telemetry]
+//!   when_full = "drop-oldest"
+//!
+//!   [queues."team-a/events"]
+//!   fanout = true
+//!   delivery = "at-most-once"
+//!
+//! The format is the part of TOML a flat settings file needs — tables,
+//! `key = value` lines whose values are strings, integers or booleans, and
+//! `#` comments — parsed here, like the QPIPE_* specs, rather than by a
+//! TOML crate. Keys this module doesn't know are errors, so a typo doesn't
+//! quietly leave a default in place. A `[tls]` table is refused: qpipe has
+//! no TLS of its own (see the README's transport security note).
+//!
+//! On SIGHUP the orchestrator reads its file again and applies what can
+//! change under running sessions: `capacity` and the queues' capacities,
+//! `[limits]` but for `egress`, and `log_level`. Other settings take a
+//! restart.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::LevelFilter;
+
+use crate::{check_queue_name, DeliveryPolicy};
+use crate::orchestrator::{FullPolicy, StatsFormat};
+
+/// An orchestrator configuration file. Every setting is optional; the
+/// orchestrator's defaults and QPIPE_* variables fill in the rest.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Control listeners, like the binary's `LISTEN_ADDR` and `--listen`:
+    /// one address, or several separated by commas.
+    pub listen:          Option<String>,
+    /// WebSocket listener, like `--ws-listen`.
+    pub ws_listen:       Option<String>,
+    /// Frames each queue holds, like `CAPACITY`. Reloadable.
+    pub capacity:        Option<usize>,
+    /// How often stats are reported (`"10s"`), like `STATS_INTERVAL_SECS`.
+    pub stats_every:     Option<Duration>,
+    /// `"text"` or `"json"`, like `--stats-format`.
+    pub stats_format:    Option<StatsFormat>,
+    /// The most verbose log level: `"off"`, `"error"`, ... `"trace"`.
+    /// Reloadable.
+    pub log_level:       Option<LevelFilter>,
+    /// Pre-shared key file, like `--auth-key`.
+    pub auth_key:        Option<PathBuf>,
+    /// Write-ahead log directory, like `--wal-dir`.
+    pub wal_dir:         Option<PathBuf>,
+    /// Like `--single-port`.
+    pub single_port:     Option<bool>,
+    /// Like `--shards`.
+    pub shards:          Option<usize>,
+    /// Like `--max-frame-bytes`.
+    pub max_frame_bytes: Option<usize>,
+    /// Like `--delivery`: the policy of queues without one of their own.
+    pub delivery:        Option<DeliveryPolicy>,
+    /// Message ids each queue remembers, like `--dedup-window`.
+    pub dedup_window:    Option<usize>,
+    /// Like `--when-full`: what queues without a policy of their own do
+    /// when full.
+    pub when_full:       Option<FullPolicy>,
+    /// The primary's address, like `--standby-of`.
+    pub standby_of:      Option<String>,
+    /// How long a standby waits for a silent primary (`"5s"`), like
+    /// `--failover-after`.
+    pub failover_after:  Option<Duration>,
+    /// `[limits]`. Reloadable.
+    pub limits:          Limits,
+    /// `[queues.<name>]`, by queue name.
+    pub queues:          BTreeMap<String, QueueConfig>,
+}
+
+/// The `[limits]` table. Rate specs take the syntax of the QPIPE_*
+/// variable they stand in for, and win over it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// QPIPE_PRODUCER_LIMIT: caps on each producer connection.
+    pub producer:     Option<String>,
+    /// QPIPE_EGRESS_LIMIT: caps on deliveries. Read at startup only.
+    pub egress:       Option<String>,
+    /// QPIPE_MAX_SESSIONS: producers and consumers open at once.
+    pub max_sessions: Option<usize>,
+    /// QPIPE_SESSION_TIMEOUTS: how long a session may stall or sit idle.
+    pub timeouts:     Option<String>,
+}
+
+/// A `[queues.<name>]` table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Frames this queue holds, in place of the global `capacity`.
+    /// Reloadable.
+    pub capacity: Option<usize>,
+    /// Every consumer gets every frame, as if the queue were listed in
+    /// QPIPE_FANOUT_QUEUES. Read when the queue is created.
+    pub fanout:   bool,
+    /// How the queue settles deliveries, in place of the global
+    /// `delivery`. Read when the queue is created.
+    pub delivery: Option<DeliveryPolicy>,
+    /// What the queue does when full, in place of the global
+    /// `when_full`. Read when the queue is created.
+    pub when_full: Option<FullPolicy>,
+}
+
+impl Config {
+    /// Read and parse the file at `path`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        Self::parse(&text)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    }
+
+    /// Parse a configuration file's text. Errors name the line at fault.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut cfg = Self::default();
+        for entry in entries(text)? {
+            let Entry { table, key, value, line } = entry;
+            let bad = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, format!("line {line}: {msg}"));
+            let tables: Vec<&str> = table.iter().map(String::as_str).collect();
+            match (tables.as_slice(), key.as_str()) {
+                ([], "listen")          => cfg.listen = Some(value.string().map_err(bad)?),
+                ([], "ws_listen")       => cfg.ws_listen = Some(value.string().map_err(bad)?),
+                ([], "capacity")        => cfg.capacity = Some(value.count().map_err(bad)?),
+                ([], "stats_every")     => cfg.stats_every = Some(
+                    crate::orchestrator::parse_duration(&value.string().map_err(bad)?).map_err(bad)?,
+                ),
+                ([], "stats_format")    => cfg.stats_format = Some(match value.string().map_err(bad)?.as_str() {
+                    "text" => StatsFormat::Text,
+                    "json" => StatsFormat::Json,
+                    other => return Err(bad(format!("stats_format: expected text or json, got {other:?}"))),
+                }),
+                ([], "log_level")       => {
+                    let level = value.string().map_err(bad)?;
+                    cfg.log_level = Some(level.parse().map_err(|_| bad(format!(
+                        "log_level: expected off, error, warn, info, debug or trace, got {level:?}",
+                    )))?);
+                }
+                ([], "auth_key")        => cfg.auth_key = Some(value.string().map_err(bad)?.into()),
+                ([], "wal_dir")         => cfg.wal_dir = Some(value.string().map_err(bad)?.into()),
+                ([], "single_port")     => cfg.single_port = Some(value.boolean().map_err(bad)?),
+                ([], "shards")          => cfg.shards = Some(value.count().map_err(bad)?.max(1)),
+                ([], "max_frame_bytes") => cfg.max_frame_bytes = Some(value.count().map_err(bad)?),
+                ([], "delivery")        => cfg.delivery = Some(value.delivery().map_err(bad)?),
+                ([], "dedup_window")    => cfg.dedup_window = Some(value.count().map_err(bad)?),
+                ([], "when_full")       => cfg.when_full = Some(value.when_full().map_err(bad)?),
+                ([], "standby_of")      => cfg.standby_of = Some(value.string().map_err(bad)?),
+                ([], "failover_after")  => cfg.failover_after = Some(
+                    crate::orchestrator::parse_duration(&value.string().map_err(bad)?).map_err(bad)?,
+                ),
+                (["limits"], "producer")     => cfg.limits.producer = Some(value.string().map_err(bad)?),
+                (["limits"], "egress")       => cfg.limits.egress = Some(value.string().map_err(bad)?),
+                (["limits"], "max_sessions") => cfg.limits.max_sessions = Some(value.count().map_err(bad)?),
+                (["limits"], "timeouts")     => cfg.limits.timeouts = Some(value.string().map_err(bad)?),
+                (["tls", ..], _) => return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("line {line}: qpipe has no TLS; terminate it in a proxy such as stunnel"),
+                )),
+                (["queues", name], key) => {
+                    check_queue_name(name).map_err(|e| bad(e.to_string()))?;
+                    let q = cfg.queues.entry(name.to_string()).or_default();
+                    match key {
+                        "capacity" => q.capacity = Some(value.count().map_err(bad)?),
+                        "fanout"   => q.fanout = value.boolean().map_err(bad)?,
+                        "delivery" => q.delivery = Some(value.delivery().map_err(bad)?),
+                        "when_full" => q.when_full = Some(value.when_full().map_err(bad)?),
+                        _ => return Err(bad(format!("unknown queue setting {key:?}"))),
+                    }
+                }
+                ([], key) => return Err(bad(format!("unknown setting {key:?}"))),
+                (tables, key) => return Err(bad(format!("unknown setting {key:?} in [{}]", tables.join(".")))),
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+/// A value on the right of `=`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl Value {
+    fn string(self) -> Result<String, String> {
+        match self {
+            Value::Str(s) => Ok(s),
+            v => Err(format!("expected a string, got {v:?}")),
+        }
+    }
+
+    fn count(self) -> Result<usize, String> {
+        match self {
+            Value::Int(n) if n >= 0 => usize::try_from(n).map_err(|_| format!("{n} is too large")),
+            v => Err(format!("expected a non-negative integer, got {v:?}")),
+        }
+    }
+
+    fn delivery(self) -> Result<DeliveryPolicy, String> {
+        DeliveryPolicy::parse(&self.string()?).map_err(|e| e.to_string())
+    }
+
+    fn when_full(self) -> Result<FullPolicy, String> {
+        FullPolicy::parse(&self.string()?).map_err(|e| e.to_string())
+    }
+
+    fn boolean(self) -> Result<bool, String> {
+        match self {
+            Value::Bool(b) => Ok(b),
+            v => Err(format!("expected true or false, got {v:?}")),
+        }
+    }
+}
+
+/// One `key = value` line, with the table it is in.
+#[derive(Debug)]
+struct Entry {
+    table: Vec<String>,
+    key:   String,
+    value: Value,
+    line:  usize,
+}
+
+/// Every `key = value` of `text`, in order. Repeated keys and tables are
+/// errors, as in TOML.
+fn entries(text: &str) -> io::Result<Vec<Entry>> {
+    let mut out: Vec<Entry> = Vec::new();
+    let mut table: Vec<String> = Vec::new();
+    let mut tables: Vec<Vec<String>> = Vec::new();
+    for (i, raw) in text.lines().enumerate() {
+        let line = i + 1;
+        let bad = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, format!("line {line}: {msg}"));
+        let s = raw.trim();
+        if s.is_empty() || s.starts_with('#') {
+            continue;
+        }
+        if let Some(rest) = s.strip_prefix('[') {
+            if rest.starts_with('[') {
+                return Err(bad("arrays of tables are not supported".into()));
+            }
+            let (keys, rest) = keys(rest).map_err(bad)?;
+            let rest = rest.trim_start().strip_prefix(']')
+                .ok_or_else(|| bad("expected ] after the table name".into()))?;
+            trailing(rest).map_err(bad)?;
+            if tables.contains(&keys) {
+                return Err(bad(format!("table [{}] defined twice", keys.join("."))));
+            }
+            tables.push(keys.clone());
+            table = keys;
+            continue;
+        }
+        let (key, rest) = key(s).map_err(bad)?;
+        let rest = rest.trim_start().strip_prefix('=')
+            .ok_or_else(|| bad(format!("expected = after {key:?}")))?;
+        let (value, rest) = value(rest.trim_start()).map_err(bad)?;
+        trailing(rest).map_err(bad)?;
+        if out.iter().any(|e| e.table == table && e.key == key) {
+            return Err(bad(format!("{key:?} set twice")));
+        }
+        out.push(Entry { table: table.clone(), key, value, line });
+    }
+    Ok(out)
+}
+
+/// Only whitespace or a comment may follow a value or table header.
+fn trailing(rest: &str) -> Result<(), String> {
+    let rest = rest.trim_start();
+    match rest.is_empty() || rest.starts_with('#') {
+        true => Ok(()),
+        false => Err(format!("unexpected {rest:?}")),
+    }
+}
+
+/// A dotted table name, `a.b` or `queues."team-a/events"`.
+fn keys(s: &str) -> Result<(Vec<String>, &str), String> {
+    let mut out = Vec::new();
+    let mut rest = s;
+    loop {
+        let (k, r) = key(rest.trim_start())?;
+        out.push(k);
+        match r.trim_start().strip_prefix('.') {
+            Some(r) => rest = r,
+            None => return Ok((out, r)),
+        }
+    }
+}
+
+/// A bare key (`A-Za-z0-9_-`) or a quoted one.
+fn key(s: &str) -> Result<(String, &str), String> {
+    if s.starts_with(['"', '\'']) {
+        return quoted(s);
+    }
+    let end = s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-')).unwrap_or(s.len());
+    if end == 0 {
+        return Err(format!("expected a key, got {s:?}"));
+    }
+    Ok((s[..end].to_string(), &s[end..]))
+}
+
+fn value(s: &str) -> Result<(Value, &str), String> {
+    if s.starts_with(['"', '\'']) {
+        if s.starts_with("\"\"\"") || s.starts_with("'''") {
+            return Err("multi-line strings are not supported".into());
+        }
+        let (v, rest) = quoted(s)?;
+        return Ok((Value::Str(v), rest));
+    }
+    let end = s.find(|c: char| c.is_whitespace() || c == '#').unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    let v = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        w if w.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-') => {
+            let digits = w.strip_prefix('+').unwrap_or(w);
+            if digits.contains("__") || digits.starts_with('_') || digits.ends_with('_') {
+                return Err(format!("bad integer {w:?}"));
+            }
+            Value::Int(digits.replace('_', "").parse().map_err(|_| format!("bad integer {w:?}"))?)
+        }
+        "" => return Err("expected a value".into()),
+        w => return Err(format!("unsupported value {w:?} (strings, integers and booleans only)")),
+    };
+    Ok((v, rest))
+}
+
+/// A `"basic"` string, with TOML's escapes, or a `'literal'` one.
+fn quoted(s: &str) -> Result<(String, &str), String> {
+    let quote = s.chars().next().unwrap_or('"');
+    let mut out = String::new();
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((out, &s[i + 1..])),
+            '\\' if quote == '"' => {
+                let (_, e) = chars.next().ok_or("unterminated string")?;
+                match e {
+                    'n' => out.push('\n'),
+                    't' => out.push('\t'),
+                    'r' => out.push('\r'),
+                    '"' => out.push('"'),
+                    '\\' => out.push('\\'),
+                    'u' | 'U' => {
+                        let n = if e == 'u' { 4 } else { 8 };
+                        let hex: String = (0..n).filter_map(|_| chars.next().map(|(_, c)| c)).collect();
+                        let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                            .ok_or_else(|| format!("bad escape \\{e}{hex}"))?;
+                        out.push(c);
+                    }
+                    e => return Err(format!("bad escape \\{e}")),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_full_file_parses() {
+        let cfg = Config::parse(r#"
+            # qpipe.toml
+            listen = "0.0.0.0:7000"   # control port
+            capacity = 100_000
+            stats_every = "10s"
+            stats_format = "json"
+            log_level = "debug"
+            single_port = true
+            shards = 4
+            delivery = "at-least-once"
+            dedup_window = 0
+            when_full = "reject-newest"
+            standby_of = "qpipe-a:7000"
+            failover_after = "10s"
+
+            [limits]
+            producer = "msgs=1000/s,mode=reject"
+            max_sessions = 500
+            timeouts = "idle=10m"
+
+            [queues.ingest]
+            capacity = 500000
+            when_full = "drop-oldest"
+
+            [queues."team-a/events"]
+            fanout = true
+            delivery = "at-most-once"
+        "#).unwrap();
+        assert_eq!(cfg.listen.as_deref(), Some("0.0.0.0:7000"));
+        assert_eq!(cfg.capacity, Some(100_000));
+        assert_eq!(cfg.stats_every, Some(Duration::from_secs(10)));
+        assert_eq!(cfg.stats_format, Some(StatsFormat::Json));
+        assert_eq!(cfg.log_level, Some(LevelFilter::Debug));
+        assert_eq!((cfg.single_port, cfg.shards), (Some(true), Some(4)));
+        assert_eq!(cfg.limits.producer.as_deref(), Some("msgs=1000/s,mode=reject"));
+        assert_eq!(cfg.limits.max_sessions, Some(500));
+        assert_eq!(cfg.limits.timeouts.as_deref(), Some("idle=10m"));
+        assert_eq!(cfg.delivery, Some(DeliveryPolicy::AtLeastOnce));
+        assert_eq!(cfg.dedup_window, Some(0));
+        assert_eq!(cfg.when_full, Some(FullPolicy::RejectNewest));
+        assert_eq!(cfg.standby_of.as_deref(), Some("qpipe-a:7000"));
+        assert_eq!(cfg.failover_after, Some(Duration::from_secs(10)));
+        assert_eq!(
+            cfg.queues["ingest"],
+            QueueConfig { capacity: Some(500_000), when_full: Some(FullPolicy::DropOldest), ..Default::default() },
+        );
+        assert_eq!(
+            cfg.queues["team-a/events"],
+            QueueConfig { fanout: true, delivery: Some(DeliveryPolicy::AtMostOnce), ..Default::default() },
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn mistakes_are_errors_that_name_their_line() {
+        for (text, line) in [
+            ("capacity = 10\ncapcity = 10", 2),
+            ("capacity = \"10\"", 1),
+            ("capacity = -1", 1),
+            ("listen = \"unterminated", 1),
+            ("\n\nlisten = \"a\" extra", 3),
+            ("[limits]\nproducer = 1", 2),
+            ("[limits]\nrate = \"1/s\"", 2),
+            ("capacity = 1\ncapacity = 2", 2),
+            ("[limits]\n[limits]", 2),
+            ("[queues.\"has space\"]\ncapacity = 1", 2),
+            ("[queues.a]\nweight = 1", 2),
+            ("[queues.a]\ndelivery = \"exactly-once\"", 2),
+            ("[queues.a]\nwhen_full = \"drop-newest\"", 2),
+            ("log_level = \"loud\"", 1),
+            ("stats_every = \"soon\"", 1),
+            ("failover_after = 5", 1),
+            ("ratio = 1.5", 1),
+        ] {
+            let err = Config::parse(text).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{text:?}");
+            assert!(err.to_string().starts_with(&format!("line {line}:")), "{text:?}: {err}");
+        }
+        let err = Config::parse("[tls]\ncert = \"/etc/qpipe/cert.pem\"").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn strings_take_toml_escapes_and_literal_quotes() {
+        let cfg = Config::parse(r#"
+            listen = "unix:///run/q\u0070ipe.sock"
+            wal_dir = 'C:\qpipe\wal'
+            ws_listen = "a\"b\\c"
+        "#).unwrap();
+        assert_eq!(cfg.listen.as_deref(), Some("unix:///run/qpipe.sock"));
+        assert_eq!(cfg.wal_dir, Some(PathBuf::from(r"C:\qpipe\wal")));
+        assert_eq!(cfg.ws_listen.as_deref(), Some(r#"a"b\c"#));
+    }
+}
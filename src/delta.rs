@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Delta encoding of consecutive payloads, for producers whose messages
+//! change little from one to the next (slow-moving telemetry structs) on
+//! links where bandwidth is scarce.
+//!
+//! A delta is taken against the previous payload on the same connection,
+//! so it only makes sense hop by hop: the producer encodes, the
+//! orchestrator decodes on receipt and queues whole messages — consumers,
+//! which each see an arbitrary subset of the stream, never see deltas. The
+//! producer asks for it in the handshake (`OPT_DELTA`) and only encodes
+//! once the orchestrator has echoed the option.
+//!
+//! Every encoded payload starts with a kind byte:
+//!
+//!   [KEYFRAME][payload]
+//!   [DELTA][varint len]([varint same][varint lit][lit bytes])*
+//!
+//! A delta op copies `same` bytes from the previous payload at the same
+//! offset, then takes `lit` literal bytes; ops run until `len` bytes are
+//! rebuilt. Offsets line up, so this suits fixed-layout records best. The
+//! encoder sends a keyframe first, then at least every `keyframe_every`
+//! payloads, and whenever a delta would not be smaller.
+
+use std::io;
+
+use crate::MAX_FRAME_SIZE;
+
+pub const KEYFRAME: u8 = 0;
+pub const DELTA: u8    = 1;
+
+/// An equal run shorter than this is cheaper to send as literal bytes than
+/// to break the literal for.
+const MIN_MATCH: usize = 4;
+
+/// Producer side: turns each payload into a keyframe or a delta.
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    prev:           Vec<u8>,
+    /// Payloads since the last keyframe; None before the first.
+    since_key:      Option<u32>,
+    keyframe_every: u32,
+}
+
+impl Encoder {
+    /// Send a keyframe at least every `keyframe_every` payloads (1 means
+    /// keyframes only).
+    pub fn new(keyframe_every: u32) -> Self {
+        Self { prev: Vec::new(), since_key: None, keyframe_every: keyframe_every.max(1) }
+    }
+
+    /// Encode `payload` against the previous one and make it the new
+    /// reference. The result is at most one byte longer than `payload`.
+    pub fn encode(&mut self, payload: &[u8]) -> Vec<u8> {
+        let due = self.since_key.is_none_or(|n| n + 1 >= self.keyframe_every);
+        let delta = (!due).then(|| diff(&self.prev, payload))
+            .filter(|d| d.len() <= payload.len());
+        let out = match delta {
+            Some(d) => {
+                self.since_key = self.since_key.map(|n| n + 1);
+                d
+            }
+            None => {
+                self.since_key = Some(0);
+                let mut k = Vec::with_capacity(payload.len() + 1);
+                k.push(KEYFRAME);
+                k.extend_from_slice(payload);
+                k
+            }
+        };
+        self.prev.clear();
+        self.prev.extend_from_slice(payload);
+        out
+    }
+}
+
+/// Orchestrator side: rebuilds payloads from keyframes and deltas.
+#[derive(Debug, Clone, Default)]
+pub struct Decoder {
+    prev: Option<Vec<u8>>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("delta: {msg}"));
+        let (&kind, rest) = data.split_first().ok_or_else(|| bad("empty payload"))?;
+        let out = match kind {
+            KEYFRAME => rest.to_vec(),
+            DELTA => {
+                let prev = self.prev.as_deref().ok_or_else(|| bad("delta before any keyframe"))?;
+                let mut rd = rest;
+                let len = read_varint(&mut rd).ok_or_else(|| bad("truncated length"))?;
+                if len > MAX_FRAME_SIZE as u64 {
+                    return Err(bad("length exceeds MAX_FRAME_SIZE"));
+                }
+                let len = len as usize;
+                let mut out = Vec::with_capacity(len);
+                while out.len() < len {
+                    let same = read_varint(&mut rd).ok_or_else(|| bad("truncated op"))? as usize;
+                    let lit = read_varint(&mut rd).ok_or_else(|| bad("truncated op"))? as usize;
+                    let at = out.len();
+                    let copy = prev.get(at..at.saturating_add(same))
+                        .ok_or_else(|| bad("copy past the reference"))?;
+                    out.extend_from_slice(copy);
+                    let bytes = rd.get(..lit).ok_or_else(|| bad("truncated literal"))?;
+                    out.extend_from_slice(bytes);
+                    rd = &rd[lit..];
+                    if same == 0 && lit == 0 {
+                        return Err(bad("empty op"));
+                    }
+                }
+                if out.len() != len || !rd.is_empty() {
+                    return Err(bad("ops disagree with the length"));
+                }
+                out
+            }
+            k => return Err(bad(&format!("unknown kind 0x{k:02x}"))),
+        };
+        self.prev = Some(out.clone());
+        Ok(out)
+    }
+}
+
+fn diff(prev: &[u8], cur: &[u8]) -> Vec<u8> {
+    let eq = |i: usize| prev.get(i) == Some(&cur[i]);
+    // Does an equal run worth copying start at `i`?
+    let match_at = |i: usize| (i..cur.len().min(i + MIN_MATCH)).all(eq);
+    let mut out = vec![DELTA];
+    write_varint(&mut out, cur.len() as u64);
+    let mut i = 0;
+    while i < cur.len() {
+        let start = i;
+        while i < cur.len() && eq(i) {
+            i += 1;
+        }
+        let same = i - start;
+        let lit_start = i;
+        while i < cur.len() && !match_at(i) {
+            i += 1;
+        }
+        write_varint(&mut out, same as u64);
+        write_varint(&mut out, (i - lit_start) as u64);
+        out.extend_from_slice(&cur[lit_start..i]);
+    }
+    out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(rd: &mut &[u8]) -> Option<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = rd.split_first()?;
+        *rd = rest;
+        v |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn telemetry(t: u32) -> Vec<u8> {
+        // A fixed-layout record where only a counter and one reading move.
+        let mut r = b"station=alpha;mode=nominal;".to_vec();
+        r.extend_from_slice(&t.to_be_bytes());
+        r.extend_from_slice(&[0x42; 64]);
+        r.extend_from_slice(&(1000 + t / 10).to_be_bytes());
+        r.extend_from_slice(b";end");
+        r
+    }
+
+    #[test]
+    fn round_trips_and_shrinks_slow_changing_payloads() {
+        let mut enc = Encoder::new(16);
+        let mut dec = Decoder::new();
+        let (mut raw, mut wire) = (0, 0);
+        for t in 0..100 {
+            let p = telemetry(t);
+            let e = enc.encode(&p);
+            assert!(e.len() <= p.len() + 1);
+            assert_eq!(dec.decode(&e).unwrap(), p);
+            raw += p.len();
+            wire += e.len();
+        }
+        assert!(wire * 4 < raw, "{wire} bytes on the wire for {raw} of payload");
+    }
+
+    #[test]
+    fn keyframes_come_first_and_on_schedule() {
+        let mut enc = Encoder::new(3);
+        let kinds: Vec<u8> = (0..7).map(|t| enc.encode(&telemetry(t))[0]).collect();
+        assert_eq!(kinds, [KEYFRAME, DELTA, DELTA, KEYFRAME, DELTA, DELTA, KEYFRAME]);
+
+        // Unrelated payloads fall back to keyframes rather than grow.
+        let mut enc = Encoder::new(100);
+        enc.encode(b"aaaaaaaaaaaaaaaa");
+        assert_eq!(enc.encode(b"zzzzzzzzzzzzzzzzzzzz")[0], KEYFRAME);
+    }
+
+    #[test]
+    fn handles_length_changes_and_empty_payloads() {
+        let mut enc = Encoder::new(1000);
+        let mut dec = Decoder::new();
+        for p in [&b"0123456789abcdef"[..], b"0123456789", b"", b"0123456789abcdefXYZ", b"0123"] {
+            assert_eq!(dec.decode(&enc.encode(p)).unwrap(), p);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let mut dec = Decoder::new();
+        assert!(dec.decode(&[]).is_err());
+        assert!(dec.decode(&[DELTA, 1, 0, 1, b'x']).is_err(), "no reference yet");
+        dec.decode(&[KEYFRAME, b'a', b'b']).unwrap();
+        assert!(dec.decode(&[DELTA, 3, 3, 0]).is_err(), "copy past the reference");
+        assert!(dec.decode(&[DELTA, 2, 0, 5, b'x']).is_err(), "truncated literal");
+        assert!(dec.decode(&[DELTA, 2, 2, 0, 9]).is_err(), "trailing bytes");
+        assert!(dec.decode(&[7]).is_err());
+        assert_eq!(dec.decode(&[DELTA, 3, 1, 2, b'x', b'y']).unwrap(), b"axy");
+    }
+}
@@ -0,0 +1,428 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! GSSAPI (Kerberos) authentication on the control handshake, for
+//! facilities that mandate Kerberos across the login-node boundary. Built
+//! only with the `gssapi` feature; it links the system's MIT
+//! `libgssapi_krb5` directly, since no GSSAPI crate is vendored.
+//!
+//! A client asks for it with an empty `OPT_GSSAPI` option. The orchestrator
+//! answers `[u16 BE 0]`, like SCRAM, then a first reply: READY, or ERROR
+//! with a reason. Then the context tokens go back and forth — the client's
+//! as `[u32 BE len][token]`, the orchestrator's as
+//! `[u8 status][u32 BE len][token]` — until the orchestrator sends DONE
+//! (with its final token: mutual authentication is always requested) or
+//! ERROR. Then the role's usual reply follows.
+//!
+//! The client names the orchestrator's host-based service, e.g.
+//! `qpipe@orchestrator.example.org` (`ProducerOptions::kerberos`,
+//! `ConnectOptions::kerberos`, or `QPIPE_KRB5_SERVICE`), and takes its own
+//! credentials from the usual ticket cache. The orchestrator accepts with
+//! the default keytab (`KRB5_KTNAME`).
+
+use std::ffi::c_void;
+use std::io::{self, Read, Write};
+use std::ptr;
+
+pub const READY: u8    = 0;
+pub const CONTINUE: u8 = 1;
+pub const DONE: u8     = 2;
+pub const ERROR: u8    = 3;
+
+/// Kerberos tokens are a few KiB, up to ~64 KiB with large PACs.
+const MAX_TOKEN: usize = 1 << 20;
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::ffi::c_void;
+
+    pub type OM_uint32 = u32;
+
+    #[repr(C)]
+    pub struct gss_buffer_desc {
+        pub length: usize,
+        pub value:  *mut c_void,
+    }
+
+    #[repr(C)]
+    pub struct gss_OID_desc {
+        pub length:   OM_uint32,
+        pub elements: *mut c_void,
+    }
+
+    pub type gss_OID = *mut gss_OID_desc;
+    pub type gss_name_t = *mut c_void;
+    pub type gss_ctx_id_t = *mut c_void;
+    pub type gss_cred_id_t = *mut c_void;
+
+    pub const GSS_S_COMPLETE: OM_uint32 = 0;
+    pub const GSS_S_CONTINUE_NEEDED: OM_uint32 = 1;
+    pub const GSS_C_MUTUAL_FLAG: OM_uint32 = 2;
+    pub const GSS_C_GSS_CODE: i32 = 1;
+    pub const GSS_C_MECH_CODE: i32 = 2;
+
+    /// Calling and routine errors; the low bits are supplementary info.
+    pub fn is_error(major: OM_uint32) -> bool {
+        major & 0xffff_0000 != 0
+    }
+
+    #[link(name = "gssapi_krb5")]
+    unsafe extern "C" {
+        pub static GSS_C_NT_HOSTBASED_SERVICE: gss_OID;
+
+        pub fn gss_import_name(
+            minor: *mut OM_uint32,
+            name: *mut gss_buffer_desc,
+            name_type: gss_OID,
+            out: *mut gss_name_t,
+        ) -> OM_uint32;
+
+        pub fn gss_init_sec_context(
+            minor: *mut OM_uint32,
+            cred: gss_cred_id_t,
+            ctx: *mut gss_ctx_id_t,
+            target: gss_name_t,
+            mech: gss_OID,
+            req_flags: OM_uint32,
+            time_req: OM_uint32,
+            bindings: *mut c_void,
+            input: *mut gss_buffer_desc,
+            actual_mech: *mut gss_OID,
+            output: *mut gss_buffer_desc,
+            ret_flags: *mut OM_uint32,
+            time_rec: *mut OM_uint32,
+        ) -> OM_uint32;
+
+        pub fn gss_accept_sec_context(
+            minor: *mut OM_uint32,
+            ctx: *mut gss_ctx_id_t,
+            cred: gss_cred_id_t,
+            input: *mut gss_buffer_desc,
+            bindings: *mut c_void,
+            src_name: *mut gss_name_t,
+            mech: *mut gss_OID,
+            output: *mut gss_buffer_desc,
+            ret_flags: *mut OM_uint32,
+            time_rec: *mut OM_uint32,
+            delegated: *mut gss_cred_id_t,
+        ) -> OM_uint32;
+
+        pub fn gss_display_name(
+            minor: *mut OM_uint32,
+            name: gss_name_t,
+            out: *mut gss_buffer_desc,
+            out_type: *mut gss_OID,
+        ) -> OM_uint32;
+
+        pub fn gss_display_status(
+            minor: *mut OM_uint32,
+            status: OM_uint32,
+            status_type: i32,
+            mech: gss_OID,
+            message_context: *mut OM_uint32,
+            out: *mut gss_buffer_desc,
+        ) -> OM_uint32;
+
+        pub fn gss_release_buffer(minor: *mut OM_uint32, buf: *mut gss_buffer_desc) -> OM_uint32;
+        pub fn gss_release_name(minor: *mut OM_uint32, name: *mut gss_name_t) -> OM_uint32;
+        pub fn gss_delete_sec_context(
+            minor: *mut OM_uint32,
+            ctx: *mut gss_ctx_id_t,
+            output: *mut gss_buffer_desc,
+        ) -> OM_uint32;
+    }
+}
+
+use ffi::*;
+
+fn empty_buffer() -> gss_buffer_desc {
+    gss_buffer_desc { length: 0, value: ptr::null_mut() }
+}
+
+fn input_buffer(bytes: &[u8]) -> gss_buffer_desc {
+    gss_buffer_desc { length: bytes.len(), value: bytes.as_ptr() as *mut c_void }
+}
+
+/// Copy out and release a buffer GSSAPI allocated.
+fn take_buffer(mut buf: gss_buffer_desc) -> Vec<u8> {
+    if buf.value.is_null() {
+        return Vec::new();
+    }
+    // SAFETY: GSSAPI filled `buf` with `length` bytes at `value`.
+    let out = unsafe { std::slice::from_raw_parts(buf.value as *const u8, buf.length) }.to_vec();
+    let mut minor = 0;
+    // SAFETY: `buf` was allocated by GSSAPI and is released once.
+    unsafe { gss_release_buffer(&mut minor, &mut buf) };
+    out
+}
+
+/// The library's own description of a failed call.
+fn status_error(what: &str, major: OM_uint32, minor: OM_uint32) -> io::Error {
+    let mut msgs = Vec::new();
+    for (code, kind) in [(major, GSS_C_GSS_CODE), (minor, GSS_C_MECH_CODE)] {
+        let mut ctx = 0;
+        loop {
+            let (mut m, mut buf) = (0, empty_buffer());
+            // SAFETY: plain out-parameters; the buffer is taken below.
+            let rc = unsafe {
+                gss_display_status(&mut m, code, kind, ptr::null_mut(), &mut ctx, &mut buf)
+            };
+            if is_error(rc) {
+                break;
+            }
+            let text = String::from_utf8_lossy(&take_buffer(buf)).into_owned();
+            if !text.is_empty() {
+                msgs.push(text);
+            }
+            if ctx == 0 {
+                break;
+            }
+        }
+    }
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("gssapi: {what}: {}", msgs.join("; ")))
+}
+
+struct Name(gss_name_t);
+
+impl Name {
+    fn service(service: &str) -> io::Result<Self> {
+        let (mut minor, mut name) = (0, ptr::null_mut());
+        let mut buf = input_buffer(service.as_bytes());
+        // SAFETY: `buf` borrows `service` for the duration of the call.
+        let major = unsafe {
+            gss_import_name(&mut minor, &mut buf, GSS_C_NT_HOSTBASED_SERVICE, &mut name)
+        };
+        if is_error(major) {
+            return Err(status_error("import_name", major, minor));
+        }
+        Ok(Self(name))
+    }
+
+    fn display(&self) -> io::Result<String> {
+        let (mut minor, mut buf) = (0, empty_buffer());
+        // SAFETY: `self.0` is a valid name; the buffer is taken below.
+        let major = unsafe { gss_display_name(&mut minor, self.0, &mut buf, ptr::null_mut()) };
+        if is_error(major) {
+            return Err(status_error("display_name", major, minor));
+        }
+        String::from_utf8(take_buffer(buf))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "gssapi: principal is not UTF-8"))
+    }
+}
+
+impl Drop for Name {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            let mut minor = 0;
+            // SAFETY: released once, here.
+            unsafe { gss_release_name(&mut minor, &mut self.0) };
+        }
+    }
+}
+
+struct Context(gss_ctx_id_t);
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            let mut minor = 0;
+            // SAFETY: deleted once, here; no output token is wanted.
+            unsafe { gss_delete_sec_context(&mut minor, &mut self.0, ptr::null_mut()) };
+        }
+    }
+}
+
+/// One step of a context exchange: the token to send, if any, and whether
+/// the context is established.
+struct Step {
+    token:    Vec<u8>,
+    complete: bool,
+}
+
+/// Client side: a security context with `service@host`.
+struct Initiator {
+    target: Name,
+    ctx:    Context,
+}
+
+impl Initiator {
+    fn new(service: &str) -> io::Result<Self> {
+        Ok(Self { target: Name::service(service)?, ctx: Context(ptr::null_mut()) })
+    }
+
+    fn step(&mut self, input: Option<&[u8]>) -> io::Result<Step> {
+        let (mut minor, mut out, mut flags) = (0, empty_buffer(), 0);
+        let mut inp = input.map(input_buffer);
+        let inp_ptr = inp.as_mut().map_or(ptr::null_mut(), |b| b as *mut _);
+        // SAFETY: default credentials and mechanism; `inp` outlives the
+        // call and the output buffer is taken below.
+        let major = unsafe {
+            gss_init_sec_context(
+                &mut minor, ptr::null_mut(), &mut self.ctx.0, self.target.0, ptr::null_mut(),
+                GSS_C_MUTUAL_FLAG, 0, ptr::null_mut(), inp_ptr, ptr::null_mut(),
+                &mut out, &mut flags, ptr::null_mut(),
+            )
+        };
+        let token = take_buffer(out);
+        if is_error(major) {
+            return Err(status_error("init_sec_context", major, minor));
+        }
+        let complete = major & 0xffff == GSS_S_COMPLETE;
+        if complete && flags & GSS_C_MUTUAL_FLAG == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied, "gssapi: mutual authentication was not performed",
+            ));
+        }
+        Ok(Step { token, complete })
+    }
+}
+
+/// Orchestrator side: accepts one context with the default keytab.
+struct Acceptor {
+    ctx: Context,
+}
+
+impl Acceptor {
+    fn new() -> Self {
+        Self { ctx: Context(ptr::null_mut()) }
+    }
+
+    /// Feed one client token. Returns the token to send back, and once the
+    /// context is established, the client's principal.
+    fn step(&mut self, input: &[u8]) -> io::Result<(Vec<u8>, Option<String>)> {
+        let (mut minor, mut out, mut src) = (0, empty_buffer(), ptr::null_mut());
+        let mut inp = input_buffer(input);
+        // SAFETY: default acceptor credentials; `inp` borrows `input` for
+        // the call; the output buffer and name are taken below.
+        let major = unsafe {
+            gss_accept_sec_context(
+                &mut minor, &mut self.ctx.0, ptr::null_mut(), &mut inp, ptr::null_mut(),
+                &mut src, ptr::null_mut(), &mut out, ptr::null_mut(), ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        let token = take_buffer(out);
+        let src = Name(src);
+        if is_error(major) {
+            return Err(status_error("accept_sec_context", major, minor));
+        }
+        match major & 0xffff {
+            GSS_S_COMPLETE => Ok((token, Some(src.display()?))),
+            GSS_S_CONTINUE_NEEDED => Ok((token, None)),
+            s => Err(status_error("accept_sec_context", s, minor)),
+        }
+    }
+}
+
+fn write_token<W: Write>(w: &mut W, status: Option<u8>, token: &[u8]) -> io::Result<()> {
+    if let Some(status) = status {
+        w.write_all(&[status])?;
+    }
+    w.write_all(&(token.len() as u32).to_be_bytes())?;
+    w.write_all(token)?;
+    w.flush()
+}
+
+fn read_token<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_TOKEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "gssapi: token too large"));
+    }
+    let mut tok = vec![0u8; len];
+    r.read_exact(&mut tok)?;
+    Ok(tok)
+}
+
+fn read_reply<R: Read>(r: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut status = [0u8; 1];
+    r.read_exact(&mut status)?;
+    let tok = read_token(r)?;
+    if status[0] == ERROR {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("orchestrator refused authentication: {}", String::from_utf8_lossy(&tok)),
+        ));
+    }
+    Ok((status[0], tok))
+}
+
+/// Tell the client why it was refused.
+pub fn refuse<W: Write>(w: &mut W, reason: &str) -> io::Result<()> {
+    write_token(w, Some(ERROR), reason.as_bytes())
+}
+
+/// Run the client side on a control connection, after the role byte and an
+/// option block carrying `OPT_GSSAPI`. `service` is a host-based service
+/// name such as `qpipe@orchestrator.example.org`.
+pub fn authenticate<S: Read + Write>(s: &mut S, service: &str) -> io::Result<()> {
+    let mut marker = [0u8; 2];
+    match s.read_exact(&mut marker) {
+        Ok(()) if marker == [0, 0] => {}
+        Ok(()) => return Err(io::Error::new(
+            io::ErrorKind::Unsupported, "orchestrator does not support authentication",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(io::Error::new(
+            io::ErrorKind::Unsupported, "orchestrator does not support authentication",
+        )),
+        Err(e) => return Err(e),
+    }
+    match read_reply(s)? {
+        (READY, _) => {}
+        (st, _) => return Err(io::Error::new(
+            io::ErrorKind::InvalidData, format!("gssapi: unexpected status {st}"),
+        )),
+    }
+    let mut init = Initiator::new(service)?;
+    let mut step = init.step(None)?;
+    loop {
+        write_token(s, None, &step.token)?;
+        let (status, token) = read_reply(s)?;
+        match status {
+            CONTINUE if !step.complete => step = init.step(Some(&token))?,
+            DONE => {
+                if !step.complete {
+                    step = init.step(Some(&token))?;
+                }
+                return match step.complete {
+                    true => Ok(()),
+                    false => Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "gssapi: orchestrator finished before the context was established",
+                    )),
+                };
+            }
+            st => return Err(io::Error::new(
+                io::ErrorKind::InvalidData, format!("gssapi: unexpected status {st}"),
+            )),
+        }
+    }
+}
+
+/// Run the orchestrator side after the marker and READY: exchange tokens
+/// until the context is established, and return the client's principal.
+/// The caller decides whether to send DONE (with the returned token) or
+/// refuse.
+pub fn accept<S: Read + Write>(s: &mut S) -> io::Result<(String, Vec<u8>)> {
+    let mut acceptor = Acceptor::new();
+    loop {
+        let token = read_token(s)?;
+        match acceptor.step(&token) {
+            Ok((out, Some(principal))) => return Ok((principal, out)),
+            Ok((out, None)) => write_token(s, Some(CONTINUE), &out)?,
+            Err(e) => {
+                refuse(s, "context not established")?;
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Say the orchestrator is ready for the client's first token.
+pub fn ready<W: Write>(w: &mut W) -> io::Result<()> {
+    write_token(w, Some(READY), &[])
+}
+
+/// Finish a successful exchange with the acceptor's last token.
+pub fn done<W: Write>(w: &mut W, token: &[u8]) -> io::Result<()> {
+    write_token(w, Some(DONE), token)
+}
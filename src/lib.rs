@@ -10,47 +10,364 @@
 //!   - flag clear: the frame body is one complete message — wire-identical
 //!     to the original single-frame protocol.
 //!   - flag set:   the frame body is one chunk of a multi-frame message:
-//!       [u128 msg_id BE][u32 chunk idx BE][u32 chunk count BE][chunk bytes]
-//! Every frame (single or chunk) is acknowledged with one ACK_PAYLOAD byte.
+//!     `[u128 msg_id BE][u32 chunk idx BE][u32 chunk count BE][chunk bytes]`
+//!
+//! Bit 30 is the CTRL flag: the body is `[u8 kind][kind-specific bytes]`
+//! and carries no application payload (see `CTRL_EOS`).
+//! Every frame (single, chunk, or control) is acknowledged with one
+//! ACK_PAYLOAD byte.
 //! Session: connect to control port, send role byte, receive
 //! (ephemeral_port, token), then connect to ephemeral_port and send token.
+//! With `OPT_SINGLE_PORT` the token goes back over the control connection
+//! instead, and the data phase follows on it.
 //!
 //! Multi-frame messages: `Producer::send` transparently chunks payloads
-//! larger than MAX_FRAME_SIZE; smaller payloads use the original
-//! single-frame path unchanged. The orchestrator routes every chunk of a
+//! larger than the orchestrator's frame-size limit (MAX_FRAME_SIZE unless
+//! it advertises a smaller one, see `OPT_MAX_FRAME`); smaller payloads use
+//! the original single-frame path unchanged. The orchestrator routes every chunk of a
 //! message to whichever consumer claimed its first chunk; chunks may reach
 //! that consumer out of order. `Consumer::recv` reassembles internally and
 //! returns complete messages in COMPLETION order. Partials can be orphaned
 //! if a producer dies mid-message — call `Consumer::gc_partials`
 //! periodically to discard them.
+//!
+//! End of stream: `Producer::send_eos` enqueues a marker behind everything
+//! the producer has sent. Once every data frame accepted before the marker
+//! has been delivered, the orchestrator hands an EOS notice (carrying an
+//! opaque group label; empty = the whole queue) to every connected consumer.
+//! `Consumer::recv_ext` surfaces it as `Delivery::Eos`; plain `recv` skips
+//! it, so EOS-unaware consumers behave exactly as before.
+//!
+//! Ack mode: `Consumer::connect_with` + `ConnectOptions::ack_mode` asks the
+//! orchestrator to settle messages on `Consumer::ack(tag)` instead of on
+//! receipt. Deliveries carry their tag and attempt count in an optional
+//! per-frame metadata block (bit 29, see `Meta`); unacked messages are
+//! redelivered per the orchestrator's retry policy. An orchestrator may fix
+//! a queue's `DeliveryPolicy` instead, and tells consumers in the handshake
+//! (`OPT_DELIVERY`): ack mode is then refused on an at-most-once queue and
+//! imposed on an at-least-once one.
+//!
+//! Buffered producers: `Producer::connect_with` + `ProducerOptions::buffer`
+//! moves the network writes to a background thread behind a bounded queue,
+//! so `send` doesn't wait for the orchestrator's ACK; `ProducerOptions::
+//! flush_policy` instead pipelines frames on the connection and collects
+//! their ACKs in batches; `ProducerOptions::delta` sends payloads as deltas
+//! against the previous one (see `delta`). `ReconnectingProducer`
+//! reconnects after failures and can journal sends to a spool directory
+//! while the orchestrator is unreachable; both reconnecting clients can
+//! fail over to a standby orchestrator (`ReconnectOptions::standby`).
+//!
+//! Prefetching consumers: `BufferedConsumer` moves a consumer's receives to
+//! a background thread that reads ahead into a bounded queue, so processing
+//! overlaps with waiting for the network; acks still go out directly.
+//!
+//! Heartbeats: `ProducerOptions::heartbeat` / `ConnectOptions::heartbeat`
+//! ping quiet connections (`CTRL_PING` frames one way, `ACK_PING` records
+//! on a consumer's back-channel the other) and time out silent peers, on
+//! the client and in the orchestrator alike.
+//!
+//! Checksums: `ProducerOptions::checksum` / `ConnectOptions::checksum` end
+//! every frame of the session with a CRC-32C (`FRAME_FLAG_CRC`, see
+//! `checksum`), verified on receipt.
+//!
+//! Single port: `ProducerOptions::single_port` / `ConnectOptions::
+//! single_port` keep the whole session on the control connection, for
+//! firewalls that let only the orchestrator's port through.
+//!
+//! Named queues: `Producer::connect_to` / `Consumer::connect_to` (or the
+//! `queue` option) pick one of the orchestrator's queues by name; each is
+//! routed independently. Sessions without a name use the default queue.
+//!
+//! Transports: every function taking an orchestrator address also accepts
+//! `unix://<path>` for a Unix domain socket (see `transport`), and with the
+//! `ws` feature `ws://host:port/path` for an orchestrator's WebSocket
+//! listener (`Producer::connect_ws` / `Consumer::connect_ws`, see `ws`).
+//! `mem://<name>` reaches an orchestrator in the same process without
+//! sockets, for tests (see `mem`). Other transports (TLS, QUIC, ...) plug
+//! in through `transport::register`.
+//!
+//! Authentication: orchestrators with users configured require SCRAM-SHA-256
+//! on the control handshake (see `scram`). Clients use
+//! `ProducerOptions::credentials` / `ConnectOptions::credentials`, or else
+//! `QPIPE_USER` and `QPIPE_PASSWORD`. With the `gssapi` feature, Kerberos
+//! works the same way (`kerberos`, `QPIPE_KRB5_SERVICE`; see `gssapi`).
+//! Orchestrators started with `--auth-key` also require a pre-shared key
+//! (`auth_key`, or `QPIPE_AUTH_KEY_FILE`; see `psk`), before any of these.
+//!
+//! Event loops: `codec::FrameDecoder` and `codec::FrameEncoder` speak the
+//! framing without doing any I/O, for non-blocking sockets of your own.
+//!
+//! Buffers: frame payloads are read into buffers from `pool`, which the
+//! orchestrator refills as frames are delivered; `Consumer::recv_into`
+//! reuses the caller's buffer the same way.
+//!
+//! Compression: with the `zstd` feature, `ProducerOptions::compress` sends
+//! large payloads zstd-compressed; the orchestrator passes them through and
+//! consumers decompress them in `recv` (see `zstd`).
+//!
+//! Embedding: `orchestrator::Orchestrator` runs the orchestrator inside any
+//! binary, e.g. for tests or single-process deployments.
+//!
+//! Administration: `admin::Admin` lists a running orchestrator's sessions
+//! and queues, pauses, refuses and resumes intake, and purges queues.
+//!
+//! Typed payloads: with the `serde` feature, `typed::TypedProducer` and
+//! `typed::TypedConsumer` send and receive serde values, one per message.
 
-use std::collections::HashMap;
-use std::io::{self, Read, Write};
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::borrow::BorrowMut;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::iter::FusedIterator;
+use std::io::{self, BufRead, BufWriter, IoSlice, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use rand::{rngs::SysRng, TryRng};
 
+pub mod admin;
+pub mod at_rest;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod checksum;
+pub mod codec;
+pub mod config;
+pub mod delta;
+pub mod digest;
+#[cfg(feature = "gssapi")]
+pub mod gssapi;
+pub mod mem;
+pub mod orchestrator;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod overflow;
+pub mod pool;
+pub mod psk;
+#[cfg(target_os = "linux")]
+mod reactor;
+mod replica;
+pub mod rpc;
+pub mod scram;
+mod spool;
+#[cfg(unix)]
+pub mod systemd;
+pub mod transport;
+#[cfg(feature = "serde")]
+pub mod typed;
+pub mod wal;
+#[cfg(feature = "ws")]
+pub mod ws;
+#[cfg(feature = "zstd")]
+pub mod zstd;
+use scram::Credentials;
+use spool::{Record, Spool};
+use transport::{Addr, SocketOptions, Stream};
+
 pub const ROLE_PRODUCER: u8    = b'P';
 pub const ROLE_CONSUMER: u8    = b'C';
 pub const ROLE_HEALTHCHECK: u8 = b'H';
 pub const ROLE_DRAIN: u8       = b'D';
 pub const ROLE_SHUTDOWN: u8    = b'S';
+pub const ROLE_WAIT_IDLE: u8   = b'W';
 pub const ACK_PAYLOAD: u8      = b'A';
 pub const ACK_HEALTH: u8       = b'H';
 pub const ACK_SHUTDOWN: u8     = b'S';
 pub const ACK_DRAIN: u8        = b'D';
+pub const ACK_IDLE: u8         = b'W';
+
+/// Export request `[ROLE_EXPORT][EXPORT_COPY | EXPORT_DRAIN]`. The reply is
+/// `[u32 BE count]` and that many frames in `put_frame` format (no ACKs),
+/// carrying their metadata. After a drain the client confirms with
+/// `[ACK_EXPORT]` once the frames are safe, and the orchestrator answers
+/// `[ACK_EXPORT]` when they have left the queue; without the confirmation
+/// they are requeued. See `export_queue`.
+///
+/// Import request `[ROLE_EXPORT][EXPORT_IMPORT][u32 BE count]`, then that
+/// many frames in the same format. The orchestrator queues them, in order,
+/// once it has them all, and answers `[ACK_EXPORT]`. See `import_queue`.
+pub const ROLE_EXPORT: u8      = b'X';
+pub const ACK_EXPORT: u8       = b'X';
+pub const EXPORT_COPY: u8      = b'C';
+pub const EXPORT_DRAIN: u8     = b'D';
+pub const EXPORT_IMPORT: u8    = b'I';
+
+/// Stats subscription `[ROLE_STATS]`. The orchestrator answers
+/// `[ACK_STATS]`, then writes every stats report as one line of JSON until
+/// it starts draining. See `watch_stats`.
+pub const ROLE_STATS: u8       = b'T';
+pub const ACK_STATS: u8        = b'T';
+
+/// Admin session `[ROLE_ADMIN]`: the orchestrator answers `[ACK_ADMIN]` and
+/// serves requests until the client hangs up. See `admin`.
+pub const ROLE_ADMIN: u8       = b'M';
+pub const ACK_ADMIN: u8        = b'M';
+
+/// Replication session `[ROLE_REPLICATE]`, opened by a standby
+/// orchestrator on its primary (see `OrchestratorOptions::standby_of`).
+/// The primary answers `[ACK_REPLICATE]`, then streams what its
+/// write-ahead logs hold and every record appended to them, until the
+/// standby hangs up.
+pub const ROLE_REPLICATE: u8   = b'R';
+pub const ACK_REPLICATE: u8    = b'R';
+
+/// Consumer back-channel record `[ACK_MESSAGE][u64 BE delivery tag]`: the
+/// message with that tag was processed (ack-mode sessions only; see
+/// `ConnectOptions::ack_mode`).
+pub const ACK_MESSAGE: u8      = b'K';
+
+/// Consumer back-channel record `[ACK_WINDOW][u32 BE window]`: from now on
+/// keep at most `window` unacked deliveries in flight to this consumer; 0
+/// pauses new deliveries (ack-mode sessions only; see `Consumer::set_window`).
+pub const ACK_WINDOW: u8       = b'N';
+
+/// Consumer back-channel record `[ACK_NACK][u64 BE delivery tag][u32 BE
+/// retries]`: the message with that tag failed. It is requeued at once, as
+/// if its visibility timeout had passed, unless it has already been
+/// redelivered `retries` times (or the retry policy's count, if lower) —
+/// then it is dead-lettered. `OPT_NACK` sessions only; see `Consumer::nack`.
+pub const ACK_NACK: u8         = b'F';
+
+/// Consumer back-channel record `[ACK_PING]`: the consumer is alive, sent
+/// every heartbeat interval (see `ConnectOptions::heartbeat`).
+pub const ACK_PING: u8         = b'P';
+
+/// Consumer back-channel record `[ACK_BYE]`: the consumer is leaving. The
+/// orchestrator stops dispatching to it, requeues what it holds and hangs
+/// up (`OPT_GOODBYE` sessions only; see `Consumer::close`).
+pub const ACK_BYE: u8          = b'B';
+
+/// Sent to a producer in place of `ACK_PAYLOAD` for a frame the
+/// orchestrator refused because the producer is over its rate limit,
+/// because an admin refuses intake (`Admin::refuse`), or because the queue
+/// is full and refuses new messages; the frame was not queued (`OPT_THROTTLE` sessions only; see
+/// `ProducerOptions::throttle_errors`).
+pub const ACK_THROTTLED: u8    = b'R';
+
+/// A producer or consumer opens its control connection with a hello,
+/// `[HELLO_MAGIC][u8 min][u8 max]`: the range of protocol versions it
+/// speaks. The orchestrator answers `[HELLO_MAGIC][u8 version]` with the
+/// newest version both speak, or 0 — and hangs up — if there is none; the
+/// role byte follows as before. The magic's first byte is no role byte, so
+/// an orchestrator from before the hello hangs up on it as on an unknown
+/// role, and the client starts over with the original handshake:
+/// `PROTOCOL_V1`.
+pub const HELLO_MAGIC: [u8; 4]  = *b"QPIP";
+
+/// The original handshake, without a hello.
+pub const PROTOCOL_V1: u8       = 1;
+/// The hello, then everything negotiated through handshake options.
+pub const PROTOCOL_V2: u8       = 2;
+/// The newest protocol version this build speaks.
+pub const PROTOCOL_VERSION: u8  = PROTOCOL_V2;
+
+/// OR'ed into the role byte when the client follows it with a handshake
+/// option block `[u16 BE len][TLV records]`. The orchestrator then appends
+/// an option block of its own to the `(port, token)` reply, echoing the
+/// options it accepted. Plain role bytes keep the original handshake.
+pub const ROLE_FLAG_OPTS: u8   = 0x80;
+
+/// Handshake option keys.
+pub const OPT_ACK_MODE: u8      = 1; // empty; consumer settles messages by ack
+pub const OPT_VISIBILITY_MS: u8 = 2; // u64 BE; reply only: ack deadline
+pub const OPT_WEIGHT: u8        = 3; // u32 BE; ack-mode in-flight window
+pub const OPT_CAPABILITIES: u8  = 4; // comma-separated consumer capability tags
+pub const OPT_RESOURCES: u8     = 5; // [u64 BE memory bytes][u32 BE gpus]
+pub const OPT_DELTA: u8         = 6; // empty; producer sends delta-encoded payloads
+pub const OPT_SCRAM: u8         = 7; // SCRAM client-first message (see `scram`)
+pub const OPT_GSSAPI: u8        = 8; // empty; Kerberos exchange follows (see `gssapi`)
+pub const OPT_QUEUE: u8         = 9; // queue name (see `check_queue_name`)
+pub const OPT_COMPRESS: u8      = 10; // u8 codec; producer sends compressed payloads
+pub const OPT_AUTH_KEY: u8      = 11; // client nonce; pre-shared key exchange follows (see `psk`)
+pub const OPT_HEARTBEAT: u8     = 12; // [u32 BE interval ms][u32 BE timeout ms] (see `ProducerOptions::heartbeat`)
+pub const OPT_CHECKSUM: u8      = 13; // empty; data frames both ways carry FRAME_FLAG_CRC
+pub const OPT_SINGLE_PORT: u8   = 14; // empty; the data phase runs over the control connection
+pub const OPT_THROTTLE: u8      = 15; // empty; producer takes ACK_THROTTLED for refused frames
+pub const OPT_NACK: u8          = 16; // empty; ack-mode consumer may send ACK_NACK
+pub const OPT_CLIENT_NAME: u8   = 17; // client label (see `check_client_name`)
+pub const OPT_MAX_FRAME: u8     = 18; // empty; reply: u32 BE frame-size limit the orchestrator enforces
+pub const OPT_GOODBYE: u8       = 19; // empty; session may end with CTRL_BYE / ACK_BYE (see `Producer::close`)
+pub const OPT_FILTER: u8        = 20; // consumers only, `Filter` spec; echoed empty
+pub const OPT_DELIVERY: u8      = 21; // consumers only, empty; reply: u8 `DeliveryPolicy` of the session
+
+/// Payload codecs (`OPT_COMPRESS`, `META_CODEC`).
+pub const CODEC_ZSTD: u8 = 1;
+
+/// Delivery policies (`OPT_DELIVERY`).
+pub const DELIVERY_AT_MOST_ONCE: u8  = 1;
+pub const DELIVERY_AT_LEAST_ONCE: u8 = 2;
+
+/// Longest accepted queue name, in bytes.
+pub const MAX_QUEUE_NAME: usize = 128;
+
+/// Queues whose names start with this are reply queues (see `rpc`): the
+/// orchestrator neither journals nor overflows them, and drops one, with
+/// whatever it still holds, once its last session has ended.
+pub const REPLY_QUEUE_PREFIX: &str = "_reply/";
+
+/// Longest accepted subscription filter spec (`Filter`), in bytes.
+pub const MAX_FILTER_LEN: usize = 1024;
+
+/// Longest accepted client name, in bytes.
+pub const MAX_CLIENT_NAME: usize = 64;
 
 pub const TOKEN_LEN: usize = 16;
 pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
 
+/// Smallest frame-size limit an orchestrator may set (`OPT_MAX_FRAME`),
+/// which keeps chunks of a useful size: a message may still take up to
+/// MAX_CHUNKS of them.
+pub const MIN_FRAME_LIMIT: usize = 64 * 1024; // 64 KiB
+
 /// Bit 31 of the length prefix. MAX_FRAME_SIZE needs only 25 bits, so the
 /// flag can never collide with a valid single-frame length. An old reader
 /// that receives a chunk frame sees an absurd length and fails loudly
 /// ("incoming frame too large") rather than silently mis-framing.
 pub const FRAME_FLAG_CHUNK: u32 = 0x8000_0000;
 
+/// Bit 30 of the length prefix: the body is a control frame. Like the chunk
+/// flag, an old reader sees an absurd length and fails loudly.
+pub const FRAME_FLAG_CTRL: u32 = 0x4000_0000;
+
+/// Control frame kinds (first body byte of a CTRL frame).
+pub const CTRL_EOS: u8 = b'E';
+pub const CTRL_PING: u8 = b'P'; // empty body; its ACK is the answer
+pub const CTRL_BYE: u8 = b'B'; // empty body; the producer's last frame
+
+/// Bit 29 of the length prefix: the body starts with a metadata block
+/// `[u16 BE len][TLV records]` (see `Meta`). Combines with CHUNK and CTRL.
+pub const FRAME_FLAG_META: u32 = 0x2000_0000;
+
+/// Metadata keys (TLV records inside a META block).
+pub const META_DELIVERY: u8 = 1; // u64 BE delivery tag
+pub const META_ATTEMPT: u8  = 2; // u32 BE prior failed deliveries
+pub const META_PRIORITY: u8 = 3; // u8, higher is served first
+pub const META_REQUIRES: u8 = 4; // comma-separated required capability tags
+pub const META_MEMORY: u8   = 5; // u64 BE estimated peak memory, bytes
+pub const META_GPUS: u8     = 6; // u32 BE GPUs needed
+pub const META_RUNTIME: u8  = 7; // u64 BE expected runtime, milliseconds
+pub const META_HEADERS: u8  = 8; // application headers (see `Headers`)
+pub const META_CODEC: u8    = 9; // u8 codec the message payload is compressed with
+pub const META_PARTITION: u8 = 10; // partition key; one consumer at a time per key
+pub const META_TTL: u8      = 11; // u64 BE time to live once queued, milliseconds
+pub const META_DELAY: u8    = 12; // u64 BE hold back from consumers, milliseconds
+pub const META_MESSAGE_ID: u8 = 13; // producer-assigned id; repeats are dropped
+pub const META_QUEUE: u8    = 14; // UTF-8 name of the queue an exported frame came from
+
+/// Largest encodable metadata block (its length prefix is a u16).
+pub const MAX_META_LEN: usize = u16::MAX as usize;
+
+/// Bit 28 of the length prefix: the body is followed by a `[u32 BE]`
+/// CRC-32C (see `checksum`) of the length prefix and the body. Readers
+/// verify it wherever it is set; sessions that negotiate `OPT_CHECKSUM`
+/// set it on every frame and reject frames without it. Combines with every
+/// other flag, and like them makes an old reader fail loudly.
+pub const FRAME_FLAG_CRC: u32 = 0x1000_0000;
+
+/// Mask of every flag bit in the length prefix.
+const FRAME_FLAGS: u32 = FRAME_FLAG_CHUNK | FRAME_FLAG_CTRL | FRAME_FLAG_META | FRAME_FLAG_CRC;
+
 /// Chunk extension header: u128 message id + u32 idx + u32 count.
 pub const CHUNK_HEADER_LEN: usize = 16 + 4 + 4;
 
@@ -65,13 +382,21 @@ pub const MAX_CHUNK_PAYLOAD: usize = MAX_FRAME_SIZE - CHUNK_HEADER_LEN;
 pub const MAX_CHUNKS: u32 = 4096;
 pub const MAX_MESSAGE_SIZE: usize = MAX_CHUNK_PAYLOAD * (MAX_CHUNKS as usize);
 
-/// One frame on the wire, post-parse.
+/// One frame on the wire, post-parse. New kinds of frame may be added in
+/// minor releases, so matches need a wildcard arm.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Frame {
     /// A complete single-frame message (the original protocol).
     Msg(Vec<u8>),
     /// One chunk of a multi-frame message.
     Chunk { id: u128, idx: u32, count: u32, payload: Vec<u8> },
+    /// End-of-stream marker for a group (empty group = the whole queue).
+    Eos(Vec<u8>),
+    /// Heartbeat on an otherwise quiet connection.
+    Ping,
+    /// A producer's clean end of its session.
+    Bye,
 }
 
 impl Frame {
@@ -79,18 +404,68 @@ impl Frame {
         match self {
             Frame::Msg(p) => p.len(),
             Frame::Chunk { payload, .. } => payload.len(),
+            Frame::Eos(_) | Frame::Ping | Frame::Bye => 0, // control frames carry no application bytes
+        }
+    }
+
+    /// Done with the frame: give its payload buffer back to the `pool`.
+    pub fn recycle(self) {
+        if let Frame::Msg(payload) | Frame::Chunk { payload, .. } = self {
+            pool::recycle(payload);
         }
     }
 }
 
+/// A complete application message, as handed out by `Consumer::recv_ext`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub payload: Vec<u8>,
+    /// Delivery tag to pass to `Consumer::ack`. `None` unless the session is
+    /// in ack mode.
+    pub tag:     Option<u64>,
+    /// How many earlier deliveries of this message went unacknowledged
+    /// (0 on first delivery, and always 0 outside ack mode).
+    pub attempt: u32,
+    /// What the producer attached with `Producer::send_with_headers` (or
+    /// `Meta::headers`); empty if nothing was.
+    pub headers: Headers,
+}
+
+#[cfg(feature = "otel")]
+impl Message {
+    /// The trace context the message was sent in; see `otel`.
+    pub fn trace_context(&self) -> Option<otel::TraceContext> {
+        otel::extract(&self.headers)
+    }
+}
+
+/// What `Consumer::recv_writer` received, besides the bytes it wrote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Streamed {
+    /// Bytes written.
+    pub len:     u64,
+    /// As in `Message`.
+    pub tag:     Option<u64>,
+    pub attempt: u32,
+    pub headers: Headers,
+}
+
+/// One item handed out by `Consumer::recv_ext`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    /// A complete application message.
+    Message(Message),
+    /// Every message enqueued before a producer's `send_eos(group)` has been
+    /// delivered (to some consumer). `group` is the producer's label.
+    Eos(Vec<u8>),
+}
+
 /// Single-shot health probe. Opens a control connection, sends the
 /// healthcheck role byte, and waits for the orchestrator's ack. Succeeds
 /// only when the orchestrator is alive and processing role bytes — not
 /// just that the TCP listener accepted the socket.
 pub fn healthcheck(orchestrator: &str) -> io::Result<()> {
-    let addr = resolve_first(orchestrator)?;
-    let mut s = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
-    s.set_nodelay(true).ok();
+    let mut s = Addr::resolve(orchestrator)?.connect(Some(Duration::from_secs(5)))?;
     s.set_read_timeout(Some(Duration::from_secs(5))).ok();
     s.set_write_timeout(Some(Duration::from_secs(5))).ok();
 
@@ -123,16 +498,16 @@ pub fn wait_until_healthy(
         match healthcheck(orchestrator) {
             Ok(()) => return Ok(()),
             Err(e) => {
-                if let Some(t) = timeout {
-                    if start.elapsed() >= t {
-                        return Err(io::Error::new(
-                            io::ErrorKind::TimedOut,
-                            format!(
-                                "orchestrator at {} not healthy within {:?}: {}",
-                                orchestrator, t, e
-                            ),
-                        ));
-                    }
+                if let Some(t) = timeout
+                    && start.elapsed() >= t
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "orchestrator at {} not healthy within {:?}: {}",
+                            orchestrator, t, e
+                        ),
+                    ));
                 }
                 thread::sleep(delay);
                 delay = (delay * 2).min(max_delay);
@@ -148,14 +523,11 @@ pub fn wait_until_healthy(
 /// A subsequent `request_shutdown` will override the drain and impose the
 /// usual timeout.
 pub fn request_drain(orchestrator: &str) -> io::Result<()> {
-    let addr = resolve_first(orchestrator)?;
-    let mut s = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
-    s.set_nodelay(true).ok();
+    let mut s = Addr::resolve(orchestrator)?.connect(Some(Duration::from_secs(5)))?;
     s.set_read_timeout(Some(Duration::from_secs(10))).ok();
     s.set_write_timeout(Some(Duration::from_secs(5))).ok();
 
-    s.write_all(&[ROLE_DRAIN])?;
-    s.flush()?;
+    open_control(&mut s, ROLE_DRAIN, &[], None, None)?;
 
     let mut ack = [0u8; 1];
     s.read_exact(&mut ack)?;
@@ -178,14 +550,11 @@ pub fn request_drain(orchestrator: &str) -> io::Result<()> {
 ///   - orchestrator closed the socket without acking
 ///   - ack byte was something other than `ACK_SHUTDOWN`
 pub fn request_shutdown(orchestrator: &str) -> io::Result<()> {
-    let addr = resolve_first(orchestrator)?;
-    let mut s = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
-    s.set_nodelay(true).ok();
+    let mut s = Addr::resolve(orchestrator)?.connect(Some(Duration::from_secs(5)))?;
     s.set_read_timeout(Some(Duration::from_secs(10))).ok();
     s.set_write_timeout(Some(Duration::from_secs(5))).ok();
 
-    s.write_all(&[ROLE_SHUTDOWN])?;
-    s.flush()?;
+    open_control(&mut s, ROLE_SHUTDOWN, &[], None, None)?;
 
     let mut ack = [0u8; 1];
     s.read_exact(&mut ack)?;
@@ -198,14 +567,476 @@ pub fn request_shutdown(orchestrator: &str) -> io::Result<()> {
     Ok(())
 }
 
-fn resolve_first(addr: &str) -> io::Result<SocketAddr> {
-    addr.to_socket_addrs()?
-        .next()
-        .ok_or_else(
-            || io::Error::new(
-                io::ErrorKind::InvalidInput, "could not resolve address"
-            )
-        )
+/// Block until the orchestrator's queue `queue` is idle: every data frame it
+/// has accepted was delivered (or dropped) and no producer is connected to
+/// it. `None` waits for every queue at once. This is the "wait for the whole
+/// campaign to drain" barrier — unlike drain and shutdown it acks on
+/// COMPLETION, not on receipt, and leaves the orchestrator running.
+///
+/// Idle is evaluated on the orchestrator's current state, so call this once
+/// producers have connected (or already finished); before any producer has
+/// shown up, an empty queue is trivially idle, as is one that doesn't exist.
+/// A `timeout` of `None` waits forever.
+pub fn wait_for_idle(
+            orchestrator: &str,
+            queue:        Option<&str>,
+            timeout:      Option<Duration>,
+        ) -> io::Result<()> {
+    if let Some(name) = queue {
+        check_queue_name(name)?;
+    }
+    let mut s = Addr::resolve(orchestrator)?.connect(Some(Duration::from_secs(5)))?;
+    s.set_read_timeout(timeout).ok();
+    s.set_write_timeout(Some(Duration::from_secs(5))).ok();
+
+    let opts: Vec<(u8, &[u8])> = queue.map(|q| (OPT_QUEUE, q.as_bytes())).into_iter().collect();
+    open_control(&mut s, ROLE_WAIT_IDLE, &opts, None, None)?;
+
+    let mut ack = [0u8; 1];
+    match s.read_exact(&mut ack) {
+        Ok(()) => {}
+        Err(e) if matches!(
+            e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("orchestrator at {orchestrator} not idle within {timeout:?}"),
+            ));
+        }
+        Err(e) => return Err(e),
+    }
+    if ack[0] != ACK_IDLE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected idle ack: 0x{:02x}", ack[0]),
+        ));
+    }
+    Ok(())
+}
+
+/// Export the messages an orchestrator has queued — every frame waiting
+/// for a consumer, with its metadata, highest priority first and FIFO
+/// within — and hand them to `keep`. Every queue is exported, the default
+/// one first; `Meta::queue` names the others' frames. Frames already
+/// handed to a consumer (including the rest of a message a consumer has
+/// started) are not included, nor are reply queues.
+///
+/// With `drain` the frames leave the queue, but only if `keep` returns Ok:
+/// persist them there. If `keep` fails, or the connection breaks before
+/// the orchestrator confirms, they are requeued as they were. Without
+/// `drain` this is a snapshot and the queue is untouched. Returns how many
+/// frames were exported.
+pub fn export_queue<F>(orchestrator: &str, drain: bool, keep: F) -> io::Result<usize>
+where
+    F: FnOnce(&[(Frame, Meta)]) -> io::Result<()>,
+{
+    let mut s = Addr::resolve(orchestrator)?.connect(Some(Duration::from_secs(5)))?;
+    s.set_read_timeout(Some(Duration::from_secs(30))).ok();
+    s.set_write_timeout(Some(Duration::from_secs(5))).ok();
+
+    let mode = if drain { EXPORT_DRAIN } else { EXPORT_COPY };
+    open_control(&mut s, ROLE_EXPORT, &[], None, None)?;
+    s.write_all(&[mode])?;
+    s.flush()?;
+
+    let mut count = [0u8; 4];
+    s.read_exact(&mut count)?;
+    let count = u32::from_be_bytes(count) as usize;
+    let mut rd = io::BufReader::new(&s);
+    let mut frames = Vec::with_capacity(count.min(1 << 16));
+    for _ in 0..count {
+        let f = get_frame(&mut rd)?.ok_or_else(|| io::Error::new(
+            io::ErrorKind::UnexpectedEof, "export ended early",
+        ))?;
+        frames.push(f);
+    }
+    drop(rd);
+    keep(&frames)?;
+    if drain {
+        s.write_all(&[ACK_EXPORT])?;
+        s.flush()?;
+        let mut ack = [0u8; 1];
+        s.read_exact(&mut ack)?;
+        if ack[0] != ACK_EXPORT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected export ack: 0x{:02x}", ack[0]),
+            ));
+        }
+    }
+    Ok(frames.len())
+}
+
+/// Put `frames` — an export, say, from before a maintenance window — back
+/// in an orchestrator's queue, in their order, behind what it holds. The
+/// orchestrator queues them all or none: none if the connection breaks
+/// before it has the last. They don't wait for room, so the queue may end
+/// up past its capacity until consumers catch up; producers wait until
+/// then. Each frame goes to the queue its `Meta::queue` names, created if
+/// need be, or to the default queue. Delivery tags and attempt counts are
+/// the orchestrator's to assign again. Returns how many frames were
+/// imported.
+pub fn import_queue(orchestrator: &str, frames: &[(Frame, Meta)]) -> io::Result<usize> {
+    let count = u32::try_from(frames.len()).map_err(|_| io::Error::new(
+        io::ErrorKind::InvalidInput, "too many frames for one import",
+    ))?;
+    let mut s = Addr::resolve(orchestrator)?.connect(Some(Duration::from_secs(5)))?;
+    s.set_read_timeout(Some(Duration::from_secs(30))).ok();
+    s.set_write_timeout(Some(Duration::from_secs(30))).ok();
+
+    open_control(&mut s, ROLE_EXPORT, &[], None, None)?;
+    let mut w = io::BufWriter::new(&s);
+    w.write_all(&[EXPORT_IMPORT])?;
+    w.write_all(&count.to_be_bytes())?;
+    for (frame, meta) in frames {
+        put_frame(&mut w, frame, meta)?;
+    }
+    w.flush()?;
+    drop(w);
+
+    let mut ack = [0u8; 1];
+    s.read_exact(&mut ack).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(
+            io::ErrorKind::ConnectionAborted, "import refused (is the orchestrator draining?)",
+        ),
+        _ => e,
+    })?;
+    if ack[0] != ACK_EXPORT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected import ack: 0x{:02x}", ack[0]),
+        ));
+    }
+    Ok(frames.len())
+}
+
+/// Write `frames` to `path` as a recording (`put_frame`s without ACKs,
+/// sealed with `key` if there is one) via a temporary file, synced before
+/// it is renamed into place, so a recording that exists is a complete one.
+/// An orchestrator imports it at startup with `OrchestratorOptions::import`.
+pub fn write_recording(path: &Path, frames: &[(Frame, Meta)], key: Option<&at_rest::Key>) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".partial");
+    let tmp = PathBuf::from(tmp);
+    let file = std::fs::File::create(&tmp)?;
+    let mut out = BufWriter::new(file.try_clone()?);
+    match key {
+        Some(key) => {
+            let mut sealed = at_rest::SealedWriter::new(&mut out, key.clone());
+            for (frame, meta) in frames {
+                put_frame(&mut sealed, frame, meta)?;
+            }
+            sealed.finish()?;
+        }
+        None => {
+            for (frame, meta) in frames {
+                put_frame(&mut out, frame, meta)?;
+            }
+        }
+    }
+    out.flush()?;
+    drop(out);
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Every frame in the recording at `path` (see `write_recording`).
+pub fn read_recording(path: &Path, key: Option<&at_rest::Key>) -> io::Result<Vec<(Frame, Meta)>> {
+    let file = io::BufReader::new(std::fs::File::open(path)?);
+    let mut rd: Box<dyn Read> = match key {
+        Some(key) => Box::new(at_rest::SealedReader::new(file, key.clone())),
+        None => Box::new(file),
+    };
+    let mut frames = Vec::new();
+    while let Some(frame) = get_frame(&mut rd)? {
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+/// Subscribe to an orchestrator's stats reports. Each is one JSON object,
+/// the same that `--stats-format json` prints: the interval's deltas at the
+/// top level (`posted_frames`, `collected_bytes`, ...), the gauges
+/// (`in_queue`, `outstanding`, `producers`, ...) and the running counters
+/// under `totals`. One arrives per stats interval; the iterator ends when
+/// the orchestrator starts draining.
+pub fn watch_stats(orchestrator: &str) -> io::Result<StatsWatch> {
+    let mut s = Addr::resolve(orchestrator)?.connect(Some(Duration::from_secs(5)))?;
+    s.set_read_timeout(Some(Duration::from_secs(10))).ok();
+    s.set_write_timeout(Some(Duration::from_secs(5))).ok();
+
+    open_control(&mut s, ROLE_STATS, &[], None, None)?;
+
+    let mut ack = [0u8; 1];
+    s.read_exact(&mut ack)?;
+    if ack[0] != ACK_STATS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected stats ack: 0x{:02x}", ack[0]),
+        ));
+    }
+    // Reports come as slowly as the orchestrator was told to make them.
+    s.set_read_timeout(None).ok();
+    Ok(StatsWatch { rd: io::BufReader::new(s) })
+}
+
+/// A stats subscription (see `watch_stats`), yielding one JSON report per
+/// stats interval.
+pub struct StatsWatch {
+    rd: io::BufReader<Stream>,
+}
+
+impl Iterator for StatsWatch {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        match self.rd.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) if !line.ends_with('\n') => Some(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof, "stats report cut short",
+            ))),
+            Ok(_) => {
+                line.pop();
+                Some(Ok(line))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Decoded handshake option block: `(key, value)` pairs in wire order.
+pub type HandshakeOptions = Vec<(u8, Vec<u8>)>;
+
+/// Write a handshake option block: `[u16 BE len][TLV records]`.
+pub fn write_options<W: Write>(w: &mut W, opts: &[(u8, &[u8])]) -> io::Result<()> {
+    let block = tlv_encode(opts.iter().copied());
+    if block.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "handshake options too large",
+        ));
+    }
+    w.write_all(&(block.len() as u16).to_be_bytes())?;
+    w.write_all(&block)
+}
+
+/// Read a handshake option block written by `write_options`.
+pub fn read_options<R: Read>(r: &mut R) -> io::Result<HandshakeOptions> {
+    let mut len = [0u8; 2];
+    r.read_exact(&mut len)?;
+    let mut block = vec![0u8; u16::from_be_bytes(len) as usize];
+    r.read_exact(&mut block)?;
+    Ok(tlv_decode(&block)?
+        .into_iter()
+        .map(|(k, v)| (k, v.to_vec()))
+        .collect())
+}
+
+/// How a client authenticates to the orchestrator.
+#[derive(Debug, Clone)]
+enum ClientAuth {
+    Scram(Credentials),
+    /// A host-based service name, e.g. `qpipe@orchestrator.example.org`.
+    #[cfg(feature = "gssapi")]
+    Gssapi(String),
+}
+
+impl ClientAuth {
+    /// The explicit choice, else `QPIPE_USER`/`QPIPE_PASSWORD`, else (with
+    /// the `gssapi` feature) `QPIPE_KRB5_SERVICE`.
+    fn resolve(explicit: Option<&ClientAuth>) -> Option<ClientAuth> {
+        let from_env = || {
+            let scram = Credentials::from_env().map(ClientAuth::Scram);
+            #[cfg(feature = "gssapi")]
+            let scram = scram.or_else(|| {
+                std::env::var("QPIPE_KRB5_SERVICE").ok().map(ClientAuth::Gssapi)
+            });
+            scram
+        };
+        explicit.cloned().or_else(from_env)
+    }
+}
+
+/// Open a control session: send the role byte and options, prove the
+/// pre-shared key if there is one — `key`, or else `QPIPE_AUTH_KEY_FILE` —
+/// and authenticate if there is a way to — `auth`, or else one from the
+/// environment (see `ClientAuth::resolve`). Without any of these, and
+/// without options, this is the original single role byte. Returns whether
+/// an option block was sent, i.e. whether the reply carries one.
+fn open_control(
+            ctrl: &mut Stream,
+            role: u8,
+            opts: &[(u8, &[u8])],
+            key:  Option<&psk::Key>,
+            auth: Option<&ClientAuth>,
+        ) -> io::Result<bool> {
+    let key = match key {
+        Some(key) => Some(key.clone()),
+        None => psk::Key::from_env()?,
+    };
+    let nonce = key.as_ref().map(|_| psk::nonce()).transpose()?;
+    let auth = ClientAuth::resolve(auth);
+    let client = match &auth {
+        Some(ClientAuth::Scram(creds)) => Some(scram::Client::new(creds)?),
+        _ => None,
+    };
+    let first = client.as_ref().map(scram::Client::first);
+    let mut opts = opts.to_vec();
+    if let Some(nonce) = &nonce {
+        opts.push((OPT_AUTH_KEY, &nonce[..]));
+    }
+    if let Some(first) = &first {
+        opts.push((OPT_SCRAM, first.as_bytes()));
+    }
+    #[cfg(feature = "gssapi")]
+    if let Some(ClientAuth::Gssapi(_)) = &auth {
+        opts.push((OPT_GSSAPI, &[]));
+    }
+
+    if opts.is_empty() {
+        ctrl.write_all(&[role])?;
+    } else {
+        ctrl.write_all(&[role | ROLE_FLAG_OPTS])?;
+        write_options(ctrl, &opts)?;
+    }
+    ctrl.flush()?;
+    if let (Some(key), Some(nonce)) = (&key, &nonce) {
+        psk::authenticate(ctrl, key, role, nonce)?;
+    }
+    if let Some(client) = client {
+        scram::authenticate(ctrl, client)?;
+    }
+    #[cfg(feature = "gssapi")]
+    if let Some(ClientAuth::Gssapi(service)) = &auth {
+        gssapi::authenticate(ctrl, service)?;
+    }
+    Ok(!opts.is_empty())
+}
+
+/// Control-port handshake shared by producers and consumers: the hello,
+/// then the role byte, options and credentials. Returns the authenticated
+/// data stream, the orchestrator's reply options and the protocol version.
+/// With `OPT_SINGLE_PORT` among `opts` the data stream is the control
+/// connection itself, and an orchestrator that doesn't echo it is
+/// `Unsupported`: one that only has a data port to offer is no use to a
+/// client that can't reach it. Sessions over a `ws://` address, or one of
+/// a registered transport, are always single-port; the option is added if
+/// it is missing. Producers talking to
+/// an orchestrator that said hello ask for its frame-size limit
+/// (`OPT_MAX_FRAME`) and offer a goodbye (`OPT_GOODBYE`), and consumers
+/// offer a goodbye and ask for the queue's delivery policy
+/// (`OPT_DELIVERY`); older ones may not know option blocks. `sock`'s
+/// connect timeout bounds the handshake's reads and writes as well.
+fn handshake(
+            orchestrator: &str,
+            role: u8,
+            opts: &[(u8, &[u8])],
+            key:  Option<&psk::Key>,
+            auth: Option<&ClientAuth>,
+            sock: &SocketOptions,
+        ) -> io::Result<(Stream, HandshakeOptions, u8)> {
+    let orchestrator_ctrl = Addr::resolve(orchestrator)?;
+    let mut opts = opts.to_vec();
+    if orchestrator_ctrl.single_port_only() && !opts.iter().any(|(k, _)| *k == OPT_SINGLE_PORT) {
+        opts.push((OPT_SINGLE_PORT, &[]));
+    }
+    let opts = &opts[..];
+    let connect = |addr: &Addr| {
+        let s = addr.connect_with(sock)?;
+        s.set_read_timeout(sock.connect_timeout)?;
+        s.set_write_timeout(sock.connect_timeout)?;
+        Ok::<_, io::Error>(s)
+    };
+    let mut ctrl = connect(&orchestrator_ctrl)?;
+    let version = match hello(&mut ctrl) {
+        Ok(version) => version,
+        // An orchestrator from before the hello took it for an unknown
+        // role and hung up; talk to it the original way.
+        Err(e) if matches!(
+            e.kind(),
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+        ) => {
+            ctrl = connect(&orchestrator_ctrl)?;
+            PROTOCOL_V1
+        }
+        Err(e) => return Err(e),
+    };
+    let mut opts = opts.to_vec();
+    match role {
+        _ if version == PROTOCOL_V1 => {}
+        ROLE_PRODUCER => {
+            opts.push((OPT_MAX_FRAME, &[]));
+            opts.push((OPT_GOODBYE, &[]));
+        }
+        // Only sessions with a back-channel reader can hear a goodbye; the
+        // orchestrator grants it to those alone, ack mode its queue imposed
+        // included.
+        ROLE_CONSUMER => {
+            opts.push((OPT_GOODBYE, &[]));
+            opts.push((OPT_DELIVERY, &[]));
+        }
+        _ => {}
+    }
+    let opts = &opts[..];
+
+    let sent_opts = open_control(&mut ctrl, role, opts, key, auth)?;
+
+    let (port, token) = read_port_token(&mut ctrl).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(
+            e.kind(), "orchestrator closed the connection (does it require authentication?)",
+        ),
+        _ => e,
+    })?;
+    let reply = if sent_opts { read_options(&mut ctrl)? } else { Vec::new() };
+    check_echoed(opts, &reply, OPT_SINGLE_PORT, "single-port sessions")?;
+    if reply.iter().any(|(k, _)| *k == OPT_SINGLE_PORT) {
+        ctrl.write_all(&token)?;
+        ctrl.flush()?;
+        ctrl.set_read_timeout(None)?;
+        ctrl.set_write_timeout(None)?;
+        return Ok((ctrl, reply, version));
+    }
+    drop(ctrl);
+
+    let stream = connect_data(&orchestrator_ctrl.data(port), token, sock)?;
+    Ok((stream, reply, version))
+}
+
+/// `connect_ws` takes only `ws://` addresses.
+#[cfg(feature = "ws")]
+fn check_ws_url(url: &str) -> io::Result<()> {
+    if !url.starts_with(transport::WS_SCHEME) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput, format!("{url}: not a {} address", transport::WS_SCHEME),
+        ));
+    }
+    Ok(())
+}
+
+/// Say hello (see `HELLO_MAGIC`) and return the version agreed on.
+fn hello(ctrl: &mut Stream) -> io::Result<u8> {
+    let mut msg = HELLO_MAGIC.to_vec();
+    msg.extend_from_slice(&[PROTOCOL_V1, PROTOCOL_VERSION]);
+    ctrl.write_all(&msg)?;
+    ctrl.flush()?;
+
+    let mut answer = [0u8; 5];
+    ctrl.read_exact(&mut answer)?;
+    if answer[..4] != HELLO_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad hello from the orchestrator"));
+    }
+    match answer[4] {
+        0 => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "orchestrator speaks none of protocol versions {PROTOCOL_V1}..={PROTOCOL_VERSION}",
+            ),
+        )),
+        v if v > PROTOCOL_VERSION => Err(io::Error::new(
+            io::ErrorKind::InvalidData, format!("orchestrator chose unknown protocol version {v}"),
+        )),
+        v => Ok(v),
+    }
 }
 
 fn read_port_token<R: Read>(r: &mut R) -> io::Result<(u16, [u8; TOKEN_LEN])> {
@@ -218,14 +1049,8 @@ fn read_port_token<R: Read>(r: &mut R) -> io::Result<(u16, [u8; TOKEN_LEN])> {
     Ok((port, token))
 }
 
-fn connect_data(
-            orchestrator_ctrl: SocketAddr,
-            port: u16,
-            token: [u8; TOKEN_LEN]
-        ) -> io::Result<TcpStream> {
-    let data_addr = SocketAddr::new(orchestrator_ctrl.ip(), port);
-    let mut s = TcpStream::connect(data_addr)?;
-    s.set_nodelay(true).ok();
+fn connect_data(data_addr: &Addr, token: [u8; TOKEN_LEN], sock: &SocketOptions) -> io::Result<Stream> {
+    let mut s = data_addr.connect_with(sock)?;
 
     // Authenticate immediately on the ephemeral port.
     s.write_all(&token)?;
@@ -240,88 +1065,732 @@ fn new_msg_id() -> io::Result<u128> {
     let mut b = [0u8; 16];
     SysRng
         .try_fill_bytes(&mut b)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        .map_err(io::Error::other)?;
     Ok(u128::from_be_bytes(b))
 }
 
-/// Write one complete single-frame message. Wire format and behavior are
-/// unchanged from the original protocol: `[u32 BE len][payload]`, then wait
-/// for one ACK byte from the peer.
-pub fn write_frame<S: Write + Read>(
-            s: &mut S,
-            payload: &[u8]
-        ) -> io::Result<()> {
-    if payload.len() > MAX_FRAME_SIZE {
-        return Err(
-            io::Error::new(io::ErrorKind::InvalidInput, "Frame too large")
-        );
+/// Encode TLV records `[u8 key][u16 BE len][value]` — the layout shared by
+/// handshake option blocks and frame metadata blocks.
+fn tlv_encode<'a>(entries: impl IntoIterator<Item = (u8, &'a [u8])>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (k, v) in entries {
+        out.push(k);
+        out.extend_from_slice(&(v.len() as u16).to_be_bytes());
+        out.extend_from_slice(v);
     }
+    out
+}
 
-    // Send frame data
-    let len = payload.len() as u32;
-    s.write_all(&len.to_be_bytes())?;
-    s.write_all(payload)?;
-    // Read acknowledgement
-    let mut ack_byte = [0u8; 1];
-    s.read_exact(&mut ack_byte)?; // Reads exactly 1 byte (should be b'A')
-    if ack_byte[0] != ACK_PAYLOAD {
-        return Err(
-            io::Error::new(io::ErrorKind::InvalidData, "Invalid ACK bit")
-        );
+/// Split a TLV block into records. Truncated records are `InvalidData`.
+fn tlv_decode(mut b: &[u8]) -> io::Result<Vec<(u8, &[u8])>> {
+    let mut out = Vec::new();
+    while !b.is_empty() {
+        if b.len() < 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "truncated TLV record header",
+            ));
+        }
+        let key = b[0];
+        let len = u16::from_be_bytes([b[1], b[2]]) as usize;
+        if b.len() < 3 + len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "truncated TLV record value",
+            ));
+        }
+        out.push((key, &b[3..3 + len]));
+        b = &b[3 + len..];
     }
+    Ok(out)
+}
 
-    Ok(())
+fn be_u64(v: &[u8]) -> io::Result<u64> {
+    v.try_into().map(u64::from_be_bytes).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "expected an 8-byte value")
+    })
 }
 
-/// Write one chunk of a multi-frame message:
-/// `[u32 BE FLAG|body_len][u128 id][u32 idx][u32 count][payload]`, then wait
-/// for one ACK byte — the same per-frame flow control as single frames.
-pub fn write_chunk_frame<S: Write + Read>(
-            s: &mut S,
-            id: u128,
-            idx: u32,
-            count: u32,
-            payload: &[u8],
-        ) -> io::Result<()> {
-    if payload.len() > MAX_CHUNK_PAYLOAD {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput, "chunk payload too large",
-        ));
+fn be_u32(v: &[u8]) -> io::Result<u32> {
+    v.try_into().map(u32::from_be_bytes).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "expected a 4-byte value")
+    })
+}
+
+/// Per-frame metadata, carried in the optional META block (bit 29). Every
+/// field is optional; an all-`None` `Meta` is not written at all, so frames
+/// without metadata stay wire-identical to the original protocol. Unknown
+/// keys are skipped on read, so newer peers can add fields freely.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Meta {
+    /// Orchestrator-assigned delivery tag (ack-mode consumers only).
+    pub delivery: Option<u64>,
+    /// Failed deliveries of this message before this one (ack mode).
+    pub attempt:  Option<u32>,
+    /// Producer-assigned scheduling priority; absent means 0.
+    pub priority: Option<u8>,
+    /// Capability tags a consumer must advertise to receive the message.
+    pub requires: Vec<String>,
+    /// Resource hint: estimated peak memory in bytes.
+    pub memory:   Option<u64>,
+    /// Resource hint: GPUs the message needs while being processed.
+    pub gpus:     Option<u32>,
+    /// Resource hint: expected processing time.
+    pub runtime:  Option<Duration>,
+    /// Application headers, passed through to consumers untouched.
+    pub headers:  Headers,
+    /// Codec the message payload is compressed with (`CODEC_*`). Set by a
+    /// compressing producer; consumers undo it before handing the message
+    /// out.
+    pub codec:    Option<u8>,
+    /// Partition key: the orchestrator hands the messages sharing a key to
+    /// one consumer at a time, so they are processed in order.
+    pub partition: Option<Vec<u8>>,
+    /// Time to live: the orchestrator drops the message, unseen, if no
+    /// consumer has taken it this long after it was queued.
+    pub ttl:      Option<Duration>,
+    /// Hold the message back: the orchestrator queues it for consumers
+    /// only this long after accepting it.
+    pub delay:    Option<Duration>,
+    /// Producer-assigned id, unique to the message: the orchestrator drops
+    /// a message whose id its queue has accepted recently, so a resend
+    /// after a lost ACK is not delivered twice.
+    pub message_id: Option<Vec<u8>>,
+    /// The named queue an exported frame came from (`export_queue`), and
+    /// goes back to on import; absent means the default queue.
+    pub queue:    Option<String>,
+}
+
+impl Meta {
+    pub fn is_empty(&self) -> bool {
+        *self == Meta::default()
     }
-    if count == 0 || idx >= count {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput, "invalid chunk header (idx/count)",
-        ));
+
+    fn encode(&self) -> Vec<u8> {
+        let delivery = self.delivery.map(u64::to_be_bytes);
+        let attempt  = self.attempt.map(u32::to_be_bytes);
+        let priority = self.priority.map(|p| [p]);
+        let requires = self.requires.join(",");
+        let memory   = self.memory.map(u64::to_be_bytes);
+        let gpus     = self.gpus.map(u32::to_be_bytes);
+        let runtime  = self.runtime.map(|d| (d.as_millis() as u64).to_be_bytes());
+        let headers  = self.headers.encode();
+        let codec    = self.codec.map(|c| [c]);
+        let ttl      = self.ttl.map(|d| (d.as_millis() as u64).to_be_bytes());
+        let delay    = self.delay.map(|d| (d.as_millis() as u64).to_be_bytes());
+        let mut entries: Vec<(u8, &[u8])> = Vec::new();
+        if let Some(v) = &delivery { entries.push((META_DELIVERY, v)); }
+        if let Some(v) = &attempt  { entries.push((META_ATTEMPT, v)); }
+        if let Some(v) = &priority { entries.push((META_PRIORITY, v)); }
+        if !requires.is_empty() { entries.push((META_REQUIRES, requires.as_bytes())); }
+        if let Some(v) = &memory  { entries.push((META_MEMORY, v)); }
+        if let Some(v) = &gpus    { entries.push((META_GPUS, v)); }
+        if let Some(v) = &runtime { entries.push((META_RUNTIME, v)); }
+        if !self.headers.is_empty() { entries.push((META_HEADERS, &headers)); }
+        if let Some(v) = &codec   { entries.push((META_CODEC, v)); }
+        if let Some(v) = &self.partition { entries.push((META_PARTITION, v)); }
+        if let Some(v) = &ttl     { entries.push((META_TTL, v)); }
+        if let Some(v) = &delay   { entries.push((META_DELAY, v)); }
+        if let Some(v) = &self.message_id { entries.push((META_MESSAGE_ID, v)); }
+        if let Some(v) = &self.queue { entries.push((META_QUEUE, v.as_bytes())); }
+        tlv_encode(entries)
     }
-    if count > MAX_CHUNKS {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput, "chunk count exceeds MAX_CHUNKS",
-        ));
+
+    fn decode(b: &[u8]) -> io::Result<Self> {
+        let mut m = Meta::default();
+        for (k, v) in tlv_decode(b)? {
+            match k {
+                META_DELIVERY => m.delivery = Some(be_u64(v)?),
+                META_ATTEMPT  => m.attempt = Some(be_u32(v)?),
+                META_PRIORITY => {
+                    m.priority = Some(*v.first().ok_or_else(|| io::Error::new(
+                        io::ErrorKind::InvalidData, "empty priority value",
+                    ))?);
+                }
+                META_REQUIRES => m.requires = split_tags(v)?,
+                META_MEMORY   => m.memory = Some(be_u64(v)?),
+                META_GPUS     => m.gpus = Some(be_u32(v)?),
+                META_RUNTIME  => m.runtime = Some(Duration::from_millis(be_u64(v)?)),
+                META_HEADERS  => m.headers = Headers::decode(v)?,
+                META_CODEC    => {
+                    m.codec = Some(*v.first().ok_or_else(|| io::Error::new(
+                        io::ErrorKind::InvalidData, "empty codec value",
+                    ))?);
+                }
+                META_PARTITION => m.partition = Some(v.to_vec()),
+                META_TTL      => m.ttl = Some(Duration::from_millis(be_u64(v)?)),
+                META_DELAY    => m.delay = Some(Duration::from_millis(be_u64(v)?)),
+                META_MESSAGE_ID => m.message_id = Some(v.to_vec()),
+                META_QUEUE    => {
+                    m.queue = Some(String::from_utf8(v.to_vec()).map_err(|_| io::Error::new(
+                        io::ErrorKind::InvalidData, "queue name is not UTF-8",
+                    ))?);
+                }
+                _ => {} // forward compatibility: ignore unknown keys
+            }
+        }
+        Ok(m)
     }
+}
 
-    let body_len = (CHUNK_HEADER_LEN + payload.len()) as u32;
-    s.write_all(&(body_len | FRAME_FLAG_CHUNK).to_be_bytes())?;
-    s.write_all(&id.to_be_bytes())?;
-    s.write_all(&idx.to_be_bytes())?;
-    s.write_all(&count.to_be_bytes())?;
-    s.write_all(payload)?;
+/// Application headers on a message, e.g. a content type or a correlation
+/// id: UTF-8 keys and values, one value per key. They travel in the META
+/// block, so all of a message's headers and other metadata share its
+/// MAX_META_LEN bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(BTreeMap<String, String>);
 
-    let mut ack_byte = [0u8; 1];
-    s.read_exact(&mut ack_byte)?;
-    if ack_byte[0] != ACK_PAYLOAD {
-        return Err(
-            io::Error::new(io::ErrorKind::InvalidData, "Invalid ACK bit")
-        );
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
     }
-    Ok(())
-}
 
-/// Fill `buf` completely from `s`, distinguishing a clean stream end from a
-/// truncated one — which `Read::read_exact` cannot do, because it reports both
-/// "EOF with 0 bytes read" and "EOF after a partial read" as the same
-/// `UnexpectedEof`, and leaves the buffer contents unspecified.
-///
-/// Returns:
+    /// Set `key` (replacing any earlier value), builder style.
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.insert(key, value);
+        self
+    }
+
+    /// Set `key`, returning the value it replaces.
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        self.0.insert(key.into(), value.into())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.0.remove(key)
+    }
+
+    /// Headers in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// `[u16 BE count]` then `[u16 BE len][key][u16 BE len][value]` per
+    /// header. Lengths past u16 can't fit the META block anyway; they are
+    /// truncated here and rejected by its length check.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = (self.0.len() as u16).to_be_bytes().to_vec();
+        for (k, v) in &self.0 {
+            for s in [k, v] {
+                out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+                out.extend_from_slice(s.as_bytes());
+            }
+        }
+        out
+    }
+
+    fn decode(mut b: &[u8]) -> io::Result<Self> {
+        let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("headers: {msg}"));
+        let mut take = |n: usize| -> io::Result<&[u8]> {
+            if b.len() < n {
+                return Err(bad("truncated"));
+            }
+            let (head, rest) = b.split_at(n);
+            b = rest;
+            Ok(head)
+        };
+        let count = u16::from_be_bytes(take(2)?.try_into().unwrap());
+        let mut h = Headers::new();
+        for _ in 0..count {
+            let mut field = || -> io::Result<String> {
+                let len = u16::from_be_bytes(take(2)?.try_into().unwrap()) as usize;
+                String::from_utf8(take(len)?.to_vec()).map_err(|_| bad("not UTF-8"))
+            };
+            let (k, v) = (field()?, field()?);
+            h.insert(k, v);
+        }
+        if !b.is_empty() {
+            return Err(bad("trailing bytes"));
+        }
+        Ok(h)
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self(iter.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+}
+
+/// Which messages a consumer subscribes to, by their headers
+/// (`ConnectOptions::filter`). The spec is comma-separated clauses, all of
+/// which must hold: `key=value` wants header `key` to be `value`,
+/// `key^=prefix` wants it to start with `prefix`. Keys and values are
+/// taken as written, spaces included, and can't contain commas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter(Vec<(String, Clause)>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Clause {
+    Equals(String),
+    Prefix(String),
+}
+
+impl Filter {
+    /// `InvalidInput` for a spec without clauses, or a clause without a
+    /// key or an `=`.
+    pub fn parse(spec: &str) -> io::Result<Self> {
+        let bad = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, format!("filter {spec:?}: {msg}"));
+        if spec.len() > MAX_FILTER_LEN {
+            return Err(bad(format!("longer than {MAX_FILTER_LEN} bytes")));
+        }
+        let clauses = spec.split(',')
+            .map(|clause| {
+                let (key, value) = clause.split_once('=').ok_or_else(|| bad(format!("{clause:?} has no '='")))?;
+                let (key, test) = match key.strip_suffix('^') {
+                    Some(key) => (key, Clause::Prefix(value.to_string())),
+                    None => (key, Clause::Equals(value.to_string())),
+                };
+                if key.is_empty() {
+                    return Err(bad(format!("{clause:?} has no header name")));
+                }
+                Ok((key.to_string(), test))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self(clauses))
+    }
+
+    /// Whether a message with `headers` passes.
+    pub fn matches(&self, headers: &Headers) -> bool {
+        self.0.iter().all(|(key, test)| headers.get(key).is_some_and(|v| match test {
+            Clause::Equals(want) => v == want,
+            Clause::Prefix(want) => v.starts_with(want.as_str()),
+        }))
+    }
+}
+
+/// The spec, as `parse` takes it.
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, test)) in self.0.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            match test {
+                Clause::Equals(v) => write!(f, "{sep}{key}={v}")?,
+                Clause::Prefix(v) => write!(f, "{sep}{key}^={v}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a queue settles what it delivers. An orchestrator may fix it per
+/// queue; otherwise each consumer picks its own, by asking for ack mode or
+/// not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeliveryPolicy {
+    /// A message is settled once its consumer has received it, and lost if
+    /// the consumer dies while processing it: plain sessions.
+    AtMostOnce,
+    /// A message is settled by its consumer's `Consumer::ack`, and
+    /// redelivered until then, per the retry policy: ack mode.
+    AtLeastOnce,
+}
+
+impl DeliveryPolicy {
+    /// `at-most-once` or `at-least-once`; `InvalidInput` otherwise.
+    pub fn parse(s: &str) -> io::Result<Self> {
+        match s {
+            "at-most-once" => Ok(Self::AtMostOnce),
+            "at-least-once" => Ok(Self::AtLeastOnce),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("delivery policy: expected at-most-once or at-least-once, got {s:?}"),
+            )),
+        }
+    }
+
+    /// The `OPT_DELIVERY` byte.
+    pub fn code(self) -> u8 {
+        match self {
+            Self::AtMostOnce => DELIVERY_AT_MOST_ONCE,
+            Self::AtLeastOnce => DELIVERY_AT_LEAST_ONCE,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            DELIVERY_AT_MOST_ONCE => Some(Self::AtMostOnce),
+            DELIVERY_AT_LEAST_ONCE => Some(Self::AtLeastOnce),
+            _ => None,
+        }
+    }
+}
+
+/// The name, as `parse` takes it.
+impl fmt::Display for DeliveryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::AtMostOnce => "at-most-once",
+            Self::AtLeastOnce => "at-least-once",
+        })
+    }
+}
+
+/// Capability tags travel as one comma-separated UTF-8 string.
+fn split_tags(v: &[u8]) -> io::Result<Vec<String>> {
+    let s = std::str::from_utf8(v).map_err(|_| io::Error::new(
+        io::ErrorKind::InvalidData, "capability tags are not UTF-8",
+    ))?;
+    Ok(s.split(',').filter(|t| !t.is_empty()).map(str::to_string).collect())
+}
+
+/// Queue names are 1..=MAX_QUEUE_NAME bytes of ASCII letters, digits and
+/// `.`, `_`, `-`, `/`.
+pub fn check_queue_name(name: &str) -> io::Result<()> {
+    let ok = (1..=MAX_QUEUE_NAME).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"._-/".contains(&b));
+    if ok {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid queue name {name:?}")))
+    }
+}
+
+/// Client names are 1..=MAX_CLIENT_NAME bytes of UTF-8 without control
+/// characters, so they fit on a log line or in a tab-separated listing.
+pub fn check_client_name(name: &str) -> io::Result<()> {
+    let ok = (1..=MAX_CLIENT_NAME).contains(&name.len()) && !name.chars().any(char::is_control);
+    if ok {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid client name {name:?}")))
+    }
+}
+
+/// Fail with `Unsupported` if the handshake reply does not echo an option
+/// that was asked for.
+fn check_echoed(req: &[(u8, &[u8])], reply: &[(u8, Vec<u8>)], key: u8, what: &str) -> io::Result<()> {
+    let asked = req.iter().any(|(k, _)| *k == key);
+    if asked && !reply.iter().any(|(k, _)| *k == key) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("orchestrator does not support {what}"),
+        ));
+    }
+    Ok(())
+}
+
+/// The frame-size limit the orchestrator advertised (`OPT_MAX_FRAME`);
+/// MAX_FRAME_SIZE for one that didn't.
+fn max_frame_echoed(reply: &[(u8, Vec<u8>)]) -> io::Result<usize> {
+    let Some((_, v)) = reply.iter().find(|(k, _)| *k == OPT_MAX_FRAME) else {
+        return Ok(MAX_FRAME_SIZE);
+    };
+    let limit = <[u8; 4]>::try_from(&v[..]).map(u32::from_be_bytes).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "bad frame-size limit from the orchestrator")
+    })? as usize;
+    if !(MIN_FRAME_LIMIT..=MAX_FRAME_SIZE).contains(&limit) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("orchestrator frame-size limit {limit} is outside {MIN_FRAME_LIMIT}..={MAX_FRAME_SIZE}"),
+        ));
+    }
+    Ok(limit)
+}
+
+/// Heartbeat settings, as carried by `OPT_HEARTBEAT`: a connection quiet
+/// for `interval` is pinged, and a peer not heard from in `timeout` is
+/// taken for dead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Heartbeat {
+    pub(crate) interval: Duration,
+    pub(crate) timeout:  Duration,
+}
+
+impl Heartbeat {
+    /// Both are sent in whole milliseconds, and the timeout has to leave
+    /// room for at least one ping.
+    pub(crate) fn new(interval: Duration, timeout: Duration) -> io::Result<Self> {
+        let ms = |d: Duration| u32::try_from(d.as_millis()).ok().filter(|&ms| ms > 0);
+        match (ms(interval), ms(timeout)) {
+            (Some(i), Some(t)) if t > i => Ok(Self {
+                interval: Duration::from_millis(i.into()),
+                timeout:  Duration::from_millis(t.into()),
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid heartbeat {interval:?}/{timeout:?}: \
+                         need 1ms <= interval < timeout <= u32::MAX ms"),
+            )),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> [u8; 8] {
+        let mut v = [0u8; 8];
+        v[..4].copy_from_slice(&(self.interval.as_millis() as u32).to_be_bytes());
+        v[4..].copy_from_slice(&(self.timeout.as_millis() as u32).to_be_bytes());
+        v
+    }
+
+    pub(crate) fn decode(v: &[u8]) -> io::Result<Self> {
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, "malformed heartbeat option");
+        let v = <[u8; 8]>::try_from(v).map_err(|_| bad())?;
+        let ms = |b: &[u8]| Duration::from_millis(u32::from_be_bytes(b.try_into().unwrap()).into());
+        Self::new(ms(&v[..4]), ms(&v[4..])).map_err(|_| bad())
+    }
+
+    /// The heartbeat the orchestrator settled on, if it echoed one.
+    fn echoed(reply: &[(u8, Vec<u8>)]) -> io::Result<Option<Self>> {
+        reply.iter()
+            .find(|(k, _)| *k == OPT_HEARTBEAT)
+            .map(|(_, v)| Self::decode(v))
+            .transpose()
+    }
+
+    /// Whether `e` is a read that gave up waiting, as a read timeout makes it.
+    pub(crate) fn missed(e: &io::Error) -> bool {
+        matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+    }
+}
+
+/// Tags must survive the comma-separated encoding unchanged.
+fn check_tags(tags: &[String]) -> io::Result<()> {
+    match tags.iter().find(|t| t.is_empty() || t.contains(',')) {
+        Some(t) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid capability tag {t:?} (empty or contains ',')"),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn check_chunk_header(
+            idx: u32,
+            count: u32,
+            kind: io::ErrorKind,
+        ) -> io::Result<()> {
+    if count == 0 || idx >= count {
+        return Err(io::Error::new(kind, "invalid chunk header (idx/count)"));
+    }
+    if count > MAX_CHUNKS {
+        return Err(io::Error::new(kind, "chunk count exceeds MAX_CHUNKS"));
+    }
+    Ok(())
+}
+
+/// Emit one frame from its parts: `flags` picks the frame type, `head` is
+/// the type-specific header (chunk header / control kind), `body` the rest.
+/// A non-empty `meta` adds the META flag and block; the CRC flag in `flags`
+/// adds the checksum trailer.
+fn put_parts<W: Write>(
+            w: &mut W,
+            flags: u32,
+            meta: &Meta,
+            head: &[u8],
+            body: &[u8],
+        ) -> io::Result<()> {
+    let meta_bytes = if meta.is_empty() { Vec::new() } else { meta.encode() };
+    if meta_bytes.len() > MAX_META_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "frame metadata too large",
+        ));
+    }
+    let (flags, meta_len) = if meta.is_empty() {
+        (flags, 0)
+    } else {
+        (flags | FRAME_FLAG_META, 2 + meta_bytes.len())
+    };
+
+    let body_len = (meta_len + head.len() + body.len()) as u32;
+    let prefix = (body_len | flags).to_be_bytes();
+    let meta_prefix = (meta_bytes.len() as u16).to_be_bytes();
+    let meta_parts: [&[u8]; 2] = if meta_len > 0 { [&meta_prefix, &meta_bytes] } else { [&[], &[]] };
+    let parts = [&prefix[..], meta_parts[0], meta_parts[1], head, body];
+    for part in parts {
+        w.write_all(part)?;
+    }
+    if flags & FRAME_FLAG_CRC != 0 {
+        let mut crc = checksum::Crc32c::new();
+        parts.iter().for_each(|part| crc.update(part));
+        w.write_all(&crc.finish().to_be_bytes())?;
+    }
+    Ok(())
+}
+
+/// The CRC flag, for frames of sessions that negotiated checksums.
+fn crc_flag(crc: bool) -> u32 {
+    if crc { FRAME_FLAG_CRC } else { 0 }
+}
+
+fn put_msg<W: Write>(w: &mut W, payload: &[u8], meta: &Meta, crc: bool) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(
+            io::Error::new(io::ErrorKind::InvalidInput, "Frame too large")
+        );
+    }
+    put_parts(w, crc_flag(crc), meta, &[], payload)
+}
+
+fn put_chunk<W: Write>(
+            w: &mut W,
+            id: u128,
+            idx: u32,
+            count: u32,
+            payload: &[u8],
+            meta: &Meta,
+            crc: bool,
+        ) -> io::Result<()> {
+    if payload.len() > MAX_CHUNK_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "chunk payload too large",
+        ));
+    }
+    check_chunk_header(idx, count, io::ErrorKind::InvalidInput)?;
+
+    let mut head = [0u8; CHUNK_HEADER_LEN];
+    head[0..16].copy_from_slice(&id.to_be_bytes());
+    head[16..20].copy_from_slice(&idx.to_be_bytes());
+    head[20..24].copy_from_slice(&count.to_be_bytes());
+    put_parts(w, FRAME_FLAG_CHUNK | crc_flag(crc), meta, &head, payload)
+}
+
+/// Write one frame (plus optional metadata) WITHOUT waiting for the peer's
+/// ACK. Building block for pipelined writers and for framed files, which
+/// reuse the wire encoding; the `write_*_frame` helpers add the ACK wait.
+pub fn put_frame<W: Write>(w: &mut W, f: &Frame, meta: &Meta) -> io::Result<()> {
+    put_frame_as(w, f, meta, false)
+}
+
+/// `put_frame` with a checksum trailer (`FRAME_FLAG_CRC`), for sessions
+/// that negotiated `OPT_CHECKSUM`.
+pub fn put_checked_frame<W: Write>(w: &mut W, f: &Frame, meta: &Meta) -> io::Result<()> {
+    put_frame_as(w, f, meta, true)
+}
+
+pub(crate) fn put_frame_as<W: Write>(w: &mut W, f: &Frame, meta: &Meta, crc: bool) -> io::Result<()> {
+    match f {
+        Frame::Msg(p) => put_msg(w, p, meta, crc),
+        Frame::Chunk { id, idx, count, payload } => {
+            put_chunk(w, *id, *idx, *count, payload, meta, crc)
+        }
+        Frame::Eos(group) => {
+            if group.len() + 1 > MAX_FRAME_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput, "EOS group label too large",
+                ));
+            }
+            put_parts(w, FRAME_FLAG_CTRL | crc_flag(crc), meta, &[CTRL_EOS], group)
+        }
+        Frame::Ping => put_parts(w, FRAME_FLAG_CTRL | crc_flag(crc), meta, &[CTRL_PING], &[]),
+        Frame::Bye => put_parts(w, FRAME_FLAG_CTRL | crc_flag(crc), meta, &[CTRL_BYE], &[]),
+    }
+}
+
+/// Wait for the single ACK byte that follows every frame.
+pub fn read_ack<R: Read>(r: &mut R) -> io::Result<()> {
+    let mut ack_byte = [0u8; 1];
+    r.read_exact(&mut ack_byte)?; // Reads exactly 1 byte (should be b'A')
+    if ack_byte[0] != ACK_PAYLOAD {
+        return Err(
+            io::Error::new(io::ErrorKind::InvalidData, "Invalid ACK bit")
+        );
+    }
+    Ok(())
+}
+
+/// Wait for the ACKs of `n` pipelined frames, taking in as many per read
+/// as have arrived — but never more than `n`, so nothing after them is
+/// consumed. Returns how many were `ACK_THROTTLED`.
+fn read_acks<R: Read>(r: &mut R, mut n: usize) -> io::Result<usize> {
+    let mut throttled = 0;
+    let mut acks = [0u8; 512];
+    while n > 0 {
+        let got = match r.read(&mut acks[..n.min(512)]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(got) => got,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if acks[..got].iter().any(|&b| b != ACK_PAYLOAD && b != ACK_THROTTLED) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid ACK bit"));
+        }
+        throttled += acks[..got].iter().filter(|&&b| b == ACK_THROTTLED).count();
+        n -= got;
+    }
+    Ok(throttled)
+}
+
+/// `Write::write_all_vectored`, which is not stable yet.
+fn write_all_vectored<W: Write>(w: &mut W, mut bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match w.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Write one complete single-frame message. Wire format and behavior are
+/// unchanged from the original protocol: `[u32 BE len][payload]`, then wait
+/// for one ACK byte from the peer.
+pub fn write_frame<S: Write + Read>(
+            s: &mut S,
+            payload: &[u8]
+        ) -> io::Result<()> {
+    // Send frame data, then read acknowledgement
+    put_msg(s, payload, &Meta::default(), false)?;
+    read_ack(s)
+}
+
+/// Write one chunk of a multi-frame message:
+/// `[u32 BE FLAG|body_len][u128 id][u32 idx][u32 count][payload]`, then wait
+/// for one ACK byte — the same per-frame flow control as single frames.
+pub fn write_chunk_frame<S: Write + Read>(
+            s: &mut S,
+            id: u128,
+            idx: u32,
+            count: u32,
+            payload: &[u8],
+        ) -> io::Result<()> {
+    put_chunk(s, id, idx, count, payload, &Meta::default(), false)?;
+    read_ack(s)
+}
+
+/// Write an end-of-stream control frame:
+/// `[u32 BE CTRL|body_len][CTRL_EOS][group]`, then wait for one ACK byte.
+pub fn write_eos_frame<S: Write + Read>(
+            s: &mut S,
+            group: &[u8],
+        ) -> io::Result<()> {
+    put_frame(s, &Frame::Eos(group.to_vec()), &Meta::default())?;
+    read_ack(s)
+}
+
+/// Write any parsed frame, with optional metadata, and wait for its ACK.
+pub fn write_frame_meta<S: Write + Read>(
+            s: &mut S,
+            f: &Frame,
+            meta: &Meta,
+        ) -> io::Result<()> {
+    put_frame(s, f, meta)?;
+    read_ack(s)
+}
+
+/// Write any parsed frame with the matching `write_*_frame` helper.
+pub fn write_any_frame<S: Write + Read>(s: &mut S, f: &Frame) -> io::Result<()> {
+    write_frame_meta(s, f, &Meta::default())
+}
+
+/// Fill `buf` completely from `s`, distinguishing a clean stream end from a
+/// truncated one — which `Read::read_exact` cannot do, because it reports both
+/// "EOF with 0 bytes read" and "EOF after a partial read" as the same
+/// `UnexpectedEof`, and leaves the buffer contents unspecified.
+///
+/// Returns:
 ///   `Ok(true)`           — `buf` was filled completely.
 ///   `Ok(false)`          — clean EOF: the stream ended with ZERO bytes read
 ///                          into `buf` (i.e. exactly at a frame boundary).
@@ -331,7 +1800,7 @@ pub fn write_chunk_frame<S: Write + Read>(
 /// `Interrupted` is retried, matching `read_exact`'s own behavior; `WouldBlock`
 /// / `TimedOut` are propagated (a stalled peer mid-prefix is an error, not a
 /// reason to spin).
-fn read_exact_or_eof<S: Read>(s: &mut S, buf: &mut [u8]) -> io::Result<bool> {
+pub(crate) fn read_exact_or_eof<S: Read>(s: &mut S, buf: &mut [u8]) -> io::Result<bool> {
     let mut filled = 0;
     while filled < buf.len() {
         match s.read(&mut buf[filled..]) {
@@ -353,10 +1822,28 @@ fn read_exact_or_eof<S: Read>(s: &mut S, buf: &mut [u8]) -> io::Result<bool> {
     Ok(true)
 }
 
-/// Read one frame — single OR chunk — acknowledging it with one ACK byte.
-/// Returns `Ok(None)` only on a *clean* EOF at a frame boundary; truncation
-/// anywhere (prefix, header, or payload) is a hard error.
-pub fn read_frame_ext<S: Read + Write>(s: &mut S) -> io::Result<Option<Frame>> {
+/// Read one frame and its metadata WITHOUT acknowledging it — the reading
+/// half of `put_frame`. Returns `Ok(None)` only on a *clean* EOF at a frame
+/// boundary; truncation anywhere (prefix, header, or payload) is a hard
+/// error. A frame with a checksum trailer (`FRAME_FLAG_CRC`) that doesn't
+/// match is `InvalidData`.
+pub fn get_frame<R: Read>(s: &mut R) -> io::Result<Option<(Frame, Meta)>> {
+    get_frame_as(s, false, MAX_FRAME_SIZE)
+}
+
+/// `get_frame` for sessions that negotiated checksums: a frame without a
+/// checksum trailer is `InvalidData` too.
+pub fn get_checked_frame<R: Read>(s: &mut R) -> io::Result<Option<(Frame, Meta)>> {
+    get_frame_as(s, true, MAX_FRAME_SIZE)
+}
+
+/// `get_frame`, requiring checksums if `crc` and refusing frames whose
+/// body (past any metadata block) is longer than `limit`.
+pub(crate) fn get_frame_as<R: Read>(
+            s: &mut R,
+            crc: bool,
+            limit: usize,
+        ) -> io::Result<Option<(Frame, Meta)>> {
     let mut len_buf = [0u8; 4];
     // Clean EOF before any prefix byte => no more frames. A *partial* prefix
     // is truncation, surfaced as Err by the helper (and propagated by `?`).
@@ -365,22 +1852,111 @@ pub fn read_frame_ext<S: Read + Write>(s: &mut S) -> io::Result<Option<Frame>> {
     }
 
     let raw = u32::from_be_bytes(len_buf);
+    if raw & FRAME_FLAG_CRC == 0 {
+        if crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "frame without the negotiated checksum",
+            ));
+        }
+        return get_body(s, raw, limit).map(Some);
+    }
+    // Everything after the prefix is summed as it is read, so the body is
+    // parsed in one pass; it is only handed out once the trailer matches.
+    let mut summed = Summed { r: s, crc: checksum::Crc32c::new() };
+    summed.crc.update(&len_buf);
+    let got = get_body(&mut summed, raw, limit)?;
+    let sum = summed.crc.finish();
+    let mut trailer = [0u8; 4];
+    s.read_exact(&mut trailer)?;
+    if u32::from_be_bytes(trailer) != sum {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame checksum mismatch"));
+    }
+    Ok(Some(got))
+}
+
+/// A reader that keeps a running CRC of everything read through it.
+struct Summed<'a, R> {
+    r:   &'a mut R,
+    crc: checksum::Crc32c,
+}
+
+impl<R: Read> Read for Summed<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.r.read(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Read the body of the frame whose length prefix was `raw`.
+fn get_body<R: Read>(s: &mut R, raw: u32, limit: usize) -> io::Result<(Frame, Meta)> {
     let is_chunk = raw & FRAME_FLAG_CHUNK != 0;
-    let body_len = (raw & !FRAME_FLAG_CHUNK) as usize;
-    if body_len > MAX_FRAME_SIZE {
+    let is_ctrl  = raw & FRAME_FLAG_CTRL != 0;
+    let has_meta = raw & FRAME_FLAG_META != 0;
+    let mut body_len = (raw & !FRAME_FLAGS) as usize;
+    if is_chunk && is_ctrl {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData, "frame sets both CHUNK and CTRL flags",
+        ));
+    }
+
+    // The metadata block rides on top of the frame-size cap, so adding
+    // metadata in transit never pushes a maximal frame over the limit.
+    let mut meta = Meta::default();
+    if has_meta {
+        if body_len < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "frame too short for metadata block",
+            ));
+        }
+        let mut ml = [0u8; 2];
+        s.read_exact(&mut ml)?;
+        let meta_len = u16::from_be_bytes(ml) as usize;
+        if body_len < 2 + meta_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "metadata block overruns frame",
+            ));
+        }
+        let mut mb = vec![0u8; meta_len];
+        s.read_exact(&mut mb)?;
+        meta = Meta::decode(&mb)?;
+        body_len -= 2 + meta_len;
+    }
+    if body_len > limit {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "incoming frame too large",
         ));
     }
 
+    if is_ctrl {
+        if body_len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "control frame without a kind byte",
+            ));
+        }
+        let mut body = vec![0u8; body_len];
+        s.read_exact(&mut body)?;
+        let frame = match body[0] {
+            CTRL_EOS => Frame::Eos(body.split_off(1)),
+            CTRL_PING => Frame::Ping,
+            CTRL_BYE => Frame::Bye,
+            k => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown control frame kind 0x{k:02x}"),
+                ));
+            }
+        };
+        return Ok((frame, meta));
+    }
+
     if !is_chunk {
         // Original single-frame path, unchanged. Payload truncation is ALWAYS
         // an error: once we've read a valid length we're committed to a frame.
-        let mut payload = vec![0u8; body_len];
+        let mut payload = pool::take(body_len);
         s.read_exact(&mut payload)?;
-        s.write_all(&[ACK_PAYLOAD])?;
-        return Ok(Some(Frame::Msg(payload)));
+        return Ok((Frame::Msg(payload), meta));
     }
 
     if body_len < CHUNK_HEADER_LEN {
@@ -397,27 +1973,45 @@ pub fn read_frame_ext<S: Read + Write>(s: &mut S) -> io::Result<Option<Frame>> {
 
     // Validate before allocating the payload. A malformed header is a fatal
     // protocol error; the session is doomed, so stream desync is moot.
-    if count == 0 || idx >= count {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData, "invalid chunk header (idx/count)",
-        ));
-    }
-    if count > MAX_CHUNKS {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData, "chunk count exceeds MAX_CHUNKS",
-        ));
-    }
+    check_chunk_header(idx, count, io::ErrorKind::InvalidData)?;
 
-    let mut payload = vec![0u8; body_len - CHUNK_HEADER_LEN];
+    let mut payload = pool::take(body_len - CHUNK_HEADER_LEN);
     s.read_exact(&mut payload)?;
-    s.write_all(&[ACK_PAYLOAD])?;
-    Ok(Some(Frame::Chunk { id, idx, count, payload }))
+    Ok((Frame::Chunk { id, idx, count, payload }, meta))
+}
+
+/// Read one frame and its metadata, acknowledging it with one ACK byte.
+pub fn read_frame_meta<S: Read + Write>(
+            s: &mut S,
+        ) -> io::Result<Option<(Frame, Meta)>> {
+    read_frame_as(s, false, MAX_FRAME_SIZE)
+}
+
+/// `read_frame_meta`, with `get_frame_as`'s checksum and size rules.
+pub(crate) fn read_frame_as<S: Read + Write>(
+            s: &mut S,
+            crc: bool,
+            limit: usize,
+        ) -> io::Result<Option<(Frame, Meta)>> {
+    let got = get_frame_as(s, crc, limit)?;
+    if got.is_some() {
+        s.write_all(&[ACK_PAYLOAD])?;
+    }
+    Ok(got)
+}
+
+/// Read one frame — single, chunk, or control — acknowledging it with one
+/// ACK byte. Returns `Ok(None)` only on a *clean* EOF at a frame boundary;
+/// truncation anywhere (prefix, header, or payload) is a hard error.
+/// Metadata, if present, is discarded; see `read_frame_meta`.
+pub fn read_frame_ext<S: Read + Write>(s: &mut S) -> io::Result<Option<Frame>> {
+    Ok(read_frame_meta(s)?.map(|(f, _)| f))
 }
 
 /// Legacy single-frame reader, kept for compatibility. Identical behavior to
 /// before for single frames (`Ok(None)` only on clean boundary EOF). If a
-/// chunk frame arrives, that's an error — transports that may carry
-/// multi-frame traffic should use `read_frame_ext`.
+/// chunk or control frame arrives, that's an error — transports that may
+/// carry multi-frame traffic should use `read_frame_ext`.
 pub fn read_frame<S: Read + Write>(s: &mut S) -> io::Result<Option<Vec<u8>>> {
     match read_frame_ext(s)? {
         None => Ok(None),
@@ -426,6 +2020,10 @@ pub fn read_frame<S: Read + Write>(s: &mut S) -> io::Result<Option<Vec<u8>>> {
             io::ErrorKind::InvalidData,
             "received multi-frame chunk; use read_frame_ext",
         )),
+        Some(Frame::Eos(_) | Frame::Ping | Frame::Bye) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "received control frame; use read_frame_ext",
+        )),
     }
 }
 
@@ -472,7 +2070,7 @@ impl Reassembler {
             ));
         }
 
-        let mismatch = self.partials.get(&id).map_or(false, |p| p.count != count);
+        let mismatch = self.partials.get(&id).is_some_and(|p| p.count != count);
         if mismatch {
             self.partials.remove(&id);
             return Err(io::Error::new(
@@ -481,7 +2079,7 @@ impl Reassembler {
             ));
         }
         let dup = self.partials.get(&id)
-            .map_or(false, |p| p.chunks.contains_key(&idx));
+            .is_some_and(|p| p.chunks.contains_key(&idx));
         if dup {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData, "duplicate chunk",
@@ -515,140 +2113,3024 @@ impl Reassembler {
 
         let p = self.partials.remove(&id).expect("entry exists");
         let mut out = Vec::with_capacity(p.bytes);
+        let mut chunks = p.chunks;
         for i in 0..p.count {
-            out.extend_from_slice(p.chunks.get(&i).expect("all chunks present"));
+            let chunk = chunks.remove(&i).expect("all chunks present");
+            out.extend_from_slice(&chunk);
+            pool::recycle(chunk);
         }
         Ok(Some(out))
     }
 
-    /// Drop partial messages that haven't received a chunk for at least
-    /// `idle_for`; returns how many were discarded. Orphans accumulate when
-    /// a producer dies mid-message or the orchestrator expires a stale
-    /// assignment, so call this on whatever cadence suits your latency
-    /// expectations (e.g. every few hundred recvs, or when the app idles).
-    pub fn gc(&mut self, idle_for: Duration) -> usize {
-        let now = Instant::now();
-        let before = self.partials.len();
-        self.partials
-            .retain(|_, p| now.duration_since(p.last_update) < idle_for);
-        before - self.partials.len()
+    /// Drop partial messages that haven't received a chunk for at least
+    /// `idle_for`; returns how many were discarded. Orphans accumulate when
+    /// a producer dies mid-message or the orchestrator expires a stale
+    /// assignment, so call this on whatever cadence suits your latency
+    /// expectations (e.g. every few hundred recvs, or when the app idles).
+    pub fn gc(&mut self, idle_for: Duration) -> usize {
+        let now = Instant::now();
+        let before = self.partials.len();
+        self.partials
+            .retain(|_, p| now.duration_since(p.last_update) < idle_for);
+        before - self.partials.len()
+    }
+
+    /// Drop the partial message `id`, if any. Returns true if one existed.
+    pub fn discard(&mut self, id: u128) -> bool {
+        self.partials.remove(&id).is_some()
+    }
+
+    /// True while message `id` is partially reassembled.
+    pub fn is_pending(&self, id: u128) -> bool {
+        self.partials.contains_key(&id)
+    }
+
+    /// (number of partial messages, total bytes buffered across them).
+    pub fn pending(&self) -> (usize, usize) {
+        let bytes = self.partials.values().map(|p| p.bytes).sum();
+        (self.partials.len(), bytes)
+    }
+}
+
+/// What a buffered `Producer` does with a message when its buffer is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Wait until the sender thread makes room: backpressure reaches the
+    /// caller, as with an unbuffered producer, just later.
+    #[default]
+    Block,
+    /// Fail at once with `ErrorKind::WouldBlock`; the message is not queued
+    /// and the caller decides whether to retry, drop, or spill it.
+    Error,
+    /// Discard the oldest message still queued to make room, so the newest
+    /// data always gets through; `Producer::dropped` counts the losses.
+    /// Streamed chunks and end-of-stream markers are never discarded: with
+    /// only those queued, `send` waits as under `Block`.
+    DropOldest,
+}
+
+/// When a producer pushes the frames it has written to the orchestrator and
+/// collects their ACKs. Anything but `Always` pipelines frames: `send`
+/// returns once the frame is written, and write errors — and the
+/// orchestrator's backpressure — surface at the next flush instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// After every frame, so each `send` returns once ACKed (the default).
+    #[default]
+    Always,
+    /// After every N frames.
+    EveryFrames(usize),
+    /// On the first send once the oldest unflushed frame is this old.
+    Every(Duration),
+    /// Only on `Producer::flush`, or when the producer is dropped.
+    Manual,
+}
+
+/// How long `Consumer::close` waits for the orchestrator to hang up.
+const CLOSE_WAIT: Duration = Duration::from_secs(5);
+
+/// Cap on pipelined frames, whatever the flush policy: the orchestrator
+/// writes one ACK per frame, and if nobody reads them it eventually stops
+/// reading frames too.
+const MAX_UNFLUSHED_FRAMES: usize = 1024;
+
+/// Options for `Producer::connect_with`. The default is the synchronous
+/// producer `Producer::connect` opens.
+#[derive(Debug, Clone, Default)]
+pub struct ProducerOptions {
+    buffer:    Option<usize>,
+    when_full: WhenFull,
+    flush:     FlushPolicy,
+    linger:    Option<Duration>,
+    delta:     Option<u32>,
+    queue:     Option<String>,
+    name:      Option<String>,
+    auth_key:  Option<psk::Key>,
+    auth:      Option<ClientAuth>,
+    heartbeat: Option<(Duration, Duration)>,
+    checksum:  bool,
+    single_port: bool,
+    throttle_errors: bool,
+    socket:    SocketOptions,
+    #[cfg(feature = "zstd")]
+    compress:  Option<i32>,
+}
+
+impl ProducerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffer up to `capacity` messages in process and write them from a
+    /// background thread, so `send` returns as soon as the message is
+    /// queued rather than when the orchestrator has ACKed it.
+    pub fn buffer(mut self, capacity: usize) -> Self {
+        self.buffer = Some(capacity.max(1));
+        self
+    }
+
+    /// What `send` does when the buffer is full (default: block).
+    pub fn when_full(mut self, policy: WhenFull) -> Self {
+        self.when_full = policy;
+        self
+    }
+
+    /// When written frames are flushed and their ACKs collected (default:
+    /// after every frame). Batching small frames this way saves a network
+    /// round-trip per `send`.
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.flush = policy;
+        self
+    }
+
+    /// Flush frames left unflushed by the flush policy at most `delay`
+    /// after they were written, even if the application stops sending —
+    /// batching without unbounded tail latency. Without a buffer this runs
+    /// a timer thread; a buffered producer's sender thread keeps the time
+    /// itself.
+    pub fn linger(mut self, delay: Duration) -> Self {
+        self.linger = Some(delay);
+        self
+    }
+
+    /// Ping the orchestrator whenever the connection has been quiet for
+    /// `interval`, and fail the connection with `TimedOut` if a ping goes
+    /// unanswered for `timeout` — so an idle producer stays known to NATs
+    /// and firewalls on the way, and a vanished orchestrator is noticed
+    /// before the next send. Pings flush frames the flush policy has left
+    /// pending. The orchestrator drops a producer it hears nothing from
+    /// for `timeout`, and may impose intervals of its own. Without a buffer
+    /// this runs a timer thread, as `linger` does. Fails with `Unsupported`
+    /// if the orchestrator doesn't support heartbeats.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Some((interval, timeout));
+        self
+    }
+
+    /// End every frame with a CRC-32C of the frame (`FRAME_FLAG_CRC`),
+    /// which the orchestrator verifies: a frame corrupted on the way —
+    /// past TCP's weak checksum, by a flaky NIC or middlebox — fails the
+    /// connection with `InvalidData` instead of being queued. Costs four
+    /// bytes a frame. Fails with `Unsupported` if the orchestrator doesn't
+    /// support checksums.
+    pub fn checksum(mut self, on: bool) -> Self {
+        self.checksum = on;
+        self
+    }
+
+    /// Send frames over the control connection instead of a second one to
+    /// the session's data port, for networks where only the orchestrator's
+    /// port is open. Fails with `Unsupported` if the orchestrator doesn't
+    /// support single-port sessions.
+    pub fn single_port(mut self, on: bool) -> Self {
+        self.single_port = on;
+        self
+    }
+
+    /// Give up on connecting, and on each step of the handshake, after
+    /// `timeout` with `TimedOut` (or `WouldBlock`), rather than waiting on
+    /// an orchestrator that doesn't answer for as long as the system does.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.socket.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Turn on TCP keepalive, probing once the connection has been quiet
+    /// for `idle` (whole seconds), so that a silently vanished
+    /// orchestrator or middlebox drop is noticed; see also `heartbeat`.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.socket.keepalive = Some(idle);
+        self
+    }
+
+    /// Ask for socket buffers of these sizes in bytes (SO_SNDBUF,
+    /// SO_RCVBUF) — larger for long fat links, smaller to feel
+    /// backpressure sooner. The system may round or cap them.
+    pub fn buffer_sizes(mut self, send: usize, recv: usize) -> Self {
+        self.socket.send_buffer = Some(send);
+        self.socket.recv_buffer = Some(recv);
+        self
+    }
+
+    /// Have frames the orchestrator refuses under its producer rate limit
+    /// (`QPIPE_PRODUCER_LIMIT` with `mode=reject`), while an admin refuses
+    /// intake (`qpipe-admin pause --refuse`), or when a full queue refuses
+    /// new messages (`--when-full reject-newest`), fail with
+    /// `QuotaExceeded`, instead of being held back until they fit. The
+    /// error comes from the call that collects the frames' ACKs — `send`
+    /// itself under `FlushPolicy::Always` — and counts every refused frame
+    /// since the last flush; the producer stays usable. A refused frame
+    /// stops a buffered producer like any failure.
+    pub fn throttle_errors(mut self, on: bool) -> Self {
+        self.throttle_errors = on;
+        self
+    }
+
+    /// Send each payload as a delta against the previous one, with a full
+    /// keyframe at least every `keyframe_every` messages — for streams of
+    /// slowly changing, fixed-layout records over constrained links. The
+    /// orchestrator rebuilds whole messages on receipt, so consumers are
+    /// unaffected. If the orchestrator doesn't support it, payloads go out
+    /// as they are. See `qpipe::delta`.
+    pub fn delta(mut self, keyframe_every: u32) -> Self {
+        self.delta = Some(keyframe_every);
+        self
+    }
+
+    /// Send to the named queue instead of the default one.
+    pub fn queue(mut self, name: impl Into<String>) -> Self {
+        self.queue = Some(name.into());
+        self
+    }
+
+    /// Name this client, e.g. `ingest-3`, so the orchestrator's log lines,
+    /// session listing and per-client counters (see `admin`) tell it apart
+    /// from other producers. Orchestrators from before client names
+    /// ignore it.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Compress payloads of COMPRESS_MIN bytes or more with zstd at `level`
+    /// (1..=19; 3 is a good start), keeping the original when compression
+    /// doesn't make it smaller. Every consumer of the queue must be built
+    /// with the `zstd` feature to read them. If the orchestrator doesn't
+    /// support it, payloads go out as they are. See `qpipe::zstd`.
+    #[cfg(feature = "zstd")]
+    pub fn compress(mut self, level: i32) -> Self {
+        self.compress = Some(level);
+        self
+    }
+
+    /// Prove this pre-shared key (see `psk`) instead of the one in
+    /// `QPIPE_AUTH_KEY_FILE`.
+    pub fn auth_key(mut self, key: psk::Key) -> Self {
+        self.auth_key = Some(key);
+        self
+    }
+
+    /// Authenticate with these credentials (SCRAM-SHA-256) instead of
+    /// `QPIPE_USER`/`QPIPE_PASSWORD`.
+    pub fn credentials(mut self, creds: Credentials) -> Self {
+        self.auth = Some(ClientAuth::Scram(creds));
+        self
+    }
+
+    /// Authenticate with Kerberos to `service` (e.g.
+    /// `qpipe@orchestrator.example.org`), using the ticket cache.
+    #[cfg(feature = "gssapi")]
+    pub fn kerberos(mut self, service: impl Into<String>) -> Self {
+        self.auth = Some(ClientAuth::Gssapi(service.into()));
+        self
+    }
+}
+
+/// Smaller payloads aren't worth compressing: zstd's frame overhead eats
+/// most of what it would save.
+#[cfg(feature = "zstd")]
+pub const COMPRESS_MIN: usize = 256;
+
+/// A producer's data connection: frames are written through a buffer and
+/// flushed — ACKs collected — as the flush policy says.
+struct Wire {
+    out:     BufWriter<Stream>,
+    policy:  FlushPolicy,
+    /// Frames written since the last flush, each owing an ACK.
+    unacked: usize,
+    /// When the oldest of them was written.
+    oldest:  Option<Instant>,
+    /// Set if delta encoding was negotiated.
+    delta:   Option<delta::Encoder>,
+    /// Set if heartbeats were negotiated.
+    heartbeat: Option<Heartbeat>,
+    /// When frames last went out and were ACKed, for the heartbeat.
+    last_io: Instant,
+    /// Writing a `send_batch`: the flush policy waits for its end.
+    batching: bool,
+    /// Set if checksums were negotiated.
+    crc:     bool,
+    /// The orchestrator's frame-size limit: longer payloads are chunked.
+    max_frame: usize,
+    /// Set if the orchestrator takes a goodbye (`OPT_GOODBYE`) and none
+    /// has been sent yet.
+    goodbye: bool,
+    /// zstd level, if compression was negotiated.
+    #[cfg(feature = "zstd")]
+    compress: Option<i32>,
+}
+
+impl Wire {
+    fn new(stream: Stream, policy: FlushPolicy, delta: Option<delta::Encoder>) -> Self {
+        Self {
+            out: BufWriter::new(stream), policy, unacked: 0, oldest: None, delta,
+            heartbeat: None, last_io: Instant::now(), batching: false, crc: false,
+            max_frame: MAX_FRAME_SIZE, goodbye: false,
+            #[cfg(feature = "zstd")]
+            compress: None,
+        }
+    }
+
+    /// Account for one frame just written, flushing if it's due.
+    fn written(&mut self) -> io::Result<()> {
+        self.unacked += 1;
+        let oldest = *self.oldest.get_or_insert_with(Instant::now);
+        let due = !self.batching && match self.policy {
+            FlushPolicy::Always => true,
+            FlushPolicy::EveryFrames(n) => self.unacked >= n,
+            FlushPolicy::Every(t) => oldest.elapsed() >= t,
+            FlushPolicy::Manual => false,
+        };
+        if due || self.unacked >= MAX_UNFLUSHED_FRAMES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Push out everything written and wait for all of it to be ACKed.
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        if self.unacked == 0 {
+            return Ok(());
+        }
+        let throttled = read_acks(self.out.get_mut(), self.unacked)?;
+        let sent = self.unacked;
+        self.unacked = 0;
+        self.oldest = None;
+        self.last_io = Instant::now();
+        if throttled > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                format!("orchestrator refused {throttled} of {sent} frames: rate limit, refused intake or full queue"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether a payload of `len` bytes goes out as it is, in one frame:
+    /// not delta-encoded, compressed or chunked.
+    fn plain(&self, len: usize) -> bool {
+        #[cfg(feature = "zstd")]
+        if self.compress.is_some() && len >= COMPRESS_MIN {
+            return false;
+        }
+        self.delta.is_none() && len <= self.max_frame
+    }
+
+    /// Write plain single-frame messages without metadata using vectored
+    /// writes — each length prefix and payload straight from where they
+    /// are, no copying into one buffer first — and account for them like
+    /// `written`.
+    fn put_plain(&mut self, payloads: &[&[u8]]) -> io::Result<()> {
+        let prefixes: Vec<[u8; 4]> = payloads.iter()
+            .map(|p| (p.len() as u32 | crc_flag(self.crc)).to_be_bytes())
+            .collect();
+        let trailers: Vec<[u8; 4]> = match self.crc {
+            true => prefixes.iter().zip(payloads).map(|(prefix, payload)| {
+                let mut crc = checksum::Crc32c::new();
+                crc.update(prefix);
+                crc.update(payload);
+                crc.finish().to_be_bytes()
+            }).collect(),
+            false => Vec::new(),
+        };
+        let mut slices: Vec<IoSlice> = prefixes.iter().zip(payloads).enumerate()
+            .flat_map(|(i, (prefix, payload))| {
+                let trailer = trailers.get(i).map_or(&[][..], |t| &t[..]);
+                [IoSlice::new(prefix), IoSlice::new(payload), IoSlice::new(trailer)]
+            })
+            .filter(|slice| !slice.is_empty())
+            .collect();
+        write_all_vectored(&mut self.out, &mut slices)?;
+        self.unacked += payloads.len();
+        self.oldest.get_or_insert_with(Instant::now);
+        if self.unacked >= MAX_UNFLUSHED_FRAMES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// How long until there is background work: frames that will have
+    /// lingered `linger`, or a heartbeat.
+    fn next_tick(&self, linger: Option<Duration>) -> Option<Duration> {
+        let flush = linger.zip(self.oldest).map(|(d, t)| d.saturating_sub(t.elapsed()));
+        let ping = self.heartbeat.map(|hb| hb.interval.saturating_sub(self.last_io.elapsed()));
+        flush.into_iter().chain(ping).min()
+    }
+
+    /// Do the background work that is due.
+    fn tick(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        if linger.zip(self.oldest).is_some_and(|(d, t)| t.elapsed() >= d) {
+            self.flush()?;
+        }
+        match self.heartbeat {
+            Some(hb) if self.last_io.elapsed() >= hb.interval => self.ping(hb),
+            _ => Ok(()),
+        }
+    }
+
+    /// Ping the orchestrator, flushing what is pending first, and give it
+    /// the heartbeat timeout to answer. Backpressure can't hold the answer
+    /// up: under a heartbeat the orchestrator ACKs a frame once it has
+    /// queued it, so the flush has already waited out any backpressure.
+    fn ping(&mut self, hb: Heartbeat) -> io::Result<()> {
+        self.flush()?;
+        put_frame_as(&mut self.out, &Frame::Ping, &Meta::default(), self.crc)?;
+        self.out.flush()?;
+        let s = self.out.get_mut();
+        s.set_read_timeout(Some(hb.timeout))?;
+        let answered = read_ack(s);
+        s.set_read_timeout(None)?;
+        answered.map_err(|e| match Heartbeat::missed(&e) {
+            true => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("orchestrator did not answer a heartbeat in {:?}", hb.timeout),
+            ),
+            false => e,
+        })?;
+        self.last_io = Instant::now();
+        Ok(())
+    }
+}
+
+/// A wire shared with a timer thread that flushes it once the oldest
+/// unflushed frame has lingered long enough, and pings the orchestrator
+/// when a heartbeat is due.
+struct Timed {
+    wire:   Arc<Mutex<Wire>>,
+    stop:   Arc<AtomicBool>,
+    /// The error that stopped the timer, reported by later calls.
+    failed: Arc<Mutex<Option<(io::ErrorKind, String)>>>,
+    timer:  Option<thread::JoinHandle<()>>,
+}
+
+impl Timed {
+    fn start(wire: Wire, linger: Option<Duration>) -> io::Result<Self> {
+        let wire = Arc::new(Mutex::new(wire));
+        let stop = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(Mutex::new(None));
+        let (wire_t, stop_t, failed_t) = (wire.clone(), stop.clone(), failed.clone());
+        let timer = thread::Builder::new()
+            .name("qpipe-timer".into())
+            .spawn(move || {
+                while !stop_t.load(Ordering::Acquire) {
+                    let mut w = wire_t.lock().unwrap();
+                    match w.next_tick(linger) {
+                        Some(wait) if wait.is_zero() => {
+                            if let Err(e) = w.tick(linger) {
+                                *failed_t.lock().unwrap() = Some((e.kind(), e.to_string()));
+                                return;
+                            }
+                        }
+                        Some(wait) => {
+                            drop(w);
+                            thread::park_timeout(wait);
+                        }
+                        None => {
+                            drop(w);
+                            thread::park(); // until the next unflushed frame
+                        }
+                    }
+                }
+            })?;
+        Ok(Self { wire, stop, failed, timer: Some(timer) })
+    }
+
+    /// Run `f` on the wire, waking the timer if `f` left the first
+    /// unflushed frame behind.
+    fn with<T>(&self, f: impl FnOnce(&mut Wire) -> io::Result<T>) -> io::Result<T> {
+        if let Some((kind, msg)) = &*self.failed.lock().unwrap() {
+            return Err(io::Error::new(*kind, format!("producer timer failed: {msg}")));
+        }
+        let mut w = self.wire.lock().unwrap();
+        let idle = w.oldest.is_none();
+        let res = f(&mut w);
+        if idle
+            && w.oldest.is_some()
+            && let Some(t) = &self.timer
+        {
+            t.thread().unpark();
+        }
+        res
+    }
+}
+
+impl Drop for Timed {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(t) = self.timer.take() {
+            t.thread().unpark();
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for Wire {
+    /// The orchestrator only enqueues a frame once its ACK is written, so
+    /// hanging up on unread ACKs could lose the last frames.
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Work for a buffered producer's sender thread.
+enum Outgoing {
+    Msg(Vec<u8>, Meta),
+    /// One chunk of a message streamed by `Producer::send_reader`.
+    Chunk { id: u128, idx: u32, count: u32, payload: Vec<u8>, meta: Meta },
+    Eos(Vec<u8>),
+    /// A `Producer::send_batch`, taking one slot in the buffer.
+    Batch(Vec<Vec<u8>>),
+    /// Reply once everything queued before it has been written and ACKed.
+    Flush(mpsc::SyncSender<()>),
+    /// `Producer::close`: the last job.
+    Bye,
+}
+
+impl Outgoing {
+    /// Messages `WhenFull::DropOldest` may discard: whole ones.
+    fn droppable(&self) -> Option<u64> {
+        match self {
+            Outgoing::Msg(..) => Some(1),
+            Outgoing::Batch(payloads) => Some(payloads.len() as u64),
+            _ => None,
+        }
+    }
+}
+
+/// A buffered producer's queue: bounded like a `sync_channel`, but the
+/// sending side can also evict its oldest message.
+struct Outbox {
+    state:    Mutex<OutboxState>,
+    /// Signalled when a job is queued or the sending side is gone.
+    queued:   Condvar,
+    /// Signalled when a job is taken or the sender thread stops.
+    room:     Condvar,
+    capacity: usize,
+    /// Messages discarded by `WhenFull::DropOldest`.
+    dropped:  AtomicU64,
+}
+
+#[derive(Default)]
+struct OutboxState {
+    jobs:    VecDeque<Outgoing>,
+    /// Set when the producer is dropped: the thread finishes the queue.
+    closed:  bool,
+    /// Set when the sender thread has stopped: nothing queued will be sent.
+    stopped: bool,
+}
+
+/// Why a job could not be queued.
+enum Refused {
+    Full,
+    Stopped,
+}
+
+impl Outbox {
+    fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::default(),
+            queued: Condvar::new(),
+            room: Condvar::new(),
+            capacity,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn put(&self, job: Outgoing, policy: WhenFull) -> Result<(), Refused> {
+        let mut st = self.state.lock().unwrap();
+        loop {
+            if st.stopped {
+                return Err(Refused::Stopped);
+            }
+            if st.jobs.len() < self.capacity {
+                break;
+            }
+            match policy {
+                WhenFull::Error => return Err(Refused::Full),
+                WhenFull::DropOldest => {
+                    if let Some(at) = st.jobs.iter().position(|j| j.droppable().is_some()) {
+                        let old = st.jobs.remove(at).and_then(|j| j.droppable()).unwrap_or(0);
+                        self.dropped.fetch_add(old, Ordering::Relaxed);
+                        break;
+                    }
+                }
+                WhenFull::Block => {}
+            }
+            st = self.room.wait(st).unwrap();
+        }
+        st.jobs.push_back(job);
+        self.queued.notify_one();
+        Ok(())
+    }
+
+    /// The sender thread's next job, waiting at most `wait` if given.
+    /// Disconnected once the producer is gone and the queue is empty.
+    fn take(&self, wait: Option<Duration>) -> Result<Outgoing, mpsc::RecvTimeoutError> {
+        let deadline = wait.map(|w| Instant::now() + w);
+        let mut st = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = st.jobs.pop_front() {
+                self.room.notify_one();
+                return Ok(job);
+            }
+            if st.closed {
+                return Err(mpsc::RecvTimeoutError::Disconnected);
+            }
+            st = match deadline {
+                None => self.queued.wait(st).unwrap(),
+                Some(d) => {
+                    let left = d.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(mpsc::RecvTimeoutError::Timeout);
+                    }
+                    self.queued.wait_timeout(st, left).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// The producer is gone: the thread finishes what is queued, then exits.
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.queued.notify_all();
+    }
+
+    /// The sender thread is gone: drop the queue, failing anyone waiting
+    /// on room or on a flush.
+    fn stop(&self) {
+        let mut st = self.state.lock().unwrap();
+        st.stopped = true;
+        st.jobs.clear();
+        self.room.notify_all();
+    }
+}
+
+/// A buffered producer's queue and the thread draining it to the socket.
+struct SendBuffer {
+    outbox:    Arc<Outbox>,
+    when_full: WhenFull,
+    /// The error that stopped the sender thread, if any. Kept as kind and
+    /// text so every later call can report it.
+    failed:    Arc<Mutex<Option<(io::ErrorKind, String)>>>,
+    worker:    Option<thread::JoinHandle<()>>,
+}
+
+impl SendBuffer {
+    fn start(
+                mut wire: Wire,
+                capacity: usize,
+                when_full: WhenFull,
+                linger: Option<Duration>,
+            ) -> io::Result<Self> {
+        let outbox = Arc::new(Outbox::new(capacity));
+        let (outbox_w, failed) = (Arc::clone(&outbox), Arc::new(Mutex::new(None)));
+        let failed_w = Arc::clone(&failed);
+        let worker = thread::Builder::new()
+            .name("qpipe-producer".into())
+            .spawn(move || {
+                loop {
+                    // With frames lingering unflushed or a heartbeat to
+                    // keep, wait for more work only until that is due.
+                    let job = match outbox_w.take(wire.next_tick(linger)) {
+                        Ok(job) => Some(job),
+                        Err(mpsc::RecvTimeoutError::Timeout) => None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    };
+                    let res = match job {
+                        None => wire.tick(linger),
+                        Some(Outgoing::Msg(payload, meta)) => send_on(&mut wire, &payload, &meta),
+                        Some(Outgoing::Chunk { id, idx, count, payload, meta }) => {
+                            chunk_on(&mut wire, id, idx, count, &payload, &meta)
+                        }
+                        Some(Outgoing::Eos(group)) => eos_on(&mut wire, &group),
+                        Some(Outgoing::Batch(payloads)) => {
+                            let payloads: Vec<&[u8]> = payloads.iter().map(Vec::as_slice).collect();
+                            batch_on(&mut wire, &payloads)
+                        }
+                        Some(Outgoing::Flush(done)) => wire.flush().map(|()| {
+                            let _ = done.send(());
+                        }),
+                        Some(Outgoing::Bye) => bye_on(&mut wire),
+                    };
+                    if let Err(e) = res {
+                        *failed_w.lock().unwrap() = Some((e.kind(), e.to_string()));
+                        outbox_w.stop(); // queued and future sends fail
+                        return;
+                    }
+                }
+            })?;
+        Ok(Self { outbox, when_full, failed, worker: Some(worker) })
+    }
+
+    /// The sender thread's failure, or a generic one if it is gone.
+    fn failure(&self) -> io::Error {
+        match &*self.failed.lock().unwrap() {
+            Some((kind, msg)) => io::Error::new(
+                *kind, format!("buffered producer stopped: {msg}"),
+            ),
+            None => io::Error::new(
+                io::ErrorKind::BrokenPipe, "buffered producer stopped",
+            ),
+        }
+    }
+
+    fn push(&self, job: Outgoing, policy: WhenFull) -> io::Result<()> {
+        if self.failed.lock().unwrap().is_some() {
+            return Err(self.failure());
+        }
+        self.outbox.put(job, policy).map_err(|e| match e {
+            Refused::Full => io::Error::new(
+                io::ErrorKind::WouldBlock, "producer buffer full",
+            ),
+            Refused::Stopped => self.failure(),
+        })
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let (done, wait) = mpsc::sync_channel(1);
+        self.push(Outgoing::Flush(done), WhenFull::Block)?;
+        wait.recv().map_err(|_| self.failure())
+    }
+}
+
+impl Drop for SendBuffer {
+    /// Closing the queue lets the thread finish what is buffered; wait for it
+    /// so messages accepted by `send` are not silently lost.
+    fn drop(&mut self) {
+        self.outbox.close();
+        if let Some(w) = self.worker.take() {
+            let _ = w.join();
+        }
+    }
+}
+
+pub struct Producer {
+    link:    Link,
+    version: u8,
+    /// The orchestrator's frame-size limit (see `max_frame_size`).
+    max_frame: usize,
+    /// The context messages are sent in (see `set_trace_context`).
+    #[cfg(feature = "otel")]
+    trace:   Option<otel::TraceContext>,
+}
+
+enum Link {
+    Direct(Wire),
+    Timed(Timed),
+    Buffered(SendBuffer),
+}
+
+impl Producer {
+    pub fn connect(orchestrator: &str) -> io::Result<Self> {
+        Self::connect_with(orchestrator, &ProducerOptions::default())
+    }
+
+    /// Connect to the named queue (see `ProducerOptions::queue`).
+    pub fn connect_to(orchestrator: &str, queue: &str) -> io::Result<Self> {
+        Self::connect_with(orchestrator, &ProducerOptions::new().queue(queue))
+    }
+
+    /// Connect to an orchestrator's WebSocket listener at
+    /// `ws://host:port/path`. `connect_with` takes such addresses too, for
+    /// sessions with options; they are always single-port.
+    #[cfg(feature = "ws")]
+    pub fn connect_ws(url: &str) -> io::Result<Self> {
+        check_ws_url(url)?;
+        Self::connect_with(url, &ProducerOptions::new().single_port(true))
+    }
+
+    /// Connect with options. With `ProducerOptions::buffer`, sends are
+    /// queued in process and written by a background thread: `send` then
+    /// only fails for invalid messages, a full buffer under
+    /// `WhenFull::Error`, or an earlier write error of the sender thread
+    /// (after which the producer is dead). Use `flush` to wait until
+    /// everything queued so far has been ACKed. Dropping a buffered
+    /// producer blocks until its buffer has been written out.
+    ///
+    /// `ProducerOptions::flush_policy` applies either way: to `send` calls
+    /// directly, or to the background thread's writes.
+    pub fn connect_with(orchestrator: &str, opts: &ProducerOptions) -> io::Result<Self> {
+        let mut req: Vec<(u8, &[u8])> = Vec::new();
+        if opts.delta.is_some() {
+            req.push((OPT_DELTA, &[]));
+        }
+        if let Some(name) = &opts.queue {
+            check_queue_name(name)?;
+            req.push((OPT_QUEUE, name.as_bytes()));
+        }
+        if let Some(name) = &opts.name {
+            check_client_name(name)?;
+            req.push((OPT_CLIENT_NAME, name.as_bytes()));
+        }
+        #[cfg(feature = "zstd")]
+        if opts.compress.is_some() {
+            req.push((OPT_COMPRESS, &[CODEC_ZSTD]));
+        }
+        let heartbeat = opts.heartbeat.map(|(i, t)| Heartbeat::new(i, t)).transpose()?;
+        let heartbeat = heartbeat.map(|hb| hb.encode());
+        if let Some(hb) = &heartbeat {
+            req.push((OPT_HEARTBEAT, hb));
+        }
+        if opts.checksum {
+            req.push((OPT_CHECKSUM, &[]));
+        }
+        if opts.single_port {
+            req.push((OPT_SINGLE_PORT, &[]));
+        }
+        if opts.throttle_errors {
+            req.push((OPT_THROTTLE, &[]));
+        }
+        let (stream, reply, version) = handshake(
+            orchestrator, ROLE_PRODUCER, &req, opts.auth_key.as_ref(), opts.auth.as_ref(), &opts.socket,
+        )?;
+        check_echoed(&req, &reply, OPT_QUEUE, "named queues")?;
+        check_echoed(&req, &reply, OPT_HEARTBEAT, "heartbeats")?;
+        check_echoed(&req, &reply, OPT_CHECKSUM, "frame checksums")?;
+        let delta = opts.delta
+            .filter(|_| reply.iter().any(|(k, _)| *k == OPT_DELTA))
+            .map(delta::Encoder::new);
+        let max_frame = max_frame_echoed(&reply)?;
+        let mut wire = Wire::new(stream, opts.flush, delta);
+        wire.heartbeat = Heartbeat::echoed(&reply)?;
+        wire.crc = opts.checksum;
+        wire.max_frame = max_frame;
+        wire.goodbye = reply.iter().any(|(k, _)| *k == OPT_GOODBYE);
+        #[cfg(feature = "zstd")]
+        {
+            wire.compress = opts.compress.filter(|_| reply.iter().any(|(k, _)| *k == OPT_COMPRESS));
+        }
+        // Under `Always` nothing is ever left unflushed to linger.
+        let linger = opts.linger.filter(|_| opts.flush != FlushPolicy::Always);
+        let link = match (opts.buffer, linger) {
+            (None, None) if wire.heartbeat.is_none() => Link::Direct(wire),
+            (None, linger) => Link::Timed(Timed::start(wire, linger)?),
+            (Some(cap), _) => Link::Buffered(
+                SendBuffer::start(wire, cap, opts.when_full, linger)?,
+            ),
+        };
+        Ok(Self {
+            link, version, max_frame,
+            #[cfg(feature = "otel")]
+            trace: None,
+        })
+    }
+
+    /// The protocol version agreed on with the orchestrator: `PROTOCOL_V1`
+    /// for one from before the hello, else what the hello settled on.
+    /// Features negotiated by handshake option don't need it; changes to
+    /// the framing itself will.
+    pub fn protocol_version(&self) -> u8 {
+        self.version
+    }
+
+    /// The largest frame body the orchestrator accepts, as it advertised in
+    /// the handshake (`OPT_MAX_FRAME`): MAX_FRAME_SIZE unless it was started
+    /// with a smaller `--max-frame-bytes`, or is too old to say.
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame
+    }
+
+    /// The largest message `send` takes: as many chunks as a message may
+    /// have (MAX_CHUNKS), each as large as `max_frame_size` allows.
+    /// MAX_MESSAGE_SIZE under the default limit.
+    pub fn max_message_size(&self) -> usize {
+        (self.max_frame - CHUNK_HEADER_LEN) * MAX_CHUNKS as usize
+    }
+
+    /// Refuse a message longer than `max_message_size`, before any of it
+    /// is written.
+    fn check_size(&self, len: u64) -> io::Result<()> {
+        if len > self.max_message_size() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "message of {len} bytes exceeds the {} the orchestrator accepts \
+                     ({MAX_CHUNKS} chunks of frames up to {} bytes)",
+                    self.max_message_size(), self.max_frame,
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Send one message. Payloads up to `max_frame_size` take the original
+    /// single-frame path, unchanged. Larger payloads are transparently split
+    /// into chunk frames under a fresh random message id; each chunk is
+    /// individually ACKed, so backpressure behaves exactly like a stream of
+    /// single frames. A payload longer than `max_message_size` is
+    /// `InvalidInput`, and nothing is written.
+    pub fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta::default())
+    }
+
+    /// Send one message at a scheduling priority (higher is served first;
+    /// plain `send` is priority 0). Messages of equal priority stay FIFO.
+    /// Orchestrators with priority aging enabled let long-waiting messages
+    /// gain priority over time, so low priorities are never starved.
+    pub fn send_with_priority(&mut self, payload: &[u8], priority: u8) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { priority: Some(priority), ..Meta::default() })
+    }
+
+    /// Send one message with application headers, which the consumer gets
+    /// back in `Message::headers`.
+    pub fn send_with_headers(&mut self, payload: &[u8], headers: &Headers) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { headers: headers.clone(), ..Meta::default() })
+    }
+
+    /// Send one message under a partition key. Messages sharing a key go
+    /// to one consumer at a time, in the order they were sent; different
+    /// keys still spread over all consumers. Orchestrators from before
+    /// partition keys ignore them.
+    pub fn send_with_key(&mut self, payload: &[u8], key: &[u8]) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { partition: Some(key.to_vec()), ..Meta::default() })
+    }
+
+    /// Send one message that expires: if no consumer has taken it within
+    /// `ttl` of the orchestrator queueing it, it is dropped unseen and
+    /// counted as expired. Orchestrators from before TTLs ignore it.
+    pub fn send_with_ttl(&mut self, payload: &[u8], ttl: Duration) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { ttl: Some(ttl), ..Meta::default() })
+    }
+
+    /// Send one message that consumers see only once `delay` has passed,
+    /// e.g. a retry to make later. The orchestrator holds it meanwhile (it
+    /// counts toward the queue's capacity), then queues it as if it had
+    /// just been sent. Orchestrators from before delays deliver it at once.
+    pub fn send_delayed(&mut self, payload: &[u8], delay: Duration) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { delay: Some(delay), ..Meta::default() })
+    }
+
+    /// Send one message under an id unique to it (a sequence number with a
+    /// producer prefix, say): if the queue has accepted a message with the
+    /// same id recently, the orchestrator ACKs this one but drops it, and
+    /// counts it as a duplicate. Sending it again after a failure is then
+    /// safe. Orchestrators from before message ids deliver every copy.
+    pub fn send_with_id(&mut self, payload: &[u8], id: &[u8]) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { message_id: Some(id.to_vec()), ..Meta::default() })
+    }
+
+    /// `send` with metadata attached to every frame of the message: a
+    /// priority, capability tags a consumer needs to receive it
+    /// (`requires`), resource hints (`memory`, `gpus`, `runtime`) that
+    /// pack work onto consumers advertising capacity, a `partition` key,
+    /// a `ttl`, a `delay`, and `headers`. The
+    /// orchestrator assigns `delivery` and `attempt` itself and ignores any
+    /// values set here.
+    pub fn send_with_meta(&mut self, payload: &[u8], meta: &Meta) -> io::Result<()> {
+        check_tags(&meta.requires)?;
+        self.check_size(payload.len() as u64)?;
+        #[cfg(feature = "otel")]
+        let meta = &*self.traced(meta);
+        match &mut self.link {
+            Link::Direct(wire) => send_on(wire, payload, meta),
+            Link::Timed(t) => t.with(|wire| send_on(wire, payload, meta)),
+            Link::Buffered(buf) => buf.push(
+                Outgoing::Msg(payload.to_vec(), meta.clone()), buf.when_full,
+            ),
+        }
+    }
+
+    /// Send several messages at once: small ones go out together in
+    /// vectored writes, and the batch is flushed once, at its end, whatever
+    /// the flush policy — one round of ACKs for the lot instead of one per
+    /// message. Large payloads are chunked, and delta encoding and
+    /// compression apply, as for `send`. A buffered producer queues the
+    /// whole batch as one entry of its buffer, so `WhenFull::Error` takes
+    /// all of it or none.
+    pub fn send_batch(&mut self, payloads: &[&[u8]]) -> io::Result<()> {
+        for p in payloads {
+            self.check_size(p.len() as u64)?;
+        }
+        match &mut self.link {
+            Link::Direct(wire) => batch_on(wire, payloads),
+            Link::Timed(t) => t.with(|wire| batch_on(wire, payloads)),
+            Link::Buffered(buf) => buf.push(
+                Outgoing::Batch(payloads.iter().map(|p| p.to_vec()).collect()), buf.when_full,
+            ),
+        }
+    }
+
+    /// Send the `len` bytes `reader` yields as one message, reading and
+    /// writing a chunk at a time, so a large payload (a file, a detector
+    /// image) is never held in memory whole. Consumers receive it like any
+    /// other message, or stream it out again with `Consumer::recv_writer`.
+    ///
+    /// `len` is needed up front because every chunk carries the chunk
+    /// count. If `reader` fails or ends early, the chunks already sent make
+    /// up a message that can never complete; consumers drop it with
+    /// `Consumer::gc_partials`, as they do when a producer dies mid-message.
+    /// A buffered producer blocks for room for each chunk, whatever its
+    /// `when_full`. Streamed payloads are not compressed.
+    pub fn send_reader<R: Read>(&mut self, reader: R, len: u64) -> io::Result<()> {
+        self.send_reader_with_meta(reader, len, &Meta::default())
+    }
+
+    /// `send_reader` with metadata; see `send_with_meta`.
+    pub fn send_reader_with_meta<R: Read>(
+                &mut self,
+                mut reader: R,
+                len: u64,
+                meta: &Meta,
+            ) -> io::Result<()> {
+        check_tags(&meta.requires)?;
+        self.check_size(len)?;
+        let short = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => io::Error::new(
+                e.kind(), format!("reader ended before the {len} bytes promised"),
+            ),
+            _ => e,
+        };
+        let len = len as usize;
+        if len <= self.max_frame {
+            let mut payload = vec![0u8; len];
+            reader.read_exact(&mut payload).map_err(short)?;
+            return self.send_with_meta(&payload, meta);
+        }
+        #[cfg(feature = "otel")]
+        let meta = &*self.traced(meta);
+
+        let per_chunk = self.max_frame - CHUNK_HEADER_LEN;
+        let count = len.div_ceil(per_chunk) as u32;
+        let id = new_msg_id()?;
+        let mut chunk = vec![0u8; per_chunk];
+        for idx in 0..count {
+            let n = (len - idx as usize * per_chunk).min(per_chunk);
+            reader.read_exact(&mut chunk[..n]).map_err(short)?;
+            let chunk = &chunk[..n];
+            match &mut self.link {
+                Link::Direct(wire) => chunk_on(wire, id, idx, count, chunk, meta)?,
+                Link::Timed(t) => t.with(|wire| chunk_on(wire, id, idx, count, chunk, meta))?,
+                Link::Buffered(buf) => buf.push(
+                    Outgoing::Chunk { id, idx, count, payload: chunk.to_vec(), meta: meta.clone() },
+                    WhenFull::Block,
+                )?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Send what follows in trace context `ctx`: each message then carries
+    /// it in a `traceparent` header, unless it has one already. `None`, the
+    /// default, adds nothing. See `otel`.
+    #[cfg(feature = "otel")]
+    pub fn set_trace_context(&mut self, ctx: Option<otel::TraceContext>) {
+        self.trace = ctx;
+    }
+
+    /// `meta` with the producer's trace context in its headers, if it has
+    /// one and they don't.
+    #[cfg(feature = "otel")]
+    fn traced<'a>(&self, meta: &'a Meta) -> std::borrow::Cow<'a, Meta> {
+        match &self.trace {
+            Some(ctx) if meta.headers.get(otel::HEADER_TRACEPARENT).is_none() => {
+                let mut meta = meta.clone();
+                otel::inject(&mut meta.headers, ctx);
+                std::borrow::Cow::Owned(meta)
+            }
+            _ => std::borrow::Cow::Borrowed(meta),
+        }
+    }
+
+    /// Mark the end of a stream. Consumers see `Delivery::Eos(group)` once
+    /// every message this orchestrator accepted before the marker has been
+    /// delivered. Use an empty `group` to mean "the whole queue", or a label
+    /// per batch when several streams share one queue. A buffered producer
+    /// queues the marker behind its buffered messages, always blocking when
+    /// full: a dropped marker would leave consumers waiting forever. The
+    /// marker is flushed whatever the flush policy, for the same reason.
+    pub fn send_eos(&mut self, group: &[u8]) -> io::Result<()> {
+        if group.len() >= self.max_frame {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("EOS group label too large for the orchestrator's {}-byte frames", self.max_frame),
+            ));
+        }
+        match &mut self.link {
+            Link::Direct(wire) => eos_on(wire, group),
+            Link::Timed(t) => t.with(|wire| eos_on(wire, group)),
+            Link::Buffered(buf) => buf.push(Outgoing::Eos(group.to_vec()), WhenFull::Block),
+        }
+    }
+
+    /// Wait until every message sent so far has been ACKed by the
+    /// orchestrator. A no-op under `FlushPolicy::Always` without a buffer,
+    /// whose sends already wait for that.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.link {
+            Link::Direct(wire) => wire.flush(),
+            Link::Timed(t) => t.with(Wire::flush),
+            Link::Buffered(buf) => buf.flush(),
+        }
+    }
+
+    /// How many messages a buffered producer has discarded under
+    /// `WhenFull::DropOldest` to make room for newer ones; a batch counts
+    /// as its messages. Always 0 otherwise.
+    pub fn dropped(&self) -> u64 {
+        match &self.link {
+            Link::Buffered(buf) => buf.outbox.dropped.load(Ordering::Relaxed),
+            _ => 0,
+        }
+    }
+
+    /// End the session: wait until everything sent has been ACKed, as
+    /// `flush` does, then say goodbye (`CTRL_BYE`), so that the
+    /// orchestrator logs a clean close rather than a lost connection.
+    /// Orchestrators from before goodbyes are just hung up on. Dropping a
+    /// producer flushes too, but has no way to report a failure.
+    pub fn close(mut self) -> io::Result<()> {
+        match &mut self.link {
+            Link::Direct(wire) => bye_on(wire),
+            Link::Timed(t) => t.with(bye_on),
+            Link::Buffered(buf) => {
+                buf.push(Outgoing::Bye, WhenFull::Block)?;
+                buf.flush()
+            }
+        }
+    }
+}
+
+/// Options for `ReconnectingProducer::connect` and
+/// `ReconnectingConsumer::connect`. The spool settings apply to producers
+/// only.
+#[derive(Debug, Clone)]
+pub struct ReconnectOptions {
+    spool_dir:   Option<PathBuf>,
+    spool_key:   Option<at_rest::Key>,
+    backoff_min: Duration,
+    backoff_max: Duration,
+    queue:       Option<String>,
+    name:        Option<String>,
+    standby:     Option<String>,
+    on_event:    Option<Listener>,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        Self {
+            spool_dir:   None,
+            spool_key:   None,
+            backoff_min: Duration::from_millis(100),
+            backoff_max: Duration::from_secs(30),
+            queue:       None,
+            name:        None,
+            standby:     None,
+            on_event:    None,
+        }
+    }
+}
+
+impl ReconnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Journal sends to this directory while the orchestrator is
+    /// unreachable, and replay them in order once it is back. Records left
+    /// by an earlier run are replayed too.
+    pub fn spool_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.spool_dir = Some(dir.into());
+        self
+    }
+
+    /// Encrypt spooled records with this key (ChaCha20-Poly1305, see
+    /// `at_rest`). The same key is needed to replay them.
+    pub fn encryption_key(mut self, key: at_rest::Key) -> Self {
+        self.spool_key = Some(key);
+        self
+    }
+
+    /// Delay between reconnect attempts: starts at `min`, doubles after
+    /// each failed attempt up to `max` (defaults: 100ms, 30s).
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.backoff_min = min;
+        self.backoff_max = max.max(min);
+        self
+    }
+
+    /// Send to (or receive from) the named queue instead of the default one.
+    pub fn queue(mut self, name: impl Into<String>) -> Self {
+        self.queue = Some(name.into());
+        self
+    }
+
+    /// Name this client on every connection; see `ProducerOptions::name`.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Fail over to the orchestrator at `addr` — a standby, see
+    /// `orchestrator::OrchestratorOptions::standby_of` — when the one
+    /// given to `connect` is out of reach: each attempt that fails moves on
+    /// to the other address, and the backoff only grows once both have
+    /// failed. The first connection tries both too.
+    pub fn standby(mut self, addr: impl Into<String>) -> Self {
+        self.standby = Some(addr.into());
+        self
+    }
+
+    /// Call `f` when the connection drops, when a reconnect attempt fails,
+    /// and when the client is connected again — e.g. to log outages or
+    /// export them as metrics. It runs on the caller's thread, inside the
+    /// send or receive that noticed.
+    pub fn on_event(mut self, f: impl Fn(&ReconnectEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(Listener(Arc::new(f)));
+        self
+    }
+}
+
+/// What `ReconnectOptions::on_event` is told.
+#[derive(Debug)]
+pub enum ReconnectEvent<'a> {
+    /// The connection failed with this error.
+    Disconnected(&'a io::Error),
+    /// Reconnect attempt `attempt` (counting from 1) failed; the next is
+    /// due after `retry_in`.
+    RetryFailed { attempt: u32, error: &'a io::Error, retry_in: Duration },
+    /// Connected again, on attempt `attempts`, `downtime` after the
+    /// connection was lost (or the first attempt failed).
+    Reconnected { attempts: u32, downtime: Duration },
+}
+
+#[derive(Clone)]
+struct Listener(Arc<dyn Fn(&ReconnectEvent) + Send + Sync>);
+
+impl fmt::Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Listener")
+    }
+}
+
+/// Reconnect bookkeeping shared by the reconnecting clients: where the
+/// next attempt goes and when it is due, how long to back off after it,
+/// and the events.
+struct Retry {
+    /// The orchestrator, then its standby if there is one.
+    addrs:        Vec<String>,
+    at:           usize,
+    min:          Duration,
+    max:          Duration,
+    delay:        Duration,
+    next_attempt: Instant,
+    failures:     u32,
+    down_since:   Option<Instant>,
+    on_event:     Option<Listener>,
+}
+
+impl Retry {
+    fn new(orchestrator: &str, opts: &ReconnectOptions) -> Self {
+        Self {
+            addrs:        std::iter::once(orchestrator).chain(opts.standby.as_deref()).map(str::to_string).collect(),
+            at:           0,
+            min:          opts.backoff_min,
+            max:          opts.backoff_max,
+            delay:        opts.backoff_min,
+            next_attempt: Instant::now(),
+            failures:     0,
+            down_since:   None,
+            on_event:     opts.on_event.clone(),
+        }
+    }
+
+    fn emit(&self, event: ReconnectEvent) {
+        if let Some(Listener(f)) = &self.on_event {
+            f(&event);
+        }
+    }
+
+    fn addr(&self) -> &str {
+        &self.addrs[self.at]
+    }
+
+    /// Whether the current round of attempts, which starts at the
+    /// orchestrator and goes on to its standby, has an address left.
+    fn untried(&self) -> bool {
+        self.at != 0
+    }
+
+    fn due(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    /// Sleep until the next attempt is due.
+    fn wait(&self) {
+        thread::sleep(self.next_attempt.saturating_duration_since(Instant::now()));
+    }
+
+    fn lost(&mut self, e: &io::Error) {
+        self.down_since.get_or_insert_with(Instant::now);
+        self.emit(ReconnectEvent::Disconnected(e));
+    }
+
+    fn failed(&mut self, e: &io::Error) {
+        self.down_since.get_or_insert_with(Instant::now);
+        self.failures += 1;
+        // The next address right away; back off once all have failed.
+        self.at = (self.at + 1) % self.addrs.len();
+        let retry_in = if self.untried() { Duration::ZERO } else { self.delay };
+        self.next_attempt = Instant::now() + retry_in;
+        self.emit(ReconnectEvent::RetryFailed {
+            attempt: self.failures, error: e, retry_in,
+        });
+        if !self.untried() {
+            self.delay = (self.delay * 2).min(self.max);
+        }
+    }
+
+    fn connected(&mut self) {
+        if let Some(since) = self.down_since.take() {
+            self.emit(ReconnectEvent::Reconnected {
+                attempts: self.failures + 1, downtime: since.elapsed(),
+            });
+        }
+        self.failures = 0;
+        self.delay = self.min;
+    }
+}
+
+/// Errors worth reconnecting over. Refused options, bad credentials and
+/// protocol violations would only fail again.
+fn is_transient(e: &io::Error) -> bool {
+    !matches!(e.kind(),
+        io::ErrorKind::InvalidInput
+        | io::ErrorKind::InvalidData
+        | io::ErrorKind::PermissionDenied
+        | io::ErrorKind::Unsupported)
+}
+
+/// A producer that survives orchestrator outages. A failed send drops the
+/// connection; later calls reconnect (re-running the handshake) once the
+/// backoff allows. Without a spool directory, sends made while disconnected
+/// fail with `NotConnected`. With one, they are journaled to disk and
+/// replayed in order — ahead of any new message — after reconnecting, so
+/// `send` only fails for invalid messages or disk errors. A spooled
+/// message too large for the orchestrator it reconnects to (see
+/// `Producer::max_message_size`) is dropped on replay.
+///
+/// Delivery is at least once: a message whose ACK was lost with the
+/// connection is sent again. Disconnects and reconnects are reported to
+/// `ReconnectOptions::on_event`.
+pub struct ReconnectingProducer {
+    opts:  ReconnectOptions,
+    conn:  Option<Producer>,
+    spool: Option<Spool>,
+    retry: Retry,
+}
+
+impl ReconnectingProducer {
+    /// Connect to `orchestrator`. Fails if it is unreachable and there is
+    /// no spool directory; with one, starts disconnected and spools.
+    pub fn connect(orchestrator: &str, opts: &ReconnectOptions) -> io::Result<Self> {
+        let spool = opts.spool_dir.as_deref()
+            .map(|dir| Spool::open(dir, opts.spool_key.clone()))
+            .transpose()?;
+        let mut p = Self {
+            opts:  opts.clone(),
+            conn:  None,
+            spool,
+            retry: Retry::new(orchestrator, opts),
+        };
+        let mut first = p.catch_up();
+        while first.is_err() && p.retry.untried() {
+            first = p.catch_up();
+        }
+        if let Err(e) = first
+            && p.spool.is_none()
+        {
+            return Err(e);
+        }
+        Ok(p)
+    }
+
+    pub fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta::default())
+    }
+
+    /// See `Producer::send_with_priority`.
+    pub fn send_with_priority(&mut self, payload: &[u8], priority: u8) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { priority: Some(priority), ..Meta::default() })
+    }
+
+    /// See `Producer::send_with_key`.
+    pub fn send_with_key(&mut self, payload: &[u8], key: &[u8]) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { partition: Some(key.to_vec()), ..Meta::default() })
+    }
+
+    /// See `Producer::send_with_ttl`. The TTL starts when the orchestrator
+    /// queues the message, so time spent in the spool does not count.
+    pub fn send_with_ttl(&mut self, payload: &[u8], ttl: Duration) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { ttl: Some(ttl), ..Meta::default() })
+    }
+
+    /// See `Producer::send_delayed`. The delay starts when the orchestrator
+    /// accepts the message, so time spent in the spool does not count.
+    pub fn send_delayed(&mut self, payload: &[u8], delay: Duration) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { delay: Some(delay), ..Meta::default() })
+    }
+
+    /// See `Producer::send_with_id`. The id rides along through the spool
+    /// and every resend, so the orchestrator drops the copies.
+    pub fn send_with_id(&mut self, payload: &[u8], id: &[u8]) -> io::Result<()> {
+        self.send_with_meta(payload, &Meta { message_id: Some(id.to_vec()), ..Meta::default() })
+    }
+
+    pub fn send_with_meta(&mut self, payload: &[u8], meta: &Meta) -> io::Result<()> {
+        check_tags(&meta.requires)?;
+        if payload.len() > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "message exceeds MAX_MESSAGE_SIZE",
+            ));
+        }
+        self.deliver(Record::Msg(payload.to_vec(), Box::new(meta.clone())))
+    }
+
+    pub fn send_eos(&mut self, group: &[u8]) -> io::Result<()> {
+        self.deliver(Record::Eos(group.to_vec()))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    /// Messages journaled and not yet replayed.
+    pub fn spooled(&self) -> usize {
+        self.spool.as_ref().map_or(0, Spool::len)
+    }
+
+    /// Reconnect if needed (and the backoff allows) and replay the spool.
+    /// Sends do this on their own; call it to drain the spool while idle.
+    /// Returns whether everything has reached the orchestrator.
+    pub fn catch_up(&mut self) -> io::Result<bool> {
+        if self.conn.is_none() {
+            if !self.retry.due() {
+                return Ok(false);
+            }
+            let opts = ProducerOptions {
+                queue: self.opts.queue.clone(), name: self.opts.name.clone(), ..ProducerOptions::default()
+            };
+            match Producer::connect_with(self.retry.addr(), &opts) {
+                Ok(p) => {
+                    self.conn = Some(p);
+                    self.retry.connected();
+                }
+                Err(e) => {
+                    self.retry.failed(&e);
+                    return Err(e);
+                }
+            }
+        }
+        let (Some(p), Some(spool)) = (&mut self.conn, &mut self.spool) else {
+            return Ok(true);
+        };
+        if spool.is_empty() {
+            return Ok(true);
+        }
+        // A record refused outright (too large for this orchestrator's
+        // frame-size limit) would hold up everything behind it forever;
+        // it is dropped instead.
+        let replayed = spool.replay(|rec| match send_record(p, rec) {
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => Ok(()),
+            sent => sent,
+        });
+        if let Err(e) = replayed {
+            self.conn = None;
+            self.retry.lost(&e);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn deliver(&mut self, rec: Record) -> io::Result<()> {
+        let caught_up = self.catch_up();
+        if let (Ok(true), Some(p)) = (&caught_up, &mut self.conn) {
+            match send_record(p, &rec) {
+                Ok(()) => return Ok(()),
+                // Refused before anything was written (too large for this
+                // orchestrator): the connection is fine, and spooling the
+                // record would only have it refused again on replay.
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => return Err(e),
+                Err(e) => {
+                    self.conn = None; // reconnect on the next call
+                    self.retry.lost(&e);
+                    if self.spool.is_none() {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        match &mut self.spool {
+            Some(spool) => spool.append(&rec),
+            None => Err(caught_up.err().unwrap_or_else(|| io::Error::new(
+                io::ErrorKind::NotConnected, "orchestrator unreachable; retrying later",
+            ))),
+        }
+    }
+}
+
+fn send_record(p: &mut Producer, rec: &Record) -> io::Result<()> {
+    match rec {
+        Record::Msg(payload, meta) => p.send_with_meta(payload, meta),
+        Record::Eos(group) => p.send_eos(group),
+    }
+}
+
+/// Write one message, compressed if the wire says so and it pays off.
+fn send_on(wire: &mut Wire, payload: &[u8], meta: &Meta) -> io::Result<()> {
+    #[cfg(feature = "zstd")]
+    if let Some(level) = wire.compress
+        && meta.codec.is_none()
+        && payload.len() >= COMPRESS_MIN
+    {
+        let packed = zstd::compress(payload, level)?;
+        if packed.len() < payload.len() {
+            return put_message(wire, &packed, &Meta { codec: Some(CODEC_ZSTD), ..meta.clone() });
+        }
+    }
+    put_message(wire, payload, meta)
+}
+
+/// Write one message (chunking it if needed); each frame is flushed and
+/// ACKed as the wire's flush policy says.
+fn put_message(wire: &mut Wire, payload: &[u8], meta: &Meta) -> io::Result<()> {
+    // An encoded payload may be one byte longer, so a delta-encoding wire
+    // only encodes payloads that still fit a single frame after that;
+    // chunked messages go out as they are.
+    if let Some(enc) = &mut wire.delta
+        && payload.len() < wire.max_frame
+    {
+        put_msg(&mut wire.out, &enc.encode(payload), meta, wire.crc)?;
+        return wire.written();
+    }
+    if payload.len() <= wire.max_frame {
+        put_msg(&mut wire.out, payload, meta, wire.crc)?;
+        return wire.written();
+    }
+
+    let per_chunk = wire.max_frame - CHUNK_HEADER_LEN;
+    let count = payload.len().div_ceil(per_chunk); // >= 2 here, <= MAX_CHUNKS
+    let id = new_msg_id()?;
+    for (idx, chunk) in payload.chunks(per_chunk).enumerate() {
+        chunk_on(wire, id, idx as u32, count as u32, chunk, meta)?;
+    }
+    Ok(())
+}
+
+fn chunk_on(
+            wire:  &mut Wire,
+            id:    u128,
+            idx:   u32,
+            count: u32,
+            chunk: &[u8],
+            meta:  &Meta,
+        ) -> io::Result<()> {
+    put_chunk(&mut wire.out, id, idx, count, chunk, meta, wire.crc)?;
+    wire.written()
+}
+
+/// Write a `send_batch`: runs of plain payloads through `put_plain`, the
+/// rest one by one, then flush.
+fn batch_on(wire: &mut Wire, payloads: &[&[u8]]) -> io::Result<()> {
+    let meta = Meta::default();
+    wire.batching = true;
+    let written = (|| -> io::Result<()> {
+        let mut rest = payloads;
+        while let Some(first) = rest.first() {
+            let run = rest.iter()
+                .take_while(|p| wire.plain(p.len()))
+                .count()
+                .min(MAX_UNFLUSHED_FRAMES - wire.unacked);
+            if run == 0 {
+                send_on(wire, first, &meta)?;
+                rest = &rest[1..];
+            } else {
+                wire.put_plain(&rest[..run])?;
+                rest = &rest[run..];
+            }
+        }
+        Ok(())
+    })();
+    wire.batching = false;
+    written?;
+    wire.flush()
+}
+
+/// Flush, then send the goodbye if the orchestrator takes one. Nothing
+/// goes out after it, heartbeat pings included.
+fn bye_on(wire: &mut Wire) -> io::Result<()> {
+    wire.flush()?;
+    if std::mem::take(&mut wire.goodbye) {
+        wire.heartbeat = None;
+        put_frame_as(&mut wire.out, &Frame::Bye, &Meta::default(), wire.crc)?;
+        wire.unacked += 1;
+        wire.flush()?;
+    }
+    Ok(())
+}
+
+fn eos_on(wire: &mut Wire, group: &[u8]) -> io::Result<()> {
+    put_frame_as(&mut wire.out, &Frame::Eos(group.to_vec()), &Meta::default(), wire.crc)?;
+    wire.unacked += 1;
+    wire.flush()
+}
+
+/// Session options for `Consumer::connect_with`. The default is the plain
+/// session `Consumer::connect` opens.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    ack_mode:     bool,
+    weight:       Option<u32>,
+    capabilities: Vec<String>,
+    resources:    Option<(u64, u32)>,
+    queue:        Option<String>,
+    name:         Option<String>,
+    auth_key:     Option<psk::Key>,
+    auth:         Option<ClientAuth>,
+    heartbeat:    Option<(Duration, Duration)>,
+    checksum:     bool,
+    single_port:  bool,
+    filter:       Option<Filter>,
+    socket:       SocketOptions,
+}
+
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Settle messages by explicit `Consumer::ack` instead of on receipt.
+    /// The orchestrator redelivers a message that isn't acked within its
+    /// visibility timeout, or whose consumer disconnects first, and
+    /// dead-letters it once its retry budget is spent — all per the
+    /// orchestrator's retry policy. Connecting fails with `Unsupported` on
+    /// an at-most-once queue; an at-least-once one is in ack mode anyway
+    /// (see `Consumer::delivery_policy`).
+    pub fn ack_mode(mut self, on: bool) -> Self {
+        self.ack_mode = on;
+        self
+    }
+
+    /// Capacity hint for ack mode: how many messages this consumer works on
+    /// at once (e.g. its core count). The orchestrator keeps at most
+    /// `weight` unacknowledged messages in flight to it, so heavier workers
+    /// get proportionally more of the queue. Without a weight an ack-mode
+    /// consumer's window is unbounded. Requires `ack_mode(true)`.
+    pub fn weight(mut self, weight: u32) -> Self {
+        self.weight = Some(weight.max(1));
+        self
+    }
+
+    /// Capability tags this consumer offers (e.g. `gpu`, `bigmem`,
+    /// `site=nersc`). Messages sent with `Meta::requires` go only to
+    /// consumers advertising every required tag. Tags may not be empty or
+    /// contain commas.
+    pub fn capabilities<I, S>(mut self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.capabilities = tags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Capacity for resource-hinted work: memory in bytes and GPU count.
+    /// The orchestrator only hands this consumer messages whose `memory`
+    /// and `gpus` hints fit what its unacked messages leave free, so a
+    /// node fills up with as much work as it can hold. Requires
+    /// `ack_mode(true)`: capacity is returned as messages are acked.
+    pub fn resources(mut self, memory: u64, gpus: u32) -> Self {
+        self.resources = Some((memory, gpus));
+        self
+    }
+
+    /// Receive from the named queue instead of the default one.
+    pub fn queue(mut self, name: impl Into<String>) -> Self {
+        self.queue = Some(name.into());
+        self
+    }
+
+    /// Subscribe to the messages whose headers pass `filter` only. The
+    /// orchestrator hands this consumer nothing else, and gives what it
+    /// passes to consumers with a matching filter before consumers without
+    /// one; the rest goes to consumers without a filter. Fails with
+    /// `Unsupported` if the orchestrator doesn't support filters.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Name this client; see `ProducerOptions::name`.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Ping the orchestrator every `interval` from a background thread, so
+    /// that it can tell a consumer busy between receives from a dead one,
+    /// and fail a receive with `TimedOut` once nothing has come from the
+    /// orchestrator for `timeout` — it pings an idle consumer every
+    /// `interval` in turn. The orchestrator drops a consumer it hears
+    /// nothing from for `timeout`, requeueing what it held, and may impose
+    /// intervals of its own. Fails with `Unsupported` if the orchestrator
+    /// doesn't support heartbeats.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Some((interval, timeout));
+        self
+    }
+
+    /// Have the orchestrator end every frame with a CRC-32C of the frame
+    /// (`FRAME_FLAG_CRC`), verified on receipt: a receive fails with
+    /// `InvalidData` on a corrupted frame, which is never ACKed, so the
+    /// orchestrator delivers the message again as for any lost connection.
+    /// Fails with `Unsupported` if the orchestrator doesn't support
+    /// checksums.
+    pub fn checksum(mut self, on: bool) -> Self {
+        self.checksum = on;
+        self
+    }
+
+    /// Receive over the control connection instead of a second one to the
+    /// session's data port; see `ProducerOptions::single_port`.
+    pub fn single_port(mut self, on: bool) -> Self {
+        self.single_port = on;
+        self
+    }
+
+    /// See `ProducerOptions::connect_timeout`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.socket.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// See `ProducerOptions::keepalive`.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.socket.keepalive = Some(idle);
+        self
+    }
+
+    /// See `ProducerOptions::buffer_sizes`.
+    pub fn buffer_sizes(mut self, send: usize, recv: usize) -> Self {
+        self.socket.send_buffer = Some(send);
+        self.socket.recv_buffer = Some(recv);
+        self
+    }
+
+    /// Prove this pre-shared key (see `psk`) instead of the one in
+    /// `QPIPE_AUTH_KEY_FILE`.
+    pub fn auth_key(mut self, key: psk::Key) -> Self {
+        self.auth_key = Some(key);
+        self
+    }
+
+    /// Authenticate with these credentials (SCRAM-SHA-256) instead of
+    /// `QPIPE_USER`/`QPIPE_PASSWORD`.
+    pub fn credentials(mut self, creds: Credentials) -> Self {
+        self.auth = Some(ClientAuth::Scram(creds));
+        self
+    }
+
+    /// Authenticate with Kerberos to `service` (e.g.
+    /// `qpipe@orchestrator.example.org`), using the ticket cache.
+    #[cfg(feature = "gssapi")]
+    pub fn kerberos(mut self, service: impl Into<String>) -> Self {
+        self.auth = Some(ClientAuth::Gssapi(service.into()));
+        self
+    }
+}
+
+/// Undo a producer's compression (`Meta::codec`) on a complete message.
+fn decode_payload(payload: Vec<u8>, codec: Option<u8>) -> io::Result<Vec<u8>> {
+    match codec {
+        None => Ok(payload),
+        #[cfg(feature = "zstd")]
+        Some(CODEC_ZSTD) => zstd::decompress(&payload, MAX_MESSAGE_SIZE),
+        Some(c) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("message is compressed with codec {c}, which this build can't decode \
+                     (CODEC_ZSTD needs the `zstd` feature)"),
+        )),
+    }
+}
+
+pub struct Consumer {
+    stream: Stream,
+    asm: Reassembler,
+    ack_mode: bool,
+    /// Set if an at-least-once queue put the session in ack mode unasked:
+    /// `recv` and the receives built on it keep the tag and ack for the
+    /// caller.
+    must_ack: bool,
+    /// Tags of what those receives handed out, acked by the next receive
+    /// or `close`: the caller has processed a message once it asks for more.
+    owed: Vec<u64>,
+    visibility: Option<Duration>,
+    /// Ack mode: (delivery tag, highest attempt) of each partial message.
+    tags: HashMap<u128, (u64, u32)>,
+    /// Deliveries that completed while `recv_writer` was streaming another
+    /// message; handed out before anything new is read.
+    ready: VecDeque<Delivery>,
+    /// Heartbeat timeout: the longest the orchestrator may stay silent.
+    idle: Option<Duration>,
+    /// When the last frame arrived, pings included.
+    heard: Instant,
+    pinger: Option<Pinger>,
+    /// Held while writing to the back-channel, so that a frame ACK, a ping
+    /// or a record written from another thread never lands inside another.
+    lock: Arc<Mutex<()>>,
+    /// Set once the orchestrator has closed the connection between frames.
+    closed: bool,
+    /// Set if checksums were negotiated.
+    crc: bool,
+    /// Set if the orchestrator takes `ACK_NACK` records.
+    nack: bool,
+    /// Set if the orchestrator takes an `ACK_BYE` record.
+    goodbye: bool,
+    /// Protocol version agreed on in the handshake.
+    version: u8,
+}
+
+/// A consumer's heartbeat thread, writing `ACK_PING` to the back-channel
+/// every interval whatever the application does between receives, under
+/// the consumer's back-channel `lock`.
+struct Pinger {
+    stop:   Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Pinger {
+    fn start(mut stream: Stream, interval: Duration, lock: Arc<Mutex<()>>) -> io::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_t = stop.clone();
+        let thread = thread::Builder::new()
+            .name("qpipe-heartbeat".into())
+            .spawn(move || loop {
+                thread::park_timeout(interval);
+                if stop_t.load(Ordering::Acquire) {
+                    return;
+                }
+                let _g = lock.lock().unwrap();
+                match stream.write_all(&[ACK_PING]).and_then(|()| stream.flush()) {
+                    // While a receive polls, the socket is briefly
+                    // nonblocking; the next ping will do.
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(_) => return, // the next receive finds out
+                    Ok(()) => {}
+                }
+            })?;
+        Ok(Self { stop, thread: Some(thread) })
+    }
+}
+
+impl Drop for Pinger {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(t) = self.thread.take() {
+            t.thread().unpark();
+            let _ = t.join();
+        }
+    }
+}
+
+/// An `ACK_MESSAGE` record for `tag`.
+fn ack_record(ack_mode: bool, tag: u64) -> io::Result<[u8; 9]> {
+    check_ack_mode(ack_mode)?;
+    let mut rec = [0u8; 9];
+    rec[0] = ACK_MESSAGE;
+    rec[1..].copy_from_slice(&tag.to_be_bytes());
+    Ok(rec)
+}
+
+/// An `ACK_NACK` record for `tag`, if the orchestrator takes them.
+fn nack_record(ack_mode: bool, nack: bool, tag: u64, retries: u32) -> io::Result<[u8; 13]> {
+    check_ack_mode(ack_mode)?;
+    if !nack {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported, "orchestrator does not support nacks",
+        ));
+    }
+    let mut rec = [0u8; 13];
+    rec[0] = ACK_NACK;
+    rec[1..9].copy_from_slice(&tag.to_be_bytes());
+    rec[9..].copy_from_slice(&retries.to_be_bytes());
+    Ok(rec)
+}
+
+/// An `ACK_WINDOW` record.
+fn window_record(ack_mode: bool, window: u32) -> io::Result<[u8; 5]> {
+    check_ack_mode(ack_mode)?;
+    let mut rec = [0u8; 5];
+    rec[0] = ACK_WINDOW;
+    rec[1..].copy_from_slice(&window.to_be_bytes());
+    Ok(rec)
+}
+
+fn check_ack_mode(ack_mode: bool) -> io::Result<()> {
+    match ack_mode {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "consumer is not in ack mode",
+        )),
+    }
+}
+
+/// Write one back-channel record whole, under the consumer's `lock`.
+fn write_back(stream: &mut Stream, lock: &Mutex<()>, rec: &[u8]) -> io::Result<()> {
+    let _g = lock.lock().unwrap();
+    stream.write_all(rec)?;
+    stream.flush()
+}
+
+/// The message `Consumer::recv_writer` is streaming out.
+struct Outflow {
+    id:      u128,
+    count:   u32,
+    /// The next chunk to write; later ones that arrive first wait in `early`.
+    next:    u32,
+    early:   BTreeMap<u32, Vec<u8>>,
+    len:     u64,
+    tag:     Option<u64>,
+    attempt: u32,
+    headers: Headers,
+}
+
+impl Consumer {
+    pub fn connect(orchestrator: &str) -> io::Result<Self> {
+        Self::connect_with(orchestrator, &ConnectOptions::default())
+    }
+
+    /// Connect to the named queue (see `ConnectOptions::queue`).
+    pub fn connect_to(orchestrator: &str, queue: &str) -> io::Result<Self> {
+        Self::connect_with(orchestrator, &ConnectOptions::new().queue(queue))
+    }
+
+    /// Connect to an orchestrator's WebSocket listener (see
+    /// `Producer::connect_ws`).
+    #[cfg(feature = "ws")]
+    pub fn connect_ws(url: &str) -> io::Result<Self> {
+        check_ws_url(url)?;
+        Self::connect_with(url, &ConnectOptions::new().single_port(true))
+    }
+
+    /// Connect with session options. Fails with `Unsupported` if the
+    /// orchestrator does not accept an option that was asked for.
+    pub fn connect_with(
+                orchestrator: &str,
+                opts: &ConnectOptions,
+            ) -> io::Result<Self> {
+        if opts.weight.is_some() && !opts.ack_mode {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "consumer weight requires ack mode",
+            ));
+        }
+        if opts.resources.is_some() && !opts.ack_mode {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "consumer resources require ack mode",
+            ));
+        }
+        check_tags(&opts.capabilities)?;
+        let weight = opts.weight.map(u32::to_be_bytes);
+        let caps = opts.capabilities.join(",");
+        let resources = opts.resources.map(|(mem, gpus)| {
+            let mut v = mem.to_be_bytes().to_vec();
+            v.extend_from_slice(&gpus.to_be_bytes());
+            v
+        });
+        let mut req: Vec<(u8, &[u8])> = Vec::new();
+        if opts.ack_mode {
+            req.push((OPT_ACK_MODE, &[]));
+            req.push((OPT_NACK, &[]));
+        }
+        if let Some(w) = &weight {
+            req.push((OPT_WEIGHT, w));
+        }
+        if !caps.is_empty() {
+            req.push((OPT_CAPABILITIES, caps.as_bytes()));
+        }
+        if let Some(r) = &resources {
+            req.push((OPT_RESOURCES, r));
+        }
+        if let Some(name) = &opts.queue {
+            check_queue_name(name)?;
+            req.push((OPT_QUEUE, name.as_bytes()));
+        }
+        if let Some(name) = &opts.name {
+            check_client_name(name)?;
+            req.push((OPT_CLIENT_NAME, name.as_bytes()));
+        }
+        let filter = opts.filter.as_ref().map(Filter::to_string);
+        if let Some(f) = &filter {
+            req.push((OPT_FILTER, f.as_bytes()));
+        }
+        let heartbeat = opts.heartbeat.map(|(i, t)| Heartbeat::new(i, t)).transpose()?;
+        let heartbeat = heartbeat.map(|hb| hb.encode());
+        if let Some(hb) = &heartbeat {
+            req.push((OPT_HEARTBEAT, hb));
+        }
+        if opts.checksum {
+            req.push((OPT_CHECKSUM, &[]));
+        }
+        if opts.single_port {
+            req.push((OPT_SINGLE_PORT, &[]));
+        }
+        let (stream, reply, version) = handshake(
+            orchestrator, ROLE_CONSUMER, &req, opts.auth_key.as_ref(), opts.auth.as_ref(), &opts.socket,
+        )?;
+
+        for (key, what) in [
+            (OPT_ACK_MODE, "ack mode"),
+            (OPT_WEIGHT, "consumer weights"),
+            (OPT_CAPABILITIES, "capability tags"),
+            (OPT_RESOURCES, "consumer resources"),
+            (OPT_QUEUE, "named queues"),
+            (OPT_HEARTBEAT, "heartbeats"),
+            (OPT_CHECKSUM, "frame checksums"),
+            (OPT_FILTER, "subscription filters"),
+        ] {
+            check_echoed(&req, &reply, key, what)?;
+        }
+        // A queue with a delivery policy has ack mode refused, or imposed.
+        // Orchestrators from before policies don't say, and those spoken
+        // to as protocol 1 aren't asked (see `handshake`).
+        let delivery = reply.iter()
+            .find(|(k, _)| *k == OPT_DELIVERY)
+            .map(|(_, v)| v.first().copied().and_then(DeliveryPolicy::from_code).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unknown delivery policy")
+            }))
+            .transpose()?;
+        let must_ack = delivery == Some(DeliveryPolicy::AtLeastOnce) && !opts.ack_mode;
+        if must_ack {
+            check_echoed(&[(OPT_ACK_MODE, &[])], &reply, OPT_ACK_MODE, "ack mode")?;
+        }
+        let visibility = reply.iter()
+            .find(|(k, _)| *k == OPT_VISIBILITY_MS)
+            .map(|(_, v)| be_u64(v).map(Duration::from_millis))
+            .transpose()?;
+        let heartbeat = Heartbeat::echoed(&reply)?;
+        let lock = Arc::new(Mutex::new(()));
+        let pinger = match heartbeat {
+            Some(hb) => {
+                stream.set_read_timeout(Some(hb.timeout))?;
+                Some(Pinger::start(stream.try_clone()?, hb.interval, lock.clone())?)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            stream,
+            asm: Reassembler::new(),
+            ack_mode: opts.ack_mode || must_ack,
+            must_ack,
+            owed: Vec::new(),
+            visibility,
+            tags: HashMap::new(),
+            ready: VecDeque::new(),
+            idle: heartbeat.map(|hb| hb.timeout),
+            heard: Instant::now(),
+            pinger,
+            lock,
+            closed: false,
+            crc: opts.checksum,
+            nack: reply.iter().any(|(k, _)| *k == OPT_NACK),
+            goodbye: reply.iter().any(|(k, _)| *k == OPT_GOODBYE),
+            version,
+        })
+    }
+
+    /// Ack mode: how long the orchestrator waits for `ack` before it
+    /// redelivers a message. `None` outside ack mode.
+    pub fn visibility_timeout(&self) -> Option<Duration> {
+        self.visibility
+    }
+
+    /// How the session settles messages: `AtLeastOnce` in ack mode, whether
+    /// asked for or imposed by an at-least-once queue. Then messages from
+    /// `recv_ack` and `recv_ext` carry a tag to `ack` once processed. In a
+    /// session that an at-least-once queue put in ack mode unasked, `recv`
+    /// and the receives built on it hand out messages without a tag, and
+    /// ack them at the next receive, or at `close`: a message the process
+    /// dies holding is redelivered.
+    pub fn delivery_policy(&self) -> DeliveryPolicy {
+        match self.ack_mode {
+            true => DeliveryPolicy::AtLeastOnce,
+            false => DeliveryPolicy::AtMostOnce,
+        }
+    }
+
+    /// The protocol version agreed on with the orchestrator; see
+    /// `Producer::protocol_version`.
+    pub fn protocol_version(&self) -> u8 {
+        self.version
+    }
+
+    /// Ack mode: report the message delivered under `tag` as processed. Acks
+    /// for a tag that has already timed out are ignored by the orchestrator
+    /// (the message is being redelivered under a fresh tag).
+    pub fn ack(&mut self, tag: u64) -> io::Result<()> {
+        let rec = ack_record(self.ack_mode, tag)?;
+        write_back(&mut self.stream, &self.lock, &rec)
+    }
+
+    /// Ack mode: report the message delivered under `tag` as failed, so it
+    /// is redelivered — to this consumer or another — after the retry
+    /// policy's backoff, without waiting out the visibility timeout. Past
+    /// the policy's retry count it is dead-lettered instead. Nacks for a
+    /// stale tag are ignored, like acks. Fails with `Unsupported` if the
+    /// orchestrator predates nacks.
+    pub fn nack(&mut self, tag: u64) -> io::Result<()> {
+        self.nack_with_retries(tag, u32::MAX)
+    }
+
+    /// Ack mode: `nack`, dead-lettering the message instead if it has
+    /// already been redelivered `retries` times. The retry policy's count
+    /// still applies if lower; 0 sends the message straight to the
+    /// dead-letter path.
+    pub fn nack_with_retries(&mut self, tag: u64, retries: u32) -> io::Result<()> {
+        let rec = nack_record(self.ack_mode, self.nack, tag, retries)?;
+        write_back(&mut self.stream, &self.lock, &rec)
+    }
+
+    /// Ack mode: change how many unacked messages the orchestrator may have
+    /// in flight to this consumer, replacing the handshake `weight` (or
+    /// bounding a consumer that had none). Acks return slots as before. A
+    /// window of 0 stops new deliveries until a later call reopens it, for
+    /// a consumer that has to stop taking work without disconnecting;
+    /// remaining chunks of messages it already holds still arrive.
+    pub fn set_window(&mut self, window: u32) -> io::Result<()> {
+        let rec = window_record(self.ack_mode, window)?;
+        write_back(&mut self.stream, &self.lock, &rec)
+    }
+
+    /// Leave the session. The orchestrator stops dispatching to this
+    /// consumer and requeues what it holds — in ack mode, every message
+    /// not yet acked — as for a lost connection, but logs a clean close;
+    /// this returns once it has hung up, or after CLOSE_WAIT. Frames
+    /// already on their way are discarded unACKed, so they go to another
+    /// consumer. Sessions without a back-channel reader (neither ack mode
+    /// nor heartbeats) and orchestrators from before goodbyes are just hung
+    /// up on; the orchestrator notices at its next delivery. What `recv`
+    /// and the like still owe acks for is acked first.
+    pub fn close(mut self) -> io::Result<()> {
+        if !self.closed {
+            self.settle()?;
+        }
+        // No pings after the goodbye.
+        drop(self.pinger.take());
+        if self.goodbye && !self.closed {
+            self.stream.write_all(&[ACK_BYE])?;
+            self.stream.flush()?;
+            self.stream.set_read_timeout(Some(CLOSE_WAIT))?;
+            match io::copy(&mut self.stream, &mut io::sink()) {
+                Ok(_) => {}
+                Err(e) if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match self.stream.shutdown(std::net::Shutdown::Both) {
+            Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Ack mode: blocks until the next message, skipping end-of-stream
+    /// notices like `recv`. The returned message always carries the tag to
+    /// pass to `ack` once it has been processed.
+    pub fn recv_ack(&mut self) -> io::Result<Message> {
+        if !self.ack_mode {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "consumer is not in ack mode",
+            ));
+        }
+        loop {
+            if let Delivery::Message(msg) = self.recv_ext()? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Blocks until the next complete *message* arrives (or the orchestrator
+    /// closes). Single-frame messages return as soon as their frame is read.
+    /// Chunks are buffered internally and the call keeps reading frames —
+    /// completing other messages first if they finish earlier — until some
+    /// message completes. Messages are therefore returned in COMPLETION
+    /// order, and a stalled multi-frame message never blocks other traffic.
+    /// Every frame is ACKed as it is read, so orchestrator-side flow control
+    /// is unaffected by reassembly.
+    ///
+    /// The message comes with its headers, and in ack mode with the tag
+    /// `ack` needs. End-of-stream notices are skipped; use `recv_ext` to see
+    /// them. If an at-least-once queue imposed ack mode, the message has no
+    /// tag: this acks it at the next receive instead; see `delivery_policy`.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        self.settle()?;
+        Ok(self.next_message(None)?.expect("no deadline"))
+    }
+
+    /// Like `recv`, but leaves the message's payload in `buf`, replacing
+    /// what was there, and returns its length. `buf`'s old allocation goes to the
+    /// buffer `pool`, where the next frame read picks it up: a loop that
+    /// keeps passing the same buffer settles into reusing a few
+    /// allocations instead of making one per message.
+    pub fn recv_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let payload = self.recv()?.payload;
+        pool::recycle(std::mem::replace(buf, payload));
+        Ok(buf.len())
+    }
+
+    /// Like `recv`, but gives up after `timeout`, returning `Ok(None)` if
+    /// no message completed by then. Chunks read in the meantime stay
+    /// buffered for the next call, and a frame is never read in part, so
+    /// the stream stays in step. The timeout applies to waiting for frames:
+    /// one that has started arriving is read to the end.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        self.settle()?;
+        self.next_message(Some(Instant::now() + timeout))
+    }
+
+    /// Like `recv`, but returns `Ok(None)` instead of waiting when no
+    /// message can be completed from frames that have already arrived.
+    pub fn try_recv(&mut self) -> io::Result<Option<Message>> {
+        self.recv_timeout(Duration::ZERO)
+    }
+
+    /// Blocks for one message like `recv`, then takes up to `max` in all
+    /// without waiting again: the rest are those that can be completed
+    /// from frames that have already arrived. `max` of 0 is taken as 1.
+    pub fn recv_batch(&mut self, max: usize) -> io::Result<Vec<Message>> {
+        let mut batch = vec![self.recv()?];
+        while batch.len() < max {
+            match self.next_message(Some(Instant::now()))? {
+                Some(msg) => batch.push(msg),
+                None => break,
+            }
+        }
+        Ok(batch)
+    }
+
+    /// `recv_ext` with a timeout; see `recv_timeout`.
+    pub fn recv_ext_timeout(&mut self, timeout: Duration) -> io::Result<Option<Delivery>> {
+        self.settle()?;
+        self.next_delivery(Some(Instant::now() + timeout))
+    }
+
+    /// Like `recv`, but also surfaces end-of-stream notices as
+    /// `Delivery::Eos`, so batch consumers know when to finalize instead of
+    /// blocking forever.
+    pub fn recv_ext(&mut self) -> io::Result<Delivery> {
+        self.settle()?;
+        Ok(self.next_delivery(None)?.expect("no deadline"))
+    }
+
+    /// The next message, for `recv` and the receives built on it; if the
+    /// queue imposed ack mode, its tag is taken out and owed.
+    fn next_message(&mut self, deadline: Option<Instant>) -> io::Result<Option<Message>> {
+        loop {
+            match self.next_delivery(deadline)? {
+                Some(Delivery::Message(mut msg)) => {
+                    if self.must_ack {
+                        self.owed.extend(msg.tag.take());
+                    }
+                    return Ok(Some(msg));
+                }
+                Some(Delivery::Eos(_)) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Ack what `recv` and the like handed out; see `owed`.
+    fn settle(&mut self) -> io::Result<()> {
+        for tag in std::mem::take(&mut self.owed) {
+            self.ack(tag)?;
+        }
+        Ok(())
+    }
+
+    /// Leave the message `recv` and the like just returned unacked, for
+    /// a caller that couldn't process it.
+    #[cfg(feature = "serde")]
+    pub(crate) fn disown(&mut self) {
+        self.owed.pop();
+    }
+
+    /// Wait for a frame to start arriving until `deadline`: true once one
+    /// has (or the connection has ended), without consuming anything.
+    fn frame_ready(&mut self, deadline: Instant) -> io::Result<bool> {
+        let left = deadline.saturating_duration_since(Instant::now());
+        // With a heartbeat, waiting longer than its timeout is pointless.
+        let wait = self.idle.map_or(left, |t| left.min(t));
+        // A zero read timeout is an error; poll without blocking instead.
+        let peeked = if wait.is_zero() {
+            self.stream.set_nonblocking(true)?;
+            let r = self.stream.peek(&mut [0u8]);
+            self.stream.set_nonblocking(false)?;
+            r
+        } else {
+            self.stream.set_read_timeout(Some(wait))?;
+            let r = self.stream.peek(&mut [0u8]);
+            self.stream.set_read_timeout(self.idle)?;
+            r
+        };
+        match peeked {
+            Ok(_) => Ok(true),
+            Err(e) if Heartbeat::missed(&e) && wait < left => Err(self.silent()),
+            Err(e) if Heartbeat::missed(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read and ACK the next frame; the connection ending here is an error.
+    fn read_next(&mut self) -> io::Result<(Frame, Meta)> {
+        let got = get_frame_as(&mut self.stream, self.crc, MAX_FRAME_SIZE).and_then(|got| {
+            if got.is_some() {
+                let _g = self.lock.lock().unwrap();
+                self.stream.write_all(&[ACK_PAYLOAD])?;
+            }
+            Ok(got)
+        });
+        match got {
+            Ok(Some(got)) => {
+                self.heard = Instant::now();
+                Ok(got)
+            }
+            Ok(None) => {
+                self.closed = true;
+                Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof, "orchestrator closed consumer connection",
+                ))
+            }
+            Err(e) if self.idle.is_some() && Heartbeat::missed(&e) => Err(self.silent()),
+            // A corrupted frame isn't ACKed: hang up, so that the
+            // orchestrator delivers it again rather than wait for the ACK.
+            Err(e) if self.crc && e.kind() == io::ErrorKind::InvalidData => {
+                self.stream.shutdown(std::net::Shutdown::Both).ok();
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The error for an orchestrator that missed its heartbeats.
+    fn silent(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("nothing from the orchestrator in {:?}", self.idle.unwrap_or_default()),
+        )
+    }
+
+    /// Write the next message to `w` and describe it. A multi-frame message
+    /// is written a chunk at a time as its chunks arrive, so it is never
+    /// held in memory whole; only chunks that overtake an earlier one wait.
+    /// Other messages that complete in the meantime are kept for the next
+    /// receive. End-of-stream notices are skipped, as by `recv`.
+    ///
+    /// If this fails part way — the connection drops, or in ack mode the
+    /// message is redelivered under a new tag — `w` holds an incomplete
+    /// message; discard it. Compressed messages are decompressed whole.
+    pub fn recv_writer<W: Write>(&mut self, mut w: W) -> io::Result<Streamed> {
+        self.settle()?;
+        let mut out: Option<Outflow> = None;
+        loop {
+            // Whole messages are written in one go; once streaming has
+            // started, they wait their turn in `ready`.
+            if out.is_none() {
+                match self.ready.pop_front() {
+                    Some(Delivery::Message(m)) => {
+                        w.write_all(&m.payload)?;
+                        return Ok(Streamed {
+                            len: m.payload.len() as u64,
+                            tag: m.tag, attempt: m.attempt, headers: m.headers,
+                        });
+                    }
+                    Some(Delivery::Eos(_)) => continue,
+                    None => {}
+                }
+            }
+
+            let (frame, meta) = self.read_next()?;
+            let attempt = meta.attempt.unwrap_or(0);
+            let (id, idx, count, payload) = match frame {
+                Frame::Chunk { id, idx, count, payload }
+                    if out.as_ref().map_or(
+                        meta.codec.is_none() && !self.asm.is_pending(id), |o| o.id == id,
+                    ) => (id, idx, count, payload),
+                frame => {
+                    // Not the streamed message: complete it as recv_ext would.
+                    if let Some(d) = self.absorb(frame, meta)? {
+                        self.ready.push_back(d);
+                    }
+                    continue;
+                }
+            };
+
+            let o = out.get_or_insert_with(|| Outflow {
+                id, count, next: 0, early: BTreeMap::new(), len: 0,
+                tag: meta.delivery, attempt, headers: meta.headers.clone(),
+            });
+            if count != o.count || idx >= count || count > MAX_CHUNKS
+                || idx < o.next || o.early.contains_key(&idx)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData, "invalid, duplicate or mismatched chunk",
+                ));
+            }
+            if meta.delivery != o.tag {
+                return Err(io::Error::other(
+                    "message was redelivered while it was being streamed; the output is incomplete",
+                ));
+            }
+            o.attempt = o.attempt.max(attempt);
+            o.early.insert(idx, payload);
+            while let Some(chunk) = o.early.remove(&o.next) {
+                w.write_all(&chunk)?;
+                o.len += chunk.len() as u64;
+                o.next += 1;
+            }
+            if o.next == o.count {
+                let o = out.take().expect("streaming");
+                return Ok(Streamed { len: o.len, tag: o.tag, attempt: o.attempt, headers: o.headers });
+            }
+        }
+    }
+
+    /// The next delivery; None if `deadline` passes before one completes.
+    fn next_delivery(&mut self, deadline: Option<Instant>) -> io::Result<Option<Delivery>> {
+        if let Some(d) = self.ready.pop_front() {
+            return Ok(Some(d));
+        }
+        loop {
+            if let Some(deadline) = deadline
+                && !self.frame_ready(deadline)?
+            {
+                return Ok(None);
+            }
+            let (frame, meta) = self.read_next()?;
+            if let Some(d) = self.absorb(frame, meta)? {
+                return Ok(Some(d));
+            }
+        }
+    }
+
+    /// Take in one frame: the delivery it completes, if any.
+    fn absorb(&mut self, frame: Frame, meta: Meta) -> io::Result<Option<Delivery>> {
+        let attempt = meta.attempt.unwrap_or(0);
+        match frame {
+            Frame::Msg(payload) => {
+                let payload = decode_payload(payload, meta.codec)?;
+                Ok(Some(Delivery::Message(Message {
+                    payload, tag: meta.delivery, attempt, headers: meta.headers,
+                })))
+            }
+            Frame::Chunk { id, idx, count, payload } => {
+                let (tag, attempt) = match meta.delivery {
+                    Some(tag) => self.track_tag(id, tag, attempt),
+                    None => (None, attempt),
+                };
+                let Some(payload) = self.asm.absorb(id, idx, count, payload)? else {
+                    return Ok(None);
+                };
+                self.tags.remove(&id);
+                let payload = decode_payload(payload, meta.codec)?;
+                Ok(Some(Delivery::Message(Message { payload, tag, attempt, headers: meta.headers })))
+            }
+            Frame::Eos(group) => Ok(Some(Delivery::Eos(group))),
+            // Already answered by its ACK; orchestrators don't say goodbye.
+            Frame::Ping | Frame::Bye => Ok(None),
+        }
+    }
+
+    /// A redelivered multi-frame message arrives under a fresh tag; any
+    /// chunks buffered from the earlier delivery are stale, so start over.
+    fn track_tag(&mut self, id: u128, tag: u64, attempt: u32) -> (Option<u64>, u32) {
+        let entry = self.tags.entry(id).or_insert((tag, attempt));
+        if entry.0 != tag {
+            self.asm.discard(id);
+            *entry = (tag, attempt);
+        }
+        entry.1 = entry.1.max(attempt);
+        (Some(entry.0), entry.1)
+    }
+
+    /// Discard partial multi-frame messages that haven't received a chunk
+    /// for at least `idle_for`. Returns how many messages were dropped.
+    pub fn gc_partials(&mut self, idle_for: Duration) -> usize {
+        let n = self.asm.gc(idle_for);
+        let asm = &self.asm;
+        self.tags.retain(|id, _| asm.is_pending(*id));
+        n
+    }
+
+    /// (partial message count, bytes buffered) currently held for reassembly.
+    pub fn pending_partials(&self) -> (usize, usize) {
+        self.asm.pending()
+    }
+}
+
+/// Receive with `for` loops and iterator adapters: each item is a message
+/// as `recv` returns it. Iteration ends when the orchestrator closes the
+/// connection (as it does after draining on shutdown), or after yielding an
+/// error. Ack-mode consumers need their tags, so they loop on `recv_ack`.
+impl IntoIterator for Consumer {
+    type Item = io::Result<Message>;
+    type IntoIter = Messages<Consumer>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Messages { consumer: self, done: false }
+    }
+}
+
+/// Like iterating over the `Consumer` itself, but leaves it usable after
+/// the loop (`for msg in &mut consumer { ... }`).
+impl<'a> IntoIterator for &'a mut Consumer {
+    type Item = io::Result<Message>;
+    type IntoIter = Messages<&'a mut Consumer>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Messages { consumer: self, done: false }
+    }
+}
+
+/// The messages of a `Consumer`, owned or borrowed; see its `IntoIterator`.
+pub struct Messages<C> {
+    consumer: C,
+    done:     bool,
+}
+
+impl<C: BorrowMut<Consumer>> Messages<C> {
+    /// The consumer being iterated over.
+    pub fn into_inner(self) -> C {
+        self.consumer
+    }
+}
+
+impl<C: BorrowMut<Consumer>> Iterator for Messages<C> {
+    type Item = io::Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let c = self.consumer.borrow_mut();
+        match c.recv() {
+            Ok(msg) => Some(Ok(msg)),
+            Err(_) if c.closed => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<C: BorrowMut<Consumer>> FusedIterator for Messages<C> {}
+
+/// How often a `BufferedConsumer`'s reader thread, waiting for frames,
+/// checks whether it should stop.
+const PREFETCH_POLL: Duration = Duration::from_millis(100);
+
+/// A consumer that reads ahead: a background thread receives up to
+/// `prefetch` deliveries into a bounded local queue while the application
+/// processes earlier ones, so that `recv` usually returns at once and
+/// network jitter is smoothed over. Deliveries come out in the order the
+/// `Consumer` would have returned them; once the connection fails, those
+/// already queued are handed out first, then every receive returns the
+/// error.
+///
+/// Prefetched messages have left the orchestrator. Outside ack mode they
+/// are settled when read, so up to `prefetch` of them are lost if the
+/// process dies; in ack mode they are redelivered, but their visibility
+/// timeout runs while they wait in the queue.
+pub struct BufferedConsumer {
+    rx:         Option<mpsc::Receiver<io::Result<Delivery>>>,
+    /// A clone of the connection for the back-channel: the reader thread
+    /// has the consumer.
+    back:       Stream,
+    lock:       Arc<Mutex<()>>,
+    stop:       Arc<AtomicBool>,
+    reader:     Option<thread::JoinHandle<Consumer>>,
+    /// The error that stopped the reader thread, reported by later calls.
+    failed:     Option<(io::ErrorKind, String)>,
+    ack_mode:   bool,
+    must_ack:   bool,
+    /// See `Consumer::owed`.
+    owed:       Vec<u64>,
+    nack:       bool,
+    visibility: Option<Duration>,
+    version:    u8,
+}
+
+impl BufferedConsumer {
+    /// Connect with session options (see `Consumer::connect_with`) and
+    /// read ahead up to `prefetch` deliveries; 0 is taken as 1.
+    pub fn connect_with(
+                orchestrator: &str,
+                opts: &ConnectOptions,
+                prefetch: usize,
+            ) -> io::Result<Self> {
+        Self::new(Consumer::connect_with(orchestrator, opts)?, prefetch)
+    }
+
+    /// Read ahead on a connected consumer, up to `prefetch` deliveries.
+    pub fn new(mut consumer: Consumer, prefetch: usize) -> io::Result<Self> {
+        let back = consumer.stream.try_clone()?;
+        let (tx, rx) = mpsc::sync_channel(prefetch.max(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_r = stop.clone();
+        let mut this = Self {
+            rx: Some(rx),
+            back,
+            lock: consumer.lock.clone(),
+            stop,
+            reader: None,
+            failed: None,
+            ack_mode: consumer.ack_mode,
+            must_ack: consumer.must_ack,
+            owed: std::mem::take(&mut consumer.owed),
+            nack: consumer.nack,
+            visibility: consumer.visibility,
+            version: consumer.version,
+        };
+        this.reader = Some(thread::Builder::new()
+            .name("qpipe-prefetch".into())
+            .spawn(move || {
+                while !stop_r.load(Ordering::Acquire) {
+                    let got = match consumer.recv_ext_timeout(PREFETCH_POLL) {
+                        // Polls are too short to miss a heartbeat by.
+                        Ok(None) if consumer.idle.is_some_and(|t| consumer.heard.elapsed() >= t) => {
+                            Err(consumer.silent())
+                        }
+                        Ok(None) => continue,
+                        Ok(Some(d)) => Ok(d),
+                        Err(e) => Err(e),
+                    };
+                    let failed = got.is_err();
+                    // A dropped receiver means we're being stopped.
+                    if tx.send(got).is_err() || failed {
+                        break;
+                    }
+                }
+                consumer
+            })?);
+        Ok(this)
+    }
+
+    /// See `Consumer::visibility_timeout`.
+    pub fn visibility_timeout(&self) -> Option<Duration> {
+        self.visibility
+    }
+
+    /// See `Consumer::delivery_policy`.
+    pub fn delivery_policy(&self) -> DeliveryPolicy {
+        match self.ack_mode {
+            true => DeliveryPolicy::AtLeastOnce,
+            false => DeliveryPolicy::AtMostOnce,
+        }
+    }
+
+    /// See `Consumer::protocol_version`.
+    pub fn protocol_version(&self) -> u8 {
+        self.version
+    }
+
+    /// Ack mode: see `Consumer::ack`. Acks go out at once, whatever the
+    /// reader thread is doing.
+    pub fn ack(&mut self, tag: u64) -> io::Result<()> {
+        let rec = ack_record(self.ack_mode, tag)?;
+        write_back(&mut self.back, &self.lock, &rec)
+    }
+
+    /// Ack mode: see `Consumer::nack`.
+    pub fn nack(&mut self, tag: u64) -> io::Result<()> {
+        self.nack_with_retries(tag, u32::MAX)
+    }
+
+    /// Ack mode: see `Consumer::nack_with_retries`.
+    pub fn nack_with_retries(&mut self, tag: u64, retries: u32) -> io::Result<()> {
+        let rec = nack_record(self.ack_mode, self.nack, tag, retries)?;
+        write_back(&mut self.back, &self.lock, &rec)
+    }
+
+    /// Ack mode: see `Consumer::set_window`. Deliveries already prefetched
+    /// are still handed out.
+    pub fn set_window(&mut self, window: u32) -> io::Result<()> {
+        let rec = window_record(self.ack_mode, window)?;
+        write_back(&mut self.back, &self.lock, &rec)
+    }
+
+    /// Blocks until the next message; see `Consumer::recv`.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        self.settle()?;
+        Ok(self.next_message(None)?.expect("no deadline"))
+    }
+
+    /// Receive into `buf`; see `Consumer::recv_into`.
+    pub fn recv_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let payload = self.recv()?.payload;
+        pool::recycle(std::mem::replace(buf, payload));
+        Ok(buf.len())
+    }
+
+    /// Like `recv`, but gives up after `timeout`, returning `Ok(None)` if
+    /// no message arrived by then.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        self.settle()?;
+        self.next_message(Some(Instant::now() + timeout))
+    }
+
+    /// Like `recv`, but returns `Ok(None)` instead of waiting when nothing
+    /// has been prefetched.
+    pub fn try_recv(&mut self) -> io::Result<Option<Message>> {
+        self.recv_timeout(Duration::ZERO)
+    }
+
+    /// Blocks for one message like `recv`, then takes up to `max` in all
+    /// from those already prefetched; see `Consumer::recv_batch`.
+    pub fn recv_batch(&mut self, max: usize) -> io::Result<Vec<Message>> {
+        let mut batch = vec![self.recv()?];
+        while batch.len() < max {
+            match self.next_message(Some(Instant::now()))? {
+                Some(msg) => batch.push(msg),
+                None => break,
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Ack mode: blocks until the next message; see `Consumer::recv_ack`.
+    pub fn recv_ack(&mut self) -> io::Result<Message> {
+        check_ack_mode(self.ack_mode)?;
+        loop {
+            if let Delivery::Message(msg) = self.recv_ext()? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Blocks until the next message or end-of-stream notice; see
+    /// `Consumer::recv_ext`.
+    pub fn recv_ext(&mut self) -> io::Result<Delivery> {
+        self.settle()?;
+        Ok(self.next_delivery(None)?.expect("no deadline"))
+    }
+
+    /// `recv_ext` with a timeout; see `recv_timeout`.
+    pub fn recv_ext_timeout(&mut self, timeout: Duration) -> io::Result<Option<Delivery>> {
+        self.settle()?;
+        self.next_delivery(Some(Instant::now() + timeout))
+    }
+
+    /// See `Consumer::next_message`.
+    fn next_message(&mut self, deadline: Option<Instant>) -> io::Result<Option<Message>> {
+        loop {
+            match self.next_delivery(deadline)? {
+                Some(Delivery::Message(mut msg)) => {
+                    if self.must_ack {
+                        self.owed.extend(msg.tag.take());
+                    }
+                    return Ok(Some(msg));
+                }
+                Some(Delivery::Eos(_)) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// See `Consumer::settle`.
+    fn settle(&mut self) -> io::Result<()> {
+        for tag in std::mem::take(&mut self.owed) {
+            self.ack(tag)?;
+        }
+        Ok(())
+    }
+
+    /// The next prefetched delivery; None if `deadline` passes first.
+    fn next_delivery(&mut self, deadline: Option<Instant>) -> io::Result<Option<Delivery>> {
+        if let Some((kind, msg)) = &self.failed {
+            return Err(io::Error::new(*kind, msg.clone()));
+        }
+        let rx = self.rx.as_ref().expect("receiver lives until drop");
+        let got = match deadline {
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            Some(d) => rx.recv_timeout(d.saturating_duration_since(Instant::now())),
+        };
+        match got {
+            Ok(Ok(d)) => Ok(Some(d)),
+            Ok(Err(e)) => {
+                self.failed = Some((e.kind(), e.to_string()));
+                Err(e)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe, "consumer reader thread stopped",
+            )),
+        }
+    }
+
+    /// Stop reading ahead and give back the consumer. Deliveries still
+    /// queued are discarded: outside ack mode they are lost, in ack mode
+    /// the orchestrator redelivers them once their visibility timeout runs
+    /// out (or the consumer is closed).
+    pub fn into_inner(mut self) -> Consumer {
+        let mut consumer = self.halt().expect("consumer reader thread panicked");
+        consumer.owed = std::mem::take(&mut self.owed);
+        consumer
+    }
+
+    /// Leave the session; see `Consumer::close`. Prefetched deliveries are
+    /// discarded, and requeued by the orchestrator as for the consumer's.
+    pub fn close(mut self) -> io::Result<()> {
+        if self.failed.is_none() {
+            self.settle()?;
+        }
+        match self.halt() {
+            Some(c) => c.close(),
+            None => Ok(()),
+        }
+    }
+
+    /// Stop the reader thread and take its consumer.
+    fn halt(&mut self) -> Option<Consumer> {
+        self.stop.store(true, Ordering::Release);
+        // Unblocks a reader waiting for room in the queue.
+        drop(self.rx.take());
+        self.reader.take().and_then(|r| r.join().ok())
     }
+}
 
-    /// (number of partial messages, total bytes buffered across them).
-    pub fn pending(&self) -> (usize, usize) {
-        let bytes = self.partials.values().map(|p| p.bytes).sum();
-        (self.partials.len(), bytes)
+impl Drop for BufferedConsumer {
+    fn drop(&mut self) {
+        self.halt();
     }
 }
 
-pub struct Producer {
-    stream: TcpStream,
+/// A consumer that rides out dropped connections. When receiving fails,
+/// it reconnects with the same session options, backing off between
+/// attempts, and carries on; receives block until it gets through. Only
+/// errors that reconnecting can't fix (refused options, bad credentials,
+/// protocol violations) are returned.
+///
+/// The orchestrator requeues whatever the old connection had not settled,
+/// so a message may arrive again after a reconnect. In ack mode, tags from
+/// before the reconnect are stale: acking one is a no-op.
+pub struct ReconnectingConsumer {
+    session:  ConnectOptions,
+    conn:     Option<Consumer>,
+    retry:    Retry,
+    /// Window from the last `set_window`, reapplied after reconnects.
+    window:   Option<u32>,
+    /// Ack mode as the last connection negotiated it: asked for, or
+    /// imposed by an at-least-once queue.
+    ack_mode: bool,
 }
 
-impl Producer {
-    pub fn connect(orchestrator: &str) -> io::Result<Self> {
-        let orchestrator_ctrl = resolve_first(orchestrator)?;
-        let mut ctrl = TcpStream::connect(orchestrator_ctrl)?;
-        ctrl.set_nodelay(true).ok();
-
-        ctrl.write_all(&[ROLE_PRODUCER])?;
-        ctrl.flush()?;
+impl ReconnectingConsumer {
+    /// Connect to `orchestrator` with `session` options (a queue or client
+    /// name set in `opts` overrides the session's). Fails if the first
+    /// attempt does, so a wrong address or refused option surfaces right
+    /// away.
+    pub fn connect(
+        orchestrator: &str,
+        session: &ConnectOptions,
+        opts: &ReconnectOptions,
+    ) -> io::Result<Self> {
+        let mut session = session.clone();
+        if let Some(name) = &opts.queue {
+            session.queue = Some(name.clone());
+        }
+        if let Some(name) = &opts.name {
+            session.name = Some(name.clone());
+        }
+        let mut retry = Retry::new(orchestrator, opts);
+        let conn = loop {
+            match Consumer::connect_with(retry.addr(), &session) {
+                Ok(c) => break c,
+                Err(e) if is_transient(&e) && retry.at + 1 < retry.addrs.len() => retry.at += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        Ok(Self {
+            session,
+            ack_mode: conn.ack_mode,
+            conn: Some(conn),
+            retry,
+            window: None,
+        })
+    }
 
-        let (port, token) = read_port_token(&mut ctrl)?;
-        drop(ctrl);
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
 
-        let stream = connect_data(orchestrator_ctrl, port, token)?;
-        Ok(Self { stream })
+    /// Blocks until the next message; see `Consumer::recv`.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        self.with_conn(Consumer::recv)
     }
 
-    /// Send one message. Payloads up to MAX_FRAME_SIZE take the original
-    /// single-frame path, unchanged. Larger payloads are transparently split
-    /// into chunk frames under a fresh random message id; each chunk is
-    /// individually ACKed, so backpressure behaves exactly like a stream of
-    /// single frames.
-    pub fn send(&mut self, payload: &[u8]) -> io::Result<()> {
-        if payload.len() <= MAX_FRAME_SIZE {
-            write_frame(&mut self.stream, payload)?;
-            self.stream.flush()?; // optional for TCP, but helps interactive demos
-            return Ok(());
-        }
+    /// Receive into `buf`; see `Consumer::recv_into`.
+    pub fn recv_into(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.with_conn(|c| c.recv_into(buf))
+    }
 
-        if payload.len() > MAX_MESSAGE_SIZE {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput, "message exceeds MAX_MESSAGE_SIZE",
-            ));
-        }
-        let count = payload.len().div_ceil(MAX_CHUNK_PAYLOAD); // >= 2 here,
-                                                               // <= MAX_CHUNKS
-        let id = new_msg_id()?;
-        for (idx, chunk) in payload.chunks(MAX_CHUNK_PAYLOAD).enumerate() {
-            write_chunk_frame(
-                &mut self.stream, id, idx as u32, count as u32, chunk
-            )?;
-        }
-        self.stream.flush()?;
-        Ok(())
+    /// Ack mode: blocks until the next message; see `Consumer::recv_ack`.
+    pub fn recv_ack(&mut self) -> io::Result<Message> {
+        self.with_conn(Consumer::recv_ack)
     }
-}
 
-pub struct Consumer {
-    stream: TcpStream,
-    asm: Reassembler,
-}
+    /// Blocks until the next message or end-of-stream notice; see
+    /// `Consumer::recv_ext`.
+    pub fn recv_ext(&mut self) -> io::Result<Delivery> {
+        self.with_conn(Consumer::recv_ext)
+    }
 
-impl Consumer {
-    pub fn connect(orchestrator: &str) -> io::Result<Self> {
-        let orchestrator_ctrl = resolve_first(orchestrator)?;
-        let mut ctrl = TcpStream::connect(orchestrator_ctrl)?;
-        ctrl.set_nodelay(true).ok();
+    /// Ack mode: see `Consumer::ack`. If the connection has dropped the
+    /// message is being redelivered anyway, so this only notes the
+    /// disconnect and reconnects on the next receive.
+    pub fn ack(&mut self, tag: u64) -> io::Result<()> {
+        check_ack_mode(self.ack_mode)?;
+        let Some(c) = &mut self.conn else { return Ok(()) };
+        match c.ack(tag) {
+            Err(e) if is_transient(&e) => {
+                self.conn = None;
+                self.retry.lost(&e);
+                Ok(())
+            }
+            res => res,
+        }
+    }
 
-        ctrl.write_all(&[ROLE_CONSUMER])?;
-        ctrl.flush()?;
+    /// Ack mode: see `Consumer::nack`. Like `ack`, a dropped connection
+    /// only means a reconnect: the message is being redelivered anyway.
+    pub fn nack(&mut self, tag: u64) -> io::Result<()> {
+        self.nack_with_retries(tag, u32::MAX)
+    }
 
-        let (port, token) = read_port_token(&mut ctrl)?;
-        drop(ctrl);
+    /// Ack mode: see `Consumer::nack_with_retries`.
+    pub fn nack_with_retries(&mut self, tag: u64, retries: u32) -> io::Result<()> {
+        check_ack_mode(self.ack_mode)?;
+        let Some(c) = &mut self.conn else { return Ok(()) };
+        match c.nack_with_retries(tag, retries) {
+            Err(e) if is_transient(&e) => {
+                self.conn = None;
+                self.retry.lost(&e);
+                Ok(())
+            }
+            res => res,
+        }
+    }
 
-        let stream = connect_data(orchestrator_ctrl, port, token)?;
-        Ok(Self { stream, asm: Reassembler::new() })
+    /// Ack mode: see `Consumer::set_window`. The window also applies to
+    /// every later connection.
+    pub fn set_window(&mut self, window: u32) -> io::Result<()> {
+        check_ack_mode(self.ack_mode)?;
+        self.window = Some(window);
+        let Some(c) = &mut self.conn else { return Ok(()) };
+        match c.set_window(window) {
+            Err(e) if is_transient(&e) => {
+                self.conn = None;
+                self.retry.lost(&e);
+                Ok(())
+            }
+            res => res,
+        }
     }
 
-    /// Blocks until the next complete *message* arrives (or the orchestrator
-    /// closes). Single-frame messages return as soon as their frame is read.
-    /// Chunks are buffered internally and the call keeps reading frames —
-    /// completing other messages first if they finish earlier — until some
-    /// message completes. Messages are therefore returned in COMPLETION
-    /// order, and a stalled multi-frame message never blocks other traffic.
-    /// Every frame is ACKed as it is read, so orchestrator-side flow control
-    /// is unaffected by reassembly.
-    pub fn recv(&mut self) -> io::Result<Vec<u8>> {
+    /// Run `op` on a live connection, reconnecting as often as it takes.
+    fn with_conn<T>(&mut self, mut op: impl FnMut(&mut Consumer) -> io::Result<T>) -> io::Result<T> {
         loop {
-            let frame = read_frame_ext(&mut self.stream)?.ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "orchestrator closed consumer connection",
-                )
-            })?;
-            match frame {
-                Frame::Msg(p) => return Ok(p),
-                Frame::Chunk { id, idx, count, payload } => {
-                    if let Some(msg) = self.asm.absorb(id, idx, count, payload)? {
-                        return Ok(msg);
+            let Some(c) = &mut self.conn else {
+                self.retry.wait();
+                let conn = Consumer::connect_with(self.retry.addr(), &self.session).and_then(|mut c| {
+                    if let Some(w) = self.window {
+                        c.set_window(w)?;
                     }
+                    Ok(c)
+                });
+                match conn {
+                    Ok(c) => {
+                        self.ack_mode = c.ack_mode;
+                        self.conn = Some(c);
+                        self.retry.connected();
+                    }
+                    Err(e) if is_transient(&e) => self.retry.failed(&e),
+                    Err(e) => return Err(e),
+                }
+                continue;
+            };
+            match op(c) {
+                Err(e) if is_transient(&e) => {
+                    self.conn = None;
+                    self.retry.lost(&e);
                 }
+                res => return res,
             }
         }
     }
-
-    /// Discard partial multi-frame messages that haven't received a chunk
-    /// for at least `idle_for`. Returns how many messages were dropped.
-    pub fn gc_partials(&mut self, idle_for: Duration) -> usize {
-        self.asm.gc(idle_for)
-    }
-
-    /// (partial message count, bytes buffered) currently held for reassembly.
-    pub fn pending_partials(&self) -> (usize, usize) {
-        self.asm.pending()
-    }
 }
 
 // Unit tests for the framing layer. Because the frame functions are bounded
@@ -840,6 +5322,240 @@ mod frame_tests {
         assert!(read_frame_ext(&mut io).is_err());
     }
 
+    // ---- control frames ----
+
+    #[test]
+    fn eos_frame_roundtrip_and_flag_bit() {
+        let mut w = DuplexMock::ready_for_acks(1);
+        write_eos_frame(&mut w, b"batch-7").unwrap();
+
+        let wire = w.written();
+        assert_eq!(wire[0] & 0xC0, 0x40, "CTRL flag set, CHUNK flag clear");
+        assert_eq!(wire[4], CTRL_EOS);
+
+        let mut r = DuplexMock::with_incoming(wire.to_vec());
+        assert_eq!(
+            read_frame_ext(&mut r).unwrap(),
+            Some(Frame::Eos(b"batch-7".to_vec()))
+        );
+        assert_eq!(r.written(), &[ACK]);
+    }
+
+    // ---- frame metadata / handshake options ----
+
+    #[test]
+    fn meta_roundtrips_on_every_frame_kind() {
+        let meta = Meta {
+            delivery: Some(42),
+            attempt:  Some(3),
+            priority: Some(7),
+            requires: vec!["gpu".into(), "site=nersc".into()],
+            memory:   Some(64 << 30),
+            gpus:     Some(2),
+            runtime:  Some(Duration::from_secs(90)),
+            headers:  Headers::new().with("content-type", "application/json").with("corr", ""),
+            codec:    Some(CODEC_ZSTD),
+            partition: Some(b"module-7".to_vec()),
+            ttl:      Some(Duration::from_secs(30)),
+            delay:    Some(Duration::from_millis(1500)),
+            message_id: Some(b"run-3/42".to_vec()),
+            queue:    Some("render".into()),
+        };
+        for f in [
+            Frame::Msg(b"m".to_vec()),
+            Frame::Chunk { id: 7, idx: 1, count: 2, payload: b"c".to_vec() },
+            Frame::Eos(b"g".to_vec()),
+        ] {
+            let mut wire = Vec::new();
+            put_frame(&mut wire, &f, &meta).unwrap();
+            assert_ne!(wire[0] & 0x20, 0, "META flag set");
+            assert_eq!(get_frame(&mut &wire[..]).unwrap(), Some((f, meta.clone())));
+        }
+    }
+
+    #[test]
+    fn malformed_headers_are_invalid_data() {
+        let good = Headers::new().with("k", "v").encode();
+        assert_eq!(Headers::decode(&good).unwrap().get("k"), Some("v"));
+        for bad in [
+            &good[..good.len() - 1],            // truncated value
+            &[0, 1, 0, 1, 0xff, 0, 0][..],      // key is not UTF-8
+            &[0, 0, 9][..],                     // trailing bytes
+        ] {
+            let err = Headers::decode(bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn filters_parse_match_and_print_back() {
+        let f = Filter::parse("run^=sim-,site=nersc").unwrap();
+        assert_eq!(f.to_string(), "run^=sim-,site=nersc");
+        assert!(f.matches(&Headers::new().with("run", "sim-4").with("site", "nersc")));
+        assert!(!f.matches(&Headers::new().with("run", "exp-4").with("site", "nersc")));
+        assert!(!f.matches(&Headers::new().with("run", "sim-4")), "every clause must hold");
+        // Values may hold '=' and '^'; only the first '=' splits.
+        let f = Filter::parse("expr=a=b^").unwrap();
+        assert!(f.matches(&Headers::new().with("expr", "a=b^")));
+        for bad in ["", "run", "=x", "^=x", "a=1,", &"k=".repeat(MAX_FILTER_LEN)] {
+            assert_eq!(Filter::parse(bad).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn delivery_policies_parse_print_back_and_round_trip_codes() {
+        for p in [DeliveryPolicy::AtMostOnce, DeliveryPolicy::AtLeastOnce] {
+            assert_eq!(DeliveryPolicy::parse(&p.to_string()).unwrap(), p);
+            assert_eq!(DeliveryPolicy::from_code(p.code()), Some(p));
+        }
+        assert_eq!(DeliveryPolicy::from_code(0), None);
+        for bad in ["", "exactly-once", "AT-MOST-ONCE"] {
+            assert_eq!(DeliveryPolicy::parse(bad).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn empty_meta_is_wire_identical_to_plain_frames() {
+        let mut plain = DuplexMock::ready_for_acks(1);
+        write_frame(&mut plain, b"same").unwrap();
+        let mut wire = Vec::new();
+        put_frame(&mut wire, &Frame::Msg(b"same".to_vec()), &Meta::default())
+            .unwrap();
+        assert_eq!(plain.written(), &wire[..]);
+    }
+
+    #[test]
+    fn unknown_meta_keys_are_skipped_and_legacy_readers_drop_meta() {
+        // META block with an unknown key 0x7f followed by META_ATTEMPT.
+        let block = tlv_encode([(0x7f, &b"future"[..]), (META_ATTEMPT, &[0, 0, 0, 9][..])]);
+        let mut wire = Vec::new();
+        let body_len = 2 + block.len() + 3;
+        wire.extend_from_slice(&(body_len as u32 | FRAME_FLAG_META).to_be_bytes());
+        wire.extend_from_slice(&(block.len() as u16).to_be_bytes());
+        wire.extend_from_slice(&block);
+        wire.extend_from_slice(b"abc");
+
+        let (f, meta) = get_frame(&mut &wire[..]).unwrap().unwrap();
+        assert_eq!(f, Frame::Msg(b"abc".to_vec()));
+        assert_eq!(meta, Meta { attempt: Some(9), ..Meta::default() });
+
+        let mut io = DuplexMock::with_incoming(wire);
+        assert_eq!(read_frame(&mut io).unwrap(), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn checksummed_frames_roundtrip_and_catch_every_bit_flip() {
+        let meta = Meta { priority: Some(7), ..Meta::default() };
+        for f in [
+            Frame::Msg(b"m".to_vec()),
+            Frame::Msg(Vec::new()),
+            Frame::Chunk { id: 7, idx: 1, count: 2, payload: b"c".to_vec() },
+            Frame::Eos(b"g".to_vec()),
+            Frame::Ping,
+        ] {
+            let mut wire = Vec::new();
+            put_checked_frame(&mut wire, &f, &meta).unwrap();
+            assert_ne!(wire[0] & 0x10, 0, "CRC flag set");
+            let mut plain = Vec::new();
+            put_frame(&mut plain, &f, &meta).unwrap();
+            assert_eq!(wire.len(), plain.len() + 4, "a four-byte trailer");
+            assert_eq!(get_checked_frame(&mut &wire[..]).unwrap(), Some((f.clone(), meta.clone())));
+            assert_eq!(get_frame(&mut &wire[..]).unwrap(), Some((f.clone(), meta.clone())));
+
+            for bit in 0..wire.len() * 8 {
+                let mut bad = wire.clone();
+                bad[bit / 8] ^= 1 << (bit % 8);
+                // A flip in the length may leave the frame short instead.
+                let err = get_checked_frame(&mut &bad[..]).unwrap_err();
+                assert!(
+                    matches!(err.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof),
+                    "{f:?}, bit {bit}: {err}",
+                );
+            }
+        }
+        // Negotiated sessions insist on the trailer.
+        let mut wire = Vec::new();
+        put_frame(&mut wire, &Frame::Msg(b"m".to_vec()), &Meta::default()).unwrap();
+        let err = get_checked_frame(&mut &wire[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn meta_block_overrunning_the_frame_is_rejected() {
+        let mut wire = Vec::new();
+        wire.extend_from_slice(&(4u32 | FRAME_FLAG_META).to_be_bytes());
+        wire.extend_from_slice(&10u16.to_be_bytes()); // claims 10, frame has 2
+        wire.extend_from_slice(&[0, 0]);
+        assert!(get_frame(&mut &wire[..]).is_err());
+    }
+
+    #[test]
+    fn frames_over_a_lowered_limit_are_rejected_on_read() {
+        let mut wire = Vec::new();
+        let meta = Meta { priority: Some(1), ..Meta::default() };
+        put_frame(&mut wire, &Frame::Msg(vec![1; 100]), &meta).unwrap();
+        // The limit counts the body only, not the metadata block.
+        assert!(get_frame_as(&mut &wire[..], false, 100).unwrap().is_some());
+        let err = get_frame_as(&mut &wire[..], false, 99).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn advertised_frame_limits_are_checked() {
+        let reply = |v: &[u8]| vec![(OPT_MAX_FRAME, v.to_vec())];
+        assert_eq!(max_frame_echoed(&[]).unwrap(), MAX_FRAME_SIZE);
+        assert_eq!(max_frame_echoed(&reply(&(1u32 << 20).to_be_bytes())).unwrap(), 1 << 20);
+        for bad in [
+            &(MIN_FRAME_LIMIT as u32 - 1).to_be_bytes()[..],
+            &(MAX_FRAME_SIZE as u32 + 1).to_be_bytes()[..],
+            &[0, 1, 0],
+        ] {
+            let err = max_frame_echoed(&reply(bad)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn queue_names_are_validated() {
+        for ok in ["a", "ingest.v2", "team-a/jobs_high", &"x".repeat(MAX_QUEUE_NAME)] {
+            assert!(check_queue_name(ok).is_ok(), "{ok:?}");
+        }
+        for bad in ["", "has space", "ünï", "a,b", &"x".repeat(MAX_QUEUE_NAME + 1)] {
+            assert!(check_queue_name(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn handshake_options_roundtrip() {
+        let mut wire = Vec::new();
+        write_options(&mut wire, &[(OPT_ACK_MODE, &[]), (OPT_VISIBILITY_MS, &[1, 2])])
+            .unwrap();
+        assert_eq!(
+            read_options(&mut &wire[..]).unwrap(),
+            vec![(OPT_ACK_MODE, vec![]), (OPT_VISIBILITY_MS, vec![1, 2])]
+        );
+
+        let mut empty = Vec::new();
+        write_options(&mut empty, &[]).unwrap();
+        assert_eq!(empty, [0, 0]);
+    }
+
+    #[test]
+    fn legacy_read_frame_rejects_control_frames() {
+        let mut w = DuplexMock::ready_for_acks(1);
+        write_eos_frame(&mut w, b"").unwrap();
+        let mut r = DuplexMock::with_incoming(w.written().to_vec());
+        assert!(read_frame(&mut r).is_err());
+    }
+
+    #[test]
+    fn read_frame_ext_rejects_unknown_control_kind() {
+        let mut wire = (1u32 | FRAME_FLAG_CTRL).to_be_bytes().to_vec();
+        wire.push(b'?');
+        let mut io = DuplexMock::with_incoming(wire);
+        assert!(read_frame_ext(&mut io).is_err());
+    }
+
     // ---- round-trip ----
 
     #[test]
@@ -851,6 +5567,434 @@ mod frame_tests {
         let mut reader = DuplexMock::with_incoming(writer.written().to_vec());
         assert_eq!(read_frame(&mut reader).unwrap(), Some(payload.to_vec()));
     }
+
+    // ---- Consumer timeouts ----
+
+    #[test]
+    fn timed_out_receives_keep_partial_messages() {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream::Tcp(std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = l.accept().unwrap();
+        let mut c = Consumer {
+            stream, asm: Reassembler::new(), ack_mode: false, must_ack: false, owed: Vec::new(), visibility: None, tags: HashMap::new(),
+            ready: VecDeque::new(), idle: None, heard: Instant::now(), pinger: None, lock: Default::default(), closed: false, crc: false, nack: false,
+            version: PROTOCOL_VERSION, goodbye: false,
+        };
+
+        assert_eq!(c.try_recv().unwrap(), None);
+        let t0 = Instant::now();
+        assert_eq!(c.recv_timeout(Duration::from_millis(50)).unwrap(), None);
+        assert!(t0.elapsed() >= Duration::from_millis(50));
+
+        put_chunk(&mut peer, 9, 0, 2, b"first half, ", &Meta::default(), false).unwrap();
+        assert_eq!(c.recv_timeout(Duration::from_millis(200)).unwrap(), None);
+        assert_eq!(c.pending_partials(), (1, 12));
+        put_chunk(&mut peer, 9, 1, 2, b"second half", &Meta::default(), false).unwrap();
+        put_msg(&mut peer, b"next", &Meta::default(), false).unwrap();
+        let whole = c.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(whole.map(|m| m.payload).as_deref(), Some(&b"first half, second half"[..]));
+        // Already here: no waiting needed.
+        assert_eq!(c.try_recv().unwrap().map(|m| m.payload).as_deref(), Some(&b"next"[..]));
+        assert_eq!(c.try_recv().unwrap(), None);
+
+        let mut acks = [0u8; 3];
+        peer.read_exact(&mut acks).unwrap();
+        assert_eq!(acks, [ACK; 3], "every frame was ACKed once");
+        drop(peer);
+        let err = c.recv_timeout(Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn buffered_consumers_read_ahead_as_far_as_their_prefetch() {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream::Tcp(std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = l.accept().unwrap();
+        let c = Consumer {
+            stream, asm: Reassembler::new(), ack_mode: true, must_ack: false, owed: Vec::new(), visibility: None, tags: HashMap::new(),
+            ready: VecDeque::new(), idle: None, heard: Instant::now(), pinger: None, lock: Default::default(), closed: false, crc: false, nack: false,
+            version: PROTOCOL_VERSION, goodbye: false,
+        };
+        let mut c = BufferedConsumer::new(c, 2).unwrap();
+        for tag in 1..=5u64 {
+            let meta = Meta { delivery: Some(tag), ..Meta::default() };
+            put_msg(&mut peer, format!("m{tag}").as_bytes(), &meta, false).unwrap();
+        }
+
+        // Two queued, and a third read while the reader waits for room.
+        let mut acks = [0u8; 3];
+        peer.read_exact(&mut acks).unwrap();
+        assert_eq!(acks, [ACK; 3]);
+        peer.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        assert!(peer.read(&mut [0u8]).is_err(), "read no further than the prefetch");
+
+        for tag in 1..=5u64 {
+            let msg = c.recv_ack().unwrap();
+            assert_eq!((msg.payload, msg.tag), (format!("m{tag}").into_bytes(), Some(tag)));
+        }
+        c.ack(5).unwrap();
+        let mut rest = [0u8; 11];
+        peer.set_read_timeout(None).unwrap();
+        peer.read_exact(&mut rest).unwrap();
+        assert_eq!(rest[..2], [ACK; 2]);
+        assert_eq!(rest[2..], ack_record(true, 5).unwrap());
+
+        drop(peer);
+        for _ in 0..2 {
+            let err = c.recv_timeout(Duration::from_secs(5)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn receiving_into_a_buffer_replaces_its_contents() {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream::Tcp(std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = l.accept().unwrap();
+        let mut c = Consumer {
+            stream, asm: Reassembler::new(), ack_mode: false, must_ack: false, owed: Vec::new(), visibility: None, tags: HashMap::new(),
+            ready: VecDeque::new(), idle: None, heard: Instant::now(), pinger: None, lock: Default::default(), closed: false, crc: false, nack: false,
+            version: PROTOCOL_VERSION, goodbye: false,
+        };
+
+        let big = vec![3u8; 70_000];
+        put_msg(&mut peer, &big, &Meta::default(), false).unwrap();
+        put_msg(&mut peer, b"small", &Meta::default(), false).unwrap();
+        put_chunk(&mut peer, 4, 0, 2, &[5u8; 4000], &Meta::default(), false).unwrap();
+        put_chunk(&mut peer, 4, 1, 2, &[6u8; 4000], &Meta::default(), false).unwrap();
+        let mut buf = b"stale".to_vec();
+        assert_eq!(c.recv_into(&mut buf).unwrap(), 70_000);
+        assert!(buf == big);
+        assert_eq!(c.recv_into(&mut buf).unwrap(), 5);
+        assert_eq!(buf, b"small");
+        assert_eq!(c.recv_into(&mut buf).unwrap(), 8000);
+        assert!(buf[..4000].iter().all(|&b| b == 5) && buf[4000..].iter().all(|&b| b == 6));
+    }
+
+    #[test]
+    fn iterating_a_consumer_ends_when_the_orchestrator_closes() {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream::Tcp(std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = l.accept().unwrap();
+        let mut c = Consumer {
+            stream, asm: Reassembler::new(), ack_mode: false, must_ack: false, owed: Vec::new(), visibility: None, tags: HashMap::new(),
+            ready: VecDeque::new(), idle: None, heard: Instant::now(), pinger: None, lock: Default::default(), closed: false, crc: false, nack: false,
+            version: PROTOCOL_VERSION, goodbye: false,
+        };
+
+        for payload in [&b"one"[..], b"two", b"three"] {
+            put_msg(&mut peer, payload, &Meta::default(), false).unwrap();
+        }
+        let first: Vec<_> = (&mut c).into_iter().take(2).map(|m| m.unwrap().payload).collect();
+        assert_eq!(first, [b"one".to_vec(), b"two".to_vec()]);
+        peer.shutdown(std::net::Shutdown::Write).unwrap();
+        let rest: io::Result<Vec<_>> = c.into_iter().map(|m| m.map(|m| m.payload)).collect();
+        assert_eq!(rest.unwrap(), [b"three".to_vec()]);
+
+        // Cut off mid-frame: an error, then the end.
+        let stream = Stream::Tcp(std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = l.accept().unwrap();
+        let c = Consumer {
+            stream, asm: Reassembler::new(), ack_mode: false, must_ack: false, owed: Vec::new(), visibility: None, tags: HashMap::new(),
+            ready: VecDeque::new(), idle: None, heard: Instant::now(), pinger: None, lock: Default::default(), closed: false, crc: false, nack: false,
+            version: PROTOCOL_VERSION, goodbye: false,
+        };
+        peer.write_all(&10u32.to_be_bytes()).unwrap();
+        drop(peer);
+        let mut msgs = c.into_iter();
+        assert_eq!(msgs.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(msgs.next().is_none());
+    }
+
+    #[test]
+    fn consumers_hang_up_on_corrupted_frames_without_acking_them() {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream::Tcp(std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = l.accept().unwrap();
+        let mut c = Consumer {
+            stream, asm: Reassembler::new(), ack_mode: false, must_ack: false, owed: Vec::new(), visibility: None, tags: HashMap::new(),
+            ready: VecDeque::new(), idle: None, heard: Instant::now(), pinger: None, lock: Default::default(), closed: false, crc: true, nack: false,
+            version: PROTOCOL_VERSION, goodbye: false,
+        };
+
+        put_checked_frame(&mut peer, &Frame::Msg(b"intact".to_vec()), &Meta::default()).unwrap();
+        assert_eq!(c.recv().unwrap().payload, b"intact");
+        let mut wire = Vec::new();
+        put_checked_frame(&mut wire, &Frame::Msg(b"damaged".to_vec()), &Meta::default()).unwrap();
+        wire[6] ^= 0x04;
+        peer.write_all(&wire).unwrap();
+        assert_eq!(c.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut acks = Vec::new();
+        peer.read_to_end(&mut acks).unwrap();
+        assert_eq!(acks, [ACK], "only the intact frame was ACKed");
+    }
+
+    #[test]
+    fn heartbeats_need_room_for_a_ping() {
+        let ms = Duration::from_millis;
+        let hb = Heartbeat::new(ms(500), ms(2000)).unwrap();
+        assert_eq!(Heartbeat::decode(&hb.encode()).unwrap(), hb);
+        for (interval, timeout) in [(ms(0), ms(1000)), (ms(1000), ms(1000)), (ms(2000), ms(1000))] {
+            let err = Heartbeat::new(interval, timeout).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{interval:?}/{timeout:?}");
+        }
+        assert!(Heartbeat::new(Duration::from_micros(500), ms(1000)).is_err(), "rounds to 0ms");
+        assert_eq!(Heartbeat::decode(&[0; 7]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn consumers_answer_pings_and_time_out_on_silence() {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream::Tcp(std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = l.accept().unwrap();
+        let idle = Duration::from_millis(200);
+        stream.set_read_timeout(Some(idle)).unwrap();
+        let mut c = Consumer {
+            stream, asm: Reassembler::new(), ack_mode: false, must_ack: false, owed: Vec::new(), visibility: None, tags: HashMap::new(),
+            ready: VecDeque::new(), idle: Some(idle), heard: Instant::now(), pinger: None, lock: Default::default(), closed: false, crc: false, nack: false,
+            version: PROTOCOL_VERSION, goodbye: false,
+        };
+
+        put_frame(&mut peer, &Frame::Ping, &Meta::default()).unwrap();
+        put_msg(&mut peer, b"after", &Meta::default(), false).unwrap();
+        assert_eq!(c.recv().unwrap().payload, b"after");
+        let mut acks = [0u8; 2];
+        peer.read_exact(&mut acks).unwrap();
+        assert_eq!(acks, [ACK_PAYLOAD; 2], "the ping is answered like any frame");
+
+        // Short waits come back empty; a wait past the heartbeat timeout
+        // ends there, as does a plain receive.
+        assert_eq!(c.recv_timeout(Duration::from_millis(50)).unwrap(), None);
+        let t0 = Instant::now();
+        let err = c.recv_timeout(Duration::from_secs(30)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(t0.elapsed() < Duration::from_secs(5));
+        assert_eq!(c.recv().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn producers_ping_when_quiet_and_time_out_unanswered_pings() {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream::Tcp(std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = l.accept().unwrap();
+        let mut wire = Wire::new(stream, FlushPolicy::Manual, None);
+        wire.heartbeat = Some(Heartbeat::new(Duration::from_millis(20), Duration::from_millis(200)).unwrap());
+
+        send_on(&mut wire, b"pending", &Meta::default()).unwrap();
+        assert!(wire.next_tick(None).unwrap() <= Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(25));
+        let answer = std::thread::spawn(move || {
+            assert_eq!(read_frame_ext(&mut peer).unwrap(), Some(Frame::Msg(b"pending".to_vec())));
+            assert_eq!(read_frame_ext(&mut peer).unwrap(), Some(Frame::Ping));
+            peer
+        });
+        wire.tick(None).unwrap();
+        let _peer = answer.join().unwrap();
+        assert!(wire.next_tick(None).unwrap() > Duration::from_millis(10), "the answer counts");
+
+        // This time nobody answers.
+        std::thread::sleep(Duration::from_millis(25));
+        let err = wire.tick(None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn batches_go_out_whole_and_are_acked_once() {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream::Tcp(std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = l.accept().unwrap();
+        // `Always` would wait for an ACK after every frame; the peer only
+        // ACKs once it has read the whole batch.
+        let mut wire = Wire::new(stream, FlushPolicy::Always, None);
+        let big = vec![7u8; MAX_FRAME_SIZE + 1];
+        let batch: [&[u8]; 4] = [b"one", b"", &big, b"four"];
+        let peer = std::thread::spawn(move || {
+            let mut frames = Vec::new();
+            while frames.len() < 5 {
+                frames.push(read_frame_ext(&mut peer).unwrap().unwrap());
+            }
+            peer.write_all(&[ACK_PAYLOAD; 5]).unwrap();
+            frames
+        });
+        batch_on(&mut wire, &batch).unwrap();
+        assert_eq!(wire.unacked, 0);
+        let frames = peer.join().unwrap();
+        assert_eq!(frames[0], Frame::Msg(b"one".to_vec()));
+        assert_eq!(frames[1], Frame::Msg(Vec::new()));
+        assert!(matches!(&frames[2], Frame::Chunk { idx: 0, count: 2, .. }));
+        assert!(matches!(&frames[3], Frame::Chunk { idx: 1, count: 2, .. }));
+        assert_eq!(frames[4], Frame::Msg(b"four".to_vec()));
+    }
+
+    #[test]
+    fn recv_writer_streams_in_order_and_keeps_what_completes_meanwhile() {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream::Tcp(std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap());
+        let (mut peer, _) = l.accept().unwrap();
+        let mut c = Consumer {
+            stream, asm: Reassembler::new(), ack_mode: false, must_ack: false, owed: Vec::new(), visibility: None, tags: HashMap::new(),
+            ready: VecDeque::new(), idle: None, heard: Instant::now(), pinger: None, lock: Default::default(), closed: false, crc: false, nack: false,
+            version: PROTOCOL_VERSION, goodbye: false,
+        };
+        let headers = Headers::new().with("k", "v");
+        let meta = Meta { headers: headers.clone(), ..Meta::default() };
+        put_chunk(&mut peer, 1, 0, 3, b"aa", &meta, false).unwrap();
+        put_chunk(&mut peer, 1, 2, 3, b"cc", &meta, false).unwrap(); // overtakes idx 1
+        put_msg(&mut peer, b"single", &Meta::default(), false).unwrap();
+        put_chunk(&mut peer, 2, 0, 2, b"x", &Meta::default(), false).unwrap();
+        put_frame(&mut peer, &Frame::Eos(b"g".to_vec()), &Meta::default()).unwrap();
+        put_chunk(&mut peer, 1, 1, 3, b"bb", &meta, false).unwrap();
+        put_chunk(&mut peer, 2, 1, 2, b"y", &Meta::default(), false).unwrap();
+
+        let mut out = Vec::new();
+        let got = c.recv_writer(&mut out).unwrap();
+        assert_eq!(out, b"aabbcc");
+        assert_eq!((got.len, got.tag, &got.headers), (6, None, &headers));
+        // What completed while message 1 streamed comes next, in order.
+        assert_eq!(c.recv().unwrap().payload, b"single");
+        assert_eq!(c.recv_ext().unwrap(), Delivery::Eos(b"g".to_vec()));
+        let mut out = Vec::new();
+        assert_eq!(c.recv_writer(&mut out).unwrap().len, 2);
+        assert_eq!(out, b"xy");
+
+        put_chunk(&mut peer, 3, 0, 2, b"p", &Meta::default(), false).unwrap();
+        put_chunk(&mut peer, 3, 0, 2, b"p", &Meta::default(), false).unwrap();
+        let err = c.recv_writer(io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "duplicate chunk");
+    }
+
+    #[test]
+    fn clients_fall_back_to_version_1_with_orchestrators_from_before_the_hello() {
+        for role in [ROLE_PRODUCER, ROLE_CONSUMER] {
+            let ctrl = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let data = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = ctrl.local_addr().unwrap().to_string();
+            let port = data.local_addr().unwrap().port();
+            let legacy = std::thread::spawn(move || {
+                // Not a role byte: hang up.
+                let (mut s, _) = ctrl.accept().unwrap();
+                let mut byte = [0u8; 1];
+                s.read_exact(&mut byte).unwrap();
+                assert_eq!(byte[0], HELLO_MAGIC[0]);
+                drop(s);
+                // The original handshake: a bare role byte, no options.
+                let (mut s, _) = ctrl.accept().unwrap();
+                s.read_exact(&mut byte).unwrap();
+                assert_eq!(byte[0], role, "no option block");
+                s.write_all(&port.to_be_bytes()).unwrap();
+                s.write_all(&[7; TOKEN_LEN]).unwrap();
+                let (mut d, _) = data.accept().unwrap();
+                let mut token = [0u8; TOKEN_LEN];
+                d.read_exact(&mut token).unwrap();
+                assert_eq!(token, [7; TOKEN_LEN]);
+                if role == ROLE_CONSUMER {
+                    write_frame(&mut d, b"old").unwrap(); // and its ACK
+                }
+            });
+            if role == ROLE_PRODUCER {
+                let p = Producer::connect(&addr).unwrap();
+                assert_eq!(p.protocol_version(), PROTOCOL_V1);
+            } else {
+                let mut c = Consumer::connect(&addr).unwrap();
+                assert_eq!(c.protocol_version(), PROTOCOL_V1);
+                assert_eq!(c.delivery_policy(), DeliveryPolicy::AtMostOnce);
+                assert_eq!(c.recv().unwrap().payload, b"old");
+            }
+            legacy.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn registered_transports_carry_whole_sessions() {
+        use crate::mem::{MemListener, MemStream};
+        use crate::transport::{self, Acceptor, Scheme, Transport};
+        use std::net::Shutdown;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // `counted://<name>`: mem:// underneath, counting what it carries.
+        static BYTES: AtomicUsize = AtomicUsize::new(0);
+        #[derive(Debug)]
+        struct Counted(MemStream);
+        impl Transport for Counted {
+            fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = (&self.0).read(buf)?;
+                BYTES.fetch_add(n, Ordering::Relaxed);
+                Ok(n)
+            }
+            fn write(&self, buf: &[u8]) -> io::Result<usize> {
+                (&self.0).write(buf)
+            }
+            fn flush(&self) -> io::Result<()> {
+                Ok(())
+            }
+            fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+                Ok(Box::new(Counted(self.0.try_clone()?)))
+            }
+            fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+                self.0.set_read_timeout(t)
+            }
+            fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+                self.0.set_write_timeout(t)
+            }
+            fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+                self.0.set_nonblocking(on)
+            }
+            fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+                self.0.shutdown(how)
+            }
+            fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.peek(buf)
+            }
+            fn peer(&self) -> String {
+                "counted".into()
+            }
+        }
+        #[derive(Debug)]
+        struct CountedListener(MemListener);
+        impl Acceptor for CountedListener {
+            fn accept(&self) -> io::Result<(Box<dyn Transport>, String)> {
+                Ok((Box::new(Counted(self.0.accept()?)), "counted".into()))
+            }
+            fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+                self.0.set_nonblocking(on)
+            }
+            fn local_addr(&self) -> io::Result<String> {
+                Ok(format!("counted://{}", self.0.name()))
+            }
+        }
+        struct CountedScheme;
+        impl Scheme for CountedScheme {
+            fn connect(&self, addr: &str, _: Option<Duration>) -> io::Result<Box<dyn Transport>> {
+                Ok(Box::new(Counted(crate::mem::connect(&addr["counted://".len()..])?)))
+            }
+            fn bind(&self, addr: &str) -> io::Result<Box<dyn Acceptor>> {
+                Ok(Box::new(CountedListener(MemListener::bind(&addr["counted://".len()..])?)))
+            }
+        }
+        transport::register("counted", CountedScheme).unwrap();
+        assert_eq!(
+            transport::register("counted", CountedScheme).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists,
+        );
+        assert_eq!(transport::register("mem", CountedScheme).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        let orch = std::sync::Arc::new(crate::orchestrator::Orchestrator::bind("counted://scheme-test").unwrap());
+        assert_eq!(orch.local_addr().to_string(), "counted://scheme-test");
+        let server = {
+            let orch = orch.clone();
+            std::thread::spawn(move || orch.run())
+        };
+        // Neither side asked for a single-port session; both get one.
+        let mut p = Producer::connect("counted://scheme-test").unwrap();
+        let mut c = Consumer::connect("counted://scheme-test").unwrap();
+        p.send(b"through the plug-in").unwrap();
+        assert_eq!(c.recv().unwrap().payload, b"through the plug-in");
+        assert!(BYTES.load(Ordering::Relaxed) > 2 * b"through the plug-in".len());
+        drop((p, c));
+        orch.shutdown();
+        server.join().unwrap().unwrap();
+    }
 }
 
 #[cfg(test)]
@@ -998,10 +6142,41 @@ mod frame_proptests {
                             done = Some(msg);
                         }
                     }
-                    Frame::Msg(_) => prop_assert!(false, "expected chunk frame"),
+                    _ => prop_assert!(false, "expected chunk frame"),
                 }
             }
             prop_assert_eq!(done, Some(payload));
         }
+
+        // Frames fed to the sans-IO decoder in arbitrary pieces come out
+        // as the blocking reader would return them.
+        #[test]
+        fn incremental_decoding_matches_blocking_reads(
+            payloads in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..512), 1..8),
+            piece in 1usize..64,
+            checksum in any::<bool>(),
+        ) {
+            let enc = codec::FrameEncoder::new().checksum(checksum);
+            let mut wire = Vec::new();
+            for p in &payloads {
+                enc.encode(&Frame::Msg(p.clone()), &Meta::default(), &mut wire).unwrap();
+            }
+            let mut blocking = Vec::new();
+            let mut r = &wire[..];
+            while let Some((f, _)) = get_frame(&mut r).unwrap() {
+                blocking.push(f);
+            }
+
+            let mut dec = codec::FrameDecoder::new().checksum(checksum);
+            let mut decoded = Vec::new();
+            for bytes in wire.chunks(piece) {
+                dec.feed(bytes);
+                while let Some((f, _)) = dec.decode().unwrap() {
+                    decoded.push(f);
+                }
+            }
+            prop_assert!(dec.finish().is_ok());
+            prop_assert_eq!(decoded, blocking);
+        }
     }
 }
@@ -0,0 +1,526 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! In-process transport: `mem://<name>` addresses, for testing pipelines
+//! without sockets. An orchestrator bound to `mem://jobs` serves producers
+//! and consumers in the same process that connect to `mem://jobs`, with
+//! the same handshake, framing and session threads as over TCP; only the
+//! bytes travel through in-memory pipes instead of the kernel. Nothing
+//! listens on a port, so tests don't race each other for one, and nothing
+//! outside the process can connect.
+//!
+//! `Orchestrator` runs one on a name of its own; `Producer` and `Consumer`
+//! are the crate's, which take `mem://` addresses like any other:
+//!
+//!   let orch = qpipe::mem::Orchestrator::start()?;
+//!   let mut p = qpipe::mem::Producer::connect(orch.addr())?;
+//!   let mut c = qpipe::mem::Consumer::connect(orch.addr())?;
+//!   p.send(b"job")?;
+//!   assert_eq!(c.recv()?.payload, b"job");
+//!
+//! Pipes hold `PIPE_BYTES` each way, past which writes block as a full
+//! socket buffer would. Dropping the last handle to a connection closes it:
+//! the other end reads EOF, and its writes fail with `BrokenPipe`. Like Unix
+//! sockets, session data connections get listeners of their own,
+//! `<name>.<n>`, until they arrive.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::orchestrator::{self, OrchestratorOptions};
+use crate::transport::MEM_SCHEME;
+
+pub use crate::{Consumer, Producer};
+
+/// Bytes buffered in each direction of a connection.
+pub const PIPE_BYTES: usize = 256 * 1024;
+
+/// One direction of a connection.
+#[derive(Default)]
+struct Pipe {
+    buf:  VecDeque<u8>,
+    /// The writing end is shut down or gone: reads drain `buf`, then EOF.
+    eof:  bool,
+    /// The reading end is shut down or gone: writes fail.
+    gone: bool,
+}
+
+#[derive(Default)]
+struct Channel {
+    pipe: Mutex<Pipe>,
+    cond: Condvar,
+}
+
+impl Channel {
+    /// Wait for the pipe to change, until `deadline` if there is one.
+    fn wait<'a>(&self, p: MutexGuard<'a, Pipe>, deadline: Option<Instant>) -> io::Result<MutexGuard<'a, Pipe>> {
+        match deadline {
+            None => Ok(self.cond.wait(p).unwrap()),
+            Some(d) => match d.checked_duration_since(Instant::now()).filter(|t| !t.is_zero()) {
+                Some(t) => Ok(self.cond.wait_timeout(p, t).unwrap().0),
+                None => Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out")),
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    read_timeout:  Option<Duration>,
+    write_timeout: Option<Duration>,
+    nonblocking:   bool,
+}
+
+/// One end of a connection, shared by its `try_clone`s; closed when the
+/// last of them goes.
+struct End {
+    rx:   Arc<Channel>,
+    tx:   Arc<Channel>,
+    opts: Mutex<Options>,
+    /// The listener's name, for `local_addr`.
+    name: String,
+}
+
+impl End {
+    fn shutdown(&self, how: Shutdown) {
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            let mut p = self.rx.pipe.lock().unwrap();
+            p.gone = true;
+            p.buf.clear();
+            self.rx.cond.notify_all();
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.tx.pipe.lock().unwrap().eof = true;
+            self.tx.cond.notify_all();
+        }
+    }
+
+    /// How long a read or write may wait, per the stream's options: `None`
+    /// to fail at once (nonblocking), `Some(None)` for no limit.
+    fn deadline(&self, write: bool) -> Option<Option<Instant>> {
+        let o = self.opts.lock().unwrap();
+        let timeout = if write { o.write_timeout } else { o.read_timeout };
+        (!o.nonblocking).then(|| timeout.map(|t| Instant::now() + t))
+    }
+
+    fn read(&self, buf: &mut [u8], consume: bool) -> io::Result<usize> {
+        let deadline = self.deadline(false);
+        let mut p = self.rx.pipe.lock().unwrap();
+        loop {
+            if p.gone || buf.is_empty() {
+                return Ok(0);
+            }
+            if !p.buf.is_empty() {
+                let n = buf.len().min(p.buf.len());
+                for (dst, src) in buf.iter_mut().zip(p.buf.iter()) {
+                    *dst = *src;
+                }
+                if consume {
+                    p.buf.drain(..n);
+                    self.rx.cond.notify_all();
+                }
+                return Ok(n);
+            }
+            if p.eof {
+                return Ok(0);
+            }
+            let Some(deadline) = deadline else {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing to read"));
+            };
+            p = self.rx.wait(p, deadline)?;
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let deadline = self.deadline(true);
+        let mut p = self.tx.pipe.lock().unwrap();
+        loop {
+            if p.eof || p.gone {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"));
+            }
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let room = PIPE_BYTES - p.buf.len();
+            if room > 0 {
+                let n = buf.len().min(room);
+                p.buf.extend(&buf[..n]);
+                self.tx.cond.notify_all();
+                return Ok(n);
+            }
+            let Some(deadline) = deadline else {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "pipe full"));
+            };
+            p = self.tx.wait(p, deadline)?;
+        }
+    }
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.shutdown(Shutdown::Both);
+    }
+}
+
+/// One end of an in-memory connection, with the methods of a `TcpStream`
+/// that `transport::Stream` needs.
+pub struct MemStream {
+    end: Arc<End>,
+}
+
+impl fmt::Debug for MemStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemStream").field("name", &self.end.name).finish()
+    }
+}
+
+impl MemStream {
+    /// Both ends of a new connection to the listener `name`.
+    fn pair(name: &str) -> (Self, Self) {
+        let (a, b) = (Arc::new(Channel::default()), Arc::new(Channel::default()));
+        let end = |rx: &Arc<Channel>, tx: &Arc<Channel>| Self {
+            end: Arc::new(End { rx: rx.clone(), tx: tx.clone(), opts: Mutex::default(), name: name.into() }),
+        };
+        (end(&a, &b), end(&b, &a))
+    }
+
+    /// Another handle to the same end, sharing its options, like
+    /// `TcpStream::try_clone`.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self { end: self.end.clone() })
+    }
+
+    /// Reads wait at most `t`, then fail with `WouldBlock`, as on Linux.
+    pub fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        check_timeout(t)?;
+        self.end.opts.lock().unwrap().read_timeout = t;
+        Ok(())
+    }
+
+    pub fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        check_timeout(t)?;
+        self.end.opts.lock().unwrap().write_timeout = t;
+        Ok(())
+    }
+
+    pub fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        self.end.opts.lock().unwrap().nonblocking = on;
+        Ok(())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.end.shutdown(how);
+        Ok(())
+    }
+
+    /// Read into `buf` without consuming, like `TcpStream::peek`.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.end.read(buf, false)
+    }
+
+    /// The name of the listener this connection was made to.
+    pub fn name(&self) -> &str {
+        &self.end.name
+    }
+}
+
+/// A zero timeout is an error, as for sockets.
+fn check_timeout(t: Option<Duration>) -> io::Result<()> {
+    match t {
+        Some(t) if t.is_zero() => Err(io::Error::new(io::ErrorKind::InvalidInput, "zero timeout")),
+        _ => Ok(()),
+    }
+}
+
+impl Read for MemStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.end.read(buf, true)
+    }
+}
+
+impl Read for &MemStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.end.read(buf, true)
+    }
+}
+
+impl Write for MemStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.end.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for &MemStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.end.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Connections waiting for a listener's `accept`.
+#[derive(Default)]
+struct Backlog {
+    /// The pending connections, and whether the listener is gone.
+    pending: Mutex<(VecDeque<MemStream>, bool)>,
+    cond:    Condvar,
+}
+
+/// Every bound listener, by name.
+static LISTENERS: Mutex<BTreeMap<String, Arc<Backlog>>> = Mutex::new(BTreeMap::new());
+
+/// The last session number handed out by `MemListener::bind_session`.
+static SESSIONS: AtomicU16 = AtomicU16::new(0);
+
+/// A listener on a `mem://` name, which it gives up when dropped.
+pub struct MemListener {
+    name:        String,
+    backlog:     Arc<Backlog>,
+    nonblocking: AtomicBool,
+}
+
+impl fmt::Debug for MemListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemListener").field("name", &self.name).finish()
+    }
+}
+
+impl MemListener {
+    /// Listen on `name`; `AddrInUse` if something in this process already
+    /// does.
+    pub fn bind(name: &str) -> io::Result<Self> {
+        let mut listeners = LISTENERS.lock().unwrap();
+        if listeners.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse, format!("{MEM_SCHEME}{name}: already listening"),
+            ));
+        }
+        let backlog = Arc::new(Backlog::default());
+        listeners.insert(name.into(), backlog.clone());
+        Ok(Self { name: name.into(), backlog, nonblocking: AtomicBool::new(false) })
+    }
+
+    /// Listen for one session's data connection next to the listener
+    /// `ctrl`, on `<ctrl>.<n>`; returns the listener and `n`.
+    pub fn bind_session(ctrl: &str) -> io::Result<(Self, u16)> {
+        for _ in 0..64 {
+            let n = SESSIONS.fetch_add(1, Ordering::Relaxed).wrapping_add(1).max(1);
+            match Self::bind(&session_name(ctrl, n)) {
+                Ok(l) => return Ok((l, n)),
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "no free session name"))
+    }
+
+    pub fn accept(&self) -> io::Result<MemStream> {
+        let mut g = self.backlog.pending.lock().unwrap();
+        loop {
+            if let Some(s) = g.0.pop_front() {
+                return Ok(s);
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no pending connection"));
+            }
+            g = self.backlog.cond.wait(g).unwrap();
+        }
+    }
+
+    pub fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        self.nonblocking.store(on, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for MemListener {
+    fn drop(&mut self) {
+        let mut listeners = LISTENERS.lock().unwrap();
+        if listeners.get(&self.name).is_some_and(|b| Arc::ptr_eq(b, &self.backlog)) {
+            listeners.remove(&self.name);
+        }
+        drop(listeners);
+        // Connections nobody will accept close, so their clients see EOF.
+        let mut g = self.backlog.pending.lock().unwrap();
+        g.0.clear();
+        g.1 = true;
+    }
+}
+
+/// The data listener name for session `n` of the listener `ctrl`.
+pub fn session_name(ctrl: &str, n: u16) -> String {
+    format!("{ctrl}.{n}")
+}
+
+/// Connect to the listener `name`; `ConnectionRefused` if there is none.
+pub fn connect(name: &str) -> io::Result<MemStream> {
+    let refused = || io::Error::new(
+        io::ErrorKind::ConnectionRefused, format!("{MEM_SCHEME}{name}: nothing listening"),
+    );
+    let backlog = LISTENERS.lock().unwrap().get(name).cloned().ok_or_else(refused)?;
+    let (client, server) = MemStream::pair(name);
+    let mut g = backlog.pending.lock().unwrap();
+    if g.1 {
+        return Err(refused());
+    }
+    g.0.push_back(server);
+    backlog.cond.notify_all();
+    Ok(client)
+}
+
+/// Names handed out by `Orchestrator::start`.
+static ORCHESTRATORS: AtomicU64 = AtomicU64::new(0);
+
+/// An orchestrator serving a `mem://` name of its own on a background
+/// thread. Dropping it asks for a shutdown without waiting for the drain;
+/// `stop` waits.
+pub struct Orchestrator {
+    orch:   Arc<orchestrator::Orchestrator>,
+    addr:   String,
+    server: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Orchestrator {
+    /// Start one with default options (and the QPIPE_* environment, as
+    /// `orchestrator::Orchestrator::bind` reads it).
+    pub fn start() -> io::Result<Self> {
+        Self::start_with(&OrchestratorOptions::default())
+    }
+
+    pub fn start_with(opts: &OrchestratorOptions) -> io::Result<Self> {
+        let n = ORCHESTRATORS.fetch_add(1, Ordering::Relaxed);
+        let addr = format!("{MEM_SCHEME}qpipe-{n}");
+        let orch = Arc::new(orchestrator::Orchestrator::bind_with(&addr, opts)?);
+        let server = {
+            let orch = orch.clone();
+            thread::Builder::new().name("qpipe-mem".into()).spawn(move || orch.run())?
+        };
+        Ok(Self { orch, addr, server: Some(server) })
+    }
+
+    /// The address clients connect to: `mem://qpipe-<n>`.
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// The orchestrator itself, for `stats`, `drain` and the like.
+    pub fn orchestrator(&self) -> &orchestrator::Orchestrator {
+        &self.orch
+    }
+
+    /// Shut down, and wait for `run` to finish: once the queues are
+    /// drained, or after QPIPE_DRAIN_TIMEOUT_SECS.
+    pub fn stop(mut self) -> io::Result<()> {
+        self.orch.shutdown();
+        match self.server.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("orchestrator thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Orchestrator {
+    fn drop(&mut self) {
+        self.orch.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipes_carry_bytes_both_ways_then_close() {
+        let l = MemListener::bind("pipes-test").unwrap();
+        assert_eq!(MemListener::bind("pipes-test").unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        let mut client = connect("pipes-test").unwrap();
+        let mut server = l.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut got = [0u8; 4];
+        assert_eq!(server.peek(&mut got).unwrap(), 4);
+        server.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"ping");
+        server.write_all(b"pong").unwrap();
+        client.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"pong");
+
+        // Nothing to read: timeouts and nonblocking reads fail as on Linux.
+        client.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        assert_eq!(client.read(&mut got).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        client.set_nonblocking(true).unwrap();
+        assert_eq!(client.peek(&mut got).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        client.set_nonblocking(false).unwrap();
+
+        // The connection outlives handles while a clone remains.
+        let clone = server.try_clone().unwrap();
+        drop(server);
+        (&clone).write_all(b"last").unwrap();
+        drop(clone);
+        client.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"last");
+        assert_eq!(client.read(&mut got).unwrap(), 0);
+        assert_eq!(client.write(b"x").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+        drop(l);
+        assert_eq!(connect("pipes-test").unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn a_full_pipe_blocks_its_writer_until_read() {
+        let l = MemListener::bind("full-pipe-test").unwrap();
+        let mut client = connect("full-pipe-test").unwrap();
+        let mut server = l.accept().unwrap();
+        client.set_write_timeout(Some(Duration::from_millis(20))).unwrap();
+        let big = vec![7u8; PIPE_BYTES];
+        client.write_all(&big).unwrap();
+        assert_eq!(client.write(b"x").unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        let writer = thread::spawn(move || {
+            client.set_write_timeout(None).unwrap();
+            client.write_all(b"more")
+        });
+        let mut got = vec![0u8; PIPE_BYTES + 4];
+        server.read_exact(&mut got).unwrap();
+        assert_eq!(&got[PIPE_BYTES..], b"more");
+        writer.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn a_pipeline_runs_in_process() {
+        use crate::{ConnectOptions, ProducerOptions};
+
+        let orch = Orchestrator::start().unwrap();
+        assert!(orch.addr().starts_with(MEM_SCHEME));
+        let mut p = Producer::connect(orch.addr()).unwrap();
+        let mut c = Consumer::connect(orch.addr()).unwrap();
+        for i in 0..100u32 {
+            p.send(&i.to_be_bytes()).unwrap();
+        }
+        for i in 0..100u32 {
+            assert_eq!(c.recv().unwrap().payload, i.to_be_bytes());
+        }
+        drop((p, c));
+        // Big messages chunk, and single-port sessions work the same way.
+        let mut p = Producer::connect_with(orch.addr(), &ProducerOptions::new().single_port(true)).unwrap();
+        let mut c = Consumer::connect_with(orch.addr(), &ConnectOptions::new().single_port(true)).unwrap();
+        let big = vec![3u8; crate::MAX_FRAME_SIZE + 1];
+        p.send(&big).unwrap();
+        assert_eq!(c.recv().unwrap().payload, big);
+        assert_eq!(orch.orchestrator().stats().posted_frames, 100 + 2);
+        drop((p, c));
+        orch.stop().unwrap();
+    }
+}
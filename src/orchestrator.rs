@@ -0,0 +1,10740 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! The orchestrator as a library, for tests and single-process deployments:
+//! `Orchestrator::bind` a control address, `run` it on a thread of its own,
+//! and `drain` or `shutdown` it from another. The `orchestrator` binary is a
+//! thin CLI over this module. Besides what `OrchestratorOptions` sets, an
+//! embedded orchestrator reads the same QPIPE_* environment variables as
+//! the binary, when it is bound.
+
+// Multi-frame routing design:
+//   The single SharedQueue is replaced by a Router holding, under ONE mutex:
+//     - `shared`:   the unassigned frames (singles + unclaimed chunks), one
+//                   FIFO lane per producer priority (see `Lanes`)
+//     - `directed`: one small VecDeque per connected consumer, for chunks
+//                   that belong to a message that consumer has claimed
+//     - `assign`:   msg_id -> (owner, delivered, count, last_seen) metadata
+//     - `tomb`:     msg_ids whose messages can no longer complete; straggler
+//                   frames of these are dropped at push or pop
+//   Claiming happens at POP time: the first consumer handler to pop a chunk
+//   of an unclaimed message becomes its owner. If a handler pops a chunk
+//   owned by someone else, it moves the frame to the owner's directed queue
+//   (total in-flight count unchanged, so redirects never block) and keeps
+//   popping. Handlers always serve their directed queue before the shared
+//   one, so claimed messages drain with priority and every redirected frame
+//   is handled exactly once — no livelock, no busy-wait, no per-message
+//   queues or threads.
+//   Assignments are deleted the moment delivered == count. Consumer death
+//   tombstones its in-flight messages; a periodic sweep expires idle
+//   assignments into tombstones (QPIPE_ASSIGN_TTL_SECS, default 600) and
+//   ages tombstones out (QPIPE_TOMBSTONE_TTL_SECS, default 600).
+//   NOTE: stats counters count FRAMES, not messages, since chunks flow
+//   through the queue individually.
+//
+// Ack mode and the retry policy:
+//   A consumer that opts into ack mode settles messages by explicit ack
+//   rather than by the per-frame ACK. Each delivery gets a fresh tag; the
+//   popped frames stay in `unacked` (tag -> items) until the ack arrives.
+//   The visibility clock starts once the consumer has the frame; a delivery
+//   whose deadline passes, or whose consumer disconnects, is requeued with
+//   its attempt counter bumped — after a backoff, and only while the retry
+//   budget lasts; then it is dead-lettered. All four knobs live in ONE
+//   RetryPolicy (QPIPE_RETRY_POLICY) so they can't be tuned against each
+//   other by accident. Plain consumers are unaffected.
+//   An ack-mode consumer may also declare a weight: at most that many
+//   unacked deliveries at once (`windows`/`inflight`). A full window only
+//   blocks NEW deliveries; chunks of messages it already holds still flow.
+//   ACK_WINDOW records on the back-channel replace the window later on,
+//   down to 0, which pauses the consumer without disconnecting it.
+//
+// Capability routing:
+//   Consumers may advertise tags (`caps`); items whose META requires tags
+//   are only popped by consumers holding all of them (`route`). Consumers
+//   scan past items they can't take, so lanes stay FIFO per consumer view.
+//   When no registered consumer qualifies, QPIPE_TAG_FALLBACK picks: wait,
+//   deliver to anyone, or dead-letter.
+//   Resource hints (memory, GPUs) work the same way against per-consumer
+//   `budgets`: unacked deliveries hold their hints until settled, and a
+//   consumer takes the first queued item that fits what remains.
+//
+// Dispatch:
+//   By default consumer handlers pop freely: whichever gets the lock first
+//   takes the next item. With QPIPE_DISPATCH, handlers blocked in
+//   `take_next` register in `waiting`, and a handler about to take a shared
+//   item defers to any waiting consumer that ranks ahead of it (`rank`:
+//   fewest unsettled deliveries, then longest since its last turn) and
+//   could take the item too, waking it instead. The ranking is a total
+//   order, so the best-placed capable consumer always takes it.
+//
+// Partition keys:
+//   Items whose META carries a partition key are only popped by the key's
+//   owner (`RouterInner::key_owner`): the consumer the key is pinned to
+//   while frames under it are handed out and unsettled (`pins`), otherwise
+//   the registered consumer the key hashes to. A delivery pins the key and
+//   `settle` unpins it, so a key only moves between consumers when nothing
+//   under it is in flight, and one consumer sees its messages in order.
+//
+// Heartbeats:
+//   Sessions that negotiate OPT_HEARTBEAT (QPIPE_HEARTBEAT overrides the
+//   client's values) are timed: a producer's data stream gets a read
+//   timeout, and its frames are ACKed after `push_stamped` instead of
+//   before, so a ping is never answered late because of backpressure. A
+//   consumer always gets a back-channel reader, which times its ACK_PING
+//   records and kicks it once they stop; its handler waits for work with
+//   `next_for_within` and pings the consumer after each idle interval.
+//
+// Stats:
+//   The reporter thread takes a `Report` every stats interval and writes
+//   it as the `[stats]` log line or, with `StatsFormat::Json`, as a line of
+//   JSON on stdout. The JSON line also goes to every ROLE_STATS session
+//   through `Stats::feed`, one channel per subscriber; the reporter closes
+//   the feed when it stops, which ends those sessions.
+//
+// Admin sessions:
+//   ROLE_ADMIN sessions (see `qpipe::admin`) read `Stats::sessions`, which
+//   each producer and consumer joins through its `ConnGuard`, and the
+//   queues' gauges. Pausing sets `RouterInner::intake`, which
+//   `push_stamped` waits out like a full queue; refusing intake also has
+//   `ProducerSession` answer new messages from producers that can be told
+//   with ACK_THROTTLED (`refuses`). `Queues::intake` carries either over
+//   to queues created later. A purge takes what an export drain would,
+//   plus the overflow, and drops it.
+//
+// Audit log:
+//   With QPIPE_AUDIT_LOG set, producer handlers hash each data frame before
+//   pushing it; the router keeps that `Stamp` by seq and turns it into one
+//   JSON line when the frame settles (delivered, dead-lettered or dropped).
+//   Lines go over a channel to a writer thread, so the disk never holds the
+//   router lock.
+//
+// Authentication:
+//   With QPIPE_USERS set, every session but a healthcheck must pass SCRAM
+//   (see `qpipe::scram`) as a user whose roles allow it: `producer`,
+//   `consumer`, or `admin` for drain, shutdown, wait-idle, export, stats and
+//   admin sessions. The
+//   exchange runs on the control connection right after the option block,
+//   before any role is served. Unknown names get a mock verifier, so they
+//   fail exactly like a wrong password. With the gssapi feature,
+//   QPIPE_PRINCIPALS admits Kerberos principals the same way (`Access`).
+//   With --auth-key, the pre-shared key exchange (`qpipe::psk`) comes
+//   first, and every session but a healthcheck must pass it too.
+//
+// Autoscaling:
+//   With QPIPE_AUTOSCALE set, the main loop samples `Router::backlog` each
+//   tick and feeds it to an `Autoscaler`, which decides when sustained
+//   pressure (or a drained queue after a scale-up) is worth a `Hook`. The
+//   hooks run off-thread, so a slow webhook never stalls the loop.
+//
+// Write-ahead log:
+//   With --wal-dir, each queue's RouterInner holds a `Wal`. Frames are
+//   journaled as they are admitted or spilled, and `journaled` maps their
+//   seq to the lsn; `settle` (and the release or export of an EOS marker)
+//   marks it done. On startup `recover` queues what the log still holds,
+//   and named queues with a log are created up front.
+//
+// Replication:
+//   Each queue's log is attached to the `Feed`, which hands what it
+//   appends to the replication sessions of standbys (see `replica`). A
+//   standby (`--standby-of`) runs `replica::follow` in `bind_with`, before
+//   any queue exists, and goes on from there once it returns: the queues
+//   recover from the copy like from a log of their own.
+//
+// Service manager:
+//   With `OrchestratorOptions::systemd`, `bind_with` takes the sockets
+//   systemd passed (`systemd::listen_fds`) in place of binding its
+//   addresses, and the orchestrator reports READY=1 once `run` has its
+//   accept loops going, then STOPPING=1 and drain progress from `drain`.
+//
+// Overflow:
+//   With QPIPE_OVERFLOW_DIR set, `push_stamped` hands frames that don't fit
+//   (or that arrive while earlier ones are still on disk) to the queue's
+//   `Overflow` instead of waiting on `not_full`. `RouterInner::spilled`
+//   keeps their count, spill times and audit stamps in memory. A refill
+//   thread per queue (`start_refill`) moves them back as room frees up.
+//   Where a push would then wait on `not_full`, the queue's `FullPolicy`
+//   may have it make room (`shed_oldest`) or come back `Pushed::Rejected`
+//   instead, which `ProducerSession::frame` answers with ACK_THROTTLED.
+//
+// Deterministic simulation (tests only):
+//   The router reads time through `Router::now` and draws session tokens
+//   through `Router::session_token`. A simulated router (`Sim`) answers both
+//   from a virtual clock and a seeded generator, and tests drive it from one
+//   thread with `try_next_for`, which never blocks — so every interleaving
+//   of producers, consumers, acks and timeouts is chosen by the seed, and a
+//   failing seed replays exactly (see `router_tests::sim`).
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::env;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, OnceLock, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::{rngs::SysRng, TryRng};
+
+use log::{debug, info, warn, error, LevelFilter};
+
+use crate::admin::{
+    ClientInfo, QueueInfo, SessionInfo, ADMIN_CLIENTS, ADMIN_ERROR, ADMIN_PAUSE, ADMIN_PURGE,
+    ADMIN_QUEUES, ADMIN_REFUSE, ADMIN_RESUME, ADMIN_SESSIONS,
+};
+use crate::at_rest::{Key, SealedWriter};
+use crate::config::{Config, Limits, QueueConfig};
+use crate::digest::{sha256, to_hex, DIGEST_LEN};
+use crate::delta::Decoder;
+#[cfg(feature = "otel")]
+use crate::otel::{self, Span, TraceContext, SPAN_KIND_INTERNAL, SPAN_KIND_PRODUCER, SPAN_KIND_SERVER};
+use crate::overflow::Overflow;
+use crate::psk;
+#[cfg(target_os = "linux")]
+use crate::reactor::{Reactor, Wake};
+use crate::replica::{self, Feed};
+use crate::scram::{self, Verifier};
+#[cfg(unix)]
+use crate::systemd;
+use crate::transport::{Addr, Listener, Stream};
+use crate::wal::{Replayed, Wal};
+#[cfg(feature = "gssapi")]
+use crate::OPT_GSSAPI;
+use crate::{
+    check_client_name, check_queue_name, get_frame, get_frame_as, put_frame, put_frame_as, read_ack, read_exact_or_eof, read_options,
+    write_options,
+    DeliveryPolicy, Filter, Frame, HandshakeOptions, Heartbeat, Meta, HELLO_MAGIC, PROTOCOL_V1, PROTOCOL_VERSION,
+    ACK_ADMIN, ACK_BYE, ACK_DRAIN, ACK_EXPORT, ACK_HEALTH, ACK_IDLE, ACK_MESSAGE, ACK_NACK, ACK_PAYLOAD, ACK_PING, ACK_SHUTDOWN,
+    ACK_STATS, ACK_THROTTLED, ACK_WINDOW, CODEC_ZSTD, EXPORT_COPY, EXPORT_DRAIN, EXPORT_IMPORT,
+    OPT_ACK_MODE, OPT_AUTH_KEY, OPT_CAPABILITIES, OPT_CHECKSUM, OPT_CLIENT_NAME, OPT_COMPRESS, OPT_DELIVERY, OPT_DELTA, OPT_FILTER, OPT_GOODBYE, OPT_HEARTBEAT, OPT_MAX_FRAME, OPT_NACK, OPT_QUEUE,
+    OPT_RESOURCES, OPT_SCRAM, OPT_SINGLE_PORT, OPT_THROTTLE, OPT_VISIBILITY_MS, OPT_WEIGHT,
+    ROLE_ADMIN, ROLE_CONSUMER, ROLE_DRAIN,
+    ROLE_EXPORT, ROLE_FLAG_OPTS, ROLE_HEALTHCHECK, ROLE_PRODUCER, ROLE_REPLICATE, ROLE_SHUTDOWN,
+    ROLE_STATS, ROLE_WAIT_IDLE, TOKEN_LEN, MAX_FRAME_SIZE, MIN_FRAME_LIMIT, REPLY_QUEUE_PREFIX,
+};
+
+// Orchestrator lifecycle state. New producer/consumer sessions are only
+// admitted while RUNNING; any other state stops admitting them but keeps
+// the control port open so admin commands (health/drain/shutdown) still
+// reach the orchestrator.
+const STATE_RUNNING:       u8 = 0;
+const STATE_DRAINING:      u8 = 1; // wait forever for queue + producers
+const STATE_SHUTTING_DOWN: u8 = 2; // wait with timeout, then exit anyway
+
+// How long to wait for queue drain after a shutdown is requested before
+// giving up and exiting anyway. Tunable via QPIPE_DRAIN_TIMEOUT_SECS.
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+
+// Once drained, how long consumers get to see their connections close
+// before the process exits under them.
+const CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+// SIGTERM and SIGINT received so far, once `Orchestrator::handle_signals`
+// routes them here. The first is a shutdown request like `--shutdown`; a
+// second skips what is left of the drain.
+static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+// SIGHUPs received so far; the run loop reloads the configuration file
+// when this moves.
+static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+// Multi-frame housekeeping. An assignment idle longer than the assign TTL
+// is presumed orphaned (producer died mid-message, or its consumer is gone)
+// and becomes a tombstone; a tombstone idle longer than the tombstone TTL
+// is forgotten. Tunable via QPIPE_ASSIGN_TTL_SECS / QPIPE_TOMBSTONE_TTL_SECS.
+const DEFAULT_ASSIGN_TTL_SECS:    u64 = 600;
+const DEFAULT_TOMBSTONE_TTL_SECS: u64 = 600;
+const SWEEP_EVERY: Duration = Duration::from_secs(5);
+
+/// Longest a producer can have a message held back (`Meta::delay`);
+/// longer delays are cut to this.
+const MAX_DELAY: Duration = Duration::from_secs(365 * 24 * 3600);
+
+// How often a ROLE_WAIT_IDLE session re-checks the idle condition, and
+// whether its client is still there.
+const IDLE_POLL: Duration = Duration::from_millis(50);
+
+// Longest a queue's overflow refill thread sleeps between checks for room.
+const REFILL_POLL: Duration = Duration::from_millis(100);
+
+// How often an idle consumer of a sharded queue looks for frames to steal
+// from the other shards, and most frames one steal moves.
+const STEAL_POLL: Duration = Duration::from_millis(20);
+const STEAL_MAX:  usize = 256;
+
+// Longest a session's data port waits between checks for its client; the
+// first checks come sooner, as the client is usually on its way.
+const DATA_POLL: Duration = Duration::from_millis(50);
+
+// Stack size for session threads, and the reactor's workers. Sessions run
+// shallow code (framing, router calls, the auth exchange), and with
+// thousands of them the 2 MiB default mostly reserves address space that is
+// never touched.
+const SESSION_STACK: usize = 512 << 10;
+
+type MsgId      = u128;
+type ConsumerId = u64;
+
+#[derive(Default)]
+struct Stats {
+    // Frames accepted from producers and enqueued
+    posted_msgs:      AtomicU64,
+    posted_bytes:     AtomicU64,
+    // Frames successfully written to consumer sockets
+    collected_msgs:   AtomicU64,
+    collected_bytes:  AtomicU64,
+    // Frames popped/held but NOT delivered (write failed, dead message, ...)
+    dropped_msgs:     AtomicU64,
+    dropped_bytes:    AtomicU64,
+    // Ack mode: deliveries requeued for another attempt, and messages that
+    // ran out of attempts
+    redelivered_msgs:   AtomicU64,
+    dead_lettered_msgs: AtomicU64,
+    // Frames drained out of the queue by an export (ROLE_EXPORT)
+    exported_msgs:      AtomicU64,
+    // Messages dropped unseen once their TTL ran out
+    expired_msgs:       AtomicU64,
+    // Frames dropped as repeats of a recently accepted message id
+    duplicate_msgs:     AtomicU64,
+    // Frames a full queue dropped or refused under its FullPolicy
+    shed_msgs:          AtomicU64,
+    // How long frames waited in a queue before going to a consumer
+    dwell:              Histogram,
+    // Connection counts
+    active_producers: AtomicUsize,
+    active_consumers: AtomicUsize,
+    // ROLE_STATS subscribers
+    feed:             StatsFeed,
+    // Connected producers and consumers, by ConnGuard
+    sessions:         Mutex<BTreeMap<u64, SessionEntry>>,
+    next_session:     AtomicU64,
+    // Counters per client name (OPT_CLIENT_NAME), at most MAX_CLIENTS
+    clients:          Mutex<BTreeMap<String, Arc<ClientStats>>>,
+}
+
+impl Stats {
+    fn snapshot(&self, queues: &Queues) -> StatsSnapshot {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        StatsSnapshot {
+            posted_frames:    load(&self.posted_msgs),
+            posted_bytes:     load(&self.posted_bytes),
+            collected_frames: load(&self.collected_msgs),
+            collected_bytes:  load(&self.collected_bytes),
+            dropped_frames:   load(&self.dropped_msgs),
+            dropped_bytes:    load(&self.dropped_bytes),
+            redelivered:      load(&self.redelivered_msgs),
+            dead_lettered:    load(&self.dead_lettered_msgs),
+            exported:         load(&self.exported_msgs),
+            expired:          load(&self.expired_msgs),
+            duplicates:       load(&self.duplicate_msgs),
+            shed:             load(&self.shed_msgs),
+            producers:        self.active_producers.load(Ordering::Relaxed),
+            consumers:        self.active_consumers.load(Ordering::Relaxed),
+            queues:           queues.count(),
+            in_queue:         queues.gauges().0,
+            outstanding:      queues.outstanding(),
+            dwell:            Dwell::of(&self.dwell.counts()),
+        }
+    }
+}
+
+/// Sub-buckets per power of two in a `Histogram`, as bits.
+const HIST_SUB_BITS: u32 = 5;
+const HIST_BUCKETS: usize = (64 - HIST_SUB_BITS as usize + 1) << HIST_SUB_BITS;
+
+/// An HDR-style histogram of microseconds: exact up to 32µs, then 32
+/// buckets per power of two, so a bucket's bound is within about 3% of any
+/// value in it. Counts only grow; an interval's histogram is the difference
+/// of two `counts`.
+struct Histogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { buckets: (0..HIST_BUCKETS).map(|_| AtomicU64::new(0)).collect() }
+    }
+}
+
+impl Histogram {
+    fn record(&self, d: Duration) {
+        let us = u64::try_from(d.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[Self::bucket(us)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> Vec<u64> {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect()
+    }
+
+    fn bucket(us: u64) -> usize {
+        let sub = 1u64 << HIST_SUB_BITS;
+        if us < sub {
+            return us as usize;
+        }
+        let shift = 63 - us.leading_zeros() - HIST_SUB_BITS;
+        (((shift + 1) as usize) << HIST_SUB_BITS) + ((us >> shift) - sub) as usize
+    }
+
+    /// The largest value bucket `i` holds.
+    fn upper(i: usize) -> u64 {
+        let sub = 1usize << HIST_SUB_BITS;
+        if i < sub {
+            return i as u64;
+        }
+        let shift = (i >> HIST_SUB_BITS) as u32 - 1;
+        let base = ((i & (sub - 1)) + sub) as u64;
+        base << shift | ((1u64 << shift) - 1)
+    }
+}
+
+impl Dwell {
+    /// Percentiles of `Histogram` counts.
+    fn of(counts: &[u64]) -> Self {
+        let count: u64 = counts.iter().sum();
+        // The bound of the bucket holding the value of rank
+        // ceil(count * permille / 1000).
+        let at = |permille: u64| {
+            let rank = (count * permille).div_ceil(1000).max(1);
+            let mut seen = 0;
+            let i = counts.iter().position(|c| {
+                seen += c;
+                seen >= rank
+            });
+            Duration::from_micros(i.map_or(0, Histogram::upper))
+        };
+        Self { count, p50: at(500), p95: at(950), p99: at(990) }
+    }
+}
+
+/// The sessions subscribed to stats reports (ROLE_STATS). Each gets its
+/// own channel; `None` once the reporter has stopped, so that late
+/// subscribers end straight away instead of waiting for reports that
+/// will never come.
+struct StatsFeed {
+    subs: Mutex<Option<Vec<mpsc::Sender<Arc<str>>>>>,
+}
+
+impl Default for StatsFeed {
+    fn default() -> Self {
+        Self { subs: Mutex::new(Some(Vec::new())) }
+    }
+}
+
+impl StatsFeed {
+    fn subscribe(&self) -> mpsc::Receiver<Arc<str>> {
+        let (tx, rx) = mpsc::channel();
+        if let Some(subs) = &mut *self.subs.lock().unwrap() {
+            subs.push(tx);
+        }
+        rx
+    }
+
+    /// Hand `report` to every subscriber still listening.
+    fn publish(&self, report: &str) {
+        if let Some(subs) = &mut *self.subs.lock().unwrap()
+            && !subs.is_empty()
+        {
+            let report: Arc<str> = report.into();
+            subs.retain(|tx| tx.send(report.clone()).is_ok());
+        }
+    }
+
+    fn close(&self) {
+        self.subs.lock().unwrap().take();
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ConnKind { Producer, Consumer }
+
+/// A connected producer or consumer, as admin sessions list it.
+struct SessionEntry {
+    kind:  ConnKind,
+    peer:  String,
+    queue: String,
+    since: Instant,
+    name:  String,
+}
+
+impl SessionEntry {
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            role:      match self.kind {
+                ConnKind::Producer => "producer",
+                ConnKind::Consumer => "consumer",
+            }.to_string(),
+            peer:      self.peer.clone(),
+            queue:     self.queue.clone(),
+            connected: self.since.elapsed(),
+            name:      self.name.clone(),
+        }
+    }
+}
+
+// At most this many client names get counters of their own; clients pick
+// their names, so they must not be able to grow the table without bound.
+const MAX_CLIENTS: usize = 1024;
+
+/// What the sessions under one client name have done (`ADMIN_CLIENTS`).
+#[derive(Default)]
+struct ClientStats {
+    producers:        AtomicU64,
+    consumers:        AtomicU64,
+    posted_frames:    AtomicU64,
+    posted_bytes:     AtomicU64,
+    collected_frames: AtomicU64,
+    collected_bytes:  AtomicU64,
+}
+
+impl ClientStats {
+    fn info(&self, name: &str) -> ClientInfo {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        ClientInfo {
+            name:             name.to_string(),
+            producers:        load(&self.producers),
+            consumers:        load(&self.consumers),
+            posted_frames:    load(&self.posted_frames),
+            posted_bytes:     load(&self.posted_bytes),
+            collected_frames: load(&self.collected_frames),
+            collected_bytes:  load(&self.collected_bytes),
+        }
+    }
+
+    fn live(&self, kind: ConnKind) -> &AtomicU64 {
+        match kind {
+            ConnKind::Producer => &self.producers,
+            ConnKind::Consumer => &self.consumers,
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.producers.load(Ordering::Relaxed) == 0 && self.consumers.load(Ordering::Relaxed) == 0
+    }
+}
+
+impl Stats {
+    /// The counters of client `name`, made on first use. With MAX_CLIENTS
+    /// names counted, those with no session left make room; failing that,
+    /// the newcomer goes uncounted.
+    fn client(&self, name: &str) -> Option<Arc<ClientStats>> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(c) = clients.get(name) {
+            return Some(c.clone());
+        }
+        if clients.len() >= MAX_CLIENTS {
+            clients.retain(|_, c| !c.is_idle());
+            if clients.len() >= MAX_CLIENTS {
+                return None;
+            }
+        }
+        Some(clients.entry(name.to_string()).or_default().clone())
+    }
+}
+
+struct ConnGuard {
+    kind:   ConnKind,
+    stats:  Arc<Stats>,
+    id:     u64,
+    client: Option<Arc<ClientStats>>,
+    /// A producer's queue, which counts it (`Router::producers`).
+    queue:  Option<Arc<Router>>,
+}
+
+impl ConnGuard {
+    fn new(kind: ConnKind, stats: Arc<Stats>, peer: String, queue: &str, name: Option<&str>) -> Self {
+        match kind {
+            ConnKind::Producer => {
+                stats.active_producers.fetch_add(1, Ordering::Relaxed);
+            }
+            ConnKind::Consumer => {
+                stats.active_consumers.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let client = name.and_then(|name| stats.client(name));
+        if let Some(c) = &client {
+            c.live(kind).fetch_add(1, Ordering::Relaxed);
+        }
+        let id = stats.next_session.fetch_add(1, Ordering::Relaxed);
+        let entry = SessionEntry {
+            kind, peer, queue: queue.to_string(), since: Instant::now(),
+            name: name.unwrap_or("").to_string(),
+        };
+        stats.sessions.lock().unwrap().insert(id, entry);
+        Self { kind, stats, id, client, queue: None }
+    }
+
+    /// Count a producer session against its queue too, until it goes.
+    fn producing_for(mut self, router: &Arc<Router>) -> Self {
+        router.producers.fetch_add(1, Ordering::Relaxed);
+        self.queue = Some(router.clone());
+        self
+    }
+
+    /// Count a frame accepted from this session's producer.
+    fn posted(&self, len: u64) {
+        self.stats.posted_msgs.fetch_add(1, Ordering::Relaxed);
+        self.stats.posted_bytes.fetch_add(len, Ordering::Relaxed);
+        if let Some(c) = &self.client {
+            c.posted_frames.fetch_add(1, Ordering::Relaxed);
+            c.posted_bytes.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a frame delivered to this session's consumer.
+    fn collected(&self, len: u64) {
+        self.stats.collected_msgs.fetch_add(1, Ordering::Relaxed);
+        self.stats.collected_bytes.fetch_add(len, Ordering::Relaxed);
+        if let Some(c) = &self.client {
+            c.collected_frames.fetch_add(1, Ordering::Relaxed);
+            c.collected_bytes.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.stats.sessions.lock().unwrap().remove(&self.id);
+        if let Some(r) = &self.queue {
+            r.producers.fetch_sub(1, Ordering::Relaxed);
+        }
+        if let Some(c) = &self.client {
+            c.live(self.kind).fetch_sub(1, Ordering::Relaxed);
+        }
+        match self.kind {
+            ConnKind::Producer => {
+                self.stats.active_producers.fetch_sub(1, Ordering::Relaxed);
+            }
+            ConnKind::Consumer => {
+                self.stats.active_consumers.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// What the audit log needs to know about an accepted data frame, taken
+/// when the producer handed it over.
+#[derive(Clone)]
+struct Stamp {
+    /// Seq for single frames, `<msg id hex>/<idx>` for chunks.
+    id:       String,
+    size:     usize,
+    digest:   [u8; DIGEST_LEN],
+    producer: Arc<str>,
+    posted:   SystemTime,
+}
+
+impl Stamp {
+    /// Hash `frame` for the audit log (outside any lock: it's the slow part).
+    /// The id is filled in at push, once the frame has a seq.
+    fn new(frame: &Frame, producer: Arc<str>) -> Self {
+        let payload: &[u8] = match frame {
+            Frame::Msg(p) | Frame::Chunk { payload: p, .. } => p,
+            Frame::Eos(_) | Frame::Ping | Frame::Bye => &[],
+        };
+        Self {
+            id: String::new(),
+            size: payload.len(),
+            digest: sha256(payload),
+            producer,
+            posted: SystemTime::now(),
+        }
+    }
+
+    /// One audit line: the frame's stamp plus how and when it settled.
+    fn line(&self, consumer: Option<&str>, outcome: &str) -> String {
+        let ms = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let line = serde_json::json!({
+            "id":         self.id,
+            "size":       self.size,
+            "sha256":     to_hex(&self.digest),
+            "producer":   &*self.producer,
+            "consumer":   consumer,
+            "posted_ms":  ms(self.posted),
+            "settled_ms": ms(SystemTime::now()),
+            "outcome":    outcome,
+        });
+        line.to_string() + "\n"
+    }
+}
+
+/// Where a queue's spans go (QPIPE_OTEL_SPANS; see `otel`).
+#[cfg(feature = "otel")]
+#[derive(Clone)]
+struct Tracer {
+    lines: mpsc::Sender<String>,
+    queue: Arc<str>,
+}
+
+#[cfg(feature = "otel")]
+impl Tracer {
+    /// The sampled context a frame is traced under, if it is a message or
+    /// a message's first chunk.
+    fn context(frame: &Frame, meta: &Meta) -> Option<TraceContext> {
+        match frame {
+            Frame::Msg(_) | Frame::Chunk { idx: 0, .. } => otel::extract(&meta.headers).filter(TraceContext::sampled),
+            _ => None,
+        }
+    }
+
+    /// Record a span from `start` until now of a message traced under
+    /// `parent`. `peer` is the client at the other end; `failed` says how
+    /// a step that didn't finish as meant ended instead.
+    fn record(
+                &self,
+                name:   &'static str,
+                kind:   u8,
+                parent: &TraceContext,
+                start:  SystemTime,
+                peer:   Option<&str>,
+                failed: Option<&str>,
+            ) {
+        let mut attrs = vec![("messaging.system", "qpipe".to_string())];
+        if !self.queue.is_empty() {
+            attrs.push(("messaging.destination.name", self.queue.to_string()));
+        }
+        if let Some(peer) = peer {
+            attrs.push(("qpipe.peer", peer.to_string()));
+        }
+        if let Some(outcome) = failed {
+            attrs.push(("qpipe.outcome", outcome.to_string()));
+        }
+        let span = Span { name, kind, parent, start, end: SystemTime::now(), attrs, error: failed };
+        match span.line() {
+            Ok(line) => {
+                self.lines.send(line).ok();
+            }
+            Err(e) => warn!("span not recorded: {}", e),
+        }
+    }
+}
+
+/// A traced frame out with a consumer: since when, and under what context.
+#[cfg(feature = "otel")]
+struct Traced {
+    parent:   TraceContext,
+    taken:    SystemTime,
+    consumer: ConsumerId,
+}
+
+/// Memory and GPUs: a message's resource hints, or a consumer's capacity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Resources {
+    memory: u64,
+    gpus:   u32,
+}
+
+impl Resources {
+    /// What a message says it needs; unhinted dimensions need nothing.
+    fn demand(meta: &Meta) -> Self {
+        Self { memory: meta.memory.unwrap_or(0), gpus: meta.gpus.unwrap_or(0) }
+    }
+
+    fn fits_in(self, room: Self) -> bool {
+        self.memory <= room.memory && self.gpus <= room.gpus
+    }
+
+    fn plus(self, o: Self) -> Self {
+        Self {
+            memory: self.memory.saturating_add(o.memory),
+            gpus:   self.gpus.saturating_add(o.gpus),
+        }
+    }
+
+    fn minus(self, o: Self) -> Self {
+        Self {
+            memory: self.memory.saturating_sub(o.memory),
+            gpus:   self.gpus.saturating_sub(o.gpus),
+        }
+    }
+}
+
+/// A consumer's advertised capacity and what its unacked work holds.
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    total: Resources,
+    used:  Resources,
+}
+
+/// What a consumer can take of the messages only some can (capability
+/// tags, resource hints), as a shard's consumers offer it to a sibling
+/// deciding what to lend them (`Router::lend`).
+#[derive(Debug, Clone, Default)]
+struct Taker {
+    caps:   BTreeSet<String>,
+    budget: Option<Resources>,
+}
+
+impl Taker {
+    fn can_take(&self, meta: &Meta) -> bool {
+        meta.requires.iter().all(|t| self.caps.contains(t))
+            && self.budget.is_none_or(|b| Resources::demand(meta).fits_in(b))
+    }
+}
+
+/// What happens to a message no connected consumer could ever take: its
+/// capability tags aren't advertised, or its resource hints exceed every
+/// consumer's capacity (QPIPE_TAG_FALLBACK).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum TagFallback {
+    /// Keep it queued until a capable consumer connects.
+    #[default]
+    Wait,
+    /// Deliver it to any consumer, ignoring its requirements.
+    Any,
+    /// Send it to the retry policy's dead-letter destination.
+    DeadLetter,
+}
+
+impl TagFallback {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "wait"        => Ok(Self::Wait),
+            "any"         => Ok(Self::Any),
+            "dead-letter" => Ok(Self::DeadLetter),
+            other => Err(format!(
+                "unknown tag fallback {other:?} (expected wait, any or dead-letter)"
+            )),
+        }
+    }
+}
+
+/// Which waiting consumer takes the next item (QPIPE_DISPATCH).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Dispatch {
+    /// Whichever consumer handler gets there first.
+    #[default]
+    Free,
+    /// Waiting consumers take turns, the one served longest ago first.
+    RoundRobin,
+    /// The waiting consumer with the fewest unsettled deliveries first,
+    /// taking turns among equals.
+    LeastOutstanding,
+}
+
+impl Dispatch {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "free"              => Ok(Self::Free),
+            "round-robin"       => Ok(Self::RoundRobin),
+            "least-outstanding" => Ok(Self::LeastOutstanding),
+            other => Err(format!(
+                "unknown dispatch {other:?} (expected free, round-robin or least-outstanding)"
+            )),
+        }
+    }
+}
+
+/// How a consumer may treat a queued item, given capability tags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Take,
+    /// Someone else can (or may later) take it.
+    Skip,
+    /// Nobody can take it and the fallback says dead-letter.
+    Dead,
+}
+
+/// Where messages that exhaust their retry budget go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DeadLetter {
+    Drop,
+    /// Append the message's frames to this file, in wire format without
+    /// ACKs (readable with `crate::get_frame`), each carrying its attempt
+    /// count in the frame metadata.
+    File(PathBuf),
+    /// Queue the messages again on this named queue, for a consumer of it
+    /// to drain. Its own dead letters are dropped.
+    Queue(String),
+}
+
+/// Failure handling for ack-mode deliveries, configured in one place:
+///
+///   QPIPE_RETRY_POLICY="timeout=30s,retries=5,backoff=1s..1m,dlq=drop"
+///
+/// Every key is optional (defaults shown). `timeout` is the visibility
+/// timeout; `retries` how many redeliveries a message gets after its first
+/// delivery; `backoff` the delay before a redelivery, doubling per attempt
+/// from the first bound up to the second (`backoff=0` redelivers at once);
+/// `dlq` is `drop`, `file:<path>` or `queue:<name>`. Durations take `ms`, `s`, `m` or `h`;
+/// a bare number means seconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RetryPolicy {
+    visibility_timeout: Duration,
+    max_retries:        u32,
+    backoff_min:        Duration,
+    backoff_max:        Duration,
+    dead_letter:        DeadLetter,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            visibility_timeout: Duration::from_secs(30),
+            max_retries:        5,
+            backoff_min:        Duration::from_secs(1),
+            backoff_max:        Duration::from_secs(60),
+            dead_letter:        DeadLetter::Drop,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn parse(spec: &str) -> io::Result<Self> {
+        let bad = |msg: String| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("QPIPE_RETRY_POLICY: {msg}"),
+        );
+        let mut p = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, val) = part.split_once('=')
+                .ok_or_else(|| bad(format!("expected key=value, got {part:?}")))?;
+            match key.trim() {
+                "timeout" => {
+                    p.visibility_timeout = parse_duration(val).map_err(bad)?;
+                }
+                "retries" => {
+                    p.max_retries = val.trim().parse()
+                        .map_err(|_| bad(format!("bad retry count {val:?}")))?;
+                }
+                "backoff" => {
+                    let (lo, hi) = val.split_once("..").unwrap_or((val, val));
+                    p.backoff_min = parse_duration(lo).map_err(bad)?;
+                    p.backoff_max = parse_duration(hi).map_err(bad)?;
+                    if p.backoff_max < p.backoff_min {
+                        return Err(bad(format!("backoff range {val:?} is reversed")));
+                    }
+                }
+                "dlq" => {
+                    p.dead_letter = match val.trim() {
+                        "drop" => DeadLetter::Drop,
+                        v => match (v.strip_prefix("file:"), v.strip_prefix("queue:")) {
+                            (Some(path), _) if !path.is_empty() => {
+                                DeadLetter::File(PathBuf::from(path))
+                            }
+                            (_, Some(name)) if !name.is_empty() => {
+                                check_queue_name(name).map_err(|e| bad(format!("dlq: {e}")))?;
+                                DeadLetter::Queue(name.to_string())
+                            }
+                            _ => return Err(bad(format!("bad dlq target {val:?}"))),
+                        },
+                    };
+                }
+                k => return Err(bad(format!("unknown key {k:?}"))),
+            }
+        }
+        if p.visibility_timeout.is_zero() {
+            return Err(bad("timeout must be positive".into()));
+        }
+        Ok(p)
+    }
+
+    /// Delay before redelivery number `attempt` (1-based).
+    fn backoff(&self, attempt: u32) -> Duration {
+        let shift = attempt.saturating_sub(1).min(31);
+        self.backoff_min
+            .checked_mul(1 << shift)
+            .unwrap_or(self.backoff_max)
+            .min(self.backoff_max)
+    }
+}
+
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("bad duration {s:?}"))?;
+    match unit {
+        "ms"     => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m"      => Ok(Duration::from_secs(n * 60)),
+        "h"      => Ok(Duration::from_secs(n * 3600)),
+        _ => Err(format!("bad duration unit in {s:?}")),
+    }
+}
+
+/// QPIPE_HEARTBEAT="<interval>,<timeout>", e.g. "10s,30s": the heartbeat
+/// imposed on clients that ask for one, in place of their own.
+fn parse_heartbeat(s: &str) -> Result<Heartbeat, String> {
+    let (interval, timeout) = s.split_once(',')
+        .ok_or_else(|| format!("expected INTERVAL,TIMEOUT, got {s:?}"))?;
+    Heartbeat::new(parse_duration(interval)?, parse_duration(timeout)?).map_err(|e| e.to_string())
+}
+
+/// When to ask for more workers, and when to let them go:
+///
+///   QPIPE_AUTOSCALE="depth=1000,wait=30s,for=1m,cooldown=5m"
+///
+/// The queue is under pressure while at least `depth` frames wait for a
+/// consumer, or the longest-waiting one has waited `wait`; either key may
+/// be left out, not both. Pressure held `for` (default 1m) fires the
+/// scale-up hook, and again every `cooldown` (default 5m) while it lasts.
+/// Once the queue has stayed empty `for` after a scale-up, the scale-down
+/// hook fires once. No hook fires within `cooldown` of the previous one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AutoscalePolicy {
+    depth:    Option<usize>,
+    wait:     Option<Duration>,
+    sustain:  Duration,
+    cooldown: Duration,
+}
+
+impl AutoscalePolicy {
+    fn parse(spec: &str) -> io::Result<Self> {
+        let bad = |msg: String| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("QPIPE_AUTOSCALE: {msg}"),
+        );
+        let mut p = Self {
+            depth:    None,
+            wait:     None,
+            sustain:  Duration::from_secs(60),
+            cooldown: Duration::from_secs(300),
+        };
+        for part in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, val) = part.split_once('=')
+                .ok_or_else(|| bad(format!("expected key=value, got {part:?}")))?;
+            match key.trim() {
+                "depth" => p.depth = Some(
+                    val.trim().parse().map_err(|_| bad(format!("bad depth {val:?}")))?,
+                ),
+                "wait"     => p.wait = Some(parse_duration(val).map_err(bad)?),
+                "for"      => p.sustain = parse_duration(val).map_err(bad)?,
+                "cooldown" => p.cooldown = parse_duration(val).map_err(bad)?,
+                k => return Err(bad(format!("unknown key {k:?}"))),
+            }
+        }
+        if p.depth.is_none() && p.wait.is_none() {
+            return Err(bad("needs depth=, wait= or both".into()));
+        }
+        Ok(p)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScaleEvent {
+    Up,
+    Down,
+}
+
+impl ScaleEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            ScaleEvent::Up   => "scale-up",
+            ScaleEvent::Down => "scale-down",
+        }
+    }
+}
+
+/// Turns periodic backlog samples into scale events per the policy.
+struct Autoscaler {
+    policy:        AutoscalePolicy,
+    pressed_since: Option<Instant>,
+    empty_since:   Option<Instant>,
+    last_fired:    Option<Instant>,
+    scaled_up:     bool,
+}
+
+impl Autoscaler {
+    fn new(policy: AutoscalePolicy) -> Self {
+        Self { policy, pressed_since: None, empty_since: None, last_fired: None, scaled_up: false }
+    }
+
+    fn observe(&mut self, now: Instant, depth: usize, wait: Duration) -> Option<ScaleEvent> {
+        let p = &self.policy;
+        let pressed = p.depth.is_some_and(|d| depth >= d) || p.wait.is_some_and(|w| wait >= w);
+        self.pressed_since = pressed.then(|| self.pressed_since.unwrap_or(now));
+        self.empty_since = (depth == 0).then(|| self.empty_since.unwrap_or(now));
+        let held = |since: Option<Instant>| since.is_some_and(|t| now - t >= p.sustain);
+        let cooled = self.last_fired.is_none_or(|t| now - t >= p.cooldown);
+
+        let event = if !cooled {
+            None
+        } else if held(self.pressed_since) {
+            Some(ScaleEvent::Up)
+        } else if held(self.empty_since) && self.scaled_up {
+            Some(ScaleEvent::Down)
+        } else {
+            None
+        };
+        if let Some(ev) = event {
+            self.scaled_up = ev == ScaleEvent::Up;
+            self.last_fired = Some(now);
+        }
+        event
+    }
+}
+
+/// Where a scale event goes: a shell command (run like QPIPE_ON_IDLE_CMD,
+/// with QPIPE_DEPTH and QPIPE_OLDEST_WAIT_MS added to its environment), or
+/// an `http://` URL that gets the event POSTed as JSON. For https, run
+/// `curl` from a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Hook {
+    Cmd(String),
+    Http { authority: String, path: String },
+}
+
+impl Hook {
+    fn parse(spec: &str) -> Result<Self, String> {
+        if spec.starts_with("https://") {
+            return Err("https is not supported; use a command such as `curl -d @- …`".into());
+        }
+        let Some(rest) = spec.strip_prefix("http://") else {
+            return Ok(Hook::Cmd(spec.to_string()));
+        };
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        if authority.is_empty() {
+            return Err(format!("no host in {spec:?}"));
+        }
+        split_authority(authority).map_err(|e| format!("{e} in {spec:?}"))?;
+        let path = if path.is_empty() { "/" } else { path };
+        Ok(Hook::Http { authority: authority.to_string(), path: path.to_string() })
+    }
+
+    fn fire(&self, event: ScaleEvent, depth: usize, wait: Duration) {
+        let wait_ms = wait.as_millis();
+        match self {
+            Hook::Cmd(cmd) => run_hook(cmd, event.as_str(), &[
+                ("QPIPE_DEPTH", depth.to_string()),
+                ("QPIPE_OLDEST_WAIT_MS", wait_ms.to_string()),
+            ]),
+            Hook::Http { authority, path } => {
+                let (authority, path) = (authority.clone(), path.clone());
+                let body = format!(
+                    r#"{{"event":"{}","depth":{depth},"oldest_wait_ms":{wait_ms}}}"#,
+                    event.as_str(),
+                );
+                thread::spawn(move || match post_json(&authority, &path, &body) {
+                    Ok(status) if (200..300).contains(&status) => {
+                        debug!("{} webhook answered {}", event.as_str(), status);
+                    }
+                    Ok(status) => warn!("{} webhook http://{}{} answered {}", event.as_str(), authority, path, status),
+                    Err(e) => warn!("{} webhook http://{}{} failed: {}", event.as_str(), authority, path, e),
+                });
+            }
+        }
+    }
+}
+
+/// `host[:port]` or `[v6]:port` of an http:// URL, port 80 if none.
+fn split_authority(authority: &str) -> Result<(&str, u16), String> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, after) = rest.split_once(']').ok_or("unclosed [ in host")?;
+            match after {
+                "" => (host, None),
+                _ => (host, Some(after.strip_prefix(':').ok_or("junk after ]")?)),
+            }
+        }
+        None => match authority.split_once(':') {
+            Some((_, port)) if port.contains(':') => return Err("IPv6 hosts need [brackets]".into()),
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        return Err("no host".into());
+    }
+    let port = match port {
+        Some(p) => p.parse().map_err(|_| format!("bad port {p:?}"))?,
+        None => 80,
+    };
+    Ok((host, port))
+}
+
+/// A minimal HTTP/1.1 POST; returns the response status code.
+fn post_json(authority: &str, path: &str, body: &str) -> io::Result<u16> {
+    use std::io::BufRead;
+    use std::net::ToSocketAddrs;
+
+    let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("malformed HTTP response: {msg}"));
+    let (host, port) = split_authority(authority).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let addr = (host, port).to_socket_addrs()?.next().ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput, format!("could not resolve {authority}"),
+    ))?;
+    let mut s = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    s.set_read_timeout(Some(Duration::from_secs(10))).ok();
+    s.set_write_timeout(Some(Duration::from_secs(10))).ok();
+    write!(
+        s,
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    )?;
+    s.flush()?;
+    // The status line, "HTTP/1.1 200 OK\r\n", whole; the rest is unread.
+    let mut line = Vec::new();
+    io::BufReader::new(s).take(1024).read_until(b'\n', &mut line)?;
+    let line = line.strip_suffix(b"\r\n").ok_or_else(|| bad("no status line"))?;
+    let line = std::str::from_utf8(line).map_err(|_| bad("status line is not UTF-8"))?;
+    let mut parts = line.splitn(3, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(v), Some(code)) if v.starts_with("HTTP/1.") && code.len() == 3 => {
+            code.parse().map_err(|_| bad(line))
+        }
+        _ => Err(bad(line)),
+    }
+}
+
+/// Egress bandwidth caps, so bulk reprocessing can't starve real-time
+/// traffic sharing the uplink:
+///
+///   QPIPE_EGRESS_LIMIT="consumer=10MiB,queue=100MiB"
+///
+/// Both keys are optional and in bytes per second: `consumer` caps each
+/// consumer connection, `queue` all deliveries together. Rates take `k`,
+/// `M`, `G` (powers of 1000) or `KiB`, `MiB`, `GiB`, optionally followed by
+/// `B` and/or `/s`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct EgressLimit {
+    per_consumer: Option<u64>,
+    queue:        Option<u64>,
+}
+
+impl EgressLimit {
+    fn parse(spec: &str) -> io::Result<Self> {
+        let bad = |msg: String| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("QPIPE_EGRESS_LIMIT: {msg}"),
+        );
+        let mut l = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, val) = part.split_once('=')
+                .ok_or_else(|| bad(format!("expected key=value, got {part:?}")))?;
+            let rate = Some(parse_rate(val).map_err(bad)?);
+            match key.trim() {
+                "consumer" => l.per_consumer = rate,
+                "queue"    => l.queue = rate,
+                k => return Err(bad(format!("unknown key {k:?}"))),
+            }
+        }
+        Ok(l)
+    }
+}
+
+/// Rate caps on each producer connection, so one misbehaving producer
+/// can't saturate the queue and starve the rest:
+///
+///   QPIPE_PRODUCER_LIMIT="msgs=1000/s,bytes=10MiB/s,mode=reject"
+///
+/// `msgs` counts messages (a chunked one once), `bytes` their payloads;
+/// either may be omitted, and both take the units of QPIPE_EGRESS_LIMIT.
+/// `mode=block` (the default) holds a producer's frames back until they
+/// fit; `mode=reject` refuses them with `ACK_THROTTLED` instead — to
+/// producers that asked for that (`OPT_THROTTLE`), still blocking others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ProducerLimit {
+    msgs:   Option<u64>,
+    bytes:  Option<u64>,
+    reject: bool,
+}
+
+impl ProducerLimit {
+    fn parse(spec: &str) -> io::Result<Self> {
+        let bad = |msg: String| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("QPIPE_PRODUCER_LIMIT: {msg}"),
+        );
+        let mut l = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, val) = part.split_once('=')
+                .ok_or_else(|| bad(format!("expected key=value, got {part:?}")))?;
+            match (key.trim(), val.trim()) {
+                ("msgs", val)  => l.msgs = Some(parse_rate(val).map_err(bad)?),
+                ("bytes", val) => l.bytes = Some(parse_rate(val).map_err(bad)?),
+                ("mode", "block")  => l.reject = false,
+                ("mode", "reject") => l.reject = true,
+                ("mode", m) => return Err(bad(format!("mode must be block or reject, got {m:?}"))),
+                (k, _) => return Err(bad(format!("unknown key {k:?}"))),
+            }
+        }
+        Ok(l)
+    }
+}
+
+/// QPIPE_SESSION_TIMEOUTS: how long a producer or consumer session may
+/// stall or sit idle before the orchestrator drops it, e.g.
+/// `read=30s,write=30s,idle=10m`. Unset keys never time out.
+///
+/// `read` bounds the rest of a producer's frame once its first byte is in,
+/// and a consumer's acknowledgement of a frame it was sent; `write` bounds
+/// each write to either. `idle` is how long a producer may send nothing,
+/// or a consumer go without a delivery.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct SessionTimeouts {
+    read:  Option<Duration>,
+    write: Option<Duration>,
+    idle:  Option<Duration>,
+}
+
+impl SessionTimeouts {
+    fn parse(spec: &str) -> io::Result<Self> {
+        let bad = |msg: String| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("QPIPE_SESSION_TIMEOUTS: {msg}"),
+        );
+        let mut t = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, val) = part.split_once('=')
+                .ok_or_else(|| bad(format!("expected key=value, got {part:?}")))?;
+            let slot = match key.trim() {
+                "read"  => &mut t.read,
+                "write" => &mut t.write,
+                "idle"  => &mut t.idle,
+                k => return Err(bad(format!("unknown key {k:?}"))),
+            };
+            let d = parse_duration(val).map_err(bad)?;
+            if d.is_zero() {
+                return Err(bad(format!("{} must be positive", key.trim())));
+            }
+            *slot = Some(d);
+        }
+        Ok(t)
+    }
+}
+
+/// QPIPE_SESSION_TOKEN: how long a session token stays good, and how many
+/// wrong ones its data port takes, e.g. `ttl=10s,attempts=3`. Past either
+/// the port is closed and the session abandoned, so a client that dies
+/// after the control exchange doesn't leave a listener behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TokenPolicy {
+    ttl:      Duration,
+    attempts: u32,
+}
+
+impl Default for TokenPolicy {
+    fn default() -> Self {
+        Self { ttl: Duration::from_secs(30), attempts: 3 }
+    }
+}
+
+impl TokenPolicy {
+    fn parse(spec: &str) -> io::Result<Self> {
+        let bad = |msg: String| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("QPIPE_SESSION_TOKEN: {msg}"),
+        );
+        let mut t = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, val) = part.split_once('=')
+                .ok_or_else(|| bad(format!("expected key=value, got {part:?}")))?;
+            match key.trim() {
+                "ttl" => t.ttl = parse_duration(val).map_err(bad)?,
+                "attempts" => {
+                    t.attempts = val.trim().parse()
+                        .map_err(|_| bad(format!("bad attempt count {val:?}")))?;
+                }
+                k => return Err(bad(format!("unknown key {k:?}"))),
+            }
+        }
+        if t.ttl.is_zero() || t.attempts == 0 {
+            return Err(bad("ttl and attempts must be positive".into()));
+        }
+        Ok(t)
+    }
+}
+
+fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("bad rate {s:?}"))?;
+    let unit = unit.strip_suffix("/s").unwrap_or(unit);
+    let unit = unit.strip_suffix('B').unwrap_or(unit);
+    let scale: u64 = match unit {
+        ""        => 1,
+        "k" | "K" => 1_000,
+        "M"       => 1_000_000,
+        "G"       => 1_000_000_000,
+        "Ki"      => 1 << 10,
+        "Mi"      => 1 << 20,
+        "Gi"      => 1 << 30,
+        _ => return Err(format!("bad rate unit in {s:?}")),
+    };
+    match n.checked_mul(scale) {
+        Some(0) => Err(format!("rate {s:?} must be positive")),
+        Some(r) => Ok(r),
+        None => Err(format!("rate {s:?} is too large")),
+    }
+}
+
+/// Token bucket pacing deliveries to `rate` bytes/s (or producers to
+/// `rate` messages or bytes/s). Holds at most 100ms worth of tokens, so an
+/// idle connection can't bank a long full-speed burst. A frame larger than
+/// that still goes out: the bucket runs into debt and the following frames
+/// wait it off.
+#[derive(Debug)]
+struct TokenBucket {
+    rate:   f64,
+    burst:  f64,
+    tokens: f64,
+    last:   Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        let burst = rate as f64 / 10.0;
+        Self { rate: rate as f64, burst, tokens: burst, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = self.last.max(now);
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+    }
+
+    fn in_debt(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens < 0.0
+    }
+
+    /// Take `bytes` tokens; returns how long the sender must wait first.
+    fn reserve(&mut self, bytes: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// A producer connection's own buckets under QPIPE_PRODUCER_LIMIT.
+struct Throttle {
+    msgs:    Option<TokenBucket>,
+    bytes:   Option<TokenBucket>,
+    /// Messages refused on their first chunk, whose later chunks follow.
+    refused: HashSet<MsgId>,
+}
+
+impl Throttle {
+    /// `None` without any cap.
+    fn new(limit: ProducerLimit, now: Instant) -> Option<Self> {
+        if limit.msgs.is_none() && limit.bytes.is_none() {
+            return None;
+        }
+        Some(Self {
+            msgs:    limit.msgs.map(|r| TokenBucket::new(r, now)),
+            bytes:   limit.bytes.map(|r| TokenBucket::new(r, now)),
+            refused: HashSet::new(),
+        })
+    }
+
+    /// What `frame` costs: a message for a single frame or a first chunk,
+    /// and its payload bytes. Control frames are free.
+    fn cost(frame: &Frame) -> (u64, u64) {
+        match frame {
+            Frame::Msg(p) => (1, p.len() as u64),
+            Frame::Chunk { idx, payload, .. } => (u64::from(*idx == 0), payload.len() as u64),
+            Frame::Eos(_) | Frame::Ping | Frame::Bye => (0, 0),
+        }
+    }
+
+    /// Blocking: how long to hold `frame` back.
+    fn delay(&mut self, frame: &Frame, now: Instant) -> Duration {
+        let (msgs, bytes) = Self::cost(frame);
+        let m = self.msgs.as_mut().map_or(Duration::ZERO, |b| b.reserve(msgs, now));
+        let b = self.bytes.as_mut().map_or(Duration::ZERO, |b| b.reserve(bytes, now));
+        m.max(b)
+    }
+
+    /// Rejecting: whether to take `frame`, refused while either bucket is
+    /// in debt. A message is judged by its first chunk, and its later
+    /// chunks share the verdict.
+    fn admits(&mut self, frame: &Frame, now: Instant) -> bool {
+        match frame {
+            Frame::Chunk { id, idx, count, .. } if *idx > 0 => {
+                if self.refused.contains(id) {
+                    if *idx + 1 == *count {
+                        self.refused.remove(id);
+                    }
+                    return false;
+                }
+            }
+            Frame::Eos(_) | Frame::Ping | Frame::Bye => {}
+            _ => {
+                let mut buckets = self.msgs.iter_mut().chain(self.bytes.iter_mut());
+                if buckets.any(|b| b.in_debt(now)) {
+                    if let Frame::Chunk { id, count, .. } = frame
+                        && *count > 1
+                    {
+                        self.refused.insert(*id);
+                    }
+                    return false;
+                }
+            }
+        }
+        self.delay(frame, now);
+        true
+    }
+}
+
+/// Whether `intake` turns `frame` away. As under `Throttle::admits`, a
+/// message is judged by its first chunk and its later chunks share the
+/// verdict — `refused` also holds messages a full queue turned away
+/// (`Pushed::Rejected`). Control frames always pass, and an EOS waits out
+/// the refusal like a pause.
+fn refuses(intake: Intake, frame: &Frame, refused: &mut HashSet<MsgId>) -> bool {
+    match frame {
+        Frame::Chunk { id, idx, count, .. } if *idx > 0 => {
+            let was = refused.contains(id);
+            if was && *idx + 1 == *count {
+                refused.remove(id);
+            }
+            was
+        }
+        Frame::Eos(_) | Frame::Ping | Frame::Bye => false,
+        _ if intake != Intake::Refused => false,
+        _ => {
+            if let Frame::Chunk { id, count, .. } = frame
+                && *count > 1
+            {
+                refused.insert(*id);
+            }
+            true
+        }
+    }
+}
+
+/// Per in-flight multi-frame message: who owns it and how far along it is.
+struct Assign {
+    owner:     ConsumerId,
+    delivered: u32,
+    count:     u32,
+    last_seen: Instant,
+}
+
+/// A queued frame plus its acceptance sequence number. Sequence numbers are
+/// assigned at push and survive requeues, so "was this frame accepted before
+/// that EOS marker?" has a definite answer however frames get shuffled.
+/// `attempts` counts unacknowledged ack-mode deliveries of the message;
+/// `meta` is what the producer attached (priority, ...); `enqueued` is when
+/// the orchestrator accepted the frame.
+#[derive(Clone)]
+struct Item {
+    seq:      u64,
+    attempts: u32,
+    enqueued: Instant,
+    meta:     Meta,
+    frame:    Frame,
+}
+
+/// The shared queue: one FIFO lane per priority, highest lane served first.
+/// With aging enabled, a lane's head gains one level for every `aging` step
+/// it has waited, so low priorities are never starved by a constant stream
+/// of high-priority traffic; on equal effective priority the earlier-
+/// accepted frame wins. Without producer priorities this is exactly the
+/// original single FIFO.
+#[derive(Default)]
+struct Lanes {
+    lanes: BTreeMap<u8, VecDeque<Item>>,
+    aging: Option<Duration>,
+    len:   usize,
+}
+
+impl Lanes {
+    fn push_back(&mut self, it: Item) {
+        let prio = it.meta.priority.unwrap_or(0);
+        self.lanes.entry(prio).or_default().push_back(it);
+        self.len += 1;
+    }
+
+    /// Every item, highest lane first and FIFO within.
+    fn iter(&self) -> impl Iterator<Item = &Item> {
+        self.lanes.values().rev().flatten()
+    }
+
+    /// Remove every item matching `pred`, in `iter` order.
+    fn take_where(&mut self, mut pred: impl FnMut(&Item) -> bool) -> Vec<Item> {
+        let mut out = Vec::new();
+        for q in self.lanes.values_mut().rev() {
+            let (take, keep): (VecDeque<Item>, VecDeque<Item>) =
+                q.drain(..).partition(&mut pred);
+            *q = keep;
+            out.extend(take);
+        }
+        self.lanes.retain(|_, q| !q.is_empty());
+        self.len -= out.len();
+        out
+    }
+
+    fn extend(&mut self, items: impl IntoIterator<Item = Item>) {
+        for it in items {
+            self.push_back(it);
+        }
+    }
+
+    #[cfg(test)]
+    fn pop_at(&mut self, now: Instant) -> Option<Item> {
+        self.pop_where(now, |_| true)
+    }
+
+    /// Pop the next item matching `pred`: each lane offers its first match,
+    /// and the lanes compete by (aged) priority exactly as their heads do.
+    fn pop_where(
+                &mut self,
+                now: Instant,
+                mut pred: impl FnMut(&Item) -> bool,
+            ) -> Option<Item> {
+        let (lane, pos) = match self.aging {
+            None => self.lanes.iter().rev().find_map(|(p, q)| {
+                q.iter().position(&mut pred).map(|i| (*p, i))
+            })?,
+            Some(step) => {
+                let step = step.as_nanos().max(1);
+                let (.., lane, pos) = self.lanes.iter()
+                    .filter_map(|(p, q)| {
+                        let i = q.iter().position(&mut pred)?;
+                        let waited = now.saturating_duration_since(q[i].enqueued);
+                        let boost = (waited.as_nanos() / step) as u64;
+                        Some((
+                            (*p as u64).saturating_add(boost),
+                            std::cmp::Reverse(q[i].seq),
+                            *p,
+                            i,
+                        ))
+                    })
+                    .max()?;
+                (lane, pos)
+            }
+        };
+        let q = self.lanes.get_mut(&lane)?;
+        let it = q.remove(pos);
+        if q.is_empty() {
+            self.lanes.remove(&lane);
+        }
+        self.len -= it.is_some() as usize;
+        it
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// When the longest-waiting lane head was enqueued.
+    fn oldest(&self) -> Option<Instant> {
+        self.lanes.values().filter_map(VecDeque::front).map(|it| it.enqueued).min()
+    }
+}
+
+/// Items that may not be served before a given time: requeues waiting out
+/// their retry backoff, and messages their producer sent delayed
+/// (`Meta::delay`). Ordered by due time (then seq), so moving the due ones
+/// out never looks at the rest, however many wait.
+#[derive(Default)]
+struct Delayed {
+    timers: BTreeMap<(Instant, u64), Item>,
+}
+
+impl Delayed {
+    fn push(&mut self, ready: Instant, it: Item) {
+        self.timers.insert((ready, it.seq), it);
+    }
+
+    /// Remove the items due by `now`, earliest first.
+    fn pop_due(&mut self, now: Instant) -> impl Iterator<Item = Item> {
+        let later = self.timers.split_off(&(now, u64::MAX));
+        std::mem::replace(&mut self.timers, later).into_values()
+    }
+
+    /// When the next item falls due.
+    fn next_due(&self) -> Option<Instant> {
+        self.timers.first_key_value().map(|((ready, _), _)| *ready)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Item> {
+        self.timers.values()
+    }
+
+    /// Remove every item matching `pred`, earliest first.
+    fn take_where(&mut self, mut pred: impl FnMut(&Item) -> bool) -> Vec<Item> {
+        self.timers.extract_if(.., |_, it| pred(it)).map(|(_, it)| it).collect()
+    }
+
+    fn take_all(&mut self) -> Vec<Item> {
+        std::mem::take(&mut self.timers).into_values().collect()
+    }
+}
+
+/// A frame handed out by `next_for`, awaiting the handler's verdict.
+enum Held {
+    /// An EOS notice: nothing to settle.
+    Notice,
+    /// Plain delivery: settles when the consumer's frame ACK arrives. Keeps
+    /// what a requeue needs to put the frame back as it was.
+    Frame { seq: u64, attempts: u32, enqueued: Instant, meta: Box<Meta> },
+    /// Ack-mode delivery: the frame ACK only starts the visibility clock.
+    Tagged(u64),
+}
+
+/// One ack-mode delivery awaiting the consumer's ack.
+struct Unacked {
+    consumer: ConsumerId,
+    /// Set for chunked messages; all their chunks share the delivery.
+    msg:      Option<MsgId>,
+    items:    Vec<Item>,
+    /// None until the first frame reaches the consumer.
+    deadline: Option<Instant>,
+    /// Resource hints held against the consumer's budget until settled.
+    demand:   Resources,
+    /// Expected runtime hint; stretches the visibility deadline.
+    runtime:  Option<Duration>,
+}
+
+/// What became of a frame `Router::push_stamped` was given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Pushed {
+    /// Queued, in memory or in the overflow.
+    Queued,
+    /// A straggler of a tombstoned message, dropped.
+    Dropped,
+    /// Turned away by a full queue (`FullPolicy::RejectNewest`).
+    Rejected,
+}
+
+/// Whether a queue takes producers' frames. An admin may pause intake,
+/// holding producers as if the queue were full, or refuse it: producers
+/// that can be told (`OPT_THROTTLE`) have new messages refused with
+/// `ACK_THROTTLED`, and the rest are held as for a pause.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Intake {
+    #[default]
+    Open,
+    Paused,
+    Refused,
+}
+
+#[derive(Default)]
+struct RouterInner {
+    shared:   Lanes,
+    directed: HashMap<ConsumerId, VecDeque<Item>>,
+    assign:   HashMap<MsgId, Assign>,
+    tomb:     HashMap<MsgId, Instant>,
+    /// Frames in `shared` plus all `directed` queues; capacity applies here.
+    total:    usize,
+    next_seq: u64,
+    /// Seqs of data frames accepted but not yet delivered or dropped.
+    outstanding: BTreeSet<u64>,
+    /// Per consumer: frames handed out by next_for and not yet confirmed
+    /// by `delivered` / `fail_delivery`, oldest first.
+    held:     HashMap<ConsumerId, VecDeque<Held>>,
+    /// EOS markers popped from the queue, waiting for every data frame
+    /// accepted before them to settle. Released strictly in order.
+    barriers: VecDeque<(u64, Vec<u8>)>,
+    /// Released EOS notices waiting to be written to each consumer.
+    notices:  HashMap<ConsumerId, VecDeque<Vec<u8>>>,
+    /// Consumers that settle by explicit ack.
+    ack_mode: HashSet<ConsumerId>,
+    /// Consumers whose connection is known dead; next_for returns None.
+    gone:     HashSet<ConsumerId>,
+    next_tag: u64,
+    /// Ack-mode deliveries awaiting their ack, by delivery tag.
+    unacked:  HashMap<u64, Unacked>,
+    /// Tag of the current delivery of each claimed chunked message.
+    msg_tags: HashMap<MsgId, u64>,
+    /// Weighted ack-mode consumers: max unacked deliveries at once (0 =
+    /// paused).
+    windows:  HashMap<ConsumerId, u32>,
+    /// Unacked deliveries per ack-mode consumer.
+    inflight: HashMap<ConsumerId, u32>,
+    /// Capability tags of the consumers that advertised any.
+    caps:     HashMap<ConsumerId, BTreeSet<String>>,
+    /// Subscription filters of the consumers that set one.
+    filters:  HashMap<ConsumerId, Filter>,
+    /// Resource budgets of the consumers that advertised capacity.
+    budgets:  HashMap<ConsumerId, Budget>,
+    /// Requeued ack-mode items waiting out their backoff, and delayed
+    /// messages waiting for their time; counted in `total`.
+    delayed:  Delayed,
+    /// Audit log: stamps of unsettled data frames by seq, the consumers'
+    /// peer addresses, and the line writer (None when auditing is off).
+    stamps:   HashMap<u64, Stamp>,
+    peers:    HashMap<ConsumerId, String>,
+    audit:    Option<mpsc::Sender<String>>,
+    /// Tracing (see `otel`): where spans go, and the traced frames out
+    /// with consumers, by seq.
+    #[cfg(feature = "otel")]
+    tracer:   Option<Tracer>,
+    #[cfg(feature = "otel")]
+    traced:   HashMap<u64, Traced>,
+    /// Frames in the disk overflow, oldest first: when each was spilled,
+    /// its audit stamp and its write-ahead log lsn. Not counted in `total`.
+    spilled:  VecDeque<(Instant, Option<Stamp>, Option<u64>)>,
+    /// Dispatch: consumers blocked waiting for an item, and the turn each
+    /// last took a shared item on (never: 0).
+    waiting:  HashSet<ConsumerId>,
+    /// Of those, the ones parked in the reactor (`poll_next`) rather than
+    /// blocked in `take_next`.
+    parked:   HashSet<ConsumerId>,
+    turns:    HashMap<ConsumerId, u64>,
+    turn:     u64,
+    /// Intake paused or refused by an admin.
+    intake:   Intake,
+    /// The write-ahead log (--wal-dir), shared by a sharded queue's
+    /// shards, and the lsn of each journaled frame by seq.
+    wal:       Option<Arc<Mutex<Wal>>>,
+    journaled: HashMap<u64, u64>,
+    /// Partition keys with frames handed out and not yet settled: the
+    /// consumer they are pinned to until then, and those frames' seqs.
+    pins:      HashMap<Vec<u8>, (ConsumerId, HashSet<u64>)>,
+    /// The partition key of each pinned seq.
+    pinned:    HashMap<u64, Vec<u8>>,
+    /// Set for the shards of a sharded queue (`Shards`): the set and this
+    /// shard's index, this shard's part of the set's EOS markers, and how
+    /// many released markers it has passed on to its consumers.
+    member:    Option<(Arc<ShardLink>, usize)>,
+    shard_barriers: VecDeque<ShardBarrier>,
+    eos_seen:  u64,
+    /// A shard's frames stolen by its siblings, and the frames it stole:
+    /// by seq here, the lender's `Lent` and seq there.
+    lent:      Option<Arc<Mutex<Lent>>>,
+    loans:     HashMap<u64, (Arc<Mutex<Lent>>, u64)>,
+}
+
+impl RouterInner {
+    /// A data frame left the system for good (delivered or dropped). If
+    /// it's still stamped, no one recorded a better outcome: it was dropped.
+    fn settle(&mut self, seq: u64) {
+        self.outstanding.remove(&seq);
+        self.audit(seq, None, "dropped");
+        self.unjournal(seq);
+        self.unpin(seq);
+        self.repay(seq);
+    }
+
+    /// A stolen frame settles at its lender too.
+    fn repay(&mut self, seq: u64) {
+        if let Some((lent, seq)) = self.loans.remove(&seq) {
+            let mut l = lent.lock().unwrap();
+            l.out.remove(&seq);
+            l.keys.remove(&seq);
+            l.back.push(seq);
+        }
+    }
+
+    /// Whether frames under partition `key` are lent to a sibling shard
+    /// and unsettled there: the key is with its consumers until they are.
+    fn key_lent(&self, key: &[u8]) -> bool {
+        self.lent.as_ref().is_some_and(|l| l.lock().unwrap().keys.values().any(|k| k == key))
+    }
+
+    /// The live consumers, as takers of tagged and resource-hinted
+    /// messages.
+    fn takers(&self) -> Vec<Taker> {
+        self.held.keys()
+            .filter(|c| !self.gone.contains(c))
+            .map(|c| Taker {
+                caps:   self.caps.get(c).cloned().unwrap_or_default(),
+                budget: self.budgets.get(c).map(|b| b.total),
+            })
+            .collect()
+    }
+
+    /// The consumer that takes messages under partition `key`: the one
+    /// still holding some, or else the consumer it hashes to. Rendezvous
+    /// hashing, so a consumer joining or leaving moves only its share of
+    /// the keys.
+    fn key_owner(&self, key: &[u8]) -> Option<ConsumerId> {
+        if let Some((c, _)) = self.pins.get(key) {
+            return Some(*c);
+        }
+        self.held.keys()
+            .filter(|c| !self.gone.contains(c))
+            .max_by_key(|c| {
+                let mut h = std::hash::DefaultHasher::new();
+                (key, c).hash(&mut h);
+                (h.finish(), **c)
+            })
+            .copied()
+    }
+
+    /// Keep partition `key` on `me` until frame `seq` settles.
+    fn pin(&mut self, key: &[u8], me: ConsumerId, seq: u64) {
+        self.pins.entry(key.to_vec()).or_insert_with(|| (me, HashSet::new())).1.insert(seq);
+        self.pinned.insert(seq, key.to_vec());
+    }
+
+    fn unpin(&mut self, seq: u64) {
+        let Some(key) = self.pinned.remove(&seq) else { return };
+        if let Some((_, seqs)) = self.pins.get_mut(&key) {
+            seqs.remove(&seq);
+            if seqs.is_empty() {
+                self.pins.remove(&key);
+            }
+        }
+    }
+
+    /// Log an accepted frame to the write-ahead log, if there is one, and
+    /// return its lsn.
+    fn journal(&mut self, frame: &Frame, meta: &Meta) -> Option<u64> {
+        let wal = self.wal.as_ref()?;
+        wal.lock().unwrap().push(frame, meta)
+            .inspect_err(|e| error!("write-ahead log append failed; a frame is not journaled: {}", e))
+            .ok()
+    }
+
+    /// Frame `seq` is gone for good; so is its write-ahead log record.
+    fn unjournal(&mut self, seq: u64) {
+        if let Some(lsn) = self.journaled.remove(&seq) {
+            self.forget(lsn);
+        }
+    }
+
+    fn forget(&mut self, lsn: u64) {
+        if let Some(wal) = &self.wal
+            && let Err(e) = wal.lock().unwrap().done(lsn)
+        {
+            error!("write-ahead log append failed; a settled frame may be replayed: {}", e);
+        }
+    }
+
+    /// Write the audit line for data frame `seq`, if it is stamped and not
+    /// already recorded. Its delivery span, if it is traced, ends too.
+    fn audit(&mut self, seq: u64, consumer: Option<ConsumerId>, outcome: &str) {
+        #[cfg(feature = "otel")]
+        self.trace_settled(seq, outcome);
+        let Some(stamp) = self.stamps.remove(&seq) else { return };
+        let peer = consumer.map(|c| {
+            self.peers.get(&c).cloned().unwrap_or_else(|| format!("consumer-{c}"))
+        });
+        if let Some(tx) = &self.audit {
+            tx.send(stamp.line(peer.as_deref(), outcome)).ok();
+        }
+    }
+
+    /// Consumer `me` took `it` after it `waited` in the queue: its queue
+    /// wait ends and its delivery starts, if it is traced.
+    #[cfg(feature = "otel")]
+    fn trace_taken(&mut self, me: ConsumerId, it: &Item, waited: Duration) {
+        let Some(tracer) = &self.tracer else { return };
+        let Some(parent) = Tracer::context(&it.frame, &it.meta) else { return };
+        let taken = SystemTime::now();
+        let queued = taken.checked_sub(waited).unwrap_or(taken);
+        tracer.record("qpipe.queue_wait", SPAN_KIND_INTERNAL, &parent, queued, None, None);
+        self.traced.insert(it.seq, Traced { parent, taken, consumer: me });
+    }
+
+    /// The delivery of frame `seq` ended as `outcome`; see `trace_taken`.
+    #[cfg(feature = "otel")]
+    fn trace_settled(&mut self, seq: u64, outcome: &str) {
+        let (Some(tracer), Some(t)) = (&self.tracer, self.traced.remove(&seq)) else { return };
+        let peer = self.peers.get(&t.consumer).map(String::as_str);
+        let failed = (outcome != "delivered").then_some(outcome);
+        tracer.record("qpipe.deliver", SPAN_KIND_PRODUCER, &t.parent, t.taken, peer, failed);
+    }
+
+    /// Release every parked EOS marker whose predecessors have all settled,
+    /// fanning a notice out to each registered consumer. Returns true if
+    /// anything was released (callers then wake the consumer handlers).
+    fn release_barriers(&mut self) -> bool {
+        // Frames lent to sibling shards are outstanding here until they
+        // settle there; then their journal records can go.
+        let (repaid, first_lent) = self.lent.as_ref().map(|l| {
+            let mut l = l.lock().unwrap();
+            (std::mem::take(&mut l.back), l.out.first().copied())
+        }).unwrap_or_default();
+        // A frame lent on by its borrower settles at the first lender.
+        for seq in repaid {
+            self.unjournal(seq);
+            self.repay(seq);
+        }
+        let first = self.outstanding.first().copied().into_iter().chain(first_lent).min();
+        let mut released = false;
+        while let Some((seq, _)) = self.barriers.front() {
+            if first.is_some_and(|first| first < *seq) {
+                break;
+            }
+            let (seq, group) = self.barriers.pop_front().expect("front exists");
+            self.unjournal(seq);
+            for q in self.notices.values_mut() {
+                q.push_back(group.clone());
+            }
+            released = true;
+        }
+        self.release_shard_barriers(first) || released
+    }
+
+    /// A shard's `release_barriers` for the markers of its sharded queue:
+    /// release its part of each whose predecessors here have settled, and
+    /// pass on the notices of those every shard has released.
+    fn release_shard_barriers(&mut self, first: Option<u64>) -> bool {
+        let Some((link, me)) = self.member.clone() else { return false };
+        let ready = |b: &ShardBarrier| {
+            b.spilled_ahead == 0 && first.is_none_or(|first| first >= b.seq)
+        };
+        if !self.shard_barriers.front().is_some_and(ready)
+            && link.released.load(Ordering::Acquire) == self.eos_seen
+        {
+            return false;
+        }
+        let mut eos = link.eos.lock().unwrap();
+        let before = eos.released_total();
+        let mut lsns = Vec::new();
+        while let Some(b) = self.shard_barriers.front()
+            && ready(b)
+        {
+            let id = b.id;
+            self.shard_barriers.pop_front();
+            lsns.extend(eos.release(id));
+        }
+        let total = eos.released_total();
+        link.released.store(total, Ordering::Release);
+        let fresh = eos.take(me);
+        self.eos_seen = total;
+        drop(eos);
+        for lsn in lsns {
+            self.forget(lsn);
+        }
+        if total > before {
+            link.wake(me);
+        }
+        for group in &fresh {
+            for q in self.notices.values_mut() {
+                q.push_back(group.clone());
+            }
+        }
+        !fresh.is_empty()
+    }
+
+    /// The oldest spilled frame is back in memory, or gone: sharded EOS
+    /// markers waiting for it wait for the frames accepted from now on.
+    fn unspilled(&mut self) {
+        let next = self.next_seq;
+        for b in &mut self.shard_barriers {
+            if b.spilled_ahead > 0 {
+                b.spilled_ahead -= 1;
+                if b.spilled_ahead == 0 {
+                    b.seq = next;
+                }
+            }
+        }
+    }
+}
+
+enum Disposition {
+    Deliver,
+    DropTombstoned,
+    Redirect(ConsumerId),
+}
+
+/// Virtual time and seeded randomness for the deterministic simulation.
+/// Time stands still until the test advances it.
+#[cfg(test)]
+struct Sim {
+    now: Mutex<Instant>,
+    rng: Mutex<u64>,
+}
+
+#[cfg(test)]
+impl Sim {
+    fn new(seed: u64) -> Self {
+        Self { now: Mutex::new(Instant::now()), rng: Mutex::new(seed) }
+    }
+
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    /// splitmix64: a well-mixed stream from any seed, zero included.
+    fn next_u64(&self) -> u64 {
+        let mut s = self.rng.lock().unwrap();
+        *s = s.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *s;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// A sharded queue (`--shards`): the routers its frames are spread over,
+/// each behind its own lock, so sessions on different shards don't
+/// contend for one. Producers are dealt out to the shards in turn and
+/// consumers to the shard with the fewest; a consumer with nothing to do
+/// steals whole messages from the other shards (see `Router::lend`).
+/// Frames under a partition key go to the key's shard, and an EOS marker
+/// to every shard: its notice goes out once all of them have released it,
+/// so it still follows every frame accepted before it.
+struct Shards {
+    routers: Vec<Arc<Router>>,
+    /// Where the next producer goes.
+    next:    AtomicUsize,
+    link:    Arc<ShardLink>,
+}
+
+/// What the shards of a sharded queue share. Its locks are taken after a
+/// shard's own, never before.
+struct ShardLink {
+    shards:   OnceLock<Vec<Weak<Router>>>,
+    /// Consumers blocked waiting, in all shards: a push then wakes the
+    /// other shards too, so their consumers can steal it.
+    idle:     AtomicUsize,
+    /// EOS markers released by every shard so far.
+    released: AtomicU64,
+    eos:      Mutex<ShardedEos>,
+}
+
+/// The EOS markers of a sharded queue.
+#[derive(Default)]
+struct ShardedEos {
+    next_id:  u64,
+    /// Markers some shard has yet to release, by id: how many shards, the
+    /// group, and the marker's write-ahead log lsn.
+    pending:  HashMap<u64, (usize, Vec<u8>, Option<u64>)>,
+    /// Groups of released markers not yet passed on by every shard; the
+    /// first is the `first`-th ever released. `taken`: how many each shard
+    /// has passed on.
+    released: VecDeque<Vec<u8>>,
+    first:    u64,
+    taken:    Vec<u64>,
+}
+
+impl ShardedEos {
+    fn released_total(&self) -> u64 {
+        self.first + self.released.len() as u64
+    }
+
+    /// One more shard released marker `id`. Once all have, its notice is
+    /// out and its journal record (returned) can go.
+    fn release(&mut self, id: u64) -> Option<u64> {
+        let (left, ..) = self.pending.get_mut(&id)?;
+        *left -= 1;
+        if *left > 0 {
+            return None;
+        }
+        let (_, group, lsn) = self.pending.remove(&id)?;
+        self.released.push_back(group);
+        lsn
+    }
+
+    /// The groups released since `shard` last asked.
+    fn take(&mut self, shard: usize) -> Vec<Vec<u8>> {
+        let from = (self.taken[shard] - self.first) as usize;
+        let fresh = self.released.iter().skip(from).cloned().collect();
+        self.taken[shard] = self.released_total();
+        let low = self.taken.iter().copied().min().unwrap_or_default();
+        while self.first < low {
+            self.released.pop_front();
+            self.first += 1;
+        }
+        fresh
+    }
+}
+
+/// A shard's part of an EOS marker of its sharded queue: released once
+/// every frame the shard accepted before it has settled — those below
+/// `seq`, lent ones included, and `spilled_ahead` more still in its
+/// overflow (`seq` is set when the last of those comes back).
+struct ShardBarrier {
+    id:            u64,
+    seq:           u64,
+    spilled_ahead: usize,
+}
+
+/// Frames a shard lent to siblings that stole them: unsettled, and
+/// settled there but still journaled here. `keys`: the partition keys of
+/// the unsettled ones, by seq.
+#[derive(Default)]
+struct Lent {
+    out:  BTreeSet<u64>,
+    back: Vec<u64>,
+    keys: HashMap<u64, Vec<u8>>,
+}
+
+/// Frames a shard lends a sibling, with their audit stamps, and the
+/// lender's `Lent`.
+type Loan = (Vec<(Item, Option<Stamp>)>, Arc<Mutex<Lent>>);
+
+impl ShardLink {
+    fn shard(&self, i: usize) -> Option<Arc<Router>> {
+        self.shards.get()?.get(i)?.upgrade()
+    }
+
+    /// The shard frames under partition `key` go to.
+    fn for_key(&self, key: &[u8]) -> usize {
+        let n = self.shards.get().map_or(1, Vec::len);
+        let mut h = std::hash::DefaultHasher::new();
+        key.hash(&mut h);
+        (h.finish() % n as u64) as usize
+    }
+
+    /// Wake the consumers waiting in every shard but `except`.
+    fn wake(&self, except: usize) {
+        for (i, r) in self.shards.get().into_iter().flatten().enumerate() {
+            if i != except && let Some(r) = r.upgrade() {
+                r.wake_consumers();
+            }
+        }
+    }
+
+    /// Put an EOS marker, journaled as `lsn`, behind what every shard has
+    /// accepted so far.
+    fn push_eos(&self, group: Vec<u8>, lsn: Option<u64>) {
+        let shards: Vec<Arc<Router>> = self.shards.get().into_iter().flatten()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let id = {
+            let mut eos = self.eos.lock().unwrap();
+            let id = eos.next_id;
+            eos.next_id += 1;
+            eos.pending.insert(id, (shards.len(), group, lsn));
+            id
+        };
+        for r in shards {
+            r.park_eos(id);
+        }
+    }
+
+    /// Find work for an idle consumer of shard `thief`: let the other
+    /// shards release what EOS markers they can, then move up to half the
+    /// frames waiting in the busiest one that has any for `thief` over to
+    /// it. Returns true if anything changed that the consumer should look
+    /// at.
+    fn steal_for(&self, thief: usize) -> bool {
+        let Some(me) = self.shard(thief) else { return false };
+        let mut changed = false;
+        let mut others: Vec<(usize, Arc<Router>)> = Vec::new();
+        for (i, r) in self.shards.get().into_iter().flatten().enumerate() {
+            let Some(r) = r.upgrade().filter(|_| i != thief) else { continue };
+            let waiting = {
+                let mut g = r.inner.lock().unwrap();
+                if g.release_barriers() {
+                    r.wake_consumers();
+                    changed = true;
+                }
+                g.shared.len()
+            };
+            if waiting > 0 {
+                others.push((waiting, r));
+            }
+        }
+        others.sort_by_key(|(waiting, _)| std::cmp::Reverse(*waiting));
+        let (room, takers) = {
+            let g = me.inner.lock().unwrap();
+            (me.capacity().saturating_sub(g.total), g.takers())
+        };
+        for (waiting, victim) in others {
+            if let Some(loan) = victim.lend(waiting.div_ceil(2).min(room).min(STEAL_MAX), &takers) {
+                me.borrow(loan);
+                return true;
+            }
+        }
+        changed
+    }
+}
+
+/// The message ids (`Meta::message_id`) of the last `window` messages a
+/// queue accepted with one, to drop repeats of them.
+struct Dedup {
+    window: usize,
+    order:  VecDeque<Vec<u8>>,
+    /// By id: the chunked message that claimed it and the last chunk index
+    /// taken, or None for a single frame.
+    seen:   HashMap<Vec<u8>, Option<(MsgId, u32)>>,
+}
+
+impl Dedup {
+    fn new(window: usize) -> Self {
+        Self { window, order: VecDeque::new(), seen: HashMap::new() }
+    }
+
+    /// Whether `frame` repeats a message already accepted under `id`;
+    /// remembered if not. Producers send a message's chunks in order, so
+    /// later chunks of the message that claimed the id are let through.
+    fn repeats(&mut self, frame: &Frame, id: &[u8]) -> bool {
+        let chunk = match frame {
+            Frame::Chunk { id, idx, .. } => Some((*id, *idx)),
+            _ => None,
+        };
+        match (self.seen.get_mut(id), chunk) {
+            (Some(Some((msg, last))), Some((m, idx))) if *msg == m && idx > *last => {
+                *last = idx;
+                false
+            }
+            (Some(_), _) => true,
+            (None, _) => {
+                if self.order.len() >= self.window
+                    && let Some(old) = self.order.pop_front()
+                {
+                    self.seen.remove(&old);
+                }
+                self.order.push_back(id.to_vec());
+                self.seen.insert(id.to_vec(), chunk);
+                false
+            }
+        }
+    }
+
+    /// Un-claim `id`, so a message the queue refused can be resent under it.
+    fn forget(&mut self, id: &[u8]) {
+        if self.seen.remove(id).is_some() {
+            self.order.retain(|o| o != id);
+        }
+    }
+}
+
+/// Consumers parked in the reactor until their queue may have work for
+/// them (`Router::park_consumer`).
+#[derive(Default)]
+struct Wakers {
+    /// Counts `Router::wake_consumers` calls.
+    epoch:  u64,
+    parked: Vec<(ConsumerId, Box<dyn FnOnce() + Send>)>,
+}
+
+/// What `Router::poll_next` found for a consumer.
+enum Polled {
+    Frame(Frame, Box<Meta>),
+    /// The consumer was kicked.
+    Gone,
+    /// Nothing for it yet: it waits, as far as dispatch goes, until it
+    /// polls again. Park it with `epoch`, and have it look again `within`
+    /// this long regardless.
+    Wait { epoch: u64, within: Option<Duration> },
+    /// Nothing for it by the deadline `take` was given.
+    Empty,
+}
+
+struct Router {
+    inner:         Mutex<RouterInner>,
+    not_empty:     Condvar,
+    /// What `not_empty` is to blocked consumers, for parked ones. Locked
+    /// after `inner`, never before.
+    wakers:        Mutex<Wakers>,
+    not_full:      Condvar,
+    /// Frames the queue holds (see `set_capacity`).
+    capacity:      AtomicUsize,
+    next_consumer: AtomicU64,
+    /// Producer sessions admitted to the queue and not yet gone; counted
+    /// on the queue's own router, not its shards'.
+    producers:     AtomicUsize,
+    stats:         Arc<Stats>,
+    policy:        RetryPolicy,
+    /// Egress caps: each consumer handler paces itself with its own bucket
+    /// (`per_consumer`), and all of them share `egress` — a fan-out
+    /// queue's subscribers too.
+    per_consumer:  Option<u64>,
+    egress:        Option<Arc<Mutex<TokenBucket>>>,
+    tag_fallback:  TagFallback,
+    dispatch:      Dispatch,
+    /// Where dead-lettered frames go; written outside the router lock.
+    dead_letters:  Mutex<Option<Grave>>,
+    /// Where frames go while the queue is full, instead of blocking their
+    /// producers (QPIPE_OVERFLOW_DIR). Locked after `inner`, never before.
+    overflow:      Option<Mutex<Overflow>>,
+    /// Set for fan-out queues (QPIPE_FANOUT_QUEUES): the routers of their
+    /// consumers, each of which gets a copy of every frame. The queue's
+    /// own lanes stay empty.
+    subscribers:   Option<Mutex<Vec<Weak<Router>>>>,
+    /// A subscriber's fan-out queue, which takes its dead letters.
+    topic:         Option<Arc<Router>>,
+    /// Set for sharded queues (`--shards`): the routers that hold the
+    /// queue's frames. Like a fan-out queue's, its own lanes stay empty.
+    shards:        Option<Shards>,
+    /// A shard's set, and its index in it.
+    member:        Option<(Arc<ShardLink>, usize)>,
+    /// The queue's delivery policy (`--delivery`), if it has one; without,
+    /// each consumer picks its own.
+    delivery:      Option<DeliveryPolicy>,
+    /// Recently accepted message ids (`--dedup-window`), checked before a
+    /// producer's frame is pushed; locked on its own.
+    dedup:         Option<Mutex<Dedup>>,
+    /// What a push does when the queue is full (`--when-full`).
+    when_full:     FullPolicy,
+    /// Set for simulated routers: virtual clock and seeded tokens.
+    #[cfg(test)]
+    sim:           Option<Sim>,
+}
+
+impl Router {
+    #[cfg(test)]
+    fn new(capacity: usize, stats: Arc<Stats>) -> Self {
+        Self::with_policy(capacity, stats, RetryPolicy::default())
+    }
+
+    fn with_policy(capacity: usize, stats: Arc<Stats>, policy: RetryPolicy) -> Self {
+        Self {
+            inner: Mutex::new(RouterInner::default()),
+            not_empty: Condvar::new(),
+            wakers: Mutex::default(),
+            not_full:  Condvar::new(),
+            capacity: AtomicUsize::new(capacity),
+            next_consumer: AtomicU64::new(1),
+            producers: AtomicUsize::new(0),
+            stats,
+            policy,
+            tag_fallback: TagFallback::default(),
+            dispatch: Dispatch::default(),
+            per_consumer: None,
+            egress: None,
+            dead_letters: Mutex::new(None),
+            overflow: None,
+            subscribers: None,
+            topic: None,
+            shards: None,
+            member: None,
+            delivery: None,
+            dedup: None,
+            when_full: FullPolicy::Block,
+            #[cfg(test)]
+            sim: None,
+        }
+    }
+
+    /// A router on a virtual clock whose randomness all derives from `seed`.
+    #[cfg(test)]
+    fn simulated(capacity: usize, policy: RetryPolicy, seed: u64) -> Self {
+        Self {
+            sim: Some(Sim::new(seed)),
+            ..Self::with_policy(capacity, Arc::new(Stats::default()), policy)
+        }
+    }
+
+    /// The router's idea of the current time: the system clock, or the
+    /// simulation's virtual one.
+    fn now(&self) -> Instant {
+        #[cfg(test)]
+        if let Some(sim) = &self.sim {
+            return sim.now();
+        }
+        Instant::now()
+    }
+
+    /// A fresh data-port session token: from the OS RNG, or from the
+    /// simulation's seed.
+    fn session_token(&self) -> io::Result<[u8; TOKEN_LEN]> {
+        let mut token = [0u8; TOKEN_LEN];
+        #[cfg(test)]
+        if let Some(sim) = &self.sim {
+            for c in token.chunks_mut(8) {
+                c.copy_from_slice(&sim.next_u64().to_be_bytes()[..c.len()]);
+            }
+            return Ok(token);
+        }
+        SysRng.try_fill_bytes(&mut token).map_err(io::Error::other)?;
+        Ok(token)
+    }
+
+    fn with_egress_limit(mut self, limit: EgressLimit) -> Self {
+        self.per_consumer = limit.per_consumer;
+        self.egress = limit.queue.map(|r| Arc::new(Mutex::new(TokenBucket::new(r, self.now()))));
+        self
+    }
+
+    /// Wait until `bytes` more may go out on the queue-wide egress cap.
+    fn pace_egress(&self, bytes: u64) {
+        if let Some(bucket) = &self.egress {
+            let wait = bucket.lock().unwrap().reserve(bytes, self.now());
+            thread::sleep(wait);
+        }
+    }
+
+    fn with_delivery(mut self, delivery: Option<DeliveryPolicy>) -> Self {
+        self.delivery = delivery;
+        self
+    }
+
+    fn with_when_full(mut self, policy: FullPolicy) -> Self {
+        self.when_full = policy;
+        self
+    }
+
+    /// Drop producers' repeats of the last `window` message ids; 0 turns
+    /// deduplication off.
+    fn with_dedup(mut self, window: usize) -> Self {
+        self.dedup = (window > 0).then(|| Mutex::new(Dedup::new(window)));
+        self
+    }
+
+    /// Whether `frame` repeats a message the queue accepted recently under
+    /// the same id, counted as a duplicate if so.
+    fn repeats(&self, frame: &Frame, meta: &Meta) -> bool {
+        let (Some(dedup), Some(id)) = (&self.dedup, &meta.message_id) else {
+            return false;
+        };
+        if !matches!(frame, Frame::Msg(_) | Frame::Chunk { .. }) {
+            return false;
+        }
+        let repeat = dedup.lock().unwrap().repeats(frame, id);
+        if repeat {
+            self.stats.duplicate_msgs.fetch_add(1, Ordering::Relaxed);
+        }
+        repeat
+    }
+
+    /// Drop the id `repeats` just remembered for a message the queue then
+    /// refused: it was never accepted, and the producer will retry it.
+    fn forget(&self, id: &[u8]) {
+        if let Some(dedup) = &self.dedup {
+            dedup.lock().unwrap().forget(id);
+        }
+    }
+
+    /// Make this a fan-out queue (see `subscribers`).
+    fn with_fanout(mut self) -> Self {
+        self.subscribers = Some(Mutex::new(Vec::new()));
+        self
+    }
+
+    fn is_fanout(&self) -> bool {
+        self.subscribers.is_some()
+    }
+
+    /// A fan-out queue's router for one more consumer, with the queue's
+    /// settings and the frames pushed from now on.
+    fn subscribe(self: &Arc<Self>) -> Arc<Router> {
+        let mut sub = Router::with_policy(self.capacity(), self.stats.clone(), self.policy.clone())
+            .with_tag_fallback(self.tag_fallback)
+            .with_dispatch(self.dispatch);
+        sub.per_consumer = self.per_consumer;
+        sub.egress = self.egress.clone();
+        sub.when_full = self.when_full;
+        sub.topic = Some(self.clone());
+        {
+            let g = self.inner.lock().unwrap();
+            let s = sub.inner.get_mut().unwrap();
+            s.shared.aging = g.shared.aging;
+            s.audit = g.audit.clone();
+            #[cfg(feature = "otel")]
+            {
+                s.tracer = g.tracer.clone();
+            }
+        }
+        let sub = Arc::new(sub);
+        if let Some(subs) = &self.subscribers {
+            subs.lock().unwrap().push(Arc::downgrade(&sub));
+        }
+        sub
+    }
+
+    /// A fan-out queue's subscribers still connected.
+    fn subscribers(&self) -> Vec<Arc<Router>> {
+        let Some(subs) = &self.subscribers else { return Vec::new() };
+        let mut subs = subs.lock().unwrap();
+        subs.retain(|s| s.strong_count() > 0);
+        subs.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /// A fan-out queue's push: a copy to every subscriber, waiting for room
+    /// in each. With nobody subscribed, data frames are dropped.
+    fn broadcast(&self, frame: Frame, meta: Meta, stamp: Option<Stamp>) {
+        {
+            let mut g = self.inner.lock().unwrap();
+            while g.intake != Intake::Open {
+                g = self.not_full.wait(g).unwrap();
+            }
+        }
+        let subs = self.subscribers();
+        if subs.is_empty() && !matches!(frame, Frame::Eos(_)) {
+            debug!("no subscribers; dropping a frame");
+            self.stats.dropped_msgs.fetch_add(1, Ordering::Relaxed);
+            self.stats.dropped_bytes.fetch_add(frame.payload_len() as u64, Ordering::Relaxed);
+            return;
+        }
+        // A subscriber whose filter doesn't pass a message never sees it;
+        // EOS notices go to all.
+        let eos = matches!(frame, Frame::Eos(_));
+        // A full reject-newest subscriber misses the copy; the others
+        // have it, so the producer isn't told.
+        for sub in subs.into_iter().filter(|sub| eos || sub.wants(&meta)) {
+            sub.push_stamped(frame.clone(), meta.clone(), stamp.clone(), true);
+        }
+    }
+
+    /// Make this a sharded queue over `members`, routers built with its
+    /// settings (see `shards`). They share its queue-wide egress cap.
+    fn with_shards(mut self, members: Vec<Router>) -> Self {
+        let link = Arc::new(ShardLink {
+            shards:   OnceLock::new(),
+            idle:     AtomicUsize::new(0),
+            released: AtomicU64::new(0),
+            eos:      Mutex::new(ShardedEos { taken: vec![0; members.len()], ..ShardedEos::default() }),
+        });
+        let routers: Vec<Arc<Router>> = members.into_iter().enumerate().map(|(i, mut r)| {
+            r.egress = self.egress.clone();
+            r.member = Some((link.clone(), i));
+            let g = r.inner.get_mut().unwrap();
+            g.member = Some((link.clone(), i));
+            g.lent = Some(Arc::default());
+            Arc::new(r)
+        }).collect();
+        link.shards.set(routers.iter().map(Arc::downgrade).collect()).ok();
+        self.shards = Some(Shards { routers, next: AtomicUsize::new(0), link });
+        self
+    }
+
+    /// A sharded queue's shards; none for other queues.
+    fn shard_routers(&self) -> Vec<Arc<Router>> {
+        self.shards.as_ref().map(|s| s.routers.clone()).unwrap_or_default()
+    }
+
+    /// The router a new producer session pushes to: for a sharded queue,
+    /// the next shard in turn.
+    fn for_producer(self: &Arc<Self>) -> Arc<Router> {
+        match &self.shards {
+            Some(s) => s.routers[s.next.fetch_add(1, Ordering::Relaxed) % s.routers.len()].clone(),
+            None => self.clone(),
+        }
+    }
+
+    /// The router a new consumer session takes from: for a sharded queue,
+    /// the shard with the fewest consumers.
+    fn for_consumer(self: &Arc<Self>) -> Arc<Router> {
+        let Some(s) = &self.shards else { return self.clone() };
+        s.routers.iter()
+            .min_by_key(|r| r.inner.lock().unwrap().held.len())
+            .expect("a sharded queue has shards")
+            .clone()
+    }
+
+    /// Park this shard's part of sharded EOS marker `id` (`ShardBarrier`)
+    /// behind everything it has accepted, waiting out a pause like a push.
+    fn park_eos(&self, id: u64) {
+        let mut g = self.inner.lock().unwrap();
+        while g.intake != Intake::Open {
+            g = self.not_full.wait(g).unwrap();
+        }
+        let barrier = ShardBarrier { id, seq: g.next_seq, spilled_ahead: g.spilled.len() };
+        g.shard_barriers.push_back(barrier);
+        if g.release_barriers() {
+            self.wake_consumers();
+        }
+    }
+
+    /// Give a sibling shard, whose consumers are `takers`, up to `max`
+    /// waiting frames in whole messages, the first one whatever its size.
+    /// Messages any consumer may take go any time. Tagged or
+    /// resource-hinted ones go if no consumer here can take them and one
+    /// there can — or, with no consumer here at all, unless the tag
+    /// fallback says to wait for one. With no consumer here, keyed
+    /// messages go too, unless their key is pinned or already lent, and
+    /// chunked ones once all their chunks are in. Until they settle there,
+    /// they stay outstanding (and journaled) here as lent, and their keys
+    /// with the borrower.
+    fn lend(&self, max: usize, takers: &[Taker]) -> Option<Loan> {
+        let mut g = self.inner.lock().unwrap();
+        let lent = g.lent.clone()?;
+        let (seqs, ids) = {
+            let g = &*g;
+            let live = g.takers();
+            let nobody = live.is_empty();
+            let l = lent.lock().unwrap();
+            let lent_keys: HashSet<&[u8]> = l.keys.values().map(Vec::as_slice).collect();
+            let lendable = |meta: &Meta| {
+                if let Some(key) = &meta.partition
+                    && (!nobody || g.key_owner(key).is_some() || lent_keys.contains(key.as_slice()))
+                {
+                    return false;
+                }
+                if meta.requires.is_empty() && Resources::demand(meta) == Resources::default() {
+                    return true;
+                }
+                !live.iter().any(|t| t.can_take(meta))
+                    && (takers.iter().any(|t| t.can_take(meta))
+                        || nobody && self.tag_fallback != TagFallback::Wait)
+            };
+            // Only whole messages: chunked ones with every chunk here, and
+            // none handed out.
+            let mut chunks: HashMap<MsgId, u32> = HashMap::new();
+            if nobody {
+                for it in g.shared.iter() {
+                    if let Frame::Chunk { id, .. } = it.frame {
+                        *chunks.entry(id).or_default() += 1;
+                    }
+                }
+            }
+            let (mut seqs, mut ids, mut seen) = (HashSet::new(), HashSet::new(), HashSet::new());
+            let mut n = 0;
+            for it in g.shared.iter() {
+                let frames = match &it.frame {
+                    Frame::Msg(_) => 1,
+                    Frame::Chunk { id, count, .. } if seen.insert(*id) => {
+                        let whole = chunks.get(id) == Some(count)
+                            && !g.assign.contains_key(id)
+                            && !g.tomb.contains_key(id);
+                        if !whole {
+                            continue;
+                        }
+                        *count as usize
+                    }
+                    _ => continue,
+                };
+                if n > 0 && n + frames > max {
+                    break;
+                }
+                if !lendable(&it.meta) {
+                    continue;
+                }
+                match it.frame {
+                    Frame::Chunk { id, .. } => ids.insert(id),
+                    _ => seqs.insert(it.seq),
+                };
+                n += frames;
+            }
+            (seqs, ids)
+        };
+        if max == 0 || seqs.is_empty() && ids.is_empty() {
+            return None;
+        }
+        let items = g.shared.take_where(|it| match it.frame {
+            Frame::Chunk { id, .. } => ids.contains(&id),
+            _ => seqs.contains(&it.seq),
+        });
+        g.total -= items.len();
+        let mut l = lent.lock().unwrap();
+        let items = items.into_iter().map(|it| {
+            g.outstanding.remove(&it.seq);
+            l.out.insert(it.seq);
+            if let Some(key) = &it.meta.partition {
+                l.keys.insert(it.seq, key.clone());
+            }
+            let stamp = g.stamps.remove(&it.seq);
+            (it, stamp)
+        }).collect();
+        drop(l);
+        self.not_full.notify_all();
+        Some((items, lent))
+    }
+
+    /// Queue frames a sibling shard lent (see `lend`).
+    fn borrow(&self, (items, lent): Loan) {
+        let mut g = self.inner.lock().unwrap();
+        for (it, stamp) in items {
+            let seq = g.next_seq;
+            g.next_seq += 1;
+            g.outstanding.insert(seq);
+            if let Some(stamp) = stamp {
+                g.stamps.insert(seq, stamp);
+            }
+            g.loans.insert(seq, (lent.clone(), it.seq));
+            g.shared.push_back(Item { seq, ..it });
+            g.total += 1;
+        }
+        self.wake_consumers();
+    }
+
+    fn with_overflow(mut self, overflow: Option<Overflow>) -> Self {
+        self.overflow = overflow.map(Mutex::new);
+        self
+    }
+
+    /// Journal to `wal` from now on, after queueing the frames replayed
+    /// from it as if just pushed — in memory while there is room, then to
+    /// the overflow, then past capacity, since they were accepted before.
+    /// A sharded queue's shards share one log, and the frames are dealt
+    /// out among them: a partition key's to its shard, a message's chunks
+    /// together.
+    fn recover(&self, wal: Wal, frames: Vec<Replayed>) {
+        let wal = Arc::new(Mutex::new(wal));
+        let Some(shards) = &self.shards else {
+            self.inner.lock().unwrap().wal = Some(wal);
+            for (lsn, frame, meta) in frames {
+                self.replay(lsn, frame, meta);
+            }
+            return;
+        };
+        for r in &shards.routers {
+            r.inner.lock().unwrap().wal = Some(wal.clone());
+        }
+        let n = shards.routers.len();
+        for (lsn, frame, meta) in frames {
+            let shard = match (&frame, &meta.partition) {
+                (Frame::Eos(group), _) => {
+                    shards.link.push_eos(group.clone(), Some(lsn));
+                    continue;
+                }
+                (_, Some(key)) => shards.link.for_key(key),
+                // A message's chunks must stay together.
+                (Frame::Chunk { id, .. }, None) => (*id % n as u128) as usize,
+                _ => shards.next.fetch_add(1, Ordering::Relaxed) % n,
+            };
+            shards.routers[shard].replay(lsn, frame, meta);
+        }
+    }
+
+    /// Queue a frame replayed from the write-ahead log as `lsn`.
+    fn replay(&self, lsn: u64, frame: Frame, meta: Meta) {
+        let mut g = self.inner.lock().unwrap();
+        let now = self.now();
+        if (g.total >= self.capacity() || !g.spilled.is_empty())
+            && let Some(ov) = &self.overflow
+        {
+            match ov.lock().unwrap().push(&frame, &meta) {
+                Ok(()) => {
+                    g.spilled.push_back((now, None, Some(lsn)));
+                    return;
+                }
+                Err(e) => error!("overflow write failed, replaying into memory: {}", e),
+            }
+        }
+        self.admit(&mut g, frame, meta, None, now, Some(lsn));
+        self.wake_consumers();
+    }
+
+    /// The write-ahead log the queue journals to, if any.
+    fn wal(&self) -> Option<Arc<Mutex<Wal>>> {
+        let r = self.shards.as_ref().map_or(self, |s| &*s.routers[0]);
+        r.inner.lock().unwrap().wal.clone()
+    }
+
+    /// Whether accepted frames go to a write-ahead log.
+    fn journaling(&self) -> bool {
+        self.inner.lock().unwrap().wal.is_some()
+    }
+
+    fn with_tag_fallback(mut self, fallback: TagFallback) -> Self {
+        self.tag_fallback = fallback;
+        self
+    }
+
+    fn with_dispatch(mut self, dispatch: Dispatch) -> Self {
+        self.dispatch = dispatch;
+        self
+    }
+
+    /// Enable priority aging: a waiting frame gains one priority level per
+    /// `step`. `None` (the default) serves strictly by priority.
+    fn with_priority_aging(mut self, step: Option<Duration>) -> Self {
+        self.inner.get_mut().unwrap().shared.aging = step;
+        self
+    }
+
+    fn set_dead_letter_sink(&self, sink: Box<dyn Write + Send>) {
+        *self.dead_letters.lock().unwrap() = Some(Grave::Sink(sink));
+    }
+
+    /// Dead-letter onto the router in `queue`, once it is set.
+    fn set_dead_letter_queue(&self, queue: Arc<OnceLock<Arc<Router>>>) {
+        *self.dead_letters.lock().unwrap() = Some(Grave::Queue(queue));
+    }
+
+    /// Start auditing: every data frame accepted from now on gets one line
+    /// in `sink` once it settles. A writer thread owns the sink and flushes
+    /// whenever it catches up.
+    fn set_audit_sink(&self, sink: Box<dyn Write + Send>) {
+        let tx = spawn_line_writer("qpipe-audit", "audit log", sink);
+        self.inner.lock().unwrap().audit = Some(tx);
+    }
+
+    /// Record spans of the traced messages through this queue, named
+    /// `queue`, as lines to `lines` (see `otel`).
+    #[cfg(feature = "otel")]
+    fn set_tracer(&self, lines: mpsc::Sender<String>, queue: &str) {
+        self.inner.lock().unwrap().tracer = Some(Tracer { lines, queue: queue.into() });
+    }
+
+    #[cfg(feature = "otel")]
+    fn tracer(&self) -> Option<Tracer> {
+        self.inner.lock().unwrap().tracer.clone()
+    }
+
+    /// True when data frames should be stamped for the audit log.
+    fn auditing(&self) -> bool {
+        self.inner.lock().unwrap().audit.is_some()
+    }
+
+    /// Name consumer `me` by its peer address in the audit log.
+    fn set_peer(&self, me: ConsumerId, peer: String) {
+        self.inner.lock().unwrap().peers.insert(me, peer);
+    }
+
+    /// Frames queued, in memory or in the overflow.
+    fn depth(&self) -> usize {
+        let g = self.inner.lock().unwrap();
+        g.total + g.spilled.len()
+    }
+
+    /// Data frames accepted but not yet delivered or dropped — queued,
+    /// parked in a directed queue, or written and awaiting the consumer's
+    /// ACK, or (ack mode) awaiting the consumer's ack. "Enqueued minus
+    /// acked." Everything in the overflow counts too.
+    fn outstanding(&self) -> usize {
+        let g = self.inner.lock().unwrap();
+        g.outstanding.len() + g.spilled.len()
+    }
+
+    /// True when nothing is outstanding and no producer is attached to
+    /// this queue — the campaign-complete condition behind ROLE_WAIT_IDLE.
+    fn is_idle(&self) -> bool {
+        self.outstanding() == 0 && self.producers.load(Ordering::Relaxed) == 0
+    }
+
+    /// Frames waiting for a consumer, and how long the longest-waiting one
+    /// has waited: the pressure autoscaling reacts to.
+    fn backlog(&self) -> (usize, Duration) {
+        let now = self.now();
+        let g = self.inner.lock().unwrap();
+        let oldest = g.shared.oldest().into_iter().chain(g.spilled.front().map(|(t, ..)| *t)).min();
+        let wait = oldest.map_or(Duration::ZERO, |t| now.saturating_duration_since(t));
+        (g.shared.len() + g.spilled.len(), wait)
+    }
+
+    /// (frames in flight, live multi-frame assignments, tombstones)
+    fn gauges(&self) -> (usize, usize, usize) {
+        let g = self.inner.lock().unwrap();
+        (g.total, g.assign.len(), g.tomb.len())
+    }
+
+    fn register_consumer(&self) -> ConsumerId {
+        self.register(false)
+    }
+
+    fn register_ack_consumer(&self) -> ConsumerId {
+        self.register(true)
+    }
+
+    /// Let ack-mode consumer `me` take at most `window` unacked deliveries
+    /// at once; heavier consumers thereby get proportionally more work. A
+    /// window of 0 pauses new deliveries to `me`.
+    fn set_window(&self, me: ConsumerId, window: u32) {
+        self.inner.lock().unwrap().windows.insert(me, window);
+        // The window may have grown, so `me` may have work now.
+        self.wake_consumers();
+    }
+
+    /// Give ack-mode consumer `me` a resource budget: it is only handed
+    /// hinted messages that fit what its unacked work leaves free.
+    fn set_resources(&self, me: ConsumerId, total: Resources) {
+        let budget = Budget { total, used: Resources::default() };
+        self.inner.lock().unwrap().budgets.insert(me, budget);
+        self.wake_consumers();
+    }
+
+    fn register(&self, ack_mode: bool) -> ConsumerId {
+        let id = self.next_consumer.fetch_add(1, Ordering::Relaxed);
+        let mut g = self.inner.lock().unwrap();
+        g.directed.insert(id, VecDeque::new());
+        g.held.insert(id, VecDeque::new());
+        g.notices.insert(id, VecDeque::new());
+        if ack_mode {
+            g.ack_mode.insert(id);
+        }
+        id
+    }
+
+    /// Record the capability tags consumer `me` advertised at handshake.
+    fn set_capabilities(&self, me: ConsumerId, caps: BTreeSet<String>) {
+        if caps.is_empty() {
+            return;
+        }
+        self.inner.lock().unwrap().caps.insert(me, caps);
+        self.wake_consumers(); // it may unblock waiting tagged work
+    }
+
+    /// Record the subscription filter consumer `me` set at handshake.
+    fn set_filter(&self, me: ConsumerId, filter: Filter) {
+        self.inner.lock().unwrap().filters.insert(me, filter);
+        self.wake_consumers(); // it may unblock waiting matches
+    }
+
+    /// Would any consumer here take a message with `meta`, as far as
+    /// filters go? Yes while nobody is connected yet.
+    fn wants(&self, meta: &Meta) -> bool {
+        let g = self.inner.lock().unwrap();
+        g.held.is_empty() || g.held.keys().any(|c| g.filters.get(c).is_none_or(|f| f.matches(&meta.headers)))
+    }
+
+    /// May `me` take `it`? Plain items and chunks of already claimed
+    /// messages go to anyone (`classify` routes the latter to their owner).
+    /// A consumer is capable of a tagged or resource-hinted item if it
+    /// advertises every required tag and — if it advertised capacity at
+    /// all — the hints fit its total capacity. A capable consumer takes the
+    /// item once the hints fit its remaining capacity; first fit in queue
+    /// order packs each consumer as full as it will go. If no registered
+    /// consumer is capable, the fallback decides.
+    ///
+    /// Subscription filters come first: a filtered consumer only takes what
+    /// its filter passes, and an unfiltered one leaves to the others what
+    /// any of their filters pass. What no filter passes waits for an
+    /// unfiltered consumer. EOS notices pass every filter.
+    fn route(&self, g: &RouterInner, me: ConsumerId, it: &Item) -> Route {
+        if matches!(&it.frame, Frame::Chunk { id, .. } if g.assign.contains_key(id)) {
+            return Route::Take;
+        }
+        if !matches!(it.frame, Frame::Eos(_)) {
+            let headers = &it.meta.headers;
+            match g.filters.get(&me) {
+                Some(f) if !f.matches(headers) => return Route::Skip,
+                None if g.filters.values().any(|f| f.matches(headers)) => return Route::Skip,
+                _ => {}
+            }
+        }
+        if let Some(key) = &it.meta.partition
+            && (g.key_owner(key) != Some(me) || g.key_lent(key))
+        {
+            return Route::Skip;
+        }
+        let req = &it.meta.requires;
+        let demand = Resources::demand(&it.meta);
+        if req.is_empty() && demand == Resources::default() {
+            return Route::Take;
+        }
+        let capable = |c: &ConsumerId| {
+            req.iter().all(|t| g.caps.get(c).is_some_and(|have| have.contains(t)))
+                && g.budgets.get(c).is_none_or(|b| demand.fits_in(b.total))
+        };
+        if capable(&me) {
+            let room = g.budgets.get(&me).map(|b| b.total.minus(b.used));
+            return match room {
+                Some(room) if !demand.fits_in(room) => Route::Skip,
+                _ => Route::Take,
+            };
+        }
+        if g.held.keys().any(capable) {
+            return Route::Skip;
+        }
+        match self.tag_fallback {
+            TagFallback::Wait       => Route::Skip,
+            TagFallback::Any        => Route::Take,
+            TagFallback::DeadLetter => Route::Dead,
+        }
+    }
+
+    /// Pop the next shared item `me` may take (or must dead-letter), and
+    /// isn't better left to a waiting consumer (see `defer`).
+    fn pop_shared(&self, g: &mut RouterInner, me: ConsumerId) -> Option<(Item, Route)> {
+        // Lift the lanes out so `route` can look at the rest of the state.
+        let mut shared = std::mem::take(&mut g.shared);
+        let mut deferred = false;
+        let it = shared.pop_where(self.now(), |it| match self.route(g, me, it) {
+            Route::Skip => false,
+            Route::Take if self.defer(g, me, it) => {
+                deferred = true;
+                false
+            }
+            _ => true,
+        });
+        g.shared = shared;
+        if deferred {
+            self.wake_consumers();
+        }
+        let it = it?;
+        let route = self.route(g, me, &it);
+        if self.dispatch != Dispatch::Free && route == Route::Take {
+            g.turn += 1;
+            let turn = g.turn;
+            g.turns.insert(me, turn);
+            // Whoever deferred to `me` may be next in line for the rest.
+            if !g.waiting.is_empty() {
+                self.wake_consumers();
+            }
+        }
+        Some((it, route))
+    }
+
+    /// Dispatch order (lowest first): unsettled deliveries when that
+    /// counts, then the turn of the last item taken.
+    fn rank(&self, g: &RouterInner, c: ConsumerId) -> (usize, u64, ConsumerId) {
+        let load = match self.dispatch {
+            Dispatch::LeastOutstanding if g.ack_mode.contains(&c) => {
+                g.inflight.get(&c).copied().unwrap_or(0) as usize
+            }
+            Dispatch::LeastOutstanding => g.held.get(&c).map_or(0, VecDeque::len),
+            _ => 0,
+        };
+        (load, g.turns.get(&c).copied().unwrap_or(0), c)
+    }
+
+    /// Whether a waiting consumer that ranks ahead of `me` could take `it`
+    /// right now, so `me` should leave it to them.
+    fn defer(&self, g: &RouterInner, me: ConsumerId, it: &Item) -> bool {
+        if self.dispatch == Dispatch::Free || g.waiting.is_empty() {
+            return false;
+        }
+        let mine = self.rank(g, me);
+        g.waiting.iter().any(|&c| {
+            c != me
+                && !Self::window_full(g, c)
+                && self.rank(g, c) < mine
+                && self.route(g, c, it) == Route::Take
+        })
+    }
+
+    /// True when `me` may not start another delivery until one is acked.
+    fn window_full(g: &RouterInner, me: ConsumerId) -> bool {
+        g.windows.get(&me).is_some_and(|w| {
+            g.inflight.get(&me).copied().unwrap_or(0) >= *w
+        })
+    }
+
+    /// With a full window, `me` can still finish messages it has claimed:
+    /// fetch one of their chunks from wherever it sits in the shared queue.
+    /// Without this a half-delivered message could never be completed —
+    /// and therefore never acked — while new work sits ahead of its chunks.
+    fn take_owned_chunk(g: &mut RouterInner, me: ConsumerId, now: Instant) -> Option<Item> {
+        let RouterInner { shared, assign, .. } = g;
+        if !assign.values().any(|a| a.owner == me) {
+            return None;
+        }
+        shared.pop_where(now, |it| matches!(
+            &it.frame,
+            Frame::Chunk { id, .. } if assign.get(id).is_some_and(|a| a.owner == me)
+        ))
+    }
+
+    /// There may be something new for consumers: wake those blocked in
+    /// `take_next` and those parked with `park_consumer`.
+    fn wake_consumers(&self) {
+        self.not_empty.notify_all();
+        let parked = {
+            let mut w = self.wakers.lock().unwrap();
+            w.epoch += 1;
+            std::mem::take(&mut w.parked)
+        };
+        for (_, wake) in parked {
+            wake();
+        }
+    }
+
+    /// Call `wake` once there may be something new for consumer `me`, which
+    /// `poll_next` left waiting at `epoch`: at once if there already is.
+    /// This replaces any earlier `wake` of `me`'s, left over from a wait
+    /// that ended otherwise.
+    fn park_consumer(&self, me: ConsumerId, epoch: u64, wake: impl FnOnce() + Send + 'static) {
+        let mut w = self.wakers.lock().unwrap();
+        if w.epoch != epoch {
+            drop(w);
+            return wake();
+        }
+        w.parked.retain(|(id, _)| *id != me);
+        w.parked.push((me, Box::new(wake)));
+    }
+
+    /// The consumer's connection is gone: make its blocked `next_for`
+    /// return None so the handler exits and unregisters promptly.
+    fn kick(&self, id: ConsumerId) {
+        let mut g = self.inner.lock().unwrap();
+        if g.held.contains_key(&id) { // still registered
+            g.gone.insert(id);
+            self.wake_consumers();
+        }
+    }
+
+    /// Kick every registered consumer. Frames they hold are still
+    /// delivered; their handlers return before taking another.
+    fn close_consumers(&self) {
+        let mut g = self.inner.lock().unwrap();
+        let ids: Vec<ConsumerId> = g.held.keys().copied().collect();
+        g.gone.extend(ids);
+        self.wake_consumers();
+    }
+
+    fn unregister_consumer(&self, id: ConsumerId) {
+        let mut g = self.inner.lock().unwrap();
+
+        // Unacked deliveries go back for another attempt (or to the dead
+        // letters). This also rescinds their claims, so the tombstoning
+        // below only ever hits plain-mode messages.
+        let mut tags: Vec<u64> = g.unacked.iter()
+            .filter(|(_, u)| u.consumer == id)
+            .map(|(t, _)| *t)
+            .collect();
+        tags.sort_unstable(); // requeue in delivery order, not hash order
+        let mut dead = Vec::new();
+        for tag in tags {
+            self.requeue_unacked(&mut g, tag, self.policy.max_retries, &mut dead);
+        }
+        g.ack_mode.remove(&id);
+        g.gone.remove(&id);
+        if g.parked.remove(&id) {
+            g.waiting.remove(&id);
+            if let Some((link, _)) = &self.member {
+                link.idle.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        self.wakers.lock().unwrap().parked.retain(|(c, _)| *c != id);
+        g.windows.remove(&id);
+        g.inflight.remove(&id);
+        g.caps.remove(&id);
+        g.filters.remove(&id);
+        g.budgets.remove(&id);
+        g.peers.remove(&id);
+        g.turns.remove(&id);
+        // Its partition keys move on (what it held is requeued above).
+        let keys: Vec<Vec<u8>> = g.pins.iter()
+            .filter(|(_, (c, _))| *c == id)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in keys {
+            for seq in g.pins.remove(&key).map(|(_, seqs)| seqs).unwrap_or_default() {
+                g.pinned.remove(&seq);
+            }
+        }
+
+        // Anything still held was written without a verdict (the handler
+        // left between a successful write and `delivered`): settle it so an
+        // EOS barrier can't wait on it forever.
+        for h in g.held.remove(&id).unwrap_or_default() {
+            if let Held::Frame { seq, .. } = h {
+                g.settle(seq);
+            }
+        }
+        g.notices.remove(&id);
+
+        // Doom every in-flight message this consumer still owns. A claim
+        // only survives to this point if at least one of its chunks was
+        // ACKed by the consumer (fail_delivery rescinds never-ACKed claims
+        // before the handler exits), and those chunks die with it.
+        let now = self.now();
+        let owned: Vec<MsgId> = g.assign.iter()
+            .filter(|(_, a)| a.owner == id)
+            .map(|(m, _)| *m)
+            .collect();
+        for m in owned {
+            g.assign.remove(&m);
+            g.tomb.insert(m, now);
+        }
+
+        // Frames parked in the directed queue: drop the doomed ones, requeue
+        // the rest — e.g. a chunk redirected here while the claim was being
+        // rescinded still belongs to a message another consumer can take
+        // whole, and dropping it would strand that message forever.
+        let leftovers = g.directed.remove(&id).unwrap_or_default();
+        for it in leftovers {
+            let doomed = matches!(
+                &it.frame, Frame::Chunk { id, .. } if g.tomb.contains_key(id)
+            );
+            if doomed {
+                g.total -= 1;
+                g.settle(it.seq);
+                self.stats.dropped_msgs.fetch_add(1, Ordering::Relaxed);
+                self.stats.dropped_bytes
+                    .fetch_add(it.frame.payload_len() as u64, Ordering::Relaxed);
+            } else {
+                g.shared.push_back(it); // already counted in `total`
+            }
+        }
+        // Always wake the others: besides requeued work, the departure may
+        // leave tagged items with no capable consumer, for the fallback.
+        g.release_barriers();
+        self.wake_consumers();
+        self.not_full.notify_all();
+        drop(g);
+        self.bury(dead);
+    }
+
+    /// Enqueue one frame from a producer. Blocks while the system is at
+    /// capacity (shared + directed combined). Returns false if the frame
+    /// belongs to a tombstoned message and was dropped instead (accounted
+    /// in the dropped counters).
+    #[cfg(test)]
+    fn push(&self, frame: Frame) -> bool {
+        self.push_with(frame, Meta::default())
+    }
+
+    /// `push` with the producer's frame metadata (priority, ...).
+    #[cfg(test)]
+    fn push_with(&self, frame: Frame, meta: Meta) -> bool {
+        self.push_stamped(frame, meta, None, false) == Pushed::Queued
+    }
+
+    /// `push_with`, keeping the frame's audit `stamp` until it settles. A
+    /// `refusable` frame — from a producer that takes `ACK_THROTTLED` — may
+    /// be turned away by a full `FullPolicy::RejectNewest` queue instead of
+    /// waiting.
+    fn push_stamped(&self, frame: Frame, meta: Meta, stamp: Option<Stamp>, refusable: bool) -> Pushed {
+        if self.is_fanout() {
+            self.broadcast(frame, meta, stamp);
+            return Pushed::Queued;
+        }
+        if let Some(shards) = &self.shards {
+            let shard = shards.next.fetch_add(1, Ordering::Relaxed) % shards.routers.len();
+            return shards.routers[shard].push_stamped(frame, meta, stamp, refusable);
+        }
+        if let Some((link, me)) = &self.member {
+            if let Frame::Eos(group) = &frame {
+                let lsn = self.inner.lock().unwrap().journal(&frame, &meta);
+                link.push_eos(group.clone(), lsn);
+                return Pushed::Queued;
+            }
+            let home = meta.partition.as_deref().map_or(*me, |key| link.for_key(key));
+            if home != *me && let Some(r) = link.shard(home) {
+                return r.push_stamped(frame, meta, stamp, refusable);
+            }
+        }
+        let mut g = self.inner.lock().unwrap();
+        loop {
+            if let Frame::Chunk { id, .. } = &frame {
+                let now = self.now();
+                if let Some(ts) = g.tomb.get_mut(id) {
+                    *ts = now; // keep tomb alive while stragglers trickle in
+                    self.stats.dropped_msgs.fetch_add(1, Ordering::Relaxed);
+                    self.stats.dropped_bytes
+                        .fetch_add(frame.payload_len() as u64, Ordering::Relaxed);
+                    return Pushed::Dropped;
+                }
+                // Refresh active assignments even when delivery is backed up,
+                // so a jammed queue doesn't expire an in-flight message.
+                if let Some(a) = g.assign.get_mut(id) {
+                    a.last_seen = now;
+                }
+            }
+            if g.intake != Intake::Open {
+                g = self.not_full.wait(g).unwrap();
+                continue;
+            }
+            if g.total < self.capacity() && g.spilled.is_empty() {
+                break;
+            }
+            // Full, or already spilling (so FIFO holds): to disk if we can.
+            if let Some(ov) = &self.overflow {
+                match ov.lock().unwrap().push(&frame, &meta) {
+                    Ok(()) => {
+                        let stamp = stamp.filter(|_| g.audit.is_some());
+                        let lsn = g.journal(&frame, &meta);
+                        g.spilled.push_back((self.now(), stamp, lsn));
+                        return Pushed::Queued;
+                    }
+                    Err(e) => error!("overflow write failed, producers wait instead: {}", e),
+                }
+            }
+            if g.total < self.capacity() {
+                break;
+            }
+            // Only a whole message is refused: the rest of one already
+            // let in waits for room.
+            let starts = matches!(frame, Frame::Msg(_) | Frame::Chunk { idx: 0, .. });
+            match self.when_full {
+                FullPolicy::RejectNewest if refusable && starts => {
+                    self.stats.shed_msgs.fetch_add(1, Ordering::Relaxed);
+                    return Pushed::Rejected;
+                }
+                FullPolicy::DropOldest if self.shed_oldest(&mut g) => {}
+                _ => g = self.not_full.wait(g).unwrap(),
+            }
+        }
+        let now = self.now();
+        let lsn = g.journal(&frame, &meta);
+        self.admit(&mut g, frame, meta, stamp, now, lsn);
+        // notify_all, not notify_one: a chunk of a claimed message can only
+        // be delivered by its owner, but any consumer might be the one that
+        // wakes first and redirects it there.
+        self.wake_consumers();
+        // Nobody here to take it: a consumer of another shard may steal it.
+        if let Some((link, me)) = &self.member
+            && g.waiting.is_empty()
+            && link.idle.load(Ordering::Relaxed) > 0
+        {
+            drop(g);
+            link.wake(*me);
+        }
+        Pushed::Queued
+    }
+
+    /// Make room in a full `FullPolicy::DropOldest` queue: drop the
+    /// longest-queued message no consumer has started, with all of its
+    /// queued chunks, counted as shed and audited as "shed". The message
+    /// is tombstoned, so its stragglers go too. False if there is nothing
+    /// to drop but EOS markers and started messages.
+    fn shed_oldest(&self, g: &mut RouterInner) -> bool {
+        let RouterInner { shared, assign, .. } = &mut *g;
+        let oldest = shared.iter()
+            .filter(|it| match &it.frame {
+                Frame::Chunk { id, .. } => !assign.contains_key(id),
+                Frame::Eos(_) => false,
+                _ => true,
+            })
+            .min_by_key(|it| it.seq);
+        let Some(oldest) = oldest else { return false };
+        let (seq, msg) = match oldest.frame {
+            Frame::Chunk { id, .. } => (oldest.seq, Some(id)),
+            _ => (oldest.seq, None),
+        };
+        let items = shared.take_where(|it| match &it.frame {
+            Frame::Chunk { id, .. } => Some(*id) == msg,
+            _ => it.seq == seq,
+        });
+        g.total -= items.len();
+        if let Some(id) = msg {
+            let now = self.now();
+            g.tomb.insert(id, now);
+        }
+        for it in items {
+            self.stats.shed_msgs.fetch_add(1, Ordering::Relaxed);
+            g.audit(it.seq, None, "shed");
+            g.settle(it.seq);
+        }
+        g.release_barriers();
+        true
+    }
+
+    /// `push_stamped` for dead letters from other queues, which must not
+    /// wait for room: their callers may be holding up a whole queue.
+    /// Returns false if there is no room in memory or on disk.
+    fn offer(&self, frame: Frame, meta: Meta) -> bool {
+        if self.is_fanout() {
+            // Every subscriber gets its chance, not just up to the first taker.
+            let taken = self.subscribers().iter()
+                .filter(|sub| sub.offer(frame.clone(), meta.clone()))
+                .count();
+            return taken > 0;
+        }
+        if let Some(shards) = &self.shards {
+            let shard = shards.next.fetch_add(1, Ordering::Relaxed) % shards.routers.len();
+            return shards.routers[shard].offer(frame, meta);
+        }
+        let mut g = self.inner.lock().unwrap();
+        if g.total >= self.capacity() || !g.spilled.is_empty() {
+            let Some(ov) = &self.overflow else { return false };
+            return match ov.lock().unwrap().push(&frame, &meta) {
+                Ok(()) => {
+                    let lsn = g.journal(&frame, &meta);
+                    g.spilled.push_back((self.now(), None, lsn));
+                    true
+                }
+                Err(e) => {
+                    error!("overflow write failed: {}", e);
+                    false
+                }
+            };
+        }
+        let now = self.now();
+        let lsn = g.journal(&frame, &meta);
+        self.admit(&mut g, frame, meta, None, now, lsn);
+        self.wake_consumers();
+        true
+    }
+
+    /// Enqueue a frame that has made it into memory, journaled as `lsn`.
+    fn admit(
+                &self,
+                g: &mut RouterInner,
+                frame: Frame,
+                meta: Meta,
+                stamp: Option<Stamp>,
+                enqueued: Instant,
+                lsn: Option<u64>,
+            ) {
+        let seq = g.next_seq;
+        g.next_seq += 1;
+        if let Some(lsn) = lsn {
+            g.journaled.insert(seq, lsn);
+        }
+        if !matches!(frame, Frame::Eos(_)) {
+            g.outstanding.insert(seq);
+            if let Some(mut stamp) = stamp.filter(|_| g.audit.is_some()) {
+                stamp.id = match &frame {
+                    Frame::Chunk { id, idx, .. } => format!("{id:032x}/{idx}"),
+                    _ => seq.to_string(),
+                };
+                g.stamps.insert(seq, stamp);
+            }
+        }
+        // A delayed message joins the queue when its time comes, and waits
+        // from then on as if it had been sent then.
+        match meta.delay.filter(|_| !matches!(frame, Frame::Eos(_))) {
+            Some(delay) => {
+                let ready = enqueued + delay.min(MAX_DELAY);
+                g.delayed.push(ready, Item { seq, attempts: 0, enqueued: ready, meta, frame });
+            }
+            None => g.shared.push_back(Item { seq, attempts: 0, enqueued, meta, frame }),
+        }
+        g.total += 1;
+    }
+
+    /// Move spilled frames back into memory as far as capacity allows.
+    /// The disk is read outside the router lock; producers keep spilling
+    /// behind these frames meanwhile, so order holds.
+    fn refill(&self) -> io::Result<usize> {
+        let Some(ov) = &self.overflow else { return Ok(0) };
+        let room = {
+            let g = self.inner.lock().unwrap();
+            self.capacity().saturating_sub(g.total).min(g.spilled.len())
+        };
+        if room == 0 {
+            return Ok(0);
+        }
+        let frames = ov.lock().unwrap().take(room)?;
+        let mut g = self.inner.lock().unwrap();
+        let n = frames.len();
+        for (frame, meta) in frames {
+            let (spilled_at, stamp, lsn) = g.spilled.pop_front().expect("spilled frames are counted");
+            self.admit(&mut g, frame, meta, stamp, spilled_at, lsn);
+            g.unspilled();
+        }
+        self.wake_consumers();
+        Ok(n)
+    }
+
+    /// With an overflow, run a thread that refills the queue whenever
+    /// consumers make room.
+    fn start_refill(self: &Arc<Self>) {
+        for shard in self.shard_routers() {
+            shard.start_refill();
+        }
+        if self.overflow.is_none() {
+            return;
+        }
+        let me = Arc::downgrade(self);
+        thread::Builder::new()
+            .name("qpipe-refill".into())
+            .spawn(move || loop {
+                let Some(r) = me.upgrade() else { return };
+                {
+                    let g = r.inner.lock().unwrap();
+                    if g.spilled.is_empty() || g.total >= r.capacity() {
+                        drop(r.not_full.wait_timeout(g, REFILL_POLL).unwrap());
+                    }
+                }
+                match r.refill() {
+                    // The wakeup may have been meant for a requeue; pass it on.
+                    Ok(n) if n > 0 => r.not_full.notify_one(),
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("overflow read failed: {}", e);
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            })
+            .expect("spawn overflow refill");
+    }
+
+    /// Blocks until a frame deliverable by consumer `me` is available.
+    /// Directed frames (chunks of messages `me` owns) take priority, then
+    /// released EOS notices; shared frames are claimed, redirected, or
+    /// dropped per the assignment map. An EOS marker popped from the shared
+    /// queue is parked as a barrier rather than delivered. Requeued items
+    /// rejoin the shared queue once their backoff has passed.
+    ///
+    /// Every returned frame is HELD until the caller reports the outcome via
+    /// `delivered` or `fail_delivery`. For ack-mode consumers the returned
+    /// metadata carries the delivery tag and attempt count. Returns None
+    /// once `me` has been kicked.
+    fn next_for(&self, me: ConsumerId) -> Option<(Frame, Meta)> {
+        self.take_next(me, None)
+    }
+
+    /// `next_for` that gives up after `wait`, returning None as it does
+    /// once `me` has been kicked; `is_gone` tells the two apart.
+    fn next_for_within(&self, me: ConsumerId, wait: Duration) -> Option<(Frame, Meta)> {
+        self.take_next(me, Some(self.now() + wait))
+    }
+
+    /// `next_for` that returns None instead of waiting when nothing is
+    /// deliverable to `me` right now; the simulation's scheduler.
+    #[cfg(test)]
+    fn try_next_for(&self, me: ConsumerId) -> Option<(Frame, Meta)> {
+        self.take_next(me, Some(self.now()))
+    }
+
+    /// `next_for` that never blocks: with nothing for `me` yet, it is left
+    /// waiting for `park_consumer` to wake it.
+    fn poll_next(&self, me: ConsumerId) -> Polled {
+        self.take(me, None, true)
+    }
+
+    /// Whether consumer `me` has been kicked.
+    fn is_gone(&self, me: ConsumerId) -> bool {
+        self.inner.lock().unwrap().gone.contains(&me)
+    }
+
+    fn take_next(&self, me: ConsumerId, deadline: Option<Instant>) -> Option<(Frame, Meta)> {
+        match self.take(me, deadline, false) {
+            Polled::Frame(frame, meta) => Some((frame, *meta)),
+            _ => None,
+        }
+    }
+
+    fn take(&self, me: ConsumerId, deadline: Option<Instant>, park: bool) -> Polled {
+        let mut g = self.inner.lock().unwrap();
+        // Back from the reactor, it no longer waits.
+        if g.parked.remove(&me) {
+            g.waiting.remove(&me);
+            if let Some((link, _)) = &self.member {
+                link.idle.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        loop {
+            if g.gone.contains(&me) {
+                return Polled::Gone;
+            }
+            let now = self.now();
+            let next_ready = Self::promote_delayed(&mut g, now);
+            // A shard's EOS markers may have moved elsewhere.
+            if self.member.is_some() && g.release_barriers() {
+                self.wake_consumers();
+            }
+
+            if let Some(group) = g.notices.get_mut(&me).and_then(|q| q.pop_front()) {
+                if let Some(h) = g.held.get_mut(&me) {
+                    h.push_back(Held::Notice);
+                }
+                return Polled::Frame(Frame::Eos(group), Box::default());
+            }
+
+            let full = Self::window_full(&g, me);
+            let directed = g.directed.get_mut(&me).and_then(|q| q.pop_front());
+            let next = match directed {
+                Some(it)     => Some((it, Route::Take)),
+                None if full => {
+                    Self::take_owned_chunk(&mut g, me, now).map(|it| (it, Route::Take))
+                }
+                None         => self.pop_shared(&mut g, me),
+            };
+            let Some((it, route)) = next else {
+                // A shard's consumer with nothing here for it helps out the
+                // others.
+                if let Some((link, i)) = &self.member
+                    && !full
+                {
+                    drop(g);
+                    let changed = link.steal_for(*i);
+                    g = self.inner.lock().unwrap();
+                    if changed {
+                        continue;
+                    }
+                }
+                if deadline.is_some_and(|d| now >= d) {
+                    return Polled::Empty;
+                }
+                g.waiting.insert(me);
+                // A shard's consumer looks for frames to steal now and then.
+                let link = self.member.as_ref().map(|(link, _)| link);
+                let poll = link.map(|link| {
+                    link.idle.fetch_add(1, Ordering::Relaxed);
+                    now + STEAL_POLL
+                });
+                let wake = next_ready.into_iter().chain(deadline).chain(poll).min();
+                if park {
+                    g.parked.insert(me);
+                    let epoch = self.wakers.lock().unwrap().epoch;
+                    return Polled::Wait { epoch, within: wake.map(|t| t.saturating_duration_since(now)) };
+                }
+                g = match wake {
+                    Some(t) => {
+                        let wait = t.saturating_duration_since(self.now());
+                        self.not_empty.wait_timeout(g, wait).unwrap().0
+                    }
+                    None => self.not_empty.wait(g).unwrap(),
+                };
+                if let Some(link) = link {
+                    link.idle.fetch_sub(1, Ordering::Relaxed);
+                }
+                g.waiting.remove(&me);
+                continue;
+            };
+
+            // Nobody took it in time.
+            if Self::stale(&g.assign, &it, now) {
+                self.expire(&mut g, &it, now);
+                self.not_full.notify_one();
+                if g.release_barriers() {
+                    self.wake_consumers();
+                }
+                continue;
+            }
+
+            if route == Route::Dead {
+                // Tagged for capabilities nobody has, and the fallback says
+                // not to wait for them.
+                g.total -= 1;
+                self.not_full.notify_one();
+                g.audit(it.seq, None, "dead-lettered");
+                g.settle(it.seq);
+                if g.release_barriers() {
+                    self.wake_consumers();
+                }
+                if !matches!(it.frame, Frame::Chunk { idx, .. } if idx > 0) {
+                    self.stats.dead_lettered_msgs.fetch_add(1, Ordering::Relaxed);
+                }
+                drop(g);
+                self.bury(vec![it]);
+                g = self.inner.lock().unwrap();
+                continue;
+            }
+
+            if let Frame::Eos(group) = it.frame {
+                g.total -= 1;
+                self.not_full.notify_one();
+                g.barriers.push_back((it.seq, group));
+                if g.release_barriers() {
+                    self.wake_consumers();
+                }
+                continue;
+            }
+
+            match Self::classify(&mut g, me, &it.frame, now) {
+                Disposition::Deliver => {
+                    g.total -= 1;
+                    self.not_full.notify_one();
+                    self.stats.dwell.record(now.saturating_duration_since(it.enqueued));
+                    #[cfg(feature = "otel")]
+                    g.trace_taken(me, &it, now.saturating_duration_since(it.enqueued));
+                    if let Some(key) = &it.meta.partition {
+                        g.pin(key, me, it.seq);
+                    }
+                    // Of the producer's metadata, only headers and the codec
+                    // are for the consumer; the rest steered routing.
+                    let out = Meta {
+                        headers: it.meta.headers.clone(), codec: it.meta.codec, ..Meta::default()
+                    };
+                    if !g.ack_mode.contains(&me) {
+                        if let Some(h) = g.held.get_mut(&me) {
+                            h.push_back(Held::Frame {
+                                seq:      it.seq,
+                                attempts: it.attempts,
+                                enqueued: it.enqueued,
+                                meta:     Box::new(it.meta),
+                            });
+                        }
+                        return Polled::Frame(it.frame, Box::new(out));
+                    }
+                    let tagged = Self::track_unacked(&mut g, me, it.clone());
+                    return Polled::Frame(it.frame, Box::new(Meta { headers: out.headers, ..tagged }));
+                }
+                Disposition::DropTombstoned => {
+                    g.total -= 1;
+                    g.settle(it.seq);
+                    if g.release_barriers() {
+                        self.wake_consumers();
+                    }
+                    self.not_full.notify_one();
+                    self.stats.dropped_msgs.fetch_add(1, Ordering::Relaxed);
+                    self.stats.dropped_bytes
+                        .fetch_add(it.frame.payload_len() as u64, Ordering::Relaxed);
+                }
+                Disposition::Redirect(owner) => {
+                    match g.directed.get_mut(&owner) {
+                        Some(q) => {
+                            // The frame stays in the system: `total` is
+                            // unchanged, so a redirect never blocks on
+                            // capacity (no deadlock path), and each frame
+                            // redirects at most once (shared -> directed).
+                            q.push_back(it);
+                            self.wake_consumers();
+                        }
+                        None => {
+                            // Owner vanished without tombstoning. Unreachable
+                            // (unregister tombstones under this same lock),
+                            // but drop defensively rather than leak.
+                            if let Frame::Chunk { id, .. } = &it.frame {
+                                let id = *id;
+                                g.assign.remove(&id);
+                                g.tomb.insert(id, now);
+                            }
+                            g.total -= 1;
+                            g.settle(it.seq);
+                            g.release_barriers();
+                            self.not_full.notify_one();
+                            self.stats.dropped_msgs
+                                .fetch_add(1, Ordering::Relaxed);
+                            self.stats.dropped_bytes.fetch_add(
+                                it.frame.payload_len() as u64, Ordering::Relaxed
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Test shorthand for `next_for` on a consumer that can't be kicked.
+    #[cfg(test)]
+    fn pop_for(&self, me: ConsumerId) -> Frame {
+        self.next_for(me).expect("consumer was kicked").0
+    }
+
+    /// Move delayed items that have come due into the shared queue: retry
+    /// backoffs that have passed, and delayed messages whose time has come.
+    /// Returns when the next one becomes ready, if any are still waiting.
+    fn promote_delayed(g: &mut RouterInner, now: Instant) -> Option<Instant> {
+        let due = g.delayed.pop_due(now);
+        g.shared.extend(due);
+        g.delayed.next_due()
+    }
+
+    /// Record an ack-mode hand-out of `it` to `me`: every chunk of one
+    /// delivery shares a tag, every new delivery gets a fresh one.
+    fn track_unacked(g: &mut RouterInner, me: ConsumerId, it: Item) -> Meta {
+        let msg = match &it.frame {
+            Frame::Chunk { id, .. } => Some(*id),
+            _ => None,
+        };
+        let known = msg.and_then(|id| g.msg_tags.get(&id).copied())
+            .filter(|t| g.unacked.contains_key(t));
+        let tag = match known {
+            Some(t) => t,
+            None => {
+                g.next_tag += 1;
+                let t = g.next_tag;
+                if let Some(id) = msg {
+                    g.msg_tags.insert(id, t);
+                }
+                let demand = Resources::demand(&it.meta);
+                g.unacked.insert(t, Unacked {
+                    consumer: me, msg, items: Vec::new(), deadline: None,
+                    demand, runtime: it.meta.runtime,
+                });
+                *g.inflight.entry(me).or_default() += 1;
+                if let Some(b) = g.budgets.get_mut(&me) {
+                    b.used = b.used.plus(demand);
+                }
+                t
+            }
+        };
+        let u = g.unacked.get_mut(&tag).expect("just ensured");
+        u.items.push(it);
+        let attempt = u.items.iter().map(|i| i.attempts).max().unwrap_or(0);
+        if let Some(h) = g.held.get_mut(&me) {
+            h.push_back(Held::Tagged(tag));
+        }
+        Meta { delivery: Some(tag), attempt: Some(attempt), ..Meta::default() }
+    }
+
+    /// The oldest frame held by `me` reached the consumer (its ACK arrived).
+    /// Plain deliveries settle here; ack-mode ones start (or, for later
+    /// chunks, restart) their visibility clock.
+    fn delivered(&self, me: ConsumerId) {
+        let mut g = self.inner.lock().unwrap();
+        match g.held.get_mut(&me).and_then(|h| h.pop_front()) {
+            Some(Held::Frame { seq, .. }) => {
+                g.audit(seq, Some(me), "delivered");
+                g.settle(seq);
+                if g.release_barriers() {
+                    self.wake_consumers();
+                }
+            }
+            Some(Held::Tagged(tag)) => {
+                if let Some(u) = g.unacked.get_mut(&tag) {
+                    // A message expected to run longer than the visibility
+                    // timeout gets its expected runtime instead.
+                    let allowed = self.policy.visibility_timeout
+                        .max(u.runtime.unwrap_or_default());
+                    u.deadline = Some(self.now() + allowed);
+                }
+            }
+            Some(Held::Notice) | None => {}
+        }
+    }
+
+    /// Consumer `me` acked delivery `tag`: the message is done. Returns
+    /// false for unknown tags — typically a delivery that already timed out
+    /// and was requeued, whose late ack must not settle the redelivery.
+    fn ack(&self, me: ConsumerId, tag: u64) -> bool {
+        let mut g = self.inner.lock().unwrap();
+        if g.unacked.get(&tag).is_none_or(|u| u.consumer != me) {
+            return false;
+        }
+        let u = g.unacked.remove(&tag).expect("checked above");
+        Self::release_slot(&mut g, &u);
+        if let Some(id) = u.msg
+            && g.msg_tags.get(&id) == Some(&tag)
+        {
+            g.msg_tags.remove(&id);
+        }
+        for it in &u.items {
+            g.audit(it.seq, Some(me), "delivered");
+            g.settle(it.seq);
+        }
+        g.release_barriers();
+        // A window slot freed up, so wake even if no barrier moved.
+        self.wake_consumers();
+        drop(g);
+        u.items.into_iter().for_each(|it| it.frame.recycle());
+        true
+    }
+
+    /// Consumer `me` nacked delivery `tag`: requeue it now rather than at
+    /// its visibility deadline, dead-lettering it past `retries`
+    /// redeliveries or the policy's count. Returns false for unknown tags,
+    /// like `ack`.
+    fn nack(&self, me: ConsumerId, tag: u64, retries: u32) -> bool {
+        let mut g = self.inner.lock().unwrap();
+        if g.unacked.get(&tag).is_none_or(|u| u.consumer != me) {
+            return false;
+        }
+        let mut dead = Vec::new();
+        self.requeue_unacked(&mut g, tag, retries.min(self.policy.max_retries), &mut dead);
+        g.release_barriers();
+        self.wake_consumers();
+        drop(g);
+        self.bury(dead);
+        true
+    }
+
+    /// A delivery is over: return its window slot and resource budget.
+    fn release_slot(g: &mut RouterInner, u: &Unacked) {
+        if let Some(n) = g.inflight.get_mut(&u.consumer) {
+            *n = n.saturating_sub(1);
+        }
+        if let Some(b) = g.budgets.get_mut(&u.consumer) {
+            b.used = b.used.minus(u.demand);
+        }
+    }
+
+    /// Whether queued `it` outlived its TTL (`Meta::ttl`) before a consumer
+    /// took it. A chunk of a message already claimed is past that point:
+    /// its consumer has the first part, so the rest goes through.
+    fn stale(assign: &HashMap<MsgId, Assign>, it: &Item, now: Instant) -> bool {
+        let Some(ttl) = it.meta.ttl else {
+            return false;
+        };
+        now.saturating_duration_since(it.enqueued) >= ttl && match &it.frame {
+            Frame::Msg(_) => true,
+            Frame::Chunk { id, .. } => !assign.contains_key(id),
+            Frame::Eos(_) | Frame::Ping | Frame::Bye => false,
+        }
+    }
+
+    /// Drop `it`, which was taken out of the queue `stale`. The first
+    /// chunk of a message to expire tombstones it, so the rest of its
+    /// chunks are dropped too. Returns whether a message expired (rather
+    /// than another chunk of one that already had).
+    fn expire(&self, g: &mut RouterInner, it: &Item, now: Instant) -> bool {
+        g.total -= 1;
+        g.audit(it.seq, None, "expired");
+        g.settle(it.seq);
+        let first = match &it.frame {
+            Frame::Chunk { id, .. } => g.tomb.insert(*id, now).is_none(),
+            _ => true,
+        };
+        if first {
+            self.stats.expired_msgs.fetch_add(1, Ordering::Relaxed);
+        }
+        first
+    }
+
+    /// Drop every queued message whose TTL ran out. `take_next` skips them
+    /// as they come up; this catches the ones stuck behind a paused queue,
+    /// waiting out a retry backoff, or meant for consumers that are busy or
+    /// gone. Messages still in the overflow are looked at once they are
+    /// read back. Returns how many messages expired.
+    fn expire_stale(&self) -> usize {
+        let mut g = self.inner.lock().unwrap();
+        let now = self.now();
+        let RouterInner { shared, delayed, assign, .. } = &mut *g;
+        let mut items = shared.take_where(|it| Self::stale(assign, it, now));
+        items.extend(delayed.take_where(|it| Self::stale(assign, it, now)));
+        if items.is_empty() {
+            return 0;
+        }
+        let expired = items.iter().filter(|it| self.expire(&mut g, it, now)).count();
+        g.release_barriers();
+        self.wake_consumers();
+        self.not_full.notify_all();
+        expired
+    }
+
+    /// Requeue every ack-mode delivery whose visibility deadline has passed
+    /// by `now`. Returns how many deliveries expired.
+    fn expire_unacked(&self, now: Instant) -> usize {
+        let mut g = self.inner.lock().unwrap();
+        let mut tags: Vec<u64> = g.unacked.iter()
+            .filter(|(_, u)| u.deadline.is_some_and(|d| d <= now))
+            .map(|(t, _)| *t)
+            .collect();
+        tags.sort_unstable();
+        let mut dead = Vec::new();
+        for &tag in &tags {
+            self.requeue_unacked(&mut g, tag, self.policy.max_retries, &mut dead);
+        }
+        // A shard may owe EOS notices to frames its siblings settled.
+        if g.release_barriers() || !tags.is_empty() {
+            self.wake_consumers();
+        }
+        drop(g);
+        self.bury(dead);
+        tags.len()
+    }
+
+    /// Take delivery `tag` back from its consumer. Its claim is rescinded
+    /// and any of its chunks parked for that consumer return to the shared
+    /// queue, so another consumer can take the whole message. The attempt
+    /// counter goes up; within `max_retries` the items wait out their
+    /// backoff in `delayed`, past it they are settled and moved to `dead`
+    /// (and a chunked message is tombstoned so stragglers are dropped).
+    fn requeue_unacked(
+                &self,
+                g: &mut RouterInner,
+                tag: u64,
+                max_retries: u32,
+                dead: &mut Vec<Item>,
+            ) {
+        let Some(u) = g.unacked.remove(&tag) else { return };
+        Self::release_slot(g, &u);
+        let now = self.now();
+        if let Some(id) = u.msg {
+            if g.msg_tags.get(&id) == Some(&tag) {
+                g.msg_tags.remove(&id);
+            }
+            if g.assign.get(&id).is_some_and(|a| a.owner == u.consumer) {
+                g.assign.remove(&id);
+            }
+            if let Some(q) = g.directed.get_mut(&u.consumer) {
+                let (theirs, rest): (VecDeque<Item>, VecDeque<Item>) =
+                    q.drain(..).partition(|it| matches!(
+                        &it.frame, Frame::Chunk { id: m, .. } if *m == id
+                    ));
+                *q = rest;
+                g.shared.extend(theirs); // already counted in `total`
+            }
+        }
+
+        let attempts = u.items.iter().map(|i| i.attempts).max().unwrap_or(0) + 1;
+        if attempts > max_retries {
+            for it in &u.items {
+                g.audit(it.seq, None, "dead-lettered");
+                g.settle(it.seq);
+            }
+            if let Some(id) = u.msg {
+                g.tomb.insert(id, now);
+            }
+            self.stats.dead_lettered_msgs.fetch_add(1, Ordering::Relaxed);
+            dead.extend(u.items.into_iter().map(|it| Item { attempts, ..it }));
+            return;
+        }
+        let ready = now + self.policy.backoff(attempts);
+        for it in u.items {
+            #[cfg(feature = "otel")]
+            g.trace_settled(it.seq, "requeued");
+            g.delayed.push(ready, Item { attempts, ..it });
+            g.total += 1;
+        }
+        self.stats.redelivered_msgs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Hand dead-lettered items to the configured sink. Called without the
+    /// router lock held: a slow disk must not stall routing.
+    fn bury(&self, dead: Vec<Item>) {
+        if dead.is_empty() {
+            return;
+        }
+        if let Some(topic) = &self.topic {
+            return topic.bury(dead);
+        }
+        let mut grave = self.dead_letters.lock().unwrap();
+        let w = match grave.as_mut() {
+            Some(Grave::Sink(w)) => w,
+            Some(Grave::Queue(queue)) => {
+                let Some(queue) = queue.get() else { return };
+                for it in dead {
+                    // What steered routing here (priority, required tags)
+                    // must not strand it there; keep what consumers see.
+                    let meta = Meta {
+                        headers: it.meta.headers, codec: it.meta.codec, ..Meta::default()
+                    };
+                    if !queue.offer(it.frame, meta) {
+                        error!("dead-letter queue is full; dropping a dead letter");
+                        self.stats.dropped_msgs.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                return;
+            }
+            None => return,
+        };
+        // One write for the batch: the sink may be shared with other queues.
+        let mut buf = Vec::new();
+        let res = dead.iter()
+            .try_for_each(|it| put_frame(
+                &mut buf, &it.frame, &Meta { attempt: Some(it.attempts), ..it.meta.clone() },
+            ))
+            .and_then(|()| w.write_all(&buf))
+            .and_then(|()| w.flush());
+        if let Err(e) = res {
+            error!("failed to write dead-lettered frames: {}", e);
+        }
+    }
+
+    /// Decide what to do with a popped frame, updating assignment
+    /// bookkeeping. Singles always deliver. Chunks deliver if unclaimed
+    /// (claiming them for `me`) or owned by `me`; redirect if owned by
+    /// another consumer; drop if their message is tombstoned.
+    fn classify(g: &mut RouterInner, me: ConsumerId, f: &Frame, now: Instant) -> Disposition {
+        let (id, count) = match f {
+            Frame::Msg(_) | Frame::Eos(_) | Frame::Ping | Frame::Bye => return Disposition::Deliver,
+            Frame::Chunk { id, count, .. } => (*id, *count),
+        };
+
+        if let Some(ts) = g.tomb.get_mut(&id) {
+            *ts = now;
+            return Disposition::DropTombstoned;
+        }
+
+        enum Hit { Unclaimed, Mine(bool /* complete */), Other(ConsumerId) }
+        let hit = match g.assign.get_mut(&id) {
+            None => Hit::Unclaimed,
+            Some(a) if a.owner == me => {
+                a.delivered += 1;
+                a.last_seen = now;
+                Hit::Mine(a.delivered >= a.count)
+            }
+            Some(a) => Hit::Other(a.owner),
+        };
+
+        match hit {
+            Hit::Unclaimed => {
+                // First chunk of this message to be popped: claim it.
+                if count > 1 {
+                    g.assign.insert(id, Assign {
+                        owner: me, delivered: 1, count, last_seen: now,
+                    });
+                } // degenerate 1-chunk message completes on delivery; no entry
+                Disposition::Deliver
+            }
+            Hit::Mine(complete) => {
+                if complete {
+                    g.assign.remove(&id); // happy-path cleanup
+                }
+                Disposition::Deliver
+            }
+            Hit::Other(owner) => Disposition::Redirect(owner),
+        }
+    }
+
+    /// Handle a failed write of `frame` to consumer `me`, losing as little
+    /// as possible. write_frame/write_chunk_frame return Ok only after the
+    /// consumer's ACK, so on failure THIS frame never arrived — and if it
+    /// was also the only frame of its message ever handed to `me`
+    /// (delivered == 1), nothing of the message reached the dead consumer:
+    /// rescind the claim and requeue the frame so another consumer can take
+    /// the whole message. Singles are requeued within the retry policy's
+    /// budget and dead-lettered past it, so a message that kills every
+    /// consumer it reaches doesn't cycle forever.
+    /// Only when earlier chunks WERE ACKed — they died inside the dead
+    /// consumer — is the message doomed: tombstone it, drop the frame.
+    /// EOS notices are simply dropped: the consumer is going away anyway.
+    /// Ack-mode frames are left alone here: they stay tracked as unacked,
+    /// and unregistering the consumer requeues the whole delivery.
+    /// Returns true if the frame was requeued. (Requeueing can block while
+    /// the queue is at capacity, like push — the same exposure the original
+    /// single-frame requeue had.)
+    fn fail_delivery(&self, me: ConsumerId, frame: Frame) -> bool {
+        enum Verdict { Requeue, UnclaimAndRequeue, Doom, Bury }
+
+        let mut g = self.inner.lock().unwrap();
+        let held = g.held.get_mut(&me).and_then(|h| h.pop_front());
+        let (seq, attempts, enqueued, meta) = match held {
+            Some(Held::Frame { seq, attempts, enqueued, meta }) => {
+                #[cfg(feature = "otel")]
+                g.trace_settled(seq, "failed");
+                (seq, attempts, enqueued, *meta)
+            }
+            // An EOS notice, an ack-mode frame, or nothing held at all.
+            _ => return false,
+        };
+        let verdict = match &frame {
+            Frame::Msg(_) if attempts >= self.policy.max_retries => Verdict::Bury,
+            Frame::Msg(_) | Frame::Eos(_) | Frame::Ping | Frame::Bye => Verdict::Requeue,
+            Frame::Chunk { id, count, .. } => match g.assign.get(id) {
+                // Never-ACKed first chunk: fully salvageable.
+                Some(a) if a.owner == me && a.delivered == 1 => {
+                    Verdict::UnclaimAndRequeue
+                }
+                // Someone else's claim (unreachable in practice): leave it
+                // alone, put the frame back; pop will redirect it.
+                Some(a) if a.owner != me => Verdict::Requeue,
+                // delivered > 1: earlier chunks were ACKed by the dead
+                // consumer and died with it.
+                Some(_) => Verdict::Doom,
+                // Entry already removed => the failed frame was the LAST
+                // chunk of a completed claim — earlier chunks were ACKed —
+                // unless it's a degenerate 1-chunk message, which is
+                // self-contained and salvageable.
+                None if *count <= 1 => Verdict::Requeue,
+                None => Verdict::Doom,
+            },
+        };
+
+        let (requeue, unclaim) = match verdict {
+            Verdict::Doom | Verdict::Bury => (false, false),
+            Verdict::Requeue => (true, false),
+            Verdict::UnclaimAndRequeue => (true, true),
+        };
+        let attempts = attempts + 1;
+
+        if let Verdict::Bury = verdict {
+            g.audit(seq, None, "dead-lettered");
+            g.settle(seq);
+            if g.release_barriers() {
+                self.wake_consumers();
+            }
+            self.stats.dead_lettered_msgs.fetch_add(1, Ordering::Relaxed);
+            drop(g);
+            self.bury(vec![Item { seq, attempts, enqueued, meta, frame }]);
+            return false;
+        }
+        if !requeue {
+            if let Frame::Chunk { id, idx, count, .. } = &frame {
+                let id = *id;
+                warn!(
+                    "message {:032x} lost with its consumer at chunk {}/{}: \
+                     its earlier chunks cannot be sent again",
+                    id, idx + 1, count,
+                );
+                g.assign.remove(&id);
+                g.tomb.insert(id, self.now());
+            }
+            g.settle(seq);
+            if g.release_barriers() {
+                self.wake_consumers();
+            }
+            return false;
+        }
+        if unclaim && let Frame::Chunk { id, .. } = &frame {
+            g.assign.remove(id);
+        }
+        while g.total >= self.capacity() {
+            g = self.not_full.wait(g).unwrap();
+        }
+        g.shared.push_back(Item { seq, attempts, enqueued, meta, frame });
+        g.total += 1;
+        self.wake_consumers();
+        true
+    }
+
+    /// What an export sees: queued items nobody holds, in serving order,
+    /// then those waiting out a retry backoff. Chunks of messages a consumer
+    /// has claimed stay out of it — the rest of the message is theirs. With
+    /// `take` the items leave the queue, held for the exporter until
+    /// `finish_export`.
+    fn export(&self, take: bool) -> Vec<Item> {
+        let mut g = self.inner.lock().unwrap();
+        let RouterInner { shared, assign, delayed, .. } = &mut *g;
+        let free = |it: &Item| !matches!(
+            &it.frame, Frame::Chunk { id, .. } if assign.contains_key(id)
+        );
+        if !take {
+            return shared.iter().chain(delayed.iter())
+                .filter(|it| free(it))
+                .cloned()
+                .collect();
+        }
+        let mut items = shared.take_where(free);
+        items.extend(delayed.take_all());
+        g.total -= items.len();
+        self.not_full.notify_all();
+        items
+    }
+
+    /// Queue the frames of a snapshot (see `import_from`) in order, behind
+    /// what is queued, journaled as if just pushed but without waiting for
+    /// room: in memory while there is some, then to the overflow, then
+    /// past capacity, like replayed frames. A sharded queue deals them out
+    /// as `recover` does; a fan-out queue's subscribers get them as from a
+    /// producer.
+    fn import(&self, frames: Vec<(Frame, Meta)>) {
+        for (frame, meta) in frames {
+            if self.is_fanout() {
+                self.broadcast(frame, meta, None);
+                continue;
+            }
+            let Some(shards) = &self.shards else {
+                self.import_one(frame, meta);
+                continue;
+            };
+            let n = shards.routers.len();
+            let shard = match (&frame, &meta.partition) {
+                (_, Some(key)) => shards.link.for_key(key),
+                (Frame::Chunk { id, .. }, None) => (*id % n as u128) as usize,
+                _ => shards.next.fetch_add(1, Ordering::Relaxed) % n,
+            };
+            shards.routers[shard].import_one(frame, meta);
+        }
+    }
+
+    fn import_one(&self, frame: Frame, meta: Meta) {
+        if let Some((link, _)) = &self.member
+            && let Frame::Eos(group) = &frame
+        {
+            let lsn = self.inner.lock().unwrap().journal(&frame, &meta);
+            link.push_eos(group.clone(), lsn);
+            return;
+        }
+        let mut g = self.inner.lock().unwrap();
+        let now = self.now();
+        if (g.total >= self.capacity() || !g.spilled.is_empty())
+            && let Some(ov) = &self.overflow
+        {
+            match ov.lock().unwrap().push(&frame, &meta) {
+                Ok(()) => {
+                    let lsn = g.journal(&frame, &meta);
+                    g.spilled.push_back((now, None, lsn));
+                    return;
+                }
+                Err(e) => error!("overflow write failed, importing into memory: {}", e),
+            }
+        }
+        let lsn = g.journal(&frame, &meta);
+        self.admit(&mut g, frame, meta, None, now, lsn);
+        self.wake_consumers();
+    }
+
+    /// The exporter has the drained `items` safe (`kept`) or gave up. Kept
+    /// items are settled; the others go back to the queue as they were —
+    /// even past capacity, since they were counted in it a moment ago.
+    fn finish_export(&self, items: Vec<Item>, kept: bool) {
+        let mut g = self.inner.lock().unwrap();
+        if !kept {
+            g.total += items.len();
+            g.shared.extend(items);
+            self.wake_consumers();
+            return;
+        }
+        let mut data = 0;
+        for it in &items {
+            if matches!(it.frame, Frame::Eos(_)) {
+                g.unjournal(it.seq);
+            } else {
+                g.audit(it.seq, None, "exported");
+                g.settle(it.seq);
+                data += 1;
+            }
+        }
+        self.stats.exported_msgs.fetch_add(data, Ordering::Relaxed);
+        if g.release_barriers() {
+            self.wake_consumers();
+        }
+    }
+
+    /// Stop or resume taking frames from producers.
+    fn set_intake(&self, intake: Intake) {
+        self.inner.lock().unwrap().intake = intake;
+        if intake == Intake::Open {
+            self.not_full.notify_all();
+        }
+        for shard in self.shard_routers() {
+            shard.set_intake(intake);
+        }
+    }
+
+    fn intake(&self) -> Intake {
+        self.inner.lock().unwrap().intake
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// Hold up to `frames` frames from now on, e.g. after a configuration
+    /// reload. Producers waiting for room, and the overflow's refill, are
+    /// woken to look again; past a lowered capacity nothing is dropped,
+    /// intake just waits until consumers bring the queue below it. A
+    /// sharded queue shares the capacity out among its shards again; each
+    /// of a fan-out queue's subscribers gets all of it.
+    fn set_capacity(&self, frames: usize) {
+        {
+            let _g = self.inner.lock().unwrap();
+            self.capacity.store(frames, Ordering::Relaxed);
+            self.not_full.notify_all();
+        }
+        let shards = self.shard_routers();
+        for shard in &shards {
+            shard.set_capacity(frames.div_ceil(shards.len()));
+        }
+        for sub in self.subscribers() {
+            sub.set_capacity(frames);
+        }
+    }
+
+    /// Drop every frame waiting in the queue — what an export drain would
+    /// take, and the whole overflow — counted as dropped and audited as
+    /// "purged". Messages losing chunks are tombstoned, so their
+    /// stragglers go too. EOS markers stay queued. Returns how many frames
+    /// went. A fan-out queue purges every subscriber, a sharded one every
+    /// shard.
+    fn purge(&self) -> io::Result<usize> {
+        if self.is_fanout() {
+            return self.subscribers().iter().map(|sub| sub.purge()).sum();
+        }
+        if let Some(shards) = &self.shards {
+            return shards.routers.iter().map(|shard| shard.purge()).sum();
+        }
+        let mut g = self.inner.lock().unwrap();
+        let now = self.now();
+        let RouterInner { shared, assign, delayed, .. } = &mut *g;
+        let mut items = shared.take_where(|it| match &it.frame {
+            Frame::Chunk { id, .. } => !assign.contains_key(id),
+            Frame::Eos(_) => false,
+            _ => true,
+        });
+        items.extend(delayed.take_all());
+        g.total -= items.len();
+        let mut purged = 0;
+        let mut drop_frame = |g: &mut RouterInner, frame: &Frame| {
+            if let Frame::Chunk { id, .. } = frame {
+                g.assign.remove(id);
+                g.tomb.insert(*id, now);
+            }
+            self.stats.dropped_msgs.fetch_add(1, Ordering::Relaxed);
+            self.stats.dropped_bytes.fetch_add(frame.payload_len() as u64, Ordering::Relaxed);
+            purged += 1;
+        };
+        for it in items {
+            drop_frame(&mut g, &it.frame);
+            g.audit(it.seq, None, "purged");
+            g.settle(it.seq);
+        }
+        // The overflow is read back in batches, so a deep one needn't fit
+        // in memory; only EOS markers are kept.
+        if let Some(ov) = &self.overflow {
+            while !g.spilled.is_empty() {
+                let frames = ov.lock().unwrap().take(g.spilled.len().min(1024))?;
+                for (frame, meta) in frames {
+                    let (spilled_at, stamp, lsn) = g.spilled.pop_front().expect("spilled frames are counted");
+                    if let Frame::Eos(_) = frame {
+                        self.admit(&mut g, frame, meta, stamp, spilled_at, lsn);
+                        g.unspilled();
+                        continue;
+                    }
+                    g.unspilled();
+                    drop_frame(&mut g, &frame);
+                    if let Some(lsn) = lsn {
+                        g.forget(lsn);
+                    }
+                    if let Some(stamp) = stamp
+                        && let Some(tx) = &g.audit
+                    {
+                        tx.send(stamp.line(None, "purged")).ok();
+                    }
+                }
+            }
+        }
+        g.release_barriers();
+        self.wake_consumers();
+        self.not_full.notify_all();
+        Ok(purged)
+    }
+
+    /// Expire stale state. Returns (assignments expired, tombstones purged).
+    fn sweep(&self, assign_ttl: Duration, tomb_ttl: Duration) -> (usize, usize) {
+        let mut g = self.inner.lock().unwrap();
+        let now = self.now();
+
+        // An expired assignment becomes a tombstone: its already-delivered
+        // chunks are stuck at the old owner, so remaining frames can never
+        // form a complete message for anyone.
+        let expired: Vec<MsgId> = g.assign.iter()
+            .filter(|(_, a)| now.duration_since(a.last_seen) >= assign_ttl)
+            .map(|(m, _)| *m)
+            .collect();
+        for m in &expired {
+            g.assign.remove(m);
+            g.tomb.insert(*m, now);
+        }
+
+        // A tombstone outlives its TTL while frames of its message are
+        // still queued: forgetting it would let them through as a new,
+        // incomplete message.
+        let queued: HashSet<MsgId> = g.shared.iter()
+            .chain(g.directed.values().flatten())
+            .chain(g.delayed.iter())
+            .filter_map(|it| match &it.frame {
+                Frame::Chunk { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect();
+        let before = g.tomb.len();
+        g.tomb.retain(|id, t| queued.contains(id) || now.duration_since(*t) < tomb_ttl);
+        (expired.len(), before - g.tomb.len())
+    }
+}
+
+/// A session's hold on a reply queue (see `Queues::attach`).
+struct Attached(Option<(Arc<Queues>, String)>);
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        if let Some((queues, name)) = &self.0 {
+            queues.detach(name);
+        }
+    }
+}
+
+// At most this many named queues; each costs a Router, so a client must not
+// be able to create them without bound.
+const MAX_QUEUES: usize = 1024;
+
+/// Builds the Router for a queue, by name ("" for the default queue) and
+/// capacity.
+type MakeRouter = dyn Fn(&str, usize) -> io::Result<Router> + Send + Sync;
+
+/// Frames each queue holds: `default`, unless `queues` names it (from a
+/// configuration file's `[queues.<name>]` tables).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Capacities {
+    default: usize,
+    queues:  BTreeMap<String, usize>,
+}
+
+impl Capacities {
+    fn of(&self, name: &str) -> usize {
+        self.queues.get(name).copied().unwrap_or(self.default)
+    }
+}
+
+/// Every queue the orchestrator serves: the default one, and named ones
+/// (`OPT_QUEUE`) created on first use. Each is a Router of its own — its
+/// own capacity, ordering, egress cap and overflow — built by `make` with
+/// the same settings; all share the Stats counters and the dead-letter and audit
+/// sinks. The aggregate methods answer for all queues at once.
+struct Queues {
+    default: Arc<Router>,
+    named:   Mutex<BTreeMap<String, Arc<Router>>>,
+    /// Sessions attached to each reply queue (`REPLY_QUEUE_PREFIX`). Only
+    /// locked under `named`.
+    attached: Mutex<HashMap<String, usize>>,
+    make:    Box<MakeRouter>,
+    /// Every queue's intake, including queues created from now on, unless
+    /// open.
+    intake:  Mutex<Intake>,
+    /// What each queue holds, including queues created from now on.
+    capacities: Mutex<Capacities>,
+    /// What standbys follow, when the queues are journaled.
+    feed:    Option<Arc<Feed>>,
+}
+
+impl Queues {
+    fn new(
+                capacities: Capacities,
+                make: impl Fn(&str, usize) -> io::Result<Router> + Send + Sync + 'static,
+            ) -> io::Result<Self> {
+        let default = Arc::new(make("", capacities.default)?);
+        default.start_refill();
+        Ok(Self {
+            default,
+            named: Mutex::new(BTreeMap::new()),
+            attached: Mutex::new(HashMap::new()),
+            make: Box::new(make),
+            intake: Mutex::new(Intake::Open),
+            capacities: Mutex::new(capacities),
+            feed: None,
+        })
+    }
+
+    fn with_feed(mut self, feed: Option<Arc<Feed>>) -> Self {
+        self.feed = feed;
+        self
+    }
+
+    /// The queue called `name` ("" is the default queue), created if need be.
+    fn get(&self, name: &str) -> io::Result<Arc<Router>> {
+        if name.is_empty() {
+            return Ok(self.default.clone());
+        }
+        self.get_in(&mut self.named.lock().unwrap(), name)
+    }
+
+    fn get_in(&self, named: &mut BTreeMap<String, Arc<Router>>, name: &str) -> io::Result<Arc<Router>> {
+        if let Some(r) = named.get(name) {
+            return Ok(r.clone());
+        }
+        if named.len() >= MAX_QUEUES {
+            return Err(io::Error::other(format!("too many queues, not creating {name:?}")));
+        }
+        info!("creating queue {:?}", name);
+        let capacity = self.capacities.lock().unwrap().of(name);
+        let r = Arc::new((self.make)(name, capacity)?);
+        r.set_intake(*self.intake.lock().unwrap());
+        r.start_refill();
+        named.insert(name.to_string(), r.clone());
+        Ok(r)
+    }
+
+    /// The queue a producer or consumer session uses, as `get`. A reply
+    /// queue counts its sessions until the returned guard drops, and is
+    /// retired when the last one has.
+    fn attach(self: &Arc<Self>, name: &str) -> io::Result<(Arc<Router>, Attached)> {
+        if !name.starts_with(REPLY_QUEUE_PREFIX) {
+            return Ok((self.get(name)?, Attached(None)));
+        }
+        let mut named = self.named.lock().unwrap();
+        let r = self.get_in(&mut named, name)?;
+        *self.attached.lock().unwrap().entry(name.to_string()).or_default() += 1;
+        Ok((r, Attached(Some((self.clone(), name.to_string())))))
+    }
+
+    fn detach(&self, name: &str) {
+        let mut named = self.named.lock().unwrap();
+        let mut attached = self.attached.lock().unwrap();
+        let Some(n) = attached.get_mut(name) else { return };
+        *n -= 1;
+        if *n > 0 {
+            return;
+        }
+        attached.remove(name);
+        let Some(r) = named.remove(name) else { return };
+        drop((attached, named));
+        match r.purge() {
+            Ok(0) => info!("retiring reply queue {:?}", name),
+            Ok(n) => info!("retiring reply queue {:?}; {} unread frame(s) dropped", name, n),
+            Err(e) => warn!("retiring reply queue {:?}: {}", name, e),
+        }
+    }
+
+    /// The queue called `name` ("" is the default queue), if it exists.
+    fn find(&self, name: &str) -> Option<Arc<Router>> {
+        match name {
+            "" => Some(self.default.clone()),
+            name => self.named.lock().unwrap().get(name).cloned(),
+        }
+    }
+
+    /// Every queue, the subscribers of the fan-out ones and the shards of
+    /// the sharded ones.
+    fn all(&self) -> Vec<Arc<Router>> {
+        let queues: Vec<Arc<Router>> = {
+            let named = self.named.lock().unwrap();
+            std::iter::once(self.default.clone()).chain(named.values().cloned()).collect()
+        };
+        let parts: Vec<Arc<Router>> = queues.iter()
+            .flat_map(|r| r.subscribers().into_iter().chain(r.shard_routers()))
+            .collect();
+        queues.into_iter().chain(parts).collect()
+    }
+
+    /// Every queue with its name, the default ("") first.
+    fn named_all(&self) -> Vec<(String, Arc<Router>)> {
+        let named = self.named.lock().unwrap();
+        std::iter::once((String::new(), self.default.clone()))
+            .chain(named.iter().map(|(n, r)| (n.clone(), r.clone())))
+            .collect()
+    }
+
+    /// Pause, refuse or resume intake of every queue, and queues yet to be
+    /// created.
+    fn set_intake(&self, intake: Intake) {
+        // Under the map's lock, so a queue created meanwhile can't miss it.
+        let named = self.named.lock().unwrap();
+        *self.intake.lock().unwrap() = intake;
+        for r in std::iter::once(&self.default).chain(named.values()) {
+            r.set_intake(intake);
+        }
+    }
+
+    /// Resize every queue whose capacity changed, and size queues created
+    /// from now on by `capacities`.
+    fn set_capacities(&self, capacities: Capacities) {
+        // Under the map's lock, so a queue created meanwhile can't miss it.
+        let named = self.named.lock().unwrap();
+        let mut current = self.capacities.lock().unwrap();
+        for (name, r) in std::iter::once(("", &self.default)).chain(named.iter().map(|(n, r)| (n.as_str(), r))) {
+            let frames = capacities.of(name);
+            if frames != current.of(name) {
+                info!("queue {:?} now holds {} frames", name, frames);
+                r.set_capacity(frames);
+            }
+        }
+        *current = capacities;
+    }
+
+    fn count(&self) -> usize {
+        1 + self.named.lock().unwrap().len()
+    }
+
+    fn now(&self) -> Instant {
+        self.default.now()
+    }
+
+    fn depth(&self) -> usize {
+        self.all().iter().map(|r| r.depth()).sum()
+    }
+
+    fn outstanding(&self) -> usize {
+        self.all().iter().map(|r| r.outstanding()).sum()
+    }
+
+    /// Every queue is idle: the condition behind the idle hook.
+    fn is_idle(&self) -> bool {
+        self.all().iter().all(|r| r.is_idle())
+    }
+
+    /// The queue called `name` ("" is the default queue) is idle, with its
+    /// shards or subscribers. One that doesn't exist is.
+    fn is_queue_idle(&self, name: &str) -> bool {
+        let Some(r) = self.find(name) else { return true };
+        r.is_idle() && r.subscribers().iter().chain(&r.shard_routers()).all(|r| r.is_idle())
+    }
+
+    /// Total backlog, and the longest wait in any queue.
+    fn backlog(&self) -> (usize, Duration) {
+        self.all().iter().map(|r| r.backlog())
+            .fold((0, Duration::ZERO), |(n, w), (rn, rw)| (n + rn, w.max(rw)))
+    }
+
+    fn gauges(&self) -> (usize, usize, usize) {
+        self.all().iter().map(|r| r.gauges())
+            .fold((0, 0, 0), |(a, b, c), (x, y, z)| (a + x, b + y, c + z))
+    }
+
+    fn expire_unacked(&self) -> usize {
+        self.all().iter().map(|r| r.expire_unacked(r.now())).sum()
+    }
+
+    fn expire_stale(&self) -> usize {
+        self.all().iter().map(|r| r.expire_stale()).sum()
+    }
+
+    fn sweep(&self, assign_ttl: Duration, tomb_ttl: Duration) -> (usize, usize) {
+        self.all().iter().map(|r| r.sweep(assign_ttl, tomb_ttl))
+            .fold((0, 0), |(a, b), (x, y)| (a + x, b + y))
+    }
+
+    fn close_consumers(&self) {
+        for r in self.all() {
+            r.close_consumers();
+        }
+    }
+}
+
+/// Where a router's dead letters go (`DeadLetter`).
+enum Grave {
+    Sink(Box<dyn Write + Send>),
+    /// The dead-letter queue's router; set once all queues can be made.
+    Queue(Arc<OnceLock<Arc<Router>>>),
+}
+
+/// A writer several routers share (dead letters, audit log). `write_all`
+/// holds the lock throughout, so records from different queues never
+/// interleave.
+#[derive(Clone)]
+struct SharedSink(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl SharedSink {
+    fn new(w: Box<dyn Write + Send>) -> Self {
+        Self(Arc::new(Mutex::new(w)))
+    }
+
+    /// Drop the writer for every clone; what they write from now on is
+    /// discarded.
+    fn close(&self) {
+        let w = std::mem::replace(&mut *self.0.lock().unwrap(), Box::new(io::sink()));
+        drop(w);
+    }
+}
+
+impl Write for SharedSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().write_all(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Start a thread, called `name`, that writes the lines sent to it into
+/// `sink` and flushes whenever it catches up. A failed write stops it;
+/// `what` names the sink in the error logged.
+fn spawn_line_writer(name: &str, what: &'static str, mut sink: Box<dyn Write + Send>) -> mpsc::Sender<String> {
+    let (tx, rx) = mpsc::channel::<String>();
+    thread::Builder::new()
+        .name(name.into())
+        .spawn(move || {
+            let res: io::Result<()> = (|| {
+                while let Ok(line) = rx.recv() {
+                    sink.write_all(line.as_bytes())?;
+                    while let Ok(line) = rx.try_recv() {
+                        sink.write_all(line.as_bytes())?;
+                    }
+                    sink.flush()?;
+                }
+                Ok(())
+            })();
+            if let Err(e) = res {
+                error!("{} write failed, nothing more is written to it: {}", what, e);
+            }
+        })
+        .expect("spawn line writer");
+    tx
+}
+
+/// Run an operator-supplied hook command (`sh -c`) without blocking the
+/// caller. The event name is exported as QPIPE_EVENT; failures are logged
+/// and otherwise ignored — a broken hook must never take the queue down.
+fn run_hook(cmd: &str, event: &str, vars: &[(&str, String)]) {
+    let cmd = cmd.to_string();
+    let event = event.to_string();
+    let vars: Vec<(String, String)> = vars.iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+    thread::spawn(move || {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&cmd)
+            .env("QPIPE_EVENT", &event)
+            .envs(vars)
+            .status();
+        match status {
+            Ok(st) if st.success() => debug!("{} hook finished", event),
+            Ok(st) => warn!("{} hook {:?} exited with {}", event, cmd, st),
+            Err(e) => warn!("{} hook {:?} failed to start: {}", event, cmd, e),
+        }
+    });
+}
+
+fn env_duration_secs(name: &str, default_secs: u64) -> Duration {
+    env::var(name)
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(default_secs))
+}
+
+/// How the orchestrator writes its stats reports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// The `[stats]` line, logged at info level.
+    #[default]
+    Text,
+    /// One JSON object per line on stdout, as `qpipe::watch_stats` yields
+    /// them, whatever the log level.
+    Json,
+}
+
+/// What a queue does with a producer's frame when it is full (and, with
+/// QPIPE_OVERFLOW_DIR, can't spill it to disk either).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FullPolicy {
+    /// Hold the producer until consumers make room: nothing is lost.
+    #[default]
+    Block,
+    /// Drop the longest-queued message no consumer has started, to make
+    /// room for the new one. EOS markers are never dropped; with nothing
+    /// else to drop, the producer waits as under `Block`.
+    DropOldest,
+    /// Refuse the new message with `ACK_THROTTLED`, which producers that
+    /// accept it (`OPT_THROTTLE`) see as a `QuotaExceeded` error. Other
+    /// producers, and the rest of a message already let in, wait as under
+    /// `Block`. A full subscriber of a fan-out queue just misses the
+    /// message, which the others got.
+    RejectNewest,
+}
+
+impl FullPolicy {
+    /// `block`, `drop-oldest` or `reject-newest`; `InvalidInput` otherwise.
+    pub fn parse(s: &str) -> io::Result<Self> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "reject-newest" => Ok(Self::RejectNewest),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("full-queue policy: expected block, drop-oldest or reject-newest, got {s:?}"),
+            )),
+        }
+    }
+}
+
+/// The name, as `parse` takes it.
+impl fmt::Display for FullPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Block => "block",
+            Self::DropOldest => "drop-oldest",
+            Self::RejectNewest => "reject-newest",
+        })
+    }
+}
+
+/// What `Orchestrator::bind_with` takes besides the environment: the
+/// binary's positional arguments, `--stats-format`, `--auth-key`,
+/// `--wal-dir`, `--single-port`, `--ws-listen`, `--delivery`, `--standby-of`,
+/// `--dedup-window`, `--when-full`, `--import`, `--max-producers`,
+/// `--max-consumers` and `--config`, and whether it runs under
+/// systemd.
+#[derive(Clone, Debug)]
+pub struct OrchestratorOptions {
+    capacity:     usize,
+    stats_every:  Duration,
+    stats_format: StatsFormat,
+    auth_key:     Option<psk::Key>,
+    wal_dir:      Option<PathBuf>,
+    single_port:  bool,
+    ws_listen:    Option<String>,
+    also_listen:  Vec<String>,
+    shards:       usize,
+    max_frame_bytes: usize,
+    delivery:     Option<DeliveryPolicy>,
+    dedup_window: usize,
+    when_full:    FullPolicy,
+    standby_of:   Option<String>,
+    failover_after: Duration,
+    import:       Option<PathBuf>,
+    max_producers: Option<usize>,
+    max_consumers: Option<usize>,
+    systemd:      bool,
+    /// The configuration file, and what it said when `config` read it.
+    config:       Option<(PathBuf, Config)>,
+}
+
+impl Default for OrchestratorOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            stats_every: Duration::from_secs(1),
+            stats_format: StatsFormat::Text,
+            auth_key: None,
+            wal_dir: None,
+            single_port: false,
+            ws_listen: None,
+            also_listen: Vec::new(),
+            shards: 1,
+            max_frame_bytes: MAX_FRAME_SIZE,
+            delivery: None,
+            dedup_window: 10_000,
+            when_full: FullPolicy::Block,
+            standby_of: None,
+            failover_after: Duration::from_secs(5),
+            import: None,
+            max_producers: None,
+            max_consumers: None,
+            systemd: false,
+            config: None,
+        }
+    }
+}
+
+impl OrchestratorOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Frames each queue holds before producers block, or spill to
+    /// QPIPE_OVERFLOW_DIR. Default 10,000.
+    pub fn capacity(mut self, frames: usize) -> Self {
+        self.capacity = frames;
+        self
+    }
+
+    /// How often the stats line is logged (and sent to QPIPE_STATSD).
+    /// Default every second.
+    pub fn stats_every(mut self, every: Duration) -> Self {
+        self.stats_every = every;
+        self
+    }
+
+    /// Log the stats line as text (the default) or print it as JSON.
+    /// Stats subscribers get JSON either way.
+    pub fn stats_format(mut self, format: StatsFormat) -> Self {
+        self.stats_format = format;
+        self
+    }
+
+    /// Require the pre-shared key exchange (see `psk`) of every session
+    /// but healthchecks, like the binary's `--auth-key`.
+    pub fn auth_key(mut self, key: psk::Key) -> Self {
+        self.auth_key = Some(key);
+        self
+    }
+
+    /// Journal every queue to a write-ahead log under `dir` (see `wal`),
+    /// one subdirectory per queue, and replay what it holds on startup:
+    /// frames not yet delivered survive a restart. Producers get their
+    /// ACKs once a frame is journaled. Like the binary's `--wal-dir`.
+    pub fn wal_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.wal_dir = Some(dir.into());
+        self
+    }
+
+    /// Serve producers and consumers only over their control connection
+    /// (`OPT_SINGLE_PORT`), never from a data port of their own, and turn
+    /// away clients that don't ask for it: for deployments where only the
+    /// orchestrator's port is open. Clients may ask for single-port
+    /// sessions either way. Like the binary's `--single-port`.
+    pub fn single_port(mut self, on: bool) -> Self {
+        self.single_port = on;
+        self
+    }
+
+    /// Also serve WebSocket clients (`ws://` addresses) on `addr`
+    /// (`host:port`), e.g. a browser dashboard consuming a queue. Sessions
+    /// there are single-port, whatever they ask for. Like the binary's
+    /// `--ws-listen`.
+    #[cfg(feature = "ws")]
+    pub fn ws_listen(mut self, addr: &str) -> Self {
+        self.ws_listen = Some(addr.to_string());
+        self
+    }
+
+    /// Take producers and consumers on `addr` as well as the address
+    /// `bind_with` gets: an IPv6 address next to an IPv4 one, say, or an
+    /// external interface next to an internal one. Each address has an
+    /// accept loop of its own, and a session's data port is bound on the
+    /// interface its client came in on. Like the binary's repeated
+    /// `--listen`.
+    pub fn also_listen(mut self, addr: &str) -> Self {
+        self.also_listen.push(addr.to_string());
+        self
+    }
+
+    /// Split every queue but the fan-out ones into `n` shards, each with
+    /// its own lock and its share of the capacity, so producers and
+    /// consumers on many cores don't all contend for one. Consumers with
+    /// nothing to do steal from the other shards; EOS notices still follow
+    /// everything sent before them. Default 1 (unsharded). Like the
+    /// binary's `--shards`.
+    pub fn shards(mut self, n: usize) -> Self {
+        self.shards = n.max(1);
+        self
+    }
+
+    /// Refuse producer frames whose body is longer than `bytes`, and tell
+    /// clients so in the handshake: producers chunk messages to fit, and
+    /// refuse ones too large for even MAX_CHUNKS such chunks before
+    /// sending any of them. Producers too old to ask are cut off at their
+    /// first frame over the limit. Between MIN_FRAME_LIMIT and
+    /// MAX_FRAME_SIZE (the default); `bind_with` fails otherwise. Like the
+    /// binary's `--max-frame-bytes`.
+    pub fn max_frame_bytes(mut self, bytes: usize) -> Self {
+        self.max_frame_bytes = bytes;
+        self
+    }
+
+    /// Fix how every queue settles deliveries, but those the configuration
+    /// file gives a `delivery` of their own and reply queues: at-most-once
+    /// queues refuse consumers in ack mode, at-least-once ones put every
+    /// consumer in ack mode, and tell the consumer so in the handshake
+    /// (`OPT_DELIVERY`). Without a policy, each consumer picks its own.
+    /// Like the binary's `--delivery`.
+    pub fn delivery(mut self, policy: DeliveryPolicy) -> Self {
+        self.delivery = Some(policy);
+        self
+    }
+
+    /// How many message ids (`Meta::message_id`) each queue remembers to
+    /// drop producers' repeats of: a message whose id is among the last
+    /// `ids` the queue accepted is ACKed, but counted as a duplicate
+    /// instead of queued. Default 10,000; 0 turns deduplication off. Like
+    /// the binary's `--dedup-window`.
+    pub fn dedup_window(mut self, ids: usize) -> Self {
+        self.dedup_window = ids;
+        self
+    }
+
+    /// What every queue does with a producer's frame when it is full, but
+    /// those the configuration file gives a `when_full` of their own:
+    /// wait for room (the default), drop its oldest message, or refuse the
+    /// new one. With QPIPE_OVERFLOW_DIR, full queues spill to disk first.
+    /// Dropped and refused frames count as `shed` in the stats. Like the
+    /// binary's `--when-full`.
+    pub fn when_full(mut self, policy: FullPolicy) -> Self {
+        self.when_full = policy;
+        self
+    }
+
+    /// Stand by for the orchestrator at `primary`: `bind_with` copies its
+    /// write-ahead logs into this one's `wal_dir` (which it needs) as they
+    /// grow, and only binds once the primary has been out of reach for
+    /// `failover_after` — then carries on from the copy. Until then nothing
+    /// listens here, so clients with both addresses (see
+    /// `ReconnectOptions::standby`) keep to the primary. The primary must
+    /// journal too, and admit the standby as an admin session: it proves
+    /// this orchestrator's `auth_key`, and the QPIPE_USER/QPIPE_PASSWORD of
+    /// its environment. Like the binary's `--standby-of`.
+    pub fn standby_of(mut self, primary: &str) -> Self {
+        self.standby_of = Some(primary.to_string());
+        self
+    }
+
+    /// How long a standby waits for a silent primary before taking over.
+    /// Default 5 seconds. Like the binary's `--failover-after`.
+    pub fn failover_after(mut self, after: Duration) -> Self {
+        self.failover_after = after;
+        self
+    }
+
+    /// Start with the frames of the recording at `path` — what `qpipe-dump`
+    /// wrote before a maintenance window, say — queued in order, each in
+    /// the queue it was exported from, behind anything the write-ahead log
+    /// replays. Read
+    /// sealed with QPIPE_AT_REST_KEY, like everything else on disk; one
+    /// that can't be read keeps `bind_with` from starting. The frames go in
+    /// on every start with this set, so drop it once they are in. Like the
+    /// binary's `--import`.
+    pub fn import(mut self, path: impl Into<PathBuf>) -> Self {
+        self.import = Some(path.into());
+        self
+    }
+
+    /// Admit at most `n` producer sessions at once, across all queues;
+    /// more are turned away at the control handshake, as past
+    /// QPIPE_MAX_SESSIONS, and reconnecting producers back off and retry.
+    /// Unlimited by default. Like the binary's `--max-producers`.
+    pub fn max_producers(mut self, n: usize) -> Self {
+        self.max_producers = Some(n);
+        self
+    }
+
+    /// Admit at most `n` consumer sessions at once, like `max_producers`.
+    /// Like the binary's `--max-consumers`.
+    pub fn max_consumers(mut self, n: usize) -> Self {
+        self.max_consumers = Some(n);
+        self
+    }
+
+    /// Run as a systemd service (see `crate::systemd`): listen on the
+    /// sockets a socket unit passed, if it passed any, instead of the
+    /// addresses `bind_with` and `also_listen` give — a socket named `ws`
+    /// instead of `ws_listen`'s — and tell the service manager when the
+    /// orchestrator is ready and when it is stopping. Off by default, so an
+    /// embedded orchestrator leaves its host's service alone; the binary
+    /// turns it on.
+    #[cfg(unix)]
+    pub fn systemd(mut self, on: bool) -> Self {
+        self.systemd = on;
+        self
+    }
+
+    /// Take settings from the configuration file at `path` (see
+    /// `crate::config`), like the binary's `--config`: those it sets
+    /// replace the ones set so far, and later calls replace them in turn.
+    /// Its `[limits]` win over the QPIPE_* variables they stand in for.
+    /// The orchestrator reads the file again on `Orchestrator::reload`,
+    /// which SIGHUP triggers under `Orchestrator::handle_signals`.
+    pub fn config(mut self, path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let cfg = Config::load(&path)?;
+        if let Some(frames) = cfg.capacity {
+            self.capacity = frames;
+        }
+        if let Some(every) = cfg.stats_every {
+            self.stats_every = every;
+        }
+        if let Some(format) = cfg.stats_format {
+            self.stats_format = format;
+        }
+        if let Some(key) = &cfg.auth_key {
+            self.auth_key = Some(psk::Key::load(key)?);
+        }
+        if let Some(dir) = &cfg.wal_dir {
+            self.wal_dir = Some(dir.clone());
+        }
+        if let Some(on) = cfg.single_port {
+            self.single_port = on;
+        }
+        if let Some(addr) = &cfg.ws_listen {
+            if !cfg!(feature = "ws") {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{}: ws_listen: built without the `ws` feature", path.display()),
+                ));
+            }
+            self.ws_listen = Some(addr.clone());
+        }
+        if let Some(n) = cfg.shards {
+            self.shards = n;
+        }
+        if let Some(bytes) = cfg.max_frame_bytes {
+            self.max_frame_bytes = bytes;
+        }
+        if let Some(policy) = cfg.delivery {
+            self.delivery = Some(policy);
+        }
+        if let Some(ids) = cfg.dedup_window {
+            self.dedup_window = ids;
+        }
+        if let Some(policy) = cfg.when_full {
+            self.when_full = policy;
+        }
+        if let Some(primary) = &cfg.standby_of {
+            self.standby_of = Some(primary.clone());
+        }
+        if let Some(after) = cfg.failover_after {
+            self.failover_after = after;
+        }
+        self.config = Some((path, cfg));
+        Ok(self)
+    }
+
+    /// The control address the configuration file names, if it names one
+    /// (several are separated by commas): for the binary, which binds what
+    /// its arguments say.
+    pub fn listen(&self) -> Option<&str> {
+        self.config.as_ref().and_then(|(_, cfg)| cfg.listen.as_deref())
+    }
+}
+
+/// A queue's subdirectory under QPIPE_OVERFLOW_DIR or the write-ahead log
+/// directory.
+fn queue_dir(name: &str) -> String {
+    match name {
+        "" => "default".to_string(),
+        name => format!("q.{}", name.replace('/', "%2F")),
+    }
+}
+
+/// Whether the named queue `name` fans out, per QPIPE_FANOUT_QUEUES: a
+/// comma-separated list of queue names, or prefixes ending in `*`.
+fn fans_out(patterns: &str, name: &str) -> bool {
+    !name.is_empty() && patterns.split(',').map(str::trim).any(|p| match p.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => !p.is_empty() && p == name,
+    })
+}
+
+/// The named queue a `queue_dir` subdirectory belongs to.
+fn queue_of_dir(dir: &str) -> Option<String> {
+    dir.strip_prefix("q.").map(|name| name.replace("%2F", "/"))
+}
+
+/// How long frames waited in their queue before a consumer took them:
+/// how many did, and the 50th, 95th and 99th percentiles (to within the
+/// histogram's precision).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Dwell {
+    pub count: u64,
+    pub p50:   Duration,
+    pub p95:   Duration,
+    pub p99:   Duration,
+}
+
+/// An orchestrator's counters and gauges, as its stats line reports them.
+/// Frame counts count every chunk of a multi-frame message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Frames accepted from producers, and their payload bytes.
+    pub posted_frames:    u64,
+    pub posted_bytes:     u64,
+    /// Frames delivered to consumers.
+    pub collected_frames: u64,
+    pub collected_bytes:  u64,
+    /// Frames taken off a queue but never delivered.
+    pub dropped_frames:   u64,
+    pub dropped_bytes:    u64,
+    /// Ack mode: deliveries requeued for another attempt.
+    pub redelivered:      u64,
+    /// Messages that ran out of attempts.
+    pub dead_lettered:    u64,
+    /// Frames drained out by exports.
+    pub exported:         u64,
+    /// Messages that outlived their TTL before a consumer took them.
+    pub expired:          u64,
+    /// Frames dropped as repeats of a message id the queue accepted
+    /// recently (`Meta::message_id`).
+    pub duplicates:       u64,
+    /// Frames a full queue dropped (`FullPolicy::DropOldest`) or refused
+    /// (`FullPolicy::RejectNewest`).
+    pub shed:             u64,
+    /// Connected producer and consumer sessions.
+    pub producers:        usize,
+    pub consumers:        usize,
+    /// Queues that exist, frames waiting in them, and frames delivered
+    /// but not yet settled.
+    pub queues:           usize,
+    pub in_queue:         usize,
+    pub outstanding:      usize,
+    /// Queue dwell times of the frames consumers took.
+    pub dwell:            Dwell,
+}
+
+/// What `Orchestrator::run` takes over when it starts.
+struct Start {
+    /// The control listeners, in the order of `Orchestrator::addrs`.
+    listeners:  Vec<Listener>,
+    ws:         Option<Listener>,
+    statsd:     Option<Statsd>,
+    scaler:     Option<Autoscaler>,
+    scale_up:   Option<Hook>,
+    scale_down: Option<Hook>,
+    on_idle:    Option<String>,
+    /// A `dlq=file:` dead-letter file, closed when `run` returns.
+    dead_letters: Option<SharedSink>,
+}
+
+/// An orchestrator: its queues, and bound control listeners that `run`
+/// serves. Share it (e.g. in an `Arc`) to reach `drain`, `shutdown` and
+/// `stats` while `run` blocks another thread.
+pub struct Orchestrator {
+    addrs:        Vec<Addr>,
+    ws_addr:      Option<Addr>,
+    capacity:     usize,
+    stats_every:  Duration,
+    stats_format: StatsFormat,
+    queues:       Arc<Queues>,
+    stats:        Arc<Stats>,
+    state:        Arc<AtomicU8>,
+    access:       Arc<Access>,
+    rules:        Arc<RwLock<SessionRules>>,
+    /// The configuration file `reload` reads, and what it said last time.
+    config:       Mutex<Option<(PathBuf, Config)>>,
+    /// The log level from before the configuration file set one.
+    log_level:    LevelFilter,
+    assign_ttl:   Duration,
+    tomb_ttl:     Duration,
+    /// Whether to report to systemd (`OrchestratorOptions::systemd`).
+    systemd:      bool,
+    start:        Mutex<Option<Start>>,
+}
+
+/// The settings `Orchestrator::reload` can change under running sessions,
+/// from the configuration file where it has them and the environment
+/// where it doesn't. `capacity` is the default queue's, which the file's
+/// `[queues]` tables override for theirs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Tunables {
+    capacities:     Capacities,
+    producer_limit: ProducerLimit,
+    max_sessions:   Option<usize>,
+    timeouts:       SessionTimeouts,
+    log_level:      Option<LevelFilter>,
+}
+
+impl Tunables {
+    fn resolve(capacity: usize, config: Option<&(PathBuf, Config)>) -> io::Result<Self> {
+        let cfg = config.map(|(_, cfg)| cfg);
+        let capacities = Capacities {
+            default: capacity,
+            queues: cfg.map(|c| c.queues.iter()
+                .filter_map(|(name, q)| Some((name.clone(), q.capacity?)))
+                .collect()).unwrap_or_default(),
+        };
+        let producer_limit = match (config, env::var("QPIPE_PRODUCER_LIMIT")) {
+            (Some((path, Config { limits, .. })), _) if limits.producer.is_some() => {
+                ProducerLimit::parse(limits.producer.as_deref().unwrap_or_default())
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: [limits] producer: {e}", path.display())))?
+            }
+            (_, Ok(spec)) => ProducerLimit::parse(&spec)?,
+            (_, Err(_)) => ProducerLimit::default(),
+        };
+        let max_sessions = match cfg.and_then(|c| c.limits.max_sessions) {
+            Some(n) => Some(n),
+            None => env::var("QPIPE_MAX_SESSIONS").ok().and_then(|s| s.parse::<usize>().ok()),
+        }.filter(|&n| n > 0);
+        let timeouts = match (config, env::var("QPIPE_SESSION_TIMEOUTS")) {
+            (Some((path, Config { limits, .. })), _) if limits.timeouts.is_some() => {
+                SessionTimeouts::parse(limits.timeouts.as_deref().unwrap_or_default())
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: [limits] timeouts: {e}", path.display())))?
+            }
+            (_, Ok(spec)) => SessionTimeouts::parse(&spec)?,
+            (_, Err(_)) => SessionTimeouts::default(),
+        };
+        Ok(Self {
+            capacities, producer_limit, max_sessions, timeouts, log_level: cfg.and_then(|c| c.log_level),
+        })
+    }
+}
+
+impl Orchestrator {
+    /// Bind `addr` (`host:port`, or `unix://<path>`) with default options.
+    pub fn bind(addr: &str) -> io::Result<Self> {
+        Self::bind_with(addr, &OrchestratorOptions::default())
+    }
+
+    /// Read the QPIPE_* configuration, set up the queues and bind `addr`,
+    /// and any `OrchestratorOptions::also_listen` addresses. Bad
+    /// configuration fails here, before any client can connect.
+    pub fn bind_with(addr: &str, opts: &OrchestratorOptions) -> io::Result<Self> {
+        let capacity = opts.capacity;
+        let tunables = Tunables::resolve(capacity, opts.config.as_ref())?;
+        let log_level = log::max_level();
+        if let Some(level) = tunables.log_level {
+            log::set_max_level(level);
+        }
+        let policy = match env::var("QPIPE_RETRY_POLICY") {
+            Ok(spec) => RetryPolicy::parse(&spec)?,
+            Err(_) => RetryPolicy::default(),
+        };
+
+        // Priority aging: one level gained per QPIPE_PRIORITY_AGING waited
+        // (e.g. "10s"); unset serves strictly by priority.
+        let aging = match env::var("QPIPE_PRIORITY_AGING") {
+            Ok(v) => Some(parse_duration(&v).map_err(|e| io::Error::new(
+                io::ErrorKind::InvalidInput, format!("QPIPE_PRIORITY_AGING: {e}"),
+            ))?).filter(|d| !d.is_zero()),
+            Err(_) => None,
+        };
+
+        // Messages requiring capability tags no consumer advertises:
+        // QPIPE_TAG_FALLBACK=wait (default) | any | dead-letter.
+        let tag_fallback = match env::var("QPIPE_TAG_FALLBACK") {
+            Ok(v) => TagFallback::parse(&v).map_err(|e| io::Error::new(
+                io::ErrorKind::InvalidInput, format!("QPIPE_TAG_FALLBACK: {e}"),
+            ))?,
+            Err(_) => TagFallback::default(),
+        };
+
+        // QPIPE_DISPATCH=free (default) | round-robin | least-outstanding.
+        let dispatch = match env::var("QPIPE_DISPATCH") {
+            Ok(v) => Dispatch::parse(&v).map_err(|e| io::Error::new(
+                io::ErrorKind::InvalidInput, format!("QPIPE_DISPATCH: {e}"),
+            ))?,
+            Err(_) => Dispatch::default(),
+        };
+
+        let egress = match (&opts.config, env::var("QPIPE_EGRESS_LIMIT")) {
+            (Some((path, Config { limits, .. })), _) if limits.egress.is_some() => {
+                EgressLimit::parse(limits.egress.as_deref().unwrap_or_default())
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: [limits] egress: {e}", path.display())))?
+            }
+            (_, Ok(spec)) => EgressLimit::parse(&spec)?,
+            (_, Err(_)) => EgressLimit::default(),
+        };
+        if egress != EgressLimit::default() {
+            info!("egress limits (bytes/s): {:?}", egress);
+        }
+
+        // Autoscaling: QPIPE_AUTOSCALE sets the thresholds, the hooks say
+        // who to tell (a command, or an http:// webhook).
+        let hook = |var: &str| match env::var(var).ok().filter(|v| !v.is_empty()) {
+            Some(v) => Hook::parse(&v).map(Some).map_err(|e| io::Error::new(
+                io::ErrorKind::InvalidInput, format!("{var}: {e}"),
+            )),
+            None => Ok(None),
+        };
+        let (scale_up, scale_down) = (hook("QPIPE_SCALE_UP_HOOK")?, hook("QPIPE_SCALE_DOWN_HOOK")?);
+        let scaler = match env::var("QPIPE_AUTOSCALE") {
+            Ok(spec) => {
+                let policy = AutoscalePolicy::parse(&spec)?;
+                info!("autoscaling: {:?}", policy);
+                Some(Autoscaler::new(policy))
+            }
+            Err(_) if scale_up.is_some() || scale_down.is_some() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "QPIPE_SCALE_UP_HOOK/QPIPE_SCALE_DOWN_HOOK need QPIPE_AUTOSCALE",
+                ));
+            }
+            Err(_) => None,
+        };
+
+        let stats  = Arc::new(Stats::default());
+        // Encryption at rest for everything the orchestrator writes to
+        // disk: QPIPE_AT_REST_KEY=file:<path> | env:<VAR> | cmd:<command>.
+        let at_rest_key = match env::var("QPIPE_AT_REST_KEY") {
+            Ok(spec) => Some(Key::load(&spec).map_err(|e| io::Error::new(
+                e.kind(), format!("QPIPE_AT_REST_KEY: {e}"),
+            ))?),
+            Err(_) => None,
+        };
+        let snapshot = opts.import.as_ref()
+            .map(|path| read_snapshot(path, at_rest_key.as_ref()).map_err(|e| io::Error::new(
+                e.kind(), format!("{}: {e}", path.display()),
+            )))
+            .transpose()?;
+        let dead_letters = match &policy.dead_letter {
+            DeadLetter::File(path) => {
+                let file = BufWriter::new(
+                    OpenOptions::new().create(true).append(true).open(path)?
+                );
+                Some(SharedSink::new(match &at_rest_key {
+                    Some(key) => {
+                        info!("dead letters in {} are encrypted at rest", path.display());
+                        Box::new(SealedWriter::new(file, key.clone()))
+                    }
+                    None => Box::new(file),
+                }))
+            }
+            _ => None,
+        };
+        // Audit log: one JSON line per accepted data frame, appended when
+        // it settles. QPIPE_AUDIT_LOG=<path>.
+        let audit = match env::var_os("QPIPE_AUDIT_LOG").filter(|p| !p.is_empty()) {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                info!("auditing every message to {}", PathBuf::from(&path).display());
+                Some(SharedSink::new(Box::new(BufWriter::new(file))))
+            }
+            None => None,
+        };
+        // Tracing (see `otel`): spans of traced messages, one OTLP/JSON
+        // line each. QPIPE_OTEL_SPANS=<path>.
+        let spans_to = env::var_os("QPIPE_OTEL_SPANS").filter(|p| !p.is_empty());
+        #[cfg(not(feature = "otel"))]
+        if spans_to.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported, "QPIPE_OTEL_SPANS: built without the `otel` feature",
+            ));
+        }
+        #[cfg(feature = "otel")]
+        let spans = match spans_to {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                info!("recording spans of traced messages to {}", PathBuf::from(&path).display());
+                Some(spawn_line_writer("qpipe-spans", "span log", Box::new(BufWriter::new(file))))
+            }
+            None => None,
+        };
+        // Disk overflow: frames past a queue's capacity are spilled under
+        // QPIPE_OVERFLOW_DIR=<dir> instead of blocking producers, one
+        // subdirectory per queue.
+        let overflow_dir = env::var_os("QPIPE_OVERFLOW_DIR").filter(|d| !d.is_empty()).map(PathBuf::from);
+        if let Some(dir) = &overflow_dir {
+            info!("queues overflow to {}", dir.display());
+        }
+        // With dlq=queue:<name>, every other queue dead-letters onto that
+        // one, whose router only exists once the queues do.
+        let dead_queue = match &policy.dead_letter {
+            DeadLetter::Queue(name) => Some((name.clone(), Arc::new(OnceLock::new()))),
+            _ => None,
+        };
+        let wal_dir = opts.wal_dir.clone();
+        if let Some(dir) = &wal_dir {
+            info!("queues are journaled to {}", dir.display());
+        }
+        // A standby copies its primary's logs until it takes over, then
+        // starts on them like any orchestrator on its own.
+        if let Some(primary) = &opts.standby_of {
+            let Some(dir) = &wal_dir else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput, "a standby needs a write-ahead log directory (--wal-dir)",
+                ));
+            };
+            info!("standing by for {}", primary);
+            sd_notify(opts.systemd, &format!("STATUS=standing by for {primary}"));
+            std::fs::create_dir_all(dir)?;
+            replica::follow(primary, dir, opts.failover_after, opts.auth_key.as_ref())?;
+        }
+        // Standbys may follow whatever is journaled.
+        let feed = wal_dir.as_ref().map(|_| Arc::new(Feed::default()));
+        let shards = opts.shards;
+        if shards > 1 {
+            info!("queues are split into {} shards", shards);
+        }
+        // Pub/sub: every consumer of a fan-out queue gets every frame.
+        let fanout = env::var("QPIPE_FANOUT_QUEUES").unwrap_or_default();
+        let fanout_queues: BTreeSet<String> = opts.config.iter()
+            .flat_map(|(_, cfg)| cfg.queues.iter().filter(|(_, q)| q.fanout).map(|(name, _)| name.clone()))
+            .collect();
+        // Delivery policies: a queue's own, or the orchestrator's.
+        let delivery = opts.delivery;
+        let delivery_queues: BTreeMap<String, DeliveryPolicy> = opts.config.iter()
+            .flat_map(|(_, cfg)| cfg.queues.iter().filter_map(|(name, q)| Some((name.clone(), q.delivery?))))
+            .collect();
+        if let Some(policy) = delivery {
+            info!("queues deliver {} unless configured otherwise", policy);
+        }
+        let dedup_window = opts.dedup_window;
+        // What full queues do: a queue's own policy, or the orchestrator's.
+        let when_full = opts.when_full;
+        let full_queues: BTreeMap<String, FullPolicy> = opts.config.iter()
+            .flat_map(|(_, cfg)| cfg.queues.iter().filter_map(|(name, q)| Some((name.clone(), q.when_full?))))
+            .collect();
+        if when_full != FullPolicy::Block {
+            info!("full-queue policy {} unless configured otherwise", when_full);
+        }
+        let queues = {
+            let (stats, policy) = (stats.clone(), policy.clone());
+            let (dead_letters, dead_queue) = (dead_letters.clone(), dead_queue.clone());
+            let (wal_dir, standbys) = (wal_dir.clone(), feed.clone());
+            Arc::new(Queues::new(tunables.capacities.clone(), move |name, capacity| {
+                // Fan-out queues hold nothing themselves: no overflow, no
+                // journal. Reply queues are short-lived and plain.
+                let reply = name.starts_with(REPLY_QUEUE_PREFIX);
+                let fanout = !reply && (fans_out(&fanout, name) || fanout_queues.contains(name));
+                if fanout {
+                    info!("queue {:?} fans out to every consumer", name);
+                }
+                // A sharded queue's shards each overflow to a subdirectory
+                // of the queue's own.
+                let shards = if fanout || reply { 1 } else { shards };
+                // Reply queues wait for room, like their requesters.
+                let when_full = full_queues.get(name).copied().unwrap_or(when_full);
+                let when_full = if reply { FullPolicy::Block } else { when_full };
+                let build = |capacity: usize, overflow: Option<PathBuf>| -> io::Result<Router> {
+                    let overflow = match overflow.filter(|_| !fanout) {
+                        Some(dir) => {
+                            let (ov, stale) = Overflow::open(&dir, at_rest_key.clone())?;
+                            if stale > 0 {
+                                warn!("discarded {} overflow segment(s) left by an earlier run", stale);
+                            }
+                            Some(ov)
+                        }
+                        None => None,
+                    };
+                    let mut router = Router::with_policy(capacity, stats.clone(), policy.clone())
+                        .with_priority_aging(aging)
+                        .with_tag_fallback(tag_fallback)
+                        .with_dispatch(dispatch)
+                        .with_egress_limit(egress)
+                        .with_overflow(overflow)
+                        .with_when_full(when_full);
+                    if fanout {
+                        router = router.with_fanout();
+                    }
+                    if let Some(sink) = &dead_letters {
+                        router.set_dead_letter_sink(Box::new(sink.clone()));
+                    }
+                    match &dead_queue {
+                        Some((dlq, _)) if dlq == name => {}
+                        Some((_, cell)) => router.set_dead_letter_queue(cell.clone()),
+                        None => {}
+                    }
+                    if let Some(sink) = &audit {
+                        router.set_audit_sink(Box::new(sink.clone()));
+                    }
+                    Ok(router)
+                };
+                if matches!(&dead_queue, Some((dlq, _)) if dlq == name) {
+                    info!("dead letters go to queue {:?}; its own are dropped", name);
+                }
+                let dir = overflow_dir.as_ref().filter(|_| !reply).map(|d| d.join(queue_dir(name)));
+                let router = if shards > 1 {
+                    let members = (0..shards)
+                        .map(|i| build(
+                            capacity.div_ceil(shards),
+                            dir.as_ref().map(|d| d.join(format!("shard-{i}"))),
+                        ))
+                        .collect::<io::Result<Vec<_>>>()?;
+                    Router::with_policy(capacity, stats.clone(), policy.clone())
+                        .with_egress_limit(egress)
+                        .with_shards(members)
+                } else {
+                    build(capacity, dir)?
+                };
+                // Reply queues are left to their requesters, which ack.
+                let delivery = delivery_queues.get(name).copied().or(delivery).filter(|_| !reply);
+                let router = router.with_delivery(delivery).with_dedup(dedup_window);
+                #[cfg(feature = "otel")]
+                if let Some(lines) = &spans {
+                    router.set_tracer(lines.clone(), name);
+                    for shard in router.shard_routers() {
+                        shard.set_tracer(lines.clone(), name);
+                    }
+                }
+                if let Some(dir) = wal_dir.as_ref().filter(|_| !fanout && !reply) {
+                    let (wal, frames, broken) = Wal::open(&dir.join(queue_dir(name)), at_rest_key.clone())?;
+                    if !frames.is_empty() {
+                        info!("replaying {} frame(s) into queue {:?} from the write-ahead log", frames.len(), name);
+                    }
+                    if broken > 0 {
+                        warn!("dropped {} frame(s) of chunked messages that can no longer complete", broken);
+                    }
+                    router.recover(wal, frames);
+                    if let (Some(feed), Some(wal)) = (&standbys, router.wal()) {
+                        feed.attach(&queue_dir(name), &wal);
+                    }
+                }
+                Ok(router)
+            })?.with_feed(feed))
+        };
+        // Named queues come back with the frames journaled to them.
+        if let Some(dir) = &wal_dir {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let journaled = std::fs::read_dir(entry.path())?
+                    .any(|f| f.is_ok_and(|f| f.path().extension().is_some_and(|e| e == "wal")));
+                if let Some(name) = queue_of_dir(&entry.file_name().to_string_lossy())
+                    && journaled
+                {
+                    queues.get(&name)?;
+                }
+            }
+        }
+        if let Some((name, cell)) = &dead_queue {
+            cell.set(queues.get(name)?).ok();
+        }
+        if let (Some(frames), Some(path)) = (snapshot, &opts.import) {
+            info!("importing {} frame(s) from {}", frames.len(), path.display());
+            import_to(&queues, frames).map_err(|e| io::Error::new(
+                e.kind(), format!("{}: {e}", path.display()),
+            ))?;
+        }
+        debug!("retry policy for ack-mode consumers: {:?}", policy);
+        let assign_ttl = env_duration_secs(
+            "QPIPE_ASSIGN_TTL_SECS", DEFAULT_ASSIGN_TTL_SECS
+        );
+        let tomb_ttl = env_duration_secs(
+            "QPIPE_TOMBSTONE_TTL_SECS", DEFAULT_TOMBSTONE_TTL_SECS
+        );
+
+        // The reporter thread can also feed a StatsD collector.
+        let statsd = match env::var("QPIPE_STATSD").ok().filter(|t| !t.is_empty()) {
+            Some(target) => {
+                let prefix = env::var("QPIPE_STATSD_PREFIX").unwrap_or_else(|_| "qpipe".into());
+                let sink = Statsd::connect(&target, &prefix).map_err(|e| io::Error::new(
+                    e.kind(), format!("QPIPE_STATSD: {target}: {e}"),
+                ))?;
+                info!("sending stats to statsd at {}", target);
+                Some(sink)
+            }
+            None => None,
+        };
+        // Authentication: QPIPE_USERS=<path> for SCRAM (see Users), and
+        // with the gssapi feature QPIPE_PRINCIPALS=<path> for Kerberos.
+        let mut access = Access { key: opts.auth_key.clone(), ..Access::default() };
+        if access.key.is_some() {
+            info!("sessions must prove the auth key");
+        }
+        if let Some(path) = env::var_os("QPIPE_USERS").filter(|p| !p.is_empty()) {
+            let users = Users::load(&PathBuf::from(&path))?;
+            info!("{} users may authenticate with SCRAM", users.entries.len());
+            access.users = Some(users);
+        }
+        if let Some(path) = env::var_os("QPIPE_PRINCIPALS").filter(|p| !p.is_empty()) {
+            #[cfg(feature = "gssapi")]
+            {
+                let principals = Principals::load(&PathBuf::from(&path))?;
+                info!("{} principals may authenticate with Kerberos", principals.entries.len());
+                access.principals = Some(principals);
+            }
+            #[cfg(not(feature = "gssapi"))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "QPIPE_PRINCIPALS={}: this orchestrator was built without the gssapi feature",
+                    PathBuf::from(&path).display(),
+                ),
+            ));
+        }
+        if access.required() {
+            info!("anonymous sessions are refused");
+        }
+        let access = Arc::new(access);
+
+        // Consumer sessions cost a thread each (two in ack mode), producers
+        // one only while they send, or always off Linux;
+        // QPIPE_MAX_SESSIONS=<n> caps them. Unlimited by default.
+        let max_sessions = tunables.max_sessions;
+        if let Some(max) = max_sessions {
+            info!("admitting at most {} producer/consumer sessions", max);
+        }
+        let (max_producers, max_consumers) = (opts.max_producers, opts.max_consumers);
+        if let Some(max) = max_producers {
+            info!("admitting at most {} producer sessions", max);
+        }
+        if let Some(max) = max_consumers {
+            info!("admitting at most {} consumer sessions", max);
+        }
+        let heartbeat = match env::var("QPIPE_HEARTBEAT") {
+            Ok(v) => Some(parse_heartbeat(&v).map_err(|e| io::Error::new(
+                io::ErrorKind::InvalidInput, format!("QPIPE_HEARTBEAT: {e}"),
+            ))?),
+            Err(_) => None,
+        };
+        if let Some(hb) = heartbeat {
+            info!("heartbeat every {:?}, timing out after {:?}", hb.interval, hb.timeout);
+        }
+        // QPIPE_MIN_PROTOCOL=<n> turns away producers and consumers that
+        // speak only older protocol versions; all are welcome by default.
+        let min_protocol = match env::var("QPIPE_MIN_PROTOCOL") {
+            Ok(v) => v.parse().ok()
+                .filter(|n| (PROTOCOL_V1..=PROTOCOL_VERSION).contains(n))
+                .ok_or_else(|| io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("QPIPE_MIN_PROTOCOL={v:?}: expected {PROTOCOL_V1}..={PROTOCOL_VERSION}"),
+                ))?,
+            Err(_) => PROTOCOL_V1,
+        };
+        if min_protocol > PROTOCOL_V1 {
+            info!("admitting clients of protocol {} and newer", min_protocol);
+        }
+        let producer_limit = tunables.producer_limit;
+        if producer_limit != ProducerLimit::default() {
+            info!("producer limits (per second): {:?}", producer_limit);
+        }
+        let single_port = opts.single_port;
+        if single_port {
+            info!("serving sessions over their control connection only");
+        }
+        let max_frame = opts.max_frame_bytes;
+        if !(MIN_FRAME_LIMIT..=MAX_FRAME_SIZE).contains(&max_frame) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("max frame size {max_frame}: expected {MIN_FRAME_LIMIT}..={MAX_FRAME_SIZE} bytes"),
+            ));
+        }
+        if max_frame < MAX_FRAME_SIZE {
+            info!("accepting frames of up to {} bytes", max_frame);
+        }
+        let timeouts = tunables.timeouts;
+        if timeouts != SessionTimeouts::default() {
+            info!("session timeouts: {:?}", timeouts);
+        }
+        let token = match env::var("QPIPE_SESSION_TOKEN") {
+            Ok(spec) => TokenPolicy::parse(&spec)?,
+            Err(_) => TokenPolicy::default(),
+        };
+        if token != TokenPolicy::default() {
+            info!("session tokens: {:?}", token);
+        }
+
+        // Optional notification when a campaign completes: fires on every
+        // busy -> idle transition (see Router::is_idle).
+        let on_idle = env::var("QPIPE_ON_IDLE_CMD").ok().filter(|c| !c.is_empty());
+
+        #[cfg(unix)]
+        let activated = if opts.systemd { systemd::listen_fds()? } else { Vec::new() };
+        #[cfg(not(unix))]
+        let activated: Vec<(String, Listener)> = Vec::new();
+        let (listeners, ws) = if activated.is_empty() {
+            let addrs = std::iter::once(addr).chain(opts.also_listen.iter().map(String::as_str))
+                .map(Addr::resolve)
+                .collect::<io::Result<Vec<_>>>()?;
+            let ws = match &opts.ws_listen {
+                #[cfg(feature = "ws")]
+                Some(a) => Some(Listener::bind(&Addr::resolve(&format!("{}{a}", crate::transport::WS_SCHEME))?)?),
+                _ => None,
+            };
+            (Listener::bind_all(&addrs)?, ws)
+        } else {
+            info!("listening on {} socket(s) passed by systemd", activated.len());
+            let (ws, listeners): (Vec<_>, Vec<_>) = activated.into_iter()
+                .map(|(_, l)| l)
+                .partition(Listener::is_ws);
+            if listeners.is_empty() || ws.len() > 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "systemd passed no control socket, or more than one named ws",
+                ));
+            }
+            (listeners, ws.into_iter().next())
+        };
+        for l in listeners.iter().chain(&ws) {
+            l.set_nonblocking(true)?;
+        }
+        Ok(Self {
+            addrs: listeners.iter().map(Listener::local_addr).collect::<io::Result<_>>()?,
+            ws_addr: ws.as_ref().map(Listener::local_addr).transpose()?,
+            capacity,
+            stats_every: opts.stats_every,
+            stats_format: opts.stats_format,
+            queues,
+            stats,
+            state: Arc::new(AtomicU8::new(STATE_RUNNING)),
+            access,
+            rules: Arc::new(RwLock::new(SessionRules {
+                max_sessions, max_producers, max_consumers, heartbeat, min_protocol, single_port,
+                producer_limit, max_frame, timeouts, token,
+            })),
+            config: Mutex::new(opts.config.clone()),
+            log_level,
+            assign_ttl,
+            tomb_ttl,
+            systemd: opts.systemd,
+            start: Mutex::new(Some(Start { listeners, ws, statsd, scaler, scale_up, scale_down, on_idle, dead_letters })),
+        })
+    }
+
+    /// The address the (first) control listener is bound to; with port 0
+    /// in `bind`, the one the system picked.
+    pub fn local_addr(&self) -> &Addr {
+        &self.addrs[0]
+    }
+
+    /// Every address a control listener is bound to: `local_addr` first,
+    /// then the `OrchestratorOptions::also_listen` ones.
+    pub fn local_addrs(&self) -> &[Addr] {
+        &self.addrs
+    }
+
+    /// The address of the WebSocket listener, if there is one
+    /// (`OrchestratorOptions::ws_listen`).
+    pub fn ws_addr(&self) -> Option<&Addr> {
+        self.ws_addr.as_ref()
+    }
+
+    /// Serve sessions until a drain or shutdown completes, requested by
+    /// `drain`, `shutdown`, an admin client or a signal (see
+    /// `handle_signals`). Consumers then see their connections closed.
+    /// An orchestrator runs once.
+    pub fn run(&self) -> io::Result<()> {
+        let Some(start) = self.start.lock().unwrap().take() else {
+            return Err(io::Error::other("orchestrator has already run"));
+        };
+        let Start { listeners, ws, statsd, mut scaler, scale_up, scale_down, on_idle, dead_letters } = start;
+        let (queues, stats, state) = (&self.queues, &self.stats, &self.state);
+        let (assign_ttl, tomb_ttl) = (self.assign_ttl, self.tomb_ttl);
+
+        // Reporter thread, optionally also feeding a StatsD collector.
+        {
+            let stats  = stats.clone();
+            let queues = queues.clone();
+            let state  = state.clone();
+            let every  = self.stats_every;
+            let format = self.stats_format;
+            thread::spawn(move || stats_reporter(stats, queues, state, every, format, statsd));
+        }
+
+        for addr in &self.addrs {
+            info!(
+                "Orchestrator control listening on {} (queue capacity {})",
+                addr, self.capacity
+            );
+        }
+        if let Some(addr) = &self.ws_addr {
+            info!("Orchestrator WebSocket listening on {}", addr);
+        }
+
+        // Signals the accept loop to stop. Set after drain completes so any
+        // late admin commands are still served until the very last moment.
+        let exit = Arc::new(AtomicBool::new(false));
+
+        // Accept loops run for the entire lifetime of the orchestrator.
+        // While the orchestrator is draining or shutting down
+        // it still admits admin requests (health/drain/shutdown) and
+        // consumers but rejects new producers — see handle_control.
+        let spawn_accept = |listener: Listener| {
+            let queues = queues.clone();
+            let stats  = stats.clone();
+            let state  = state.clone();
+            let exit   = exit.clone();
+            let access = self.access.clone();
+            let rules  = self.rules.clone();
+            thread::spawn(move || accept_loop(listener, queues, stats, state, exit, access, rules))
+        };
+        let listeners: Vec<Listener> = listeners.into_iter().chain(ws).collect();
+        // On Linux the reactor waits on the sockets: connections cost a
+        // thread only while they have something to read. Other transports
+        // get accept loops of their own, and a thread per session.
+        #[cfg(target_os = "linux")]
+        let (listeners, reactor_closed) = {
+            let (watched, others): (Vec<_>, Vec<_>) = listeners.into_iter()
+                .partition(|l| l.poll_fd().is_some());
+            let reactor = Arc::new(Reactor::new(SESSION_STACK)?);
+            let serving = Serving {
+                queues: queues.clone(),
+                stats:  stats.clone(),
+                state:  state.clone(),
+                access: self.access.clone(),
+                rules:  self.rules.clone(),
+            };
+            let accepts = watched.into_iter()
+                .map(|listener| {
+                    let fd = listener.poll_fd().expect("partitioned on it");
+                    let (reactor, serving) = (reactor.clone(), serving.clone());
+                    let accept: Box<dyn FnMut() + Send> = Box::new(move || accept_ready(&reactor, &listener, &serving));
+                    (fd, accept)
+                })
+                .collect();
+            let (closed, on_closed) = mpsc::channel();
+            let exit = exit.clone();
+            thread::spawn(move || reactor.run(accepts, &exit, move || { let _ = closed.send(()); }));
+            (others, on_closed)
+        };
+        let accept_handles: Vec<_> = listeners.into_iter().map(spawn_accept).collect();
+        let serving = self.addrs.iter().chain(&self.ws_addr).map(Addr::to_string).collect::<Vec<_>>();
+        sd_notify(self.systemd, &format!("READY=1\nSTATUS=serving on {}", serving.join(", ")));
+        let mut was_idle = true;
+
+        // Block until something flips the state out of RUNNING, expiring
+        // stale multi-frame bookkeeping every few seconds along the way.
+        let mut last_sweep = Instant::now();
+        let mut reloads = RELOADS.load(Ordering::SeqCst);
+        while state.load(Ordering::SeqCst) == STATE_RUNNING {
+            thread::sleep(Duration::from_millis(100));
+            if SIGNALS.load(Ordering::SeqCst) > 0 {
+                info!("shutdown requested by signal");
+                state.store(STATE_SHUTTING_DOWN, Ordering::SeqCst);
+                break;
+            }
+            if RELOADS.load(Ordering::SeqCst) != reloads {
+                reloads = RELOADS.load(Ordering::SeqCst);
+                match self.reload() {
+                    Ok(()) => info!("configuration reloaded"),
+                    Err(e) => error!("reload failed, keeping the current settings: {}", e),
+                }
+            }
+            let idle = queues.is_idle();
+            if idle && !was_idle {
+                info!("queue idle: all accepted frames settled, no producers");
+                if let Some(cmd) = &on_idle {
+                    run_hook(cmd, "idle", &[]);
+                }
+            }
+            was_idle = idle;
+            if let Some(scaler) = &mut scaler {
+                let (depth, wait) = queues.backlog();
+                if let Some(event) = scaler.observe(queues.now(), depth, wait) {
+                    info!("{}: {} frame(s) waiting, oldest for {:?}", event.as_str(), depth, wait);
+                    let hook = match event {
+                        ScaleEvent::Up   => &scale_up,
+                        ScaleEvent::Down => &scale_down,
+                    };
+                    if let Some(hook) = hook {
+                        hook.fire(event, depth, wait);
+                    }
+                }
+            }
+            let expired = queues.expire_unacked();
+            if expired > 0 {
+                info!("{} unacknowledged deliveries timed out", expired);
+            }
+            if last_sweep.elapsed() >= SWEEP_EVERY {
+                let (expired, _purged) = queues.sweep(assign_ttl, tomb_ttl);
+                if expired > 0 {
+                    warn!(
+                        "expired {} stale multi-frame assignment(s); \
+                         straggler frames will be dropped",
+                        expired
+                    );
+                }
+                let expired = queues.expire_stale();
+                if expired > 0 {
+                    info!("{} queued message(s) outlived their TTL", expired);
+                }
+                last_sweep = Instant::now();
+            }
+        }
+
+        // ── Drain phase ─────────────────────────────────────────────────────
+        // While we're in here the accept loop is still running, so a
+        // follow-up `--shutdown` can land and upgrade STATE_DRAINING →
+        // STATE_SHUTTING_DOWN.
+        let drain_result = drain(queues.clone(), stats.clone(), state.clone(), assign_ttl, tomb_ttl, self.systemd);
+
+        // Stop accepting, and wait for the listeners to close.
+        exit.store(true, Ordering::SeqCst);
+        for handle in accept_handles {
+            let _ = handle.join();
+        }
+        #[cfg(target_os = "linux")]
+        let _ = reactor_closed.recv();
+        sd_notify(self.systemd, "STATUS=closing consumer connections");
+
+        // Close consumer connections from our end, so they read EOF at a
+        // frame boundary instead of a reset when the process goes.
+        queues.close_consumers();
+        let closing = Instant::now();
+        while stats.active_consumers.load(Ordering::Relaxed) > 0
+            && closing.elapsed() < CLOSE_GRACE
+            && SIGNALS.load(Ordering::SeqCst) < 2
+        {
+            thread::sleep(Duration::from_millis(20));
+        }
+        // A sealed one gets its last record (`SealedWriter`).
+        if let Some(sink) = dead_letters {
+            sink.close();
+        }
+        drain_result
+    }
+
+    /// Stop admitting producers and finish once every queue has drained,
+    /// like `orchestrator --drain`; no timeout.
+    pub fn drain(&self) {
+        // Doesn't downgrade a shutdown already under way.
+        let _ = self.state.compare_exchange(
+            STATE_RUNNING, STATE_DRAINING,
+            Ordering::SeqCst, Ordering::SeqCst
+        );
+    }
+
+    /// Stop admitting producers and finish once every queue has drained,
+    /// or after QPIPE_DRAIN_TIMEOUT_SECS, like `orchestrator --shutdown`.
+    pub fn shutdown(&self) {
+        self.state.store(STATE_SHUTTING_DOWN, Ordering::SeqCst);
+    }
+
+    /// Read the configuration file again (`OrchestratorOptions::config`)
+    /// and apply what can change under running sessions: queue capacities
+    /// at once, producer limits and the session cap to sessions opened
+    /// from now on, and the log level. Anything else it changes waits for
+    /// a restart, with a warning. A file that no longer parses changes
+    /// nothing. NotFound if the orchestrator was set up without one.
+    pub fn reload(&self) -> io::Result<()> {
+        let mut current = self.config.lock().unwrap();
+        let Some((path, old)) = current.as_ref() else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no configuration file to reload"));
+        };
+        let config = (path.clone(), Config::load(path)?);
+        let new = &config.1;
+        let tunables = Tunables::resolve(new.capacity.unwrap_or(self.capacity), Some(&config))?;
+
+        // What only a restart applies, with the reloadable settings cleared.
+        let fixed = |c: &Config| Config {
+            capacity:  None,
+            log_level: None,
+            limits:    Limits { egress: c.limits.egress.clone(), ..Default::default() },
+            queues:    c.queues.iter()
+                .filter(|(_, q)| q.fanout || q.delivery.is_some())
+                .map(|(name, q)| (name.clone(), QueueConfig { capacity: None, ..q.clone() }))
+                .collect(),
+            ..c.clone()
+        };
+        if fixed(old) != fixed(new) {
+            warn!("{}: some changes only apply after a restart", path.display());
+        }
+
+        self.queues.set_capacities(tunables.capacities);
+        {
+            let mut rules = self.rules.write().unwrap();
+            if rules.producer_limit != tunables.producer_limit {
+                info!("producer limits (per second) for new sessions: {:?}", tunables.producer_limit);
+            }
+            if rules.max_sessions != tunables.max_sessions {
+                info!("session cap now {:?}", tunables.max_sessions);
+            }
+            if rules.timeouts != tunables.timeouts {
+                info!("session timeouts for new sessions: {:?}", tunables.timeouts);
+            }
+            rules.producer_limit = tunables.producer_limit;
+            rules.max_sessions = tunables.max_sessions;
+            rules.timeouts = tunables.timeouts;
+        }
+        log::set_max_level(tunables.log_level.unwrap_or(self.log_level));
+        *current = Some(config);
+        Ok(())
+    }
+
+    /// Treat SIGTERM and SIGINT as a shutdown request; a second signal
+    /// skips what is left of the drain. SIGHUP reloads the configuration
+    /// file (see `reload`). The handlers are process-wide, so this is for
+    /// a binary that owns its process, as `orchestrator` does.
+    #[cfg(unix)]
+    pub fn handle_signals(&self) {
+        use std::ffi::c_int;
+        const SIGHUP: c_int = 1;
+        const SIGINT: c_int = 2;
+        const SIGTERM: c_int = 15;
+        unsafe extern "C" {
+            fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+        }
+        // SAFETY: the handlers only touch atomics, which is async-signal-safe.
+        unsafe {
+            signal(SIGINT, on_signal);
+            signal(SIGTERM, on_signal);
+            signal(SIGHUP, on_reload);
+        }
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot(&self.queues)
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(_signum: std::ffi::c_int) {
+    SIGNALS.fetch_add(1, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn on_reload(_signum: std::ffi::c_int) {
+    RELOADS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Start a session thread. Failing to (thread or memory limits) is an
+/// error for that session only; the accept loop carries on.
+fn spawn_session(name: &str, f: impl FnOnce() + Send + 'static) -> io::Result<()> {
+    thread::Builder::new()
+        .name(name.into())
+        .stack_size(SESSION_STACK)
+        .spawn(f)
+        .map(drop)
+}
+
+/// What `handle_control` applies to every producer and consumer session.
+#[derive(Debug, Clone, Copy, Default)]
+struct SessionRules {
+    /// QPIPE_MAX_SESSIONS: how many may be open at once.
+    max_sessions: Option<usize>,
+    /// `--max-producers` and `--max-consumers`: how many of each.
+    max_producers: Option<usize>,
+    max_consumers: Option<usize>,
+    /// QPIPE_HEARTBEAT: imposed on clients asking for a heartbeat.
+    heartbeat:    Option<Heartbeat>,
+    /// QPIPE_MIN_PROTOCOL: the oldest protocol version admitted.
+    min_protocol: u8,
+    /// `--single-port`: no data ports; sessions must ask for
+    /// `OPT_SINGLE_PORT`.
+    single_port:  bool,
+    /// QPIPE_PRODUCER_LIMIT: caps on each producer connection.
+    producer_limit: ProducerLimit,
+    /// `--max-frame-bytes`: the longest frame body producers may send,
+    /// advertised to clients that ask (`OPT_MAX_FRAME`).
+    max_frame: usize,
+    /// QPIPE_SESSION_TIMEOUTS: when a stalled or idle session is dropped.
+    timeouts:  SessionTimeouts,
+    /// QPIPE_SESSION_TOKEN: when a session's token is no longer taken.
+    token:     TokenPolicy,
+}
+
+fn accept_loop(
+            listener: Listener,
+            queues:   Arc<Queues>,
+            stats:    Arc<Stats>,
+            state:    Arc<AtomicU8>,
+            exit:     Arc<AtomicBool>,
+            access:   Arc<Access>,
+            rules:    Arc<RwLock<SessionRules>>,
+        ) {
+    let ws = listener.is_ws();
+    while !exit.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                debug!("Spawning handler thread");
+                // A session keeps the rules it was admitted under.
+                let rules  = *rules.read().unwrap();
+                let queues = queues.clone();
+                let stats  = stats.clone();
+                let state  = state.clone();
+                let access = access.clone();
+                let client = peer.clone();
+                let spawned = spawn_session("qpipe-session", move || {
+                    let stream = if ws {
+                        match stream.upgrade_ws() {
+                            Ok(s) => s,
+                            Err(e) => return warn!("WebSocket handshake with {} failed: '{}'", client, e),
+                        }
+                    } else {
+                        stream
+                    };
+                    if let Err(e) = serve_control(stream, queues, stats, state, &access, rules) {
+                        warn!("Session error: '{}'", e);
+                    }
+                    debug!("Handler thread done");
+                });
+                // The connection closes with the unstarted closure.
+                if let Err(e) = spawned {
+                    warn!("dropping connection from {}: cannot start a session thread: {}", peer, e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => warn!("Control accept error: '{}'", e),
+        }
+    }
+}
+
+/// What serving a connection from the reactor takes.
+#[cfg(target_os = "linux")]
+#[derive(Clone)]
+struct Serving {
+    queues: Arc<Queues>,
+    stats:  Arc<Stats>,
+    state:  Arc<AtomicU8>,
+    access: Arc<Access>,
+    rules:  Arc<RwLock<SessionRules>>,
+}
+
+/// Accept what is waiting on `listener`, and park each connection until its
+/// client has written.
+#[cfg(target_os = "linux")]
+fn accept_ready(reactor: &Arc<Reactor>, listener: &Listener, serving: &Serving) {
+    let ws = listener.is_ws();
+    loop {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                // A session keeps the rules it was admitted under.
+                let rules = *serving.rules.read().unwrap();
+                let fd = stream.poll_fd().expect("accepted from a socket");
+                let (r, serving) = (reactor.clone(), serving.clone());
+                reactor.park(fd, None, move |_| serve_ready(r, stream, peer, ws, serving, rules));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => return warn!("Control accept error: '{}'", e),
+        }
+    }
+}
+
+/// Serve a connection the reactor found readable: its handshake, then,
+/// parked until each is due, its data connection, its producer's frames or
+/// its consumer's deliveries and back-channel. No worker is held while a
+/// session waits.
+#[cfg(target_os = "linux")]
+fn serve_ready(reactor: Arc<Reactor>, stream: Stream, peer: String, ws: bool, serving: Serving, rules: SessionRules) {
+    let stream = if ws {
+        match stream.upgrade_ws() {
+            Ok(s) => s,
+            Err(e) => return warn!("WebSocket handshake with {} failed: '{}'", peer, e),
+        }
+    } else {
+        stream
+    };
+    let Serving { queues, stats, state, access, .. } = serving;
+    let admitted = match handle_control(stream, queues, stats, state, &access, rules) {
+        Ok(Some(admitted)) => admitted,
+        Ok(None) => return,
+        Err(e) => return warn!("Session error: '{}'", e),
+    };
+    let Admitted { data, token, deadline, policy, start } = admitted;
+    let fd = match &data {
+        DataConn::Control(ctrl) => ctrl.poll_fd(),
+        DataConn::Port(listener, _) => listener.poll_fd(),
+    };
+    let r = reactor.clone();
+    let connect = move |_| {
+        let started = Admitted::connect(data, &token, deadline, policy).and_then(start);
+        match started {
+            Ok(Session::Producer(producer)) => {
+                let why = if producer.stream.poll_fd().is_some() { Wake::Ready } else { Wake::Stopped };
+                serve_producer(r, producer, why);
+            }
+            Ok(Session::Consumer(consumer, reader)) => {
+                if let Some(reader) = reader {
+                    match reader.rd.poll_fd() {
+                        Some(_) => serve_acks(r.clone(), reader, Wake::Ready),
+                        None => if let Err(e) = reader.spawn() {
+                            return warn!("Session error: '{}'", e);
+                        },
+                    }
+                }
+                serve_consumer(r, consumer, Wake::Woken);
+            }
+            Err(e) => warn!("Session error: '{}'", e),
+        }
+    };
+    // Past the deadline, the wait ends in an error on its own.
+    match fd {
+        Some(fd) => reactor.park(fd, Some(deadline), connect),
+        None => connect(Wake::Stopped),
+    }
+}
+
+/// Serve a producer's frames while they come, then park it until the next
+/// arrives or it has been silent too long.
+#[cfg(target_os = "linux")]
+fn serve_producer(reactor: Arc<Reactor>, mut producer: Box<ProducerSession>, why: Wake) {
+    let flow = match why {
+        Wake::Ready => producer.serve(true),
+        Wake::Stopped => producer.serve(false),
+        Wake::TimedOut => {
+            producer.silent();
+            Ok(Flow::Closed)
+        }
+        Wake::Woken => unreachable!("producers are parked without a waker"),
+    };
+    match flow {
+        Ok(Flow::Idle) => {
+            let fd = producer.stream.poll_fd().expect("only sockets go idle");
+            let r = reactor.clone();
+            reactor.park(fd, producer.deadline(), move |why| serve_producer(r, producer, why));
+        }
+        Ok(Flow::Closed) => debug!("Stopping producer"),
+        Err(e) => {
+            debug!("Stopping producer");
+            warn!("Session error: '{}'", e);
+        }
+    }
+}
+
+/// Deliver to a consumer while its queue has frames for it, then park it
+/// until the queue wakes it, a heartbeat or its idle timeout is due, or, for
+/// a consumer without a back-channel reader, its client hangs up.
+#[cfg(target_os = "linux")]
+fn serve_consumer(reactor: Arc<Reactor>, mut consumer: Box<ConsumerHandler>, why: Wake) {
+    let flow = match why {
+        Wake::Woken => consumer.serve(true),
+        Wake::TimedOut if consumer.waited() => consumer.serve(true),
+        Wake::TimedOut => Ok(Flow::Closed),
+        // Such a client only writes to answer a delivery: this is a hangup,
+        // or it is out of turn.
+        Wake::Ready => Ok(Flow::Closed),
+        Wake::Stopped => consumer.serve(false),
+    };
+    match flow {
+        Ok(Flow::Idle) => {
+            let (epoch, deadline) = consumer.park();
+            let fd = consumer.frame_acks.is_none().then(|| consumer.stream.poll_fd()).flatten();
+            let (router, cid) = (consumer.router.clone(), consumer.cid);
+            let r = reactor.clone();
+            if let Some(waker) = reactor.park_waker(fd, deadline, move |why| serve_consumer(r, consumer, why)) {
+                router.park_consumer(cid, epoch, move || waker.wake());
+            }
+        }
+        Ok(Flow::Closed) => debug!("Stopping consumer"),
+        Err(e) => {
+            debug!("Stopping consumer");
+            warn!("Session error: '{}'", e);
+        }
+    }
+}
+
+/// Take a consumer's back-channel records while they come, then park its
+/// reader until the next arrives or the heartbeat timeout passes.
+#[cfg(target_os = "linux")]
+fn serve_acks(reactor: Arc<Reactor>, mut reader: AckReader, why: Wake) {
+    let res = match why {
+        Wake::Ready | Wake::Woken => reader.serve(true),
+        Wake::Stopped => reader.serve(false),
+        Wake::TimedOut => Err(io::Error::new(io::ErrorKind::TimedOut, "back-channel went silent")),
+    };
+    match res {
+        Ok(Flow::Idle) => {
+            let fd = reader.rd.poll_fd().expect("only sockets go idle");
+            let r = reactor.clone();
+            reactor.park(fd, reader.deadline(), move |why| serve_acks(r, reader, why));
+        }
+        res => reader.end(res),
+    }
+}
+
+fn drain(
+            queues: Arc<Queues>,
+            stats:  Arc<Stats>,
+            state:  Arc<AtomicU8>,
+            assign_ttl: Duration,
+            tomb_ttl:   Duration,
+            systemd:    bool,
+        ) -> io::Result<()> {
+    let initial_state = state.load(Ordering::SeqCst);
+    let drain_timeout = env_duration_secs(
+        "QPIPE_DRAIN_TIMEOUT_SECS", DEFAULT_DRAIN_TIMEOUT_SECS
+    );
+
+    match initial_state {
+        STATE_DRAINING => {
+            info!("entering DRAIN state — no timeout, waiting for queue to empty");
+        }
+        STATE_SHUTTING_DOWN => {
+            info!(
+                "entering SHUTDOWN state — drain timeout {:?}",
+                drain_timeout
+            );
+        }
+        _ => {} // unreachable but harmless
+    }
+    sd_notify(systemd, "STOPPING=1\nSTATUS=draining");
+
+    let drain_start = Instant::now();
+    // Set the first time we observe STATE_SHUTTING_DOWN; the timeout is
+    // measured from that point. For a pure shutdown this is the very first
+    // iteration; for drain-then-shutdown it's whenever the upgrade happens.
+    let mut shutdown_at: Option<Instant> = None;
+    let mut last_progress_log = Instant::now();
+    let mut last_sweep = Instant::now();
+
+    loop {
+        queues.expire_unacked();
+        match SIGNALS.load(Ordering::SeqCst) {
+            0 => {}
+            1 => state.store(STATE_SHUTTING_DOWN, Ordering::SeqCst),
+            _ => {
+                warn!("second signal: exiting without finishing the drain");
+                return Ok(());
+            }
+        }
+        let st        = state.load(Ordering::SeqCst);
+        let depth     = queues.depth();
+        let outstanding = queues.outstanding();
+        let producers = stats.active_producers.load(Ordering::Relaxed);
+        let consumers = stats.active_consumers.load(Ordering::Relaxed);
+
+        // Detect drain → shutdown transition; start the timeout clock.
+        if st == STATE_SHUTTING_DOWN && shutdown_at.is_none() {
+            shutdown_at = Some(Instant::now());
+            if initial_state == STATE_DRAINING {
+                info!(
+                    "drain upgraded to shutdown; timeout {:?} from now",
+                    drain_timeout
+                );
+            }
+        }
+
+        // Clean exit: nothing buffered or awaiting an ack, no producers
+        // still feeding.
+        if depth == 0 && outstanding == 0 && producers == 0 {
+            info!(
+                "drained cleanly in {:?} (consumers still attached: {})",
+                drain_start.elapsed(), consumers
+            );
+            return Ok(());
+        }
+
+        // Timeout only applies when shutdown was requested (now or earlier).
+        if let Some(t0) = shutdown_at
+            && t0.elapsed() >= drain_timeout
+        {
+            warn!(
+                "drain timeout after {:?}: depth={}, outstanding={}, active_producers={}, active_consumers={}; exiting",
+                drain_timeout, depth, outstanding, producers, consumers
+            );
+            return Ok(());
+        }
+
+        // Keep multi-frame bookkeeping tidy while draining, too.
+        if last_sweep.elapsed() >= SWEEP_EVERY {
+            let _ = queues.sweep(assign_ttl, tomb_ttl);
+            let _ = queues.expire_stale();
+            last_sweep = Instant::now();
+        }
+
+        // Periodic progress line so the log reflects ongoing drain state.
+        if last_progress_log.elapsed() >= Duration::from_secs(5) {
+            let label = if st == STATE_DRAINING { "draining" } else { "shutting down" };
+            info!(
+                "{}: in_queue={}, active_producers={}, active_consumers={}, elapsed={:?}",
+                label, depth, producers, consumers, drain_start.elapsed()
+            );
+            sd_notify(systemd, &format!(
+                "STATUS={label}: {depth} queued, {outstanding} unsettled, {producers} producer(s) left",
+            ));
+            last_progress_log = Instant::now();
+        }
+
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Tell systemd `state` if the orchestrator runs under it
+/// (`OrchestratorOptions::systemd`); a service manager that can't be
+/// reached costs a warning, never the orchestrator.
+fn sd_notify(systemd: bool, state: &str) {
+    #[cfg(unix)]
+    if systemd && let Err(e) = systemd::notify(state) {
+        warn!("systemd notification failed: {}", e);
+    }
+    #[cfg(not(unix))]
+    let _ = (systemd, state);
+}
+
+/// Pushes the stats line's numbers to a StatsD collector (statsd, Telegraf,
+/// the Datadog agent) over UDP, one datagram per report:
+///
+///   QPIPE_STATSD=127.0.0.1:8125   QPIPE_STATSD_PREFIX=qpipe (default)
+///
+/// Per-interval deltas go out as counters (`|c`), levels as gauges (`|g`).
+/// UDP is fire-and-forget: a missing collector costs nothing but the packet.
+struct Statsd {
+    sock:   UdpSocket,
+    prefix: String,
+}
+
+enum Metric {
+    Counter(&'static str, u64),
+    Gauge(&'static str, u64),
+}
+
+impl Statsd {
+    fn connect(target: &str, prefix: &str) -> io::Result<Self> {
+        let sock = UdpSocket::bind(if target.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" })?;
+        sock.connect(target)?;
+        Ok(Self { sock, prefix: prefix.trim_end_matches('.').to_string() })
+    }
+
+    fn render(&self, metrics: &[Metric]) -> String {
+        let sep = if self.prefix.is_empty() { "" } else { "." };
+        metrics.iter().map(|m| match m {
+            Metric::Counter(name, v) => format!("{}{sep}{name}:{v}|c\n", self.prefix),
+            Metric::Gauge(name, v)   => format!("{}{sep}{name}:{v}|g\n", self.prefix),
+        }).collect()
+    }
+
+    fn send(&self, metrics: &[Metric]) {
+        let packet = self.render(metrics);
+        if let Err(e) = self.sock.send(packet.trim_end().as_bytes()) {
+            debug!("statsd: {}", e);
+        }
+    }
+}
+
+/// One stats report: what the counters gained over an interval, and the
+/// gauges at its end.
+struct Report {
+    now:      StatsSnapshot,
+    last:     StatsSnapshot,
+    interval: Duration,
+    assigns:  usize,
+    tombs:    usize,
+    oldest:   Duration,
+    /// Dwell times of the frames taken over the interval.
+    dwell:    Dwell,
+}
+
+impl Report {
+    /// `dwell` holds the dwell histogram's counts as of the last report,
+    /// and is brought up to date.
+    fn take(stats: &Stats, queues: &Queues, last: StatsSnapshot, dwell: &mut Vec<u64>, interval: Duration) -> Self {
+        let now = stats.snapshot(queues);
+        let (_, assigns, tombs) = queues.gauges();
+        let (_, oldest) = queues.backlog();
+        let counts = stats.dwell.counts();
+        let taken: Vec<u64> = counts.iter().zip(dwell.iter().chain(std::iter::repeat(&0)))
+            .map(|(n, l)| n - l)
+            .collect();
+        *dwell = counts;
+        Self { now, last, interval, assigns, tombs, oldest, dwell: Dwell::of(&taken) }
+    }
+
+    /// The counters' growth over the interval.
+    fn delta(&self) -> StatsSnapshot {
+        let (n, l) = (&self.now, &self.last);
+        StatsSnapshot {
+            posted_frames:    n.posted_frames - l.posted_frames,
+            posted_bytes:     n.posted_bytes - l.posted_bytes,
+            collected_frames: n.collected_frames - l.collected_frames,
+            collected_bytes:  n.collected_bytes - l.collected_bytes,
+            dropped_frames:   n.dropped_frames - l.dropped_frames,
+            dropped_bytes:    n.dropped_bytes - l.dropped_bytes,
+            redelivered:      n.redelivered - l.redelivered,
+            dead_lettered:    n.dead_lettered - l.dead_lettered,
+            exported:         n.exported - l.exported,
+            expired:          n.expired - l.expired,
+            duplicates:       n.duplicates - l.duplicates,
+            shed:             n.shed - l.shed,
+            ..*n
+        }
+    }
+
+    /// The `[stats]` log line.
+    fn text(&self) -> String {
+        let (d, n) = (self.delta(), &self.now);
+        format!(
+            "[stats] +{} frames ({} B) posted | \
+             +{} frames ({} B) collected | \
+             +{} frames ({} B) dropped | \
+             queues={} in_queue={} outstanding={} multiframe_assignments={} tombstones={} | \
+             dwell p50={}us p95={}us p99={}us | \
+             producers={} consumers={} | totals: posted={} collected={} dropped={} \
+             redelivered={} dead_lettered={} exported={} expired={} duplicates={} shed={}",
+            d.posted_frames, d.posted_bytes, d.collected_frames, d.collected_bytes,
+            d.dropped_frames, d.dropped_bytes,
+            n.queues, n.in_queue, n.outstanding, self.assigns, self.tombs,
+            self.dwell.p50.as_micros(), self.dwell.p95.as_micros(), self.dwell.p99.as_micros(),
+            n.producers, n.consumers, n.posted_frames, n.collected_frames, n.dropped_frames,
+            n.redelivered, n.dead_lettered, n.exported, n.expired, n.duplicates, n.shed,
+        )
+    }
+
+    /// The same numbers as one line of JSON: the interval's deltas, its
+    /// dwell times and the gauges at the top level, the running counters
+    /// under `totals`.
+    fn json(&self) -> String {
+        let counters = |c: &StatsSnapshot| format!(
+            "\"posted_frames\":{},\"posted_bytes\":{},\"collected_frames\":{},\"collected_bytes\":{},\
+             \"dropped_frames\":{},\"dropped_bytes\":{},\"redelivered\":{},\"dead_lettered\":{},\
+             \"exported\":{},\"expired\":{},\"duplicates\":{},\"shed\":{}",
+            c.posted_frames, c.posted_bytes, c.collected_frames, c.collected_bytes,
+            c.dropped_frames, c.dropped_bytes, c.redelivered, c.dead_lettered, c.exported,
+            c.expired, c.duplicates, c.shed,
+        );
+        let n = &self.now;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let dwell = &self.dwell;
+        format!(
+            "{{\"time_ms\":{},\"interval_ms\":{},{},\"queues\":{},\"in_queue\":{},\
+             \"outstanding\":{},\"oldest_wait_ms\":{},\"multiframe_assignments\":{},\
+             \"tombstones\":{},\"dwell_us\":{{\"count\":{},\"p50\":{},\"p95\":{},\"p99\":{}}},\
+             \"producers\":{},\"consumers\":{},\"totals\":{{{}}}}}\n",
+            time.as_millis(), self.interval.as_millis(), counters(&self.delta()),
+            n.queues, n.in_queue, n.outstanding, self.oldest.as_millis(), self.assigns,
+            self.tombs, dwell.count, dwell.p50.as_micros(), dwell.p95.as_micros(), dwell.p99.as_micros(),
+            n.producers, n.consumers, counters(n),
+        )
+    }
+
+    fn metrics(&self) -> [Metric; 23] {
+        let (d, n) = (self.delta(), &self.now);
+        [
+            Metric::Counter("posted.frames",          d.posted_frames),
+            Metric::Counter("posted.bytes",           d.posted_bytes),
+            Metric::Counter("collected.frames",       d.collected_frames),
+            Metric::Counter("collected.bytes",        d.collected_bytes),
+            Metric::Counter("dropped.frames",         d.dropped_frames),
+            Metric::Counter("dropped.bytes",          d.dropped_bytes),
+            Metric::Counter("redelivered",            d.redelivered),
+            Metric::Counter("dead_lettered",          d.dead_lettered),
+            Metric::Counter("exported",               d.exported),
+            Metric::Counter("expired",                d.expired),
+            Metric::Counter("duplicates",             d.duplicates),
+            Metric::Counter("shed",                   d.shed),
+            Metric::Gauge("queues",                   n.queues as u64),
+            Metric::Gauge("queue.depth",              n.in_queue as u64),
+            Metric::Gauge("queue.outstanding",        n.outstanding as u64),
+            Metric::Gauge("queue.oldest_wait_ms",     self.oldest.as_millis() as u64),
+            Metric::Gauge("multiframe.assignments",   self.assigns as u64),
+            Metric::Gauge("multiframe.tombstones",    self.tombs as u64),
+            Metric::Gauge("queue.dwell.p50_us",       self.dwell.p50.as_micros() as u64),
+            Metric::Gauge("queue.dwell.p95_us",       self.dwell.p95.as_micros() as u64),
+            Metric::Gauge("queue.dwell.p99_us",       self.dwell.p99.as_micros() as u64),
+            Metric::Gauge("producers",                n.producers as u64),
+            Metric::Gauge("consumers",                n.consumers as u64),
+        ]
+    }
+}
+
+fn stats_reporter(
+            stats:  Arc<Stats>,
+            queues: Arc<Queues>,
+            state:  Arc<AtomicU8>,
+            every:  Duration,
+            format: StatsFormat,
+            statsd: Option<Statsd>,
+        ) {
+    let mut last = StatsSnapshot::default();
+    let mut dwell = Vec::new();
+    let mut since = Instant::now();
+
+    // Run only while accepting traffic; stop once the orchestrator is
+    // draining or shutting down so the drain-phase log lines aren't
+    // interleaved with throughput noise.
+    while state.load(Ordering::Relaxed) == STATE_RUNNING {
+        thread::sleep(every);
+
+        let report = Report::take(&stats, &queues, last, &mut dwell, since.elapsed());
+        since = Instant::now();
+        let json = report.json();
+        match format {
+            StatsFormat::Text => info!("{}", report.text()),
+            StatsFormat::Json => {
+                let mut out = io::stdout().lock();
+                if let Err(e) = out.write_all(json.as_bytes()).and_then(|()| out.flush()) {
+                    debug!("stats: {}", e);
+                }
+            }
+        }
+        stats.feed.publish(&json);
+        if let Some(statsd) = &statsd {
+            statsd.send(&report.metrics());
+        }
+        last = report.now;
+    }
+    stats.feed.close();
+}
+
+fn handle_control(
+            mut ctrl: Stream,
+            queues:   Arc<Queues>,
+            stats:    Arc<Stats>,
+            state:    Arc<AtomicU8>,
+            access:   &Access,
+            rules:    SessionRules,
+        ) -> io::Result<Option<Admitted>> {
+    ctrl.set_nodelay(true).ok();
+
+    let mut role = [0u8; 1];
+    ctrl.read_exact(&mut role)?;
+    // Producers and consumers from protocol 2 on say hello first.
+    let version = if role[0] == HELLO_MAGIC[0] {
+        let version = answer_hello(&mut ctrl, rules.min_protocol)?;
+        ctrl.read_exact(&mut role)?;
+        version
+    } else {
+        PROTOCOL_V1
+    };
+    let has_opts = role[0] & ROLE_FLAG_OPTS != 0;
+    let role = role[0] & !ROLE_FLAG_OPTS;
+    // Unknown option keys are ignored; the reply echoes the accepted ones,
+    // so a client can tell an old orchestrator from a refusal.
+    let opts = if has_opts { read_options(&mut ctrl)? } else { Vec::new() };
+    let mut session = if role == ROLE_CONSUMER {
+        consumer_session(&opts)
+    } else {
+        ConsumerSession::default()
+    };
+    // A client asking for heartbeats gets the orchestrator's, if it has
+    // its own, and learns which from the echo.
+    let heartbeat = match opts.iter().find(|(k, _)| *k == OPT_HEARTBEAT) {
+        Some((_, v)) if role == ROLE_PRODUCER || role == ROLE_CONSUMER => {
+            Some(rules.heartbeat.map_or_else(|| Heartbeat::decode(v), Ok)?)
+        }
+        _ => None,
+    };
+    session.heartbeat = heartbeat;
+    session.timeouts = rules.timeouts;
+    // Checksummed sessions have every frame either way carry a CRC.
+    let checksum = (role == ROLE_PRODUCER || role == ROLE_CONSUMER)
+        && opts.iter().any(|(k, _)| *k == OPT_CHECKSUM);
+    session.checksum = checksum;
+    let delta = role == ROLE_PRODUCER && opts.iter().any(|(k, _)| *k == OPT_DELTA);
+    // Only producers that know `ACK_THROTTLED` get it; the rest are held
+    // back instead.
+    let throttle = role == ROLE_PRODUCER && opts.iter().any(|(k, _)| *k == OPT_THROTTLE);
+    let mut limit = rules.producer_limit;
+    limit.reject &= throttle;
+    // Single-port sessions send the token back, and run, over this
+    // connection; nothing is bound for them.
+    let single_port = (role == ROLE_PRODUCER || role == ROLE_CONSUMER)
+        && opts.iter().any(|(k, _)| *k == OPT_SINGLE_PORT);
+    // Compressed payloads pass through opaque; the orchestrator only has to
+    // know the codec so that consumers can undo it.
+    let compress = role == ROLE_PRODUCER
+        && opts.iter().any(|(k, v)| *k == OPT_COMPRESS && v[..] == [CODEC_ZSTD]);
+    let queue = match opts.iter().find(|(k, _)| *k == OPT_QUEUE) {
+        Some((_, v)) => {
+            let name = std::str::from_utf8(v).map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidData, "queue name is not UTF-8",
+            ))?;
+            check_queue_name(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Some(name.to_string())
+        }
+        None => None,
+    };
+    // What the client calls itself, for log lines and per-client stats.
+    let name = match opts.iter().find(|(k, _)| *k == OPT_CLIENT_NAME) {
+        Some((_, v)) => {
+            let name = std::str::from_utf8(v).map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidData, "client name is not UTF-8",
+            ))?;
+            check_client_name(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Some(name.to_string())
+        }
+        None => None,
+    };
+
+    // ── Admin roles ─────────────────────────────────────────────────────────
+    // Always honored, regardless of lifecycle state. In particular, SHUTDOWN
+    // is honored while DRAINING — that's the whole point of being able to
+    // get impatient.
+
+    if role == ROLE_HEALTHCHECK {
+        ctrl.write_all(&[ACK_HEALTH])?;
+        ctrl.flush()?;
+        return Ok(None);
+    }
+
+    // Everything past a liveness probe may need credentials.
+    if !authenticate(&mut ctrl, role, &opts, access, name.as_deref())? {
+        return Ok(None);
+    }
+
+    if role == ROLE_WAIT_IDLE {
+        // Ack on COMPLETION: hold the connection until the queue named
+        // with OPT_QUEUE, or every queue, is idle. A caller that gives up
+        // simply closes its end, which the wait between checks notices,
+        // freeing the worker.
+        let idle = || match &queue {
+            Some(name) => queues.is_queue_idle(name),
+            None => queues.is_idle(),
+        };
+        ctrl.set_read_timeout(Some(IDLE_POLL))?;
+        while !idle() {
+            match ctrl.peek(&mut [0u8]) {
+                // Hung up, or (against protocol) said something: done.
+                Ok(_) => return Ok(None),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        ctrl.write_all(&[ACK_IDLE])?;
+        ctrl.flush()?;
+        return Ok(None);
+    }
+
+    if role == ROLE_EXPORT {
+        let accepting = state.load(Ordering::SeqCst) == STATE_RUNNING;
+        return export_to(&mut ctrl, &queues, accepting).map(|()| None);
+    }
+
+    if role == ROLE_ADMIN {
+        return admin_session(&mut ctrl, &queues, &stats).map(|()| None);
+    }
+
+    if role == ROLE_STATS {
+        // Stream every report until the reporter stops (the orchestrator
+        // is draining) or the subscriber goes away, which is no error.
+        let reports = stats.feed.subscribe();
+        ctrl.write_all(&[ACK_STATS])?;
+        ctrl.flush()?;
+        for report in reports {
+            if let Err(e) = ctrl.write_all(report.as_bytes()) {
+                debug!("stats subscriber {} gone: {}", ctrl.peer(), e);
+                break;
+            }
+        }
+        return Ok(None);
+    }
+
+    if role == ROLE_REPLICATE {
+        let Some(feed) = &queues.feed else {
+            warn!("refusing standby {}: there is no write-ahead log to follow", ctrl.peer());
+            return Ok(None);
+        };
+        return replica::serve(&mut ctrl, feed).map(|()| None);
+    }
+
+    if role == ROLE_DRAIN {
+        let peer = ctrl.peer();
+        info!("drain requested by {}", peer);
+        ctrl.write_all(&[ACK_DRAIN])?;
+        ctrl.flush()?;
+        // Enter drain only if currently running. Idempotent if already
+        // draining; doesn't downgrade from shutting-down.
+        let _ = state.compare_exchange(
+            STATE_RUNNING, STATE_DRAINING,
+            Ordering::SeqCst, Ordering::SeqCst
+        );
+        return Ok(None);
+    }
+
+    if role == ROLE_SHUTDOWN {
+        let peer = ctrl.peer();
+        info!("shutdown requested by {}", peer);
+        ctrl.write_all(&[ACK_SHUTDOWN])?;
+        ctrl.flush()?;
+        // Shutdown overrides any prior state, including drain.
+        state.store(STATE_SHUTTING_DOWN, Ordering::SeqCst);
+        return Ok(None);
+    }
+
+    if role != ROLE_PRODUCER && role != ROLE_CONSUMER {
+        return Err(
+            io::Error::new(io::ErrorKind::InvalidData, "unknown role byte")
+        );
+    }
+    // Hellos below the minimum were refused in `answer_hello`; this turns
+    // away the clients too old to say one.
+    if version < rules.min_protocol {
+        warn!(
+            "rejecting role 0x{:02x} session from {}: protocol {} is below QPIPE_MIN_PROTOCOL={}",
+            role, ctrl.peer(), version, rules.min_protocol,
+        );
+        return Ok(None);
+    }
+    // WebSocket sessions, and those of registered transports, have only
+    // the one connection.
+    if (rules.single_port || ctrl.single_port_only()) && !single_port {
+        warn!(
+            "rejecting role 0x{:02x} session from {}: only single-port sessions are served",
+            role, ctrl.peer(),
+        );
+        return Ok(None);
+    }
+
+    // ── Producer / consumer ────────────────────────────────────────────────
+    // Producers are only admitted while RUNNING. During drain/shutdown the
+    // orchestrator is trying to wind down, and a fresh producer would extend
+    // the drain indefinitely. Consumers are what winds it down, so they are
+    // still welcome — including ones reconnecting after a restart.
+    if role == ROLE_PRODUCER && state.load(Ordering::SeqCst) != STATE_RUNNING {
+        debug!(
+            "rejecting role 0x{:02x} session: orchestrator is not running",
+            role
+        );
+        return Ok(None);
+    }
+    // Past the session cap, turn producers and consumers away the same way;
+    // reconnecting clients back off and try again. Admin roles are still
+    // served above, so an overloaded orchestrator can be drained.
+    if let Some(max) = rules.max_sessions {
+        let live = stats.active_producers.load(Ordering::Relaxed)
+            + stats.active_consumers.load(Ordering::Relaxed);
+        if live >= max {
+            warn!("rejecting role 0x{:02x} session: {} sessions open (QPIPE_MAX_SESSIONS)", role, live);
+            return Ok(None);
+        }
+    }
+    // Each role has its own cap besides.
+    let (cap, live, kind) = if role == ROLE_PRODUCER {
+        (rules.max_producers, &stats.active_producers, "producer")
+    } else {
+        (rules.max_consumers, &stats.active_consumers, "consumer")
+    };
+    if let Some(max) = cap {
+        let live = live.load(Ordering::Relaxed);
+        if live >= max {
+            warn!("rejecting {} session from {}: {} {}s open (--max-{}s)", kind, ctrl.peer(), live, kind, kind);
+            return Ok(None);
+        }
+    }
+
+    // The session holds on to a reply queue as long as to its router.
+    let (router, attached) = queues.attach(queue.as_deref().unwrap_or(""))?;
+    // A queue's delivery policy wins over what a consumer asked for: ack
+    // mode is refused on an at-most-once queue, and imposed on an
+    // at-least-once one, on consumers that can be told so.
+    let asks_delivery = role == ROLE_CONSUMER && opts.iter().any(|(k, _)| *k == OPT_DELIVERY);
+    match router.delivery.filter(|_| role == ROLE_CONSUMER) {
+        Some(DeliveryPolicy::AtMostOnce) if session.ack_mode => {
+            session.ack_mode = false;
+            session.weight = None;
+            session.resources = None;
+        }
+        Some(DeliveryPolicy::AtLeastOnce) if !session.ack_mode => {
+            if !asks_delivery {
+                warn!(
+                    "rejecting consumer session from {}: queue {:?} is at-least-once, and the client can't ack",
+                    ctrl.peer(), queue.as_deref().unwrap_or(""),
+                );
+                return Ok(None);
+            }
+            session.ack_mode = true;
+        }
+        _ => {}
+    }
+    // Producers may end with CTRL_BYE. Consumers say goodbye on their
+    // back-channel, which only a reader thread watches between deliveries.
+    let goodbye = opts.iter().any(|(k, _)| *k == OPT_GOODBYE)
+        && (role == ROLE_PRODUCER
+            || role == ROLE_CONSUMER && (session.ack_mode || session.heartbeat.is_some()));
+    let (data_listener, port) = if single_port {
+        (None, 0)
+    } else {
+        let (listener, port) = Listener::bind_session(&ctrl.local_addr()?)?;
+        (Some(listener), port)
+    };
+
+    let token = router.session_token()?;
+
+    // The session counts from here, before the client hears it is in, so
+    // the next handshake sees it against the caps.
+    let kind = if role == ROLE_PRODUCER { ConnKind::Producer } else { ConnKind::Consumer };
+    let mut conn = ConnGuard::new(kind, stats, ctrl.peer(), queue.as_deref().unwrap_or(""), name.as_deref());
+    if matches!(kind, ConnKind::Producer) {
+        conn = conn.producing_for(&router);
+    }
+    ctrl.write_all(&port.to_be_bytes())?;
+    ctrl.write_all(&token)?;
+    if has_opts {
+        let visibility_ms =
+            (router.policy.visibility_timeout.as_millis() as u64).to_be_bytes();
+        let weight_be = session.weight.map(u32::to_be_bytes);
+        let mut reply: Vec<(u8, &[u8])> = Vec::new();
+        if session.ack_mode {
+            reply.push((OPT_ACK_MODE, &[]));
+            reply.push((OPT_VISIBILITY_MS, &visibility_ms));
+            // Clients that know policies know nacks too.
+            if opts.iter().any(|(k, _)| *k == OPT_NACK) || asks_delivery {
+                reply.push((OPT_NACK, &[]));
+            }
+        }
+        let delivery = match session.ack_mode {
+            true => [DeliveryPolicy::AtLeastOnce.code()],
+            false => [DeliveryPolicy::AtMostOnce.code()],
+        };
+        if asks_delivery {
+            reply.push((OPT_DELIVERY, &delivery));
+        }
+        if let Some(w) = &weight_be {
+            reply.push((OPT_WEIGHT, w));
+        }
+        if opts.iter().any(|(k, _)| *k == OPT_CAPABILITIES) && role == ROLE_CONSUMER {
+            reply.push((OPT_CAPABILITIES, &[]));
+        }
+        if session.resources.is_some() {
+            reply.push((OPT_RESOURCES, &[]));
+        }
+        if session.filter.is_some() {
+            reply.push((OPT_FILTER, &[]));
+        }
+        if delta {
+            reply.push((OPT_DELTA, &[]));
+        }
+        if compress {
+            reply.push((OPT_COMPRESS, &[CODEC_ZSTD]));
+        }
+        if queue.is_some() {
+            reply.push((OPT_QUEUE, &[]));
+        }
+        let heartbeat_be = heartbeat.map(|hb| hb.encode());
+        if let Some(hb) = &heartbeat_be {
+            reply.push((OPT_HEARTBEAT, hb));
+        }
+        if checksum {
+            reply.push((OPT_CHECKSUM, &[]));
+        }
+        if single_port {
+            reply.push((OPT_SINGLE_PORT, &[]));
+        }
+        if throttle {
+            reply.push((OPT_THROTTLE, &[]));
+        }
+        if goodbye {
+            reply.push((OPT_GOODBYE, &[]));
+        }
+        let max_frame_be = (rules.max_frame as u32).to_be_bytes();
+        if opts.iter().any(|(k, _)| *k == OPT_MAX_FRAME) {
+            reply.push((OPT_MAX_FRAME, &max_frame_be));
+        }
+        write_options(&mut ctrl, &reply)?;
+    }
+    ctrl.flush()?;
+
+    let data = match data_listener {
+        None => DataConn::Control(ctrl),
+        Some(listener) => {
+            drop(ctrl);
+            DataConn::Port(listener, port)
+        }
+    };
+    let start = move |data: Stream| {
+        if role == ROLE_PRODUCER {
+            debug!("Starting producer");
+            let framing = Framing { checksum, max_frame: rules.max_frame, delta, refusable: throttle };
+            let router = router.for_producer();
+            let mut session = ProducerSession::new(data, router, conn, heartbeat, rules.timeouts, framing, limit)?;
+            session.attached = attached;
+            Ok(Session::Producer(Box::new(session)))
+        } else {
+            debug!("Starting consumer");
+            let router = if router.is_fanout() { router.subscribe() } else { router.for_consumer() };
+            let (consumer, reader) = ConsumerHandler::new(data, router, conn, session, attached)?;
+            Ok(Session::Consumer(Box::new(consumer), reader))
+        }
+    };
+    Ok(Some(Admitted {
+        data,
+        token,
+        deadline: Instant::now() + rules.token.ttl,
+        policy:   rules.token,
+        start:    Box::new(start),
+    }))
+}
+
+/// A producer or consumer session that `handle_control` has admitted,
+/// waiting for its client to present the token on the data connection.
+struct Admitted {
+    data:     DataConn,
+    token:    [u8; TOKEN_LEN],
+    /// When the token is no longer taken.
+    deadline: Instant,
+    policy:   TokenPolicy,
+    /// Starts the session on its data connection, and hands it back to be
+    /// served.
+    start:    Box<dyn FnOnce(Stream) -> io::Result<Session> + Send>,
+}
+
+/// Where an admitted session's data connection comes from.
+enum DataConn {
+    /// A single-port session's control connection.
+    Control(Stream),
+    /// The session's own data port.
+    Port(Listener, u16),
+}
+
+impl Admitted {
+    /// The data connection, once the client has presented the token on it;
+    /// `accept_data` and `single_port_data` wait for that until the
+    /// deadline.
+    fn connect(data: DataConn, token: &[u8; TOKEN_LEN], deadline: Instant, policy: TokenPolicy) -> io::Result<Stream> {
+        match data {
+            DataConn::Control(ctrl) => single_port_data(ctrl, token, deadline),
+            DataConn::Port(listener, port) => accept_data(listener, port, token, deadline, policy),
+        }
+    }
+}
+
+/// A session under way after `Admitted::start`.
+enum Session {
+    Producer(Box<ProducerSession>),
+    /// With the reader of its back-channel, if it has one.
+    Consumer(Box<ConsumerHandler>, Option<AckReader>),
+}
+
+/// Serve a control connection on the calling thread, through its
+/// session's end.
+fn serve_control(
+            ctrl:   Stream,
+            queues: Arc<Queues>,
+            stats:  Arc<Stats>,
+            state:  Arc<AtomicU8>,
+            access: &Access,
+            rules:  SessionRules,
+        ) -> io::Result<()> {
+    let Some(Admitted { data, token, deadline, policy, start }) = handle_control(ctrl, queues, stats, state, access, rules)? else {
+        return Ok(());
+    };
+    let data = Admitted::connect(data, &token, deadline, policy)?;
+    match start(data)? {
+        Session::Producer(mut producer) => {
+            let x = producer.serve(false);
+            debug!("Stopping producer");
+            x.map(drop)
+        }
+        Session::Consumer(mut consumer, reader) => {
+            if let Some(reader) = reader {
+                reader.spawn()?;
+            }
+            let x = consumer.serve(false);
+            debug!("Stopping consumer");
+            x.map(drop)
+        }
+    }
+}
+
+/// The data stream of a single-port session: the control connection, once
+/// the client has sent the token back on it.
+fn single_port_data(mut ctrl: Stream, token: &[u8; TOKEN_LEN], deadline: Instant) -> io::Result<Stream> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "no session token in time"));
+    }
+    ctrl.set_read_timeout(Some(left)).ok();
+    let mut got = [0u8; TOKEN_LEN];
+    ctrl.read_exact(&mut got)?;
+    if got != *token {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "wrong session token"));
+    }
+    ctrl.set_read_timeout(None).ok();
+    debug!("client {} authenticated on its control connection", ctrl.peer());
+    Ok(ctrl)
+}
+
+/// Wait on a session's data port for the client that knows its token,
+/// until `deadline` or as many wrong tries as `policy` allows. The port is
+/// closed either way; for a Unix socket, that removes it.
+fn accept_data(
+            data_listener: Listener,
+            port:     u16,
+            token:    &[u8; TOKEN_LEN],
+            deadline: Instant,
+            policy:   TokenPolicy,
+        ) -> io::Result<Stream> {
+    data_listener.set_nonblocking(true)?;
+    let mut poll = Duration::from_millis(1);
+    let mut failed = 0;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut, format!("no client on data port {port} within {:?}", policy.ttl),
+            ));
+        }
+        let (mut s, peer) = match data_listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(poll.min(left));
+                poll = (poll * 2).min(DATA_POLL);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        s.set_nonblocking(false)?;
+        s.set_nodelay(true).ok();
+        s.set_read_timeout(Some(left)).ok();
+
+        let mut got = [0u8; TOKEN_LEN];
+        match s.read_exact(&mut got) {
+            Ok(()) if got == *token => {
+                s.set_read_timeout(None).ok();
+                debug!("client {} authenticated on data port {}", peer, port);
+                return Ok(s);
+            }
+            _ => {
+                failed += 1;
+                warn!("wrong or no session token from {} on data port {}", peer, port);
+                if failed >= policy.attempts {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied, format!("{failed} failed attempts on data port {port}"),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Answer a client's hello (see `crate::HELLO_MAGIC`), whose first byte
+/// has been read, with the newest version both sides speak — at least
+/// `min` — or with 0 and an error if there is none.
+fn answer_hello(ctrl: &mut Stream, min: u8) -> io::Result<u8> {
+    let mut rest = [0u8; 5];
+    ctrl.read_exact(&mut rest)?;
+    if rest[..3] != HELLO_MAGIC[1..] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad hello"));
+    }
+    let (client_min, client_max) = (rest[3], rest[4]);
+    let version = client_max.min(PROTOCOL_VERSION);
+    let agreed = version >= client_min.max(min);
+    ctrl.write_all(&HELLO_MAGIC)?;
+    ctrl.write_all(&[if agreed { version } else { 0 }])?;
+    ctrl.flush()?;
+    if !agreed {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "client speaks protocol versions {client_min}..={client_max}, \
+                 this orchestrator {}..={PROTOCOL_VERSION}",
+                min.max(PROTOCOL_V1),
+            ),
+        ));
+    }
+    Ok(version)
+}
+
+/// Serve an admin session (see `qpipe::admin`): answer requests until the
+/// client hangs up.
+fn admin_session(ctrl: &mut Stream, queues: &Queues, stats: &Stats) -> io::Result<()> {
+    let peer = ctrl.peer();
+    ctrl.write_all(&[ACK_ADMIN])?;
+    ctrl.flush()?;
+    loop {
+        let mut head = [0u8; 3];
+        match ctrl.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let mut arg = vec![0u8; u16::from_be_bytes([head[1], head[2]]) as usize];
+        ctrl.read_exact(&mut arg)?;
+        let reply = match String::from_utf8(arg) {
+            Ok(arg) => admin_request(head[0], &arg, queues, stats, &peer),
+            Err(_) => Err("argument is not UTF-8".to_string()),
+        };
+        let (status, body) = match reply {
+            Ok(body) => (ACK_ADMIN, body),
+            Err(e) => (ADMIN_ERROR, e),
+        };
+        let mut out = Vec::with_capacity(5 + body.len());
+        out.push(status);
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend_from_slice(body.as_bytes());
+        ctrl.write_all(&out)?;
+        ctrl.flush()?;
+    }
+}
+
+/// One admin request: the reply body, or why it was refused.
+fn admin_request(op: u8, arg: &str, queues: &Queues, stats: &Stats, peer: &str) -> Result<String, String> {
+    let queue = |name: &str| queues.find(name).ok_or_else(|| format!("no queue named {name:?}"));
+    match op {
+        ADMIN_SESSIONS => {
+            let sessions = stats.sessions.lock().unwrap();
+            Ok(sessions.values().map(|s| s.info().line() + "\n").collect())
+        }
+        ADMIN_CLIENTS => {
+            let clients = stats.clients.lock().unwrap();
+            Ok(clients.iter().map(|(name, c)| c.info(name).line() + "\n").collect())
+        }
+        ADMIN_QUEUES => Ok(queues.named_all().into_iter().map(|(name, r)| {
+            // A fan-out queue reports what its subscribers hold, a sharded
+            // one what its shards do.
+            let parts: Vec<Arc<Router>> = r.subscribers().into_iter().chain(r.shard_routers()).collect();
+            let routers = std::iter::once(&r).chain(&parts);
+            let info = QueueInfo {
+                name,
+                depth:       routers.clone().map(|r| r.depth() as u64).sum(),
+                outstanding: routers.clone().map(|r| r.outstanding() as u64).sum(),
+                oldest_wait: routers.map(|r| r.backlog().1).max().unwrap_or_default(),
+                paused:      r.intake() != Intake::Open,
+                refusing:    r.intake() == Intake::Refused,
+            };
+            info.line() + "\n"
+        }).collect()),
+        ADMIN_PAUSE | ADMIN_REFUSE | ADMIN_RESUME => {
+            let (intake, done) = match op {
+                ADMIN_PAUSE  => (Intake::Paused, "paused"),
+                ADMIN_REFUSE => (Intake::Refused, "refused"),
+                _            => (Intake::Open, "resumed"),
+            };
+            match arg {
+                "" => queues.set_intake(intake),
+                name => queue(name)?.set_intake(intake),
+            }
+            info!(
+                "intake {} for {} by {}",
+                done,
+                if arg.is_empty() { "all queues".to_string() } else { format!("queue {arg:?}") },
+                peer,
+            );
+            Ok(String::new())
+        }
+        ADMIN_PURGE => {
+            let purged = queue(arg)?.purge().map_err(|e| e.to_string())?;
+            warn!("purged {} frames from queue {:?} for {}", purged, arg, peer);
+            Ok(purged.to_string())
+        }
+        _ => Err(format!("unknown admin op 0x{op:02x}")),
+    }
+}
+
+/// Serve an export request (see `qpipe::export_queue`): send every queue's
+/// frames with their metadata, the named queues' with their name
+/// (`Meta::queue`); for a drain, keep them out of the queues only once the
+/// client confirms it has them. Reply queues belong to sessions, and stay
+/// out of it. An import request is served by `import_from`, unless the
+/// orchestrator no longer `accepting` frames.
+fn export_to(ctrl: &mut Stream, queues: &Queues, accepting: bool) -> io::Result<()> {
+    let mut mode = [0u8; 1];
+    ctrl.read_exact(&mut mode)?;
+    let drain = match mode[0] {
+        EXPORT_COPY  => false,
+        EXPORT_DRAIN => true,
+        EXPORT_IMPORT if accepting => return import_from(ctrl, queues),
+        EXPORT_IMPORT => {
+            warn!("refusing import from {}: draining", ctrl.peer());
+            return Ok(());
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown export mode")),
+    };
+    let peer = ctrl.peer();
+    // A sharded queue's frames are in its shards, and go back to them.
+    let taken: Vec<(Option<String>, Arc<Router>, Vec<Item>)> = queues.named_all().into_iter()
+        .filter(|(name, _)| !name.starts_with(REPLY_QUEUE_PREFIX))
+        .flat_map(|(name, router)| {
+            let mut parts = router.shard_routers();
+            if parts.is_empty() {
+                parts.push(router);
+            }
+            let name = (!name.is_empty()).then_some(name);
+            parts.into_iter().map(move |r| (name.clone(), r))
+        })
+        .map(|(name, r)| {
+            let items = r.export(drain);
+            (name, r, items)
+        })
+        .collect();
+    let items: Vec<(&Option<String>, &Item)> = taken.iter()
+        .flat_map(|(name, _, items)| items.iter().map(move |it| (name, it)))
+        .collect();
+    info!(
+        "{} of {} queued frames requested by {}",
+        if drain { "drain" } else { "copy" }, items.len(), peer,
+    );
+
+    let sent: io::Result<()> = (|| {
+        let mut w = BufWriter::new(&*ctrl);
+        w.write_all(&(items.len() as u32).to_be_bytes())?;
+        for (queue, it) in &items {
+            let attempt = (it.attempts > 0).then_some(it.attempts);
+            put_frame(&mut w, &it.frame, &Meta { attempt, queue: (*queue).clone(), ..it.meta.clone() })?;
+        }
+        w.flush()
+    })();
+    if !drain {
+        return sent;
+    }
+    let confirmed = sent.and_then(|()| {
+        // The client may take its time making the frames durable.
+        ctrl.set_read_timeout(Some(Duration::from_secs(600))).ok();
+        let mut ack = [0u8; 1];
+        ctrl.read_exact(&mut ack)?;
+        match ack[0] {
+            ACK_EXPORT => Ok(()),
+            b => Err(io::Error::new(
+                io::ErrorKind::InvalidData, format!("unexpected export confirmation 0x{b:02x}"),
+            )),
+        }
+    });
+    let kept = confirmed.as_ref().is_ok();
+    if let Err(e) = &confirmed {
+        warn!("export to {} not confirmed ({}); requeueing {} frames", peer, e, items.len());
+    }
+    drop(items);
+    for (_, r, items) in taken {
+        r.finish_export(items, kept);
+    }
+    if !kept {
+        return Ok(());
+    }
+    ctrl.write_all(&[ACK_EXPORT])?;
+    ctrl.flush()
+}
+
+/// Serve an import request (see `qpipe::import_queue`): read every frame,
+/// then queue them all in order and confirm. Nothing is queued if the
+/// client goes before the last one.
+fn import_from(ctrl: &mut Stream, queues: &Queues) -> io::Result<()> {
+    let mut count = [0u8; 4];
+    ctrl.read_exact(&mut count)?;
+    let count = u32::from_be_bytes(count) as usize;
+    let mut rd = io::BufReader::new(&*ctrl);
+    let mut frames = Vec::with_capacity(count.min(1 << 16));
+    for _ in 0..count {
+        // Delivery tags and attempt counts are ours to assign.
+        let (frame, meta) = get_frame(&mut rd)?.ok_or_else(|| io::Error::new(
+            io::ErrorKind::UnexpectedEof, "import ended early",
+        ))?;
+        frames.push((frame, Meta { delivery: None, attempt: None, ..meta }));
+    }
+    drop(rd);
+    info!("importing {} frame(s) from {}", frames.len(), ctrl.peer());
+    import_to(queues, frames)?;
+    ctrl.write_all(&[ACK_EXPORT])?;
+    ctrl.flush()
+}
+
+/// Queue the frames of a snapshot in order, each in the queue its
+/// `Meta::queue` names (created if need be) or the default queue. None is
+/// queued if a name is bad, is a reply queue's, or its queue can't be
+/// created.
+fn import_to(queues: &Queues, frames: Vec<(Frame, Meta)>) -> io::Result<()> {
+    let mut by_queue: BTreeMap<String, Vec<(Frame, Meta)>> = BTreeMap::new();
+    for (frame, mut meta) in frames {
+        let name = meta.queue.take().unwrap_or_default();
+        by_queue.entry(name).or_default().push((frame, meta));
+    }
+    let routers = by_queue.keys()
+        .map(|name| {
+            if !name.is_empty() {
+                check_queue_name(name)?;
+            }
+            if name.starts_with(REPLY_QUEUE_PREFIX) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData, format!("{name:?}: reply queues aren't imported"),
+                ));
+            }
+            queues.get(name)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    for (r, (_, frames)) in routers.into_iter().zip(by_queue) {
+        r.import(frames);
+    }
+    Ok(())
+}
+
+/// The frames of a recording (`qpipe::read_recording`), read sealed with
+/// `key` if there is one.
+fn read_snapshot(path: &std::path::Path, key: Option<&Key>) -> io::Result<Vec<(Frame, Meta)>> {
+    Ok(crate::read_recording(path, key)?.into_iter()
+        .map(|(frame, meta)| (frame, Meta { delivery: None, attempt: None, ..meta }))
+        .collect())
+}
+
+/// How a producer's frames are read: with checksums if it negotiated them
+/// (`OPT_CHECKSUM`), none longer than the orchestrator's frame-size limit
+/// (`OrchestratorOptions::max_frame_bytes`), and delta-decoded if it asked
+/// for that (`OPT_DELTA`). A producer that knows `ACK_THROTTLED`
+/// (`OPT_THROTTLE`) may have frames refused with it.
+#[derive(Debug, Clone, Copy)]
+struct Framing {
+    checksum:  bool,
+    max_frame: usize,
+    delta:     bool,
+    refusable: bool,
+}
+
+/// A producer's session: the frames it sends are queued one at a time by
+/// `frame`, which the reactor calls only as they arrive (`serve`).
+struct ProducerSession {
+    stream:    Stream,
+    router:    Arc<Router>,
+    conn:      ConnGuard,
+    /// Its hold on a reply queue, if it answers on one.
+    attached:  Attached,
+    timeouts:  SessionTimeouts,
+    framing:   Framing,
+    limit:     ProducerLimit,
+    /// How long the producer may send nothing at all.
+    silence:   Option<Duration>,
+    ack_queued: bool,
+    throttle:  Option<Throttle>,
+    throttled: bool,
+    // Messages turned away while an admin refuses intake, or by a full
+    // reject-newest queue, by id while their later chunks follow.
+    refused:   HashSet<MsgId>,
+    refusing:  bool,
+    shedding:  bool,
+    // Delta-encoded single frames are rebuilt here, so everything past
+    // this point — stats, audit stamps, the queue — sees whole messages.
+    decoder:   Option<Decoder>,
+    audit_as:  Option<Arc<str>>,
+    #[cfg(feature = "otel")]
+    tracer:    Option<Tracer>,
+}
+
+/// Where `ProducerSession::serve`, `ConsumerHandler::serve` or
+/// `AckReader::serve` left a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    /// The client is gone, or was dropped.
+    Closed,
+    /// Nothing to read, or to deliver, for now.
+    Idle,
+}
+
+impl ProducerSession {
+    fn new(
+                stream:    Stream,
+                router:    Arc<Router>,
+                conn:      ConnGuard,
+                heartbeat: Option<Heartbeat>,
+                timeouts:  SessionTimeouts,
+                framing:   Framing,
+                limit:     ProducerLimit,
+            ) -> io::Result<Self> {
+        let audit_as: Option<Arc<str>> = router.auditing().then(|| {
+            stream.peer().into()
+        });
+        // With a heartbeat or a write-ahead log, frames are ACKed once queued
+        // rather than on receipt: backpressure then holds up data frames,
+        // never the answer to a ping, and an ACKed frame is on disk. Frames
+        // that may be refused are ACKed once the throttle has had its say.
+        let ack_queued = heartbeat.is_some() || router.journaling() || framing.refusable;
+        let throttle = Throttle::new(limit, router.now());
+        // A producer silent for its heartbeat timeout, or its idle timeout, is
+        // gone; one that stops halfway through a frame gets the read timeout.
+        let silence = heartbeat.map(|hb| hb.timeout).into_iter().chain(timeouts.idle).min();
+        stream.set_read_timeout(silence)?;
+        stream.set_write_timeout(timeouts.write)?;
+        Ok(Self {
+            #[cfg(feature = "otel")]
+            tracer: router.tracer(),
+            stream, router, conn, timeouts, framing, limit, silence, ack_queued, throttle,
+            attached:  Attached(None),
+            throttled: false,
+            refused:   HashSet::new(),
+            refusing:  false,
+            shedding:  false,
+            decoder:   framing.delta.then(Decoder::new),
+            audit_as,
+        })
+    }
+
+    /// Take frames until the producer is gone or, with `park`, until none
+    /// has arrived; without, the session blocks for its frames.
+    fn serve(&mut self, park: bool) -> io::Result<Flow> {
+        loop {
+            #[cfg(target_os = "linux")]
+            if park && !self.stream.readable()? {
+                return Ok(Flow::Idle);
+            }
+            #[cfg(not(target_os = "linux"))]
+            let _ = park;
+            if !self.frame()? {
+                return Ok(Flow::Closed);
+            }
+        }
+    }
+
+    /// When a producer waiting for its next frame has been silent too long.
+    #[cfg(target_os = "linux")]
+    fn deadline(&self) -> Option<Instant> {
+        self.silence.map(|t| Instant::now() + t)
+    }
+
+    /// Drop a producer that has sent nothing for its `silence`.
+    fn silent(&self) {
+        let timeout = self.silence.unwrap_or_default();
+        warn!("producer {} sent nothing for {:?}; dropping it", self.stream.peer(), timeout);
+    }
+
+    /// Read, queue and answer one frame; false once the session is over.
+    fn frame(&mut self) -> io::Result<bool> {
+        let (framing, limit) = (self.framing, self.limit);
+        let router = &self.router;
+        let stream = &mut self.stream;
+        let got = match self.timeouts.read {
+            None => get_frame_as(stream, framing.checksum, framing.max_frame),
+            Some(read) => {
+                let mut first = [0u8; 1];
+                match read_exact_or_eof(stream, &mut first) {
+                    Ok(false) => Ok(None),
+                    Ok(true) => {
+                        stream.set_read_timeout(Some(read))?;
+                        let rest = &mut (&first[..]).chain(&mut *stream);
+                        let got = get_frame_as(rest, framing.checksum, framing.max_frame);
+                        if got.as_ref().is_err_and(Heartbeat::missed) {
+                            warn!("producer {} stalled mid-frame for {:?}; dropping it", stream.peer(), read);
+                            return Ok(false);
+                        }
+                        stream.set_read_timeout(self.silence)?;
+                        got
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        let (frame, meta) = match got {
+            Ok(Some(got)) => got,
+            Ok(None) => return Ok(false),
+            Err(e) if Heartbeat::missed(&e) => {
+                self.silent();
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+        #[cfg(feature = "otel")]
+        let received = SystemTime::now();
+        if !self.ack_queued {
+            stream.write_all(&[ACK_PAYLOAD])?;
+        }
+        if let Frame::Bye = frame {
+            if self.ack_queued {
+                stream.write_all(&[ACK_PAYLOAD])?;
+            }
+            info!("producer {} closed its session", stream.peer());
+            return Ok(false);
+        }
+        let frame = match (frame, &mut self.decoder) {
+            (Frame::Msg(p), Some(dec)) => Frame::Msg(dec.decode(&p).inspect_err(|e| {
+                error!("dropping producer connection: {}", e);
+            })?),
+            (frame, _) => frame,
+        };
+        if framing.refusable && refuses(router.intake(), &frame, &mut self.refused) {
+            if !self.refusing {
+                info!("intake refused; turning away producer {}'s messages", stream.peer());
+                self.refusing = true;
+            }
+            stream.write_all(&[ACK_THROTTLED])?;
+            return Ok(true);
+        }
+        if let Some(t) = &mut self.throttle {
+            let now = router.now();
+            let (wait, refused) = if limit.reject {
+                (Duration::ZERO, !t.admits(&frame, now))
+            } else {
+                (t.delay(&frame, now), false)
+            };
+            if (refused || !wait.is_zero()) && !self.throttled {
+                warn!("producer {} is over QPIPE_PRODUCER_LIMIT; throttling it", stream.peer());
+                self.throttled = true;
+            }
+            if refused {
+                stream.write_all(&[ACK_THROTTLED])?;
+                return Ok(true);
+            }
+            thread::sleep(wait);
+        }
+        let repeat = router.repeats(&frame, &meta);
+        if repeat {
+            debug!("dropped a repeat of a message from {}", stream.peer());
+        }
+        // A ping only wants its ACK, and so does a repeat.
+        if !matches!(frame, Frame::Ping) && !repeat {
+            if let Frame::Eos(group) = &frame {
+                info!(
+                    "end-of-stream marker enqueued (group {:?})",
+                    String::from_utf8_lossy(group)
+                );
+            }
+            let len = frame.payload_len() as u64;
+            let data = !matches!(frame, Frame::Eos(_));
+            // A refused first chunk takes the rest of its message with it.
+            let message = match &frame {
+                Frame::Chunk { id, count, .. } if *count > 1 => Some(*id),
+                _ => None,
+            };
+            // Only producer-owned keys are honored; delivery tags and
+            // attempt counts are the orchestrator's to assign.
+            let meta = Meta { delivery: None, attempt: None, ..meta };
+            // Only refusable producers can be turned away by a full queue.
+            let claimed = meta.message_id.clone().filter(|_| framing.refusable);
+            let stamp = self.audit_as.as_ref()
+                .filter(|_| data)
+                .map(|p| Stamp::new(&frame, p.clone()));
+            #[cfg(feature = "otel")]
+            let traced = self.tracer.as_ref().and_then(|t| Some((t, Tracer::context(&frame, &meta)?)));
+            let pushed = router.push_stamped(frame, meta, stamp, framing.refusable);
+            match pushed {
+                Pushed::Queued if data => self.conn.posted(len),
+                Pushed::Queued => {}
+                Pushed::Dropped => {
+                    // Straggler of a tombstoned message; push already
+                    // accounted for it in the dropped counters.
+                    self.conn.posted(len);
+                    debug!("dropped straggler frame of a dead message");
+                }
+                Pushed::Rejected => {
+                    if !self.shedding {
+                        info!("queue full; refusing producer {}'s new messages", stream.peer());
+                        self.shedding = true;
+                    }
+                    self.refused.extend(message);
+                    // Or its retry would be dropped as a repeat.
+                    if let Some(id) = &claimed {
+                        router.forget(id);
+                    }
+                }
+            }
+            #[cfg(feature = "otel")]
+            if let Some((t, parent)) = traced {
+                let failed = match pushed {
+                    Pushed::Queued => None,
+                    Pushed::Dropped => Some("dropped"),
+                    Pushed::Rejected => Some("shed"),
+                };
+                t.record("qpipe.enqueue", SPAN_KIND_SERVER, &parent, received, Some(&stream.peer()), failed);
+            }
+            if pushed == Pushed::Rejected {
+                stream.write_all(&[ACK_THROTTLED])?;
+                return Ok(true);
+            }
+        }
+        if self.ack_queued {
+            stream.write_all(&[ACK_PAYLOAD])?;
+        }
+        Ok(true)
+    }
+}
+
+/// The users of an orchestrator that requires authentication, from the
+/// QPIPE_USERS file: one user per line, `NAME ROLES VERIFIER`, where ROLES
+/// is a comma-separated subset of `producer`, `consumer` and `admin`, and
+/// VERIFIER is what `orchestrator --hash-password` prints. Blank lines and
+/// `#` comments are skipped.
+struct Users {
+    entries: HashMap<String, (Vec<String>, Verifier)>,
+    /// Keys the mock verifiers of unknown names; fresh per process.
+    secret:  [u8; 32],
+}
+
+const USER_ROLES: [&str; 3] = ["producer", "consumer", "admin"];
+
+impl Users {
+    fn load(path: &std::path::Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|e| io::Error::new(
+            e.kind(), format!("QPIPE_USERS {}: {e}", path.display()),
+        ))?;
+        Self::parse(&text).map_err(|e| io::Error::new(
+            e.kind(), format!("QPIPE_USERS {}: {e}", path.display()),
+        ))
+    }
+
+    fn parse(text: &str) -> io::Result<Self> {
+        let mut entries = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = |msg: String| io::Error::new(
+                io::ErrorKind::InvalidInput, format!("line {}: {msg}", n + 1),
+            );
+            let [name, roles, verifier] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(bad("expected NAME ROLES VERIFIER".into()));
+            };
+            let roles = parse_roles(roles).map_err(bad)?;
+            let verifier = Verifier::parse(verifier).map_err(|e| bad(e.to_string()))?;
+            if entries.insert(name.to_string(), (roles, verifier)).is_some() {
+                return Err(bad(format!("user {name:?} listed twice")));
+            }
+        }
+        let mut secret = [0u8; 32];
+        SysRng.try_fill_bytes(&mut secret).map_err(io::Error::other)?;
+        Ok(Self { entries, secret })
+    }
+
+    fn verifier(&self, name: &str) -> Verifier {
+        self.entries.get(name)
+            .map_or_else(|| Verifier::mock(&self.secret, name), |(_, v)| v.clone())
+    }
+
+    fn allows(&self, name: &str, role: u8) -> bool {
+        self.entries.get(name).is_some_and(|(roles, _)| roles.iter().any(|r| r == role_name(role)))
+    }
+}
+
+/// The QPIPE_USERS line for `name` with `roles` (comma-separated) and
+/// `password`, as `orchestrator --hash-password` prints it.
+pub fn user_line(name: &str, roles: &str, password: &str) -> io::Result<String> {
+    parse_roles(roles).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if password.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty password"));
+    }
+    let verifier = Verifier::new(password, scram::DEFAULT_ITERATIONS)?;
+    Ok(format!("{name} {roles} {verifier}"))
+}
+
+fn parse_roles(roles: &str) -> Result<Vec<String>, String> {
+    roles.split(',')
+        .map(|r| match USER_ROLES.contains(&r) {
+            true => Ok(r.to_string()),
+            false => Err(format!("unknown role {r:?} (expected {})", USER_ROLES.join(", "))),
+        })
+        .collect()
+}
+
+/// The user role a session role byte needs.
+fn role_name(role: u8) -> &'static str {
+    match role {
+        ROLE_PRODUCER => "producer",
+        ROLE_CONSUMER => "consumer",
+        _ => "admin",
+    }
+}
+
+/// Who may open which sessions. Authentication is required as soon as any
+/// mechanism is configured.
+#[derive(Default)]
+struct Access {
+    /// The pre-shared key (`--auth-key`); checked before the others.
+    key:        Option<psk::Key>,
+    users:      Option<Users>,
+    #[cfg(feature = "gssapi")]
+    principals: Option<Principals>,
+}
+
+impl Access {
+    fn required(&self) -> bool {
+        #[cfg(feature = "gssapi")]
+        if self.principals.is_some() {
+            return true;
+        }
+        self.users.is_some()
+    }
+}
+
+/// Authenticate a control connection if the client asked to or the
+/// orchestrator requires it. Returns whether the session may go on; a
+/// refusal has already been logged and, where the client can read it, sent.
+fn authenticate(
+            ctrl:   &mut Stream,
+            role:   u8,
+            opts:   &HandshakeOptions,
+            access: &Access,
+            name:   Option<&str>,
+        ) -> io::Result<bool> {
+    // Log lines name the client as well as its address, when it gave one.
+    let peer = match name {
+        Some(name) => format!("{} ({:?})", ctrl.peer(), name),
+        None => ctrl.peer(),
+    };
+    let opt = |key| opts.iter().find(|(k, _)| *k == key).map(|(_, v)| v);
+    match (opt(OPT_AUTH_KEY), &access.key) {
+        (Some(nonce), key) => {
+            ctrl.write_all(&[0, 0])?;
+            if !psk_session(ctrl, role, nonce, key.as_ref(), &peer)? {
+                return Ok(false);
+            }
+        }
+        (None, Some(_)) => {
+            warn!("refusing {} session from {} without the auth key", role_name(role), peer);
+            return Ok(false);
+        }
+        (None, None) => {}
+    }
+    if let Some(first) = opt(OPT_SCRAM) {
+        ctrl.write_all(&[0, 0])?;
+        return scram_session(ctrl, role, first, access.users.as_ref(), &peer);
+    }
+    #[cfg(feature = "gssapi")]
+    if opt(OPT_GSSAPI).is_some() {
+        ctrl.write_all(&[0, 0])?;
+        return gssapi_session(ctrl, role, access.principals.as_ref(), &peer);
+    }
+    if access.required() {
+        warn!("refusing unauthenticated {} session from {}", role_name(role), peer);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// The orchestrator side of a pre-shared key exchange, after the marker.
+/// Anything short of a valid proof closes the connection unanswered.
+fn psk_session(
+            ctrl:  &mut Stream,
+            role:  u8,
+            nonce: &[u8],
+            key:   Option<&psk::Key>,
+            peer:  &str,
+        ) -> io::Result<bool> {
+    let Some(key) = key else {
+        info!("{} offered an auth key, but none is configured", peer);
+        return Ok(false);
+    };
+    if nonce.len() != psk::NONCE_LEN {
+        warn!("bad auth key nonce from {}", peer);
+        return Ok(false);
+    }
+    let ours = psk::nonce()?;
+    ctrl.write_all(&ours)?;
+    ctrl.flush()?;
+    let mut proof = [0u8; DIGEST_LEN];
+    ctrl.read_exact(&mut proof)?;
+    if !psk::same(&proof, &key.client_proof(role, nonce, &ours)) {
+        warn!("wrong auth key from {} ({})", peer, role_name(role));
+        return Ok(false);
+    }
+    ctrl.write_all(&key.server_proof(role, nonce, &ours))?;
+    ctrl.flush()?;
+    Ok(true)
+}
+
+/// The orchestrator side of a SCRAM exchange, after the marker.
+fn scram_session(
+            ctrl:  &mut Stream,
+            role:  u8,
+            first: &[u8],
+            users: Option<&Users>,
+            peer:  &str,
+        ) -> io::Result<bool> {
+    let Some(users) = users else {
+        info!("{} asked to authenticate, but no QPIPE_USERS are configured", peer);
+        scram::write_message(ctrl, "e=authentication-not-configured")?;
+        return Ok(false);
+    };
+    let started = std::str::from_utf8(first)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "scram: not UTF-8"))
+        .and_then(|first| {
+            let name = scram::Server::user_of(first)?;
+            scram::Server::start(first, users.verifier(&name))
+        });
+    let (server, server_first) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            warn!("bad authentication attempt from {}: {}", peer, e);
+            scram::write_message(ctrl, "e=invalid-encoding")?;
+            return Ok(false);
+        }
+    };
+    scram::write_message(ctrl, &server_first)?;
+    let client_final = scram::read_message(ctrl)?;
+    let user = server.user().to_string();
+    match server.finish(&client_final) {
+        Ok(server_final) if users.allows(&user, role) => {
+            info!("{} authenticated as {:?} ({})", peer, user, role_name(role));
+            scram::write_message(ctrl, &server_final)?;
+            Ok(true)
+        }
+        Ok(_) => {
+            warn!("user {:?} at {} may not open {} sessions", user, peer, role_name(role));
+            scram::write_message(ctrl, "e=not-authorized")?;
+            Ok(false)
+        }
+        Err(e) => {
+            warn!("authentication failed for {}: {}", peer, e);
+            scram::write_message(ctrl, "e=invalid-proof")?;
+            Ok(false)
+        }
+    }
+}
+
+/// Kerberos principals allowed in when QPIPE_PRINCIPALS is set: one per
+/// line, `PRINCIPAL ROLES`, with roles as in the users file. `*@REALM`
+/// admits every principal of a realm not listed on its own.
+#[cfg(feature = "gssapi")]
+struct Principals {
+    entries: HashMap<String, Vec<String>>,
+}
+
+#[cfg(feature = "gssapi")]
+impl Principals {
+    fn load(path: &std::path::Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|e| io::Error::new(
+            e.kind(), format!("QPIPE_PRINCIPALS {}: {e}", path.display()),
+        ))?;
+        Self::parse(&text).map_err(|e| io::Error::new(
+            e.kind(), format!("QPIPE_PRINCIPALS {}: {e}", path.display()),
+        ))
+    }
+
+    fn parse(text: &str) -> io::Result<Self> {
+        let mut entries = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = |msg: String| io::Error::new(
+                io::ErrorKind::InvalidInput, format!("line {}: {msg}", n + 1),
+            );
+            let [principal, roles] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(bad("expected PRINCIPAL ROLES".into()));
+            };
+            if !principal.contains('@') {
+                return Err(bad(format!("{principal:?} has no realm")));
+            }
+            let roles = parse_roles(roles).map_err(bad)?;
+            if entries.insert(principal.to_string(), roles).is_some() {
+                return Err(bad(format!("principal {principal:?} listed twice")));
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    fn allows(&self, principal: &str, role: u8) -> bool {
+        let realm = principal.rsplit_once('@').map(|(_, r)| format!("*@{r}"));
+        self.entries.get(principal)
+            .or_else(|| realm.and_then(|r| self.entries.get(&r)))
+            .is_some_and(|roles| roles.iter().any(|r| r == role_name(role)))
+    }
+}
+
+/// The orchestrator side of a Kerberos exchange, after the marker.
+#[cfg(feature = "gssapi")]
+fn gssapi_session(
+            ctrl:       &mut Stream,
+            role:       u8,
+            principals: Option<&Principals>,
+            peer:       &str,
+        ) -> io::Result<bool> {
+    use crate::gssapi;
+
+    let Some(principals) = principals else {
+        info!("{} asked for Kerberos, but no QPIPE_PRINCIPALS are configured", peer);
+        gssapi::refuse(ctrl, "authentication-not-configured")?;
+        return Ok(false);
+    };
+    gssapi::ready(ctrl)?;
+    let (principal, token) = match gssapi::accept(ctrl) {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            warn!("Kerberos authentication failed for {}: {}", peer, e);
+            return Ok(false);
+        }
+    };
+    if !principals.allows(&principal, role) {
+        warn!("principal {:?} at {} may not open {} sessions", principal, peer, role_name(role));
+        gssapi::refuse(ctrl, "not-authorized")?;
+        return Ok(false);
+    }
+    info!("{} authenticated as {:?} ({})", peer, principal, role_name(role));
+    gssapi::done(ctrl, &token)?;
+    Ok(true)
+}
+
+/// Consumer session options negotiated at handshake.
+#[derive(Debug, Default)]
+struct ConsumerSession {
+    /// Settle on explicit acks rather than on the per-frame ACK.
+    ack_mode:  bool,
+    /// Ack mode: at most this many deliveries in flight.
+    weight:    Option<u32>,
+    /// Capability tags the consumer advertised.
+    caps:      BTreeSet<String>,
+    /// Which messages it subscribes to.
+    filter:    Option<Filter>,
+    /// Ack mode: capacity for resource-hinted messages.
+    resources: Option<Resources>,
+    /// Negotiated in `handle_control`, as for producers.
+    heartbeat: Option<Heartbeat>,
+    /// Negotiated in `handle_control`, as for producers.
+    checksum:  bool,
+    /// QPIPE_SESSION_TIMEOUTS, as the session was admitted under.
+    timeouts:  SessionTimeouts,
+}
+
+/// Read a consumer's session from its handshake options. Options that need
+/// ack mode are ignored without it, and so not echoed back; so is a filter
+/// that doesn't parse.
+fn consumer_session(opts: &[(u8, Vec<u8>)]) -> ConsumerSession {
+    let opt = |key| opts.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_slice());
+    let ack_mode = opt(OPT_ACK_MODE).is_some();
+    let weight = opt(OPT_WEIGHT)
+        .and_then(|v| <[u8; 4]>::try_from(v).ok())
+        .map(|b| u32::from_be_bytes(b).max(1))
+        .filter(|_| ack_mode);
+    let caps = opt(OPT_CAPABILITIES)
+        .map(|v| String::from_utf8_lossy(v)
+            .split(',')
+            .filter(|t| !t.is_empty())
+            .map(str::to_string)
+            .collect())
+        .unwrap_or_default();
+    let resources = opt(OPT_RESOURCES)
+        .and_then(|v| <[u8; 12]>::try_from(v).ok())
+        .map(|b| Resources {
+            memory: u64::from_be_bytes(b[..8].try_into().unwrap()),
+            gpus:   u32::from_be_bytes(b[8..].try_into().unwrap()),
+        })
+        .filter(|_| ack_mode);
+    let filter = opt(OPT_FILTER)
+        .and_then(|v| std::str::from_utf8(v).ok())
+        .and_then(|spec| Filter::parse(spec).ok());
+    ConsumerSession {
+        ack_mode, weight, caps, filter, resources, heartbeat: None, checksum: false,
+        timeouts: SessionTimeouts::default(),
+    }
+}
+
+/// A consumer session on its data connection, from registration to its end
+/// (dropping it unregisters the consumer). Between deliveries it either
+/// blocks for work (`serve(false)`) or, served from the reactor, returns to
+/// be parked until its queue wakes it (`serve(true)`, `park`).
+struct ConsumerHandler {
+    stream:        Stream,
+    router:        Arc<Router>,
+    conn:          ConnGuard,
+    cid:           ConsumerId,
+    heartbeat:     Option<Heartbeat>,
+    checksum:      bool,
+    timeouts:      SessionTimeouts,
+    /// Ack mode or heartbeats: frame ACKs, which the back-channel's reader
+    /// (`AckReader`) forwards.
+    frame_acks:    Option<mpsc::Receiver<()>>,
+    /// Pings written whose ACKs haven't been taken from `frame_acks` yet.
+    pings:         usize,
+    own_bucket:    Option<TokenBucket>,
+    last_delivery: Instant,
+    /// The last delivery or ping.
+    last_sent:     Instant,
+    /// Where `poll_next` left the consumer waiting.
+    waiting:       Option<(u64, Option<Duration>)>,
+    /// A reply queue is kept as long as its sessions.
+    _attached:     Attached,
+}
+
+impl ConsumerHandler {
+    /// Register the consumer on `router`. Ack-mode and heartbeat sessions
+    /// come with the reader of their back-channel, for the caller to run.
+    fn new(
+                stream:     Stream,
+                router:     Arc<Router>,
+                conn:       ConnGuard,
+                session:    ConsumerSession,
+                attached:   Attached,
+            ) -> io::Result<(Self, Option<AckReader>)> {
+        debug!("consumer session {:?}", session);
+        let ack_mode = session.ack_mode;
+        let cid = if ack_mode {
+            router.register_ack_consumer()
+        } else {
+            router.register_consumer()
+        };
+        if let Some(w) = session.weight {
+            router.set_window(cid, w);
+        }
+        if let Some(r) = session.resources {
+            router.set_resources(cid, r);
+        }
+        router.set_capabilities(cid, session.caps);
+        if let Some(f) = session.filter {
+            router.set_filter(cid, f);
+        }
+        router.set_peer(cid, stream.peer());
+        let now = router.now();
+        let mut consumer = Self {
+            stream: stream.try_clone()?,
+            router: router.clone(),
+            conn,
+            cid,
+            heartbeat: session.heartbeat,
+            checksum: session.checksum,
+            timeouts: session.timeouts,
+            frame_acks: None,
+            pings: 0,
+            own_bucket: router.per_consumer.map(|r| TokenBucket::new(r, now)),
+            last_delivery: now,
+            last_sent: now,
+            waiting: None,
+            _attached: attached,
+        };
+
+        // Ack mode: the back-channel carries message acks besides frame
+        // ACKs, so a reader owns it and forwards the frame ACKs here. The
+        // same goes for heartbeats, which the reader times. Shutting the
+        // socket down on exit stops the reader.
+        stream.set_write_timeout(consumer.timeouts.write)?;
+        if !ack_mode && consumer.heartbeat.is_none() {
+            stream.set_read_timeout(consumer.timeouts.read)?;
+            return Ok((consumer, None));
+        }
+        let (reader, rx) = AckReader::new(stream, router, cid, consumer.heartbeat)?;
+        consumer.frame_acks = Some(rx);
+        Ok((consumer, Some(reader)))
+    }
+
+    /// Deliver frames until the session is over (`Flow::Closed`) or, with
+    /// `park`, until there is nothing to deliver (`Flow::Idle`); without,
+    /// it blocks for its frames.
+    fn serve(&mut self, park: bool) -> io::Result<Flow> {
+        loop {
+            let next = if park {
+                match self.router.poll_next(self.cid) {
+                    Polled::Frame(frame, meta) => Some((frame, *meta)),
+                    Polled::Wait { epoch, within } => {
+                        self.waiting = Some((epoch, within));
+                        return Ok(Flow::Idle);
+                    }
+                    Polled::Gone | Polled::Empty => None,
+                }
+            } else {
+                match self.wait().map(|at| at.saturating_duration_since(self.router.now())) {
+                    Some(wait) => self.router.next_for_within(self.cid, wait),
+                    None => self.router.next_for(self.cid),
+                }
+            };
+            let open = match next {
+                Some((frame, meta)) => self.deliver(frame, meta)?,
+                None => self.waited(),
+            };
+            if !open {
+                return Ok(Flow::Closed);
+            }
+        }
+    }
+
+    /// When the consumer is due a ping or has been idle too long, if ever.
+    fn wait(&self) -> Option<Instant> {
+        let ping = self.heartbeat.map(|hb| self.last_sent + hb.interval);
+        let idle = self.timeouts.idle.map(|idle| self.last_delivery + idle);
+        ping.into_iter().chain(idle).min()
+    }
+
+    /// How to park the consumer after `serve(true)` found nothing for it:
+    /// the epoch to hand `Router::park_consumer`, and when to look again
+    /// regardless.
+    #[cfg(target_os = "linux")]
+    fn park(&self) -> (u64, Option<Instant>) {
+        let (epoch, within) = self.waiting.expect("parked after Flow::Idle");
+        let now = self.router.now();
+        let wait = within.into_iter()
+            .chain(self.wait().map(|at| at.saturating_duration_since(now)))
+            .min();
+        (epoch, wait.map(|wait| Instant::now() + wait))
+    }
+
+    /// Nothing came for the consumer while it waited: drop it if it was
+    /// kicked or has had nothing for too long, and ping it if its heartbeat
+    /// is due. False once the session is over.
+    fn waited(&mut self) -> bool {
+        if self.router.is_gone(self.cid) {
+            debug!("consumer connection closed");
+            return false;
+        }
+        let now = self.router.now();
+        if let Some(idle) = self.timeouts.idle
+            && now - self.last_delivery >= idle
+        {
+            warn!("consumer {} had nothing delivered for {:?}; dropping it", self.stream.peer(), idle);
+            return false;
+        }
+        let Some(hb) = self.heartbeat.filter(|hb| now - self.last_sent >= hb.interval) else {
+            return true;
+        };
+        // Nothing for a whole interval: ping, so that the consumer can tell
+        // a quiet queue from a dead orchestrator. It may answer only when
+        // it next receives; the reader keeps watch meanwhile, and no other
+        // ping goes out until it has.
+        let rx = self.frame_acks.as_ref().expect("heartbeats run a reader");
+        while self.pings > 0 && rx.try_recv().is_ok() {
+            self.pings -= 1;
+        }
+        if self.pings == 0 {
+            if let Err(e) = put_frame_as(&mut self.stream, &Frame::Ping, &Meta::default(), self.checksum)
+                .and_then(|()| self.stream.flush())
+            {
+                debug!("consumer did not take a ping: {}", e);
+                return false;
+            }
+            self.pings += 1;
+        }
+        self.last_sent = now.max(self.last_sent + hb.interval);
+        true
+    }
+
+    /// Write one frame and wait for its ACK. False once the session is
+    /// over.
+    fn deliver(&mut self, frame: Frame, meta: Meta) -> io::Result<bool> {
+        let (router, cid, timeouts) = (&self.router, self.cid, self.timeouts);
+        let len = frame.payload_len() as u64;
+        let is_data = !matches!(frame, Frame::Eos(_));
+
+        // Egress shaping: the frame is already held for this consumer, so
+        // waiting here delays only this connection.
+        if is_data {
+            if let Some(b) = &mut self.own_bucket {
+                thread::sleep(b.reserve(len, router.now()));
+            }
+            router.pace_egress(len);
+        }
+
+        // A frame's ACK, within the read timeout if there is one; answers
+        // to pings come first.
+        let closed = || io::Error::new(io::ErrorKind::UnexpectedEof, "consumer closed the connection");
+        let frame_ack = |rx: &mpsc::Receiver<()>| match timeouts.read {
+            Some(read) => rx.recv_timeout(read).map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => io::Error::new(
+                    io::ErrorKind::TimedOut, format!("no ACK for a frame within {read:?}"),
+                ),
+                mpsc::RecvTimeoutError::Disconnected => closed(),
+            }),
+            None => rx.recv().map_err(|_| closed()),
+        };
+        let stream = &mut self.stream;
+        let pings = &mut self.pings;
+        let written = put_frame_as(stream, &frame, &meta, self.checksum).and_then(|()| {
+            match &self.frame_acks {
+                Some(rx) => {
+                    while *pings > 0 {
+                        frame_ack(rx)?;
+                        *pings -= 1;
+                    }
+                    frame_ack(rx)
+                }
+                None => read_ack(stream),
+            }
+        });
+        match written {
+            Ok(()) => {
+                router.delivered(cid);
+                self.last_delivery = router.now();
+                self.last_sent = self.last_delivery;
+                if is_data {
+                    self.conn.collected(len);
+                }
+                stream.flush().ok();
+                // Ack mode holds a copy for redelivery; this one is done.
+                frame.recycle();
+                Ok(true)
+            }
+            Err(e) => {
+                if is_data {
+                    self.conn.stats.dropped_msgs.fetch_add(1, Ordering::Relaxed);
+                    self.conn.stats.dropped_bytes.fetch_add(len, Ordering::Relaxed);
+                }
+
+                // write_frame only returns Ok after the consumer's ACK, so
+                // this frame never arrived. Let the router salvage what it
+                // can: singles and never-ACKed first chunks are requeued for
+                // another consumer; messages that already had chunks ACKed
+                // by this (now dead) consumer are doomed and tombstoned.
+                if router.fail_delivery(cid, frame) {
+                    debug!("requeued undelivered frame for another consumer");
+                }
+                // Kicked by its reader: it said goodbye or went silent, and
+                // that was logged there.
+                if router.is_gone(cid) {
+                    debug!("consumer {} is gone: {}", stream.peer(), e);
+                    return Ok(false);
+                }
+
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::BrokenPipe
+                        | io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::UnexpectedEof
+                ) {
+                    warn!("Write failed with: '{}'. Dropping client.", e);
+                    return Ok(false);
+                }
+                if Heartbeat::missed(&e) {
+                    warn!("consumer {} stalled ({}); dropping it", stream.peer(), e);
+                    return Ok(false);
+                }
+                error!("Write failed with: '{}'. Dropping client.", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for ConsumerHandler {
+    // The directed queue and any owned assignments must be cleaned up on
+    // EVERY exit path, or frames leak and drain never ends.
+    fn drop(&mut self) {
+        if self.frame_acks.is_some() {
+            self.stream.shutdown(Shutdown::Both).ok();
+        }
+        self.router.unregister_consumer(self.cid);
+    }
+}
+
+/// Reader half of an ack-mode or heartbeat consumer session: frame ACKs go
+/// to its handler, message acks and window changes straight to the router.
+/// When the connection ends (or misbehaves, or is silent past the
+/// heartbeat timeout) the consumer is kicked, so its handler stops waiting
+/// for work and requeues what it still holds.
+struct AckReader {
+    rd:        Stream,
+    router:    Arc<Router>,
+    cid:       ConsumerId,
+    heartbeat: Option<Heartbeat>,
+    tx:        mpsc::Sender<()>,
+}
+
+impl AckReader {
+    fn new(
+                rd:        Stream,
+                router:    Arc<Router>,
+                cid:       ConsumerId,
+                heartbeat: Option<Heartbeat>,
+            ) -> io::Result<(Self, mpsc::Receiver<()>)> {
+        let (tx, rx) = mpsc::channel();
+        if let Some(hb) = heartbeat {
+            rd.set_read_timeout(Some(hb.timeout))?;
+        }
+        Ok((Self { rd, router, cid, heartbeat, tx }, rx))
+    }
+
+    /// Read the back-channel on a thread of its own.
+    fn spawn(mut self) -> io::Result<()> {
+        spawn_session("qpipe-acks", move || {
+            let res = self.serve(false);
+            self.end(res);
+        })
+    }
+
+    /// Take records until the back-channel is done (`Flow::Closed`) or,
+    /// with `park`, until none has arrived; without, it blocks for them.
+    fn serve(&mut self, park: bool) -> io::Result<Flow> {
+        loop {
+            #[cfg(target_os = "linux")]
+            if park && !self.rd.readable()? {
+                return Ok(Flow::Idle);
+            }
+            #[cfg(not(target_os = "linux"))]
+            let _ = park;
+            if !self.record()? {
+                return Ok(Flow::Closed);
+            }
+        }
+    }
+
+    /// When a reader waiting for its next record has waited too long.
+    #[cfg(target_os = "linux")]
+    fn deadline(&self) -> Option<Instant> {
+        self.heartbeat.map(|hb| Instant::now() + hb.timeout)
+    }
+
+    /// Take one record; false once the back-channel is done.
+    fn record(&mut self) -> io::Result<bool> {
+        let (rd, router, cid) = (&mut self.rd, &self.router, self.cid);
+        let mut b = [0u8; 1];
+        rd.read_exact(&mut b)?;
+        match b[0] {
+            ACK_PAYLOAD => {
+                if self.tx.send(()).is_err() {
+                    return Ok(false); // handler already gone
+                }
+            }
+            ACK_MESSAGE => {
+                let mut tag = [0u8; 8];
+                rd.read_exact(&mut tag)?;
+                let tag = u64::from_be_bytes(tag);
+                if !router.ack(cid, tag) {
+                    debug!("ignoring ack for stale delivery tag {}", tag);
+                }
+            }
+            ACK_NACK => {
+                let mut rec = [0u8; 12];
+                rd.read_exact(&mut rec)?;
+                let tag = u64::from_be_bytes(rec[..8].try_into().unwrap());
+                let retries = u32::from_be_bytes(rec[8..].try_into().unwrap());
+                if !router.nack(cid, tag, retries) {
+                    debug!("ignoring nack for stale delivery tag {}", tag);
+                }
+            }
+            ACK_WINDOW => {
+                let mut window = [0u8; 4];
+                rd.read_exact(&mut window)?;
+                router.set_window(cid, u32::from_be_bytes(window));
+            }
+            ACK_PING => {} // only here to be heard
+            ACK_BYE => {
+                info!("consumer {} closed its session", rd.peer());
+                return Ok(false);
+            }
+            b => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected consumer back-channel byte 0x{b:02x}"),
+                ));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Log how the back-channel ended, and kick the consumer.
+    fn end(self, res: io::Result<Flow>) {
+        match res {
+            Err(e) if self.heartbeat.is_some() && Heartbeat::missed(&e) => warn!(
+                "consumer {} sent nothing for {:?}; dropping it",
+                self.rd.peer(), self.heartbeat.map(|hb| hb.timeout).unwrap_or_default(),
+            ),
+            Err(e) => debug!("consumer back-channel closed: {}", e),
+            Ok(_) => {}
+        }
+        self.router.kick(self.cid);
+    }
+}
+
+// Deterministic, single-threaded tests for the Router's claim / redirect /
+// tombstone logic. Each sequence is arranged so pop_for never blocks.
+#[cfg(test)]
+mod router_tests {
+    use super::*;
+    use crate::Frame;
+
+    fn mk(capacity: usize) -> Router {
+        Router::new(capacity, Arc::new(Stats::default()))
+    }
+
+    fn ch(id: u128, idx: u32, count: u32) -> Frame {
+        Frame::Chunk { id, idx, count, payload: vec![idx as u8] }
+    }
+
+    #[test]
+    fn singles_flow_through() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        assert!(r.push(Frame::Msg(b"x".to_vec())));
+        assert_eq!(r.pop_for(a), Frame::Msg(b"x".to_vec()));
+        assert_eq!(r.gauges(), (0, 0, 0));
+    }
+
+    #[test]
+    fn chunks_follow_the_claiming_consumer() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        let b = r.register_consumer();
+        for f in [
+            ch(1, 0, 3), ch(1, 1, 3), ch(1, 2, 3),
+            Frame::Msg(b"solo".to_vec()),
+        ] {
+            assert!(r.push(f));
+        }
+
+        // A pops first and thereby claims message 1.
+        assert_eq!(r.pop_for(a), ch(1, 0, 3));
+        // B pops chunks 1 and 2, redirects both into A's directed queue, and
+        // ends up delivering the unrelated single-frame message.
+        assert_eq!(r.pop_for(b), Frame::Msg(b"solo".to_vec()));
+        // A drains its directed queue.
+        assert_eq!(r.pop_for(a), ch(1, 1, 3));
+        assert_eq!(r.pop_for(a), ch(1, 2, 3));
+        // Completion removed the assignment; nothing left anywhere.
+        assert_eq!(r.gauges(), (0, 0, 0));
+    }
+
+    #[test]
+    fn consumer_death_tombstones_its_messages() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        assert!(r.push(ch(9, 0, 3)));
+        assert_eq!(r.pop_for(a), ch(9, 0, 3)); // claim
+        r.unregister_consumer(a);
+
+        // Stragglers of the dead message are refused at the door...
+        assert!(!r.push(ch(9, 1, 3)));
+        assert_eq!(r.gauges(), (0, 0, 1));
+
+        // ...and the tombstone itself eventually ages out.
+        let (expired, purged) =
+            r.sweep(Duration::from_secs(600), Duration::ZERO);
+        assert_eq!((expired, purged), (0, 1));
+        assert_eq!(r.gauges(), (0, 0, 0));
+    }
+
+    #[test]
+    fn unregister_drops_directed_frames() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        let b = r.register_consumer();
+        for f in [ch(5, 0, 2), ch(5, 1, 2), Frame::Msg(b"x".to_vec())] {
+            assert!(r.push(f));
+        }
+        assert_eq!(r.pop_for(a), ch(5, 0, 2));               // A claims msg 5
+        assert_eq!(r.pop_for(b), Frame::Msg(b"x".to_vec())); // 5/1 → A's queue
+        assert_eq!(r.depth(), 1);
+        r.unregister_consumer(a); // drops the parked frame, tombstones msg 5
+        assert_eq!(r.gauges(), (0, 0, 1));
+    }
+
+    #[test]
+    fn stale_assignments_expire_into_tombstones() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        assert!(r.push(ch(7, 0, 2)));
+        assert_eq!(r.pop_for(a), ch(7, 0, 2));
+        assert_eq!(r.gauges().1, 1);
+
+        // assign_ttl = 0 expires immediately; the entry becomes a tombstone.
+        let (expired, _) = r.sweep(Duration::ZERO, Duration::from_secs(600));
+        assert_eq!(expired, 1);
+        let (_, assigns, tombs) = r.gauges();
+        assert_eq!((assigns, tombs), (0, 1));
+        assert!(!r.push(ch(7, 1, 2)), "late chunk is dropped, not re-claimed");
+    }
+
+    #[test]
+    fn tombstones_outlive_their_ttl_while_frames_are_queued() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        for idx in 0..3 {
+            assert!(r.push(ch(7, idx, 3)));
+        }
+        assert_eq!(r.pop_for(a), ch(7, 0, 3));
+        r.delivered(a);
+        r.unregister_consumer(a); // message 7 is doomed, 2 chunks still queued
+
+        assert_eq!(r.sweep(Duration::ZERO, Duration::ZERO), (0, 0), "stragglers queued");
+        let b = r.register_consumer();
+        assert!(r.push(Frame::Msg(b"after".to_vec())));
+        assert_eq!(r.pop_for(b), Frame::Msg(b"after".to_vec()), "stragglers dropped");
+        assert_eq!(r.sweep(Duration::ZERO, Duration::ZERO), (0, 1));
+    }
+
+    #[test]
+    fn users_file_maps_names_to_roles() {
+        let v = Verifier::new("pw", 16).unwrap();
+        let users = Users::parse(&format!(
+            "# comment\n\nalice producer,consumer {v}\nops admin {v}\n",
+        )).unwrap();
+        assert!(users.allows("alice", ROLE_PRODUCER) && users.allows("alice", ROLE_CONSUMER));
+        assert!(!users.allows("alice", ROLE_DRAIN) && users.allows("ops", ROLE_EXPORT));
+        assert!(!users.allows("mallory", ROLE_PRODUCER));
+        assert_eq!(users.verifier("alice"), v);
+        assert_eq!(users.verifier("mallory"), users.verifier("mallory"));
+
+        for bad in [
+            format!("alice root {v}"),
+            format!("alice producer {v}\nalice consumer {v}"),
+            "alice producer plaintext".to_string(),
+            "alice producer".to_string(),
+        ] {
+            assert!(Users::parse(&bad).is_err(), "{bad}");
+        }
+    }
+
+    #[cfg(feature = "gssapi")]
+    #[test]
+    fn principals_map_to_roles_with_realm_wildcards() {
+        let p = Principals::parse(
+            "alice@LAB.EXAMPLE producer,admin\n*@HPC.EXAMPLE consumer\nbob@HPC.EXAMPLE producer\n",
+        ).unwrap();
+        assert!(p.allows("alice@LAB.EXAMPLE", ROLE_SHUTDOWN));
+        assert!(!p.allows("alice@LAB.EXAMPLE", ROLE_CONSUMER));
+        assert!(p.allows("carol@HPC.EXAMPLE", ROLE_CONSUMER));
+        // An explicit entry replaces the realm's, it doesn't add to it.
+        assert!(p.allows("bob@HPC.EXAMPLE", ROLE_PRODUCER));
+        assert!(!p.allows("bob@HPC.EXAMPLE", ROLE_CONSUMER));
+        assert!(!p.allows("eve@OTHER.EXAMPLE", ROLE_CONSUMER));
+        assert!(Principals::parse("alice producer").is_err(), "no realm");
+        assert!(Principals::parse("a@R producer\na@R consumer").is_err());
+    }
+
+    #[test]
+    fn export_copies_or_drains_what_nobody_holds() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        assert!(r.push(ch(7, 0, 2)));
+        assert_eq!(r.pop_for(a), ch(7, 0, 2)); // message 7 is a's now
+        assert!(r.push(Frame::Msg(b"low".to_vec())));
+        assert!(r.push_with(Frame::Msg(b"high".to_vec()), Meta { priority: Some(5), ..Meta::default() }));
+        assert!(r.push(ch(7, 1, 2)));
+
+        let frames = |items: &[Item]| items.iter().map(|it| it.frame.clone()).collect::<Vec<_>>();
+        let want = [Frame::Msg(b"high".to_vec()), Frame::Msg(b"low".to_vec())];
+        assert_eq!(frames(&r.export(false)), want, "serving order, claimed chunk left out");
+        assert_eq!(r.depth(), 3, "a copy leaves the queue alone");
+
+        let held = r.export(true);
+        assert_eq!(frames(&held), want);
+        assert_eq!(r.depth(), 1);
+        r.finish_export(held, false);
+        assert_eq!(r.depth(), 3, "unconfirmed drains are requeued");
+
+        let held = r.export(true);
+        r.finish_export(held, true);
+        assert_eq!(r.depth(), 1);
+        assert_eq!(r.outstanding(), 2, "a's chunks are still in flight");
+        assert_eq!(r.stats.exported_msgs.load(Ordering::Relaxed), 2);
+        assert_eq!(r.pop_for(a), ch(7, 1, 2));
+    }
+
+    #[test]
+    fn imports_go_in_behind_the_queue_in_order_past_capacity() {
+        let r = mk(2);
+        let a = r.register_consumer();
+        assert!(r.push(Frame::Msg(b"queued".to_vec())));
+        r.import(["one", "two", "three"].map(|m| (Frame::Msg(m.as_bytes().to_vec()), Meta::default())).into());
+        assert_eq!(r.depth(), 4, "imports don't wait for room");
+        for want in ["queued", "one", "two", "three"] {
+            assert_eq!(r.pop_for(a), Frame::Msg(want.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn first_chunk_write_failure_is_salvaged() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        let b = r.register_consumer();
+        assert!(r.push(ch(3, 0, 2)));
+        let f = r.pop_for(a); // A claims msg 3 — but the write will fail
+        assert_eq!(f, ch(3, 0, 2));
+
+        assert!(r.fail_delivery(a, f), "never-ACKed first chunk is requeued");
+        assert_eq!(r.gauges(), (1, 0, 0)); // back in queue; claim rescinded
+
+        assert_eq!(r.pop_for(b), ch(3, 0, 2)); // B re-claims from scratch
+        assert!(r.push(ch(3, 1, 2)), "message is alive, not tombstoned");
+    }
+
+    #[test]
+    fn later_chunk_write_failure_dooms_message() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        for f in [ch(4, 0, 3), ch(4, 1, 3)] {
+            assert!(r.push(f));
+        }
+        assert_eq!(r.pop_for(a), ch(4, 0, 3)); // delivered + ACKed (simulated)
+        r.delivered(a);
+        let f = r.pop_for(a);                  // this one's write fails
+        assert_eq!(f, ch(4, 1, 3));
+
+        assert!(!r.fail_delivery(a, f), "chunk 0 died with the consumer");
+        assert_eq!(r.gauges(), (0, 0, 1));
+        assert!(!r.push(ch(4, 2, 3)), "stragglers are refused");
+    }
+
+    #[test]
+    fn salvage_then_unregister_loses_nothing() {
+        // The "zombie handler" scenario: a disconnected consumer's handler
+        // (parked in pop_for; disconnects are detected lazily) claims a
+        // fresh message's first chunk, the write fails, and a chunk that
+        // was redirected to it meanwhile must survive its unregistration.
+        let r = mk(8);
+        let a = r.register_consumer();
+        let b = r.register_consumer();
+        for f in [ch(6, 0, 2), ch(6, 1, 2), Frame::Msg(b"x".to_vec())] {
+            assert!(r.push(f));
+        }
+
+        let first = r.pop_for(a);                            // A claims msg 6
+        assert_eq!(first, ch(6, 0, 2));
+        assert_eq!(r.pop_for(b), Frame::Msg(b"x".to_vec())); // B parks 6/1 at A
+
+        assert!(r.fail_delivery(a, first)); // claim rescinded, chunk 0 requeued
+        r.unregister_consumer(a);           // 6/1 must be requeued, not dropped
+
+        assert_eq!(r.pop_for(b), ch(6, 0, 2)); // B takes the whole message
+        assert_eq!(r.pop_for(b), ch(6, 1, 2));
+        assert_eq!(r.gauges(), (0, 0, 0));     // completed; no tombstones
+    }
+
+    #[test]
+    fn outstanding_counts_until_delivery_is_confirmed() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        assert!(r.push(Frame::Msg(b"x".to_vec())));
+        assert!(r.push(Frame::Eos(Vec::new()))); // markers are not work
+        assert_eq!(r.outstanding(), 1);
+        assert!(!r.is_idle());
+
+        let f = r.pop_for(a);
+        assert_eq!(r.outstanding(), 1, "written but unACKed still counts");
+        assert!(r.fail_delivery(a, f));
+        assert_eq!(r.outstanding(), 1, "requeued frames still count");
+
+        assert_eq!(r.pop_for(a), Frame::Msg(b"x".to_vec()));
+        r.delivered(a);
+        assert_eq!(r.outstanding(), 0);
+        assert!(r.is_idle());
+    }
+
+    #[test]
+    fn eos_waits_for_earlier_frames_then_reaches_everyone() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        let b = r.register_consumer();
+        for f in [
+            Frame::Msg(b"1".to_vec()),
+            Frame::Eos(b"g".to_vec()),
+            Frame::Msg(b"2".to_vec()),
+        ] {
+            assert!(r.push(f));
+        }
+
+        // A holds "1" (written, not yet ACKed). B parks the marker as a
+        // barrier and moves on to the post-marker frame.
+        assert_eq!(r.pop_for(a), Frame::Msg(b"1".to_vec()));
+        assert_eq!(r.pop_for(b), Frame::Msg(b"2".to_vec()));
+        r.delivered(b);
+
+        // Only once "1" is confirmed does the notice fan out to both.
+        r.delivered(a);
+        assert_eq!(r.pop_for(a), Frame::Eos(b"g".to_vec()));
+        assert_eq!(r.pop_for(b), Frame::Eos(b"g".to_vec()));
+        assert_eq!(r.gauges(), (0, 0, 0));
+    }
+
+    #[test]
+    fn eos_is_not_released_past_a_requeued_frame() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        let b = r.register_consumer();
+        assert!(r.push(Frame::Msg(b"x".to_vec())));
+        assert!(r.push(Frame::Eos(Vec::new())));
+
+        let f = r.pop_for(a);
+        assert!(r.fail_delivery(a, f)); // requeued BEHIND the marker
+
+        // B parks the marker, then must deliver "x" before anyone sees EOS.
+        assert_eq!(r.pop_for(b), Frame::Msg(b"x".to_vec()));
+        r.delivered(b);
+        assert_eq!(r.pop_for(b), Frame::Eos(Vec::new()));
+        assert_eq!(r.pop_for(a), Frame::Eos(Vec::new()));
+    }
+
+    // ---- shards ----
+
+    fn mk_sharded(n: usize) -> Arc<Router> {
+        let stats = Arc::new(Stats::default());
+        let members = (0..n).map(|_| Router::new(8, stats.clone())).collect();
+        Arc::new(Router::new(8, stats).with_shards(members))
+    }
+
+    #[test]
+    fn an_idle_shard_steals_and_eos_waits_for_every_shard() {
+        let q = mk_sharded(2);
+        let shards = q.shard_routers();
+        let (p0, p1) = (q.for_producer(), q.for_producer());
+        assert!(Arc::ptr_eq(&p0, &shards[0]) && Arc::ptr_eq(&p1, &shards[1]));
+        let c = q.for_consumer();
+        let me = c.register_consumer();
+        assert!(Arc::ptr_eq(&c, &shards[0]));
+        assert!(Arc::ptr_eq(&q.for_consumer(), &shards[1]), "the emptier shard");
+
+        for m in [b"a", b"b", b"c"] {
+            assert!(p1.push(Frame::Msg(m.to_vec())));
+        }
+        assert!(p0.push(Frame::Eos(b"g".to_vec())));
+
+        // Shard 1 has no consumer: shard 0's steals its frames, which stay
+        // outstanding over there until delivered here.
+        for m in [b"a", b"b", b"c"] {
+            assert_eq!(c.try_next_for(me).map(|(f, _)| f), Some(Frame::Msg(m.to_vec())));
+        }
+        assert_eq!((shards[1].depth(), shards[1].outstanding()), (0, 0));
+        assert_eq!(c.outstanding(), 3);
+        assert!(c.try_next_for(me).is_none(), "shard 0 released the marker, shard 1 can't yet");
+        c.delivered(me);
+        c.delivered(me);
+        assert!(c.try_next_for(me).is_none());
+        c.delivered(me);
+        assert_eq!(c.try_next_for(me).map(|(f, _)| f), Some(Frame::Eos(b"g".to_vec())));
+        assert!(c.try_next_for(me).is_none(), "one notice per marker");
+    }
+
+    #[test]
+    fn partition_keys_pick_a_shard_and_the_front_reaches_them_all() {
+        let q = mk_sharded(3);
+        let shards = q.shard_routers();
+        let depths = || shards.iter().map(|s| s.depth()).collect::<Vec<_>>();
+        let keyed = Meta { partition: Some(b"k".to_vec()), ..Meta::default() };
+        for _ in 0..3 {
+            assert!(q.for_producer().push_with(Frame::Msg(b"x".to_vec()), keyed.clone()));
+        }
+        let home = q.shards.as_ref().unwrap().link.for_key(b"k");
+        let want: Vec<usize> = (0..3).map(|i| if i == home { 3 } else { 0 }).collect();
+        assert_eq!(depths(), want, "every producer's keyed frames go to the key's shard");
+
+        // Keyed frames stay for a consumer of their shard.
+        let other = &shards[(home + 1) % 3];
+        let me = other.register_consumer();
+        let owner = shards[home].register_consumer();
+        assert!(other.try_next_for(me).is_none());
+
+        // Frames offered to the queue itself go round the shards.
+        for _ in 0..3 {
+            assert!(q.push(Frame::Msg(b"y".to_vec())));
+        }
+        assert_eq!(depths().iter().sum::<usize>(), 6);
+        assert!(depths().iter().all(|&d| d >= 1));
+
+        q.set_intake(Intake::Paused);
+        assert!(shards.iter().all(|s| s.intake() == Intake::Paused));
+        assert_eq!(q.purge().unwrap(), 6);
+        assert_eq!(depths(), [0, 0, 0]);
+
+        // Without one, an idle consumer elsewhere steals them in order, and
+        // the key stays with it until they settle.
+        q.set_intake(Intake::Open);
+        let keyed_msg = |m: &[u8]| shards[home].push_with(Frame::Msg(m.to_vec()), keyed.clone());
+        assert!(keyed_msg(b"1") && keyed_msg(b"2"));
+        shards[home].unregister_consumer(owner);
+        assert_eq!(other.try_next_for(me).map(|(f, _)| f), Some(Frame::Msg(b"1".to_vec())));
+        let owner = shards[home].register_consumer();
+        assert!(shards[home].try_next_for(owner).is_none(), "the key is lent");
+        other.delivered(me);
+        assert_eq!(shards[home].try_next_for(owner).map(|(f, _)| f), Some(Frame::Msg(b"2".to_vec())));
+    }
+
+    #[test]
+    fn shards_without_consumers_lend_whole_chunked_and_tagged_messages() {
+        let q = mk_sharded(2);
+        let shards = q.shard_routers();
+        let chunk = |id, idx| Frame::Chunk { id, idx, count: 2, payload: vec![idx as u8] };
+        assert!(shards[1].push(chunk(7, 0)));
+        assert!(shards[1].push_with(Frame::Msg(b"train".to_vec()), needs(&["gpu"])));
+        let me = shards[0].register_consumer();
+        // Half a message isn't lent, nor what no consumer here can take.
+        assert!(shards[0].try_next_for(me).is_none());
+        assert!(shards[1].push(chunk(7, 1)));
+        assert_eq!(shards[0].try_next_for(me).map(|(f, _)| f), Some(chunk(7, 0)));
+        shards[0].delivered(me);
+        assert_eq!(shards[0].try_next_for(me).map(|(f, _)| f), Some(chunk(7, 1)));
+        shards[0].delivered(me);
+        assert!(shards[0].try_next_for(me).is_none());
+
+        let gpu = shards[0].register_consumer();
+        shards[0].set_capabilities(gpu, caps(&["gpu"]));
+        assert_eq!(shards[0].try_next_for(gpu).map(|(f, _)| f), Some(Frame::Msg(b"train".to_vec())));
+        assert_eq!(shards[1].depth(), 0);
+    }
+
+    // ---- message TTLs ----
+
+    #[test]
+    fn messages_past_their_ttl_are_dropped_unseen_and_counted() {
+        let r = Router::simulated(64, RetryPolicy::default(), 7);
+        let sim = r.sim.as_ref().unwrap();
+        let ttl = |secs| Meta { ttl: Some(Duration::from_secs(secs)), ..Meta::default() };
+        let chunk = |id, idx| Frame::Chunk { id, idx, count: 2, payload: vec![idx as u8] };
+        assert!(r.push_with(Frame::Msg(b"short".to_vec()), ttl(10)));
+        assert!(r.push(Frame::Msg(b"forever".to_vec())));
+        assert!(r.push_with(chunk(9, 0), ttl(10)));
+        assert!(r.push_with(chunk(9, 1), ttl(10)));
+        assert!(r.push_with(Frame::Msg(b"long".to_vec()), ttl(60)));
+        sim.advance(Duration::from_secs(10));
+
+        // Popping skips the expired message and both chunks of the other.
+        let me = r.register_consumer();
+        assert_eq!(r.pop_for(me), Frame::Msg(b"forever".to_vec()));
+        assert_eq!(r.pop_for(me), Frame::Msg(b"long".to_vec()));
+        assert_eq!(r.depth(), 0);
+        assert_eq!(r.stats.expired_msgs.load(Ordering::Relaxed), 2, "a chunked message counts once");
+        assert_eq!(r.stats.dropped_msgs.load(Ordering::Relaxed), 0);
+
+        // A claimed message keeps going: its consumer has the first part.
+        assert!(r.push_with(chunk(10, 0), ttl(10)));
+        assert!(r.push_with(chunk(10, 1), ttl(10)));
+        assert!(matches!(r.pop_for(me), Frame::Chunk { idx: 0, .. }));
+        sim.advance(Duration::from_secs(10));
+        assert!(matches!(r.pop_for(me), Frame::Chunk { idx: 1, .. }));
+    }
+
+    #[test]
+    fn repeated_message_ids_are_counted_within_the_window() {
+        let r = Router::new(64, Arc::new(Stats::default())).with_dedup(2);
+        let id = |id: &str| Meta { message_id: Some(id.as_bytes().to_vec()), ..Meta::default() };
+        let msg = || Frame::Msg(b"m".to_vec());
+        let chunk = |id, idx| Frame::Chunk { id, idx, count: 2, payload: vec![idx as u8] };
+        assert!(!r.repeats(&msg(), &id("a")));
+        assert!(r.repeats(&msg(), &id("a")));
+        assert!(!r.repeats(&msg(), &Meta::default()), "messages without an id always go through");
+        assert!(!r.repeats(&Frame::Eos(Vec::new()), &id("a")));
+
+        // Later chunks of the message that claimed an id are its own.
+        assert!(!r.repeats(&chunk(9, 0), &id("b")));
+        assert!(!r.repeats(&chunk(9, 1), &id("b")));
+        assert!(r.repeats(&chunk(9, 1), &id("b")), "a resent chunk");
+        assert!(r.repeats(&chunk(10, 0), &id("b")), "another message under the id");
+        assert!(r.repeats(&msg(), &id("b")));
+        assert_eq!(r.stats.duplicate_msgs.load(Ordering::Relaxed), 4);
+
+        // Only the last two ids are remembered.
+        assert!(!r.repeats(&msg(), &id("c")));
+        assert!(!r.repeats(&msg(), &id("a")), "forgotten");
+        assert!(r.repeats(&msg(), &id("c")));
+
+        // An id given back is free again, and drops out of the window.
+        r.forget(b"c");
+        assert!(!r.repeats(&msg(), &id("c")));
+        assert!(r.repeats(&msg(), &id("a")), "still remembered");
+
+        let off = Router::new(64, Arc::new(Stats::default())).with_dedup(0);
+        assert!(!off.repeats(&msg(), &id("a")));
+        assert!(!off.repeats(&msg(), &id("a")));
+    }
+
+    #[test]
+    fn the_sweep_expires_messages_nobody_pops_and_releases_eos_behind_them() {
+        let r = Router::simulated(64, RetryPolicy::default(), 7);
+        let me = r.register_consumer();
+        let meta = Meta { ttl: Some(Duration::from_secs(1)), ..Meta::default() };
+        assert!(r.push_with(Frame::Msg(b"late".to_vec()), meta));
+        assert!(r.push(Frame::Eos(b"batch".to_vec())));
+        assert_eq!(r.expire_stale(), 0, "not due yet");
+        r.sim.as_ref().unwrap().advance(Duration::from_secs(1));
+        assert_eq!(r.expire_stale(), 1);
+        assert_eq!(r.depth(), 1, "only the EOS marker is left");
+        assert_eq!(r.try_next_for(me).map(|(f, _)| f), Some(Frame::Eos(b"batch".to_vec())));
+    }
+
+    // ---- delayed messages ----
+
+    #[test]
+    fn delayed_messages_wait_for_their_time_then_queue_as_if_just_sent() {
+        let r = Router::simulated(64, RetryPolicy::default(), 7);
+        let sim = r.sim.as_ref().unwrap();
+        let after = |secs| Meta { delay: Some(Duration::from_secs(secs)), ..Meta::default() };
+        assert!(r.push_with(Frame::Msg(b"in-5s".to_vec()), after(5)));
+        assert!(r.push_with(Frame::Msg(b"in-2s".to_vec()), after(2)));
+        assert!(r.push(Frame::Msg(b"now".to_vec())));
+        assert!(r.push(Frame::Eos(b"batch".to_vec())));
+        assert_eq!(r.depth(), 4, "delayed messages take up room meanwhile");
+
+        let me = r.register_consumer();
+        let take = || {
+            let f = r.try_next_for(me).map(|(f, _)| f);
+            if matches!(f, Some(Frame::Msg(_))) {
+                r.delivered(me);
+            }
+            f
+        };
+        assert_eq!(take(), Some(Frame::Msg(b"now".to_vec())));
+        assert_eq!(take(), None, "the EOS waits for the delayed messages");
+        sim.advance(Duration::from_secs(2));
+        assert_eq!(take(), Some(Frame::Msg(b"in-2s".to_vec())));
+        assert_eq!(take(), None);
+
+        // Its TTL only starts once it's due.
+        let ttl = Meta { ttl: Some(Duration::from_secs(1)), ..after(5) };
+        assert!(r.push_with(Frame::Msg(b"fleeting".to_vec()), ttl));
+        sim.advance(Duration::from_secs(3));
+        assert_eq!(take(), Some(Frame::Msg(b"in-5s".to_vec())));
+        assert_eq!(take(), Some(Frame::Eos(b"batch".to_vec())));
+        assert_eq!(take(), None);
+        sim.advance(Duration::from_secs(2));
+        assert_eq!(take(), Some(Frame::Msg(b"fleeting".to_vec())));
+    }
+
+    // ---- ack mode & retry policy ----
+
+    fn mk_policy(policy: RetryPolicy) -> Router {
+        Router::with_policy(8, Arc::new(Stats::default()), policy)
+    }
+
+    /// Immediate redelivery, so expiries never leave items in `delayed`.
+    fn no_backoff(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            backoff_min: Duration::ZERO,
+            backoff_max: Duration::ZERO,
+            ..RetryPolicy::default()
+        }
+    }
+
+    fn next(r: &Router, me: ConsumerId) -> (Frame, Meta) {
+        r.next_for(me).expect("consumer was kicked")
+    }
+
+    fn past_deadline() -> Instant {
+        Instant::now() + RetryPolicy::default().visibility_timeout
+            + Duration::from_secs(1)
+    }
+
+    #[test]
+    fn ack_mode_settles_on_ack_not_on_frame_ack() {
+        let r = mk_policy(no_backoff(5));
+        let a = r.register_ack_consumer();
+        assert!(r.push(Frame::Msg(b"x".to_vec())));
+
+        let (f, meta) = next(&r, a);
+        assert_eq!(f, Frame::Msg(b"x".to_vec()));
+        assert_eq!(meta.attempt, Some(0));
+        let tag = meta.delivery.expect("ack mode tags deliveries");
+        r.delivered(a);
+        assert_eq!(r.outstanding(), 1, "frame ACK alone does not settle");
+
+        assert!(r.ack(a, tag));
+        assert_eq!(r.outstanding(), 0);
+        assert!(!r.ack(a, tag), "a tag settles once");
+    }
+
+    #[test]
+    fn unacked_delivery_times_out_and_is_redelivered() {
+        let r = mk_policy(no_backoff(5));
+        let a = r.register_ack_consumer();
+        let b = r.register_ack_consumer();
+        assert!(r.push(Frame::Msg(b"x".to_vec())));
+
+        let (_, first) = next(&r, a);
+        r.delivered(a);
+        assert_eq!(r.expire_unacked(Instant::now()), 0, "not yet due");
+        assert_eq!(r.expire_unacked(past_deadline()), 1);
+
+        let (f, second) = next(&r, b);
+        assert_eq!(f, Frame::Msg(b"x".to_vec()));
+        assert_eq!(second.attempt, Some(1));
+        assert_ne!(second.delivery, first.delivery);
+
+        // A's late ack must not settle B's delivery.
+        assert!(!r.ack(a, first.delivery.unwrap()));
+        assert_eq!(r.outstanding(), 1);
+        r.delivered(b);
+        assert!(r.ack(b, second.delivery.unwrap()));
+        assert_eq!(r.outstanding(), 0);
+    }
+
+    /// In-memory dead-letter / audit sink.
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn exhausted_retries_go_to_the_dead_letters() {
+        let r = mk_policy(no_backoff(1));
+        let sink = Sink::default();
+        r.set_dead_letter_sink(Box::new(sink.clone()));
+        let a = r.register_ack_consumer();
+        assert!(r.push(Frame::Msg(b"poison".to_vec())));
+
+        for attempt in 0..2 {
+            let (_, meta) = next(&r, a);
+            assert_eq!(meta.attempt, Some(attempt));
+            r.delivered(a);
+            assert_eq!(r.expire_unacked(past_deadline()), 1);
+        }
+        assert_eq!(r.outstanding(), 0, "dead letters are settled");
+        assert_eq!(r.depth(), 0);
+        assert_eq!(r.stats.redelivered_msgs.load(Ordering::Relaxed), 1);
+        assert_eq!(r.stats.dead_lettered_msgs.load(Ordering::Relaxed), 1);
+
+        let buf = sink.0.lock().unwrap().clone();
+        let (f, meta) = crate::get_frame(&mut &buf[..]).unwrap().unwrap();
+        assert_eq!(f, Frame::Msg(b"poison".to_vec()));
+        assert_eq!(meta.attempt, Some(2));
+    }
+
+    #[test]
+    fn nacks_requeue_at_once_until_their_retries_run_out() {
+        let r = mk_policy(no_backoff(5));
+        let sink = Sink::default();
+        r.set_dead_letter_sink(Box::new(sink.clone()));
+        let a = r.register_ack_consumer();
+        let b = r.register_ack_consumer();
+        assert!(r.push(Frame::Msg(b"flaky".to_vec())));
+
+        // No visibility deadline to wait out: the nack requeues it.
+        let (_, first) = next(&r, a);
+        r.delivered(a);
+        assert!(!r.nack(b, first.delivery.unwrap(), u32::MAX), "not B's delivery");
+        assert!(r.nack(a, first.delivery.unwrap(), u32::MAX));
+        assert!(!r.ack(a, first.delivery.unwrap()), "nacked tags are stale");
+        let (_, second) = next(&r, b);
+        assert_eq!(second.attempt, Some(1));
+
+        // A nack's own retry count, when lower than the policy's, wins.
+        r.delivered(b);
+        assert!(r.nack(b, second.delivery.unwrap(), 1));
+        assert_eq!(r.outstanding(), 0, "dead letters are settled");
+        assert_eq!(r.stats.redelivered_msgs.load(Ordering::Relaxed), 1);
+        assert_eq!(r.stats.dead_lettered_msgs.load(Ordering::Relaxed), 1);
+        let buf = sink.0.lock().unwrap().clone();
+        let (f, meta) = crate::get_frame(&mut &buf[..]).unwrap().unwrap();
+        assert_eq!(f, Frame::Msg(b"flaky".to_vec()));
+        assert_eq!(meta.attempt, Some(2));
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn traced_messages_get_a_wait_and_a_delivery_per_attempt() {
+        let r = mk_policy(no_backoff(5));
+        let (tx, rx) = mpsc::channel();
+        r.set_tracer(tx, "jobs");
+        let a = r.register_ack_consumer();
+        let parent = TraceContext::new_root().unwrap();
+        let mut meta = Meta::default();
+        otel::inject(&mut meta.headers, &parent);
+        assert!(r.push_with(Frame::Msg(b"job".to_vec()), meta));
+        assert!(r.push(Frame::Msg(b"untraced".to_vec())));
+
+        let (_, first) = next(&r, a);
+        r.delivered(a);
+        assert!(r.nack(a, first.delivery.unwrap(), u32::MAX));
+        let (_, plain) = next(&r, a);
+        r.delivered(a);
+        assert!(r.ack(a, plain.delivery.unwrap()));
+        let (_, second) = next(&r, a);
+        r.delivered(a);
+        assert!(r.ack(a, second.delivery.unwrap()));
+
+        let spans: Vec<String> = rx.try_iter().collect();
+        let named = |name: &str| spans.iter().filter(|l| l.contains(&format!(r#""name":"{name}""#))).count();
+        assert_eq!((spans.len(), named("qpipe.queue_wait"), named("qpipe.deliver")), (4, 2, 2));
+        assert!(spans.iter().all(|l| l.contains(&parent.trace_id()) && l.contains(r#""stringValue":"jobs""#)));
+        assert!(spans[1].contains(r#""status":{"code":2,"message":"requeued"}"#), "{}", spans[1]);
+        assert!(spans[3].contains(r#""status":{"code":1}"#), "{}", spans[3]);
+    }
+
+    #[test]
+    fn singles_that_keep_failing_plain_delivery_are_dead_lettered() {
+        let r = mk_policy(no_backoff(1));
+        let sink = Sink::default();
+        r.set_dead_letter_sink(Box::new(sink.clone()));
+        assert!(r.push(Frame::Msg(b"poison".to_vec())));
+
+        let a = r.register_consumer();
+        let f = r.pop_for(a);
+        assert!(r.fail_delivery(a, f), "within the budget: requeued");
+        let f = r.pop_for(a);
+        assert!(!r.fail_delivery(a, f), "past it: dead-lettered");
+        assert_eq!(r.outstanding(), 0, "dead letters are settled");
+        assert_eq!(r.stats.dead_lettered_msgs.load(Ordering::Relaxed), 1);
+
+        let buf = sink.0.lock().unwrap().clone();
+        let (f, meta) = crate::get_frame(&mut &buf[..]).unwrap().unwrap();
+        assert_eq!(f, Frame::Msg(b"poison".to_vec()));
+        assert_eq!(meta.attempt, Some(2));
+    }
+
+    #[test]
+    fn dead_letter_queues_take_whole_messages_until_full() {
+        let r = mk_policy(no_backoff(0));
+        let dlq = Arc::new(mk(2));
+        let cell = Arc::new(OnceLock::new());
+        r.set_dead_letter_queue(cell.clone());
+        let a = r.register_ack_consumer();
+        assert!(r.push(Frame::Msg(b"early".to_vec())));
+        next(&r, a);
+        r.delivered(a);
+        assert_eq!(r.expire_unacked(past_deadline()), 1);
+        assert_eq!(dlq.depth(), 0, "dropped before the queue is set");
+
+        cell.set(dlq.clone()).ok();
+        for i in 0..3u8 {
+            assert!(r.push_with(Frame::Msg(vec![i]), Meta { priority: Some(4), ..Meta::default() }));
+            next(&r, a);
+            r.delivered(a);
+            assert_eq!(r.expire_unacked(past_deadline()), 1);
+        }
+        assert_eq!(dlq.depth(), 2, "the third found no room");
+        let d = dlq.register_consumer();
+        let (f, meta) = next(&dlq, d);
+        assert_eq!(f, Frame::Msg(vec![0]));
+        assert_eq!(meta, Meta::default(), "routing metadata stays behind");
+        assert_eq!(r.stats.dead_lettered_msgs.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn every_stamped_frame_gets_one_audit_line_when_it_settles() {
+        let r = mk_policy(no_backoff(0));
+        let sink = Sink::default();
+        r.set_audit_sink(Box::new(sink.clone()));
+        assert!(r.auditing());
+        let a = r.register_consumer();
+        r.set_peer(a, "10.0.0.2:4000".into());
+        let b = r.register_ack_consumer();
+        let stamped = |body: &[u8]| {
+            let f = Frame::Msg(body.to_vec());
+            let st = Stamp::new(&f, "10.0.0.1:3000".into());
+            (f, st)
+        };
+
+        let (f, st) = stamped(b"alpha");
+        assert_eq!(r.push_stamped(f, Meta::default(), Some(st), false), Pushed::Queued);
+        assert_eq!(r.pop_for(a), Frame::Msg(b"alpha".to_vec()));
+        r.delivered(a);
+
+        let (f, st) = stamped(b"poison");
+        assert_eq!(r.push_stamped(f, Meta::default(), Some(st), false), Pushed::Queued);
+        next(&r, b);
+        r.delivered(b);
+        assert_eq!(r.expire_unacked(past_deadline()), 1);
+
+        // The writer thread catches up asynchronously.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let text = loop {
+            let text = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+            if text.lines().count() >= 2 || Instant::now() > deadline {
+                break text;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2, "{text}");
+        assert!(lines[0].starts_with(r#"{"id":"0","size":5,"#), "{}", lines[0]);
+        assert!(lines[0].contains(&format!(r#""sha256":"{}""#, to_hex(&sha256(b"alpha")))));
+        assert!(lines[0].contains(r#""producer":"10.0.0.1:3000","consumer":"10.0.0.2:4000""#));
+        assert!(lines[0].ends_with(r#""outcome":"delivered"}"#));
+        assert!(lines[1].contains(r#""id":"1""#));
+        assert!(lines[1].contains(r#""consumer":null"#));
+        assert!(lines[1].ends_with(r#""outcome":"dead-lettered"}"#));
+    }
+
+    #[test]
+    fn audit_lines_stay_json_whatever_the_names() {
+        let mut st = Stamp::new(&Frame::Msg(b"x".to_vec()), r#"say "hi"\"#.into());
+        st.id = "7".into();
+        let line = st.line(Some("tab\tand\nnewline"), "delivered");
+        assert_eq!(line.lines().count(), 1, "{line}");
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(v["producer"], r#"say "hi"\"#);
+        assert_eq!(v["consumer"], "tab\tand\nnewline");
+        assert_eq!(v["size"], 1);
+    }
+
+    #[test]
+    fn consumer_death_requeues_its_unacked_chunked_message() {
+        let r = mk_policy(no_backoff(5));
+        let a = r.register_ack_consumer();
+        let b = r.register_ack_consumer();
+        for f in [ch(4, 0, 3), ch(4, 1, 3)] {
+            assert!(r.push(f));
+        }
+
+        // A claims the message and receives the first chunk.
+        let (f, meta) = next(&r, a);
+        assert_eq!(f, ch(4, 0, 3));
+        r.delivered(a);
+        // B pops the second chunk and redirects it to A, then A dies.
+        assert!(r.push(Frame::Msg(b"solo".to_vec())));
+        assert_eq!(next(&r, b).0, Frame::Msg(b"solo".to_vec()));
+        r.unregister_consumer(a);
+
+        // Nothing was tombstoned: the claim was rescinded and B can take
+        // the whole message, including the last chunk still in flight.
+        assert!(r.push(ch(4, 2, 3)));
+        let mut got = Vec::new();
+        let mut tags = HashSet::new();
+        for _ in 0..3 {
+            let (f, m) = next(&r, b);
+            r.delivered(b);
+            assert_ne!(m.delivery, meta.delivery);
+            tags.insert(m.delivery.unwrap());
+            got.push(f);
+        }
+        got.sort_by_key(|f| match f { Frame::Chunk { idx, .. } => *idx, _ => 99 });
+        assert_eq!(got, vec![ch(4, 0, 3), ch(4, 1, 3), ch(4, 2, 3)]);
+        assert_eq!(tags.len(), 1, "one delivery, one tag");
+        assert!(r.ack(b, tags.into_iter().next().unwrap()));
+        // Only the unacked "solo" delivery from B remains outstanding.
+        assert_eq!(r.outstanding(), 1);
+        assert_eq!(r.gauges(), (0, 0, 0));
+    }
+
+    #[test]
+    fn kicked_consumer_stops_waiting_for_work() {
+        let r = mk_policy(no_backoff(5));
+        let a = r.register_ack_consumer();
+        r.kick(a);
+        assert!(r.next_for(a).is_none());
+        r.unregister_consumer(a);
+        r.kick(a); // late kick after unregister is a no-op
+        assert!(r.inner.lock().unwrap().gone.is_empty());
+    }
+
+    #[test]
+    fn idle_waits_end_without_kicking_the_consumer() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        assert!(r.next_for_within(a, Duration::from_millis(20)).is_none());
+        assert!(!r.is_gone(a), "idle, not gone");
+        assert!(r.push(Frame::Msg(b"x".to_vec())));
+        assert_eq!(r.next_for_within(a, Duration::from_secs(5)).unwrap().0, Frame::Msg(b"x".to_vec()));
+        r.delivered(a);
+        r.kick(a);
+        assert!(r.next_for_within(a, Duration::from_secs(5)).is_none());
+        assert!(r.is_gone(a));
+    }
+
+    #[test]
+    fn parked_consumers_are_woken_by_their_queue() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        let Polled::Wait { epoch, within: None } = r.poll_next(a) else {
+            panic!("nothing to take yet");
+        };
+        let (tx, woke) = std::sync::mpsc::channel();
+        let t = tx.clone();
+        r.park_consumer(a, epoch, move || t.send("first").unwrap());
+        let t = tx.clone();
+        r.park_consumer(a, epoch, move || t.send("second").unwrap()); // replaces the first
+        assert!(woke.try_recv().is_err(), "woken with nothing new");
+        assert!(r.push(Frame::Msg(b"x".to_vec())));
+        assert_eq!(woke.try_recv(), Ok("second"));
+        assert!(woke.try_recv().is_err(), "woken twice");
+
+        // Parked too late, it is woken at once.
+        r.park_consumer(a, epoch, move || tx.send("late").unwrap());
+        assert_eq!(woke.try_recv(), Ok("late"));
+        let Polled::Frame(frame, _) = r.poll_next(a) else {
+            panic!("the frame is there");
+        };
+        assert_eq!(frame, Frame::Msg(b"x".to_vec()));
+        r.delivered(a);
+        assert!(matches!(r.poll_next(a), Polled::Wait { .. }));
+        r.kick(a);
+        assert!(matches!(r.poll_next(a), Polled::Gone));
+        r.unregister_consumer(a);
+        assert!(r.wakers.lock().unwrap().parked.is_empty());
+    }
+
+    #[test]
+    fn retry_policy_parses_and_validates() {
+        let p = RetryPolicy::parse(
+            "timeout=90s, retries=2, backoff=250ms..10s, dlq=file:/tmp/dead.q",
+        ).unwrap();
+        assert_eq!(p, RetryPolicy {
+            visibility_timeout: Duration::from_secs(90),
+            max_retries:        2,
+            backoff_min:        Duration::from_millis(250),
+            backoff_max:        Duration::from_secs(10),
+            dead_letter:        DeadLetter::File("/tmp/dead.q".into()),
+        });
+        assert_eq!(RetryPolicy::parse("").unwrap(), RetryPolicy::default());
+        assert_eq!(
+            RetryPolicy::parse("timeout=2m").unwrap().visibility_timeout,
+            Duration::from_secs(120),
+        );
+
+        for bad in [
+            "timeout=0", "timeout=5x", "retries=-1", "backoff=5s..1s",
+            "dlq=file:", "dlq=s3", "colour=blue", "timeout",
+        ] {
+            assert!(RetryPolicy::parse(bad).is_err(), "{bad:?} should fail");
+        }
+    }
+
+    #[test]
+    fn egress_limits_parse_and_validate() {
+        let l = EgressLimit::parse("consumer=10MiB/s, queue=2GB").unwrap();
+        assert_eq!(l, EgressLimit {
+            per_consumer: Some(10 << 20),
+            queue:        Some(2_000_000_000),
+        });
+        assert_eq!(EgressLimit::parse("").unwrap(), EgressLimit::default());
+        assert_eq!(parse_rate("500k").unwrap(), 500_000);
+        assert_eq!(parse_rate("64").unwrap(), 64);
+        for bad in ["consumer=0", "queue=5x", "uplink=1M", "consumer", "queue=99999999999G"] {
+            assert!(EgressLimit::parse(bad).is_err(), "{bad:?} should fail");
+        }
+    }
+
+    #[test]
+    fn autoscale_policy_parses() {
+        let p = AutoscalePolicy::parse("depth=1000, wait=30s,for=2m").unwrap();
+        assert_eq!(p, AutoscalePolicy {
+            depth:    Some(1000),
+            wait:     Some(Duration::from_secs(30)),
+            sustain:  Duration::from_secs(120),
+            cooldown: Duration::from_secs(300),
+        });
+        for bad in ["", "for=1m", "depth=lots", "wait=3y", "depth", "burst=5"] {
+            assert!(AutoscalePolicy::parse(bad).is_err(), "{bad:?} should fail");
+        }
+        assert_eq!(Hook::parse("http://ops:8080").unwrap(), Hook::Http {
+            authority: "ops:8080".into(), path: "/".into(),
+        });
+        assert_eq!(Hook::parse("http://ops/scale?q=1").unwrap(), Hook::Http {
+            authority: "ops".into(), path: "/scale?q=1".into(),
+        });
+        assert_eq!(Hook::parse("kubectl scale …").unwrap(), Hook::Cmd("kubectl scale …".into()));
+        assert!(Hook::parse("https://ops/scale").is_err());
+        assert_eq!(split_authority("ops").unwrap(), ("ops", 80));
+        assert_eq!(split_authority("ops:8080").unwrap(), ("ops", 8080));
+        assert_eq!(split_authority("[::1]").unwrap(), ("::1", 80));
+        assert_eq!(split_authority("[::1]:8080").unwrap(), ("::1", 8080));
+        for bad in ["http://[::1", "http://::1", "http://[::1]x", "http://ops:http", "http://:80"] {
+            assert!(Hook::parse(bad).is_err(), "{bad:?} should fail");
+        }
+    }
+
+    #[test]
+    fn webhooks_read_the_whole_status_line() {
+        use std::net::TcpListener;
+
+        let post = |reply: &'static [u8]| {
+            let l = TcpListener::bind("127.0.0.1:0").unwrap();
+            let authority = l.local_addr().unwrap().to_string();
+            let server = thread::spawn(move || {
+                let (mut s, _) = l.accept().unwrap();
+                // The whole request, up to its `{}` body, so closing doesn't reset.
+                let mut req = Vec::new();
+                while !req.ends_with(b"{}") {
+                    let mut buf = [0u8; 4096];
+                    let n = s.read(&mut buf).unwrap();
+                    assert!(n > 0, "request cut short");
+                    req.extend_from_slice(&buf[..n]);
+                }
+                s.write_all(reply).unwrap();
+            });
+            let got = post_json(&authority, "/", "{}");
+            server.join().unwrap();
+            got
+        };
+        assert_eq!(post(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap(), 204);
+        assert_eq!(post(b"HTTP/1.0 503 Service Unavailable\r\nRetry-After: 5\r\n\r\n").unwrap(), 503);
+        for reply in [&b"HTTP/1.1 20"[..], b"SMTP/1.1 200 OK\r\n", b"HTTP/1.1 2000 OK\r\n"] {
+            assert_eq!(post(reply).unwrap_err().kind(), io::ErrorKind::InvalidData, "{reply:?}");
+        }
+    }
+
+    #[test]
+    fn autoscaler_waits_out_sustain_and_cooldown() {
+        let mut a = Autoscaler::new(AutoscalePolicy::parse("depth=10,for=10s,cooldown=60s").unwrap());
+        let t0 = Instant::now();
+        let at = |s: u64| t0 + Duration::from_secs(s);
+        let idle = Duration::ZERO;
+
+        // An empty queue never scales down before anything scaled up.
+        assert_eq!(a.observe(at(0), 0, idle), None);
+        assert_eq!(a.observe(at(30), 0, idle), None);
+        // A spike shorter than `for` is ignored.
+        assert_eq!(a.observe(at(31), 50, idle), None);
+        assert_eq!(a.observe(at(35), 5, idle), None);
+        // Sustained pressure fires once, then again only after the cooldown.
+        assert_eq!(a.observe(at(40), 50, idle), None);
+        assert_eq!(a.observe(at(50), 50, idle), Some(ScaleEvent::Up));
+        assert_eq!(a.observe(at(60), 50, idle), None);
+        assert_eq!(a.observe(at(110), 50, idle), Some(ScaleEvent::Up));
+        // Drained: scale-down waits for `for` and the cooldown, then fires once.
+        assert_eq!(a.observe(at(115), 0, idle), None);
+        assert_eq!(a.observe(at(130), 0, idle), None);
+        assert_eq!(a.observe(at(170), 0, idle), Some(ScaleEvent::Down));
+        assert_eq!(a.observe(at(400), 0, idle), None);
+
+        // The wait threshold alone counts as pressure too.
+        let mut a = Autoscaler::new(AutoscalePolicy::parse("wait=1m,for=0s").unwrap());
+        assert_eq!(a.observe(at(0), 1, Duration::from_secs(59)), None);
+        assert_eq!(a.observe(at(1), 1, Duration::from_secs(60)), Some(ScaleEvent::Up));
+    }
+
+    #[test]
+    fn named_queues_are_separate_routers() {
+        let stats = Arc::new(Stats::default());
+        let capacities = Capacities { default: 8, queues: BTreeMap::from([("b".to_string(), 4)]) };
+        let q = Queues::new(capacities, move |_, capacity| Ok(Router::new(capacity, stats.clone()))).unwrap();
+        assert!(Arc::ptr_eq(&q.get("").unwrap(), &q.default));
+        let a = q.get("a").unwrap();
+        assert!(Arc::ptr_eq(&a, &q.get("a").unwrap()), "created once");
+        let b = q.get("b").unwrap();
+        assert_eq!(q.count(), 3);
+
+        assert!(a.push(Frame::Msg(b"for a".to_vec())));
+        assert!(b.push(Frame::Msg(b"for b".to_vec())));
+        assert!(b.push(Frame::Msg(b"for b too".to_vec())));
+        assert_eq!((a.depth(), b.depth(), q.default.depth()), (1, 2, 0));
+        assert_eq!(q.depth(), 3);
+        assert_eq!(q.backlog().0, 3);
+        let c = a.register_consumer();
+        assert_eq!(a.pop_for(c), Frame::Msg(b"for a".to_vec()));
+        a.delivered(c);
+        assert_eq!(q.depth(), 2);
+        assert!(!q.is_idle(), "b still holds frames");
+        assert!(q.is_queue_idle("a") && !q.is_queue_idle("b"));
+        assert!(q.is_queue_idle("nonexistent"));
+        assert!(Arc::ptr_eq(&a.stats, &b.stats), "stats are shared");
+
+        // A reload resizes the queues whose capacity changed, and sizes
+        // the ones created later.
+        assert_eq!((q.default.capacity(), a.capacity(), b.capacity()), (8, 8, 4));
+        q.set_capacities(Capacities { default: 16, queues: BTreeMap::from([("c".to_string(), 2)]) });
+        assert_eq!((q.default.capacity(), a.capacity(), b.capacity()), (16, 16, 16));
+        assert_eq!(q.get("c").unwrap().capacity(), 2);
+    }
+
+    #[test]
+    fn reply_queues_go_with_their_last_session() {
+        let stats = Arc::new(Stats::default());
+        let capacities = Capacities { default: 8, queues: BTreeMap::new() };
+        let q = {
+            let stats = stats.clone();
+            Arc::new(Queues::new(capacities, move |_, capacity| Ok(Router::new(capacity, stats.clone()))).unwrap())
+        };
+        let name = format!("{REPLY_QUEUE_PREFIX}r1");
+
+        let (r, requester) = q.attach(&name).unwrap();
+        let (same, replier) = q.attach(&name).unwrap();
+        assert!(Arc::ptr_eq(&r, &same));
+        assert!(r.push(Frame::Msg(b"unread".to_vec())));
+        drop(replier);
+        assert!(q.find(&name).is_some(), "the requester is still there");
+        drop(requester);
+        assert!(q.find(&name).is_none());
+        assert_eq!(r.depth(), 0);
+        assert_eq!(stats.dropped_msgs.load(Ordering::Relaxed), 1);
+
+        // Other queues stay for good.
+        drop(q.attach("jobs").unwrap());
+        assert!(q.find("jobs").is_some());
+    }
+
+    #[test]
+    fn raising_the_capacity_lets_waiting_producers_in() {
+        let r = Arc::new(mk(1));
+        assert!(r.push(Frame::Msg(b"a".to_vec())));
+        let blocked = {
+            let r = r.clone();
+            thread::spawn(move || r.push(Frame::Msg(b"b".to_vec())))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished(), "the queue is full");
+        r.set_capacity(2);
+        assert!(blocked.join().unwrap());
+        assert_eq!(r.depth(), 2);
+    }
+
+    #[test]
+    fn backlog_reports_depth_and_oldest_wait() {
+        let r = mk(8);
+        assert_eq!(r.backlog(), (0, Duration::ZERO));
+        assert!(r.push(Frame::Msg(b"a".to_vec())));
+        thread::sleep(Duration::from_millis(20));
+        assert!(r.push(Frame::Msg(b"b".to_vec())));
+        let (depth, wait) = r.backlog();
+        assert_eq!(depth, 2);
+        assert!(wait >= Duration::from_millis(20), "{wait:?}");
+    }
+
+    #[test]
+    fn full_queue_spills_to_disk_and_refills_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ov, _) = Overflow::open(dir.path(), None).unwrap();
+        let r = mk(2).with_overflow(Some(ov));
+        let a = r.register_consumer();
+        // Past capacity nothing blocks; the extra frames go to disk.
+        for i in 0..5u8 {
+            assert!(r.push(Frame::Msg(vec![i])));
+        }
+        assert_eq!((r.depth(), r.outstanding()), (5, 5));
+        assert_eq!(r.backlog().0, 5);
+        assert_eq!(r.refill().unwrap(), 0, "no room yet");
+
+        let mut got = Vec::new();
+        while got.len() < 5 {
+            got.push(r.pop_for(a));
+            r.delivered(a);
+            r.refill().unwrap();
+        }
+        assert_eq!(got, (0..5u8).map(|i| Frame::Msg(vec![i])).collect::<Vec<_>>());
+        assert!(r.is_idle());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn journaled_frames_not_settled_come_back_after_a_restart() {
+        let (wal_dir, ov_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let journaled = |capacity| {
+            let (ov, _) = Overflow::open(ov_dir.path(), None).unwrap();
+            let (wal, frames, _) = Wal::open(wal_dir.path(), None).unwrap();
+            let r = mk(capacity).with_overflow(Some(ov));
+            r.recover(wal, frames);
+            r
+        };
+        let r = journaled(2);
+        assert!(r.journaling());
+        let plain = r.register_consumer();
+        let acker = r.register_ack_consumer();
+        for i in 0..4u8 {
+            assert!(r.push(Frame::Msg(vec![i]))); // 2 and 3 spill
+        }
+        assert!(r.push(Frame::Eos(b"g".to_vec())));
+        assert_eq!(r.pop_for(plain), Frame::Msg(vec![0]));
+        r.delivered(plain);
+        assert_eq!(r.pop_for(acker), Frame::Msg(vec![1])); // never acked
+        r.delivered(acker);
+        drop(r);
+
+        // The overflow is not a journal: the log alone brings them back.
+        let r = journaled(10);
+        let c = r.register_consumer();
+        let mut got = Vec::new();
+        for _ in 0..4 {
+            got.push(r.pop_for(c));
+            r.delivered(c);
+        }
+        let mut want: Vec<Frame> = (1..4u8).map(|i| Frame::Msg(vec![i])).collect();
+        want.push(Frame::Eos(b"g".to_vec()));
+        assert_eq!(got, want);
+        drop(r);
+        let (wal, frames, _) = Wal::open(wal_dir.path(), None).unwrap();
+        assert!(frames.is_empty() && wal.is_empty());
+    }
+
+    #[test]
+    fn paused_intake_holds_producers_until_resumed() {
+        let r = Arc::new(mk(4));
+        r.set_intake(Intake::Paused);
+        let producer = {
+            let r = r.clone();
+            thread::spawn(move || r.push(Frame::Msg(b"held".to_vec())))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(r.depth(), 0, "paused with room to spare");
+        r.set_intake(Intake::Open);
+        assert!(producer.join().unwrap());
+        assert_eq!(r.depth(), 1);
+    }
+
+    #[test]
+    fn purge_drops_waiting_frames_but_not_eos_or_claimed_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ov, _) = Overflow::open(dir.path(), None).unwrap();
+        let r = mk(3).with_overflow(Some(ov));
+        let c = r.register_consumer();
+        assert!(r.push(ch(7, 0, 2)));
+        assert!(r.push(ch(7, 1, 2)));
+        assert_eq!(r.pop_for(c), ch(7, 0, 2)); // claims message 7
+        assert!(r.push(Frame::Msg(b"x".to_vec())));
+        assert!(r.push(Frame::Eos(b"early".to_vec())));
+        assert!(r.push(ch(9, 0, 2))); // spilled from here on
+        assert!(r.push(Frame::Eos(b"late".to_vec())));
+
+        assert_eq!(r.purge().unwrap(), 2);
+        assert_eq!(r.stats.dropped_msgs.load(Ordering::Relaxed), 2);
+        assert_eq!(r.depth(), 3, "chunk 7/1 and both EOS markers stay");
+        assert!(!r.push(ch(9, 1, 2)), "the rest of a purged message goes too");
+        assert_eq!(r.pop_for(c), ch(7, 1, 2));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn full_queues_shed_their_oldest_message_or_refuse_the_newest() {
+        let queued = |r: &Router| -> Vec<Frame> {
+            r.inner.lock().unwrap().shared.iter().map(|it| it.frame.clone()).collect()
+        };
+        let msg = |m: &[u8]| Frame::Msg(m.to_vec());
+
+        // Whole messages go, oldest first, but not ones a consumer has
+        // started, nor EOS markers.
+        let r = mk(4).with_when_full(FullPolicy::DropOldest);
+        let c = r.register_consumer();
+        assert!(r.push(ch(7, 0, 2)));
+        assert!(r.push(ch(7, 1, 2)));
+        assert_eq!(r.pop_for(c), ch(7, 0, 2)); // claims message 7
+        assert!(r.push(ch(9, 0, 2)));
+        assert!(r.push(ch(9, 1, 2)));
+        assert!(r.push(msg(b"a")));
+        assert!(r.push(Frame::Eos(b"g".to_vec())));
+        assert!(r.push(msg(b"b")));
+        assert_eq!(queued(&r), [ch(7, 1, 2), msg(b"a"), Frame::Eos(b"g".to_vec()), msg(b"b")]);
+        assert!(r.push(msg(b"c")));
+        assert_eq!(queued(&r), [ch(7, 1, 2), Frame::Eos(b"g".to_vec()), msg(b"b"), msg(b"c")]);
+        assert_eq!(r.stats.shed_msgs.load(Ordering::Relaxed), 3);
+        assert!(!r.push(ch(9, 1, 2)), "a straggler of a shed message");
+
+        // Refusable producers are told; nothing is queued.
+        let r = mk(2).with_when_full(FullPolicy::RejectNewest);
+        assert!(r.push(msg(b"a")));
+        assert!(r.push(msg(b"b")));
+        assert_eq!(r.push_stamped(msg(b"c"), Meta::default(), None, true), Pushed::Rejected);
+        assert_eq!(r.push_stamped(ch(5, 0, 2), Meta::default(), None, true), Pushed::Rejected);
+        assert_eq!(queued(&r), [msg(b"a"), msg(b"b")]);
+        assert_eq!(r.stats.shed_msgs.load(Ordering::Relaxed), 2);
+
+        for policy in ["block", "drop-oldest", "reject-newest"] {
+            assert_eq!(FullPolicy::parse(policy).unwrap().to_string(), policy);
+        }
+        assert!(FullPolicy::parse("drop-newest").is_err());
+    }
+
+    #[test]
+    fn statsd_lines_carry_prefix_and_type() {
+        let sink = Statsd::connect("127.0.0.1:9", "qpipe.").unwrap();
+        assert_eq!(
+            sink.render(&[Metric::Counter("posted.frames", 3), Metric::Gauge("queue.depth", 12)]),
+            "qpipe.posted.frames:3|c\nqpipe.queue.depth:12|g\n",
+        );
+        let bare = Statsd::connect("127.0.0.1:9", "").unwrap();
+        assert_eq!(bare.render(&[Metric::Gauge("consumers", 0)]), "consumers:0|g\n");
+    }
+
+    #[test]
+    fn stats_reports_give_deltas_gauges_and_totals() {
+        let last = StatsSnapshot { posted_frames: 10, posted_bytes: 100, ..Default::default() };
+        let now = StatsSnapshot {
+            posted_frames: 15, posted_bytes: 160, collected_frames: 4, redelivered: 1,
+            producers: 2, queues: 1, in_queue: 11, ..Default::default()
+        };
+        let report = Report {
+            now, last, interval: Duration::from_millis(1500),
+            assigns: 0, tombs: 0, oldest: Duration::from_millis(250),
+            dwell: Dwell { count: 4, p50: Duration::from_micros(90), ..Default::default() },
+        };
+        assert_eq!(report.delta().posted_frames, 5);
+        assert_eq!(report.delta().in_queue, 11, "gauges aren't differenced");
+        assert!(report.text().starts_with("[stats] +5 frames (60 B) posted | +4 frames (0 B) collected"));
+
+        let json = report.json();
+        let json = json.strip_suffix('\n').unwrap();
+        let (_, rest) = json.split_once(",\"interval_ms\":").unwrap();
+        assert_eq!(
+            rest,
+            "1500,\"posted_frames\":5,\"posted_bytes\":60,\"collected_frames\":4,\"collected_bytes\":0,\
+             \"dropped_frames\":0,\"dropped_bytes\":0,\"redelivered\":1,\"dead_lettered\":0,\"exported\":0,\
+             \"expired\":0,\"duplicates\":0,\"shed\":0,\"queues\":1,\"in_queue\":11,\"outstanding\":0,\"oldest_wait_ms\":250,\
+             \"multiframe_assignments\":0,\"tombstones\":0,\
+             \"dwell_us\":{\"count\":4,\"p50\":90,\"p95\":0,\"p99\":0},\"producers\":2,\"consumers\":0,\
+             \"totals\":{\"posted_frames\":15,\"posted_bytes\":160,\"collected_frames\":4,\
+             \"collected_bytes\":0,\"dropped_frames\":0,\"dropped_bytes\":0,\"redelivered\":1,\
+             \"dead_lettered\":0,\"exported\":0,\"expired\":0,\"duplicates\":0,\"shed\":0}}",
+        );
+    }
+
+    #[test]
+    fn dwell_histogram_buckets_stay_close_and_give_percentiles() {
+        for us in (0..100_000).chain([u64::MAX / 3, u64::MAX]) {
+            let i = Histogram::bucket(us);
+            assert!(i < HIST_BUCKETS);
+            assert!(us <= Histogram::upper(i), "{us} in bucket {i}");
+            assert!(i == 0 || Histogram::upper(i - 1) < us, "{us} in bucket {i}");
+            assert!(Histogram::upper(i) - us <= us / 32, "{us} too far from its bucket's bound");
+        }
+
+        let h = Histogram::default();
+        assert_eq!(Dwell::of(&h.counts()), Dwell::default());
+        for ms in 1..=100 {
+            h.record(Duration::from_millis(ms));
+        }
+        let before = h.counts();
+        let dwell = Dwell::of(&before);
+        assert_eq!(dwell.count, 100);
+        for (p, want) in [(dwell.p50, 50), (dwell.p95, 95), (dwell.p99, 99)] {
+            let ms = Duration::from_millis(want);
+            assert!(p >= ms && p <= ms + ms / 32, "{p:?} for {ms:?}");
+        }
+
+        // An interval's percentiles are of what it recorded alone.
+        h.record(Duration::from_secs(3));
+        let taken: Vec<u64> = h.counts().iter().zip(&before).map(|(n, l)| n - l).collect();
+        let dwell = Dwell::of(&taken);
+        assert_eq!(dwell.count, 1);
+        assert!(dwell.p50 >= Duration::from_secs(3) && dwell.p50 == dwell.p99);
+    }
+
+    #[test]
+    fn client_counters_stay_bounded_and_make_room_from_idle_names() {
+        let stats = Arc::new(Stats::default());
+        let live = ConnGuard::new(ConnKind::Producer, stats.clone(), "p".into(), "", Some("busy"));
+        live.posted(5);
+        for i in 1..MAX_CLIENTS {
+            assert!(stats.client(&format!("idle-{i}")).is_some());
+        }
+        let late = ConnGuard::new(ConnKind::Consumer, stats.clone(), "c".into(), "", Some("late"));
+        late.collected(7);
+        let clients = stats.clients.lock().unwrap();
+        assert_eq!(clients.keys().collect::<Vec<_>>(), ["busy", "late"], "idle names made room");
+        assert_eq!(clients["busy"].info("busy").posted_bytes, 5);
+        assert_eq!(clients["late"].info("late").consumers, 1);
+        drop(clients);
+        drop(late);
+        assert!(stats.clients.lock().unwrap()["late"].is_idle(), "its counters outlive the session");
+        assert_eq!(stats.collected_bytes.load(Ordering::Relaxed), 7);
+    }
+
+    #[test]
+    fn stats_feed_ends_subscriptions_when_closed() {
+        let feed = StatsFeed::default();
+        let rx = feed.subscribe();
+        let gone = feed.subscribe();
+        drop(gone);
+        feed.publish("{}\n");
+        assert_eq!(feed.subs.lock().unwrap().as_ref().unwrap().len(), 1, "dead subscribers pruned");
+        feed.close();
+        assert_eq!(rx.iter().map(|r| r.to_string()).collect::<Vec<_>>(), ["{}\n"]);
+        assert!(feed.subscribe().recv().is_err(), "late subscribers end at once");
+    }
+
+    #[test]
+    fn token_bucket_paces_to_its_rate() {
+        let t0 = Instant::now();
+        let mut b = TokenBucket::new(1000, t0);
+        // 100ms of burst goes out at once, then 1000 bytes/s.
+        assert_eq!(b.reserve(100, t0), Duration::ZERO);
+        assert_eq!(b.reserve(50, t0), Duration::from_millis(50));
+        assert_eq!(b.reserve(50, t0), Duration::from_millis(100));
+        // Refills with time, but never beyond the burst.
+        let later = t0 + Duration::from_secs(10);
+        assert_eq!(b.reserve(100, later), Duration::ZERO);
+        assert_eq!(b.reserve(10, later), Duration::from_millis(10));
+        // An oversized frame goes out and leaves debt behind it.
+        let mut b = TokenBucket::new(1000, t0);
+        assert_eq!(b.reserve(1100, t0), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn producer_limits_parse_and_validate() {
+        let l = ProducerLimit::parse("msgs=1k/s, bytes=10MiB, mode=reject").unwrap();
+        assert_eq!(l, ProducerLimit { msgs: Some(1000), bytes: Some(10 << 20), reject: true });
+        assert_eq!(ProducerLimit::parse("mode=block").unwrap(), ProducerLimit::default());
+        for bad in ["msgs=0", "bytes=5x", "mode=drop", "frames=10", "msgs"] {
+            assert!(ProducerLimit::parse(bad).is_err(), "{bad:?} should fail");
+        }
+    }
+
+    #[test]
+    fn session_timeouts_parse_and_validate() {
+        let t = SessionTimeouts::parse("read=30s, idle=10m").unwrap();
+        let secs = |n| Some(Duration::from_secs(n));
+        assert_eq!(t, SessionTimeouts { read: secs(30), write: None, idle: secs(600) });
+        assert_eq!(SessionTimeouts::parse("").unwrap(), SessionTimeouts::default());
+        for bad in ["read=0s", "idle=soon", "connect=1s", "write"] {
+            assert!(SessionTimeouts::parse(bad).is_err(), "{bad:?} should fail");
+        }
+    }
+
+    #[test]
+    fn token_policies_parse_and_validate() {
+        let t = TokenPolicy::parse("ttl=5s, attempts=1").unwrap();
+        assert_eq!(t, TokenPolicy { ttl: Duration::from_secs(5), attempts: 1 });
+        assert_eq!(TokenPolicy::parse("").unwrap(), TokenPolicy::default());
+        for bad in ["ttl=0s", "attempts=0", "attempts=-1", "tries=3", "ttl"] {
+            assert!(TokenPolicy::parse(bad).is_err(), "{bad:?} should fail");
+        }
+    }
+
+    #[test]
+    fn throttles_hold_back_or_refuse_whole_messages() {
+        let t0 = Instant::now();
+        let limit = ProducerLimit { msgs: Some(10), ..ProducerLimit::default() };
+        assert!(Throttle::new(ProducerLimit::default(), t0).is_none());
+
+        // Blocking: one message's worth of burst, then one every 100ms.
+        let mut t = Throttle::new(limit, t0).unwrap();
+        assert_eq!(t.delay(&Frame::Msg(vec![1]), t0), Duration::ZERO);
+        assert_eq!(t.delay(&Frame::Msg(vec![2]), t0), Duration::from_millis(100));
+        assert_eq!(t.delay(&Frame::Eos(Vec::new()), t0), Duration::from_millis(100));
+
+        // Rejecting: refused while in debt, and a chunked message is
+        // judged whole by its first chunk.
+        let mut t = Throttle::new(limit, t0).unwrap();
+        let chunk = |id, idx| Frame::Chunk { id, idx, count: 2, payload: vec![0; 8] };
+        assert!(t.admits(&Frame::Msg(vec![1]), t0));
+        assert!(t.admits(&Frame::Msg(vec![2]), t0), "the last of the burst");
+        assert!(!t.admits(&chunk(7, 0), t0));
+        assert!(t.admits(&Frame::Ping, t0));
+        let later = t0 + Duration::from_millis(100);
+        assert!(!t.admits(&chunk(7, 1), later), "the rest of a refused message");
+        assert!(t.admits(&chunk(8, 0), later));
+        assert!(t.admits(&chunk(8, 1), later), "the rest of an admitted message");
+        assert!(t.refused.is_empty());
+    }
+
+    #[test]
+    fn refused_intake_turns_away_whole_messages_but_not_control_frames() {
+        let mut refused = HashSet::new();
+        let chunk = |id, idx| Frame::Chunk { id, idx, count: 2, payload: vec![0; 8] };
+        assert!(!refuses(Intake::Paused, &Frame::Msg(vec![1]), &mut refused), "paused holds instead");
+        assert!(!refuses(Intake::Open, &chunk(8, 0), &mut refused));
+        assert!(refuses(Intake::Refused, &Frame::Msg(vec![1]), &mut refused));
+        assert!(refuses(Intake::Refused, &chunk(7, 0), &mut refused));
+        for control in [Frame::Ping, Frame::Eos(Vec::new())] {
+            assert!(!refuses(Intake::Refused, &control, &mut refused));
+        }
+        assert!(!refuses(Intake::Refused, &chunk(8, 1), &mut refused), "the rest of an admitted message");
+        assert!(refuses(Intake::Open, &chunk(7, 1), &mut refused), "the rest of a refused message");
+        assert!(refused.is_empty());
+    }
+
+    #[test]
+    fn fanout_queues_copy_every_frame_to_each_subscriber() {
+        assert!(fans_out("calib, alerts/*", "calib"));
+        assert!(fans_out("calib, alerts/*", "alerts/disk"));
+        assert!(!fans_out("calib, alerts/*", "calibration"));
+        assert!(!fans_out("*", ""), "the default queue stays MPMC");
+        assert!(!fans_out("", "calib"));
+
+        let topic = Arc::new(mk(8).with_fanout());
+        // Nobody listening: the frame is dropped, not queued.
+        assert!(topic.push(Frame::Msg(b"early".to_vec())));
+        assert_eq!(topic.depth(), 0);
+        assert_eq!(topic.stats.dropped_msgs.load(Ordering::Relaxed), 1);
+
+        let (a, b) = (topic.subscribe(), topic.subscribe());
+        let (ca, cb) = (a.register_consumer(), b.register_consumer());
+        assert!(topic.push(Frame::Msg(b"x".to_vec())));
+        assert!(topic.push(Frame::Eos(b"done".to_vec())));
+        for (sub, c) in [(&a, ca), (&b, cb)] {
+            assert_eq!(sub.pop_for(c), Frame::Msg(b"x".to_vec()));
+            sub.delivered(c);
+            assert_eq!(sub.pop_for(c), Frame::Eos(b"done".to_vec()));
+        }
+
+        // A subscriber that goes away stops getting copies.
+        drop(b);
+        assert_eq!(topic.subscribers().len(), 1);
+        assert!(topic.push(Frame::Msg(b"y".to_vec())));
+        assert_eq!(a.depth(), 1);
+        assert_eq!(topic.purge().unwrap(), 1);
+        assert_eq!(a.depth(), 0);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let p = RetryPolicy::parse("backoff=1s..5s").unwrap();
+        let secs: Vec<u64> = (1..=5).map(|n| p.backoff(n).as_secs()).collect();
+        assert_eq!(secs, vec![1, 2, 4, 5, 5]);
+        assert_eq!(p.backoff(u32::MAX), Duration::from_secs(5));
+    }
+
+    // ---- partition keys ----
+
+    fn keyed(key: &str, body: &str) -> (Frame, Meta) {
+        (Frame::Msg(body.as_bytes().to_vec()), Meta { partition: Some(key.as_bytes().to_vec()), ..Meta::default() })
+    }
+
+    #[test]
+    fn partition_keys_stay_on_one_consumer_while_in_flight() {
+        let r = mk(64);
+        let (a, b, c) = (r.register_consumer(), r.register_consumer(), r.register_consumer());
+
+        // Keys spread over every consumer, each key to one of them.
+        for i in 0..60 {
+            let (f, m) = keyed(&format!("module-{}", i % 30), "x");
+            assert!(r.push_with(f, m));
+        }
+        let mut owners: HashMap<ConsumerId, usize> = HashMap::new();
+        for me in [a, b, c] {
+            while let Some((Frame::Msg(_), _)) = r.try_next_for(me) {
+                r.delivered(me);
+                *owners.entry(me).or_default() += 1;
+            }
+        }
+        assert_eq!(owners.values().sum::<usize>(), 60);
+        assert_eq!(owners.len(), 3, "{owners:?}");
+        assert!(owners.values().all(|n| n % 2 == 0), "a key's two messages went together");
+
+        // C joins (stops being gone) and takes over a key of A's — but
+        // only once A has settled what it holds under that key.
+        r.kick(c);
+        let owner = |key: &str| r.inner.lock().unwrap().key_owner(key.as_bytes());
+        let set_gone = |gone: bool| {
+            let mut g = r.inner.lock().unwrap();
+            if gone { g.gone.insert(c) } else { g.gone.remove(&c) };
+        };
+        let key = (0..).map(|i| format!("k{i}")).find(|k| {
+            let before = owner(k);
+            set_gone(false);
+            let after = owner(k);
+            set_gone(true);
+            before == Some(a) && after == Some(c)
+        }).unwrap();
+        for body in ["1", "2"] {
+            let (f, m) = keyed(&key, body);
+            assert!(r.push_with(f, m));
+        }
+        assert!(r.try_next_for(b).is_none());
+        assert_eq!(r.pop_for(a), Frame::Msg(b"1".to_vec()));
+        set_gone(false);
+        assert!(r.try_next_for(c).is_none(), "pinned to A");
+        assert_eq!(r.pop_for(a), Frame::Msg(b"2".to_vec()));
+        r.delivered(a);
+        r.delivered(a);
+        let (f, m) = keyed(&key, "3");
+        assert!(r.push_with(f, m));
+        assert!(r.try_next_for(a).is_none());
+        assert_eq!(r.pop_for(c), Frame::Msg(b"3".to_vec()));
+    }
+
+    // ---- dispatch ----
+
+    fn wait(r: &Router, c: ConsumerId, on: bool) {
+        let mut g = r.inner.lock().unwrap();
+        if on { g.waiting.insert(c) } else { g.waiting.remove(&c) };
+    }
+
+    #[test]
+    fn round_robin_leaves_items_to_consumers_waiting_longer() {
+        let r = mk(8).with_dispatch(Dispatch::RoundRobin);
+        let (a, b) = (r.register_consumer(), r.register_consumer());
+        for i in 0..3u8 {
+            assert!(r.push(Frame::Msg(vec![i])));
+        }
+        assert_eq!(r.try_next_for(a).unwrap().0, Frame::Msg(vec![0]), "nobody waiting");
+        r.delivered(a);
+        wait(&r, b, true);
+        assert!(r.try_next_for(a).is_none(), "b hasn't had a turn yet");
+        wait(&r, b, false);
+        assert_eq!(r.try_next_for(b).unwrap().0, Frame::Msg(vec![1]));
+        r.delivered(b);
+        wait(&r, b, true);
+        assert_eq!(r.try_next_for(a).unwrap().0, Frame::Msg(vec![2]), "a's turn again");
+
+        let free = mk(8);
+        let (a, b) = (free.register_consumer(), free.register_consumer());
+        assert!(free.push(Frame::Msg(vec![0])));
+        wait(&free, b, true);
+        assert!(free.try_next_for(a).is_some(), "free dispatch never defers");
+    }
+
+    #[test]
+    fn least_outstanding_defers_only_to_consumers_that_can_take_the_item() {
+        let r = mk_policy(no_backoff(5)).with_dispatch(Dispatch::LeastOutstanding);
+        let (a, b) = (r.register_ack_consumer(), r.register_ack_consumer());
+        r.set_capabilities(b, ["gpu".to_string()].into());
+        for i in 0..2u8 {
+            assert!(r.push(Frame::Msg(vec![i])));
+            next(&r, b);
+            r.delivered(b);
+        }
+        assert!(r.push(Frame::Msg(b"any".to_vec())));
+        assert!(r.push_with(Frame::Msg(b"gpu".to_vec()), needs(&["gpu"])));
+
+        // b holds two unacked deliveries, a none: a goes first, even though
+        // b's last turn is further back than a's (a never had one).
+        wait(&r, a, true);
+        let (f, _) = r.try_next_for(b).unwrap();
+        assert_eq!(f, Frame::Msg(b"gpu".to_vec()), "only b can take it");
+        wait(&r, a, false);
+        assert_eq!(r.try_next_for(a).unwrap().0, Frame::Msg(b"any".to_vec()));
+
+        assert!(Dispatch::parse("least-outstanding").is_ok());
+        assert!(Dispatch::parse("random").is_err());
+    }
+
+    // ---- priorities ----
+
+    fn prio(p: u8) -> Meta {
+        Meta { priority: Some(p), ..Meta::default() }
+    }
+
+    #[test]
+    fn higher_priorities_are_served_first_and_fifo_within() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        assert!(r.push(Frame::Msg(b"bulk-1".to_vec())));
+        assert!(r.push_with(Frame::Msg(b"ctl-1".to_vec()), prio(9)));
+        assert!(r.push(Frame::Msg(b"bulk-2".to_vec())));
+        assert!(r.push_with(Frame::Msg(b"ctl-2".to_vec()), prio(9)));
+        assert!(r.push_with(Frame::Msg(b"mid".to_vec()), prio(4)));
+
+        let order: Vec<Frame> = (0..5).map(|_| r.pop_for(a)).collect();
+        let want: Vec<Frame> = ["ctl-1", "ctl-2", "mid", "bulk-1", "bulk-2"]
+            .iter()
+            .map(|m| Frame::Msg(m.as_bytes().to_vec()))
+            .collect();
+        assert_eq!(order, want);
+    }
+
+    #[test]
+    fn aging_lets_long_waiting_low_priority_frames_through() {
+        let now = Instant::now();
+        let item = |seq: u64, p: u8, waited: u64| Item {
+            seq,
+            attempts: 0,
+            enqueued: now - Duration::from_secs(waited),
+            meta: prio(p),
+            frame: Frame::Msg(vec![seq as u8]),
+        };
+        let fill = |lanes: &mut Lanes| {
+            lanes.push_back(item(1, 0, 60)); // waited a minute at priority 0
+            lanes.push_back(item(2, 5, 0));  // fresh at priority 5
+        };
+
+        // Strict priority: the fresh high-priority frame wins.
+        let mut strict = Lanes::default();
+        fill(&mut strict);
+        assert_eq!(strict.pop_at(now).unwrap().seq, 2);
+
+        // One level per 10s: 60s of waiting is worth +6 > 5.
+        let mut aged = Lanes { aging: Some(Duration::from_secs(10)), ..Lanes::default() };
+        fill(&mut aged);
+        assert_eq!(aged.pop_at(now).unwrap().seq, 1);
+        assert_eq!(aged.pop_at(now).unwrap().seq, 2);
+        assert_eq!(aged.len(), 0);
+        assert!(aged.pop_at(now).is_none());
+    }
+
+    #[test]
+    fn requeued_frames_keep_their_priority() {
+        let r = mk(8);
+        let a = r.register_consumer();
+        assert!(r.push_with(Frame::Msg(b"urgent".to_vec()), prio(7)));
+        let f = r.pop_for(a);
+        assert!(r.push(Frame::Msg(b"bulk".to_vec())));
+        assert!(r.fail_delivery(a, f));
+        assert_eq!(r.pop_for(a), Frame::Msg(b"urgent".to_vec()));
+    }
+
+    #[test]
+    fn weighted_consumer_holds_at_most_its_window() {
+        let r = Arc::new(mk_policy(no_backoff(5)));
+        let a = r.register_ack_consumer();
+        r.set_window(a, 2);
+        for p in [b"1", b"2", b"3"] {
+            assert!(r.push(Frame::Msg(p.to_vec())));
+        }
+        let (_, first) = next(&r, a);
+        r.delivered(a);
+        next(&r, a);
+        r.delivered(a);
+
+        let (tx, rx) = mpsc::channel();
+        let r2 = Arc::clone(&r);
+        let t = thread::spawn(move || tx.send(r2.next_for(a)).unwrap());
+        assert!(
+            rx.recv_timeout(Duration::from_millis(100)).is_err(),
+            "a third delivery must wait for an ack",
+        );
+        assert!(r.ack(a, first.delivery.unwrap()));
+        let (f, _) = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(f, Frame::Msg(b"3".to_vec()));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn full_window_still_completes_a_claimed_message() {
+        let r = mk_policy(no_backoff(5));
+        let a = r.register_ack_consumer();
+        r.set_window(a, 1);
+        assert!(r.push(ch(1, 0, 2)));
+        assert!(r.push(Frame::Msg(b"later".to_vec())));
+        assert!(r.push(ch(1, 1, 2)));
+
+        let (f, meta) = next(&r, a);
+        assert_eq!(f, ch(1, 0, 2));
+        r.delivered(a);
+        // The window is full, but the rest of message 1 skips past "later".
+        assert_eq!(next(&r, a).0, ch(1, 1, 2));
+        r.delivered(a);
+        assert!(r.ack(a, meta.delivery.unwrap()));
+        assert_eq!(next(&r, a).0, Frame::Msg(b"later".to_vec()));
+    }
+
+    #[test]
+    fn a_zero_window_pauses_a_consumer_until_reopened() {
+        let r = mk_policy(no_backoff(5));
+        let a = r.register_ack_consumer();
+        r.set_window(a, 0);
+        assert!(r.push(ch(1, 0, 2)));
+        assert!(r.push(ch(1, 1, 2)));
+        assert!(r.try_next_for(a).is_none(), "paused consumers take no new work");
+
+        r.set_window(a, 1);
+        let (f, meta) = next(&r, a);
+        assert_eq!(f, ch(1, 0, 2));
+        r.delivered(a);
+        // Pausing again still lets the claimed message finish.
+        r.set_window(a, 0);
+        assert_eq!(next(&r, a).0, ch(1, 1, 2));
+        r.delivered(a);
+        assert!(r.ack(a, meta.delivery.unwrap()));
+        assert_eq!(r.depth(), 0);
+    }
+
+    fn needs(tags: &[&str]) -> Meta {
+        Meta { requires: tags.iter().map(|t| t.to_string()).collect(), ..Meta::default() }
+    }
+
+    fn caps(tags: &[&str]) -> BTreeSet<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn tagged_messages_go_only_to_capable_consumers() {
+        let r = mk(8);
+        let plain = r.register_consumer();
+        let gpu = r.register_consumer();
+        r.set_capabilities(gpu, caps(&["gpu", "site=nersc"]));
+        assert!(r.push_with(Frame::Msg(b"train".to_vec()), needs(&["gpu"])));
+        assert!(r.push(Frame::Msg(b"plot".to_vec())));
+
+        assert_eq!(r.pop_for(plain), Frame::Msg(b"plot".to_vec()));
+        assert_eq!(r.pop_for(gpu), Frame::Msg(b"train".to_vec()));
+    }
+
+    #[test]
+    fn tag_fallback_decides_what_nobody_can_take() {
+        let unroutable = || (Frame::Msg(b"quantum".to_vec()), needs(&["qpu"]));
+
+        // any: requirements are ignored while nobody can meet them.
+        let r = mk(8).with_tag_fallback(TagFallback::Any);
+        let a = r.register_consumer();
+        let (f, m) = unroutable();
+        assert!(r.push_with(f, m));
+        assert_eq!(r.pop_for(a), Frame::Msg(b"quantum".to_vec()));
+
+        // dead-letter: settled and counted, then the queue moves on.
+        let r = mk(8).with_tag_fallback(TagFallback::DeadLetter);
+        let a = r.register_consumer();
+        let (f, m) = unroutable();
+        assert!(r.push_with(f, m));
+        assert!(r.push(Frame::Msg(b"next".to_vec())));
+        assert_eq!(r.pop_for(a), Frame::Msg(b"next".to_vec()));
+        assert_eq!(r.stats.dead_lettered_msgs.load(Ordering::Relaxed), 1);
+
+        // wait (default): it stays queued until a capable consumer shows up.
+        let r = mk(8);
+        let a = r.register_consumer();
+        let (f, m) = unroutable();
+        assert!(r.push_with(f, m));
+        assert!(r.push(Frame::Msg(b"next".to_vec())));
+        assert_eq!(r.pop_for(a), Frame::Msg(b"next".to_vec()));
+        assert_eq!(r.depth(), 1);
+        let q = r.register_consumer();
+        r.set_capabilities(q, caps(&["qpu"]));
+        assert_eq!(r.pop_for(q), Frame::Msg(b"quantum".to_vec()));
+    }
+
+    fn about(run: &str) -> Meta {
+        Meta { headers: [("run", run)].into_iter().collect(), ..Meta::default() }
+    }
+
+    #[test]
+    fn filtered_consumers_get_their_matches_and_the_rest_goes_to_the_pool() {
+        let r = mk(8);
+        let plain = r.register_consumer();
+        let sim = r.register_consumer();
+        r.set_filter(sim, Filter::parse("run^=sim-").unwrap());
+        assert!(r.push_with(Frame::Msg(b"a".to_vec()), about("sim-7")));
+        assert!(r.push_with(Frame::Msg(b"b".to_vec()), about("exp-3")));
+        assert!(r.push(Frame::Msg(b"c".to_vec())));
+
+        // The unfiltered consumer leaves the match to the filtered one.
+        assert_eq!(r.pop_for(plain), Frame::Msg(b"b".to_vec()));
+        r.delivered(plain);
+        assert_eq!(r.pop_for(plain), Frame::Msg(b"c".to_vec()));
+        assert_eq!(r.pop_for(sim), Frame::Msg(b"a".to_vec()));
+
+        // With only filtered consumers, what no filter passes waits.
+        r.unregister_consumer(plain);
+        assert!(r.push_with(Frame::Msg(b"d".to_vec()), about("exp-4")));
+        assert!(r.try_next_for(sim).is_none());
+        assert_eq!(r.depth(), 1);
+    }
+
+    #[test]
+    fn fanout_subscribers_only_get_copies_their_filter_passes() {
+        let topic = Arc::new(mk(8).with_fanout());
+        let (a, b) = (topic.subscribe(), topic.subscribe());
+        a.register_consumer();
+        let cb = b.register_consumer();
+        b.set_filter(cb, Filter::parse("run=sim-1").unwrap());
+        assert!(topic.push_with(Frame::Msg(b"x".to_vec()), about("sim-2")));
+        assert!(topic.push_with(Frame::Msg(b"y".to_vec()), about("sim-1")));
+        assert!(topic.push(Frame::Eos(b"done".to_vec())));
+        assert_eq!((a.depth(), b.depth()), (3, 2));
+        assert_eq!(b.pop_for(cb), Frame::Msg(b"y".to_vec()));
+        b.delivered(cb);
+        assert_eq!(b.pop_for(cb), Frame::Eos(b"done".to_vec()));
+    }
+
+    fn hint(memory: u64, gpus: u32) -> Meta {
+        Meta { memory: Some(memory), gpus: Some(gpus), ..Meta::default() }
+    }
+
+    #[test]
+    fn hinted_work_is_packed_into_remaining_capacity() {
+        let r = mk_policy(no_backoff(5));
+        let node = r.register_ack_consumer();
+        r.set_resources(node, Resources { memory: 100, gpus: 1 });
+        assert!(r.push_with(Frame::Msg(b"big".to_vec()), hint(60, 0)));
+        assert!(r.push_with(Frame::Msg(b"big2".to_vec()), hint(60, 0)));
+        assert!(r.push_with(Frame::Msg(b"small".to_vec()), hint(30, 1)));
+
+        let (f, big) = next(&r, node);
+        assert_eq!(f, Frame::Msg(b"big".to_vec()));
+        r.delivered(node);
+        // 40 left: "big2" doesn't fit yet, so "small" backfills.
+        let (f, small) = next(&r, node);
+        assert_eq!(f, Frame::Msg(b"small".to_vec()));
+        r.delivered(node);
+        {
+            let g = r.inner.lock().unwrap();
+            assert_eq!(g.budgets[&node].used, Resources { memory: 90, gpus: 1 });
+        }
+        assert!(r.ack(node, big.delivery.unwrap()));
+        assert_eq!(next(&r, node).0, Frame::Msg(b"big2".to_vec()));
+        assert!(r.ack(node, small.delivery.unwrap()));
+    }
+
+    #[test]
+    fn oversized_hints_go_elsewhere_or_to_the_fallback() {
+        let r = mk_policy(no_backoff(5));
+        let small = r.register_ack_consumer();
+        r.set_resources(small, Resources { memory: 10, gpus: 0 });
+        let unlimited = r.register_consumer(); // advertised nothing: unconstrained
+        assert!(r.push_with(Frame::Msg(b"huge".to_vec()), hint(1000, 0)));
+        assert!(r.push(Frame::Msg(b"plain".to_vec())));
+        assert_eq!(next(&r, small).0, Frame::Msg(b"plain".to_vec()));
+        assert_eq!(r.pop_for(unlimited), Frame::Msg(b"huge".to_vec()));
+
+        let r = mk_policy(no_backoff(5)).with_tag_fallback(TagFallback::Any);
+        let small = r.register_ack_consumer();
+        r.set_resources(small, Resources { memory: 10, gpus: 0 });
+        assert!(r.push_with(Frame::Msg(b"huge".to_vec()), hint(1000, 0)));
+        assert_eq!(next(&r, small).0, Frame::Msg(b"huge".to_vec()));
+    }
+
+    #[test]
+    fn expected_runtime_extends_the_visibility_deadline() {
+        let r = mk_policy(no_backoff(5));
+        let a = r.register_ack_consumer();
+        let long = Meta { runtime: Some(Duration::from_secs(3600)), ..Meta::default() };
+        assert!(r.push_with(Frame::Msg(b"sim".to_vec()), long));
+        next(&r, a);
+        r.delivered(a);
+        assert_eq!(r.expire_unacked(past_deadline()), 0, "still within its runtime");
+        assert_eq!(r.expire_unacked(Instant::now() + Duration::from_secs(3601)), 1);
+    }
+
+    // ---- deterministic simulation ----
+
+    /// Seeded random walks through a simulated router: producers, plain and
+    /// ack-mode consumers, failed writes, disconnects, timeouts and sweeps,
+    /// interleaved on one thread by one seed against a virtual clock. A
+    /// failure names its seed; replay it with QPIPE_SIM_SEED=<seed>, or run
+    /// more seeds with QPIPE_SIM_SEEDS=<count>.
+    mod sim {
+        use super::*;
+        use std::collections::BTreeMap;
+
+        const STEPS: usize = 400;
+        const DEFAULT_SEEDS: u64 = 64;
+
+        /// One simulated consumer connection. Like a real handler it holds
+        /// at most one frame between `next_for` and its frame ACK.
+        struct Client {
+            id:       ConsumerId,
+            ack_mode: bool,
+            held:     Option<(Frame, Meta)>,
+            /// Ack mode: frames received per delivery tag, in order.
+            received: BTreeMap<u64, Vec<Frame>>,
+        }
+
+        /// Where a single message ended up.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum Fate { Completed, Lost }
+
+        struct World {
+            r:        Router,
+            dead:     Sink,
+            clients:  Vec<Client>,
+            next_id:  u64,
+            next_msg: u128,
+            singles:  BTreeMap<u64, Option<Fate>>,
+            /// (message, chunk) -> consumer that completed it.
+            chunks:   BTreeMap<(u128, u32), ConsumerId>,
+            /// Per EOS group: the singles pushed before it.
+            eos:      BTreeMap<Vec<u8>, Vec<u64>>,
+            trace:    Vec<String>,
+        }
+
+        fn policy() -> RetryPolicy {
+            RetryPolicy {
+                visibility_timeout: Duration::from_secs(10),
+                max_retries:        2,
+                backoff_min:        Duration::from_secs(1),
+                backoff_max:        Duration::from_secs(4),
+                dead_letter:        DeadLetter::Drop,
+            }
+        }
+
+        fn single_id(f: &Frame) -> Option<u64> {
+            match f {
+                Frame::Msg(p) => Some(u64::from_be_bytes(p[..8].try_into().unwrap())),
+                _ => None,
+            }
+        }
+
+        impl World {
+            fn new(seed: u64) -> Self {
+                let r = Router::simulated(1 << 20, policy(), seed);
+                let dead = Sink::default();
+                r.set_dead_letter_sink(Box::new(dead.clone()));
+                Self {
+                    r, dead, clients: Vec::new(), next_id: 0, next_msg: 0,
+                    singles: BTreeMap::new(), chunks: BTreeMap::new(),
+                    eos: BTreeMap::new(), trace: Vec::new(),
+                }
+            }
+
+            fn rand(&self, n: u64) -> u64 {
+                self.r.sim.as_ref().unwrap().next_u64() % n.max(1)
+            }
+
+            fn log(&mut self, event: String) {
+                self.trace.push(event);
+            }
+
+            fn dead_singles(&self) -> BTreeSet<u64> {
+                let buf = self.dead.0.lock().unwrap().clone();
+                let mut rd = &buf[..];
+                let mut out = BTreeSet::new();
+                while let Some((f, _)) = crate::get_frame(&mut rd).unwrap() {
+                    out.extend(single_id(&f));
+                }
+                out
+            }
+
+            fn connect(&mut self) {
+                let ack_mode = self.rand(2) == 0;
+                let id = self.r.register(ack_mode);
+                if ack_mode && self.rand(2) == 0 {
+                    self.r.set_window(id, 1 + self.rand(3) as u32);
+                }
+                let token = self.r.session_token().unwrap();
+                self.log(format!("c{id} connect ack={ack_mode} token={}", to_hex(&token)));
+                self.clients.push(Client { id, ack_mode, held: None, received: BTreeMap::new() });
+            }
+
+            /// The handler's connection ends; a plain frame it held is lost.
+            fn disconnect(&mut self, i: usize) {
+                let c = self.clients.swap_remove(i);
+                if !c.ack_mode
+                    && let Some(id) = c.held.as_ref().and_then(|(f, _)| single_id(f))
+                {
+                    self.singles.insert(id, Some(Fate::Lost));
+                }
+                self.r.unregister_consumer(c.id);
+                self.log(format!("c{} disconnect", c.id));
+            }
+
+            fn push(&mut self) {
+                match self.rand(10) {
+                    0..=5 => {
+                        let id = self.next_id;
+                        self.next_id += 1;
+                        let meta = Meta { priority: Some(self.rand(3) as u8), ..Meta::default() };
+                        assert!(self.r.push_with(Frame::Msg(id.to_be_bytes().to_vec()), meta));
+                        self.singles.insert(id, None);
+                        self.log(format!("push single {id}"));
+                    }
+                    6..=8 => {
+                        let msg = self.next_msg;
+                        self.next_msg += 1;
+                        let count = 1 + self.rand(4) as u32;
+                        for idx in 0..count {
+                            self.r.push(Frame::Chunk { id: msg, idx, count, payload: vec![idx as u8] });
+                        }
+                        self.log(format!("push message {msg} x{count}"));
+                    }
+                    _ => {
+                        let group = (self.eos.len() as u32).to_be_bytes().to_vec();
+                        self.eos.insert(group.clone(), self.singles.keys().copied().collect());
+                        assert!(self.r.push(Frame::Eos(group.clone())));
+                        self.log(format!("push eos {group:?}"));
+                    }
+                }
+            }
+
+            fn poll(&mut self, i: usize) -> Result<(), String> {
+                if self.clients[i].held.is_some() {
+                    return Ok(());
+                }
+                let me = self.clients[i].id;
+                let Some((f, meta)) = self.r.try_next_for(me) else { return Ok(()) };
+                self.log(format!("c{me} got {f:?} tag={:?} attempt={:?}", meta.delivery, meta.attempt));
+                if let Frame::Eos(group) = &f {
+                    // A notice may only overtake nothing: every single pushed
+                    // before the marker has settled one way or another.
+                    let dead = self.dead_singles();
+                    for id in &self.eos[group] {
+                        if self.singles[id].is_none() && !dead.contains(id) {
+                            return Err(format!("eos {group:?} released before single {id} settled"));
+                        }
+                    }
+                }
+                self.clients[i].held = Some((f, meta));
+                Ok(())
+            }
+
+            /// The held frame's ACK arrived.
+            fn frame_ack(&mut self, i: usize) -> Result<(), String> {
+                let Some((f, meta)) = self.clients[i].held.take() else { return Ok(()) };
+                let me = self.clients[i].id;
+                self.r.delivered(me);
+                self.log(format!("c{me} frame-ack"));
+                if matches!(f, Frame::Eos(_)) {
+                    return Ok(());
+                }
+                match meta.delivery {
+                    Some(tag) => self.clients[i].received.entry(tag).or_default().push(f),
+                    None => self.complete(me, &f)?,
+                }
+                Ok(())
+            }
+
+            /// An ack-mode client acks a delivery once it holds all of it.
+            fn ack(&mut self, i: usize) -> Result<(), String> {
+                let me = self.clients[i].id;
+                let ready = self.clients[i].received.iter()
+                    .find(|(_, fs)| match &fs[0] {
+                        Frame::Chunk { count, .. } => fs.len() as u32 >= *count,
+                        _ => true,
+                    })
+                    .map(|(t, _)| *t);
+                let Some(tag) = ready else { return Ok(()) };
+                let frames = self.clients[i].received.remove(&tag).unwrap();
+                let settled = self.r.ack(me, tag);
+                self.log(format!("c{me} ack {tag} -> {settled}"));
+                if settled {
+                    for f in &frames {
+                        self.complete(me, f)?;
+                    }
+                }
+                Ok(())
+            }
+
+            /// A write failed: the handler reports it and leaves.
+            fn fail(&mut self, i: usize) {
+                if let Some((f, _)) = self.clients[i].held.take() {
+                    let me = self.clients[i].id;
+                    let requeued = self.r.fail_delivery(me, f);
+                    self.log(format!("c{me} write failed, requeued={requeued}"));
+                }
+                self.disconnect(i);
+            }
+
+            fn complete(&mut self, me: ConsumerId, f: &Frame) -> Result<(), String> {
+                match f {
+                    Frame::Msg(_) => {
+                        let id = single_id(f).unwrap();
+                        match self.singles.insert(id, Some(Fate::Completed)) {
+                            Some(None) => Ok(()),
+                            before => Err(format!("single {id} completed again (was {before:?})")),
+                        }
+                    }
+                    Frame::Chunk { id, idx, .. } => {
+                        if let Some(prev) = self.chunks.insert((*id, *idx), me) {
+                            return Err(format!("chunk {id}/{idx} completed twice (c{prev}, c{me})"));
+                        }
+                        let owners: BTreeSet<_> = self.chunks.range((*id, 0)..=(*id, u32::MAX))
+                            .map(|(_, c)| *c)
+                            .collect();
+                        if owners.len() > 1 {
+                            return Err(format!("message {id} split across consumers {owners:?}"));
+                        }
+                        Ok(())
+                    }
+                    Frame::Eos(_) | Frame::Ping | Frame::Bye => Ok(()),
+                }
+            }
+
+            fn advance(&mut self, by: Duration) {
+                self.r.sim.as_ref().unwrap().advance(by);
+                let expired = self.r.expire_unacked(self.r.now());
+                self.log(format!("advance {by:?}, {expired} expired"));
+            }
+
+            fn step(&mut self) -> Result<(), String> {
+                let n = self.clients.len();
+                let pick = self.rand(n as u64) as usize;
+                match self.rand(100) {
+                    0..=24 => self.push(),
+                    25..=49 if n > 0 => self.poll(pick)?,
+                    50..=69 if n > 0 => self.frame_ack(pick)?,
+                    70..=79 if n > 0 => self.ack(pick)?,
+                    80..=82 if n > 0 => self.fail(pick),
+                    83..=90 => {
+                        let by = Duration::from_millis(self.rand(15_000));
+                        self.advance(by);
+                    }
+                    91..=92 => {
+                        let swept = self.r.sweep(Duration::from_secs(60), Duration::from_secs(120));
+                        self.log(format!("sweep {swept:?}"));
+                    }
+                    93..=95 if n > 0 => self.disconnect(pick),
+                    _ if n < 6 => self.connect(),
+                    _ => {}
+                }
+                Ok(())
+            }
+
+            /// Let well-behaved clients empty the queue, then account for
+            /// every single: completed, dead-lettered or lost in flight.
+            fn finish(&mut self) -> Result<(), String> {
+                for _ in 0..10_000 {
+                    if self.r.outstanding() == 0 && self.r.depth() == 0 {
+                        break;
+                    }
+                    if self.clients.is_empty() {
+                        self.connect();
+                    }
+                    for i in 0..self.clients.len() {
+                        self.poll(i)?;
+                        self.frame_ack(i)?;
+                        self.ack(i)?;
+                    }
+                    self.advance(Duration::from_secs(1));
+                }
+                if self.r.outstanding() != 0 || self.r.depth() != 0 {
+                    return Err(format!(
+                        "queue never drained: outstanding={} depth={}",
+                        self.r.outstanding(), self.r.depth(),
+                    ));
+                }
+                let dead = self.dead_singles();
+                for (id, fate) in &self.singles {
+                    match (fate, dead.contains(id)) {
+                        (Some(_), false) | (None, true) => {}
+                        (None, false) => return Err(format!("single {id} vanished")),
+                        (Some(f), true) => {
+                            return Err(format!("single {id} both {f:?} and dead-lettered"));
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        /// Run one seed; Err carries the failing step and the trace tail.
+        fn run(seed: u64) -> Result<Vec<String>, String> {
+            let mut w = World::new(seed);
+            let mut res = Ok(());
+            for step in 0..STEPS {
+                res = w.step().map_err(|e| format!("step {step}: {e}"));
+                if res.is_err() {
+                    break;
+                }
+            }
+            res = res.and_then(|()| w.finish().map_err(|e| format!("drain: {e}")));
+            match res {
+                Ok(()) => Ok(w.trace),
+                Err(e) => {
+                    let tail = w.trace[w.trace.len().saturating_sub(20)..].join("\n  ");
+                    Err(format!("{e}\n  last events:\n  {tail}"))
+                }
+            }
+        }
+
+        fn seeds() -> Vec<u64> {
+            if let Ok(s) = env::var("QPIPE_SIM_SEED") {
+                return vec![s.parse().expect("QPIPE_SIM_SEED must be a number")];
+            }
+            let n = env::var("QPIPE_SIM_SEEDS").ok()
+                .map(|s| s.parse().expect("QPIPE_SIM_SEEDS must be a number"))
+                .unwrap_or(DEFAULT_SEEDS);
+            (0..n).collect()
+        }
+
+        #[test]
+        fn random_schedules_lose_and_duplicate_nothing() {
+            for seed in seeds() {
+                if let Err(e) = run(seed) {
+                    panic!("seed {seed} failed (replay with QPIPE_SIM_SEED={seed}): {e}");
+                }
+            }
+        }
+
+        #[test]
+        fn a_seed_replays_exactly() {
+            let first = run(7).unwrap();
+            assert_eq!(run(7).unwrap(), first, "same seed, same schedule and outcomes");
+            assert_ne!(run(8).unwrap(), first, "different seeds explore differently");
+            assert!(first.iter().any(|e| e.contains("token=")));
+        }
+    }
+}
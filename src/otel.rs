@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! End-to-end tracing through the queue (`otel` feature): W3C trace
+//! context travels in a message header from producer to consumer, and the
+//! orchestrator records spans for each traced message in between.
+//!
+//!   let parent = TraceContext::parse(&incoming_traceparent)?;
+//!   producer.set_trace_context(Some(parent));
+//!   producer.send(b"job")?;          // carries `traceparent: 00-…-01`
+//!
+//!   let msg = consumer.recv_ack()?;
+//!   let parent = msg.trace_context(); // continue the trace from here
+//!
+//! Once given a context with `Producer::set_trace_context`, a producer puts
+//! it in a `traceparent` header (`HEADER_TRACEPARENT`) on each message it
+//! sends, unless the message has one already. `Message::trace_context`
+//! reads it back, as `extract` does from any headers. Batches
+//! (`send_batch`) carry no headers, and so no context.
+//!
+//! With `QPIPE_OTEL_SPANS=<path>`, the orchestrator records three spans for
+//! each traced message, children of the context it arrived with:
+//! `qpipe.enqueue`, from receipt until the queue took it (a wait for room
+//! included); `qpipe.queue_wait`, from then until a consumer took it; and
+//! `qpipe.deliver`, from then until it settled, by its ACK or, in ack mode,
+//! its ack. A message taken again after a failed delivery gets another
+//! wait, counted from when it was queued, and another delivery. A chunked
+//! message is traced by its first chunk. The file gets one
+//! OTLP/JSON `ExportTraceServiceRequest` per line, the format the
+//! OpenTelemetry Collector's `otlpjsonfile` receiver reads. Messages whose
+//! context isn't sampled get no spans.
+
+use std::fmt;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::digest::to_hex;
+use crate::{psk, Headers};
+
+/// Header carrying a message's W3C trace context.
+pub const HEADER_TRACEPARENT: &str = "traceparent";
+
+/// The `sampled` bit of the trace flags.
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// A W3C trace context: the trace, the span a message was sent from, and
+/// the trace flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    span_id:  [u8; 8],
+    flags:    u8,
+}
+
+impl TraceContext {
+    /// A new sampled trace, at a random root span.
+    pub fn new_root() -> io::Result<Self> {
+        let r = psk::nonce()?;
+        Ok(Self {
+            trace_id: r[..16].try_into().unwrap(),
+            span_id:  r[16..24].try_into().unwrap(),
+            flags:    FLAG_SAMPLED,
+        })
+    }
+
+    /// The same trace at a new random span, for a span started in this
+    /// context.
+    pub fn child(&self) -> io::Result<Self> {
+        let r = psk::nonce()?;
+        Ok(Self { span_id: r[..8].try_into().unwrap(), ..*self })
+    }
+
+    /// Read a `traceparent` value. `InvalidInput` for one that isn't
+    /// `version-trace_id-span_id-flags` in lowercase hex, or has an
+    /// all-zero id. Later versions may append fields, which are skipped.
+    pub fn parse(traceparent: &str) -> io::Result<Self> {
+        let bad = || io::Error::new(io::ErrorKind::InvalidInput, format!("bad traceparent {traceparent:?}"));
+        let mut fields = traceparent.split('-');
+        let mut field = |len: usize| fields.next().filter(|f| f.len() == len).and_then(from_hex).ok_or_else(bad);
+        let version = field(2)?[0];
+        let trace_id: [u8; 16] = field(32)?.try_into().unwrap();
+        let span_id: [u8; 8] = field(16)?.try_into().unwrap();
+        let flags = field(2)?[0];
+        let extra = fields.next().is_some();
+        if version == 0xff || version == 0 && extra || trace_id == [0; 16] || span_id == [0; 8] {
+            return Err(bad());
+        }
+        Ok(Self { trace_id, span_id, flags })
+    }
+
+    pub fn trace_id(&self) -> String {
+        to_hex(&self.trace_id)
+    }
+
+    pub fn span_id(&self) -> String {
+        to_hex(&self.span_id)
+    }
+
+    /// Whether the trace is being recorded.
+    pub fn sampled(&self) -> bool {
+        self.flags & FLAG_SAMPLED != 0
+    }
+}
+
+/// The `traceparent` value, version 00.
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{}-{}-{:02x}", self.trace_id(), self.span_id(), self.flags)
+    }
+}
+
+/// Lowercase hex only, as W3C trace context has it.
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    };
+    s.as_bytes().chunks(2).map(|p| Some(digit(p[0])? << 4 | digit(*p.get(1)?)?)).collect()
+}
+
+/// Set `ctx` as the trace context `headers` carry.
+pub fn inject(headers: &mut Headers, ctx: &TraceContext) {
+    headers.insert(HEADER_TRACEPARENT, ctx.to_string());
+}
+
+/// The trace context `headers` carry; `None` without one, or with one
+/// that doesn't parse.
+pub fn extract(headers: &Headers) -> Option<TraceContext> {
+    headers.get(HEADER_TRACEPARENT).and_then(|v| TraceContext::parse(v).ok())
+}
+
+/// OTLP span kinds.
+pub(crate) const SPAN_KIND_INTERNAL: u8 = 1;
+pub(crate) const SPAN_KIND_SERVER: u8 = 2;
+pub(crate) const SPAN_KIND_PRODUCER: u8 = 4;
+
+/// One span the orchestrator recorded.
+pub(crate) struct Span<'a> {
+    pub name:   &'static str,
+    pub kind:   u8,
+    pub parent: &'a TraceContext,
+    pub start:  SystemTime,
+    pub end:    SystemTime,
+    pub attrs:  Vec<(&'static str, String)>,
+    /// Set for a span that ended some other way than intended.
+    pub error:  Option<&'a str>,
+}
+
+impl Span<'_> {
+    /// The span as an OTLP/JSON export request on one line, under a new
+    /// random span id.
+    pub(crate) fn line(&self) -> io::Result<String> {
+        let id = self.parent.child()?;
+        let nanos = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let attr = |k: &str, v: &str| format!(r#"{{"key":{},"value":{{"stringValue":{}}}}}"#, json_str(k), json_str(v));
+        let attrs: Vec<String> = self.attrs.iter().map(|(k, v)| attr(k, v)).collect();
+        let status = match self.error {
+            Some(msg) => format!(r#"{{"code":2,"message":{}}}"#, json_str(msg)),
+            None => r#"{"code":1}"#.to_string(),
+        };
+        Ok(format!(
+            concat!(
+                r#"{{"resourceSpans":[{{"resource":{{"attributes":[{}]}},"#,
+                r#""scopeSpans":[{{"scope":{{"name":"qpipe","version":"{}"}},"spans":[{{"#,
+                r#""traceId":"{}","spanId":"{}","parentSpanId":"{}","name":{},"kind":{},"#,
+                r#""startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":[{}],"status":{}"#,
+                "}}]}}]}}]}}\n",
+            ),
+            attr("service.name", "qpipe-orchestrator"), env!("CARGO_PKG_VERSION"),
+            id.trace_id(), id.span_id(), self.parent.span_id(), json_str(self.name), self.kind,
+            nanos(self.start), nanos(self.end.max(self.start)), attrs.join(","), status,
+        ))
+    }
+}
+
+/// `s` as a JSON string literal.
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparents_parse_and_print_back() {
+        let tp = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let ctx = TraceContext::parse(tp).unwrap();
+        assert_eq!(ctx.to_string(), tp);
+        assert!(ctx.sampled());
+        assert_eq!(ctx.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+
+        let child = ctx.child().unwrap();
+        assert_eq!(child.trace_id(), ctx.trace_id());
+        assert_ne!(child.span_id(), ctx.span_id());
+
+        // A later version may add fields; 00 may not.
+        assert!(TraceContext::parse(&tp.replacen("00", "01", 1).replace("-01", "-00-future")).is_ok());
+        for bad in [
+            &format!("{tp}-x")[..],
+            &tp.to_uppercase(),
+            &tp.replacen("00", "ff", 1),
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "",
+        ] {
+            assert!(TraceContext::parse(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn context_round_trips_through_headers() {
+        let ctx = TraceContext::new_root().unwrap();
+        let mut h = Headers::new().with("k", "v");
+        inject(&mut h, &ctx);
+        assert_eq!(extract(&h), Some(ctx));
+        assert_eq!(extract(&Headers::new().with(HEADER_TRACEPARENT, "junk")), None);
+    }
+
+    #[test]
+    fn spans_are_one_otlp_json_line_each() {
+        let parent = TraceContext::new_root().unwrap();
+        let t = UNIX_EPOCH + std::time::Duration::from_millis(1500);
+        let span = Span {
+            name: "qpipe.deliver", kind: SPAN_KIND_PRODUCER, parent: &parent, start: t, end: t,
+            attrs: vec![("qpipe.outcome", "dead \"lettered\"".into())], error: Some("dead-lettered"),
+        };
+        let line = span.line().unwrap();
+        assert!(line.ends_with("}]}]}]}\n") && line.matches('\n').count() == 1, "{line}");
+        assert!(line.contains(&format!(r#""traceId":"{}""#, parent.trace_id())));
+        assert!(line.contains(&format!(r#""parentSpanId":"{}""#, parent.span_id())));
+        assert!(line.contains(r#""startTimeUnixNano":"1500000000""#));
+        assert!(line.contains(r#""stringValue":"dead \"lettered\"""#));
+        assert!(line.contains(r#""status":{"code":2,"message":"dead-lettered"}"#));
+    }
+}
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Disk overflow behind a full orchestrator queue.
+//!
+//! Once a queue holds its in-memory capacity, further frames are appended
+//! here instead of blocking their producers, and read back oldest-first as
+//! consumers make room. Frames keep their metadata; chunks and EOS markers
+//! go through like any other frame, so order is exactly what it would have
+//! been in memory.
+//!
+//! Segments are numbered files (`<n>.ovf`) of records `[u32 BE len][frame
+//! in put_frame format]`, rolled over every SEGMENT_BYTES so the space of
+//! consumed frames is returned as the backlog shrinks. With an at-rest key
+//! the frame bytes are sealed (see `at_rest`).
+//!
+//! The overflow extends the queue's memory; it is not a journal. Like the
+//! in-memory queue it does not survive a restart: segments left by an
+//! earlier run are discarded when the directory is opened.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::at_rest::{self, Key};
+use crate::{get_frame, put_frame, Frame, Meta};
+
+/// Roll over to a new segment once the current one is this big.
+const SEGMENT_BYTES: u64 = 64 << 20;
+
+/// Associated data binding sealed records to the overflow format.
+const OVERFLOW_AAD: &[u8] = b"qpipe-overflow-v1";
+
+pub struct Overflow {
+    dir:    PathBuf,
+    key:    Option<Key>,
+    /// Segment numbers holding unread frames, oldest first.
+    segs:   VecDeque<u64>,
+    /// The segment being appended to, and its size so far.
+    writer: Option<(u64, BufWriter<File>, u64)>,
+    /// The oldest segment, positioned at its first unread frame.
+    reader: Option<(u64, BufReader<File>)>,
+    len:    usize,
+}
+
+impl Overflow {
+    /// Open (creating if needed) an overflow directory. Segments left
+    /// behind by an earlier run are removed; the count is returned so the
+    /// caller can say so.
+    pub fn open(dir: &Path, key: Option<Key>) -> io::Result<(Self, usize)> {
+        fs::create_dir_all(dir)?;
+        let mut stale = 0;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "ovf") {
+                fs::remove_file(&path)?;
+                stale += 1;
+            }
+        }
+        let ov = Self {
+            dir: dir.to_path_buf(), key, segs: VecDeque::new(), writer: None, reader: None, len: 0,
+        };
+        Ok((ov, stale))
+    }
+
+    /// Frames waiting on disk.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn seg_path(&self, seg: u64) -> PathBuf {
+        self.dir.join(format!("{seg:012}.ovf"))
+    }
+
+    /// Append one frame behind everything already spilled.
+    pub fn push(&mut self, frame: &Frame, meta: &Meta) -> io::Result<()> {
+        let mut rec = Vec::new();
+        put_frame(&mut rec, frame, meta)?;
+        if let Some(key) = &self.key {
+            rec = at_rest::seal(key, OVERFLOW_AAD, &rec)?;
+        }
+        if self.writer.as_ref().is_none_or(|(_, _, size)| *size >= SEGMENT_BYTES) {
+            if let Some((_, mut w, _)) = self.writer.take() {
+                w.flush()?;
+            }
+            let seg = self.segs.back().map_or(1, |s| s + 1);
+            let f = OpenOptions::new().create(true).append(true).open(self.seg_path(seg))?;
+            self.segs.push_back(seg);
+            self.writer = Some((seg, BufWriter::new(f), 0));
+        }
+        let (_, w, size) = self.writer.as_mut().expect("just opened");
+        w.write_all(&(rec.len() as u32).to_be_bytes())?;
+        w.write_all(&rec)?;
+        *size += 4 + rec.len() as u64;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Take up to `max` frames, oldest first, deleting segments as they are
+    /// used up.
+    pub fn take(&mut self, max: usize) -> io::Result<Vec<(Frame, Meta)>> {
+        let mut out = Vec::new();
+        while out.len() < max && self.len > 0 {
+            let Some(&seg) = self.segs.front() else { break };
+            if let Some((wseg, w, _)) = &mut self.writer
+                && *wseg == seg
+            {
+                // The reader is about to catch up with buffered appends.
+                w.flush()?;
+            }
+            if self.reader.as_ref().is_none_or(|(rseg, _)| *rseg != seg) {
+                self.reader = Some((seg, BufReader::new(File::open(self.seg_path(seg))?)));
+            }
+            match self.read_record(seg)? {
+                Some(f) => {
+                    out.push(f);
+                    self.len -= 1;
+                }
+                None => {
+                    // Used up. The segment being written is only dropped
+                    // once everything in it has been read.
+                    if self.writer.as_ref().is_some_and(|(wseg, _, _)| *wseg == seg) {
+                        self.writer = None;
+                    }
+                    self.reader = None;
+                    self.segs.pop_front();
+                    fs::remove_file(self.seg_path(seg))?;
+                }
+            }
+        }
+        if self.len == 0 {
+            // Everything read: start over rather than grow the last segment.
+            self.writer = None;
+            self.reader = None;
+            while let Some(seg) = self.segs.pop_front() {
+                fs::remove_file(self.seg_path(seg))?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// The next record of the open reader; None at the end of the segment.
+    fn read_record(&mut self, seg: u64) -> io::Result<Option<(Frame, Meta)>> {
+        let bad = |msg: &str| io::Error::new(
+            io::ErrorKind::InvalidData, format!("overflow segment {seg}: {msg}"),
+        );
+        let (_, r) = self.reader.as_mut().expect("reader open");
+        let mut len = [0u8; 4];
+        match r.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut rec = vec![0u8; u32::from_be_bytes(len) as usize];
+        r.read_exact(&mut rec)?;
+        if let Some(key) = &self.key {
+            rec = at_rest::open(key, OVERFLOW_AAD, &rec)?;
+        }
+        get_frame(&mut &rec[..])?.map(Some).ok_or_else(|| bad("empty record"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(i: u32) -> (Frame, Meta) {
+        (Frame::Msg(i.to_be_bytes().to_vec()), Meta { priority: Some((i % 3) as u8), ..Meta::default() })
+    }
+
+    #[test]
+    fn frames_come_back_in_order_while_appends_continue() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut ov, stale) = Overflow::open(dir.path(), None).unwrap();
+        assert_eq!(stale, 0);
+        let chunk = Frame::Chunk { id: 7, idx: 1, count: 2, payload: vec![0xee; 100] };
+        ov.push(&chunk, &Meta::default()).unwrap();
+        for i in 0..10 {
+            let (f, m) = msg(i);
+            ov.push(&f, &m).unwrap();
+        }
+        ov.push(&Frame::Eos(b"g".to_vec()), &Meta::default()).unwrap();
+        assert_eq!(ov.len(), 12);
+
+        assert_eq!(ov.take(1).unwrap(), [(chunk, Meta::default())]);
+        // Interleave reads and writes; the reader follows the writer.
+        let got = ov.take(4).unwrap();
+        assert_eq!(got, (0..4).map(msg).collect::<Vec<_>>());
+        let (f, m) = msg(10);
+        ov.push(&f, &m).unwrap();
+        let mut got = ov.take(100).unwrap();
+        assert_eq!(got.remove(6), (Frame::Eos(b"g".to_vec()), Meta::default()));
+        assert_eq!(got, (4..11).map(msg).collect::<Vec<_>>());
+        assert!(ov.is_empty());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0, "used-up segments are deleted");
+
+        // Appends after a full drain start a fresh segment.
+        ov.push(&f, &m).unwrap();
+        assert_eq!(ov.take(5).unwrap(), [msg(10)]);
+    }
+
+    #[test]
+    fn stale_segments_are_discarded_and_sealed_ones_hide_payloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Key::from_bytes([3; 32]);
+        let (mut ov, _) = Overflow::open(dir.path(), Some(key.clone())).unwrap();
+        ov.push(&Frame::Msg(b"plaintext-marker".to_vec()), &Meta::default()).unwrap();
+        ov.push(&Frame::Msg(b"second".to_vec()), &Meta::default()).unwrap();
+        ov.writer.as_mut().unwrap().1.flush().unwrap();
+        let disk = fs::read(ov.seg_path(1)).unwrap();
+        assert!(!disk.windows(16).any(|w| w == b"plaintext-marker"));
+        assert_eq!(ov.take(1).unwrap()[0].0, Frame::Msg(b"plaintext-marker".to_vec()));
+        drop(ov);
+
+        let (ov, stale) = Overflow::open(dir.path(), Some(key)).unwrap();
+        assert_eq!((stale, ov.len()), (1, 0));
+    }
+}
@@ -0,0 +1,217 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Recycled payload buffers for the frame hot path. Reading a frame needs a
+//! buffer of its length, and a busy session reads thousands a second whose
+//! buffers are dropped as soon as the frame is delivered; handing those
+//! allocations back here lets the next frame's read reuse them instead of
+//! going to the allocator (and, for large frames, faulting in fresh pages).
+//!
+//! `get_frame` and friends take their payload buffers from the process-wide
+//! pool; the orchestrator gives a frame's buffer back once it has been
+//! delivered and settled, and `Consumer::recv_into` swaps the caller's old
+//! buffer in for the received one. Anything else may `recycle` buffers it
+//! is done with. The pool is bounded: past `MAX_PER_CLASS` buffers of a
+//! size, or `MAX_POOLED_BYTES` in all, buffers are simply freed.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// Buffers smaller than this go back to the allocator, which serves them
+/// as quickly as the pool would (`cargo bench --bench pool`).
+pub const MIN_POOLED: usize = 16 * 1024;
+
+/// Most buffers kept of each size class: one per bit of `Class::full`.
+pub const MAX_PER_CLASS: usize = 32;
+
+/// Most bytes of capacity kept in all.
+pub const MAX_POOLED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Size classes by power of two: class `k` holds buffers with a capacity
+/// of at least `2^k` bytes (and less than `2^(k+1)`).
+const CLASSES: usize = usize::BITS as usize;
+
+/// A bounded pool of `Vec<u8>` allocations, sorted by capacity.
+///
+/// Each size class is a row of slots, each holding one buffer or none,
+/// and a bitmask of the full ones, so a take or recycle goes straight to a
+/// slot likely to serve it. Slots are only ever `try_lock`ed: one that is
+/// busy, or turns out to have changed hands, is passed over for the next,
+/// so no caller waits on another, and readers and the threads giving
+/// their buffers back don't contend on one lock.
+#[derive(Debug)]
+pub struct Pool {
+    classes: [Class; CLASSES],
+    /// Capacity held in all, reserved before a buffer goes into a slot.
+    bytes:   AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Class {
+    slots: [Mutex<Option<Vec<u8>>>; MAX_PER_CLASS],
+    /// Bit `i` is set while slot `i` holds a buffer; changed only by
+    /// whoever holds the slot.
+    full:  AtomicU32,
+}
+
+impl Class {
+    const fn new() -> Self {
+        Self { slots: [const { Mutex::new(None) }; MAX_PER_CLASS], full: AtomicU32::new(0) }
+    }
+}
+
+/// The indexes of the set bits of `mask`, lowest first.
+fn bits(mut mask: u32) -> impl Iterator<Item = usize> {
+    std::iter::from_fn(move || {
+        let i = mask.trailing_zeros();
+        mask &= mask.wrapping_sub(1);
+        (i < u32::BITS).then_some(i as usize)
+    })
+}
+
+/// The class whose buffers all hold at least `len` bytes.
+fn class_for(len: usize) -> usize {
+    len.checked_next_power_of_two().map_or(CLASSES, |n| n.trailing_zeros() as usize)
+}
+
+/// The class a buffer of capacity `cap` (> 0) belongs in.
+fn class_of(cap: usize) -> usize {
+    (usize::BITS - 1 - cap.leading_zeros()) as usize
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pool {
+    pub const fn new() -> Self {
+        Self { classes: [const { Class::new() }; CLASSES], bytes: AtomicUsize::new(0) }
+    }
+
+    /// A buffer of `len` bytes, reusing a pooled allocation when one is big
+    /// enough. A reused buffer holds whatever was last written to it, for
+    /// the caller to overwrite — `get_frame` reads the payload over it —
+    /// so taking one costs no zeroing. Fresh allocations are zeroed and
+    /// exactly `len`, so a buffer that sits in a queue for a while wastes
+    /// nothing.
+    pub fn take(&self, len: usize) -> Vec<u8> {
+        if len >= MIN_POOLED
+            && let Some(class) = self.classes.get(class_for(len))
+        {
+            for i in bits(class.full.load(Ordering::Acquire)) {
+                let Ok(mut slot) = class.slots[i].try_lock() else { continue };
+                if let Some(mut buf) = slot.take() {
+                    class.full.fetch_and(!(1 << i), Ordering::Release);
+                    drop(slot);
+                    self.bytes.fetch_sub(buf.capacity(), Ordering::Relaxed);
+                    // Pooled buffers are full length (see `recycle`), and
+                    // at least `len`: this only shortens.
+                    buf.resize(len, 0);
+                    return buf;
+                }
+            }
+        }
+        vec![0u8; len]
+    }
+
+    /// Give `buf`'s allocation back for a later `take`; its contents are
+    /// left as they are.
+    pub fn recycle(&self, mut buf: Vec<u8>) {
+        let cap = buf.capacity();
+        if cap < MIN_POOLED {
+            return;
+        }
+        let class = &self.classes[class_of(cap)];
+        let empty = !class.full.load(Ordering::Acquire);
+        if empty == 0 {
+            return;
+        }
+        let reserved = self.bytes.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+            (b + cap <= MAX_POOLED_BYTES).then_some(b + cap)
+        });
+        if reserved.is_err() {
+            return;
+        }
+        // Filling the spare capacity here, once per allocation, is what
+        // lets every later `take` of it skip the zeroing.
+        buf.resize(cap, 0);
+        for i in bits(empty) {
+            let Ok(mut slot) = class.slots[i].try_lock() else { continue };
+            if slot.is_none() {
+                *slot = Some(buf);
+                class.full.fetch_or(1 << i, Ordering::Release);
+                return;
+            }
+        }
+        self.bytes.fetch_sub(cap, Ordering::Relaxed);
+    }
+
+    /// How many buffers are pooled, and their capacity in bytes.
+    pub fn pooled(&self) -> (usize, usize) {
+        let held = self.classes.iter().map(|c| c.full.load(Ordering::Relaxed).count_ones() as usize).sum();
+        (held, self.bytes.load(Ordering::Relaxed))
+    }
+}
+
+/// The process-wide pool behind `take` and `recycle`.
+pub static POOL: Pool = Pool::new();
+
+/// `Pool::take` on the process-wide pool.
+pub fn take(len: usize) -> Vec<u8> {
+    POOL.take(len)
+}
+
+/// `Pool::recycle` on the process-wide pool.
+pub fn recycle(buf: Vec<u8>) {
+    POOL.recycle(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recycled_buffers_are_reused_for_lengths_they_can_hold() {
+        let pool = Pool::new();
+        let mut buf = pool.take(40_000);
+        assert_eq!(buf, vec![0u8; 40_000]);
+        buf.fill(7);
+        let ptr = buf.as_ptr();
+        pool.recycle(buf);
+        assert_eq!(pool.pooled(), (1, 40_000));
+
+        // 40000 bytes sit in the 32 KiB class, which only promises 32768:
+        // a 32769-byte take can't rely on it, a 32768-byte one can.
+        let other = pool.take(32_769);
+        assert_ne!(other.as_ptr(), ptr);
+        let again = pool.take(32_768);
+        assert_eq!((again.as_ptr(), again.len()), (ptr, 32_768));
+        assert!(again.iter().all(|&b| b == 7), "reused buffers aren't zeroed again");
+        assert_eq!(pool.pooled(), (0, 0));
+
+        // A buffer recycled short of its capacity is filled out once, so
+        // any take it can serve is full length.
+        let mut short = Vec::with_capacity(65_536);
+        short.extend_from_slice(&[1; 100]);
+        pool.recycle(short);
+        let long = pool.take(64_000);
+        assert_eq!(long.len(), 64_000);
+        assert!(long[..100].iter().all(|&b| b == 1) && long[100..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn the_pool_stays_bounded() {
+        let pool = Pool::new();
+        pool.recycle(vec![0u8; MIN_POOLED - 1]);
+        assert_eq!(pool.pooled(), (0, 0));
+        for _ in 0..MAX_PER_CLASS + 5 {
+            pool.recycle(Vec::with_capacity(MIN_POOLED));
+        }
+        assert_eq!(pool.pooled(), (MAX_PER_CLASS, MAX_PER_CLASS * MIN_POOLED));
+        for _ in 0..8 {
+            pool.recycle(Vec::with_capacity(16 * 1024 * 1024));
+        }
+        let (_, bytes) = pool.pooled();
+        assert!(bytes <= MAX_POOLED_BYTES, "{bytes} bytes pooled");
+    }
+}
@@ -0,0 +1,174 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Pre-shared key authentication on the control handshake, for pipelines
+//! that only need to keep out whoever else can reach the control port.
+//! Every client and the orchestrator hold the same secret key. Only HMACs
+//! of random nonces cross the wire, never the key itself, and the proof
+//! goes both ways: the client proves it holds the key, then the
+//! orchestrator does.
+//!
+//! A client with a key sets `ROLE_FLAG_OPTS` and sends a random nonce as
+//! `OPT_AUTH_KEY`. The orchestrator answers with `[u16 BE 0]` (as for
+//! SCRAM) and a nonce of its own, and the exchange is:
+//!
+//!   client: HMAC-SHA256(key, "qpipe-client" | role | client nonce | server nonce)
+//!   server: HMAC-SHA256(key, "qpipe-server" | role | client nonce | server nonce)
+//!
+//! An orchestrator that isn't convinced closes the connection instead of
+//! answering. Users or Kerberos, if configured too, authenticate next; then
+//! the role's usual reply follows.
+
+use std::env;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use rand::{rngs::SysRng, TryRng};
+
+use crate::digest::{hmac_sha256, DIGEST_LEN};
+
+/// Bytes in each side's nonce.
+pub const NONCE_LEN: usize = 32;
+
+pub type Nonce = [u8; NONCE_LEN];
+pub type Proof = [u8; DIGEST_LEN];
+
+/// A shared key. `Debug` doesn't show it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key(Vec<u8>);
+
+impl Key {
+    pub fn new(key: impl Into<Vec<u8>>) -> io::Result<Self> {
+        let key = key.into();
+        if key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty auth key"));
+        }
+        Ok(Self(key))
+    }
+
+    /// Read a key file: its bytes, less one trailing line break, so that
+    /// `echo "$SECRET" > key` and `head -c 32 /dev/urandom > key` both work.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut key = std::fs::read(path).map_err(|e| io::Error::new(
+            e.kind(), format!("auth key {}: {e}", path.display()),
+        ))?;
+        if key.ends_with(b"\n") {
+            key.pop();
+            if key.ends_with(b"\r") {
+                key.pop();
+            }
+        }
+        Self::new(key).map_err(|e| io::Error::new(
+            e.kind(), format!("auth key {}: {e}", path.display()),
+        ))
+    }
+
+    /// The key in the file `QPIPE_AUTH_KEY_FILE` names, if it is set —
+    /// what clients fall back to when no key is given explicitly.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        match env::var_os("QPIPE_AUTH_KEY_FILE").filter(|p| !p.is_empty()) {
+            Some(path) => Self::load(Path::new(&path)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn proof(&self, side: &[u8], role: u8, client: &[u8], server: &[u8]) -> Proof {
+        let mut msg = Vec::with_capacity(side.len() + 1 + 2 * NONCE_LEN);
+        msg.extend_from_slice(side);
+        msg.push(role);
+        msg.extend_from_slice(client);
+        msg.extend_from_slice(server);
+        hmac_sha256(&self.0, &msg)
+    }
+
+    /// What the client sends for a session of `role`.
+    pub fn client_proof(&self, role: u8, client: &[u8], server: &[u8]) -> Proof {
+        self.proof(b"qpipe-client", role, client, server)
+    }
+
+    /// What the orchestrator answers once convinced.
+    pub fn server_proof(&self, role: u8, client: &[u8], server: &[u8]) -> Proof {
+        self.proof(b"qpipe-server", role, client, server)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+pub fn nonce() -> io::Result<Nonce> {
+    let mut b = [0u8; NONCE_LEN];
+    SysRng.try_fill_bytes(&mut b).map_err(io::Error::other)?;
+    Ok(b)
+}
+
+/// Constant-time comparison, so a proof check leaks nothing through timing.
+pub fn same(a: &Proof, b: &Proof) -> bool {
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Run the client side of the exchange on a control connection, after the
+/// role byte and an option block carrying `client` as `OPT_AUTH_KEY`.
+pub fn authenticate<S: Read + Write>(s: &mut S, key: &Key, role: u8, client: &Nonce) -> io::Result<()> {
+    let eof_as = |e: io::Error, kind, msg: &str| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(kind, msg.to_string()),
+        _ => e,
+    };
+    let mut marker = [0u8; 2];
+    s.read_exact(&mut marker).map_err(|e| eof_as(
+        e, io::ErrorKind::Unsupported, "orchestrator does not support authentication",
+    ))?;
+    if marker != [0, 0] {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported, "orchestrator does not support authentication",
+        ));
+    }
+    let mut server = [0u8; NONCE_LEN];
+    s.read_exact(&mut server).map_err(|e| eof_as(
+        e, io::ErrorKind::PermissionDenied, "orchestrator does not take an auth key",
+    ))?;
+    s.write_all(&key.client_proof(role, client, &server))?;
+    s.flush()?;
+    let mut proof = [0u8; DIGEST_LEN];
+    s.read_exact(&mut proof).map_err(|e| eof_as(
+        e, io::ErrorKind::PermissionDenied, "orchestrator refused the auth key",
+    ))?;
+    if !same(&proof, &key.server_proof(role, client, &server)) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied, "orchestrator does not hold the auth key",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_bind_key_side_role_and_nonces() {
+        let key = Key::new("s3cret").unwrap();
+        let (c, s) = (nonce().unwrap(), nonce().unwrap());
+        let proof = key.client_proof(1, &c, &s);
+        assert!(same(&proof, &key.client_proof(1, &c, &s)));
+        assert!(!same(&proof, &Key::new("other").unwrap().client_proof(1, &c, &s)));
+        assert!(!same(&proof, &key.client_proof(2, &c, &s)), "role");
+        assert!(!same(&proof, &key.client_proof(1, &s, &c)), "nonces");
+        assert!(!same(&proof, &key.server_proof(1, &c, &s)), "a client proof is no server proof");
+        assert!(Key::new("").is_err());
+        assert_eq!(format!("{key:?}"), "Key(..)");
+    }
+
+    #[test]
+    fn key_files_lose_one_line_break() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, "s3cret\r\n").unwrap();
+        assert_eq!(Key::load(&path).unwrap(), Key::new("s3cret").unwrap());
+        std::fs::write(&path, "s3cret\n\n").unwrap();
+        assert_eq!(Key::load(&path).unwrap(), Key::new("s3cret\n").unwrap());
+        std::fs::write(&path, "\n").unwrap();
+        assert_eq!(Key::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,434 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Readiness-based I/O for the orchestrator, on Linux's epoll.
+//!
+//! One thread waits on every listening socket and on every connection with
+//! nothing to read yet: connections that haven't sent their handshake,
+//! sessions' data ports before their clients arrive, and producers between
+//! frames. Whatever becomes readable goes to a pool of workers, which serve
+//! it with the same blocking code as ever and `park` it again once it would
+//! block. Threads thus go to connections with work to do, not to every one
+//! open: the pool starts a worker when all are busy, and a worker left
+//! without work for `WORKER_IDLE` ends.
+//!
+//! Consumers waiting for their queue park here too, with a `Waker` the
+//! queue calls once there may be work for them (`park_waker`), and a
+//! descriptor only if nothing else watches their connection.
+//!
+//! A wait may have a deadline, for idle and token timeouts. Once the
+//! reactor has stopped, `park` runs its task at once with `Wake::Stopped`,
+//! and the task carries on blocking, as on a thread of its own.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ffi::c_int;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+const EPOLL_CLOEXEC: c_int = 0o2000000;
+const EPOLL_CTL_ADD: c_int = 1;
+const EPOLL_CTL_DEL: c_int = 2;
+const EPOLLIN: u32 = 0x001;
+const EPOLLRDHUP: u32 = 0x2000;
+const EPOLLONESHOT: u32 = 1 << 30;
+
+/// `struct epoll_event`, which the kernel packs on x86-64 only.
+#[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+#[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+#[derive(Clone, Copy)]
+struct EpollEvent {
+    events: u32,
+    data:   u64,
+}
+
+unsafe extern "C" {
+    fn epoll_create1(flags: c_int) -> c_int;
+    fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+    fn epoll_wait(epfd: c_int, events: *mut EpollEvent, max: c_int, timeout: c_int) -> c_int;
+}
+
+/// Longest the reactor waits before looking at its stop flag again.
+const TICK: Duration = Duration::from_millis(100);
+
+/// How long a worker waits for work before its thread ends.
+const WORKER_IDLE: Duration = Duration::from_secs(10);
+
+/// Events taken per wait.
+const EVENTS: usize = 256;
+
+/// Marks the keys of listeners, which index `run`'s list; parked tasks
+/// have theirs counted up from 0.
+const LISTENER: u64 = 1 << 63;
+
+/// Why a parked task runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Wake {
+    /// Its descriptor is readable, or the peer has hung up.
+    Ready,
+    /// Its deadline passed first.
+    TimedOut,
+    /// The reactor has stopped; the task has to block for what it waits on.
+    Stopped,
+    /// Its `Waker` was called.
+    Woken,
+}
+
+type Task = Box<dyn FnOnce(Wake) + Send>;
+
+/// A listening socket, and what accepts its connections. Accepting runs on
+/// the reactor's thread, so it must not block.
+pub(crate) type Accept = (RawFd, Box<dyn FnMut() + Send>);
+
+/// Runs a task parked with `Reactor::park_waker`, if it still is.
+pub(crate) struct Waker {
+    reactor: Weak<Reactor>,
+    key:     u64,
+}
+
+impl Waker {
+    pub(crate) fn wake(&self) {
+        if let Some(reactor) = self.reactor.upgrade() {
+            reactor.wake(self.key, Wake::Woken);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Parked {
+    tasks:     HashMap<u64, (Option<RawFd>, Option<Instant>, Task)>,
+    deadlines: BTreeSet<(Instant, u64)>,
+    next:      u64,
+    stopped:   bool,
+}
+
+pub(crate) struct Reactor {
+    epoll:   OwnedFd,
+    parked:  Mutex<Parked>,
+    workers: Arc<Workers>,
+}
+
+impl Reactor {
+    /// A reactor whose workers have `stack`-byte stacks.
+    pub(crate) fn new(stack: usize) -> io::Result<Self> {
+        // SAFETY: no pointers; a valid descriptor is ours to own.
+        let fd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            // SAFETY: `fd` was just opened, and nothing else owns it.
+            epoll:   unsafe { OwnedFd::from_raw_fd(fd) },
+            parked:  Mutex::default(),
+            workers: Arc::new(Workers { jobs: Mutex::default(), more: Condvar::new(), stack }),
+        })
+    }
+
+    /// Run `task` on a worker once `fd` is readable, or has hung up, or at
+    /// `deadline` if that comes first. `task` owns whatever keeps `fd`
+    /// open; it is unregistered before `task` runs.
+    pub(crate) fn park(&self, fd: RawFd, deadline: Option<Instant>, task: impl FnOnce(Wake) + Send + 'static) {
+        self.register(Some(fd), deadline, Box::new(task));
+    }
+
+    /// `park`, but the task also runs once the returned `Waker` is called,
+    /// and needn't wait on a descriptor. None if the task has run already,
+    /// the reactor having stopped.
+    pub(crate) fn park_waker(
+                self:     &Arc<Self>,
+                fd:       Option<RawFd>,
+                deadline: Option<Instant>,
+                task:     impl FnOnce(Wake) + Send + 'static,
+            ) -> Option<Waker> {
+        let key = self.register(fd, deadline, Box::new(task))?;
+        Some(Waker { reactor: Arc::downgrade(self), key })
+    }
+
+    fn register(&self, fd: Option<RawFd>, deadline: Option<Instant>, task: Task) -> Option<u64> {
+        let mut parked = self.parked.lock().unwrap();
+        if parked.stopped {
+            drop(parked);
+            task(Wake::Stopped);
+            return None;
+        }
+        let key = parked.next;
+        parked.next += 1;
+        // Registered under the lock, so an event can't find the task
+        // missing.
+        if let Some(fd) = fd
+            && let Err(e) = self.ctl(EPOLL_CTL_ADD, fd, EPOLLIN | EPOLLRDHUP | EPOLLONESHOT, key)
+        {
+            drop(parked);
+            warn!("cannot wait on a connection ({}); serving it blocking", e);
+            task(Wake::Stopped);
+            return None;
+        }
+        parked.tasks.insert(key, (fd, deadline, task));
+        if let Some(at) = deadline {
+            parked.deadlines.insert((at, key));
+        }
+        Some(key)
+    }
+
+    /// Accept on `listeners` and wake parked tasks until `exit` is set;
+    /// then close the listeners, call `closed`, and carry on until no task
+    /// is parked. Tasks parked after that run at once (`Wake::Stopped`).
+    pub(crate) fn run(&self, mut listeners: Vec<Accept>, exit: &AtomicBool, closed: impl FnOnce()) {
+        for (i, (fd, _)) in listeners.iter().enumerate() {
+            if let Err(e) = self.ctl(EPOLL_CTL_ADD, *fd, EPOLLIN, LISTENER | i as u64) {
+                warn!("cannot wait on a listener: {}", e);
+            }
+        }
+        let mut closed = Some(closed);
+        let mut events = [EpollEvent { events: 0, data: 0 }; EVENTS];
+        loop {
+            if exit.load(Ordering::SeqCst) {
+                if let Some(closed) = closed.take() {
+                    for (fd, _) in &listeners {
+                        self.ctl(EPOLL_CTL_DEL, *fd, 0, 0).ok();
+                    }
+                    listeners.clear();
+                    closed();
+                }
+                let mut parked = self.parked.lock().unwrap();
+                if parked.tasks.is_empty() {
+                    parked.stopped = true;
+                    return;
+                }
+            }
+            let wait = self.parked.lock().unwrap().deadlines.first()
+                .map_or(TICK, |(at, _)| at.saturating_duration_since(Instant::now()).min(TICK));
+            // Rounded up, so a deadline isn't polled for until it has passed.
+            let ms = wait.as_micros().div_ceil(1000) as c_int;
+            // SAFETY: `events` holds EVENTS entries for the kernel to fill.
+            let n = unsafe { epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), EVENTS as c_int, ms) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    warn!("epoll_wait: {}", e);
+                    thread::sleep(TICK);
+                }
+                continue;
+            }
+            for event in &events[..n as usize] {
+                let key = event.data;
+                if key & LISTENER == 0 {
+                    self.wake(key, Wake::Ready);
+                } else if let Some((_, accept)) = listeners.get_mut((key & !LISTENER) as usize) {
+                    accept();
+                }
+            }
+            let now = Instant::now();
+            loop {
+                let due = self.parked.lock().unwrap().deadlines.first().copied().filter(|(at, _)| *at <= now);
+                match due {
+                    Some((_, key)) => self.wake(key, Wake::TimedOut),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Hand the task parked under `key`, if it still is, to a worker.
+    fn wake(&self, key: u64, why: Wake) {
+        let mut parked = self.parked.lock().unwrap();
+        let Some((fd, deadline, task)) = parked.tasks.remove(&key) else {
+            return;
+        };
+        if let Some(at) = deadline {
+            parked.deadlines.remove(&(at, key));
+        }
+        drop(parked);
+        // Before the task gets to close `fd`.
+        if let Some(fd) = fd {
+            self.ctl(EPOLL_CTL_DEL, fd, 0, 0).ok();
+        }
+        self.workers.spawn(Box::new(move || task(why)));
+    }
+
+    fn ctl(&self, op: c_int, fd: RawFd, events: u32, key: u64) -> io::Result<()> {
+        let mut event = EpollEvent { events, data: key };
+        // SAFETY: `event` outlives the call; the kernel only reads it.
+        if unsafe { epoll_ctl(self.epoll.as_raw_fd(), op, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Threads for the reactor's tasks, started as they are needed.
+struct Workers {
+    jobs:  Mutex<Jobs>,
+    more:  Condvar,
+    stack: usize,
+}
+
+#[derive(Default)]
+struct Jobs {
+    queue: VecDeque<Box<dyn FnOnce() + Send>>,
+    /// Workers waiting for a job.
+    idle:  usize,
+}
+
+impl Workers {
+    fn spawn(self: &Arc<Self>, job: Box<dyn FnOnce() + Send>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.queue.push_back(job);
+        if jobs.idle >= jobs.queue.len() {
+            self.more.notify_one();
+            return;
+        }
+        let workers = self.clone();
+        let started = thread::Builder::new()
+            .name("qpipe-session".into())
+            .stack_size(self.stack)
+            .spawn(move || workers.work());
+        // As with a session thread that can't be started, the connection
+        // the job serves is dropped with it.
+        if let Err(e) = started {
+            jobs.queue.pop_back();
+            warn!("dropping a connection: cannot start a worker thread: {}", e);
+        }
+    }
+
+    fn work(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        loop {
+            if let Some(job) = jobs.queue.pop_front() {
+                drop(jobs);
+                job();
+                jobs = self.jobs.lock().unwrap();
+                continue;
+            }
+            jobs.idle += 1;
+            let (next, waited) = self.more.wait_timeout(jobs, WORKER_IDLE).unwrap();
+            jobs = next;
+            jobs.idle -= 1;
+            if waited.timed_out() && jobs.queue.is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+
+    /// A running reactor, and the flag that stops it.
+    fn start(listeners: Vec<Accept>) -> (Arc<Reactor>, Arc<AtomicBool>, mpsc::Receiver<()>) {
+        let reactor = Arc::new(Reactor::new(64 << 10).unwrap());
+        let exit = Arc::new(AtomicBool::new(false));
+        let (closed, on_closed) = mpsc::channel();
+        let (r, e) = (reactor.clone(), exit.clone());
+        thread::spawn(move || r.run(listeners, &e, move || closed.send(()).unwrap()));
+        (reactor, exit, on_closed)
+    }
+
+    #[test]
+    fn parked_tasks_run_once_readable_or_at_their_deadline() {
+        let (reactor, _exit, _) = start(Vec::new());
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let (tx, woke) = mpsc::channel();
+        let fd = a.as_raw_fd();
+        let t = tx.clone();
+        reactor.park(fd, None, move |why| t.send((why, a)).unwrap());
+        assert!(woke.recv_timeout(Duration::from_millis(200)).is_err(), "woken with nothing to read");
+        b.write_all(b"x").unwrap();
+        let (why, a) = woke.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(why, Wake::Ready);
+
+        // Readable again, but registered afresh: parked anew, it runs again.
+        let fd = a.as_raw_fd();
+        let t = tx.clone();
+        reactor.park(fd, None, move |why| t.send((why, a)).unwrap());
+        let (why, a) = woke.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(why, Wake::Ready);
+
+        let (c, _d) = UnixStream::pair().unwrap();
+        let fd = c.as_raw_fd();
+        let started = Instant::now();
+        reactor.park(fd, Some(started + Duration::from_millis(50)), move |why| tx.send((why, c)).unwrap());
+        let (why, _) = woke.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(why, Wake::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        drop(a);
+    }
+
+    #[test]
+    fn wakers_run_their_task_once() {
+        let (reactor, _exit, _) = start(Vec::new());
+        let (tx, woke) = mpsc::channel();
+        let t = tx.clone();
+        let waker = reactor.park_waker(None, None, move |why| t.send(why).unwrap()).unwrap();
+        assert!(woke.recv_timeout(Duration::from_millis(100)).is_err(), "ran before it was woken");
+        waker.wake();
+        assert_eq!(woke.recv_timeout(Duration::from_secs(5)).unwrap(), Wake::Woken);
+        waker.wake(); // its task is gone
+        assert!(woke.recv_timeout(Duration::from_millis(100)).is_err(), "ran twice");
+
+        // Past its deadline, its waker comes too late.
+        let t = tx.clone();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let waker = reactor.park_waker(None, Some(deadline), move |why| t.send(why).unwrap()).unwrap();
+        assert_eq!(woke.recv_timeout(Duration::from_secs(5)).unwrap(), Wake::TimedOut);
+        waker.wake();
+        assert!(woke.recv_timeout(Duration::from_millis(100)).is_err(), "ran twice");
+
+        // Waiting on its connection as well, it runs once for either.
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let fd = a.as_raw_fd();
+        let waker = reactor.park_waker(Some(fd), None, move |why| tx.send(why).unwrap()).unwrap();
+        waker.wake();
+        assert_eq!(woke.recv_timeout(Duration::from_secs(5)).unwrap(), Wake::Woken);
+        b.write_all(b"x").unwrap();
+        assert!(woke.recv_timeout(Duration::from_millis(100)).is_err(), "ran twice");
+        drop(a);
+    }
+
+    #[test]
+    fn listeners_accept_until_the_reactor_stops() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, accepted) = mpsc::channel();
+        let fd = listener.as_raw_fd();
+        let accept = move || {
+            while let Ok((s, _)) = listener.accept() {
+                tx.send(s).unwrap();
+            }
+        };
+        let (reactor, exit, closed) = start(vec![(fd, Box::new(accept))]);
+        let _c1 = TcpStream::connect(addr).unwrap();
+        let _c2 = TcpStream::connect(addr).unwrap();
+        for _ in 0..2 {
+            accepted.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        // A task still parked keeps the reactor going, but not the listener.
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let (woke, on_wake) = mpsc::channel();
+        let w = woke.clone();
+        reactor.park(a.as_raw_fd(), None, move |why| w.send((why, a)).unwrap());
+        exit.store(true, Ordering::SeqCst);
+        closed.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(TcpStream::connect(addr).is_err(), "listener still open");
+        b.write_all(b"x").unwrap();
+        assert_eq!(on_wake.recv_timeout(Duration::from_secs(5)).unwrap().0, Wake::Ready);
+
+        // With nothing parked, it stops within a tick, and tasks then run
+        // where they are parked.
+        thread::sleep(3 * TICK);
+        let (c, _d) = UnixStream::pair().unwrap();
+        let fd = c.as_raw_fd();
+        reactor.park(fd, None, move |why| woke.send((why, c)).unwrap());
+        assert_eq!(on_wake.try_recv().unwrap().0, Wake::Stopped);
+    }
+}
@@ -0,0 +1,338 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Queue replication between a primary orchestrator and a standby
+//! (`OrchestratorOptions::standby_of`).
+//!
+//! Replication ships write-ahead logs: the primary's `Feed` taps every
+//! queue's `Wal`, and a replication session (`ROLE_REPLICATE`) streams
+//! what the logs hold, then every push (a frame accepted) and done (a
+//! frame settled) as it is appended:
+//!
+//!   record:  `[u8 'R'][u16 BE len][queue directory][write-ahead log record]`
+//!   synced:  `[u8 'Y']` — everything the logs held has been sent
+//!   ping:    `[u8 'P']` — sent every PING_EVERY the stream is quiet
+//!
+//! The standby (`follow`) appends the records to logs of its own, laid out
+//! like the primary's, under a staging directory. When the session breaks
+//! after the stream was synced, the staging logs replace the last copy;
+//! a primary lost mid-sync leaves that copy in place. Once the primary has
+//! been silent for the failover time, the standby stops following, and
+//! the orchestrator starts on the copy as if it had journaled it itself.
+//!
+//! The primary doesn't wait for the standby: what it accepted in the last
+//! moments before it failed may not have reached the copy, and deliveries
+//! it settled may not have either — those are delivered again.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::transport::{Addr, Stream};
+use crate::wal::Wal;
+use crate::{open_control, psk, ACK_REPLICATE, ROLE_REPLICATE};
+
+const OP_RECORD: u8 = b'R';
+const OP_SYNCED: u8 = b'Y';
+const OP_PING: u8   = b'P';
+
+/// How often a quiet stream carries a ping, so the standby can tell a
+/// quiet primary from a lost one.
+const PING_EVERY: Duration = Duration::from_secs(1);
+
+/// Records a replication session may fall behind by before the primary
+/// drops it; the standby reconnects and syncs again.
+const BACKLOG: usize = 1 << 16;
+
+/// Where the standby writes a session's stream until the session ends.
+const STAGING: &str = ".staging";
+
+/// Pause between the standby's attempts to reach its primary.
+const RETRY_EVERY: Duration = Duration::from_millis(200);
+
+/// A record for one queue's log, by its directory under the log root.
+type Shipped = (Arc<str>, Vec<u8>);
+
+/// A queue's log, by its directory; gone with the queue.
+type Attached = (Arc<str>, Weak<Mutex<Wal>>);
+
+/// The primary's side: the logs of every journaled queue, and the
+/// replication sessions following them.
+#[derive(Default)]
+pub(crate) struct Feed {
+    wals:      Mutex<Vec<Attached>>,
+    followers: Mutex<Vec<SyncSender<Shipped>>>,
+}
+
+impl Feed {
+    /// Ship what `wal`, the log in directory `dir`, appends from now on.
+    pub(crate) fn attach(self: &Arc<Self>, dir: &str, wal: &Arc<Mutex<Wal>>) {
+        let dir: Arc<str> = dir.into();
+        let feed = Arc::downgrade(self);
+        let mut w = wal.lock().unwrap();
+        let name = dir.clone();
+        w.tap(move |rec| {
+            if let Some(feed) = feed.upgrade() {
+                feed.ship(&name, rec);
+            }
+        });
+        self.wals.lock().unwrap().push((dir, Arc::downgrade(wal)));
+    }
+
+    /// Called under the log's lock, so each session sees a log's records
+    /// in the order they were appended.
+    fn ship(&self, dir: &Arc<str>, rec: &[u8]) {
+        self.followers.lock().unwrap().retain(|tx| match tx.try_send((dir.clone(), rec.to_vec())) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("a standby fell {} records behind; it has to sync again", BACKLOG);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    /// A new session: what the logs hold, then the records appended since.
+    /// A record may show up in both — `Wal::apply_from` takes it once.
+    fn follow(&self) -> io::Result<(Vec<Shipped>, Receiver<Shipped>)> {
+        let (tx, rx) = mpsc::sync_channel(BACKLOG);
+        self.followers.lock().unwrap().push(tx);
+        let wals = self.wals.lock().unwrap().clone();
+        let mut held = Vec::new();
+        for (dir, wal) in wals {
+            let Some(wal) = wal.upgrade() else { continue };
+            for rec in wal.lock().unwrap().live_records()? {
+                held.push((dir.clone(), rec));
+            }
+        }
+        Ok((held, rx))
+    }
+}
+
+/// Serve a replication session on `ctrl` until the standby goes away or
+/// falls too far behind.
+pub(crate) fn serve(ctrl: &mut Stream, feed: &Feed) -> io::Result<()> {
+    let peer = ctrl.peer();
+    let (held, rx) = feed.follow()?;
+    info!("standby {} is following; syncing {} frame(s)", peer, held.len());
+    ctrl.write_all(&[ACK_REPLICATE])?;
+    if let Err(e) = stream(&mut BufWriter::new(ctrl), &held, &rx) {
+        info!("standby {} stopped following: {}", peer, e);
+    }
+    Ok(())
+}
+
+/// Write `held`, then what `rx` gets, until that fails.
+fn stream<W: Write>(out: &mut W, held: &[Shipped], rx: &Receiver<Shipped>) -> io::Result<()> {
+    for (dir, rec) in held {
+        put_record(out, dir, rec)?;
+    }
+    out.write_all(&[OP_SYNCED])?;
+    out.flush()?;
+    loop {
+        match rx.recv_timeout(PING_EVERY) {
+            Ok((dir, rec)) => {
+                put_record(out, &dir, &rec)?;
+                for (dir, rec) in rx.try_iter() {
+                    put_record(out, &dir, &rec)?;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => out.write_all(&[OP_PING])?,
+            Err(RecvTimeoutError::Disconnected) => return Err(io::Error::other("fell too far behind")),
+        }
+        out.flush()?;
+    }
+}
+
+fn put_record<W: Write>(w: &mut W, dir: &str, rec: &[u8]) -> io::Result<()> {
+    w.write_all(&[OP_RECORD])?;
+    w.write_all(&(dir.len() as u16).to_be_bytes())?;
+    w.write_all(dir.as_bytes())?;
+    w.write_all(rec)
+}
+
+/// The standby's side: copy the write-ahead logs of the orchestrator at
+/// `primary` into `root`, and return once it has been out of reach for
+/// `failover` — but not before a first complete copy.
+pub(crate) fn follow(primary: &str, root: &Path, failover: Duration, key: Option<&psk::Key>) -> io::Result<()> {
+    let mut synced = false;
+    let mut heard = Instant::now();
+    loop {
+        match copy(primary, root, failover, key, &mut synced, &mut heard) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Err(e),
+            Err(e) => debug!("replication from {} interrupted: {}", primary, e),
+            Ok(()) => {}
+        }
+        if synced && heard.elapsed() >= failover {
+            warn!("primary {} silent for {:?}; taking over", primary, heard.elapsed());
+            return Ok(());
+        }
+        thread::sleep(RETRY_EVERY);
+    }
+}
+
+/// One replication session, until it breaks. The stream goes to logs
+/// under the staging directory; if it got as far as synced, they are the
+/// new copy.
+fn copy(
+            primary: &str,
+            root: &Path,
+            failover: Duration,
+            key: Option<&psk::Key>,
+            synced: &mut bool,
+            heard: &mut Instant,
+        ) -> io::Result<()> {
+    let mut ctrl = Addr::resolve(primary)?.connect(Some(Duration::from_secs(5)))?;
+    ctrl.set_read_timeout(Some(failover)).ok();
+    ctrl.set_write_timeout(Some(Duration::from_secs(5))).ok();
+    open_control(&mut ctrl, ROLE_REPLICATE, &[], key, None)?;
+    let mut ack = [0u8; 1];
+    ctrl.read_exact(&mut ack)?;
+    if ack[0] != ACK_REPLICATE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected replication ack: 0x{:02x}", ack[0]),
+        ));
+    }
+    *heard = Instant::now();
+    info!("following primary {}", primary);
+
+    let staging = root.join(STAGING);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let mut wals = HashMap::new();
+    let mut complete = false;
+    let broke = apply(&mut BufReader::new(ctrl), &staging, &mut wals, &mut complete, heard);
+    // Closed before they move.
+    drop(wals);
+    if complete {
+        swap_in(root, &staging)?;
+        *synced = true;
+    }
+    broke
+}
+
+/// Apply the stream on `rd` to logs under `staging`, noting when it is
+/// `complete`, until it breaks.
+fn apply<R: Read>(
+            rd: &mut R,
+            staging: &Path,
+            wals: &mut HashMap<String, Wal>,
+            complete: &mut bool,
+            heard: &mut Instant,
+        ) -> io::Result<()> {
+    loop {
+        let mut op = [0u8; 1];
+        rd.read_exact(&mut op)?;
+        *heard = Instant::now();
+        match op[0] {
+            OP_PING => {}
+            OP_SYNCED => {
+                *complete = true;
+                info!("in sync with the primary");
+            }
+            OP_RECORD => {
+                let mut len = [0u8; 2];
+                rd.read_exact(&mut len)?;
+                let mut dir = vec![0u8; u16::from_be_bytes(len) as usize];
+                rd.read_exact(&mut dir)?;
+                let dir = String::from_utf8(dir).ok().filter(|d| is_queue_dir(d)).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "bad queue directory in replication stream")
+                })?;
+                if !wals.contains_key(&dir) {
+                    // Fresh, so there is nothing to replay (or unseal).
+                    let (wal, ..) = Wal::open(&staging.join(&dir), None)?;
+                    wals.insert(dir.clone(), wal);
+                }
+                wals.get_mut(&dir).expect("just opened").apply_from(rd)?;
+            }
+            op => return Err(io::Error::new(
+                io::ErrorKind::InvalidData, format!("unknown replication op 0x{op:02x}"),
+            )),
+        }
+    }
+}
+
+/// A directory the primary's log root may have: no path of its own.
+fn is_queue_dir(dir: &str) -> bool {
+    !dir.is_empty() && !dir.starts_with('.') && !dir.contains(['/', '\\'])
+}
+
+/// Make what `staging` holds the copy under `root`.
+fn swap_in(root: &Path, staging: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        if entry.path() != staging && entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+    for entry in fs::read_dir(staging)? {
+        let entry = entry?;
+        fs::rename(entry.path(), root.join(entry.file_name()))?;
+    }
+    fs::remove_dir(staging)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Frame, Meta};
+
+    fn msg(i: u8) -> Frame {
+        Frame::Msg(vec![i])
+    }
+
+    #[test]
+    fn a_feed_ships_what_logs_hold_then_what_they_append() {
+        let primary = tempfile::tempdir().unwrap();
+        let feed = Arc::new(Feed::default());
+        let (wal, ..) = Wal::open(&primary.path().join("default"), None).unwrap();
+        let wal = Arc::new(Mutex::new(wal));
+        feed.attach("default", &wal);
+        let first = wal.lock().unwrap().push(&msg(1), &Meta::default()).unwrap();
+        wal.lock().unwrap().push(&msg(2), &Meta::default()).unwrap();
+        wal.lock().unwrap().done(first).unwrap();
+
+        let (held, rx) = feed.follow().unwrap();
+        assert_eq!(held.len(), 1);
+        let third = wal.lock().unwrap().push(&msg(3), &Meta::default()).unwrap();
+        wal.lock().unwrap().done(third).unwrap();
+        wal.lock().unwrap().push(&msg(4), &Meta::default()).unwrap();
+
+        // Replayed into a log of its own, the copy holds what the
+        // primary's would replay.
+        let standby = tempfile::tempdir().unwrap();
+        let (mut copy, ..) = Wal::open(standby.path(), None).unwrap();
+        for (dir, rec) in held.into_iter().chain(rx.try_iter()) {
+            assert_eq!(&*dir, "default");
+            copy.apply_from(&mut &rec[..]).unwrap();
+        }
+        drop(copy);
+        let (_, frames, _) = Wal::open(standby.path(), None).unwrap();
+        let frames: Vec<Frame> = frames.into_iter().map(|(_, f, _)| f).collect();
+        assert_eq!(frames, [msg(2), msg(4)]);
+    }
+
+    #[test]
+    fn a_synced_copy_replaces_the_last_one_whole() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("q.gone")).unwrap();
+        let staging = root.path().join(STAGING);
+        fs::create_dir_all(staging.join("default")).unwrap();
+        fs::write(staging.join("default").join("000000000001.wal"), b"").unwrap();
+        swap_in(root.path(), &staging).unwrap();
+        let mut left: Vec<String> = fs::read_dir(root.path()).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["default"]);
+        assert!(["default", "q.a%2Fb"].iter().all(|d| is_queue_dir(d)));
+        assert!(["", ".", "..", ".staging", "a/b", "a\\b"].iter().all(|d| !is_queue_dir(d)));
+    }
+}
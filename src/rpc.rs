@@ -0,0 +1,270 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Request/reply on top of producers and consumers: a `Requester` sends
+//! tasks to a queue and gets each result back from whichever `Replier`
+//! took the task.
+//!
+//!   let mut rq = Requester::connect_to("orchestrator:7000", "render")?;
+//!   let result = rq.request(b"frame 17", Duration::from_secs(60))?;
+//!
+//!   let mut rp = Replier::connect_to("orchestrator:7000", "render")?;
+//!   loop {
+//!       let req = rp.recv()?;
+//!       rp.reply(&req, &render(&req.message.payload))?;
+//!   }
+//!
+//! Nothing new goes over the wire. A requester consumes from a reply queue
+//! of its own, `REPLY_QUEUE_PREFIX` plus a random suffix, and stamps each
+//! request with two headers: `HEADER_REPLY_TO`, that queue's name, and
+//! `HEADER_CORRELATION_ID`, an id unique within the requester. A replier
+//! sends the result to the named queue under the same id. The orchestrator
+//! drops a reply queue once nobody is connected to it, so replies to a
+//! requester that has gone are discarded rather than piling up.
+//!
+//! Each reply is a short producer session of its own, closed before
+//! `reply` returns; in ack mode, the request is acked only after that. A
+//! replier that dies between the two leaves the request to be redelivered,
+//! so a requester can get two replies for one id; `request` takes the first
+//! and discards the rest.
+
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::digest::to_hex;
+use crate::{
+    psk, ConnectOptions, Consumer, Delivery, Headers, Message, Producer, ProducerOptions, REPLY_QUEUE_PREFIX,
+};
+
+/// Header naming the queue a request's reply goes to.
+pub const HEADER_REPLY_TO: &str = "qpipe-reply-to";
+
+/// Header carrying the id that pairs a reply with its request.
+pub const HEADER_CORRELATION_ID: &str = "qpipe-correlation-id";
+
+/// Sends requests and collects their replies.
+pub struct Requester {
+    requests: Producer,
+    replies:  Consumer,
+    reply_to: String,
+    next_id:  u64,
+    /// Ids sent whose reply hasn't been handed out yet.
+    pending:  HashSet<String>,
+    /// Replies that arrived while `request` waited for another one.
+    early:    VecDeque<(String, Message)>,
+}
+
+impl Requester {
+    /// Send requests to the default queue.
+    pub fn connect(orchestrator: &str) -> io::Result<Self> {
+        Self::connect_with(orchestrator, &ProducerOptions::new(), &ConnectOptions::new())
+    }
+
+    /// Send requests to the named queue.
+    pub fn connect_to(orchestrator: &str, queue: &str) -> io::Result<Self> {
+        Self::connect_with(orchestrator, &ProducerOptions::new().queue(queue), &ConnectOptions::new())
+    }
+
+    /// Connect with options for the request producer (queue, auth,
+    /// buffering...) and for the reply consumer, whose queue is set here.
+    /// The reply consumer is in ack mode, and acks each reply as it reads
+    /// it; that also lets the orchestrator see it leave at once.
+    pub fn connect_with(orchestrator: &str, requests: &ProducerOptions, replies: &ConnectOptions) -> io::Result<Self> {
+        let nonce = psk::nonce()?;
+        let reply_to = format!("{REPLY_QUEUE_PREFIX}{}", to_hex(&nonce[..12]));
+        // Listening first, so no reply can come before its queue exists.
+        let replies = Consumer::connect_with(orchestrator, &replies.clone().queue(&reply_to).ack_mode(true))?;
+        let requests = Producer::connect_with(orchestrator, requests)?;
+        Ok(Self { requests, replies, reply_to, next_id: 0, pending: HashSet::new(), early: VecDeque::new() })
+    }
+
+    /// The reply queue this requester listens on.
+    pub fn reply_to(&self) -> &str {
+        &self.reply_to
+    }
+
+    /// Send a request without waiting for its reply, returning its
+    /// correlation id. Replies come from `recv_reply`.
+    pub fn send(&mut self, payload: &[u8]) -> io::Result<String> {
+        self.send_with_headers(payload, &Headers::new())
+    }
+
+    /// `send` with application headers of the caller's; the two rpc headers
+    /// are set over any of theirs.
+    pub fn send_with_headers(&mut self, payload: &[u8], headers: &Headers) -> io::Result<String> {
+        self.next_id += 1;
+        let id = self.next_id.to_string();
+        let headers = headers.clone()
+            .with(HEADER_REPLY_TO, self.reply_to.as_str())
+            .with(HEADER_CORRELATION_ID, id.as_str());
+        self.requests.send_with_headers(payload, &headers)?;
+        self.pending.insert(id.clone());
+        Ok(id)
+    }
+
+    /// The next reply to any request sent, with its correlation id; `None`
+    /// if none came within `timeout`. Replies to ids already answered, or
+    /// never sent, are discarded.
+    pub fn recv_reply(&mut self, timeout: Duration) -> io::Result<Option<(String, Message)>> {
+        match self.early.pop_front() {
+            Some(reply) => Ok(Some(reply)),
+            None => self.next_reply(Instant::now() + timeout),
+        }
+    }
+
+    /// Send a request and wait for its reply. `TimedOut` if none came
+    /// within `timeout`; a reply that comes later is discarded. Replies to
+    /// other requests that arrive meanwhile are kept for `recv_reply`.
+    pub fn request(&mut self, payload: &[u8], timeout: Duration) -> io::Result<Message> {
+        let deadline = Instant::now() + timeout;
+        let id = self.send(payload)?;
+        loop {
+            match self.next_reply(deadline)? {
+                Some((got, msg)) if got == id => return Ok(msg),
+                Some(other) => self.early.push_back(other),
+                None => {
+                    self.pending.remove(&id);
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut, format!("no reply to request {id} within {timeout:?}"),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// The next reply off the wire to a request still pending.
+    fn next_reply(&mut self, deadline: Instant) -> io::Result<Option<(String, Message)>> {
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.replies.recv_ext_timeout(left)? {
+                None => return Ok(None),
+                Some(Delivery::Eos(_)) => {}
+                Some(Delivery::Message(msg)) => {
+                    if let Some(tag) = msg.tag {
+                        self.replies.ack(tag)?;
+                    }
+                    let id = msg.headers.get(HEADER_CORRELATION_ID).unwrap_or_default().to_string();
+                    if self.pending.remove(&id) {
+                        return Ok(Some((id, msg)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Close both sessions; see `Producer::close`. The reply queue goes
+    /// with them.
+    pub fn close(self) -> io::Result<()> {
+        self.requests.close()?;
+        self.replies.close()
+    }
+}
+
+/// A request as a `Replier` received it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// The request as delivered, headers and (in ack mode) tag included.
+    pub message: Message,
+    reply_to:    Option<String>,
+    correlation: Option<String>,
+}
+
+impl Request {
+    fn new(message: Message) -> Self {
+        let header = |key| message.headers.get(key).map(str::to_string);
+        let (reply_to, correlation) = (header(HEADER_REPLY_TO), header(HEADER_CORRELATION_ID));
+        Self { message, reply_to, correlation }
+    }
+
+    /// Where the reply goes; `None` for a message sent by a plain producer,
+    /// which expects no reply.
+    pub fn reply_to(&self) -> Option<&str> {
+        self.reply_to.as_deref()
+    }
+
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation.as_deref()
+    }
+}
+
+/// Takes requests from a queue and sends back replies.
+pub struct Replier {
+    requests:     Consumer,
+    orchestrator: String,
+    replies:      ProducerOptions,
+}
+
+impl Replier {
+    /// Take requests from the default queue.
+    pub fn connect(orchestrator: &str) -> io::Result<Self> {
+        Self::connect_with(orchestrator, &ConnectOptions::new(), &ProducerOptions::new())
+    }
+
+    /// Take requests from the named queue.
+    pub fn connect_to(orchestrator: &str, queue: &str) -> io::Result<Self> {
+        Self::connect_with(orchestrator, &ConnectOptions::new().queue(queue), &ProducerOptions::new())
+    }
+
+    /// Connect with options for the request consumer (queue, ack mode,
+    /// capabilities...) and for the producers replies go out on, whose
+    /// queue is set per reply.
+    pub fn connect_with(orchestrator: &str, requests: &ConnectOptions, replies: &ProducerOptions) -> io::Result<Self> {
+        Ok(Self {
+            requests:     Consumer::connect_with(orchestrator, requests)?,
+            orchestrator: orchestrator.to_string(),
+            replies:      replies.clone(),
+        })
+    }
+
+    /// Blocks until the next request, skipping end-of-stream notices.
+    pub fn recv(&mut self) -> io::Result<Request> {
+        loop {
+            if let Delivery::Message(msg) = self.requests.recv_ext()? {
+                return Ok(Request::new(msg));
+            }
+        }
+    }
+
+    /// Send `payload` back to `req`'s requester, then ack `req` if it
+    /// carries a tag. `InvalidInput` for a request without a reply queue,
+    /// or one naming a queue outside `REPLY_QUEUE_PREFIX`; in ack mode it
+    /// is left unacked, for `requests_mut` to settle.
+    pub fn reply(&mut self, req: &Request, payload: &[u8]) -> io::Result<()> {
+        self.reply_with_headers(req, payload, &Headers::new())
+    }
+
+    /// `reply` with application headers of the caller's.
+    pub fn reply_with_headers(&mut self, req: &Request, payload: &[u8], headers: &Headers) -> io::Result<()> {
+        let Some(reply_to) = req.reply_to() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "request has no reply queue"));
+        };
+        // Only ever to a reply queue: a request can't aim a reply at a work
+        // queue.
+        if !reply_to.starts_with(REPLY_QUEUE_PREFIX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, format!("{reply_to:?} is not a reply queue"),
+            ));
+        }
+        let mut headers = headers.clone();
+        headers.remove(HEADER_REPLY_TO);
+        if let Some(id) = req.correlation_id() {
+            headers.insert(HEADER_CORRELATION_ID, id);
+        }
+        let mut p = Producer::connect_with(&self.orchestrator, &self.replies.clone().queue(reply_to))?;
+        p.send_with_headers(payload, &headers)?;
+        p.close()?;
+        match req.message.tag {
+            Some(tag) => self.requests.ack(tag),
+            None => Ok(()),
+        }
+    }
+
+    /// The request consumer, for nacks, windows and the like.
+    pub fn requests_mut(&mut self) -> &mut Consumer {
+        &mut self.requests
+    }
+
+    /// See `Consumer::close`.
+    pub fn close(self) -> io::Result<()> {
+        self.requests.close()
+    }
+}
@@ -0,0 +1,450 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! SCRAM-SHA-256 (RFC 5802, RFC 7677) user/password authentication on the
+//! control handshake, for sites that manage credentials as user/password
+//! pairs. The orchestrator stores only verifiers (salted, iterated keys),
+//! never passwords, and the exchange authenticates both ways: the client
+//! proves it knows the password, and the orchestrator proves it holds the
+//! user's verifier.
+//!
+//! A client that authenticates sets `ROLE_FLAG_OPTS` and sends its
+//! client-first message as `OPT_SCRAM`. The orchestrator answers with
+//! `[u16 BE 0]` — a port no data listener can have, so an orchestrator that
+//! doesn't authenticate is told apart by its reply — and the exchange runs
+//! as length-prefixed messages `[u16 BE len][UTF-8]`:
+//!
+//!   server-first (or `e=<error>`) → client-final → server-final (`v=…`
+//!   or `e=<error>`)
+//!
+//! after which the role's usual reply follows. No channel binding is used
+//! and names and passwords are taken as UTF-8 bytes, without SASLprep.
+
+use std::env;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64;
+use rand::{rngs::SysRng, TryRng};
+
+use crate::digest::{hmac_sha256, pbkdf2_sha256, sha256, DIGEST_LEN};
+
+pub const MECHANISM: &str = "SCRAM-SHA-256";
+/// RFC 7677's minimum, and what `Verifier::new` uses by default.
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+/// Refuse servers asking for more, rather than spin for minutes.
+const MAX_ITERATIONS: u32 = 10_000_000;
+const GS2_HEADER: &str = "n,,";
+
+type Key = [u8; DIGEST_LEN];
+
+fn denied(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg.into())
+}
+
+fn malformed(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("scram: {}", msg.into()))
+}
+
+fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut b = [0u8; N];
+    SysRng.try_fill_bytes(&mut b).map_err(io::Error::other)?;
+    Ok(b)
+}
+
+fn nonce() -> io::Result<String> {
+    Ok(B64.encode(random_bytes::<18>()?))
+}
+
+/// Constant-time comparison, so a proof check leaks nothing through timing.
+fn same(a: &Key, b: &Key) -> bool {
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn xor(a: &Key, b: &Key) -> Key {
+    let mut out = *a;
+    for (o, x) in out.iter_mut().zip(b) {
+        *o ^= x;
+    }
+    out
+}
+
+/// The `k=v` attributes of a SCRAM message, in order.
+fn attributes(msg: &str) -> io::Result<Vec<(char, &str)>> {
+    msg.split(',')
+        .map(|a| {
+            let mut c = a.chars();
+            match (c.next(), c.next()) {
+                (Some(k), Some('=')) => Ok((k, &a[2..])),
+                _ => Err(malformed(format!("bad attribute {a:?}"))),
+            }
+        })
+        .collect()
+}
+
+fn attribute<'a>(attrs: &[(char, &'a str)], key: char) -> io::Result<&'a str> {
+    attrs.iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| *v)
+        .ok_or_else(|| malformed(format!("missing {key}= attribute")))
+}
+
+/// A server-side `e=` error becomes the client's error.
+fn check_error(msg: &str) -> io::Result<()> {
+    match msg.strip_prefix("e=") {
+        Some(e) => Err(denied(format!("orchestrator refused authentication: {e}"))),
+        None => Ok(()),
+    }
+}
+
+fn escape_user(user: &str) -> String {
+    user.replace('=', "=3D").replace(',', "=2C")
+}
+
+fn unescape_user(user: &str) -> io::Result<String> {
+    if user.replace("=2C", "").replace("=3D", "").contains('=') {
+        return Err(malformed("bad escape in user name"));
+    }
+    Ok(user.replace("=2C", ",").replace("=3D", "="))
+}
+
+/// What the orchestrator stores per user: enough to check a proof and to
+/// prove itself, but not to log in as the user.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Verifier {
+    iterations: u32,
+    salt:       Vec<u8>,
+    stored_key: Key,
+    server_key: Key,
+}
+
+impl Verifier {
+    /// Derive a verifier for `password` under a fresh random salt.
+    pub fn new(password: &str, iterations: u32) -> io::Result<Self> {
+        Ok(Self::derive(password, &random_bytes::<16>()?, iterations.max(1)))
+    }
+
+    fn derive(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let salted = pbkdf2_sha256(password.as_bytes(), salt, iterations);
+        Self {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: sha256(&hmac_sha256(&salted, b"Client Key")),
+            server_key: hmac_sha256(&salted, b"Server Key"),
+        }
+    }
+
+    /// A stand-in for a user that doesn't exist: stable per name under
+    /// `secret`, so probing names can't tell it from a real one, and
+    /// matched by no password.
+    pub fn mock(secret: &[u8], user: &str) -> Self {
+        let salt = hmac_sha256(secret, format!("salt:{user}").as_bytes());
+        Self {
+            iterations: DEFAULT_ITERATIONS,
+            salt: salt[..16].to_vec(),
+            stored_key: hmac_sha256(secret, format!("stored:{user}").as_bytes()),
+            server_key: hmac_sha256(secret, format!("server:{user}").as_bytes()),
+        }
+    }
+
+    /// Parse the textual form `Display` writes:
+    /// `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`, base64.
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let bad = || io::Error::new(
+            io::ErrorKind::InvalidInput, format!("not a {MECHANISM} verifier"),
+        );
+        let rest = s.strip_prefix(MECHANISM).and_then(|r| r.strip_prefix('$')).ok_or_else(bad)?;
+        let (params, keys) = rest.split_once('$').ok_or_else(bad)?;
+        let (iterations, salt) = params.split_once(':').ok_or_else(bad)?;
+        let (stored, server) = keys.split_once(':').ok_or_else(bad)?;
+        let key = |k: &str| -> io::Result<Key> {
+            B64.decode(k).ok().and_then(|v| v.try_into().ok()).ok_or_else(bad)
+        };
+        Ok(Self {
+            iterations: iterations.parse().ok().filter(|&i| i > 0).ok_or_else(bad)?,
+            salt: B64.decode(salt).map_err(|_| bad())?,
+            stored_key: key(stored)?,
+            server_key: key(server)?,
+        })
+    }
+}
+
+impl fmt::Display for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{MECHANISM}${}:{}${}:{}",
+            self.iterations, B64.encode(&self.salt),
+            B64.encode(self.stored_key), B64.encode(self.server_key),
+        )
+    }
+}
+
+impl fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Verifier").field("iterations", &self.iterations).finish_non_exhaustive()
+    }
+}
+
+/// A user name and password to authenticate with.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    user:     String,
+    password: String,
+}
+
+impl Credentials {
+    pub fn new(user: impl Into<String>, password: impl Into<String>) -> Self {
+        Self { user: user.into(), password: password.into() }
+    }
+
+    /// `QPIPE_USER` and `QPIPE_PASSWORD`, if both are set — what clients
+    /// fall back to when no credentials are given explicitly.
+    pub fn from_env() -> Option<Self> {
+        Some(Self::new(env::var("QPIPE_USER").ok()?, env::var("QPIPE_PASSWORD").ok()?))
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials").field("user", &self.user).finish_non_exhaustive()
+    }
+}
+
+/// Client side of one exchange.
+pub struct Client {
+    password:   String,
+    nonce:      String,
+    first_bare: String,
+    /// The signature the server must present, once the proof is sent.
+    expect:     Option<Key>,
+}
+
+impl Client {
+    pub fn new(creds: &Credentials) -> io::Result<Self> {
+        Ok(Self::with_nonce(creds, nonce()?))
+    }
+
+    fn with_nonce(creds: &Credentials, nonce: String) -> Self {
+        let first_bare = format!("n={},r={nonce}", escape_user(&creds.user));
+        Self { password: creds.password.clone(), nonce, first_bare, expect: None }
+    }
+
+    /// The client-first message, sent as `OPT_SCRAM`.
+    pub fn first(&self) -> String {
+        format!("{GS2_HEADER}{}", self.first_bare)
+    }
+
+    /// Answer the server-first message with the client-final message.
+    pub fn respond(&mut self, server_first: &str) -> io::Result<String> {
+        check_error(server_first)?;
+        let attrs = attributes(server_first)?;
+        let nonce = attribute(&attrs, 'r')?;
+        if !nonce.starts_with(&self.nonce) || nonce.len() == self.nonce.len() {
+            return Err(malformed("server nonce does not extend ours"));
+        }
+        let salt = B64.decode(attribute(&attrs, 's')?).map_err(|_| malformed("bad salt"))?;
+        let iterations: u32 = attribute(&attrs, 'i')?.parse()
+            .ok()
+            .filter(|i| (1..=MAX_ITERATIONS).contains(i))
+            .ok_or_else(|| malformed("bad iteration count"))?;
+
+        let salted = pbkdf2_sha256(self.password.as_bytes(), &salt, iterations);
+        let client_key = hmac_sha256(&salted, b"Client Key");
+        let server_key = hmac_sha256(&salted, b"Server Key");
+        let without_proof = format!("c={},r={nonce}", B64.encode(GS2_HEADER));
+        let auth = format!("{},{server_first},{without_proof}", self.first_bare);
+        let proof = xor(&client_key, &hmac_sha256(&sha256(&client_key), auth.as_bytes()));
+        self.expect = Some(hmac_sha256(&server_key, auth.as_bytes()));
+        Ok(format!("{without_proof},p={}", B64.encode(proof)))
+    }
+
+    /// Check the server-final message: the orchestrator knew our verifier.
+    pub fn verify(&self, server_final: &str) -> io::Result<()> {
+        check_error(server_final)?;
+        let expect = self.expect.as_ref().ok_or_else(|| malformed("final before first"))?;
+        let attrs = attributes(server_final)?;
+        let got = B64.decode(attribute(&attrs, 'v')?).ok()
+            .and_then(|v| Key::try_from(v).ok())
+            .ok_or_else(|| malformed("bad server signature"))?;
+        if !same(&got, expect) {
+            return Err(denied("orchestrator failed to prove it knows our credentials"));
+        }
+        Ok(())
+    }
+}
+
+/// Server side of one exchange.
+pub struct Server {
+    user:     String,
+    verifier: Verifier,
+    nonce:    String,
+    /// client-first-bare "," server-first, the head of the auth message.
+    head:     String,
+}
+
+impl Server {
+    /// The user name a client-first message names.
+    pub fn user_of(client_first: &str) -> io::Result<String> {
+        let bare = Self::bare(client_first)?;
+        unescape_user(attribute(&attributes(bare)?, 'n')?)
+    }
+
+    fn bare(client_first: &str) -> io::Result<&str> {
+        let bare = client_first.strip_prefix("n,,")
+            .or_else(|| client_first.strip_prefix("y,,"))
+            .ok_or_else(|| malformed("unsupported GS2 header (channel binding or authzid)"))?;
+        if bare.starts_with("m=") {
+            return Err(malformed("unsupported mandatory extension"));
+        }
+        Ok(bare)
+    }
+
+    /// Start an exchange for `client_first` against the named user's
+    /// verifier (a `Verifier::mock` if there is no such user). Returns the
+    /// server-first message to send.
+    pub fn start(client_first: &str, verifier: Verifier) -> io::Result<(Self, String)> {
+        Self::with_nonce(client_first, verifier, &nonce()?)
+    }
+
+    fn with_nonce(client_first: &str, verifier: Verifier, ours: &str) -> io::Result<(Self, String)> {
+        let bare = Self::bare(client_first)?;
+        let attrs = attributes(bare)?;
+        let user = unescape_user(attribute(&attrs, 'n')?)?;
+        let nonce = format!("{}{ours}", attribute(&attrs, 'r')?);
+        let first = format!(
+            "r={nonce},s={},i={}", B64.encode(&verifier.salt), verifier.iterations,
+        );
+        let head = format!("{bare},{first}");
+        Ok((Self { user, verifier, nonce, head }, first))
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Check the client-final message. Returns the server-final message if
+    /// the proof holds, `PermissionDenied` if it doesn't.
+    pub fn finish(&self, client_final: &str) -> io::Result<String> {
+        let (without_proof, proof) = client_final.rsplit_once(",p=")
+            .ok_or_else(|| malformed("missing proof"))?;
+        let attrs = attributes(without_proof)?;
+        if attribute(&attrs, 'c')? != B64.encode(GS2_HEADER)
+            && attribute(&attrs, 'c')? != B64.encode("y,,")
+        {
+            return Err(malformed("channel binding mismatch"));
+        }
+        if attribute(&attrs, 'r')? != self.nonce {
+            return Err(malformed("nonce mismatch"));
+        }
+        let proof = B64.decode(proof).ok()
+            .and_then(|v| Key::try_from(v).ok())
+            .ok_or_else(|| malformed("bad proof"))?;
+        let auth = format!("{},{without_proof}", self.head);
+        let client_key = xor(&proof, &hmac_sha256(&self.verifier.stored_key, auth.as_bytes()));
+        if !same(&sha256(&client_key), &self.verifier.stored_key) {
+            return Err(denied(format!("invalid proof for user {:?}", self.user)));
+        }
+        let signature = hmac_sha256(&self.verifier.server_key, auth.as_bytes());
+        Ok(format!("v={}", B64.encode(signature)))
+    }
+}
+
+/// Write one exchange message `[u16 BE len][UTF-8]`.
+pub fn write_message<W: Write>(w: &mut W, msg: &str) -> io::Result<()> {
+    let len = u16::try_from(msg.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "scram message too long"))?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(msg.as_bytes())?;
+    w.flush()
+}
+
+pub fn read_message<R: Read>(r: &mut R) -> io::Result<String> {
+    let mut len = [0u8; 2];
+    r.read_exact(&mut len)?;
+    let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| malformed("message is not UTF-8"))
+}
+
+/// Run the client side of the exchange on a control connection, after the
+/// role byte and an option block carrying `client.first()`.
+pub fn authenticate<S: Read + Write>(s: &mut S, mut client: Client) -> io::Result<()> {
+    let mut marker = [0u8; 2];
+    match s.read_exact(&mut marker) {
+        Ok(()) if marker == [0, 0] => {}
+        Ok(()) => return Err(io::Error::new(
+            io::ErrorKind::Unsupported, "orchestrator does not support authentication",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(io::Error::new(
+            io::ErrorKind::Unsupported, "orchestrator does not support authentication",
+        )),
+        Err(e) => return Err(e),
+    }
+    let reply = client.respond(&read_message(s)?)?;
+    write_message(s, &reply)?;
+    client.verify(&read_message(s)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_rfc_7677_exchange() {
+        let creds = Credentials::new("user", "pencil");
+        let mut client = Client::with_nonce(&creds, "rOprNGfwEbeRWgbNEkqO".into());
+        assert_eq!(client.first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+
+        let salt = B64.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let verifier = Verifier::derive("pencil", &salt, 4096);
+        let (server, first) = Server::with_nonce(
+            &client.first(), verifier, "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0",
+        ).unwrap();
+        assert_eq!(
+            first,
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+        );
+        let last = client.respond(&first).unwrap();
+        assert_eq!(
+            last,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+        );
+        let fin = server.finish(&last).unwrap();
+        assert_eq!(fin, "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+        client.verify(&fin).unwrap();
+    }
+
+    #[test]
+    fn wrong_passwords_and_impostor_servers_are_refused() {
+        let verifier = Verifier::new("right", 64).unwrap();
+        let mut client = Client::new(&Credentials::new("a,b=c", "wrong")).unwrap();
+        assert_eq!(Server::user_of(&client.first()).unwrap(), "a,b=c");
+        let (server, first) = Server::start(&client.first(), verifier.clone()).unwrap();
+        let last = client.respond(&first).unwrap();
+        assert_eq!(server.finish(&last).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        // A server holding some other verifier can't fake the final message.
+        let mut client = Client::new(&Credentials::new("u", "right")).unwrap();
+        let (server, first) = Server::start(&client.first(), verifier).unwrap();
+        let last = client.respond(&first).unwrap();
+        server.finish(&last).unwrap();
+        let impostor = Verifier::new("other", 64).unwrap();
+        let fake = format!("v={}", B64.encode(hmac_sha256(&impostor.server_key, b"x")));
+        assert_eq!(client.verify(&fake).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(client.verify("e=invalid-proof").is_err());
+    }
+
+    #[test]
+    fn verifiers_round_trip_and_mocks_are_stable() {
+        let v = Verifier::new("secret", 4096).unwrap();
+        assert_eq!(Verifier::parse(&v.to_string()).unwrap(), v);
+        assert!(Verifier::parse("SCRAM-SHA-256$0:AA==$AA==:AA==").is_err());
+        assert!(Verifier::parse("md5$whatever").is_err());
+        assert_eq!(Verifier::mock(b"k", "nobody"), Verifier::mock(b"k", "nobody"));
+        assert_ne!(Verifier::mock(b"k", "nobody").salt, Verifier::mock(b"k", "else").salt);
+    }
+}
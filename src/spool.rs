@@ -0,0 +1,325 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! On-disk journal behind `ReconnectingProducer`'s spool directory.
+//!
+//! Records are appended to numbered segment files (`<n>.spool`) while the
+//! orchestrator is unreachable and replayed oldest-first once it is back.
+//! Each segment has a sidecar (`<n>.pos`) holding the byte offset replayed
+//! so far, so a producer that dies mid-replay resends at most the one record
+//! it was sending; fully replayed segments are deleted. A record cut short by
+//! a crash during append is ignored.
+//!
+//! Record: `[u8 kind][u16 BE meta_len][meta TLV][u64 BE len][bytes]`, kind
+//! `M` (message, bytes = payload) or `E` (end of stream, bytes = group).
+//! With an at-rest key every record is wrapped as `[u8 'X'][u32 BE len]
+//! [sealed record]` (see `at_rest`), so nothing lands on disk in plaintext.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::at_rest::{self, Key};
+use crate::Meta;
+
+const KIND_MSG: u8    = b'M';
+const KIND_EOS: u8    = b'E';
+const KIND_SEALED: u8 = b'X';
+
+/// Associated data binding sealed records to the spool format.
+const SPOOL_AAD: &[u8] = b"qpipe-spool-v1";
+
+/// One spooled send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Record {
+    Msg(Vec<u8>, Box<Meta>),
+    Eos(Vec<u8>),
+}
+
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let (kind, meta, body) = match self {
+            Record::Msg(payload, meta) => (KIND_MSG, meta.encode(), payload),
+            Record::Eos(group) => (KIND_EOS, Vec::new(), group),
+        };
+        let mut buf = Vec::with_capacity(11 + meta.len() + body.len());
+        buf.push(kind);
+        buf.extend_from_slice(&(meta.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&meta);
+        buf.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W, key: Option<&Key>) -> io::Result<()> {
+        let buf = match key {
+            None => self.encode(),
+            Some(key) => {
+                let sealed = at_rest::seal(key, SPOOL_AAD, &self.encode())?;
+                let mut buf = Vec::with_capacity(5 + sealed.len());
+                buf.push(KIND_SEALED);
+                buf.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&sealed);
+                buf
+            }
+        };
+        w.write_all(&buf) // one write: a crash leaves at most a torn tail
+    }
+
+    /// Read one record; `None` at a clean end or a torn tail.
+    fn read_from<R: Read>(r: &mut R, key: Option<&Key>) -> io::Result<Option<(Self, u64)>> {
+        let mut head = [0u8; 3];
+        match read_full(r, &mut head[..1])? {
+            0 => return Ok(None),
+            _ if head[0] == KIND_SEALED => return Self::read_sealed(r, key),
+            _ => {}
+        }
+        if read_full(r, &mut head[1..])? < 2 {
+            return Ok(None);
+        }
+        let mut meta = vec![0u8; u16::from_be_bytes([head[1], head[2]]) as usize];
+        let mut len = [0u8; 8];
+        if read_full(r, &mut meta)? < meta.len() || read_full(r, &mut len)? < len.len() {
+            return Ok(None);
+        }
+        let len = u64::from_be_bytes(len);
+        let mut body = Vec::new();
+        if r.take(len).read_to_end(&mut body)? < len as usize {
+            return Ok(None);
+        }
+        let rec = match head[0] {
+            KIND_MSG => Record::Msg(body, Box::new(Meta::decode(&meta)?)),
+            KIND_EOS => Record::Eos(body),
+            k => return Err(io::Error::new(
+                io::ErrorKind::InvalidData, format!("unknown spool record kind {k:#04x}"),
+            )),
+        };
+        Ok(Some((rec, 11 + meta.len() as u64 + len)))
+    }
+
+    /// The rest of a sealed record, its `KIND_SEALED` byte already read.
+    fn read_sealed<R: Read>(r: &mut R, key: Option<&Key>) -> io::Result<Option<(Self, u64)>> {
+        let mut len = [0u8; 4];
+        if read_full(r, &mut len)? < len.len() {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(len) as u64;
+        let mut sealed = Vec::new();
+        if r.take(len).read_to_end(&mut sealed)? < len as usize {
+            return Ok(None);
+        }
+        let key = key.ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData, "spool record is encrypted but no key is configured",
+        ))?;
+        let plain = at_rest::open(key, SPOOL_AAD, &sealed)?;
+        match Self::read_from(&mut &plain[..], None)? {
+            Some((rec, _)) => Ok(Some((rec, 5 + len))),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData, "sealed spool record is truncated",
+            )),
+        }
+    }
+}
+
+/// `read_exact` that reports how much it got instead of failing on EOF.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut got = 0;
+    while got < buf.len() {
+        match r.read(&mut buf[got..]) {
+            Ok(0) => break,
+            Ok(n) => got += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(got)
+}
+
+pub(crate) struct Spool {
+    dir:     PathBuf,
+    /// Seal new records with this key; needed to replay sealed ones.
+    key:     Option<Key>,
+    /// Segment numbers with records left to replay, oldest first.
+    segs:    VecDeque<u64>,
+    /// The segment being appended to. Replay closes it, so a segment is
+    /// never appended to and replayed at once.
+    writer:  Option<(u64, File)>,
+    /// Records not yet replayed.
+    pending: usize,
+}
+
+impl Spool {
+    /// Open (creating if needed) a spool directory, picking up whatever a
+    /// previous run left behind.
+    pub(crate) fn open(dir: &Path, key: Option<Key>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut segs: Vec<u64> = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(n) = name.strip_suffix(".spool").and_then(|n| n.parse().ok()) {
+                segs.push(n);
+            }
+        }
+        segs.sort_unstable();
+        let mut spool = Self {
+            dir: dir.to_path_buf(), key, segs: segs.into(), writer: None, pending: 0,
+        };
+        for seg in spool.segs.clone() {
+            let mut r = spool.reader(seg)?;
+            while Record::read_from(&mut r, spool.key.as_ref())?.is_some() {
+                spool.pending += 1;
+            }
+        }
+        Ok(spool)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pending
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending == 0
+    }
+
+    fn seg_path(&self, seg: u64) -> PathBuf {
+        self.dir.join(format!("{seg:012}.spool"))
+    }
+
+    fn pos_path(&self, seg: u64) -> PathBuf {
+        self.dir.join(format!("{seg:012}.pos"))
+    }
+
+    fn replayed(&self, seg: u64) -> io::Result<u64> {
+        match fs::read(self.pos_path(seg)) {
+            Ok(b) => Ok(b.try_into().map(u64::from_be_bytes).unwrap_or(0)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// A reader positioned after the records already replayed.
+    fn reader(&self, seg: u64) -> io::Result<BufReader<File>> {
+        let mut f = File::open(self.seg_path(seg))?;
+        f.seek(SeekFrom::Start(self.replayed(seg)?))?;
+        Ok(BufReader::new(f))
+    }
+
+    pub(crate) fn append(&mut self, rec: &Record) -> io::Result<()> {
+        if self.writer.is_none() {
+            let seg = self.segs.back().map_or(1, |s| s + 1);
+            let f = OpenOptions::new().create(true).append(true).open(self.seg_path(seg))?;
+            self.segs.push_back(seg);
+            self.writer = Some((seg, f));
+        }
+        let (_, f) = self.writer.as_mut().expect("just opened");
+        rec.write_to(f, self.key.as_ref())?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Hand every pending record, oldest first, to `send`. Stops at the
+    /// first error, leaving that record pending for the next replay.
+    pub(crate) fn replay(
+                &mut self,
+                mut send: impl FnMut(&Record) -> io::Result<()>,
+            ) -> io::Result<()> {
+        self.writer = None;
+        while let Some(&seg) = self.segs.front() {
+            let mut pos = self.replayed(seg)?;
+            let mut r = self.reader(seg)?;
+            while let Some((rec, len)) = Record::read_from(&mut r, self.key.as_ref())? {
+                send(&rec)?;
+                pos += len;
+                fs::write(self.pos_path(seg), pos.to_be_bytes())?;
+                self.pending -= 1;
+            }
+            fs::remove_file(self.seg_path(seg))?;
+            match fs::remove_file(self.pos_path(seg)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            self.segs.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(p: &[u8]) -> Record {
+        Record::Msg(p.to_vec(), Box::new(Meta { priority: Some(2), ..Meta::default() }))
+    }
+
+    fn drain(spool: &mut Spool) -> Vec<Record> {
+        let mut out = Vec::new();
+        spool.replay(|r| { out.push(r.clone()); Ok(()) }).unwrap();
+        out
+    }
+
+    #[test]
+    fn replays_in_order_and_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = Spool::open(dir.path(), None).unwrap();
+        s.append(&msg(b"a")).unwrap();
+        s.append(&Record::Eos(b"run-1".to_vec())).unwrap();
+        assert_eq!(s.len(), 2);
+        assert_eq!(drain(&mut s), [msg(b"a"), Record::Eos(b"run-1".to_vec())]);
+        assert!(s.is_empty());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn failed_replay_resumes_where_it_stopped_even_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = Spool::open(dir.path(), None).unwrap();
+        for p in [b"1", b"2", b"3"] {
+            s.append(&msg(p)).unwrap();
+        }
+        let mut sent = 0;
+        let err = s.replay(|_| {
+            if sent == 1 {
+                return Err(io::Error::from(io::ErrorKind::ConnectionReset));
+            }
+            sent += 1;
+            Ok(())
+        });
+        assert!(err.is_err());
+        assert_eq!(s.len(), 2);
+        s.append(&msg(b"4")).unwrap(); // goes to a fresh segment
+
+        let mut s = Spool::open(dir.path(), None).unwrap();
+        assert_eq!(s.len(), 3);
+        assert_eq!(drain(&mut s), [msg(b"2"), msg(b"3"), msg(b"4")]);
+    }
+
+    #[test]
+    fn torn_tail_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = Spool::open(dir.path(), None).unwrap();
+        s.append(&msg(b"whole")).unwrap();
+        let (seg, _) = s.writer.take().unwrap();
+        let mut f = OpenOptions::new().append(true).open(s.seg_path(seg)).unwrap();
+        f.write_all(&[KIND_MSG, 0, 0, 0, 0, 0]).unwrap(); // crash mid-append
+
+        let mut s = Spool::open(dir.path(), None).unwrap();
+        assert_eq!(s.len(), 1);
+        assert_eq!(drain(&mut s), [msg(b"whole")]);
+    }
+
+    #[test]
+    fn encrypted_spools_hide_payloads_and_need_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Key::from_bytes([9; 32]);
+        let mut s = Spool::open(dir.path(), Some(key.clone())).unwrap();
+        s.append(&msg(b"plaintext-marker")).unwrap();
+        let (seg, _) = s.writer.take().unwrap();
+        let disk = fs::read(s.seg_path(seg)).unwrap();
+        assert!(!disk.windows(16).any(|w| w == b"plaintext-marker"));
+
+        assert!(Spool::open(dir.path(), None).is_err());
+        let mut s = Spool::open(dir.path(), Some(key)).unwrap();
+        assert_eq!(drain(&mut s), [msg(b"plaintext-marker")]);
+    }
+}
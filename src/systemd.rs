@@ -0,0 +1,228 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Running as a systemd service: socket activation and readiness
+//! notifications, without linking libsystemd.
+//!
+//! A socket unit binds the orchestrator's addresses and passes them to the
+//! service as file descriptors from 3 on (`listen_fds`):
+//!
+//!   LISTEN_PID=<pid the fds are for>  LISTEN_FDS=<count>
+//!   LISTEN_FDNAMES=<name>:<name>...   (FileDescriptorName=, optional)
+//!
+//! The sockets stay open while the service restarts, so clients that
+//! connect meanwhile wait in the backlog instead of being refused. A
+//! socket named `ws` takes WebSocket clients (with the `ws` feature); any
+//! other, producers and consumers.
+//!
+//! `notify` sends the service manager state lines (see sd_notify(3)) over
+//! the datagram socket NOTIFY_SOCKET names, `@` for the abstract
+//! namespace: `READY=1` once the accept loops run, `STOPPING=1` when a
+//! drain begins, `STATUS=...` along the way.
+
+use std::env;
+use std::ffi::{c_int, OsStr};
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use crate::transport::Listener;
+
+/// The first descriptor a service manager passes (SD_LISTEN_FDS_START).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Set once the passed descriptors are taken, so none is owned twice.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+const LISTEN_VARS: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+/// LISTEN_* as `unset_listen_env` found them.
+static LISTEN_ENV: OnceLock<[Option<String>; 3]> = OnceLock::new();
+
+const F_SETFD: c_int = 2;
+const FD_CLOEXEC: c_int = 1;
+
+unsafe extern "C" {
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+}
+
+/// Take LISTEN_* out of the environment, keeping them for `listen_fds`,
+/// so that programs the process runs (hooks) don't see them. Those would
+/// not take the sockets anyway, LISTEN_PID naming another process.
+///
+/// # Safety
+///
+/// No other thread may read or write the environment meanwhile (see
+/// `std::env::remove_var`): call it first thing in `main`, before any
+/// thread starts.
+pub unsafe fn unset_listen_env() {
+    LISTEN_ENV.get_or_init(|| LISTEN_VARS.map(|name| env::var(name).ok()));
+    for name in LISTEN_VARS {
+        // SAFETY: the caller's.
+        unsafe { env::remove_var(name) };
+    }
+}
+
+/// Take the listening sockets the service manager passed to this process,
+/// each with its name (`unknown` without LISTEN_FDNAMES). None if it
+/// passed none, or passed them to another process, or on any call after
+/// the first. LISTEN_* are read, from the environment unless
+/// `unset_listen_env` took them out, and left as they are.
+pub fn listen_fds() -> io::Result<Vec<(String, Listener)>> {
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+    let [pid, fds, names] = match LISTEN_ENV.get() {
+        Some(vars) => vars.clone(),
+        None => LISTEN_VARS.map(|name| env::var(name).ok()),
+    };
+    passed(pid.as_deref(), fds.as_deref(), names.as_deref())?
+        .into_iter()
+        // SAFETY: the service manager opened these for this process (its
+        // pid is LISTEN_PID), and TAKEN keeps them from being taken twice.
+        .map(|(name, fd)| unsafe { adopt(fd, &name) }.map(|l| (name, l)))
+        .collect()
+}
+
+/// The names and descriptors LISTEN_* pass to this process.
+fn passed(pid: Option<&str>, fds: Option<&str>, names: Option<&str>) -> io::Result<Vec<(String, RawFd)>> {
+    if pid.and_then(|p| p.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let Some(fds) = fds else {
+        return Ok(Vec::new());
+    };
+    let n = fds.parse::<RawFd>().ok()
+        .filter(|&n| n >= 0)
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("LISTEN_FDS={fds:?}: expected a count"),
+        ))?;
+    let mut names: Vec<String> = names.into_iter().flat_map(|n| n.split(':')).map(str::to_string).collect();
+    names.resize(n as usize, "unknown".into());
+    Ok(names.into_iter().zip(LISTEN_FDS_START..LISTEN_FDS_START + n).collect())
+}
+
+/// Own the listening socket `fd` as a `Listener`: TCP if it has an IP
+/// address, else a Unix socket with a path, whose file stays the service
+/// manager's (`Listener::UnixActivated`).
+///
+/// # Safety
+///
+/// `fd` must be an open socket nothing else owns.
+unsafe fn adopt(fd: RawFd, name: &str) -> io::Result<Listener> {
+    let context = |e: io::Error| io::Error::new(e.kind(), format!("fd {fd} ({name}): {e}"));
+    let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+    // Close-on-exec, like the sockets std opens, so hooks the orchestrator
+    // runs don't inherit it.
+    // SAFETY: `fd` is open, and owned by `tcp` from here on.
+    if unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) } < 0 {
+        return Err(context(io::Error::last_os_error()));
+    }
+    // Only an IP socket's address reads back as a `SocketAddr`.
+    if tcp.local_addr().is_ok() {
+        return match name {
+            #[cfg(feature = "ws")]
+            "ws" => Ok(Listener::Ws(tcp)),
+            #[cfg(not(feature = "ws"))]
+            "ws" => Err(context(io::Error::new(io::ErrorKind::Unsupported, "built without the `ws` feature"))),
+            _ => Ok(Listener::Tcp(tcp)),
+        };
+    }
+    let unix = unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+    let addr = unix.local_addr().map_err(context)?;
+    match addr.as_pathname() {
+        _ if name == "ws" => Err(context(io::Error::new(
+            io::ErrorKind::InvalidInput, "WebSocket clients need a TCP socket",
+        ))),
+        Some(path) => Ok(Listener::UnixActivated(unix, path.to_path_buf())),
+        None => Err(context(io::Error::new(
+            io::ErrorKind::InvalidInput, "expected a TCP socket or a Unix socket with a path",
+        ))),
+    }
+}
+
+/// Tell the service manager `state`, one `KEY=value` per line. Ok(false)
+/// if none listens (NOTIFY_SOCKET is unset).
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(path) if !path.is_empty() => send(&path, state).map(|()| true),
+        _ => Ok(false),
+    }
+}
+
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let sock = UnixDatagram::unbound()?;
+    if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return sock.send_to_addr(state.as_bytes(), &addr).map(drop);
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported, format!("NOTIFY_SOCKET={}: no abstract sockets here", name.escape_ascii()),
+        ));
+    }
+    sock.send_to(state.as_bytes(), Path::new(path)).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const F_GETFD: c_int = 1;
+
+    #[test]
+    fn passed_descriptors_are_counted_from_three_and_named() {
+        let me = std::process::id().to_string();
+        assert_eq!(
+            passed(Some(&me), Some("3"), Some("control:ws")).unwrap(),
+            vec![("control".into(), 3), ("ws".into(), 4), ("unknown".into(), 5)],
+        );
+        assert_eq!(passed(Some(&me), Some("1"), None).unwrap(), vec![("unknown".into(), 3)]);
+        // For another process, or none at all.
+        assert!(passed(Some("1"), Some("2"), None).unwrap().is_empty());
+        assert!(passed(None, Some("2"), None).unwrap().is_empty());
+        assert!(passed(Some(&me), None, None).unwrap().is_empty());
+        assert_eq!(passed(Some(&me), Some("two"), None).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn adopted_sockets_keep_their_address_and_their_file() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let fd = tcp.into_raw_fd();
+        // As a service manager passes it: inheritable.
+        assert_eq!(unsafe { fcntl(fd, F_SETFD, 0) }, 0);
+        let l = unsafe { adopt(fd, "control") }.unwrap();
+        assert!(matches!(l, Listener::Tcp(_)));
+        assert_eq!(l.local_addr().unwrap().to_string(), addr.to_string());
+        assert_eq!(unsafe { fcntl(fd, F_GETFD) } & FD_CLOEXEC, FD_CLOEXEC);
+
+        let path = env::temp_dir().join(format!("qpipe-activated-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+        let l = unsafe { adopt(unix.into_raw_fd(), "unknown") }.unwrap();
+        assert!(matches!(&l, Listener::UnixActivated(_, p) if *p == path));
+        drop(l);
+        // The socket unit's file outlives the service.
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn notifications_are_datagrams_to_the_named_socket() {
+        let path = env::temp_dir().join(format!("qpipe-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1\nSTATUS=serving").unwrap();
+        let mut buf = [0; 64];
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=serving");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
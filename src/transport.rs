@@ -0,0 +1,849 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Transports: TCP, and Unix domain sockets for pipelines that share a
+//! host.
+//!
+//! An orchestrator address is `host:port` for TCP or `unix://<path>` for a
+//! Unix socket (`unix:///run/qpipe.sock` for an absolute path). The
+//! handshake is the same over both. Over a Unix socket there is no
+//! ephemeral port: the `port` field of the reply is a session number `n`,
+//! and the data connection goes to the socket `<path>.<n>` next to the
+//! control socket. The orchestrator removes it as soon as the client has
+//! connected; the session token still has to be presented on it.
+//!
+//! With the `ws` feature, `ws://host:port/path` reaches an orchestrator's
+//! WebSocket listener (see src/ws.rs). WebSocket sessions are always
+//! single-port: there is no second connection to upgrade.
+//!
+//! `mem://<name>` reaches an orchestrator in the same process over
+//! in-memory pipes (see `mem`); session data connections go to
+//! `<name>.<n>`, as over a Unix socket.
+//!
+//! Other transports plug in from outside the crate: `register` a `Scheme`
+//! under a name, and addresses `<name>://...` connect (and orchestrators
+//! listen) through it. Its connections are `Transport`s, which carry
+//! single-port sessions only, like WebSocket ones. Everything above the
+//! byte stream — the hello, options, authentication and framing — is the
+//! crate's, unchanged.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, SocketAddrV6, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicU16, Ordering};
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, RawFd};
+
+use crate::mem::{self, MemListener, MemStream};
+#[cfg(feature = "ws")]
+use crate::ws::{self, WsStream};
+
+/// Prefix of Unix socket addresses.
+pub const UNIX_SCHEME: &str = "unix://";
+
+/// Prefix of WebSocket addresses.
+pub const WS_SCHEME: &str = "ws://";
+
+/// Prefix of in-process addresses.
+pub const MEM_SCHEME: &str = "mem://";
+
+/// A connection over a transport from outside this crate (see `Scheme`).
+/// Like a socket's, its methods take `&self`: `try_clone`s share the
+/// connection, and one thread may read while another writes.
+pub trait Transport: Send + Sync + fmt::Debug {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&self, buf: &[u8]) -> io::Result<usize>;
+    fn flush(&self) -> io::Result<()>;
+    /// Another handle to the same connection, like `TcpStream::try_clone`.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+    /// Reads and writes past the timeout fail with `WouldBlock` or
+    /// `TimedOut`.
+    fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()>;
+    fn set_nonblocking(&self, on: bool) -> io::Result<()>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    /// Read into `buf` without consuming, like `TcpStream::peek`.
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>;
+    /// The other end, for logs.
+    fn peer(&self) -> String;
+}
+
+/// Connections accepted by an orchestrator listening through a `Scheme`.
+pub trait Acceptor: Send + Sync + fmt::Debug {
+    /// The next connection, and its peer for logs; `WouldBlock` when
+    /// nonblocking and there is none.
+    fn accept(&self) -> io::Result<(Box<dyn Transport>, String)>;
+    fn set_nonblocking(&self, on: bool) -> io::Result<()>;
+    /// The address clients connect to, scheme and all.
+    fn local_addr(&self) -> io::Result<String>;
+}
+
+/// A transport registered under a name (see `register`). Addresses are
+/// handed over whole, `<name>://` included.
+pub trait Scheme: Send + Sync {
+    fn connect(&self, addr: &str, timeout: Option<Duration>) -> io::Result<Box<dyn Transport>>;
+    /// Listen on `addr`, for an orchestrator. Client-only transports (say,
+    /// TLS towards a terminating proxy) needn't.
+    fn bind(&self, addr: &str) -> io::Result<Box<dyn Acceptor>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("{addr}: this transport only connects")))
+    }
+}
+
+/// Registered schemes, by name.
+static SCHEMES: Mutex<BTreeMap<String, Arc<dyn Scheme>>> = Mutex::new(BTreeMap::new());
+
+/// Make `<name>://...` addresses connect through `scheme`, everywhere this
+/// crate takes an orchestrator address. `AlreadyExists` for a name already
+/// registered or built in (`unix`, `ws`, `mem`); `wss` is free for a TLS
+/// transport to take.
+pub fn register(name: &str, scheme: impl Scheme + 'static) -> io::Result<()> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b"+-.".contains(&b)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("bad scheme name {name:?}")));
+    }
+    let mut schemes = SCHEMES.lock().unwrap();
+    if ["unix", "ws", "mem"].contains(&name) || schemes.contains_key(name) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{name}:// is taken")));
+    }
+    schemes.insert(name.into(), Arc::new(scheme));
+    Ok(())
+}
+
+/// The registered scheme of `addr`, if it has one.
+fn registered(addr: &str) -> Option<Arc<dyn Scheme>> {
+    let (name, _) = addr.split_once("://")?;
+    SCHEMES.lock().unwrap().get(name).cloned()
+}
+
+/// The registered scheme of an `Addr::Custom`.
+fn scheme_of(addr: &str) -> io::Result<Arc<dyn Scheme>> {
+    registered(addr).ok_or_else(|| io::Error::new(
+        io::ErrorKind::Unsupported, format!("{addr}: no transport registered for it"),
+    ))
+}
+
+/// A resolved orchestrator address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(feature = "ws")]
+    Ws(ws::Url),
+    Mem(String),
+    /// Through a registered `Scheme`; the whole address.
+    Custom(String),
+}
+
+impl Addr {
+    /// Parse `unix://<path>`, `ws://host:port[/path]`, `mem://<name>` or
+    /// an address of a registered scheme, or resolve `host:port` (first
+    /// address wins).
+    pub fn resolve(addr: &str) -> io::Result<Self> {
+        if registered(addr).is_some() {
+            return Ok(Addr::Custom(addr.into()));
+        }
+        if let Some(name) = addr.strip_prefix(MEM_SCHEME) {
+            return match name {
+                "" => Err(io::Error::new(io::ErrorKind::InvalidInput, "empty in-memory listener name")),
+                _ => Ok(Addr::Mem(name.into())),
+            };
+        }
+        if addr.starts_with("wss://") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{addr}: no TLS here; terminate wss:// in a proxy and point it at a ws:// listener"),
+            ));
+        }
+        if addr.starts_with(WS_SCHEME) {
+            #[cfg(feature = "ws")]
+            return ws::Url::parse(addr).map(Addr::Ws);
+            #[cfg(not(feature = "ws"))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{addr}: built without the `ws` feature"),
+            ));
+        }
+        if let Some(path) = addr.strip_prefix(UNIX_SCHEME) {
+            #[cfg(unix)]
+            return match path {
+                "" => Err(io::Error::new(io::ErrorKind::InvalidInput, "empty unix socket path")),
+                _ => Ok(Addr::Unix(path.into())),
+            };
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{path}: unix sockets are not available on this platform"),
+            ));
+        }
+        addr.to_socket_addrs()?
+            .next()
+            .map(Addr::Tcp)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve address"))
+    }
+
+    /// Connect, giving up on TCP after `timeout` if one is given.
+    pub fn connect(&self, timeout: Option<Duration>) -> io::Result<Stream> {
+        match self {
+            Addr::Tcp(a) => {
+                let s = match timeout {
+                    Some(t) => TcpStream::connect_timeout(a, t)?,
+                    None => TcpStream::connect(a)?,
+                };
+                s.set_nodelay(true).ok();
+                Ok(Stream::Tcp(s))
+            }
+            #[cfg(unix)]
+            Addr::Unix(p) => UnixStream::connect(p).map(Stream::Unix),
+            #[cfg(feature = "ws")]
+            Addr::Ws(u) => ws::connect(u, timeout).map(Stream::Ws),
+            Addr::Mem(name) => mem::connect(name).map(Stream::Mem),
+            Addr::Custom(a) => scheme_of(a)?.connect(a, timeout).map(Stream::Custom),
+        }
+    }
+
+    /// `connect` with `opts`' timeout, then `Stream::tune`.
+    pub(crate) fn connect_with(&self, opts: &SocketOptions) -> io::Result<Stream> {
+        let s = self.connect(opts.connect_timeout)?;
+        s.tune(opts)?;
+        Ok(s)
+    }
+
+    /// Where the data connection of a session goes, given the `port` of
+    /// the handshake reply. Single-port-only sessions have none; this is
+    /// the address itself.
+    pub fn data(&self, port: u16) -> Addr {
+        match self {
+            Addr::Tcp(a) => Addr::Tcp(SocketAddr::new(a.ip(), port)),
+            #[cfg(unix)]
+            Addr::Unix(p) => Addr::Unix(session_path(p, port)),
+            #[cfg(feature = "ws")]
+            Addr::Ws(u) => Addr::Ws(u.clone()),
+            Addr::Mem(name) => Addr::Mem(mem::session_name(name, port)),
+            Addr::Custom(a) => Addr::Custom(a.clone()),
+        }
+    }
+
+    /// Whether this is a WebSocket address.
+    pub fn is_ws(&self) -> bool {
+        #[cfg(feature = "ws")]
+        if let Addr::Ws(_) = self {
+            return true;
+        }
+        false
+    }
+
+    /// Whether sessions here must be single-port: WebSocket ones, and
+    /// those of registered schemes, have no data connection.
+    pub fn single_port_only(&self) -> bool {
+        self.is_ws() || matches!(self, Addr::Custom(_))
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Tcp(a) => write!(f, "{a}"),
+            #[cfg(unix)]
+            Addr::Unix(p) => write!(f, "{UNIX_SCHEME}{}", p.display()),
+            #[cfg(feature = "ws")]
+            Addr::Ws(u) => write!(f, "{u}"),
+            Addr::Mem(name) => write!(f, "{MEM_SCHEME}{name}"),
+            Addr::Custom(a) => write!(f, "{a}"),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn session_path(ctrl: &std::path::Path, n: u16) -> PathBuf {
+    let mut p = ctrl.as_os_str().to_owned();
+    p.push(format!(".{n}"));
+    p.into()
+}
+
+/// A connection over any transport.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "ws")]
+    Ws(WsStream),
+    Mem(MemStream),
+    Custom(Box<dyn Transport>),
+}
+
+/// Dispatch a method that every stream type has. Registered transports
+/// read and write through `&self` already, so the `&Stream` impls give them
+/// an expression of their own.
+macro_rules! each {
+    ($self:expr, $s:ident => $e:expr) => {
+        each!($self, $s => $e, $s => $e)
+    };
+    ($self:expr, $s:ident => $e:expr, $c:ident => $ce:expr) => {
+        match $self {
+            Stream::Tcp($s) => $e,
+            #[cfg(unix)]
+            Stream::Unix($s) => $e,
+            #[cfg(feature = "ws")]
+            Stream::Ws($s) => $e,
+            Stream::Mem($s) => $e,
+            Stream::Custom($c) => $ce,
+        }
+    };
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.try_clone().map(Stream::Unix),
+            #[cfg(feature = "ws")]
+            Stream::Ws(s) => s.try_clone().map(Stream::Ws),
+            Stream::Mem(s) => s.try_clone().map(Stream::Mem),
+            Stream::Custom(s) => s.try_clone().map(Stream::Custom),
+        }
+    }
+
+    /// Whether this is a WebSocket connection.
+    pub fn is_ws(&self) -> bool {
+        #[cfg(feature = "ws")]
+        if let Stream::Ws(_) = self {
+            return true;
+        }
+        false
+    }
+
+    /// Whether this connection's sessions must be single-port (see
+    /// `Addr::single_port_only`).
+    pub fn single_port_only(&self) -> bool {
+        self.is_ws() || matches!(self, Stream::Custom(_))
+    }
+
+    /// Serve the WebSocket opening handshake on a TCP connection accepted
+    /// by a WebSocket listener (see `Listener::is_ws`).
+    pub fn upgrade_ws(self) -> io::Result<Self> {
+        match self {
+            #[cfg(feature = "ws")]
+            Stream::Tcp(s) => ws::accept(s).map(Stream::Ws),
+            #[cfg(not(feature = "ws"))]
+            Stream::Tcp(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "built without the `ws` feature")),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "only TCP connections upgrade to WebSocket")),
+        }
+    }
+
+    pub fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        each!(self, s => s.set_read_timeout(t))
+    }
+
+    pub fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        each!(self, s => s.set_write_timeout(t))
+    }
+
+    pub fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        each!(self, s => s.set_nonblocking(on))
+    }
+
+    /// Disable Nagle on TCP; Unix sockets and pipes don't batch, so it's a
+    /// no-op, as it is for registered transports.
+    pub fn set_nodelay(&self, on: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_nodelay(on),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+            #[cfg(feature = "ws")]
+            Stream::Ws(s) => s.set_nodelay(on),
+            Stream::Mem(_) => Ok(()),
+            Stream::Custom(_) => Ok(()),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        each!(self, s => s.shutdown(how))
+    }
+
+    /// Apply `opts`' keepalive and buffer sizes to a TCP connection; other
+    /// transports have no such knobs, and are left as they are.
+    pub(crate) fn tune(&self, opts: &SocketOptions) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => tune_tcp(s, opts),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read into `buf` without consuming, like `TcpStream::peek`.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.peek(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => unix_peek(s, buf),
+            #[cfg(feature = "ws")]
+            Stream::Ws(s) => s.peek(buf),
+            Stream::Mem(s) => s.peek(buf),
+            Stream::Custom(s) => s.peek(buf),
+        }
+    }
+
+    /// The descriptor to wait on for this stream to become readable, if
+    /// its reads come straight off a socket: WebSocket streams buffer
+    /// frames, and in-process and registered ones have none.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn poll_fd(&self) -> Option<RawFd> {
+        match self {
+            Stream::Tcp(s) => Some(s.as_raw_fd()),
+            Stream::Unix(s) => Some(s.as_raw_fd()),
+            _ => None,
+        }
+    }
+
+    /// Whether a read would return at once, with data or with the peer
+    /// gone, for a stream with a `poll_fd`; true for any other.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn readable(&self) -> io::Result<bool> {
+        use std::ffi::{c_int, c_void};
+        const MSG_PEEK: c_int = 2;
+        const MSG_DONTWAIT: c_int = 0x40;
+        unsafe extern "C" {
+            fn recv(fd: c_int, buf: *mut c_void, len: usize, flags: c_int) -> isize;
+        }
+        let Some(fd) = self.poll_fd() else {
+            return Ok(true);
+        };
+        let mut byte = 0u8;
+        loop {
+            // SAFETY: recv writes at most one byte, into `byte`.
+            let n = unsafe { recv(fd, &mut byte as *mut u8 as *mut c_void, 1, MSG_PEEK | MSG_DONTWAIT) };
+            if n >= 0 {
+                return Ok(true);
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::WouldBlock => return Ok(false),
+                io::ErrorKind::Interrupted => continue,
+                // The read reports it.
+                _ => return Ok(true),
+            }
+        }
+    }
+
+    /// The other end, for logs: `ip:port`, or `unix` for a Unix socket
+    /// (whose clients are rarely bound to a name), `mem` in-process.
+    pub fn peer(&self) -> String {
+        match self {
+            Stream::Tcp(s) => s.peer_addr().map_or("<unknown>".into(), |a| a.to_string()),
+            #[cfg(unix)]
+            Stream::Unix(_) => "unix".into(),
+            #[cfg(feature = "ws")]
+            Stream::Ws(s) => s.peer_addr().map_or("<unknown>".into(), |a| format!("ws {a}")),
+            Stream::Mem(_) => "mem".into(),
+            Stream::Custom(s) => s.peer(),
+        }
+    }
+
+    /// The local end; for a stream accepted on a Unix socket, that
+    /// socket's path, and in-process the listener's name. Registered
+    /// transports don't say, having no data connections to place.
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        match self {
+            Stream::Tcp(s) => s.local_addr().map(Addr::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.local_addr()?.as_pathname()
+                .map(|p| Addr::Unix(p.to_path_buf()))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unnamed unix socket")),
+            #[cfg(feature = "ws")]
+            Stream::Ws(s) => s.local_addr().map(Addr::Tcp),
+            Stream::Mem(s) => Ok(Addr::Mem(s.name().into())),
+            Stream::Custom(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported, "registered transports have no local address",
+            )),
+        }
+    }
+}
+
+/// How clients set up their connections (`ProducerOptions::keepalive` and
+/// the like). Unset fields leave the system's defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct SocketOptions {
+    /// Bounds the TCP connect, and then each read and write of the
+    /// handshake.
+    pub(crate) connect_timeout: Option<Duration>,
+    /// Idle time before TCP keepalive probes, and between them.
+    pub(crate) keepalive:       Option<Duration>,
+    pub(crate) send_buffer:     Option<usize>,
+    pub(crate) recv_buffer:     Option<usize>,
+}
+
+/// Socket option numbers, which differ between systems.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sockopt {
+    use std::ffi::c_int;
+    pub const SOL_SOCKET:    c_int = 1;
+    pub const SO_KEEPALIVE:  c_int = 9;
+    pub const SO_SNDBUF:     c_int = 7;
+    pub const SO_RCVBUF:     c_int = 8;
+    pub const TCP_KEEPIDLE:  c_int = 4;
+    pub const TCP_KEEPINTVL: c_int = 5;
+    pub const SO_REUSEADDR:  c_int = 2;
+    pub const AF_INET6:      c_int = 10;
+    pub const IPV6_V6ONLY:   c_int = 26;
+}
+
+#[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+mod sockopt {
+    use std::ffi::c_int;
+    pub const SOL_SOCKET:    c_int = 0xffff;
+    pub const SO_KEEPALIVE:  c_int = 0x8;
+    pub const SO_SNDBUF:     c_int = 0x1001;
+    pub const SO_RCVBUF:     c_int = 0x1002;
+    /// TCP_KEEPALIVE on Apple systems.
+    #[cfg(target_vendor = "apple")]
+    pub const TCP_KEEPIDLE:  c_int = 0x10;
+    #[cfg(target_os = "freebsd")]
+    pub const TCP_KEEPIDLE:  c_int = 0x100;
+    #[cfg(target_vendor = "apple")]
+    pub const TCP_KEEPINTVL: c_int = 0x101;
+    #[cfg(target_os = "freebsd")]
+    pub const TCP_KEEPINTVL: c_int = 0x200;
+    pub const SO_REUSEADDR:  c_int = 0x4;
+    #[cfg(target_vendor = "apple")]
+    pub const AF_INET6:      c_int = 30;
+    #[cfg(target_os = "freebsd")]
+    pub const AF_INET6:      c_int = 28;
+    pub const IPV6_V6ONLY:   c_int = 27;
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple", target_os = "freebsd"))]
+fn tune_tcp(s: &TcpStream, opts: &SocketOptions) -> io::Result<()> {
+    use std::ffi::{c_int, c_void};
+    use std::os::fd::AsRawFd;
+    use sockopt::*;
+    const IPPROTO_TCP: c_int = 6;
+    unsafe extern "C" {
+        fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, len: u32) -> c_int;
+    }
+    let set = |level, name, value: c_int| {
+        // SAFETY: `value` is a live c_int, and its size is passed along.
+        let rc = unsafe {
+            setsockopt(s.as_raw_fd(), level, name, &value as *const c_int as *const c_void, size_of::<c_int>() as u32)
+        };
+        if rc < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    };
+    let clamp = |n: usize| c_int::try_from(n).unwrap_or(c_int::MAX);
+    if let Some(n) = opts.send_buffer {
+        set(SOL_SOCKET, SO_SNDBUF, clamp(n))?;
+    }
+    if let Some(n) = opts.recv_buffer {
+        set(SOL_SOCKET, SO_RCVBUF, clamp(n))?;
+    }
+    if let Some(idle) = opts.keepalive {
+        let secs = clamp(idle.as_secs().max(1) as usize);
+        set(SOL_SOCKET, SO_KEEPALIVE, 1)?;
+        set(IPPROTO_TCP, TCP_KEEPIDLE, secs)?;
+        set(IPPROTO_TCP, TCP_KEEPINTVL, secs)?;
+    }
+    Ok(())
+}
+
+/// A TCP listener on an IPv6 address that takes no IPv4 clients, so that
+/// an IPv4 listener can have the same port.
+#[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple", target_os = "freebsd"))]
+fn bind_v6_only(addr: SocketAddrV6) -> io::Result<TcpListener> {
+    use std::ffi::{c_int, c_void};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use sockopt::*;
+    const SOCK_STREAM: c_int = 1;
+    const IPPROTO_IPV6: c_int = 41;
+    const F_SETFD: c_int = 2;
+    const FD_CLOEXEC: c_int = 1;
+    unsafe extern "C" {
+        fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+        fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, len: u32) -> c_int;
+        fn bind(fd: c_int, addr: *const c_void, len: u32) -> c_int;
+        fn listen(fd: c_int, backlog: c_int) -> c_int;
+        fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    }
+    /// `struct sockaddr_in6`.
+    #[repr(C)]
+    struct SockaddrIn6 {
+        #[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+        len:      u8,
+        #[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+        family:   u8,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        family:   u16,
+        port:     [u8; 2],
+        flowinfo: u32,
+        addr:     [u8; 16],
+        scope_id: u32,
+    }
+    let check = |rc: c_int| if rc < 0 { Err(io::Error::last_os_error()) } else { Ok(rc) };
+    // SAFETY: a new descriptor, owned from here on.
+    let fd = unsafe { OwnedFd::from_raw_fd(check(socket(AF_INET6, SOCK_STREAM, 0))?) };
+    let on: c_int = 1;
+    let sa = SockaddrIn6 {
+        #[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+        len:      size_of::<SockaddrIn6>() as u8,
+        family:   AF_INET6 as _,
+        port:     addr.port().to_be_bytes(),
+        flowinfo: addr.flowinfo().to_be(),
+        addr:     addr.ip().octets(),
+        scope_id: addr.scope_id(),
+    };
+    // SAFETY: `fd` is open; `on` and `sa` are live, and their sizes are
+    // passed along.
+    unsafe {
+        check(fcntl(fd.as_raw_fd(), F_SETFD, FD_CLOEXEC))?;
+        for name in [SO_REUSEADDR, IPV6_V6ONLY] {
+            let level = if name == IPV6_V6ONLY { IPPROTO_IPV6 } else { SOL_SOCKET };
+            check(setsockopt(fd.as_raw_fd(), level, name, &on as *const c_int as *const c_void, size_of::<c_int>() as u32))?;
+        }
+        check(bind(fd.as_raw_fd(), &sa as *const SockaddrIn6 as *const c_void, size_of::<SockaddrIn6>() as u32))?;
+        check(listen(fd.as_raw_fd(), 128))?;
+    }
+    Ok(TcpListener::from(fd))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple", target_os = "freebsd")))]
+fn bind_v6_only(addr: SocketAddrV6) -> io::Result<TcpListener> {
+    TcpListener::bind(addr)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple", target_os = "freebsd")))]
+fn tune_tcp(_: &TcpStream, opts: &SocketOptions) -> io::Result<()> {
+    match (opts.keepalive, opts.send_buffer, opts.recv_buffer) {
+        (None, None, None) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported, "keepalive and buffer sizes are not supported on this platform",
+        )),
+    }
+}
+
+#[cfg(unix)]
+fn unix_peek(s: &UnixStream, buf: &mut [u8]) -> io::Result<usize> {
+    use std::ffi::{c_int, c_void};
+    use std::os::fd::AsRawFd;
+    const MSG_PEEK: c_int = 2;
+    unsafe extern "C" {
+        fn recv(fd: c_int, buf: *mut c_void, len: usize, flags: c_int) -> isize;
+    }
+    // SAFETY: recv writes at most `buf.len()` bytes into `buf`.
+    let n = unsafe { recv(s.as_raw_fd(), buf.as_mut_ptr() as *mut c_void, buf.len(), MSG_PEEK) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        each!(self, s => s.read(buf))
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        each!(*self, s => (&*s).read(buf), s => s.read(buf))
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        each!(self, s => s.write(buf))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        each!(self, s => s.flush())
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        each!(*self, s => (&*s).write(buf), s => s.write(buf))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        each!(*self, s => (&*s).flush(), s => s.flush())
+    }
+}
+
+/// A listening socket over any transport. A Unix socket's file is
+/// removed when its listener is dropped. A WebSocket listener accepts
+/// plain TCP connections; `Stream::upgrade_ws` serves the handshake, off
+/// the accept loop.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+    /// A Unix socket a service manager passed in (see `systemd`): its
+    /// file is the manager's, and stays when the listener is dropped.
+    #[cfg(unix)]
+    UnixActivated(UnixListener, PathBuf),
+    #[cfg(feature = "ws")]
+    Ws(TcpListener),
+    Mem(MemListener),
+    Custom(Box<dyn Acceptor>),
+}
+
+/// The last session number handed out on a Unix socket.
+#[cfg(unix)]
+static SESSIONS: AtomicU16 = AtomicU16::new(0);
+
+impl Listener {
+    /// Listen on `addr`. A Unix socket file left by an earlier run is
+    /// replaced, unless something still answers on it.
+    pub fn bind(addr: &Addr) -> io::Result<Self> {
+        match addr {
+            Addr::Tcp(a) => TcpListener::bind(a).map(Listener::Tcp),
+            #[cfg(unix)]
+            Addr::Unix(p) => {
+                if p.exists() {
+                    if UnixStream::connect(p).is_ok() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse, format!("{}: already listening", p.display()),
+                        ));
+                    }
+                    std::fs::remove_file(p)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(p)?, p.clone()))
+            }
+            #[cfg(feature = "ws")]
+            Addr::Ws(u) => TcpListener::bind(u.addr).map(Listener::Ws),
+            Addr::Mem(name) => MemListener::bind(name).map(Listener::Mem),
+            Addr::Custom(a) => scheme_of(a)?.bind(a).map(Listener::Custom),
+        }
+    }
+
+    /// Listen on every one of `addrs`, e.g. an IPv4 and an IPv6 address,
+    /// or an internal and an external interface. An IPv6 address whose
+    /// port an IPv4 one shares takes IPv6 clients only, so the two don't
+    /// both claim IPv4 ones; any other IPv6 address is dual-stack or not
+    /// as the system has it. Nothing stays bound if one of them fails.
+    pub fn bind_all(addrs: &[Addr]) -> io::Result<Vec<Self>> {
+        let v4_ports: Vec<u16> = addrs.iter()
+            .filter_map(|a| match a {
+                Addr::Tcp(SocketAddr::V4(a)) => Some(a.port()),
+                _ => None,
+            })
+            .collect();
+        addrs.iter()
+            .map(|addr| match addr {
+                Addr::Tcp(SocketAddr::V6(a)) if v4_ports.contains(&a.port()) => {
+                    bind_v6_only(*a).map(Listener::Tcp)
+                }
+                addr => Listener::bind(addr),
+            }.map_err(|e| io::Error::new(e.kind(), format!("{addr}: {e}"))))
+            .collect()
+    }
+
+    /// Whether this listener takes WebSocket clients.
+    pub fn is_ws(&self) -> bool {
+        #[cfg(feature = "ws")]
+        if let Listener::Ws(_) = self {
+            return true;
+        }
+        false
+    }
+
+    /// Listen for one session's data connection alongside the control
+    /// socket `ctrl`; returns the listener and the `port` for the reply.
+    pub fn bind_session(ctrl: &Addr) -> io::Result<(Self, u16)> {
+        match ctrl {
+            Addr::Tcp(a) => {
+                let l = TcpListener::bind(SocketAddr::new(a.ip(), 0))?;
+                let port = l.local_addr()?.port();
+                Ok((Listener::Tcp(l), port))
+            }
+            #[cfg(unix)]
+            Addr::Unix(p) => {
+                // Numbers wrap; skip any still taken by a waiting session
+                // (or left behind by a crash).
+                for _ in 0..64 {
+                    let n = SESSIONS.fetch_add(1, Ordering::Relaxed).wrapping_add(1).max(1);
+                    let path = session_path(p, n);
+                    match UnixListener::bind(&path) {
+                        Ok(l) => return Ok((Listener::Unix(l, path), n)),
+                        Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                Err(io::Error::new(io::ErrorKind::AddrInUse, "no free session socket"))
+            }
+            #[cfg(feature = "ws")]
+            Addr::Ws(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported, "websocket sessions have no data connection",
+            )),
+            Addr::Mem(name) => MemListener::bind_session(name).map(|(l, n)| (Listener::Mem(l), n)),
+            Addr::Custom(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported, "registered transports have no data connection",
+            )),
+        }
+    }
+
+    /// Accept a connection; also returns the peer for logs (see
+    /// `Stream::peer`).
+    pub fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(l) => l.accept().map(|(s, a)| (Stream::Tcp(s), a.to_string())),
+            #[cfg(unix)]
+            Listener::Unix(l, _) | Listener::UnixActivated(l, _) => {
+                l.accept().map(|(s, _)| (Stream::Unix(s), "unix".into()))
+            }
+            #[cfg(feature = "ws")]
+            Listener::Ws(l) => l.accept().map(|(s, a)| (Stream::Tcp(s), format!("ws {a}"))),
+            Listener::Mem(l) => l.accept().map(|s| (Stream::Mem(s), "mem".into())),
+            Listener::Custom(l) => l.accept().map(|(s, peer)| (Stream::Custom(s), peer)),
+        }
+    }
+
+    pub fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(l) => l.set_nonblocking(on),
+            #[cfg(unix)]
+            Listener::Unix(l, _) | Listener::UnixActivated(l, _) => l.set_nonblocking(on),
+            #[cfg(feature = "ws")]
+            Listener::Ws(l) => l.set_nonblocking(on),
+            Listener::Mem(l) => l.set_nonblocking(on),
+            Listener::Custom(l) => l.set_nonblocking(on),
+        }
+    }
+
+    /// The descriptor to wait on for a connection to accept, for the
+    /// listeners the kernel accepts on.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn poll_fd(&self) -> Option<RawFd> {
+        match self {
+            Listener::Tcp(l) => Some(l.as_raw_fd()),
+            Listener::Unix(l, _) | Listener::UnixActivated(l, _) => Some(l.as_raw_fd()),
+            #[cfg(feature = "ws")]
+            Listener::Ws(l) => Some(l.as_raw_fd()),
+            _ => None,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        match self {
+            Listener::Tcp(l) => l.local_addr().map(Addr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, p) | Listener::UnixActivated(_, p) => Ok(Addr::Unix(p.clone())),
+            #[cfg(feature = "ws")]
+            Listener::Ws(l) => Ok(Addr::Ws(ws::Url::parse(&format!("{WS_SCHEME}{}/", l.local_addr()?))?)),
+            Listener::Mem(l) => Ok(Addr::Mem(l.name().into())),
+            Listener::Custom(l) => l.local_addr().map(Addr::Custom),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, p) = self {
+            std::fs::remove_file(p).ok();
+        }
+    }
+}
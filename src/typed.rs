@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Typed producers and consumers for serde payloads, built with the `serde`
+//! feature. They wrap `Producer` / `Consumer` and serialize each value into
+//! one message, so nothing changes on the wire.
+//!
+//! The format is a type parameter. The default, `MsgPack`, writes MessagePack
+//! maps with field names (`rmp_serde::to_vec_named`), which the CLI tools
+//! and the Nushell integration read as they are, and which tolerate fields
+//! being added on one side first. Other formats (bincode, JSON, ...)
+//! implement `Format` over the crate of their choice.
+//!
+//! A message that doesn't decode as `T` is an `InvalidData` error from
+//! `recv`. It has been received by then: a plain consumer has settled it,
+//! and in ack mode, asked for or imposed by an at-least-once queue, it is
+//! left unacked, so it is retried and eventually dead-lettered like any
+//! message its consumer can't process.
+
+use std::io;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{ConnectOptions, Consumer, Headers, Producer, ProducerOptions};
+
+/// How a typed producer or consumer turns values into payloads and back.
+pub trait Format {
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> io::Result<T>;
+}
+
+/// MessagePack with named fields (see the module docs).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgPack;
+
+impl Format for MsgPack {
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(|e| io::Error::new(
+            io::ErrorKind::InvalidInput, format!("msgpack encode: {e}"),
+        ))
+    }
+
+    fn decode<T: DeserializeOwned>(payload: &[u8]) -> io::Result<T> {
+        rmp_serde::from_slice(payload).map_err(|e| io::Error::new(
+            io::ErrorKind::InvalidData, format!("msgpack decode: {e}"),
+        ))
+    }
+}
+
+/// A `Producer` that sends values of type `T`.
+pub struct TypedProducer<T, F = MsgPack> {
+    inner:  Producer,
+    _types: PhantomData<fn(&T, F)>,
+}
+
+impl<T: Serialize, F: Format> TypedProducer<T, F> {
+    pub fn connect(orchestrator: &str) -> io::Result<Self> {
+        Producer::connect(orchestrator).map(Self::new)
+    }
+
+    /// Connect with producer options (buffering, delta encoding, queue...).
+    pub fn connect_with(orchestrator: &str, opts: &ProducerOptions) -> io::Result<Self> {
+        Producer::connect_with(orchestrator, opts).map(Self::new)
+    }
+
+    /// Wrap a connected producer.
+    pub fn new(inner: Producer) -> Self {
+        Self { inner, _types: PhantomData }
+    }
+
+    pub fn send(&mut self, value: &T) -> io::Result<()> {
+        self.inner.send(&F::encode(value)?)
+    }
+
+    /// See `Producer::send_with_priority`.
+    pub fn send_with_priority(&mut self, value: &T, priority: u8) -> io::Result<()> {
+        self.inner.send_with_priority(&F::encode(value)?, priority)
+    }
+
+    /// See `Producer::send_with_key`.
+    pub fn send_with_key(&mut self, value: &T, key: &[u8]) -> io::Result<()> {
+        self.inner.send_with_key(&F::encode(value)?, key)
+    }
+
+    /// Send with application headers; see `Producer::send_with_headers`.
+    pub fn send_with_headers(&mut self, value: &T, headers: &Headers) -> io::Result<()> {
+        self.inner.send_with_headers(&F::encode(value)?, headers)
+    }
+
+    /// See `Producer::close`.
+    pub fn close(self) -> io::Result<()> {
+        self.inner.close()
+    }
+
+    /// The untyped producer, for EOS markers, priorities and the like.
+    pub fn get_mut(&mut self) -> &mut Producer {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> Producer {
+        self.inner
+    }
+}
+
+/// A `Consumer` that receives values of type `T`.
+pub struct TypedConsumer<T, F = MsgPack> {
+    inner:  Consumer,
+    _types: PhantomData<fn(F) -> T>,
+}
+
+impl<T: DeserializeOwned, F: Format> TypedConsumer<T, F> {
+    pub fn connect(orchestrator: &str) -> io::Result<Self> {
+        Consumer::connect(orchestrator).map(Self::new)
+    }
+
+    /// Connect with session options (ack mode, queue, capabilities...).
+    pub fn connect_with(orchestrator: &str, opts: &ConnectOptions) -> io::Result<Self> {
+        Consumer::connect_with(orchestrator, opts).map(Self::new)
+    }
+
+    /// Wrap a connected consumer.
+    pub fn new(inner: Consumer) -> Self {
+        Self { inner, _types: PhantomData }
+    }
+
+    /// Blocks until the next value; see `Consumer::recv`.
+    pub fn recv(&mut self) -> io::Result<T> {
+        let msg = self.inner.recv()?;
+        self.decode(&msg.payload)
+    }
+
+    /// See `Consumer::recv_timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<T>> {
+        self.inner.recv_timeout(timeout)?.map(|m| self.decode(&m.payload)).transpose()
+    }
+
+    /// See `Consumer::try_recv`.
+    pub fn try_recv(&mut self) -> io::Result<Option<T>> {
+        self.inner.try_recv()?.map(|m| self.decode(&m.payload)).transpose()
+    }
+
+    /// The value `recv` and the like received; one that doesn't decode is left
+    /// unacked if the queue imposed ack mode (see the module docs).
+    fn decode(&mut self, payload: &[u8]) -> io::Result<T> {
+        F::decode(payload).inspect_err(|_| self.inner.disown())
+    }
+
+    /// Ack mode: the next value and the tag to pass to `ack`.
+    pub fn recv_ack(&mut self) -> io::Result<(T, u64)> {
+        let msg = self.inner.recv_ack()?;
+        let tag = msg.tag.ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData, "ack-mode delivery without a tag",
+        ))?;
+        Ok((F::decode(&msg.payload)?, tag))
+    }
+
+    /// Ack mode: see `Consumer::ack`.
+    pub fn ack(&mut self, tag: u64) -> io::Result<()> {
+        self.inner.ack(tag)
+    }
+
+    /// Ack mode: see `Consumer::nack`.
+    pub fn nack(&mut self, tag: u64) -> io::Result<()> {
+        self.inner.nack(tag)
+    }
+
+    /// See `Consumer::close`.
+    pub fn close(self) -> io::Result<()> {
+        self.inner.close()
+    }
+
+    /// The untyped consumer, for `recv_ext`, partial-message GC and the like.
+    pub fn get_mut(&mut self) -> &mut Consumer {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> Consumer {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct V1 { id: u64, name: String }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct V2 { id: u64, name: String, #[serde(default)] tags: Vec<String> }
+
+    #[test]
+    fn msgpack_keeps_field_names_and_rejects_garbage() {
+        let v = V1 { id: 7, name: "alice".into() };
+        let bytes = MsgPack::encode(&v).unwrap();
+        assert!(bytes.windows(4).any(|w| w == b"name"), "named fields");
+        assert_eq!(MsgPack::decode::<V1>(&bytes).unwrap(), v);
+        // A reader that has moved on still reads what older writers send.
+        let v2: V2 = MsgPack::decode(&bytes).unwrap();
+        assert_eq!((v2.id, v2.tags.len()), (7, 0));
+        let err = MsgPack::decode::<V1>(b"\xc1 not msgpack").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,504 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! Write-ahead log behind an orchestrator queue (`--wal-dir`).
+//!
+//! Every frame a queue accepts is appended here before its producer gets
+//! the ACK, and marked done once it settles — delivered, dead-lettered,
+//! exported or dropped. An orchestrator that restarts on the same directory
+//! replays whatever was not done, so undelivered frames survive a crash.
+//!
+//! Segments are numbered files (`<n>.wal`) of records, rolled over every
+//! SEGMENT_BYTES:
+//!
+//!   push: `[u8 'P'][u64 BE lsn][u32 BE len][frame in put_frame format]`
+//!   done: `[u8 'D'][u64 BE lsn]`
+//!
+//! With an at-rest key the frame bytes of a push are sealed (see
+//! `at_rest`), and its kind is `S` instead.
+//!
+//! Log sequence numbers (lsn) count up across restarts. A done record only
+//! ever follows its push, so segments are deleted oldest first, once none
+//! of their pushes is live. On rollover the oldest segment is compacted if
+//! its live pushes take up at most a quarter of it: they are copied to the
+//! current segment and it goes, so one slow message can't pin the log.
+//! Each record is a single write, so a crash leaves at most a torn tail,
+//! which is ignored.
+//!
+//! Records reach the operating system as they are written, which is enough
+//! to survive the orchestrator dying; segments are synced to disk only as
+//! they are rolled over.
+//!
+//! A `tap` sees every push and done record as it is appended, so a primary
+//! can ship them to a standby orchestrator, which `apply`s them to a log
+//! of its own: the standby's log replays what the primary's would.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::at_rest::{self, Key};
+use crate::{get_frame, put_frame, Frame, Meta};
+
+/// Roll over to a new segment once the current one is this big.
+const SEGMENT_BYTES: u64 = 16 << 20;
+
+/// Associated data binding sealed frames to the log format.
+const WAL_AAD: &[u8] = b"qpipe-wal-v1";
+
+const KIND_PUSH: u8   = b'P';
+const KIND_SEALED: u8 = b'S';
+const KIND_DONE: u8   = b'D';
+
+/// One record read back from a segment; pushes keep their kind and their
+/// frame bytes as written.
+enum Record {
+    Push(u64, u8, Vec<u8>),
+    Done(u64),
+}
+
+impl Record {
+    /// Read one record; `None` at a clean end or a torn tail.
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Option<Self>> {
+        let mut head = [0u8; 9];
+        if read_full(r, &mut head)? < head.len() {
+            return Ok(None);
+        }
+        let lsn = u64::from_be_bytes(head[1..].try_into().unwrap());
+        match head[0] {
+            KIND_DONE => Ok(Some(Record::Done(lsn))),
+            kind @ (KIND_PUSH | KIND_SEALED) => {
+                let mut len = [0u8; 4];
+                if read_full(r, &mut len)? < len.len() {
+                    return Ok(None);
+                }
+                let mut body = vec![0u8; u32::from_be_bytes(len) as usize];
+                if read_full(r, &mut body)? < body.len() {
+                    return Ok(None);
+                }
+                Ok(Some(Record::Push(lsn, kind, body)))
+            }
+            k => Err(io::Error::new(
+                io::ErrorKind::InvalidData, format!("unknown write-ahead log record kind {k:#04x}"),
+            )),
+        }
+    }
+}
+
+fn push_record(kind: u8, lsn: u64, body: &[u8]) -> Vec<u8> {
+    let mut rec = Vec::with_capacity(13 + body.len());
+    rec.push(kind);
+    rec.extend_from_slice(&lsn.to_be_bytes());
+    rec.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    rec.extend_from_slice(body);
+    rec
+}
+
+/// `read_exact` that reports how much it got instead of failing on EOF.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut got = 0;
+    while got < buf.len() {
+        match r.read(&mut buf[got..]) {
+            Ok(0) => break,
+            Ok(n) => got += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(got)
+}
+
+/// A frame replayed from the log, with its lsn.
+pub type Replayed = (u64, Frame, Meta);
+
+/// What `Wal::tap` hands records to.
+type Tap = Box<dyn FnMut(&[u8]) + Send>;
+
+/// A segment's size, and how much of it is live push records.
+#[derive(Clone, Copy, Debug, Default)]
+struct Usage {
+    size: u64,
+    live: u64,
+}
+
+pub struct Wal {
+    dir:      PathBuf,
+    key:      Option<Key>,
+    segment_bytes: u64,
+    /// Segments on disk, oldest first.
+    segs:     BTreeMap<u64, Usage>,
+    /// The segment being appended to, and its size so far.
+    writer:   Option<(u64, File, u64)>,
+    /// Live lsns: the segment holding each one's push record, and its size.
+    live:     HashMap<u64, (u64, u64)>,
+    next_lsn: u64,
+    /// Called with every record appended by `push` and `done`.
+    tap:      Option<Tap>,
+}
+
+impl Wal {
+    /// Open (creating if needed) a log directory and replay it: the frames
+    /// pushed and not done, in the order they were pushed, with their lsns.
+    /// Chunked messages missing chunks — some were delivered, or their
+    /// producer went away mid-message — can never complete, and are marked
+    /// done instead; the second value counts their frames.
+    pub fn open(dir: &Path, key: Option<Key>) -> io::Result<(Self, Vec<Replayed>, usize)> {
+        fs::create_dir_all(dir)?;
+        let mut nums: Vec<u64> = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            if let Some(n) = name.to_string_lossy().strip_suffix(".wal").and_then(|n| n.parse().ok()) {
+                nums.push(n);
+            }
+        }
+        nums.sort_unstable();
+        let mut wal = Self {
+            dir: dir.to_path_buf(), key, segment_bytes: SEGMENT_BYTES,
+            segs: BTreeMap::new(), writer: None, live: HashMap::new(), next_lsn: 1, tap: None,
+        };
+
+        // A push copied forward by a compaction that was cut short can
+        // show up twice; the later copy is its home.
+        let mut pending: BTreeMap<u64, (u8, Vec<u8>)> = BTreeMap::new();
+        for &seg in &nums {
+            let size = fs::metadata(wal.seg_path(seg))?.len();
+            wal.segs.insert(seg, Usage { size, live: 0 });
+            let mut r = BufReader::new(File::open(wal.seg_path(seg))?);
+            while let Some(rec) = Record::read_from(&mut r)? {
+                match rec {
+                    Record::Push(lsn, kind, body) => {
+                        wal.live.insert(lsn, (seg, 13 + body.len() as u64));
+                        pending.insert(lsn, (kind, body));
+                        wal.next_lsn = wal.next_lsn.max(lsn + 1);
+                    }
+                    Record::Done(lsn) => {
+                        wal.live.remove(&lsn);
+                        pending.remove(&lsn);
+                        wal.next_lsn = wal.next_lsn.max(lsn + 1);
+                    }
+                }
+            }
+        }
+        for (seg, len) in wal.live.values() {
+            wal.segs.get_mut(seg).expect("segment was read").live += len;
+        }
+        if wal.live.is_empty() {
+            for seg in std::mem::take(&mut wal.segs).into_keys() {
+                fs::remove_file(wal.seg_path(seg))?;
+            }
+        }
+
+        let mut frames = Vec::with_capacity(pending.len());
+        for (lsn, (kind, body)) in pending {
+            let body = match (kind, &wal.key) {
+                (KIND_PUSH, _) => body,
+                (_, Some(key)) => at_rest::open(key, WAL_AAD, &body)?,
+                (_, None) => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "write-ahead log record is encrypted but no key is configured",
+                )),
+            };
+            let (frame, meta) = get_frame(&mut &body[..])?.ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData, format!("write-ahead log record {lsn} is empty"),
+            ))?;
+            frames.push((lsn, frame, meta));
+        }
+        let mut chunks: HashMap<u128, (u32, u32)> = HashMap::new();
+        for (_, frame, _) in &frames {
+            if let Frame::Chunk { id, count, .. } = frame {
+                chunks.entry(*id).or_insert((0, *count)).0 += 1;
+            }
+        }
+        let (whole, broken): (Vec<_>, Vec<_>) = frames.into_iter().partition(|(_, f, _)| match f {
+            Frame::Chunk { id, .. } => chunks[id].0 >= chunks[id].1,
+            _ => true,
+        });
+        for (lsn, ..) in &broken {
+            wal.done(*lsn)?;
+        }
+        Ok((wal, whole, broken.len()))
+    }
+
+    /// Frames pushed and not yet done.
+    pub fn len(&self) -> usize {
+        self.live.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live.is_empty()
+    }
+
+    fn seg_path(&self, seg: u64) -> PathBuf {
+        self.dir.join(format!("{seg:012}.wal"))
+    }
+
+    /// Log one frame the queue has accepted; returns its lsn for `done`.
+    pub fn push(&mut self, frame: &Frame, meta: &Meta) -> io::Result<u64> {
+        let mut body = Vec::new();
+        put_frame(&mut body, frame, meta)?;
+        let kind = match &self.key {
+            Some(key) => {
+                body = at_rest::seal(key, WAL_AAD, &body)?;
+                KIND_SEALED
+            }
+            None => KIND_PUSH,
+        };
+        let lsn = self.next_lsn;
+        self.append_push(lsn, &push_record(kind, lsn, &body))?;
+        Ok(lsn)
+    }
+
+    fn append_push(&mut self, lsn: u64, rec: &[u8]) -> io::Result<()> {
+        let seg = self.write(rec)?;
+        self.next_lsn = self.next_lsn.max(lsn + 1);
+        self.live.insert(lsn, (seg, rec.len() as u64));
+        self.segs.get_mut(&seg).expect("segment is open").live += rec.len() as u64;
+        if let Some(tap) = &mut self.tap {
+            tap(rec);
+        }
+        Ok(())
+    }
+
+    /// Log that frame `lsn` settled: it is not replayed again.
+    pub fn done(&mut self, lsn: u64) -> io::Result<()> {
+        let Some((seg, len)) = self.live.remove(&lsn) else { return Ok(()) };
+        if let Some(usage) = self.segs.get_mut(&seg) {
+            usage.live -= len;
+        }
+        let mut rec = vec![KIND_DONE];
+        rec.extend_from_slice(&lsn.to_be_bytes());
+        self.write(&rec)?;
+        if let Some(tap) = &mut self.tap {
+            tap(&rec);
+        }
+        Ok(())
+    }
+
+    /// Hand every record appended from now on to `f`, as written.
+    pub fn tap(&mut self, f: impl FnMut(&[u8]) + Send + 'static) {
+        self.tap = Some(Box::new(f));
+    }
+
+    /// The push records of the frames not yet done, oldest first, as
+    /// written: with what the tap gets from now on, what a copy of this
+    /// log needs.
+    pub fn live_records(&self) -> io::Result<Vec<Vec<u8>>> {
+        let mut recs = BTreeMap::new();
+        for &seg in self.segs.keys() {
+            let mut r = BufReader::new(File::open(self.seg_path(seg))?);
+            while let Some(rec) = Record::read_from(&mut r)? {
+                if let Record::Push(lsn, kind, body) = rec
+                    && self.live.get(&lsn).is_some_and(|(home, _)| *home == seg)
+                {
+                    recs.insert(lsn, push_record(kind, lsn, &body));
+                }
+            }
+        }
+        Ok(recs.into_values().collect())
+    }
+
+    /// Read one record another log wrote (see `tap`) off `r` and append it
+    /// to this one, lsn and all. A push already live here is skipped, as
+    /// is a done for a frame this log never had.
+    pub fn apply_from<R: Read>(&mut self, r: &mut R) -> io::Result<()> {
+        let rec = Record::read_from(r)?.ok_or_else(|| io::Error::new(
+            io::ErrorKind::UnexpectedEof, "write-ahead log record cut short",
+        ))?;
+        match rec {
+            Record::Push(lsn, _, _) if self.live.contains_key(&lsn) => Ok(()),
+            Record::Push(lsn, kind, body) => self.append_push(lsn, &push_record(kind, lsn, &body)),
+            Record::Done(lsn) => self.done(lsn),
+        }
+    }
+
+    /// Append one record to the current segment, rolling over first if it
+    /// is full. Returns the segment written to.
+    fn write(&mut self, rec: &[u8]) -> io::Result<u64> {
+        if self.writer.as_ref().is_none_or(|(_, _, size)| *size >= self.segment_bytes) {
+            self.roll_over()?;
+        }
+        let (seg, f, size) = self.writer.as_mut().expect("just opened");
+        f.write_all(rec)?; // one write: a crash leaves at most a torn tail
+        *size += rec.len() as u64;
+        let seg = *seg;
+        self.segs.get_mut(&seg).expect("segment is open").size += rec.len() as u64;
+        Ok(seg)
+    }
+
+    /// Start a new segment, then let go of old ones nothing live needs.
+    fn roll_over(&mut self) -> io::Result<()> {
+        if let Some((_, f, _)) = self.writer.take() {
+            f.sync_data()?;
+        }
+        let seg = self.segs.keys().next_back().map_or(1, |s| s + 1);
+        let f = OpenOptions::new().create(true).append(true).open(self.seg_path(seg))?;
+        self.segs.insert(seg, Usage::default());
+        self.writer = Some((seg, f, 0));
+        self.compact()
+    }
+
+    /// Delete the oldest segments while none of their pushes is live,
+    /// copying the few live ones of a mostly settled segment forward.
+    fn compact(&mut self) -> io::Result<()> {
+        let head = self.writer.as_ref().map(|(seg, ..)| *seg);
+        while let Some((&seg, &usage)) = self.segs.first_key_value()
+            && Some(seg) != head
+        {
+            if usage.live * 4 > usage.size {
+                break;
+            }
+            if usage.live > 0 {
+                self.copy_forward(seg)?;
+            }
+            self.segs.remove(&seg);
+            fs::remove_file(self.seg_path(seg))?;
+        }
+        Ok(())
+    }
+
+    /// Re-append the live pushes of `seg` to the current segment.
+    fn copy_forward(&mut self, seg: u64) -> io::Result<()> {
+        let mut r = BufReader::new(File::open(self.seg_path(seg))?);
+        while let Some(rec) = Record::read_from(&mut r)? {
+            if let Record::Push(lsn, kind, body) = rec
+                && self.live.get(&lsn).is_some_and(|(home, _)| *home == seg)
+            {
+                // Written past the rollover size: copying must not roll over.
+                let (head, f, size) = self.writer.as_mut().expect("rolled over");
+                let rec = push_record(kind, lsn, &body);
+                f.write_all(&rec)?;
+                *size += rec.len() as u64;
+                let (head, len) = (*head, rec.len() as u64);
+                self.live.insert(lsn, (head, len));
+                let usage = self.segs.get_mut(&head).expect("segment is open");
+                usage.size += len;
+                usage.live += len;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(i: u32) -> (Frame, Meta) {
+        (Frame::Msg(i.to_be_bytes().to_vec()), Meta { priority: Some((i % 3) as u8), ..Meta::default() })
+    }
+
+    fn frames(recovered: Vec<Replayed>) -> Vec<(Frame, Meta)> {
+        recovered.into_iter().map(|(_, f, m)| (f, m)).collect()
+    }
+
+    #[test]
+    fn frames_not_done_are_replayed_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut wal, got, broken) = Wal::open(dir.path(), None).unwrap();
+        assert_eq!((got.len(), broken), (0, 0));
+        let lsns: Vec<u64> = (0..5).map(|i| {
+            let (f, m) = msg(i);
+            wal.push(&f, &m).unwrap()
+        }).collect();
+        wal.push(&Frame::Eos(b"g".to_vec()), &Meta::default()).unwrap();
+        wal.done(lsns[1]).unwrap();
+        wal.done(lsns[3]).unwrap();
+        assert_eq!(wal.len(), 4);
+        drop(wal);
+
+        let (mut wal, got, _) = Wal::open(dir.path(), None).unwrap();
+        let mut want: Vec<_> = [0, 2, 4].map(msg).to_vec();
+        want.push((Frame::Eos(b"g".to_vec()), Meta::default()));
+        assert_eq!(frames(got), want);
+        // Sequence numbers carry on where the last run stopped.
+        let (f, m) = msg(5);
+        assert!(wal.push(&f, &m).unwrap() > *lsns.last().unwrap() + 1);
+    }
+
+    #[test]
+    fn a_torn_tail_is_ignored_and_a_settled_log_starts_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut wal, ..) = Wal::open(dir.path(), None).unwrap();
+        let (f, m) = msg(1);
+        let lsn = wal.push(&f, &m).unwrap();
+        let (seg, file, _) = wal.writer.as_mut().unwrap();
+        file.write_all(&[KIND_PUSH, 0, 0, 0]).unwrap(); // crash mid-append
+        let seg = *seg;
+        drop(wal);
+
+        let (mut wal, got, _) = Wal::open(dir.path(), None).unwrap();
+        assert_eq!(frames(got), [msg(1)]);
+        wal.done(lsn).unwrap();
+        drop(wal);
+        let (wal, got, _) = Wal::open(dir.path(), None).unwrap();
+        assert!(got.is_empty() && wal.is_empty());
+        assert!(!wal.seg_path(seg).exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn rollover_deletes_settled_segments_and_compacts_mostly_settled_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut wal, ..) = Wal::open(dir.path(), None).unwrap();
+        wal.segment_bytes = 200;
+        let mut lsns = Vec::new();
+        for i in 0..40 {
+            let (f, m) = msg(i);
+            lsns.push(wal.push(&f, &m).unwrap());
+        }
+        assert!(wal.segs.len() > 4, "{:?}", wal.segs);
+        // Settle everything but #3; its segment gets compacted.
+        for (i, lsn) in lsns.iter().enumerate() {
+            if i != 3 {
+                wal.done(*lsn).unwrap();
+            }
+        }
+        for i in 40..60 {
+            let (f, m) = msg(i);
+            let lsn = wal.push(&f, &m).unwrap();
+            wal.done(lsn).unwrap();
+        }
+        assert!(wal.segs.len() <= 2, "{:?}", wal.segs);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), wal.segs.len());
+        drop(wal);
+
+        let (wal, got, _) = Wal::open(dir.path(), None).unwrap();
+        assert_eq!(frames(got), [msg(3)]);
+        assert_eq!(wal.len(), 1);
+    }
+
+    #[test]
+    fn incomplete_chunked_messages_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut wal, ..) = Wal::open(dir.path(), None).unwrap();
+        let ch = |id: u128, idx: u32| Frame::Chunk { id, idx, count: 2, payload: vec![idx as u8] };
+        let first = wal.push(&ch(1, 0), &Meta::default()).unwrap();
+        wal.push(&ch(1, 1), &Meta::default()).unwrap();
+        wal.push(&ch(2, 0), &Meta::default()).unwrap();
+        wal.push(&ch(2, 1), &Meta::default()).unwrap();
+        wal.push(&ch(3, 0), &Meta::default()).unwrap(); // producer went away
+        wal.done(first).unwrap(); // delivered before the crash
+        drop(wal);
+
+        let (wal, got, broken) = Wal::open(dir.path(), None).unwrap();
+        assert_eq!(frames(got), [(ch(2, 0), Meta::default()), (ch(2, 1), Meta::default())]);
+        assert_eq!((broken, wal.len()), (2, 2));
+        drop(wal);
+        let (_, got, broken) = Wal::open(dir.path(), None).unwrap();
+        assert_eq!((got.len(), broken), (2, 0));
+    }
+
+    #[test]
+    fn sealed_logs_hide_payloads_and_need_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Key::from_bytes([5; 32]);
+        let (mut wal, ..) = Wal::open(dir.path(), Some(key.clone())).unwrap();
+        wal.push(&Frame::Msg(b"plaintext-marker".to_vec()), &Meta::default()).unwrap();
+        let disk = fs::read(wal.seg_path(1)).unwrap();
+        assert!(!disk.windows(16).any(|w| w == b"plaintext-marker"));
+        drop(wal);
+
+        assert!(Wal::open(dir.path(), None).is_err());
+        let (_, got, _) = Wal::open(dir.path(), Some(key)).unwrap();
+        assert_eq!(frames(got), [(Frame::Msg(b"plaintext-marker".to_vec()), Meta::default())]);
+    }
+}
@@ -0,0 +1,580 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! WebSocket transport (RFC 6455), for browser clients and for networks
+//! whose proxies pass HTTP but not raw TCP. A `ws://host:port/` connection
+//! carries the byte stream of a single-port session — hello, role, options,
+//! token, then frames and ACKs — in binary messages. Each write goes out as
+//! one message and reads run message payloads together, so message
+//! boundaries mean nothing: a browser may split or merge records as it
+//! likes. The orchestrator serves WebSocket clients on a listener of their
+//! own (`--ws-listen`), and only single-port sessions on it.
+//!
+//! No WebSocket crate is vendored. This is the subset qpipe needs: binary
+//! and continuation frames, ping/pong and close, no extensions and no TLS
+//! (`wss://` belongs in a proxy in front). The opening handshake needs
+//! SHA-1, implemented here and pinned to the FIPS 180 test vectors.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64;
+use rand::{rngs::SysRng, TryRng};
+
+use crate::transport::WS_SCHEME;
+
+/// The subprotocol a client may ask for; the orchestrator echoes it.
+pub const SUBPROTOCOL: &str = "qpipe";
+
+/// Appended to the client's key before hashing (RFC 6455, section 1.3).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest opening handshake either side reads.
+const MAX_HEAD: usize = 8192;
+
+/// How long a server waits for a client's opening handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8         = 0x1;
+const OP_BINARY: u8       = 0x2;
+const OP_CLOSE: u8        = 0x8;
+const OP_PING: u8         = 0x9;
+const OP_PONG: u8         = 0xa;
+
+/// A parsed `ws://` address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    /// `host:port` as written, for the Host header.
+    pub host: String,
+    pub addr: SocketAddr,
+    pub path: String,
+}
+
+impl Url {
+    /// Parse `ws://host:port[/path]` and resolve the host (first address
+    /// wins). The port is required.
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{url}: {msg}"));
+        let rest = url.strip_prefix(WS_SCHEME).ok_or_else(|| bad("not a ws:// address"))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(bad("no host"));
+        }
+        let addr = host.to_socket_addrs()
+            .map_err(|e| bad(&e.to_string()))?
+            .next()
+            .ok_or_else(|| bad("could not resolve address"))?;
+        Ok(Self { host: host.to_string(), addr, path: path.to_string() })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{WS_SCHEME}{}{}", self.host, self.path)
+    }
+}
+
+/// One side of a WebSocket connection, as a byte stream. Clones share the
+/// read state, so a message half read by one is finished by another, and
+/// whole frames are written under a shared lock, so they never interleave.
+#[derive(Debug)]
+pub struct WsStream {
+    tcp:    TcpStream,
+    /// Clients mask what they send and servers must not (section 5.1).
+    client: bool,
+    rd:     Arc<Mutex<Reader>>,
+    /// Held while a frame is written; true once a close frame has gone.
+    wr:     Arc<Mutex<bool>>,
+}
+
+#[derive(Debug, Default)]
+struct Reader {
+    /// The frame header read so far — and for control frames, the payload.
+    head: Vec<u8>,
+    /// Payload bytes of the current data frame still to come.
+    left: u64,
+    mask: Option<[u8; 4]>,
+    /// Payload bytes of the current data frame read, for unmasking.
+    pos:  u64,
+    /// Set once the peer has sent a close frame.
+    closed: bool,
+}
+
+/// How long the frame header starting with `head` is, once enough of it is
+/// there to tell; control frames count their payload too.
+fn head_len(head: &[u8]) -> usize {
+    if head.len() < 2 {
+        return 2;
+    }
+    let len7 = (head[1] & 0x7f) as usize;
+    let ext = match len7 {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask = if head[1] & 0x80 != 0 { 4 } else { 0 };
+    let control = if head[0] & 0x08 != 0 { len7 } else { 0 };
+    2 + ext + mask + control
+}
+
+fn unmask(buf: &mut [u8], mask: [u8; 4], pos: u64) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b ^= mask[((pos + i as u64) % 4) as usize];
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("websocket: {msg}"))
+}
+
+impl WsStream {
+    fn new(tcp: TcpStream, client: bool) -> Self {
+        Self { tcp, client, rd: Arc::default(), wr: Arc::default() }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            tcp: self.tcp.try_clone()?,
+            client: self.client,
+            rd: self.rd.clone(),
+            wr: self.wr.clone(),
+        })
+    }
+
+    pub fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_read_timeout(t)
+    }
+
+    pub fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_write_timeout(t)
+    }
+
+    pub fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        self.tcp.set_nonblocking(on)
+    }
+
+    pub fn set_nodelay(&self, on: bool) -> io::Result<()> {
+        self.tcp.set_nodelay(on)
+    }
+
+    /// Whether bytes are waiting, like `TcpStream::peek`; they may be a
+    /// frame header or a ping rather than payload.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tcp.peek(buf)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.local_addr()
+    }
+
+    /// Shutting down the write side sends a close frame first.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            self.send(OP_CLOSE, &1000u16.to_be_bytes()).ok();
+        }
+        self.tcp.shutdown(how)
+    }
+
+    /// Write one frame. Nothing more goes out after a close frame.
+    fn send(&self, op: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | op);
+        let masked = if self.client { 0x80 } else { 0 };
+        match payload.len() {
+            n @ 0..=125 => frame.push(masked | n as u8),
+            n @ 126..=0xffff => {
+                frame.push(masked | 126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                frame.push(masked | 127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        let at = frame.len();
+        if self.client {
+            let mut mask = [0u8; 4];
+            SysRng.try_fill_bytes(&mut mask).map_err(io::Error::other)?;
+            frame.extend_from_slice(&mask);
+            frame.extend_from_slice(payload);
+            unmask(&mut frame[at + 4..], mask, 0);
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        let mut closed = self.wr.lock().unwrap();
+        if *closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "websocket closed"));
+        }
+        *closed = op == OP_CLOSE;
+        (&self.tcp).write_all(&frame)
+    }
+
+    fn read_payload(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut r = self.rd.lock().unwrap();
+        loop {
+            if r.closed {
+                return Ok(0);
+            }
+            if r.left > 0 {
+                let want = buf.len().min(r.left.min(usize::MAX as u64) as usize);
+                let n = (&self.tcp).read(&mut buf[..want])?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof, "connection closed inside a websocket frame",
+                    ));
+                }
+                if let Some(mask) = r.mask {
+                    unmask(&mut buf[..n], mask, r.pos);
+                }
+                r.pos += n as u64;
+                r.left -= n as u64;
+                return Ok(n);
+            }
+
+            // A new frame: its header, a piece at a time, so that a read
+            // timeout in the middle of one loses nothing.
+            loop {
+                let need = head_len(&r.head);
+                if r.head.len() >= need {
+                    break;
+                }
+                let mut piece = [0u8; 14 + 125];
+                let n = (&self.tcp).read(&mut piece[..need - r.head.len()])?;
+                if n == 0 {
+                    if r.head.is_empty() {
+                        return Ok(0); // closed between frames, without a close frame
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof, "connection closed inside a websocket frame",
+                    ));
+                }
+                r.head.extend_from_slice(&piece[..n]);
+            }
+            let head = std::mem::take(&mut r.head);
+            let (op, fin, masked) = (head[0] & 0x0f, head[0] & 0x80 != 0, head[1] & 0x80 != 0);
+            if head[0] & 0x70 != 0 {
+                return Err(invalid("no extensions were negotiated"));
+            }
+            if masked == self.client {
+                return Err(invalid(if self.client { "masked frame from the server" } else { "unmasked frame from a client" }));
+            }
+            let (len, mut at) = match head[1] & 0x7f {
+                126 => (u16::from_be_bytes([head[2], head[3]]) as u64, 4),
+                127 => (u64::from_be_bytes(head[2..10].try_into().unwrap()), 10),
+                n => (n as u64, 2),
+            };
+            let mask = masked.then(|| {
+                let m = [head[at], head[at + 1], head[at + 2], head[at + 3]];
+                at += 4;
+                m
+            });
+            match op {
+                OP_BINARY | OP_CONTINUATION => {
+                    (r.left, r.mask, r.pos) = (len, mask, 0);
+                }
+                OP_TEXT => return Err(invalid("text messages are not part of the protocol")),
+                OP_CLOSE | OP_PING | OP_PONG => {
+                    if !fin || len > 125 {
+                        return Err(invalid("bad control frame"));
+                    }
+                    let mut payload = head[at..].to_vec();
+                    if let Some(mask) = mask {
+                        unmask(&mut payload, mask, 0);
+                    }
+                    match op {
+                        OP_PING => self.send(OP_PONG, &payload)?,
+                        OP_CLOSE => {
+                            r.closed = true;
+                            // Answer with the peer's status code, if it sent one.
+                            self.send(OP_CLOSE, payload.get(..2).unwrap_or(&[])).ok();
+                            return Ok(0);
+                        }
+                        _ => {}
+                    }
+                }
+                _ => return Err(invalid(&format!("unknown opcode 0x{op:x}"))),
+            }
+        }
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_payload(buf)
+    }
+}
+
+impl Read for &WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_payload(buf)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for &WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            self.send(OP_BINARY, buf)?;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    B64.encode(sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// Read an HTTP head up to its blank line: the first line, and the headers
+/// with lowercased names. A byte at a time, so nothing after it is taken.
+fn read_head(mut tcp: &TcpStream) -> io::Result<(String, Vec<(String, String)>)> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            return Err(invalid("handshake too long"));
+        }
+        let mut b = [0u8; 1];
+        if tcp.read(&mut b)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during the websocket handshake"));
+        }
+        head.push(b[0]);
+    }
+    let head = String::from_utf8(head).map_err(|_| invalid("handshake is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let first = lines.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect();
+    Ok((first, headers))
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+}
+
+/// Whether a comma-separated header value lists `token`.
+fn lists(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Open a WebSocket connection to `url`, giving up on TCP after `timeout`
+/// if one is given.
+pub fn connect(url: &Url, timeout: Option<Duration>) -> io::Result<WsStream> {
+    let tcp = match timeout {
+        Some(t) => TcpStream::connect_timeout(&url.addr, t)?,
+        None => TcpStream::connect(url.addr)?,
+    };
+    tcp.set_nodelay(true).ok();
+    let mut nonce = [0u8; 16];
+    SysRng.try_fill_bytes(&mut nonce).map_err(io::Error::other)?;
+    let key = B64.encode(nonce);
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: {SUBPROTOCOL}\r\n\r\n",
+        url.path, url.host,
+    );
+    (&tcp).write_all(request.as_bytes())?;
+    let (status, headers) = read_head(&tcp)?;
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused, format!("websocket upgrade refused: {status}"),
+        ));
+    }
+    if header(&headers, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+        return Err(invalid("wrong Sec-WebSocket-Accept"));
+    }
+    Ok(WsStream::new(tcp, true))
+}
+
+/// Serve the opening handshake of a client that connected to `tcp`. A
+/// request that isn't a WebSocket upgrade gets an HTTP error.
+pub fn accept(tcp: TcpStream) -> io::Result<WsStream> {
+    tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let (request, headers) = read_head(&tcp)?;
+    let refuse = |status: &str, extra: &str, why: &str| {
+        let reply = format!("HTTP/1.1 {status}\r\n{extra}Content-Length: 0\r\nConnection: close\r\n\r\n");
+        (&tcp).write_all(reply.as_bytes()).ok();
+        Err(invalid(why))
+    };
+    if !request.starts_with("GET ") {
+        return refuse("405 Method Not Allowed", "", "handshake is not a GET");
+    }
+    let key = match header(&headers, "sec-websocket-key") {
+        Some(k) if lists(header(&headers, "upgrade"), "websocket")
+            && lists(header(&headers, "connection"), "upgrade") => k,
+        _ => return refuse("400 Bad Request", "", "not a websocket upgrade"),
+    };
+    if header(&headers, "sec-websocket-version") != Some("13") {
+        return refuse("426 Upgrade Required", "Sec-WebSocket-Version: 13\r\n", "unsupported websocket version");
+    }
+    let protocol = if lists(header(&headers, "sec-websocket-protocol"), SUBPROTOCOL) {
+        format!("Sec-WebSocket-Protocol: {SUBPROTOCOL}\r\n")
+    } else {
+        String::new()
+    };
+    let reply = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n{protocol}\r\n",
+        accept_key(key),
+    );
+    (&tcp).write_all(reply.as_bytes())?;
+    tcp.set_read_timeout(None)?;
+    tcp.set_nodelay(true).ok();
+    Ok(WsStream::new(tcp, false))
+}
+
+/// SHA-1 (FIPS 180-4). Only the WebSocket handshake uses it, where it
+/// guards against confused proxies, not against attackers.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476, 0xc3d2_e1f0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in msg.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19  => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _       => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*wi);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t);
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+    let mut out = [0u8; 20];
+    for (o, x) in out.chunks_exact_mut(4).zip(h) {
+        o.copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn hex(b: &[u8]) -> String {
+        b.iter().map(|x| format!("{x:02x}")).collect()
+    }
+
+    #[test]
+    fn sha1_matches_the_fips_vectors() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+        );
+        assert_eq!(hex(&sha1(&vec![b'a'; 1_000_000])), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+        // RFC 6455, section 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn urls_need_a_host_and_port() {
+        let u = Url::parse("ws://127.0.0.1:7001").unwrap();
+        assert_eq!((u.host.as_str(), u.path.as_str(), u.addr.port()), ("127.0.0.1:7001", "/", 7001));
+        assert_eq!(Url::parse("ws://127.0.0.1:7001/qpipe").unwrap().path, "/qpipe");
+        assert!(Url::parse("ws:///x").is_err());
+        assert!(Url::parse("ws://127.0.0.1").is_err());
+        assert!(Url::parse("http://127.0.0.1:80").is_err());
+    }
+
+    fn pair() -> (WsStream, WsStream) {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("ws://{}/", l.local_addr().unwrap())).unwrap();
+        let server = std::thread::spawn(move || accept(l.accept().unwrap().0).unwrap());
+        let client = connect(&url, None).unwrap();
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn bytes_cross_in_both_directions_whatever_the_message_sizes() {
+        let (mut c, mut s) = pair();
+        let big: Vec<u8> = (0..70_000u32).map(|i| i as u8).collect();
+        for size in [1, 125, 126, 65_535, 65_536, 70_000] {
+            c.write_all(&big[..size]).unwrap();
+            let mut got = vec![0u8; size];
+            s.read_exact(&mut got).unwrap();
+            assert!(got == big[..size], "{size} bytes to the server");
+            s.write_all(&big[..size]).unwrap();
+            c.read_exact(&mut got).unwrap();
+            assert!(got == big[..size], "{size} bytes to the client");
+        }
+        // Reads run messages together; clones share what is half read.
+        c.write_all(b"abc").unwrap();
+        c.write_all(b"def").unwrap();
+        let mut two = [0u8; 2];
+        s.read_exact(&mut two).unwrap();
+        let mut rest = [0u8; 4];
+        s.try_clone().unwrap().read_exact(&mut rest).unwrap();
+        assert_eq!((&two, &rest), (b"ab", b"cdef"));
+    }
+
+    #[test]
+    fn pings_are_answered_and_a_close_ends_the_stream() {
+        let (mut c, mut s) = pair();
+        c.send(OP_PING, b"hi").unwrap();
+        c.write_all(b"x").unwrap();
+        let mut b = [0u8; 1];
+        s.read_exact(&mut b).unwrap(); // answers the ping on the way
+        s.write_all(b"y").unwrap();
+        c.read_exact(&mut b).unwrap(); // skips the pong
+        assert_eq!(&b, b"y");
+
+        c.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(s.read(&mut b).unwrap(), 0);
+        assert_eq!(s.write(b"late").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn servers_turn_away_what_is_not_an_upgrade() {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut tcp = TcpStream::connect(l.local_addr().unwrap()).unwrap();
+        tcp.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(accept(l.accept().unwrap().0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut reply = String::new();
+        tcp.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 400"), "{reply}");
+    }
+}
@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//! zstd payload compression, for producers sending large, redundant
+//! payloads (JSON and the like) over links where bandwidth matters. Built
+//! only with the `zstd` feature; it links the system's `libzstd` directly,
+//! since no compression crate is vendored.
+//!
+//! A producer asks for it with `OPT_COMPRESS` carrying the codec byte
+//! (`CODEC_ZSTD`); an orchestrator that echoes it passes compressed
+//! payloads through untouched, marked with `META_CODEC`. Consumers built
+//! with the feature decompress in `recv`. Each message is compressed whole,
+//! before chunking, as one zstd frame with its content size in the header,
+//! so the receiver can refuse oversized output before allocating it.
+
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::io;
+
+mod ffi {
+    use std::ffi::{c_char, c_int, c_uint, c_void};
+
+    pub const CONTENTSIZE_UNKNOWN: u64 = u64::MAX;
+    pub const CONTENTSIZE_ERROR: u64 = u64::MAX - 1;
+
+    #[link(name = "zstd")]
+    unsafe extern "C" {
+        pub fn ZSTD_compressBound(src_size: usize) -> usize;
+        pub fn ZSTD_compress(
+            dst: *mut c_void,
+            dst_capacity: usize,
+            src: *const c_void,
+            src_size: usize,
+            level: c_int,
+        ) -> usize;
+        pub fn ZSTD_decompress(
+            dst: *mut c_void,
+            dst_capacity: usize,
+            src: *const c_void,
+            src_size: usize,
+        ) -> usize;
+        pub fn ZSTD_getFrameContentSize(src: *const c_void, src_size: usize) -> u64;
+        pub fn ZSTD_isError(code: usize) -> c_uint;
+        pub fn ZSTD_getErrorName(code: usize) -> *const c_char;
+    }
+}
+
+/// Map a zstd return code to the byte count it carries, or an error.
+fn check(code: usize, what: &str) -> io::Result<usize> {
+    // SAFETY: both functions take any code; the name is a static string.
+    unsafe {
+        if ffi::ZSTD_isError(code) == 0 {
+            return Ok(code);
+        }
+        let name: *const c_char = ffi::ZSTD_getErrorName(code);
+        let msg = CStr::from_ptr(name).to_string_lossy();
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("zstd {what}: {msg}")))
+    }
+}
+
+/// Compress `data` as one zstd frame at `level` (1..=19; 3 is zstd's
+/// default).
+pub fn compress(data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    // SAFETY: `out` has the capacity zstd asks for, and only the `n` bytes
+    // it reports written are exposed.
+    unsafe {
+        let mut out = Vec::with_capacity(ffi::ZSTD_compressBound(data.len()));
+        let n = check(ffi::ZSTD_compress(
+            out.as_mut_ptr() as *mut c_void, out.capacity(),
+            data.as_ptr() as *const c_void, data.len(),
+            level as c_int,
+        ), "compress")?;
+        out.set_len(n);
+        Ok(out)
+    }
+}
+
+/// Decompress one zstd frame, refusing frames that don't state their size
+/// or would expand past `max` bytes.
+pub fn decompress(data: &[u8], max: usize) -> io::Result<Vec<u8>> {
+    let bad = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    // SAFETY: zstd reads at most `data.len()` bytes and writes at most the
+    // capacity of `out`; only the `n` bytes it reports are exposed.
+    unsafe {
+        let size = ffi::ZSTD_getFrameContentSize(data.as_ptr() as *const c_void, data.len());
+        match size {
+            ffi::CONTENTSIZE_ERROR => return Err(bad("zstd: not a zstd frame".into())),
+            ffi::CONTENTSIZE_UNKNOWN => return Err(bad("zstd: frame does not state its size".into())),
+            n if n > max as u64 => return Err(bad(format!("zstd: frame expands to {n} bytes"))),
+            _ => {}
+        }
+        let mut out = Vec::with_capacity(size as usize);
+        let n = check(ffi::ZSTD_decompress(
+            out.as_mut_ptr() as *mut c_void, out.capacity(),
+            data.as_ptr() as *const c_void, data.len(),
+        ), "decompress")?;
+        out.set_len(n);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips_and_refuses_bombs() {
+        let json = br#"{"name":"sample","values":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]}"#.repeat(100);
+        let z = compress(&json, 3).unwrap();
+        assert!(z.len() < json.len() / 10, "{} -> {}", json.len(), z.len());
+        assert_eq!(decompress(&z, json.len()).unwrap(), json);
+        assert!(decompress(&z, json.len() - 1).is_err(), "over the limit");
+        assert!(decompress(b"not zstd", 100).is_err());
+        assert!(decompress(&z[..z.len() - 1], json.len()).is_err(), "truncated");
+    }
+}
@@ -7,8 +7,9 @@
 //   predicates = "3"
 // (add them, then re-vendor: `cargo vendor vendor` and commit.)
 //
-// The orchestrator loop lives in its binary (not the library), so we spawn it
-// as a child process. Readiness + teardown use the crate's own public helpers
+// Most tests spawn the orchestrator binary as a child process, as a
+// deployment would; one runs it in-process through qpipe::orchestrator.
+// Readiness + teardown use the crate's own public helpers
 // (qpipe::wait_until_healthy / qpipe::request_shutdown) rather than hand-rolled
 // polling or SIGKILL.
 
@@ -38,12 +39,30 @@ struct Orchestrator {
 
 impl Orchestrator {
     fn start() -> Self {
-        let addr = format!("127.0.0.1:{}", free_port());
+        Self::start_with_env(&[])
+    }
+
+    /// Start with extra environment variables (QPIPE_* knobs).
+    fn start_with_env(env: &[(&str, &str)]) -> Self {
+        Self::start_with(&[], env)
+    }
+
+    /// Start with extra positional args after LISTEN_ADDR (CAPACITY, STATS)
+    /// and extra environment variables.
+    fn start_with(args: &[&str], env: &[(&str, &str)]) -> Self {
+        Self::start_on(&format!("127.0.0.1:{}", free_port()), args, env)
+    }
+
+    /// Start listening on a given address, e.g. one clients already target.
+    fn start_on(addr: &str, args: &[&str], env: &[(&str, &str)]) -> Self {
+        let addr = addr.to_string();
 
         // First positional arg is LISTEN_ADDR (README: orchestrator [LISTEN_ADDR] [CAPACITY] [STATS]).
         let child = StdCommand::new(cargo_bin("orchestrator"))
             .arg(&addr)
+            .args(args)
             .env("RUST_LOG", "warn")
+            .envs(env.iter().copied())
             .spawn()
             .expect("failed to spawn orchestrator binary");
 
@@ -229,3 +248,3248 @@ fn base64_mode_roundtrips_binary() {
         .expect("consumer emitted valid base64");
     assert_eq!(decoded, raw);
 }
+
+#[test]
+fn producer_sends_files_length_prefixed_stdin_and_paced_ndjson() {
+    use qpipe::Consumer;
+
+    let orch = Orchestrator::start();
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let dir = tempfile::tempdir().unwrap();
+    for (name, body) in [("b.bin", &b"\x00two"[..]), ("a.bin", b"one"), ("c.txt", b"skipped")] {
+        std::fs::write(dir.path().join(name), body).unwrap();
+    }
+    let pattern = format!("{}/*.bin", dir.path().display());
+    Command::new(cargo_bin("producer"))
+        .args([orch.addr.as_str(), "--file", &pattern])
+        .timeout(Duration::from_secs(10))
+        .assert()
+        .success();
+    assert_eq!(c.recv().unwrap().payload, b"one");
+    assert_eq!(c.recv().unwrap().payload, b"\x00two");
+
+    let mut stdin = Vec::new();
+    for msg in [&b"x\ny"[..], b""] {
+        stdin.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        stdin.extend_from_slice(msg);
+    }
+    Command::new(cargo_bin("producer"))
+        .args([orch.addr.as_str(), "--binary"])
+        .write_stdin(stdin)
+        .timeout(Duration::from_secs(10))
+        .assert()
+        .success();
+    assert_eq!(c.recv().unwrap().payload, b"x\ny");
+    assert_eq!(c.recv().unwrap().payload, b"");
+
+    // Four messages at 10/s take at least 300ms.
+    let start = std::time::Instant::now();
+    Command::new(cargo_bin("producer"))
+        .args([orch.addr.as_str(), "--ndjson", "--rate", "10"])
+        .write_stdin("{\"n\": 1}\n\n[2]\n\"three\"\n4\n")
+        .timeout(Duration::from_secs(10))
+        .assert()
+        .success();
+    assert!(start.elapsed() >= Duration::from_millis(300), "{:?}", start.elapsed());
+    for want in [&b"{\"n\": 1}"[..], b"[2]", b"\"three\"", b"4"] {
+        assert_eq!(c.recv().unwrap().payload, want);
+    }
+
+    Command::new(cargo_bin("producer"))
+        .args([orch.addr.as_str(), "--ndjson"])
+        .write_stdin("{\"ok\": true}\nnot json\n")
+        .timeout(Duration::from_secs(10))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("line 2 is not a JSON value"));
+    Command::new(cargo_bin("producer"))
+        .args([orch.addr.as_str(), "--file", "/nonexistent/*.bin"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("matches no files"));
+}
+
+#[test]
+fn consumer_sinks_write_numbered_files_and_run_a_command_per_message() {
+    use qpipe::Producer;
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_RETRY_POLICY", "retries=1,backoff=0")]);
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("out");
+    std::fs::create_dir(&out).unwrap();
+    std::fs::write(out.join("0000000007"), b"from an earlier run").unwrap();
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for msg in [&b"a"[..], b"\x00b"] {
+        p.send(msg).unwrap();
+    }
+    let wait_for = |path: &std::path::Path| {
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while !path.exists() {
+            assert!(std::time::Instant::now() < deadline, "{} never appeared", path.display());
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    };
+    // Every message acked, so none comes back once the consumer is gone.
+    let settled = || {
+        let mut admin = qpipe::admin::Admin::connect(&orch.addr).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while admin.queues().unwrap().iter().any(|q| q.outstanding > 0) {
+            assert!(std::time::Instant::now() < deadline, "messages never settled");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    };
+
+    let mut consumer = StdCommand::new(cargo_bin("consumer"))
+        .args([orch.addr.as_str(), "--dir"])
+        .arg(&out)
+        .spawn()
+        .expect("spawn consumer");
+    wait_for(&out.join("0000000009"));
+    settled();
+    consumer.kill().unwrap();
+    consumer.wait().unwrap();
+    assert_eq!(std::fs::read(out.join("0000000008")).unwrap(), b"a");
+    assert_eq!(std::fs::read(out.join("0000000009")).unwrap(), b"\x00b");
+
+    // The command sees each payload and its attempt; "bad" fails twice
+    // and is dead-lettered, the rest succeed once.
+    for msg in [&b"good"[..], b"bad"] {
+        p.send(msg).unwrap();
+    }
+    let log = dir.path().join("log");
+    let done = dir.path().join("done");
+    let cmd = format!(
+        "x=$(cat); echo \"$x $QPIPE_ATTEMPT\" >> {log}; [ \"$x\" = good ] && touch {done}; [ \"$x\" != bad ]",
+        log = log.display(), done = done.display(),
+    );
+    let mut consumer = StdCommand::new(cargo_bin("consumer"))
+        .args([orch.addr.as_str(), "--exec", &cmd])
+        .spawn()
+        .expect("spawn consumer");
+    wait_for(&done);
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while std::fs::read_to_string(&log).unwrap().lines().count() < 3 {
+        assert!(std::time::Instant::now() < deadline, "{}", std::fs::read_to_string(&log).unwrap());
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    settled();
+    consumer.kill().unwrap();
+    consumer.wait().unwrap();
+    let mut lines: Vec<_> = std::fs::read_to_string(&log).unwrap().lines().map(String::from).collect();
+    lines.sort();
+    assert_eq!(lines, ["bad 0", "bad 1", "good 0"]);
+
+    Command::new(cargo_bin("consumer"))
+        .args([orch.addr.as_str(), "--dir"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--dir needs a DIR"));
+}
+
+#[test]
+fn nu_consumer_converts_to_msgpack_compacts_json_and_batches_it() {
+    use qpipe::{Producer, ProducerOptions};
+    use rmpv::Value;
+
+    let orch = Orchestrator::start();
+    let run_bin = |bin: &str, queue: &str, mode: &[&str], msgs: &[&[u8]], lines: usize| {
+        let mut consumer = StdCommand::new(cargo_bin(bin))
+            .arg(&orch.addr)
+            .args(mode)
+            .env("QPIPE_QUEUE", queue)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .expect("spawn consumer");
+        let opts = ProducerOptions::new().queue(queue);
+        let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+        for msg in msgs {
+            p.send(msg).unwrap();
+        }
+        let out = if lines > 0 {
+            read_n_lines(&mut consumer, lines, Duration::from_secs(10)).join("\n").into_bytes()
+        } else {
+            std::thread::sleep(Duration::from_millis(500));
+            consumer.kill().unwrap();
+            let mut out = Vec::new();
+            std::io::Read::read_to_end(&mut consumer.stdout.take().unwrap(), &mut out).unwrap();
+            out
+        };
+        let _ = consumer.kill();
+        consumer.wait().unwrap();
+        out
+    };
+    let run = |queue: &str, mode: &[&str], msgs: &[&[u8]], lines: usize| {
+        run_bin("nu_consumer", queue, mode, msgs, lines)
+    };
+    let unpack = |out: Vec<u8>| {
+        let mut r = &out[..];
+        let mut values = Vec::new();
+        while !r.is_empty() {
+            values.push(rmpv::decode::read_value(&mut r).unwrap());
+        }
+        values
+    };
+    let pretty = b"{\n  \"a\": [1, -2.5],\n  \"s\": \"x\\n\\u00e9\"\n}";
+
+    let mut packed = Vec::new();
+    rmpv::encode::write_value(&mut packed, &Value::from("typed")).unwrap();
+    let out = run("m", &["--msgpack"], &[&packed, pretty, b"\xff\x00"], 0);
+    let record = Value::Map(vec![
+        (Value::from("a"), Value::Array(vec![Value::from(1u64), Value::from(-2.5)])),
+        (Value::from("s"), Value::from("x\n\u{e9}")),
+    ]);
+    assert_eq!(unpack(out), [Value::from("typed"), record, Value::Binary(vec![0xff, 0])]);
+
+    // Short JSON is read as JSON, though each is a MessagePack value too;
+    // producers of those say so.
+    let short: [&[u8]; 5] = [b"1", b"7", b"[]", b"\"a\"", b"null"];
+    let out = run("s", &["--msgpack"], &short, 0);
+    assert_eq!(
+        unpack(out),
+        [Value::from(1u64), Value::from(7u64), Value::Array(vec![]), Value::from("a"), Value::Nil],
+    );
+    let out = run("p", &["--msgpack", "--input-format", "msgpack"], &[b"1", &packed], 0);
+    assert_eq!(unpack(out), [Value::from(0x31u64), Value::from("typed")]);
+    let out = run("q", &["--input-format", "json", "--msgpack"], &[b"1", &packed], 0);
+    assert_eq!(unpack(out), [Value::from(1u64), Value::Binary(packed.clone())]);
+
+    // The consumer's --jsonl compacts JSON the same way. Compacting
+    // re-serializes strings, so escapes that needn't be come out as text;
+    // keys keep their order and numbers their digits.
+    let wide = b"{\n  \"z\": 1.50,\n  \"a\": 123456789012345678901234567890\n}";
+    for bin in ["nu_consumer", "consumer"] {
+        let out = run_bin(bin, &format!("j-{bin}"), &["--jsonl"], &[pretty, b"plain text", b"1", wide], 4);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"a\":[1,-2.5],\"s\":\"x\\n\u{e9}\"}\nplain text\n1\n{\"z\":1.50,\"a\":123456789012345678901234567890}",
+        );
+    }
+
+    // Two full batches, then the rest once the stream goes quiet.
+    let out = run("b", &["--batch", "2"], &[pretty, b"2", b"\"three\"", b"[4]", b"null"], 3);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "[{\"a\":[1,-2.5],\"s\":\"x\\n\u{e9}\"},2]\n[\"three\",[4]]\n[null]",
+    );
+
+    // Multi-line text that isn't JSON still can't go out as one line.
+    let consumer = StdCommand::new(cargo_bin("nu_consumer"))
+        .args([orch.addr.as_str(), "--jsonl"])
+        .env("QPIPE_QUEUE", "bad")
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("spawn consumer");
+    let opts = ProducerOptions::new().queue("bad");
+    Producer::connect_with(&orch.addr, &opts).unwrap().send(b"two\nlines").unwrap();
+    let out = consumer.wait_with_output().unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("is not JSON"));
+
+    Command::new(cargo_bin("nu_consumer"))
+        .args([orch.addr.as_str(), "--jsonl", "--input-format", "json"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("only applies to --msgpack"));
+}
+
+#[test]
+fn eos_notice_follows_the_data_sent_before_it() {
+    use qpipe::{Consumer, Delivery, Producer};
+
+    let orch = Orchestrator::start();
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+
+    for msg in [&b"one"[..], b"two", b"three"] {
+        p.send(msg).unwrap();
+    }
+    p.send_eos(b"batch").unwrap();
+
+    let mut got = Vec::new();
+    loop {
+        match c.recv_ext().unwrap() {
+            Delivery::Message(m) => got.push(m.payload),
+            Delivery::Eos(group) => {
+                assert_eq!(group, b"batch");
+                break;
+            }
+        }
+    }
+    assert_eq!(got, [b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]);
+}
+
+#[test]
+fn wait_for_idle_returns_once_the_campaign_drains() {
+    use qpipe::{Consumer, Producer};
+
+    let orch = Orchestrator::start();
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for _ in 0..5 {
+        p.send(b"work").unwrap();
+    }
+
+    // Producer still attached and nothing consumed: not idle.
+    let err = qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_millis(300)))
+        .expect_err("queue is busy");
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+    drop(p);
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for _ in 0..5 {
+        c.recv().unwrap();
+    }
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5)))
+        .expect("idle after everything was consumed");
+}
+
+#[test]
+fn wait_for_idle_can_wait_for_one_queue() {
+    use qpipe::{ConnectOptions, Consumer, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start();
+    let mut p = Producer::connect_with(&orch.addr, &ProducerOptions::new().queue("busy")).unwrap();
+    p.send(b"work").unwrap();
+    let idle = Producer::connect_with(&orch.addr, &ProducerOptions::new().queue("calm")).unwrap();
+    drop(idle);
+
+    // Another queue's producer and backlog don't hold up this one.
+    qpipe::wait_for_idle(&orch.addr, Some("calm"), Some(Duration::from_secs(5))).expect("calm is idle");
+    let out = StdCommand::new(cargo_bin("qpipe-admin")).args([&orch.addr, "wait-idle", "calm"]).output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    for queue in [Some("busy"), None] {
+        let err = qpipe::wait_for_idle(&orch.addr, queue, Some(Duration::from_millis(300)))
+            .expect_err("busy isn't idle");
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    drop(p);
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().queue("busy")).unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"work");
+    qpipe::wait_for_idle(&orch.addr, Some("busy"), Some(Duration::from_secs(5))).expect("busy drained");
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5))).expect("all idle");
+}
+
+#[test]
+fn unacked_messages_are_retried_then_dead_lettered() {
+    use qpipe::{ConnectOptions, Consumer, Delivery, Producer};
+
+    let dlq = std::env::temp_dir()
+        .join(format!("qpipe-dlq-{}-{}.q", std::process::id(), free_port()));
+    let policy = format!("timeout=300ms,retries=1,backoff=0,dlq=file:{}", dlq.display());
+    let orch = Orchestrator::start_with_env(&[("QPIPE_RETRY_POLICY", &policy)]);
+
+    let opts = ConnectOptions::new().ack_mode(true);
+    let mut c = Consumer::connect_with(&orch.addr, &opts).expect("consumer connect");
+    assert_eq!(c.visibility_timeout(), Some(Duration::from_millis(300)));
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(b"poison").unwrap();
+    p.send(b"fine").unwrap();
+    drop(p);
+
+    let next = |c: &mut Consumer| match c.recv_ext().unwrap() {
+        Delivery::Message(m) => m,
+        Delivery::Eos(_) => panic!("unexpected EOS"),
+    };
+    // "poison" is never acked: delivered, redelivered once, then buried.
+    let first = next(&mut c);
+    assert_eq!((first.payload.as_slice(), first.attempt), (&b"poison"[..], 0));
+    let fine = next(&mut c);
+    assert_eq!(fine.payload, b"fine");
+    c.ack(fine.tag.expect("tagged")).unwrap();
+    let again = next(&mut c);
+    assert_eq!((again.payload.as_slice(), again.attempt), (&b"poison"[..], 1));
+    assert_ne!(again.tag, first.tag);
+
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5)))
+        .expect("dead-lettering settles the message");
+    let bytes = std::fs::read(&dlq).expect("dead-letter file");
+    let _ = std::fs::remove_file(&dlq);
+    let (frame, meta) = qpipe::get_frame(&mut &bytes[..]).unwrap().unwrap();
+    assert_eq!(frame, qpipe::Frame::Msg(b"poison".to_vec()));
+    assert_eq!(meta.attempt, Some(2));
+}
+
+#[test]
+fn nacked_messages_come_back_at_once_then_go_to_the_dead_letters() {
+    use qpipe::{ConnectOptions, Consumer, Producer};
+    use std::time::Instant;
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_RETRY_POLICY", "timeout=30s,backoff=0,dlq=queue:dead")]);
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().ack_mode(true)).expect("consumer connect");
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(b"flaky").unwrap();
+    drop(p);
+
+    let start = Instant::now();
+    let first = c.recv_ack().unwrap();
+    c.nack(first.tag.unwrap()).unwrap();
+    let again = c.recv_ack().unwrap();
+    assert_eq!((again.payload.as_slice(), again.attempt), (&b"flaky"[..], 1));
+    assert!(start.elapsed() < Duration::from_secs(10), "no visibility timeout waited out");
+    // Out of the retries this nack allows: dead-lettered.
+    c.nack_with_retries(again.tag.unwrap(), 1).unwrap();
+
+    let mut dead = Consumer::connect_to(&orch.addr, "dead").expect("consumer connect");
+    assert_eq!(dead.recv().unwrap().payload, b"flaky");
+    let mut plain = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert_eq!(plain.nack(1).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn dead_letters_can_go_to_a_queue_a_consumer_drains() {
+    use qpipe::{ConnectOptions, Consumer, Producer};
+
+    let policy = "timeout=300ms,retries=0,backoff=0,dlq=queue:dead";
+    let orch = Orchestrator::start_with_env(&[("QPIPE_RETRY_POLICY", policy)]);
+
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(b"poison").unwrap();
+    drop(p);
+    let opts = ConnectOptions::new().ack_mode(true);
+    let mut c = Consumer::connect_with(&orch.addr, &opts).expect("consumer connect");
+    assert_eq!(c.recv_ack().unwrap().payload, b"poison");
+    drop(c); // gone without acking, and the budget is spent
+
+    let mut dead = Consumer::connect_to(&orch.addr, "dead").expect("dead-letter consumer");
+    assert_eq!(dead.recv_timeout(Duration::from_secs(5)).unwrap().map(|m| m.payload).as_deref(), Some(&b"poison"[..]));
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5))).expect("drained");
+}
+
+#[test]
+fn unacked_messages_move_on_when_their_consumer_disconnects() {
+    use qpipe::{ConnectOptions, Consumer, Delivery, Producer};
+
+    let orch = Orchestrator::start();
+    let opts = ConnectOptions::new().ack_mode(true);
+    let mut a = Consumer::connect_with(&orch.addr, &opts).expect("consumer connect");
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(b"job").unwrap();
+
+    let Delivery::Message(m) = a.recv_ext().unwrap() else { panic!("expected a message") };
+    assert_eq!(m.attempt, 0);
+    drop(a); // crashed mid-job
+
+    let mut b = Consumer::connect_with(&orch.addr, &opts).expect("consumer connect");
+    let m = b.recv_ack().unwrap();
+    assert_eq!((m.payload.as_slice(), m.attempt), (&b"job"[..], 1));
+    b.ack(m.tag.unwrap()).unwrap();
+    drop(p);
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5))).expect("idle");
+
+    let mut plain = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert_eq!(plain.recv_ack().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn aged_low_priority_messages_overtake_fresh_high_priority_ones() {
+    use qpipe::{Consumer, Producer};
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_PRIORITY_AGING", "200ms")]);
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(b"old-bulk").unwrap();
+    std::thread::sleep(Duration::from_millis(1000)); // ages to ~5: past 3, short of 9
+    p.send_with_priority(b"urgent", 3).unwrap();
+    p.send_with_priority(b"more-urgent", 9).unwrap();
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let got: Vec<Vec<u8>> = (0..3).map(|_| c.recv().unwrap().payload).collect();
+    assert_eq!(got, [b"more-urgent".to_vec(), b"old-bulk".to_vec(), b"urgent".to_vec()]);
+}
+
+#[test]
+fn producer_cli_sends_at_qpipe_priority() {
+    use qpipe::Consumer;
+
+    let orch = Orchestrator::start();
+    for (lines, prio) in [("bulk-1\nbulk-2\n", ""), ("recalibrate\n", "9")] {
+        Command::new(cargo_bin("producer"))
+            .arg(&orch.addr)
+            .env("QPIPE_PRIORITY", prio)
+            .write_stdin(lines)
+            .timeout(Duration::from_secs(10))
+            .assert()
+            .success();
+    }
+    Command::new(cargo_bin("producer"))
+        .arg(&orch.addr)
+        .env("QPIPE_PRIORITY", "urgent")
+        .write_stdin("x\n")
+        .assert()
+        .failure();
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let got: Vec<Vec<u8>> = (0..3).map(|_| c.recv().unwrap().payload).collect();
+    assert_eq!(got, [b"recalibrate".to_vec(), b"bulk-1".to_vec(), b"bulk-2".to_vec()]);
+}
+
+#[test]
+fn messages_nobody_takes_within_their_ttl_expire() {
+    use qpipe::{Consumer, Producer};
+
+    let orch = Orchestrator::start_with(&["100", "1"], &[]);
+    Command::new(cargo_bin("producer"))
+        .arg(&orch.addr)
+        .env("QPIPE_TTL", "300ms")
+        .write_stdin("stale-1\nstale-2\n")
+        .timeout(Duration::from_secs(10))
+        .assert()
+        .success();
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send_with_ttl(b"fresh", Duration::from_secs(60)).unwrap();
+    p.send(b"forever").unwrap();
+    std::thread::sleep(Duration::from_millis(600));
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let got: Vec<Vec<u8>> = (0..2).map(|_| c.recv().unwrap().payload).collect();
+    assert_eq!(got, [b"fresh".to_vec(), b"forever".to_vec()]);
+
+    let watch = qpipe::watch_stats(&orch.addr).expect("subscribe");
+    let report = watch.map(Result::unwrap)
+        .find(|r| r.contains("\"collected_frames\":2,"))
+        .expect("a report with the deliveries");
+    assert!(report.ends_with("\"exported\":0,\"expired\":2,\"duplicates\":0,\"shed\":0}}"), "{report}");
+}
+
+#[test]
+fn repeats_of_a_message_id_are_dropped_and_counted() {
+    use qpipe::{Consumer, Meta, Producer};
+
+    let orch = Orchestrator::start_with(&["--max-frame-bytes", "65536"], &[]);
+    let big = vec![7u8; 200_000];
+    let id = |id: &[u8]| Meta { message_id: Some(id.to_vec()), ..Meta::default() };
+    // The second session stands in for a producer resending after a
+    // reconnect, chunked message and all.
+    for _ in 0..2 {
+        let mut p = Producer::connect(&orch.addr).expect("producer connect");
+        p.send_with_id(b"one", b"run-1/1").unwrap();
+        p.send_with_meta(&big, &id(b"run-1/2")).unwrap();
+        p.send(b"anonymous").unwrap();
+        p.close().unwrap();
+    }
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let got: Vec<Vec<u8>> = (0..4).map(|_| c.recv().unwrap().payload).collect();
+    assert_eq!(got, [b"one".to_vec(), big, b"anonymous".to_vec(), b"anonymous".to_vec()]);
+    assert_eq!(c.try_recv().unwrap(), None);
+
+    let watch = qpipe::watch_stats(&orch.addr).expect("subscribe");
+    let report = watch.map(Result::unwrap)
+        .find(|r| r.contains("\"duplicates\":5,\"shed\":0}"))
+        .expect("a report with the duplicates: one single frame, four chunks");
+    assert!(report.contains("\"collected_frames\":7,"), "{report}");
+}
+
+#[test]
+fn delayed_messages_reach_consumers_once_their_time_comes() {
+    use qpipe::{Consumer, Producer};
+    use std::time::Instant;
+
+    let orch = Orchestrator::start();
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let sent = Instant::now();
+    p.send_delayed(b"retry", Duration::from_millis(700)).unwrap();
+    p.send(b"first").unwrap();
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert_eq!(c.recv().unwrap().payload, b"first");
+    assert_eq!(c.recv().unwrap().payload, b"retry");
+    assert!(sent.elapsed() >= Duration::from_millis(700), "{:?}", sent.elapsed());
+}
+
+#[test]
+fn heavier_consumers_hold_more_messages_in_flight() {
+    use qpipe::{ConnectOptions, Consumer, Delivery, Producer};
+
+    let orch = Orchestrator::start();
+    let weighted = |w| ConnectOptions::new().ack_mode(true).weight(w);
+    let mut big = Consumer::connect_with(&orch.addr, &weighted(3)).expect("consumer connect");
+    let mut small = Consumer::connect_with(&orch.addr, &weighted(1)).expect("consumer connect");
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..5u8 {
+        p.send(&[i]).unwrap();
+    }
+
+    let recv = |c: &mut Consumer| match c.recv_ext().unwrap() {
+        Delivery::Message(m) => m.tag.unwrap(),
+        Delivery::Eos(_) => panic!("expected a message"),
+    };
+    let big_tags: Vec<u64> = (0..3).map(|_| recv(&mut big)).collect();
+    let small_tag = recv(&mut small);
+    // Both windows are full; an ack opens exactly one slot.
+    small.ack(small_tag).unwrap();
+    let last = recv(&mut small);
+    small.ack(last).unwrap();
+    for t in big_tags {
+        big.ack(t).unwrap();
+    }
+    drop(p);
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5))).expect("idle");
+
+    let err = Consumer::connect_with(&orch.addr, &ConnectOptions::new().weight(4))
+        .err()
+        .expect("weight without ack mode is refused");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn consumers_can_pause_and_resize_their_window() {
+    use qpipe::{ConnectOptions, Consumer, Delivery, Producer};
+
+    let orch = Orchestrator::start();
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().ack_mode(true))
+        .expect("consumer connect");
+    c.set_window(0).unwrap();
+    std::thread::sleep(Duration::from_millis(100)); // the record reaches the router
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..2u8 {
+        p.send(&[i]).unwrap();
+    }
+
+    let wait = Duration::from_millis(300);
+    assert!(c.recv_ext_timeout(wait).unwrap().is_none(), "paused consumers get nothing");
+    c.set_window(1).unwrap();
+    let tag = |d| match d {
+        Some(Delivery::Message(m)) => m.tag.unwrap(),
+        other => panic!("expected a message, got {other:?}"),
+    };
+    let first = tag(c.recv_ext_timeout(Duration::from_secs(5)).unwrap());
+    assert!(c.recv_ext_timeout(wait).unwrap().is_none(), "the window holds one");
+    c.ack(first).unwrap();
+    let second = tag(c.recv_ext_timeout(Duration::from_secs(5)).unwrap());
+    c.ack(second).unwrap();
+    drop(p);
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5))).expect("idle");
+
+    let mut plain = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert_eq!(plain.set_window(1).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn round_robin_dispatch_serves_every_consumer() {
+    use qpipe::{Consumer, Producer};
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_DISPATCH", "round-robin")]);
+    let (tx, rx) = mpsc::channel();
+    for id in 0..3 {
+        let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            while let Ok(m) = c.recv() {
+                if tx.send((id, m)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    std::thread::sleep(Duration::from_millis(200)); // all three waiting
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..30u8 {
+        p.send(&[i]).unwrap();
+    }
+    let mut per = [0; 3];
+    for _ in 0..30 {
+        let (id, _) = rx.recv_timeout(Duration::from_secs(10)).expect("delivery");
+        per[id] += 1;
+    }
+    assert!(per.iter().all(|&n| n >= 5), "uneven shares: {per:?}");
+}
+
+#[test]
+fn messages_sharing_a_partition_key_reach_one_consumer_in_order() {
+    use qpipe::{ConnectOptions, Consumer, Producer};
+    use std::collections::{HashMap, HashSet};
+
+    let orch = Orchestrator::start();
+    let (tx, rx) = mpsc::channel();
+    for id in 0..3 {
+        let opts = ConnectOptions::new().ack_mode(true).weight(4);
+        let mut c = Consumer::connect_with(&orch.addr, &opts).expect("consumer connect");
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            while let Ok(m) = c.recv_ack() {
+                std::thread::sleep(Duration::from_millis(2)); // let the others overtake
+                if tx.send((id, m.payload)).is_err() || c.ack(m.tag.unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    std::thread::sleep(Duration::from_millis(200)); // all three connected
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for seq in 0..20u8 {
+        for module in 0..6u8 {
+            p.send_with_key(&[module, seq], &[module]).unwrap();
+        }
+    }
+    let mut seen: HashMap<u8, (usize, Vec<u8>)> = HashMap::new();
+    let mut used = HashSet::new();
+    for _ in 0..120 {
+        let (id, m) = rx.recv_timeout(Duration::from_secs(10)).expect("delivery");
+        let (owner, seqs) = seen.entry(m[0]).or_insert((id, Vec::new()));
+        assert_eq!(*owner, id, "module {} moved between consumers", m[0]);
+        seqs.push(m[1]);
+        used.insert(id);
+    }
+    for (module, (_, seqs)) in seen {
+        assert_eq!(seqs, (0..20).collect::<Vec<u8>>(), "module {module}");
+    }
+    assert!(used.len() > 1, "every module went to one consumer");
+}
+
+#[test]
+fn messages_requiring_capabilities_reach_only_capable_consumers() {
+    use qpipe::{ConnectOptions, Consumer, Meta, Producer};
+
+    let orch = Orchestrator::start();
+    let mut plain = Consumer::connect(&orch.addr).expect("consumer connect");
+    let opts = ConnectOptions::new().capabilities(["gpu", "bigmem"]);
+    let mut gpu = Consumer::connect_with(&orch.addr, &opts).expect("consumer connect");
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let needs_gpu = Meta { requires: vec!["gpu".into()], ..Meta::default() };
+    p.send_with_meta(b"train", &needs_gpu).unwrap();
+    p.send(b"plot").unwrap();
+
+    assert_eq!(plain.recv().unwrap().payload, b"plot");
+    assert_eq!(gpu.recv().unwrap().payload, b"train");
+
+    let bad = Meta { requires: vec!["a,b".into()], ..Meta::default() };
+    let err = p.send_with_meta(b"x", &bad).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn filtered_consumers_get_matching_messages_and_the_pool_the_rest() {
+    use qpipe::{ConnectOptions, Consumer, Filter, Headers, Producer};
+
+    let orch = Orchestrator::start();
+    let mut pool = Consumer::connect(&orch.addr).expect("consumer connect");
+    let opts = ConnectOptions::new().filter(Filter::parse("run^=sim-,site=nersc").unwrap());
+    let mut sims = Consumer::connect_with(&orch.addr, &opts).expect("consumer connect");
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let about = |run: &str| Headers::new().with("run", run).with("site", "nersc");
+    p.send_with_headers(b"sim", &about("sim-12")).unwrap();
+    p.send_with_headers(b"exp", &about("exp-3")).unwrap();
+    p.send(b"bare").unwrap();
+
+    assert_eq!(pool.recv().unwrap().payload, b"exp");
+    assert_eq!(pool.recv().unwrap().payload, b"bare");
+    assert_eq!(sims.recv().unwrap().payload, b"sim");
+}
+
+#[test]
+fn queue_delivery_policies_refuse_or_impose_ack_mode() {
+    use qpipe::{ConnectOptions, Consumer, DeliveryPolicy, Producer};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("qpipe.toml");
+    std::fs::write(&config, "delivery = \"at-least-once\"\n[queues.logs]\ndelivery = \"at-most-once\"\n").unwrap();
+    let orch = Orchestrator::start_with(&["--config", config.to_str().unwrap()], &[]);
+
+    // At most once: ack mode is refused, plain sessions served.
+    let err = Consumer::connect_with(&orch.addr, &ConnectOptions::new().queue("logs").ack_mode(true)).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported, "{err}");
+    let logs = Consumer::connect_to(&orch.addr, "logs").expect("consumer connect");
+    assert_eq!(logs.delivery_policy(), DeliveryPolicy::AtMostOnce);
+
+    // At least once: a plain consumer is put in ack mode. `recv` acks
+    // what it handed out once the consumer asks for more; what it holds
+    // when it leaves without closing comes back.
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert_eq!(c.delivery_policy(), DeliveryPolicy::AtLeastOnce);
+    assert!(c.visibility_timeout().is_some());
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(b"one").unwrap();
+    p.send(b"two").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"one");
+    assert_eq!(c.recv_timeout(Duration::from_secs(5)).unwrap().unwrap().payload, b"two");
+    drop(c);
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let again = c.recv_ack().unwrap();
+    assert_eq!((again.payload.as_slice(), again.attempt), (&b"two"[..], 1));
+    c.ack(again.tag.unwrap()).unwrap();
+    drop(c);
+
+    // Tags from a reconnecting consumer the queue put in ack mode are
+    // good for its `ack`.
+    p.send(b"three").unwrap();
+    let mut r = qpipe::ReconnectingConsumer::connect(
+        &orch.addr, &ConnectOptions::new(), &qpipe::ReconnectOptions::new(),
+    ).expect("consumer connect");
+    let three = r.recv_ack().unwrap();
+    assert_eq!(three.payload, b"three");
+    r.ack(three.tag.unwrap()).unwrap();
+    drop(r);
+
+    // The consumer binary's stdout modes ack what they print.
+    p.send(b"four").unwrap();
+    let mut out = StdCommand::new(cargo_bin("consumer"))
+        .args([orch.addr.as_str(), "--base64"])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(out.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    assert_eq!(line, "Zm91cg==\n");
+    std::thread::sleep(Duration::from_millis(200)); // the ack lands
+    out.kill().unwrap();
+    out.wait().unwrap();
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert!(c.recv_ext_timeout(Duration::from_millis(500)).unwrap().is_none(), "four was acked");
+
+    // A consumer too old to be told is turned away.
+    let mut old = TcpStream::connect(&orch.addr).unwrap();
+    old.write_all(&[qpipe::ROLE_CONSUMER]).unwrap();
+    old.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut reply = Vec::new();
+    old.read_to_end(&mut reply).expect("the orchestrator hangs up");
+    assert!(reply.is_empty(), "{reply:?}");
+}
+
+#[test]
+fn resource_hints_pack_work_onto_consumers_with_room() {
+    use qpipe::{ConnectOptions, Consumer, Delivery, Meta, Producer};
+
+    let orch = Orchestrator::start();
+    let opts = ConnectOptions::new().ack_mode(true).resources(64 << 30, 0);
+    let mut node = Consumer::connect_with(&orch.addr, &opts).expect("consumer connect");
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let needs = |gib: u64| Meta { memory: Some(gib << 30), ..Meta::default() };
+    p.send_with_meta(b"40G", &needs(40)).unwrap();
+    p.send_with_meta(b"30G", &needs(30)).unwrap();
+    p.send_with_meta(b"20G", &needs(20)).unwrap();
+
+    let recv = |c: &mut Consumer| match c.recv_ext().unwrap() {
+        Delivery::Message(m) => (m.payload, m.tag.unwrap()),
+        Delivery::Eos(_) => panic!("expected a message"),
+    };
+    let (first, t1) = recv(&mut node);
+    let (second, t2) = recv(&mut node);
+    assert_eq!((first.as_slice(), second.as_slice()), (&b"40G"[..], &b"20G"[..]));
+    node.ack(t1).unwrap();
+    let (third, t3) = recv(&mut node);
+    assert_eq!(third, b"30G");
+    node.ack(t2).unwrap();
+    node.ack(t3).unwrap();
+    drop(p);
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5))).expect("idle");
+}
+
+#[test]
+fn buffered_producer_delivers_everything_in_order() {
+    use qpipe::{Consumer, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start();
+    let opts = ProducerOptions::new().buffer(16);
+    let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+    for i in 0..100u32 {
+        p.send(&i.to_be_bytes()).unwrap();
+    }
+    p.flush().unwrap();
+    drop(p);
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for i in 0..100u32 {
+        assert_eq!(c.recv().unwrap().payload, i.to_be_bytes());
+    }
+}
+
+#[test]
+fn flush_policies_pipeline_frames_without_losing_any() {
+    use qpipe::{Consumer, FlushPolicy, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start();
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let policies = [
+        FlushPolicy::EveryFrames(7),
+        FlushPolicy::Every(Duration::from_micros(500)),
+        FlushPolicy::Manual,
+    ];
+    for policy in policies {
+        let opts = ProducerOptions::new().flush_policy(policy);
+        let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+        for i in 0..50u32 {
+            p.send(&i.to_be_bytes()).unwrap();
+        }
+        if policy != FlushPolicy::Manual {
+            p.flush().unwrap();
+        }
+        drop(p); // flushes whatever is left
+        for i in 0..50u32 {
+            assert_eq!(c.recv().unwrap().payload, i.to_be_bytes(), "{policy:?}");
+        }
+    }
+}
+
+#[test]
+fn batches_arrive_in_order_and_come_off_in_batches() {
+    use qpipe::{Consumer, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start();
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let big = vec![3u8; qpipe::MAX_FRAME_SIZE + 10];
+    let records: Vec<Vec<u8>> = (0..30u32).map(|i| i.to_be_bytes().to_vec()).collect();
+    let mut batch: Vec<&[u8]> = records.iter().map(Vec::as_slice).collect();
+    batch.insert(10, &big);
+    for opts in [ProducerOptions::new(), ProducerOptions::new().buffer(2)] {
+        let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+        p.send_batch(&batch).unwrap();
+        drop(p);
+        let mut got = Vec::new();
+        while got.len() < batch.len() {
+            let more = c.recv_batch(8).unwrap();
+            assert!((1..=8).contains(&more.len()));
+            got.extend(more.into_iter().map(|m| m.payload));
+        }
+        assert_eq!(got, batch);
+    }
+}
+
+#[test]
+fn checksummed_sessions_deliver_every_kind_of_frame() {
+    use qpipe::{ConnectOptions, Consumer, Delivery, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start();
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().checksum(true))
+        .expect("consumer connect");
+    for opts in [ProducerOptions::new(), ProducerOptions::new().buffer(4)] {
+        let mut p = Producer::connect_with(&orch.addr, &opts.checksum(true))
+            .expect("producer connect");
+        p.send(b"single").unwrap();
+        p.send_reader(&[5u8; 1000][..], 1000).unwrap();
+        p.send_batch(&[b"one", b"two"]).unwrap();
+        p.send_eos(b"done").unwrap();
+        drop(p);
+        for want in [&b"single"[..], &[5u8; 1000], b"one", b"two"] {
+            match c.recv_ext().unwrap() {
+                Delivery::Message(m) => assert!(m.payload == want, "{} bytes", m.payload.len()),
+                eos => panic!("{eos:?} before the data"),
+            }
+        }
+        assert_eq!(c.recv_ext().unwrap(), Delivery::Eos(b"done".to_vec()));
+    }
+}
+
+#[test]
+fn single_port_orchestrators_serve_only_sessions_on_the_control_connection() {
+    use qpipe::{ConnectOptions, Consumer, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start_with(&["--single-port"], &[]);
+    let err = Producer::connect(&orch.addr).err().expect("a two-port producer is turned away");
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof, "{err}");
+
+    // Through netsim too, which has no data port to rewrite.
+    let sim = NetSim::start(&orch.addr, "");
+    let mut c = Consumer::connect_with(&sim.addr, &ConnectOptions::new().single_port(true).ack_mode(true))
+        .expect("consumer connect");
+    for opts in [ProducerOptions::new(), ProducerOptions::new().buffer(4)] {
+        let mut p = Producer::connect_with(&orch.addr, &opts.single_port(true))
+            .expect("producer connect");
+        p.send(b"one").unwrap();
+        p.send(&vec![7u8; qpipe::MAX_FRAME_SIZE + 1]).unwrap();
+        drop(p);
+        for want in [&b"one"[..], &vec![7u8; qpipe::MAX_FRAME_SIZE + 1]] {
+            let m = c.recv_ack().unwrap();
+            assert!(m.payload == want, "{} bytes", m.payload.len());
+            c.ack(m.tag.unwrap()).unwrap();
+        }
+    }
+}
+
+#[cfg(feature = "ws")]
+#[test]
+fn websocket_clients_share_queues_with_tcp_clients() {
+    use qpipe::{ConnectOptions, Consumer, Producer};
+
+    let ws = format!("127.0.0.1:{}", free_port());
+    let orch = Orchestrator::start_with(&["--ws-listen", &ws], &[]);
+    let url = format!("ws://{ws}/");
+
+    // A WebSocket producer to a TCP consumer...
+    let mut p = Producer::connect_ws(&url).expect("ws producer connect");
+    p.send(b"over ws").unwrap();
+    p.send(&vec![7u8; qpipe::MAX_FRAME_SIZE + 1]).unwrap();
+    drop(p);
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert_eq!(c.recv().unwrap().payload, b"over ws");
+    assert_eq!(c.recv().unwrap().payload, vec![7u8; qpipe::MAX_FRAME_SIZE + 1]);
+    drop(c);
+
+    // ...and a TCP producer to a WebSocket consumer, acking as it goes.
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..3u32 {
+        p.send(&i.to_be_bytes()).unwrap();
+    }
+    drop(p);
+    let mut c = Consumer::connect_with(&url, &ConnectOptions::new().ack_mode(true))
+        .expect("ws consumer connect");
+    for i in 0..3u32 {
+        let m = c.recv_ack().unwrap();
+        assert_eq!(m.payload, i.to_be_bytes());
+        c.ack(m.tag.unwrap()).unwrap();
+    }
+
+    let err = Producer::connect_ws(&orch.addr).err().expect("not a ws:// address");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{err}");
+}
+
+#[test]
+fn producer_limits_hold_back_or_refuse_what_is_over_the_rate() {
+    use qpipe::{Consumer, Producer, ProducerOptions};
+    use std::time::Instant;
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_PRODUCER_LIMIT", "msgs=20/s")]);
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let start = Instant::now();
+    for i in 0..10u32 {
+        p.send(&i.to_be_bytes()).unwrap();
+    }
+    drop(p);
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for i in 0..10u32 {
+        assert_eq!(c.recv().unwrap().payload, i.to_be_bytes());
+    }
+    let took = start.elapsed();
+    assert!(took >= Duration::from_millis(350), "10 messages at 20/s took {took:?}");
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_PRODUCER_LIMIT", "msgs=5/s,mode=reject")]);
+    let opts = ProducerOptions::new().throttle_errors(true);
+    let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+    let mut sent = Vec::new();
+    for i in 0..10u32 {
+        match p.send(&i.to_be_bytes()) {
+            Ok(()) => sent.push(i),
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::QuotaExceeded, "{e}"),
+        }
+    }
+    assert!(!sent.is_empty() && sent.len() < 10, "sent {sent:?}");
+    // Producers that don't know about refusals are held back instead.
+    let mut slow = Producer::connect(&orch.addr).expect("producer connect");
+    slow.send(b"late").unwrap();
+    drop((p, slow));
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for i in sent {
+        assert_eq!(c.recv().unwrap().payload, i.to_be_bytes());
+    }
+    assert_eq!(c.recv().unwrap().payload, b"late");
+}
+
+#[test]
+fn full_queues_shed_the_oldest_or_refuse_the_newest() {
+    use qpipe::{Consumer, Producer, ProducerOptions};
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("qpipe.toml");
+    std::fs::write(&config, "[queues.metrics]\ncapacity = 2\nwhen_full = \"reject-newest\"\n").unwrap();
+    let orch = Orchestrator::start_with(
+        &["--when-full", "drop-oldest", "--config", config.to_str().unwrap(), "3", "1"], &[],
+    );
+
+    // With nobody consuming, the newest three of five get through.
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..5u8 {
+        p.send(&[i]).unwrap();
+    }
+    // Frames are ACKed on receipt, and queued just after.
+    std::thread::sleep(Duration::from_millis(200));
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for i in 2..5u8 {
+        assert_eq!(c.recv().unwrap().payload, [i]);
+    }
+
+    let opts = ProducerOptions::new().queue("metrics").throttle_errors(true);
+    let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+    p.send(b"a").unwrap();
+    p.send(b"b").unwrap();
+    assert_eq!(p.send(b"c").unwrap_err().kind(), std::io::ErrorKind::QuotaExceeded);
+    let mut metrics = Consumer::connect_to(&orch.addr, "metrics").expect("consumer connect");
+    assert_eq!(metrics.recv().unwrap().payload, b"a");
+    p.send(b"d").unwrap();
+    assert_eq!(metrics.recv().unwrap().payload, b"b");
+    assert_eq!(metrics.recv().unwrap().payload, b"d");
+
+    let watch = qpipe::watch_stats(&orch.addr).expect("subscribe");
+    let report = watch.map(Result::unwrap)
+        .find(|r| r.contains("\"collected_frames\":6,"))
+        .expect("a report with the deliveries");
+    assert!(report.ends_with("\"shed\":3}}"), "{report}");
+}
+
+#[test]
+fn a_refused_message_can_be_resent_under_its_id() {
+    use qpipe::{Consumer, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start_with(
+        &["--when-full", "reject-newest", "--dedup-window", "100", "2", "1"], &[],
+    );
+    let opts = ProducerOptions::new().throttle_errors(true);
+    let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+    p.send_with_id(b"a", b"id-a").unwrap();
+    p.send_with_id(b"b", b"id-b").unwrap();
+    let err = p.send_with_id(b"c", b"id-c").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::QuotaExceeded);
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert_eq!(c.recv().unwrap().payload, b"a");
+    // The refusal didn't claim the id, so the retry isn't taken for a repeat.
+    p.send_with_id(b"c", b"id-c").unwrap();
+    p.send_with_id(b"c", b"id-c").unwrap();
+    let wait = Duration::from_secs(5);
+    assert_eq!(c.recv_timeout(wait).unwrap().map(|m| m.payload).as_deref(), Some(&b"b"[..]));
+    assert_eq!(c.recv_timeout(wait).unwrap().map(|m| m.payload).as_deref(), Some(&b"c"[..]));
+    assert_eq!(c.recv_timeout(Duration::from_millis(300)).unwrap(), None);
+}
+
+#[test]
+fn fanout_queues_give_every_consumer_every_message() {
+    use qpipe::{ConnectOptions, Consumer, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_FANOUT_QUEUES", "alerts/*")]);
+    let feed = ConnectOptions::new().queue("alerts/disk");
+    let mut subs = [
+        Consumer::connect_with(&orch.addr, &feed).expect("consumer connect"),
+        Consumer::connect_with(&orch.addr, &feed).expect("consumer connect"),
+    ];
+    let jobs = ConnectOptions::new().queue("jobs");
+    let mut workers = [
+        Consumer::connect_with(&orch.addr, &jobs).expect("consumer connect"),
+        Consumer::connect_with(&orch.addr, &jobs).expect("consumer connect"),
+    ];
+    std::thread::sleep(Duration::from_millis(200)); // all subscribed
+
+    let mut p = Producer::connect_with(&orch.addr, &ProducerOptions::new().queue("alerts/disk")).unwrap();
+    let mut jobs = Producer::connect_with(&orch.addr, &ProducerOptions::new().queue("jobs")).unwrap();
+    for i in 0..3u32 {
+        p.send(&i.to_be_bytes()).unwrap();
+    }
+    p.send_eos(b"").unwrap();
+    jobs.send(b"once").unwrap();
+    drop((p, jobs));
+
+    for c in &mut subs {
+        for i in 0..3u32 {
+            assert_eq!(c.recv().unwrap().payload, i.to_be_bytes());
+        }
+        assert_eq!(c.recv_ext().unwrap(), qpipe::Delivery::Eos(Vec::new()));
+    }
+    // Other queues still hand each message to just one consumer.
+    let got: Vec<Vec<u8>> = workers.iter_mut()
+        .filter_map(|c| c.recv_timeout(Duration::from_millis(500)).unwrap().map(|m| m.payload))
+        .collect();
+    assert_eq!(got, vec![b"once".to_vec()]);
+}
+
+#[test]
+fn delta_encoded_producers_deliver_whole_messages() {
+    use qpipe::{Consumer, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start();
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let record = |i: u32| {
+        let mut r = format!("sensor=7;seq={i:06};").into_bytes();
+        r.extend_from_slice(&[i as u8 / 10; 200]);
+        r
+    };
+    for opts in [ProducerOptions::new().delta(8), ProducerOptions::new().delta(8).buffer(16)] {
+        let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+        for i in 0..40u32 {
+            p.send(&record(i)).unwrap();
+        }
+        p.send(b"").unwrap();
+        drop(p);
+        for i in 0..40u32 {
+            assert_eq!(c.recv().unwrap().payload, record(i));
+        }
+        assert_eq!(c.recv().unwrap().payload, b"");
+    }
+}
+
+#[test]
+fn streamed_messages_arrive_whole() {
+    use qpipe::{Consumer, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start();
+    let image: Vec<u8> = (0..qpipe::MAX_FRAME_SIZE * 2 + 5).map(|i| (i % 251) as u8).collect();
+    for opts in [ProducerOptions::new(), ProducerOptions::new().buffer(2)] {
+        let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+        p.send_reader(&image[..], image.len() as u64).unwrap();
+        p.send_reader(&b"small"[..], 5).unwrap();
+        let err = p.send_reader(&b"short"[..], 6).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        drop(p);
+
+        let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+        let mut out = Vec::new();
+        assert_eq!(c.recv_writer(&mut out).unwrap().len, image.len() as u64);
+        assert!(out == image, "streamed image differs");
+        assert_eq!(c.recv().unwrap().payload, b"small");
+    }
+}
+
+#[test]
+fn headers_reach_the_consumer_with_their_message() {
+    use qpipe::{ConnectOptions, Consumer, Headers, Producer};
+
+    let orch = Orchestrator::start();
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let json = Headers::new().with("content-type", "application/json").with("correlation-id", "7");
+    let big = vec![b'x'; qpipe::MAX_FRAME_SIZE * 2]; // chunked
+    p.send_with_headers(b"{}", &json).unwrap();
+    p.send_with_headers(&big, &Headers::new().with("content-type", "application/octet-stream"))
+        .unwrap();
+    p.send(b"plain").unwrap();
+
+    {
+        let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+        let m = c.recv().unwrap();
+        assert_eq!((m.payload.as_slice(), &m.headers), (&b"{}"[..], &json));
+        let m = c.recv().unwrap();
+        assert_eq!(m.payload, big);
+        assert_eq!(m.headers.get("content-type"), Some("application/octet-stream"));
+        assert!(c.recv().unwrap().headers.is_empty());
+    }
+
+    // Ack mode carries them next to the delivery tag.
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().ack_mode(true))
+        .expect("consumer connect");
+    p.send_with_headers(b"acked", &json).unwrap();
+    let m = c.recv_ack().unwrap();
+    assert_eq!(m.headers, json);
+    c.ack(m.tag.unwrap()).unwrap();
+}
+
+#[test]
+fn compressed_payloads_are_undone_in_recv() {
+    use qpipe::{Consumer, Meta, Producer, CODEC_ZSTD};
+
+    let orch = Orchestrator::start();
+
+    #[cfg(feature = "zstd")]
+    {
+        use qpipe::ProducerOptions;
+        let json = br#"{"sample":"s-0001","counts":[0,0,0,0,0,0,0,0,0,0,0,0]}"#.repeat(4000);
+        let opts = ProducerOptions::new().compress(3);
+        let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+        p.send(&json).unwrap();
+        p.send(b"small").unwrap();
+        drop(p);
+        // The queue holds the compressed bytes, marked with their codec.
+        qpipe::export_queue(&orch.addr, false, |frames| {
+            let (qpipe::Frame::Msg(z), meta) = &frames[0] else { panic!("{:?}", frames[0].0) };
+            assert_eq!(meta.codec, Some(CODEC_ZSTD));
+            assert!(z.len() < json.len() / 10, "{} -> {}", json.len(), z.len());
+            assert_eq!(frames[1].1.codec, None, "too small to be worth it");
+            Ok(())
+        })
+        .unwrap();
+        let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+        assert_eq!(c.recv().unwrap().payload, json);
+        assert_eq!(c.recv().unwrap().payload, b"small");
+    }
+
+    // A payload marked compressed that this build can't undo, or that isn't
+    // valid zstd, is an error rather than garbage handed to the application.
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send_with_meta(b"not zstd", &Meta { codec: Some(CODEC_ZSTD), ..Meta::default() }).unwrap();
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let want = if cfg!(feature = "zstd") {
+        std::io::ErrorKind::InvalidData
+    } else {
+        std::io::ErrorKind::Unsupported
+    };
+    assert_eq!(c.recv().unwrap_err().kind(), want);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn typed_clients_exchange_serde_values() {
+    use qpipe::typed::{TypedConsumer, TypedProducer};
+    use qpipe::ConnectOptions;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Event { id: u64, name: String, blob: Vec<u8> }
+
+    let orch = Orchestrator::start();
+    let mut p = TypedProducer::<Event>::connect(&orch.addr).expect("producer connect");
+    let big = Event { id: 2, name: "big".into(), blob: vec![7; qpipe::MAX_FRAME_SIZE + 1] };
+    p.send(&Event { id: 1, name: "alice".into(), blob: vec![] }).unwrap();
+    p.send(&big).unwrap();
+    // On the same session: an ACK means read, not yet queued, so another
+    // producer's message could overtake these.
+    p.get_mut().send(b"not an event").unwrap();
+
+    let opts = ConnectOptions::new().ack_mode(true);
+    let mut c = TypedConsumer::<Event>::connect_with(&orch.addr, &opts).expect("consumer connect");
+    let (e, tag) = c.recv_ack().unwrap();
+    assert_eq!((e.id, e.name.as_str()), (1, "alice"));
+    c.ack(tag).unwrap();
+    let (e, tag) = c.recv_ack().unwrap();
+    assert_eq!(e, big);
+    c.ack(tag).unwrap();
+    assert_eq!(c.recv_ack().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn payload_only_receives_ack_for_their_caller_on_at_least_once_queues() {
+    use qpipe::{BufferedConsumer, ConnectOptions, Consumer, Producer, ReconnectOptions, ReconnectingConsumer};
+
+    let orch = Orchestrator::start_with(&["--delivery", "at-least-once"], &[]);
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    // Each consumer takes `a` and `b` and is dropped without closing: `a`
+    // was acked when it asked for `b`, which comes back, and nothing else.
+    let left_behind = |what: &str| {
+        let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+        let m = c.recv_ack().unwrap();
+        assert_eq!((m.payload.as_slice(), m.attempt), (&b"b"[..], 1), "{what}");
+        c.ack(m.tag.unwrap()).unwrap();
+        assert!(c.recv_timeout(Duration::from_millis(300)).unwrap().is_none(), "{what}");
+        c.close().unwrap();
+    };
+
+    p.send(b"a").unwrap();
+    p.send(b"b").unwrap();
+    let got: Vec<_> = Consumer::connect(&orch.addr).unwrap().into_iter().take(2).map(|m| m.unwrap().payload).collect();
+    assert_eq!(got, [b"a", b"b"]);
+    left_behind("iterator");
+
+    p.send(b"a").unwrap();
+    p.send(b"b").unwrap();
+    let mut c = BufferedConsumer::connect_with(&orch.addr, &ConnectOptions::new(), 4).unwrap();
+    assert_eq!(c.recv_batch(1).unwrap()[0].payload, b"a");
+    assert_eq!(c.recv_timeout(Duration::from_secs(5)).unwrap().unwrap().payload, b"b");
+    drop(c);
+    left_behind("buffered");
+
+    p.send(b"a").unwrap();
+    p.send(b"b").unwrap();
+    let mut c = ReconnectingConsumer::connect(&orch.addr, &ConnectOptions::new(), &ReconnectOptions::new()).unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"a");
+    assert_eq!(c.recv().unwrap().payload, b"b");
+    drop(c);
+    left_behind("reconnecting");
+
+    // Closing acks the last one too.
+    p.send(b"a").unwrap();
+    let mut c = Consumer::connect(&orch.addr).unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"a");
+    c.close().unwrap();
+    let mut c = Consumer::connect(&orch.addr).unwrap();
+    assert!(c.recv_timeout(Duration::from_millis(300)).unwrap().is_none(), "a was acked");
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn typed_consumers_ack_what_decodes_on_at_least_once_queues() {
+    use qpipe::typed::{Format, MsgPack, TypedConsumer};
+    use qpipe::{Consumer, Producer};
+
+    let orch = Orchestrator::start_with(&["--delivery", "at-least-once"], &[]);
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(&MsgPack::encode(&1u32).unwrap()).unwrap();
+    p.send(b"\xc1 not a u32").unwrap();
+    p.send(&MsgPack::encode(&2u32).unwrap()).unwrap();
+
+    let mut c = TypedConsumer::<u32>::connect(&orch.addr).expect("consumer connect");
+    assert_eq!(c.recv().unwrap(), 1);
+    assert_eq!(c.recv().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(c.recv().unwrap(), 2);
+    c.close().unwrap();
+
+    // What didn't decode was left unacked, for a retry.
+    let mut c = Consumer::connect(&orch.addr).unwrap();
+    let m = c.recv_ack().unwrap();
+    assert_eq!((m.payload.as_slice(), m.attempt), (&b"\xc1 not a u32"[..], 1));
+    c.ack(m.tag.unwrap()).unwrap();
+    assert!(c.recv_timeout(Duration::from_millis(300)).unwrap().is_none(), "1 and 2 were acked");
+}
+
+#[test]
+fn named_queues_keep_their_traffic_apart() {
+    use qpipe::{Consumer, Producer};
+
+    let orch = Orchestrator::start();
+    let mut ingest = Producer::connect_to(&orch.addr, "ingest").expect("producer connect");
+    let mut render = Producer::connect_to(&orch.addr, "render").expect("producer connect");
+    let mut plain = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..3u8 {
+        ingest.send(&[b'i', i]).unwrap();
+        render.send(&[b'r', i]).unwrap();
+        plain.send(&[b'd', i]).unwrap();
+    }
+
+    let mut c = Consumer::connect_to(&orch.addr, "render").expect("consumer connect");
+    for i in 0..3u8 {
+        assert_eq!(c.recv().unwrap().payload, [b'r', i]);
+    }
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for i in 0..3u8 {
+        assert_eq!(c.recv().unwrap().payload, [b'd', i]);
+    }
+    let mut c = Consumer::connect_to(&orch.addr, "ingest").expect("consumer connect");
+    for i in 0..3u8 {
+        assert_eq!(c.recv().unwrap().payload, [b'i', i]);
+    }
+
+    let err = Producer::connect_to(&orch.addr, "no spaces").err().expect("invalid name");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn sharded_queues_still_deliver_everything_before_each_eos_notice() {
+    use qpipe::{Consumer, Delivery, Producer};
+
+    let orch = Orchestrator::start_with(&["--shards", "4"], &[]);
+    // Three producers on three shards, each ending with its own marker.
+    for p in 0..3u8 {
+        let mut prod = Producer::connect(&orch.addr).expect("producer connect");
+        for i in 0..20u8 {
+            prod.send(&[p, i]).unwrap();
+        }
+        prod.send_eos(&[p]).unwrap();
+    }
+
+    // One consumer, on one shard, steals the others' frames; a notice
+    // comes only after everything sent before it.
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let mut got: Vec<Vec<u8>> = Vec::new();
+    let mut notices = 0;
+    while notices < 3 {
+        match c.recv_ext().unwrap() {
+            Delivery::Message(m) => got.push(m.payload),
+            Delivery::Eos(group) => {
+                let sent = got.iter().filter(|m| m[0] == group[0]).count();
+                assert_eq!(sent, 20, "producer {}'s notice came early", group[0]);
+                notices += 1;
+            }
+        }
+    }
+    got.sort();
+    let want: Vec<Vec<u8>> = (0..3u8).flat_map(|p| (0..20u8).map(move |i| vec![p, i])).collect();
+    assert_eq!(got, want);
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5)))
+        .expect("idle once everything is consumed");
+}
+
+#[test]
+fn producers_chunk_to_the_advertised_frame_limit_and_refuse_what_cannot_fit() {
+    use qpipe::{Consumer, Producer, CHUNK_HEADER_LEN, MAX_CHUNKS};
+
+    let orch = Orchestrator::start_with(&["--max-frame-bytes", "65536"], &[]);
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    assert_eq!(p.max_frame_size(), 65536);
+    assert_eq!(p.max_message_size(), (65536 - CHUNK_HEADER_LEN) * MAX_CHUNKS as usize);
+
+    // Larger than a frame: chunked to fit rather than refused.
+    let big: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    p.send(&big).unwrap();
+    p.send_reader(&big[..], big.len() as u64).unwrap();
+
+    // Too large for any number of chunks: refused before a byte is read
+    // or written, and the producer carries on.
+    let err = p.send_reader(std::io::empty(), p.max_message_size() as u64 + 1).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    let err = p.send_eos(&[b'g'; 65536]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    p.send(b"still here").unwrap();
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert_eq!(c.recv().unwrap().payload, big);
+    assert_eq!(c.recv().unwrap().payload, big);
+    assert_eq!(c.recv().unwrap().payload, b"still here");
+}
+
+#[test]
+fn full_queues_overflow_to_disk_instead_of_blocking() {
+    use qpipe::{Consumer, Producer};
+
+    let dir = tempfile::tempdir().unwrap();
+    // Room for two frames in memory; without the overflow the third send
+    // would wait for a consumer that isn't there yet.
+    let orch = Orchestrator::start_with(
+        &["2"], &[("QPIPE_OVERFLOW_DIR", dir.path().to_str().unwrap())],
+    );
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let mut q = Producer::connect_to(&orch.addr, "jobs/big").expect("producer connect");
+    for i in 0..20u32 {
+        p.send(&i.to_be_bytes()).unwrap();
+        q.send(&i.to_be_bytes()).unwrap();
+    }
+    drop((p, q));
+    for sub in ["default", "q.jobs%2Fbig"] {
+        let segs = std::fs::read_dir(dir.path().join(sub)).unwrap().count();
+        assert!(segs > 0, "nothing spilled under {sub}");
+    }
+
+    for queue in [None, Some("jobs/big")] {
+        let mut c = match queue {
+            Some(name) => Consumer::connect_to(&orch.addr, name),
+            None => Consumer::connect(&orch.addr),
+        }
+        .expect("consumer connect");
+        for i in 0..20u32 {
+            assert_eq!(c.recv().unwrap().payload, i.to_be_bytes());
+        }
+    }
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5))).expect("idle");
+}
+
+#[test]
+fn sigterm_drains_the_queue_then_closes_consumers_cleanly() {
+    use qpipe::{Consumer, Producer};
+
+    let mut orch = Orchestrator::start();
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..3u32 {
+        p.send(&i.to_be_bytes()).unwrap();
+    }
+    drop(p);
+    let mut child = orch.child.take().unwrap();
+    let status = StdCommand::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // No new producers once the signal is seen; consumers are still served.
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while Producer::connect(&orch.addr).is_ok() {
+        assert!(std::time::Instant::now() < deadline, "producers still admitted");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for i in 0..3u32 {
+        assert_eq!(c.recv().unwrap().payload, i.to_be_bytes());
+    }
+    // Drained: the orchestrator hangs up at a frame boundary and exits.
+    let err = c.recv().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof, "{err}");
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(std::time::Instant::now() < deadline, "orchestrator still running");
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success(), "{status}");
+}
+
+#[test]
+fn embedded_orchestrators_serve_clients_until_shut_down() {
+    use qpipe::orchestrator::{Orchestrator, OrchestratorOptions};
+    use qpipe::{Consumer, Producer};
+    use std::sync::Arc;
+
+    let opts = OrchestratorOptions::new().capacity(16);
+    let orch = Arc::new(Orchestrator::bind_with("127.0.0.1:0", &opts).expect("bind"));
+    let addr = orch.local_addr().to_string();
+    let server = {
+        let orch = orch.clone();
+        std::thread::spawn(move || orch.run())
+    };
+
+    let mut c = Consumer::connect(&addr).expect("consumer connect");
+    let mut p = Producer::connect(&addr).expect("producer connect");
+    p.send(b"in-process").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"in-process");
+    drop(p);
+    qpipe::wait_for_idle(&addr, None, Some(Duration::from_secs(5))).expect("idle");
+
+    orch.shutdown();
+    server.join().unwrap().expect("clean exit");
+    assert!(c.recv().is_err(), "consumers are closed on the way out");
+    let stats = orch.stats();
+    assert_eq!((stats.posted_frames, stats.collected_frames), (1, 1));
+    assert_eq!((stats.producers, stats.consumers, stats.in_queue), (0, 0, 0));
+    assert!(orch.run().is_err(), "an orchestrator runs once");
+}
+
+#[test]
+fn sharded_queues_deliver_what_lands_in_a_shard_without_consumers() {
+    use qpipe::orchestrator::{Orchestrator, OrchestratorOptions};
+    use qpipe::{Consumer, Producer};
+    use std::sync::Arc;
+
+    // Small frames, so 200 KiB messages go in chunks.
+    let opts = OrchestratorOptions::new().shards(2).max_frame_bytes(qpipe::MIN_FRAME_LIMIT);
+    let orch = Arc::new(Orchestrator::bind_with("127.0.0.1:0", &opts).expect("bind"));
+    let addr = orch.local_addr().to_string();
+    let server = {
+        let orch = orch.clone();
+        std::thread::spawn(move || orch.run())
+    };
+
+    // One consumer for two shards; a producer on each.
+    let mut c = Consumer::connect(&addr).expect("consumer connect");
+    let mut producers = [Producer::connect(&addr).unwrap(), Producer::connect(&addr).unwrap()];
+    let mut want = Vec::new();
+    for (i, p) in producers.iter_mut().enumerate() {
+        let big = vec![i as u8; 200 * 1024];
+        p.send(&big).unwrap();
+        want.push(big);
+        // Enough keys that some belong to either shard.
+        for key in 0..8 {
+            for n in 0..2 {
+                let m = format!("p{i}/k{key}/{n}").into_bytes();
+                p.send_with_key(&m, format!("k{key}").as_bytes()).unwrap();
+                want.push(m);
+            }
+        }
+    }
+    drop(producers);
+
+    let mut got = Vec::new();
+    while got.len() < want.len() {
+        match c.recv_timeout(Duration::from_secs(10)).unwrap() {
+            Some(m) => got.push(m.payload),
+            None => panic!("{} of {} messages delivered", got.len(), want.len()),
+        }
+    }
+    // Each key's messages in order.
+    for i in 0..2 {
+        for key in 0..8 {
+            let at = |n| got.iter().position(|m| *m == format!("p{i}/k{key}/{n}").into_bytes());
+            assert!(at(0) < at(1), "p{i}/k{key}");
+        }
+    }
+    got.sort();
+    want.sort();
+    assert_eq!(got, want);
+
+    orch.shutdown();
+    server.join().unwrap().expect("clean exit");
+}
+
+#[test]
+fn unix_socket_sessions_work_like_tcp_ones() {
+    use qpipe::{ConnectOptions, Consumer, Producer};
+
+    let dir = tempfile::tempdir().unwrap();
+    let sock = dir.path().join("qpipe.sock");
+    let addr = format!("unix://{}", sock.display());
+    let orch = Orchestrator::start_on(&addr, &[], &[]);
+
+    let mut p = Producer::connect(&addr).expect("producer connect");
+    let big = vec![0x5a; qpipe::MAX_FRAME_SIZE + 10]; // chunked
+    p.send(b"hello").unwrap();
+    p.send(&big).unwrap();
+    let mut c = Consumer::connect_with(&addr, &ConnectOptions::new().ack_mode(true))
+        .expect("consumer connect");
+    for want in [&b"hello"[..], &big] {
+        let m = c.recv_ack().unwrap();
+        assert_eq!(m.payload, want);
+        c.ack(m.tag.unwrap()).unwrap();
+    }
+    assert_eq!(c.try_recv().unwrap(), None);
+    drop(p);
+    qpipe::wait_for_idle(&addr, None, Some(Duration::from_secs(5))).unwrap();
+
+    // Session sockets are gone once their clients are connected.
+    let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(names, ["qpipe.sock"]);
+
+    // A second orchestrator can't take over a live socket.
+    let out = StdCommand::new(cargo_bin("orchestrator")).arg(&addr).output().unwrap();
+    assert!(!out.status.success());
+    drop((c, orch));
+}
+
+#[test]
+fn sessions_past_the_cap_are_turned_away() {
+    use qpipe::{Consumer, Producer};
+    use std::time::Instant;
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_MAX_SESSIONS", "1")]);
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(b"queued").unwrap();
+    assert!(Consumer::connect(&orch.addr).is_err(), "second session admitted");
+    // Admin roles don't count against the cap.
+    qpipe::wait_until_healthy(&orch.addr, Some(Duration::from_secs(1))).expect("healthy");
+
+    drop(p);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut c = loop {
+        match Consumer::connect(&orch.addr) {
+            Ok(c) => break c,
+            Err(e) if Instant::now() > deadline => panic!("slot never freed: {e}"),
+            Err(_) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    };
+    assert_eq!(c.recv().unwrap().payload, b"queued");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn idle_producers_hold_no_thread() {
+    use qpipe::{Consumer, Producer};
+
+    let orch = Orchestrator::start();
+    let pid = orch.child.as_ref().unwrap().id();
+    let threads = || std::fs::read_dir(format!("/proc/{pid}/task")).unwrap().count();
+    let before = threads();
+    let mut producers: Vec<_> = (0..200)
+        .map(|_| Producer::connect(&orch.addr).expect("producer connect"))
+        .collect();
+    for (i, p) in producers.iter_mut().enumerate() {
+        p.send(&(i as u32).to_be_bytes()).unwrap();
+    }
+    let open = threads();
+    assert!(open < before + 20, "{open} threads for 200 producers, from {before}");
+
+    // Parked, they are still served.
+    for p in &mut producers {
+        p.send(b"again").unwrap();
+    }
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for _ in 0..400 {
+        c.recv().unwrap();
+    }
+}
+
+#[test]
+fn sighup_reloads_the_configuration_file_without_dropping_sessions() {
+    use qpipe::{Consumer, Producer};
+    use std::time::Instant;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("qpipe.toml");
+    std::fs::write(&config, "capacity = 100\n[limits]\nmax_sessions = 1\n").unwrap();
+    let mut orch = Orchestrator::start_with(&["--config", config.to_str().unwrap()], &[]);
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(b"before").unwrap();
+    assert!(Consumer::connect(&orch.addr).is_err(), "second session admitted");
+
+    let child = orch.child.take().unwrap();
+    let hup = || {
+        let status = StdCommand::new("kill").args(["-HUP", &child.id().to_string()]).status().unwrap();
+        assert!(status.success());
+    };
+    // A file that no longer parses changes nothing.
+    std::fs::write(&config, "capacity = lots\n").unwrap();
+    hup();
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(Consumer::connect(&orch.addr).is_err(), "cap lifted by a broken file");
+
+    std::fs::write(&config, "capacity = 100\n[limits]\nmax_sessions = 2\n").unwrap();
+    hup();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut c = loop {
+        match Consumer::connect(&orch.addr) {
+            Ok(c) => break c,
+            Err(e) if Instant::now() > deadline => panic!("cap never raised: {e}"),
+            Err(_) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    };
+    // The producer's session outlived both reloads.
+    p.send(b"after").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"before");
+    assert_eq!(c.recv().unwrap().payload, b"after");
+    orch.child = Some(child);
+}
+
+#[test]
+fn heartbeats_keep_quiet_sessions_and_drop_silent_ones() {
+    use qpipe::{ConnectOptions, Consumer, Producer, ProducerOptions};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Instant;
+
+    // The orchestrator imposes its own heartbeat on clients asking for one.
+    let orch = Orchestrator::start_with_env(&[("QPIPE_HEARTBEAT", "100ms,400ms")]);
+    let (interval, timeout) = (Duration::from_secs(1), Duration::from_secs(60));
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().heartbeat(interval, timeout))
+        .expect("consumer connect");
+    let mut p = Producer::connect_with(&orch.addr, &ProducerOptions::new().heartbeat(interval, timeout))
+        .expect("producer connect");
+    // Both stay quiet well past the timeout; their pings keep them in.
+    std::thread::sleep(Duration::from_secs(1));
+    p.send(b"still here").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"still here");
+
+    // Sessions that ask for heartbeats and then never ping: the producer
+    // is dropped, and so is the consumer, whatever it is sent meanwhile.
+    for role in [qpipe::ROLE_PRODUCER, qpipe::ROLE_CONSUMER] {
+        let mut ctrl = TcpStream::connect(&orch.addr).unwrap();
+        ctrl.write_all(&[role | qpipe::ROLE_FLAG_OPTS]).unwrap();
+        let asked = [0, 0, 0x03, 0xe8, 0, 0, 0xea, 0x60]; // 1s, 60s
+        qpipe::write_options(&mut ctrl, &[(qpipe::OPT_HEARTBEAT, &asked)]).unwrap();
+        let mut port = [0u8; 2];
+        let mut token = [0u8; qpipe::TOKEN_LEN];
+        ctrl.read_exact(&mut port).unwrap();
+        ctrl.read_exact(&mut token).unwrap();
+        let reply = qpipe::read_options(&mut ctrl).unwrap();
+        let imposed = [0, 0, 0, 100, 0, 0, 0x01, 0x90]; // 100ms, 400ms
+        assert_eq!(reply, vec![(qpipe::OPT_HEARTBEAT, imposed.to_vec())]);
+        let mut data = TcpStream::connect(("127.0.0.1", u16::from_be_bytes(port))).unwrap();
+        data.write_all(&token).unwrap();
+        data.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let t0 = Instant::now();
+        data.read_to_end(&mut Vec::new()).expect("the orchestrator hangs up");
+        assert!(t0.elapsed() >= Duration::from_millis(300), "{:?}", t0.elapsed());
+    }
+}
+
+#[test]
+fn session_timeouts_drop_stalled_and_idle_peers() {
+    use qpipe::{ConnectOptions, Consumer, Producer, ProducerOptions};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Instant;
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_SESSION_TIMEOUTS", "read=300ms,idle=2s")]);
+
+    // A producer that stops halfway through a frame gets the read timeout,
+    // well before the idle one.
+    let mut ctrl = TcpStream::connect(&orch.addr).unwrap();
+    ctrl.write_all(&[qpipe::ROLE_PRODUCER]).unwrap();
+    let mut port = [0u8; 2];
+    let mut token = [0u8; qpipe::TOKEN_LEN];
+    ctrl.read_exact(&mut port).unwrap();
+    ctrl.read_exact(&mut token).unwrap();
+    let mut data = TcpStream::connect(("127.0.0.1", u16::from_be_bytes(port))).unwrap();
+    data.write_all(&token).unwrap();
+    data.write_all(&[0, 0, 0, 10, b'h', b'a']).unwrap();
+    data.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let t0 = Instant::now();
+    data.read_to_end(&mut Vec::new()).expect("the orchestrator hangs up");
+    assert!(t0.elapsed() < Duration::from_millis(1500), "{:?}", t0.elapsed());
+
+    // Deliveries keep a consumer in; without any it is dropped once idle.
+    let opts = ConnectOptions::new().keepalive(Duration::from_secs(30)).buffer_sizes(1 << 16, 1 << 16);
+    let mut c = Consumer::connect_with(&orch.addr, &opts).expect("consumer connect");
+    let mut p = Producer::connect_with(&orch.addr, &ProducerOptions::new().keepalive(Duration::from_secs(30)))
+        .expect("producer connect");
+    for _ in 0..3 {
+        std::thread::sleep(Duration::from_millis(800));
+        p.send(b"busy").unwrap();
+        assert_eq!(c.recv().unwrap().payload, b"busy");
+    }
+    let t0 = Instant::now();
+    assert!(c.recv().is_err(), "an idle consumer is dropped");
+    assert!(t0.elapsed() >= Duration::from_millis(1500), "{:?}", t0.elapsed());
+
+    // An orchestrator that accepts but never answers: the connect timeout
+    // bounds the handshake too.
+    let mute = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = mute.local_addr().unwrap().to_string();
+    let t0 = Instant::now();
+    let opts = ProducerOptions::new().connect_timeout(Duration::from_millis(300));
+    assert!(Producer::connect_with(&addr, &opts).is_err());
+    assert!(t0.elapsed() < Duration::from_secs(5), "{:?}", t0.elapsed());
+}
+
+#[test]
+fn session_tokens_expire_and_data_ports_close() {
+    use qpipe::{Consumer, Producer};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Instant;
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_SESSION_TOKEN", "ttl=500ms,attempts=2")]);
+    let session = || {
+        let mut ctrl = TcpStream::connect(&orch.addr).unwrap();
+        ctrl.write_all(&[qpipe::ROLE_PRODUCER]).unwrap();
+        let mut port = [0u8; 2];
+        ctrl.read_exact(&mut port).unwrap();
+        ctrl.read_exact(&mut [0u8; qpipe::TOKEN_LEN]).unwrap();
+        ("127.0.0.1", u16::from_be_bytes(port))
+    };
+    let closes = |port| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while TcpStream::connect(port).is_ok() {
+            assert!(Instant::now() < deadline, "data port {port:?} still open");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    };
+
+    // A client that never shows up.
+    let port = session();
+    std::thread::sleep(Duration::from_millis(700));
+    closes(port);
+
+    // Wrong tokens, until the port gives up on them.
+    let port = session();
+    for _ in 0..2 {
+        let mut data = TcpStream::connect(port).unwrap();
+        data.write_all(&[0; qpipe::TOKEN_LEN]).unwrap();
+        data.read_to_end(&mut Vec::new()).unwrap();
+    }
+    closes(port);
+
+    // Clients that come straight over are unaffected.
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    p.send(b"on time").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"on time");
+}
+
+#[test]
+fn closing_clients_flush_and_hand_back_what_they_held() {
+    use qpipe::{ConnectOptions, Consumer, Producer, ProducerOptions};
+    use std::time::Instant;
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_RETRY_POLICY", "timeout=30s,backoff=0")]);
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().ack_mode(true)).expect("consumer connect");
+
+    // A buffered producer's close waits until both messages are ACKed.
+    let mut p = Producer::connect_with(&orch.addr, &ProducerOptions::new().buffer(16)).expect("producer connect");
+    p.send(b"held").unwrap();
+    p.send(b"kept").unwrap();
+    p.close().expect("clean producer close");
+
+    let held = c.recv_ack().unwrap();
+    assert_eq!(held.payload, b"held");
+    let kept = c.recv_ack().unwrap();
+    c.ack(kept.tag.unwrap()).unwrap();
+    let start = Instant::now();
+    c.close().expect("clean consumer close");
+
+    // The unacked message goes to the next consumer without waiting out the
+    // visibility timeout.
+    let mut next = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert_eq!(next.recv().unwrap().payload, b"held");
+    assert!(start.elapsed() < Duration::from_secs(10), "requeued at close");
+
+    // The orchestrator is still there for the next session.
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(b"after").unwrap();
+    p.close().unwrap();
+    assert_eq!(next.recv().unwrap().payload, b"after");
+}
+
+#[test]
+fn buffered_consumers_prefetch_and_hand_back_what_they_held_at_close() {
+    use qpipe::{BufferedConsumer, ConnectOptions, Consumer, Producer};
+    use std::time::Instant;
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_RETRY_POLICY", "timeout=30s,backoff=0")]);
+    let opts = ConnectOptions::new().ack_mode(true).heartbeat(Duration::from_millis(100), Duration::from_secs(1));
+    let mut c = BufferedConsumer::connect_with(&orch.addr, &opts, 4).expect("consumer connect");
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..6 {
+        p.send(format!("m{i}").as_bytes()).unwrap();
+    }
+
+    // Pings and acks share the connection with the reader thread.
+    for i in 0..2 {
+        let m = c.recv_ack().unwrap();
+        assert_eq!(m.payload, format!("m{i}").into_bytes());
+        std::thread::sleep(Duration::from_millis(300));
+        c.ack(m.tag.unwrap()).unwrap();
+    }
+    let start = Instant::now();
+    c.close().expect("clean consumer close");
+
+    // What was prefetched but never acked goes to the next consumer.
+    let mut next = Consumer::connect(&orch.addr).expect("consumer connect");
+    let mut rest: Vec<_> = (0..4).map(|_| String::from_utf8(next.recv().unwrap().payload).unwrap()).collect();
+    rest.sort();
+    assert_eq!(rest, ["m2", "m3", "m4", "m5"]);
+    assert!(start.elapsed() < Duration::from_secs(10), "requeued at close");
+}
+
+#[test]
+fn requests_get_their_replies_from_whichever_worker_took_them() {
+    use qpipe::admin::Admin;
+    use qpipe::rpc::{Replier, Requester, HEADER_CORRELATION_ID};
+    use qpipe::{ConnectOptions, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start();
+    let worker = {
+        let addr = orch.addr.clone();
+        std::thread::spawn(move || {
+            let opts = ConnectOptions::new().queue("jobs").ack_mode(true);
+            let mut rp = Replier::connect_with(&addr, &opts, &ProducerOptions::new()).expect("replier connect");
+            for _ in 0..3 {
+                let req = rp.recv().unwrap();
+                let mut out = req.message.payload.clone();
+                out.reverse();
+                rp.reply(&req, &out).unwrap();
+            }
+            // A plain message expects no reply.
+            let req = rp.recv().unwrap();
+            assert_eq!(req.reply_to(), None);
+            assert_eq!(rp.reply(&req, b"").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        })
+    };
+
+    let mut rq = Requester::connect_to(&orch.addr, "jobs").expect("requester connect");
+    let reply = rq.request(b"abc", Duration::from_secs(10)).unwrap();
+    assert_eq!(reply.payload, b"cba");
+    assert_eq!(reply.headers.get(HEADER_CORRELATION_ID), Some("1"));
+    // Pipelined: the replies pair up by id.
+    let a = rq.send(b"one").unwrap();
+    let b = rq.send(b"two").unwrap();
+    let mut got = std::collections::BTreeMap::new();
+    while got.len() < 2 {
+        let (id, msg) = rq.recv_reply(Duration::from_secs(10)).unwrap().expect("a reply");
+        got.insert(id, msg.payload);
+    }
+    assert_eq!((&got[&a][..], &got[&b][..]), (&b"eno"[..], &b"owt"[..]));
+    Producer::connect_to(&orch.addr, "jobs").unwrap().send(b"fire and forget").unwrap();
+    worker.join().unwrap();
+    assert_eq!(
+        rq.request(b"nobody home", Duration::from_millis(200)).unwrap_err().kind(),
+        std::io::ErrorKind::TimedOut,
+    );
+
+    // The reply queue goes with the requester.
+    let reply_to = rq.reply_to().to_string();
+    let listed = || Admin::connect(&orch.addr).unwrap().queues().unwrap().iter().any(|q| q.name == reply_to);
+    assert!(listed());
+    rq.close().unwrap();
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while listed() {
+        assert!(std::time::Instant::now() < deadline, "reply queue {reply_to} still there");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn the_orchestrator_listens_on_every_address_given() {
+    use qpipe::{Consumer, Producer};
+
+    if std::net::TcpListener::bind("[::1]:0").is_err() {
+        eprintln!("no IPv6 loopback here; skipping");
+        return;
+    }
+    // Wildcards of both families on one port, and a Unix socket.
+    let port = free_port();
+    let sock = std::env::temp_dir().join(format!("qpipe-listen-{}-{port}.sock", std::process::id()));
+    let unix = format!("unix://{}", sock.display());
+    let child = StdCommand::new(cargo_bin("orchestrator"))
+        .args(["--listen", &format!("0.0.0.0:{port}"), "--listen", &format!("[::]:{port}"), "--listen", &unix])
+        .env("RUST_LOG", "warn")
+        .spawn()
+        .expect("failed to spawn orchestrator binary");
+    let v4 = format!("127.0.0.1:{port}");
+    let v6 = format!("[::1]:{port}");
+    qpipe::wait_until_healthy(&v4, Some(Duration::from_secs(5))).expect("healthy");
+    let _orch = Orchestrator { addr: v4.clone(), child: Some(child) };
+
+    // One consumer at a time: with free dispatch either could take both.
+    let mut p = Producer::connect(&v4).expect("producer over IPv4");
+    let mut c6 = Consumer::connect(&v6).expect("consumer over IPv6");
+    p.send(b"one").unwrap();
+    assert_eq!(c6.recv().unwrap().payload, b"one");
+    c6.close().unwrap();
+    let mut cu = Consumer::connect(&unix).expect("consumer over the Unix socket");
+    p.send(b"two").unwrap();
+    assert_eq!(cu.recv().unwrap().payload, b"two");
+
+    // A taken address keeps the orchestrator from starting at all.
+    let out = StdCommand::new(cargo_bin("orchestrator"))
+        .args(["--listen", &format!("127.0.0.1:{}", free_port()), "--listen", &v4])
+        .env("RUST_LOG", "error")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains(&v4), "names the address");
+}
+
+#[cfg(unix)]
+#[test]
+fn socket_units_pass_the_listener_and_hear_when_it_is_ready_and_stopping() {
+    use qpipe::{Consumer, Producer};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::os::unix::process::CommandExt;
+
+    unsafe extern "C" {
+        fn dup(fd: i32) -> i32;
+        fn dup2(fd: i32, to: i32) -> i32;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let notify = dir.path().join("notify");
+    let manager = UnixDatagram::bind(&notify).unwrap();
+    manager.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let next = || {
+        let mut buf = [0; 256];
+        let n = manager.recv(&mut buf).expect("a notification");
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    };
+
+    // What a socket unit does: the bound socket as fd 3, for the pid the
+    // service runs as. The address on the command line can't be bound.
+    let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    let fd = socket.as_raw_fd();
+    let mut cmd = StdCommand::new("sh");
+    cmd.args(["-c", "LISTEN_PID=$$ exec \"$0\" unix:///nonexistent/qpipe.sock"])
+        .arg(cargo_bin("orchestrator"))
+        .env("LISTEN_FDS", "1")
+        .env("NOTIFY_SOCKET", &notify)
+        .env("RUST_LOG", "warn");
+    // SAFETY: dup and dup2 are async-signal-safe. dup's copy isn't
+    // close-on-exec, and neither is dup2's.
+    unsafe {
+        cmd.pre_exec(move || match dup(fd) {
+            3 => Ok(()),
+            copy if copy >= 0 && dup2(copy, 3) == 3 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        });
+    }
+    let child = cmd.spawn().expect("failed to spawn orchestrator binary");
+    let _orch = Orchestrator { addr: addr.clone(), child: Some(child) };
+    drop(socket);
+
+    let ready = next();
+    assert!(ready.starts_with("READY=1\n"), "{ready:?}");
+    assert!(ready.contains(&addr), "{ready:?}");
+    let mut c = Consumer::connect(&addr).expect("consumer connect");
+    let mut p = Producer::connect(&addr).expect("producer connect");
+    p.send(b"activated").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"activated");
+    drop(p);
+
+    qpipe::request_drain(&addr).unwrap();
+    let stopping = next();
+    assert!(stopping.starts_with("STOPPING=1\n"), "{stopping:?}");
+}
+
+#[test]
+fn stats_reports_come_as_json_lines_to_stdout_and_subscribers() {
+    use qpipe::{Consumer, Producer};
+    use std::process::Stdio;
+
+    let addr = format!("127.0.0.1:{}", free_port());
+    let child = StdCommand::new(cargo_bin("orchestrator"))
+        .args([&addr, "100", "1", "--stats-format", "json"])
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to spawn orchestrator binary");
+    qpipe::wait_until_healthy(&addr, Some(Duration::from_secs(5))).expect("healthy");
+    let mut orch = Orchestrator { addr, child: Some(child) };
+
+    let mut watch = qpipe::watch_stats(&orch.addr).expect("subscribe");
+    let mut cli = StdCommand::new(cargo_bin("orchestrator"))
+        .args(["--stats", &orch.addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for _ in 0..3 {
+        p.send(b"counted").unwrap();
+        c.recv().unwrap();
+    }
+
+    // Subscribers see the totals catch up within a report or two.
+    let totals = |report: &str| report[report.find("\"totals\":").expect(report)..].to_string();
+    let report = watch.by_ref().map(Result::unwrap)
+        .find(|r| totals(r).contains("\"collected_frames\":3,"))
+        .expect("a report with the deliveries");
+    assert!(report.starts_with("{\"time_ms\":") && report.ends_with("}}"), "{report}");
+    assert!(totals(&report).starts_with("\"totals\":{\"posted_frames\":3,\"posted_bytes\":21,"));
+    assert!(report.contains("\"producers\":1,\"consumers\":1,"), "{report}");
+
+    // The orchestrator prints the same objects; `--stats` relays them.
+    let printed = read_n_lines(orch.child.as_mut().unwrap(), 1, Duration::from_secs(5));
+    assert!(printed[0].starts_with("{\"time_ms\":"), "{printed:?}");
+    let relayed = read_n_lines(&mut cli, 1, Duration::from_secs(5));
+    assert!(relayed[0].contains("\"totals\":{"), "{relayed:?}");
+
+    // Reports stop once the orchestrator drains, and so do subscriptions.
+    drop(p);
+    qpipe::request_drain(&orch.addr).unwrap();
+    assert!(watch.all(|r| r.is_ok()), "subscription ends cleanly");
+    assert!(cli.wait().unwrap().success());
+}
+
+#[test]
+fn admin_cli_lists_sessions_pauses_intake_and_purges() {
+    use qpipe::admin::Admin;
+    use qpipe::{Consumer, Producer};
+
+    let orch = Orchestrator::start();
+    let admin = |args: &[&str]| {
+        let out = StdCommand::new(cargo_bin("qpipe-admin")).arg(&orch.addr).args(args).output().unwrap();
+        (out.status.success(), String::from_utf8_lossy(&out.stdout).into_owned()
+            + &String::from_utf8_lossy(&out.stderr))
+    };
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let _c = Consumer::connect_to(&orch.addr, "side").expect("consumer connect");
+    for i in 0..3u8 {
+        p.send(&[i]).unwrap();
+    }
+
+    let (ok, out) = admin(&["sessions"]);
+    assert!(ok, "{out}");
+    let roles: Vec<_> = out.lines().skip(1).map(|l| l.split_whitespace().next().unwrap()).collect();
+    assert_eq!(roles, ["producer", "consumer"], "{out}");
+    assert!(out.contains("(default)") && out.contains("side"), "{out}");
+
+    // While paused, what the producer sends waits outside the queue.
+    assert!(admin(&["pause"]).0);
+    p.send(b"held").unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    let mut session = Admin::connect(&orch.addr).expect("admin connect");
+    let queues = session.queues().unwrap();
+    assert_eq!(queues.len(), 2);
+    assert!(queues.iter().all(|q| q.paused), "{queues:?}");
+    assert_eq!((queues[0].name.as_str(), queues[0].depth), ("", 3));
+
+    let (ok, out) = admin(&["purge"]);
+    assert!(ok && out.contains("purged 3 frames"), "{out}");
+    session.resume(None).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(session.queues().unwrap()[0].depth, 1, "the held frame is in");
+
+    let (ok, out) = admin(&["purge", "nope"]);
+    assert!(!ok && out.contains("no queue named \"nope\""), "{out}");
+    assert!(!admin(&["frobnicate"]).0);
+}
+
+#[test]
+fn roles_have_their_own_caps_and_refused_intake_turns_producers_away() {
+    use qpipe::admin::Admin;
+    use qpipe::{Consumer, Producer, ProducerOptions};
+    use std::io;
+
+    let orch = Orchestrator::start_with(&["--max-producers", "1", "--max-consumers", "1"], &[]);
+    let admin = |args: &[&str]| {
+        let out = StdCommand::new(cargo_bin("qpipe-admin")).arg(&orch.addr).args(args).output().unwrap();
+        (out.status.success(), String::from_utf8_lossy(&out.stdout).into_owned()
+            + &String::from_utf8_lossy(&out.stderr))
+    };
+    let opts = ProducerOptions::new().throttle_errors(true);
+    let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert!(Producer::connect(&orch.addr).is_err(), "second producer admitted");
+    assert!(Consumer::connect(&orch.addr).is_err(), "second consumer admitted");
+    p.send(b"before").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"before");
+
+    // Refused intake answers producers that can be told at once.
+    let (ok, out) = admin(&["pause", "--refuse"]);
+    assert!(ok && out.contains("intake refused for all queues"), "{out}");
+    let (ok, out) = admin(&["queues"]);
+    assert!(ok && out.contains("refusing"), "{out}");
+    assert_eq!(p.send(b"refused").unwrap_err().kind(), io::ErrorKind::QuotaExceeded);
+    assert!(Admin::connect(&orch.addr).unwrap().queues().unwrap()[0].refusing);
+
+    assert!(admin(&["resume"]).0);
+    p.send(b"after").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"after");
+    assert!(!admin(&["resume", "--refuse"]).0);
+}
+
+#[test]
+fn named_clients_are_listed_and_counted_by_name() {
+    use qpipe::admin::Admin;
+    use qpipe::{ConnectOptions, Consumer, Producer, ProducerOptions};
+    use std::io;
+
+    let orch = Orchestrator::start();
+    let named = ProducerOptions::new().name("ingest-1");
+    let mut p = Producer::connect_with(&orch.addr, &named).expect("producer connect");
+    for i in 0..3u8 {
+        p.send(&[i; 10]).unwrap();
+    }
+    Command::new(cargo_bin("producer"))
+        .arg(&orch.addr)
+        .env("QPIPE_NAME", "ingest-1")
+        .write_stdin("hello\n")
+        .timeout(Duration::from_secs(10))
+        .assert()
+        .success();
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().name("worker 7"))
+        .expect("consumer connect");
+    for _ in 0..4 {
+        c.recv().unwrap();
+    }
+    let bad = ProducerOptions::new().name("tab\there");
+    assert_eq!(Producer::connect_with(&orch.addr, &bad).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+
+    let mut admin = Admin::connect(&orch.addr).expect("admin connect");
+    let names: Vec<String> = admin.sessions().unwrap().into_iter().map(|s| s.name).collect();
+    assert_eq!(names, ["ingest-1", "worker 7"]);
+    // The consumer's last ACK may still be on its way.
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    let clients = loop {
+        let clients = admin.clients().unwrap();
+        if clients.iter().any(|c| c.collected_frames == 4) || std::time::Instant::now() > deadline {
+            break clients;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    let counts: Vec<_> = clients.iter()
+        .map(|c| (c.name.as_str(), c.producers, c.consumers, c.posted_frames, c.posted_bytes, c.collected_frames))
+        .collect();
+    assert_eq!(counts, [("ingest-1", 1, 0, 4, 35, 0), ("worker 7", 0, 1, 0, 0, 4)]);
+
+    let out = StdCommand::new(cargo_bin("qpipe-admin")).args([&orch.addr, "clients"]).output().unwrap();
+    let out = String::from_utf8_lossy(&out.stdout);
+    assert!(out.lines().nth(1).is_some_and(|l| l.starts_with("ingest-1")), "{out}");
+}
+
+#[test]
+fn lingering_frames_are_flushed_without_further_sends() {
+    use qpipe::{Consumer, FlushPolicy, Producer, ProducerOptions};
+
+    let orch = Orchestrator::start();
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let manual = ProducerOptions::new()
+        .flush_policy(FlushPolicy::Manual)
+        .linger(Duration::from_millis(5));
+    for opts in [manual.clone(), manual.buffer(16)] {
+        let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+        for i in 0..3u32 {
+            p.send(&i.to_be_bytes()).unwrap();
+        }
+        // No flush, no drop: only the linger timer can push these out.
+        for i in 0..3u32 {
+            assert_eq!(c.recv().unwrap().payload, i.to_be_bytes());
+        }
+        drop(p);
+    }
+}
+
+#[test]
+fn buffered_producer_can_refuse_instead_of_blocking() {
+    use qpipe::{Consumer, Producer, ProducerOptions, WhenFull};
+
+    // Capacity 2 and no consumer: the orchestrator soon stops ACKing.
+    let orch = Orchestrator::start_with(&["2"], &[]);
+    let opts = ProducerOptions::new().buffer(1).when_full(WhenFull::Error);
+    let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+    let mut accepted = 0u32;
+    let err = loop {
+        match p.send(&accepted.to_be_bytes()) {
+            Ok(()) => accepted += 1,
+            Err(e) => break e,
+        }
+        assert!(accepted < 100, "the buffer never filled up");
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for i in 0..accepted {
+        assert_eq!(c.recv().unwrap().payload, i.to_be_bytes());
+    }
+    p.flush().unwrap();
+}
+
+#[test]
+fn buffered_producer_can_drop_its_oldest_messages_to_keep_up() {
+    use qpipe::{Consumer, Producer, ProducerOptions, WhenFull};
+
+    // Capacity 2 and no consumer: the orchestrator soon stops ACKing, yet
+    // sending never blocks.
+    let orch = Orchestrator::start_with(&["2"], &[]);
+    let opts = ProducerOptions::new().buffer(4).when_full(WhenFull::DropOldest);
+    let mut p = Producer::connect_with(&orch.addr, &opts).expect("producer connect");
+    for i in 0..50u32 {
+        p.send(&i.to_be_bytes()).unwrap();
+    }
+    let dropped = p.dropped();
+    assert!(dropped > 0, "the buffer never filled up");
+
+    // The newest messages survive, in order.
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let mut got = Vec::new();
+    while got.last() != Some(&49) {
+        got.push(u32::from_be_bytes(c.recv().unwrap().payload.try_into().unwrap()));
+    }
+    assert!(got.windows(2).all(|w| w[0] < w[1]), "{got:?}");
+    assert_eq!(got.len() as u64 + dropped, 50, "{got:?}");
+    p.flush().unwrap();
+    assert_eq!(p.dropped(), dropped);
+}
+
+#[test]
+fn reconnecting_producer_spools_through_an_outage() {
+    use qpipe::{Consumer, ReconnectOptions, ReconnectingProducer};
+
+    let spool = tempfile::tempdir().unwrap();
+    let addr = format!("127.0.0.1:{}", free_port());
+    let opts = ReconnectOptions::new()
+        .spool_dir(spool.path())
+        .backoff(Duration::from_millis(10), Duration::from_millis(50));
+
+    // Orchestrator not up yet: everything goes to the spool.
+    let mut p = ReconnectingProducer::connect(&addr, &opts).expect("spooling start");
+    assert!(!p.is_connected());
+    for m in [&b"one"[..], b"two", b"three"] {
+        p.send(m).unwrap();
+    }
+    assert_eq!(p.spooled(), 3);
+
+    let orch = Orchestrator::start_on(&addr, &[], &[]);
+    std::thread::sleep(Duration::from_millis(60)); // let the backoff lapse
+    p.send(b"four").unwrap(); // replays the spool first
+    assert!(p.is_connected());
+    assert_eq!(p.spooled(), 0);
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for want in [&b"one"[..], b"two", b"three", b"four"] {
+        assert_eq!(c.recv().unwrap().payload, want);
+    }
+}
+
+#[test]
+fn reconnecting_producer_without_spool_reports_the_outage() {
+    use qpipe::{ReconnectOptions, ReconnectingProducer};
+
+    let addr = format!("127.0.0.1:{}", free_port());
+    assert!(ReconnectingProducer::connect(&addr, &ReconnectOptions::new()).is_err());
+}
+
+#[test]
+fn reconnecting_consumer_resumes_after_a_restart() {
+    use qpipe::{ConnectOptions, Producer, ReconnectEvent, ReconnectOptions, ReconnectingConsumer};
+    use std::sync::{Arc, Mutex};
+
+    let addr = format!("127.0.0.1:{}", free_port());
+    let orch = Orchestrator::start_on(&addr, &[], &[]);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let log = events.clone();
+    let opts = ReconnectOptions::new()
+        .backoff(Duration::from_millis(10), Duration::from_millis(50))
+        .on_event(move |e| log.lock().unwrap().push(match e {
+            ReconnectEvent::Disconnected(_) => "lost".to_string(),
+            ReconnectEvent::RetryFailed { attempt, .. } => format!("retry {attempt}"),
+            ReconnectEvent::Reconnected { attempts, .. } => format!("back on {attempts}"),
+        }));
+    let mut c = ReconnectingConsumer::connect(&addr, &ConnectOptions::new(), &opts)
+        .expect("consumer connect");
+    Producer::connect(&addr).unwrap().send(b"before").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"before");
+    drop(orch);
+
+    // recv blocks through the outage and picks up on the new orchestrator.
+    let restart = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(300));
+        let orch = Orchestrator::start_on(&addr, &[], &[]);
+        Producer::connect(&orch.addr).unwrap().send(b"after").unwrap();
+        orch
+    });
+    assert_eq!(c.recv().unwrap().payload, b"after");
+    assert!(c.is_connected());
+    let _orch = restart.join().unwrap();
+
+    let events = events.lock().unwrap();
+    let n = events.len();
+    assert!(n >= 3, "{events:?}");
+    assert_eq!(events[0], "lost");
+    for (i, e) in events[1..n - 1].iter().enumerate() {
+        assert_eq!(*e, format!("retry {}", i + 1));
+    }
+    assert_eq!(events[n - 1], format!("back on {}", n - 1));
+}
+
+#[test]
+fn a_standby_takes_over_what_its_primary_had_queued() {
+    use qpipe::{ConnectOptions, ReconnectOptions, ReconnectingConsumer, ReconnectingProducer};
+
+    let (wal_a, wal_b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let mut primary = Orchestrator::start_with(&["--wal-dir", wal_a.path().to_str().unwrap()], &[]);
+    let standby_addr = format!("127.0.0.1:{}", free_port());
+    let standby = StdCommand::new(cargo_bin("orchestrator"))
+        .arg(&standby_addr)
+        .args(["--wal-dir", wal_b.path().to_str().unwrap()])
+        .args(["--standby-of", &primary.addr, "--failover-after", "1"])
+        .env("RUST_LOG", "warn")
+        .spawn()
+        .expect("failed to spawn the standby");
+    let standby = Orchestrator { addr: standby_addr.clone(), child: Some(standby) };
+
+    let opts = ReconnectOptions::new()
+        .standby(&standby_addr)
+        .backoff(Duration::from_millis(10), Duration::from_millis(100));
+    let mut p = ReconnectingProducer::connect(&primary.addr, &opts).expect("producer connect");
+    let mut c = ReconnectingConsumer::connect(&primary.addr, &ConnectOptions::new(), &opts)
+        .expect("consumer connect");
+    for m in [&b"one"[..], b"two", b"three"] {
+        p.send(m).unwrap();
+    }
+    assert_eq!(c.recv().unwrap().payload, b"one");
+
+    // The standby follows into a staging copy; give it the records.
+    let copied = wal_b.path().join(".staging").join("default");
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while !copied.exists() {
+        assert!(std::time::Instant::now() < deadline, "the standby never followed");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    std::thread::sleep(Duration::from_millis(300));
+
+    // The primary dies hard; what it still held comes from the standby.
+    // Whatever was on its way to the consumer may come twice.
+    let mut dead = primary.child.take().unwrap();
+    dead.kill().unwrap();
+    dead.wait().unwrap();
+    let mut got = Vec::new();
+    while got.last().is_none_or(|m| m != b"three") {
+        got.push(c.recv().unwrap().payload);
+    }
+    got.dedup();
+    assert_eq!(got, [&b"two"[..], b"three"]);
+    // Without a spool, the send that finds the primary gone fails; the
+    // next ones reconnect, to the standby.
+    let mut tries = 0;
+    while let Err(e) = p.send(b"four") {
+        tries += 1;
+        assert!(tries < 10, "{e}");
+    }
+    assert_eq!(c.recv().unwrap().payload, b"four");
+    assert!(qpipe::wait_until_healthy(&standby.addr, Some(Duration::from_secs(1))).is_ok());
+}
+
+#[test]
+fn a_standby_needs_a_write_ahead_log() {
+    Command::new(cargo_bin("orchestrator"))
+        .args([&format!("127.0.0.1:{}", free_port()), "--standby-of", "127.0.0.1:1"])
+        .timeout(Duration::from_secs(10))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("a standby needs a write-ahead log directory"));
+}
+
+#[test]
+fn dead_letters_can_be_encrypted_at_rest() {
+    use qpipe::at_rest::{Key, SealedReader};
+    use qpipe::{ConnectOptions, Consumer, Delivery, Producer};
+
+    let dir = tempfile::tempdir().unwrap();
+    let dlq = dir.path().join("dead.q");
+    let policy = format!("timeout=200ms,retries=0,dlq=file:{}", dlq.display());
+    let hex_key = "42".repeat(32);
+    let orch = Orchestrator::start_with_env(&[
+        ("QPIPE_RETRY_POLICY", &policy),
+        ("QPIPE_AT_REST_KEY", "env:QPIPE_TEST_KEY"),
+        ("QPIPE_TEST_KEY", &hex_key),
+    ]);
+
+    let opts = ConnectOptions::new().ack_mode(true);
+    let mut c = Consumer::connect_with(&orch.addr, &opts).expect("consumer connect");
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.send(b"classified-payload").unwrap();
+    drop(p);
+    let Delivery::Message(_) = c.recv_ext().unwrap() else { panic!("expected a message") };
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5))).expect("idle");
+
+    let bytes = std::fs::read(&dlq).expect("dead-letter file");
+    assert!(!bytes.windows(10).any(|w| w == b"classified"), "no plaintext on disk");
+    let key = Key::from_hex(&hex_key).unwrap();
+    let (frame, _) = qpipe::get_frame(&mut SealedReader::new(&bytes[..], key.clone()))
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame, qpipe::Frame::Msg(b"classified-payload".to_vec()));
+    // Until the orchestrator exits, the file lacks its last record.
+    let mut rd = SealedReader::new(&bytes[..], key.clone());
+    qpipe::get_frame(&mut rd).unwrap();
+    assert_eq!(qpipe::get_frame(&mut rd).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+
+    let mut orch = orch;
+    let mut child = orch.child.take().unwrap();
+    qpipe::request_shutdown(&orch.addr).unwrap();
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while child.try_wait().unwrap().is_none() {
+        assert!(std::time::Instant::now() < deadline, "orchestrator still running");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let bytes = std::fs::read(&dlq).expect("dead-letter file");
+    let mut rd = SealedReader::new(&bytes[..], key);
+    assert!(qpipe::get_frame(&mut rd).unwrap().is_some());
+    assert!(qpipe::get_frame(&mut rd).unwrap().is_none(), "a complete stream");
+}
+
+#[test]
+fn scram_users_need_the_right_password_and_role() {
+    use qpipe::scram::Credentials;
+    use qpipe::{ConnectOptions, Consumer, Producer, ProducerOptions};
+
+    let hash = |name: &str, roles: &str, password: &str| {
+        let out = Command::cargo_bin("orchestrator").unwrap()
+            .args(["--hash-password", name, roles])
+            .write_stdin(format!("{password}\n"))
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout).unwrap()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let users = dir.path().join("users");
+    let lines = [
+        hash("ingest", "producer", "s3cret"),
+        hash("worker", "consumer", "hunter2"),
+        hash("ops", "admin", "opspass"),
+    ];
+    std::fs::write(&users, format!("# qpipe users\n{}", lines.concat())).unwrap();
+    let orch = Orchestrator::start_with_env(&[("QPIPE_USERS", users.to_str().unwrap())]);
+
+    let as_user = |u, pw| ProducerOptions::new().credentials(Credentials::new(u, pw));
+    let mut c = Consumer::connect_with(
+        &orch.addr, &ConnectOptions::new().credentials(Credentials::new("worker", "hunter2")),
+    ).expect("consumer connect");
+    let mut p = Producer::connect_with(&orch.addr, &as_user("ingest", "s3cret"))
+        .expect("producer connect");
+    p.send(b"authenticated").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"authenticated");
+
+    for (user, pw) in [("ingest", "wrong"), ("nobody", "s3cret"), ("worker", "hunter2")] {
+        let err = Producer::connect_with(&orch.addr, &as_user(user, pw)).err().expect(user);
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied, "{user}: {err}");
+    }
+    assert!(Producer::connect(&orch.addr).is_err(), "anonymous sessions are refused");
+    assert!(qpipe::request_drain(&orch.addr).is_err(), "so are anonymous admin requests");
+    qpipe::healthcheck(&orch.addr).expect("healthchecks stay open");
+
+    // Admin helpers and the CLIs take their credentials from the environment.
+    Command::cargo_bin("orchestrator").unwrap()
+        .args(["--drain", &orch.addr])
+        .env("QPIPE_USER", "ingest").env("QPIPE_PASSWORD", "s3cret")
+        .assert()
+        .failure();
+    Command::cargo_bin("orchestrator").unwrap()
+        .args(["--drain", &orch.addr])
+        .env("QPIPE_USER", "ops").env("QPIPE_PASSWORD", "opspass")
+        .assert()
+        .success();
+
+    // An orchestrator without users won't pretend to authenticate.
+    let open = Orchestrator::start();
+    let err = Producer::connect_with(&open.addr, &as_user("ingest", "s3cret")).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied, "{err}");
+}
+
+#[test]
+fn auth_key_keeps_out_sessions_without_it() {
+    use qpipe::psk::Key;
+    use qpipe::{ConnectOptions, Consumer, Producer, ProducerOptions};
+
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("key");
+    std::fs::write(&key_file, "correct horse battery staple\n").unwrap();
+    let orch = Orchestrator::start_with(&["--auth-key", key_file.to_str().unwrap()], &[]);
+
+    let key = Key::new("correct horse battery staple").unwrap();
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().auth_key(key.clone()))
+        .expect("consumer connect");
+    let mut p = Producer::connect_with(&orch.addr, &ProducerOptions::new().auth_key(key.clone()))
+        .expect("producer connect");
+    p.send(b"keyed").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"keyed");
+
+    let wrong = ProducerOptions::new().auth_key(Key::new("wrong").unwrap());
+    let err = Producer::connect_with(&orch.addr, &wrong).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied, "{err}");
+    assert!(Producer::connect(&orch.addr).is_err(), "sessions without the key are closed");
+    assert!(qpipe::request_drain(&orch.addr).is_err(), "so are admin requests");
+    qpipe::healthcheck(&orch.addr).expect("healthchecks stay open");
+
+    // The CLIs and admin helpers read the key file from the environment.
+    Command::cargo_bin("orchestrator").unwrap()
+        .args(["--drain", &orch.addr])
+        .env("QPIPE_AUTH_KEY_FILE", &key_file)
+        .assert()
+        .success();
+
+    // A client with a key won't settle for an orchestrator without one.
+    let open = Orchestrator::start();
+    let err = Producer::connect_with(&open.addr, &ProducerOptions::new().auth_key(key))
+        .err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied, "{err}");
+}
+
+#[test]
+fn audit_log_records_what_each_consumer_received() {
+    use qpipe::digest::{sha256, to_hex};
+    use qpipe::{Consumer, Producer};
+    use std::time::Instant;
+
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("audit.jsonl");
+    let orch = Orchestrator::start_with_env(&[("QPIPE_AUDIT_LOG", log.to_str().unwrap())]);
+
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for body in [&b"first"[..], b"second"] {
+        p.send(body).unwrap();
+    }
+    drop(p);
+    let got = [c.recv().unwrap().payload, c.recv().unwrap().payload];
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5))).expect("idle");
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let text = loop {
+        let text = std::fs::read_to_string(&log).unwrap_or_default();
+        if text.lines().count() >= 2 || Instant::now() > deadline {
+            break text;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 2, "{text}");
+    for (line, body) in lines.iter().zip(&got) {
+        // What the consumer processed hashes to what the producer sent.
+        assert!(line.contains(&format!(r#""sha256":"{}""#, to_hex(&sha256(body)))), "{line}");
+        assert!(line.contains(r#""consumer":"127.0.0.1:"#), "{line}");
+        assert!(line.contains(r#""outcome":"delivered""#), "{line}");
+    }
+}
+
+#[cfg(feature = "otel")]
+#[test]
+fn traced_messages_carry_their_context_and_get_orchestrator_spans() {
+    use qpipe::otel::TraceContext;
+    use qpipe::{ConnectOptions, Consumer, Producer};
+    use std::time::Instant;
+
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("spans.jsonl");
+    let orch = Orchestrator::start_with_env(&[("QPIPE_OTEL_SPANS", log.to_str().unwrap())]);
+
+    let parent = TraceContext::new_root().unwrap();
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    p.set_trace_context(Some(parent));
+    p.send(b"traced").unwrap();
+    p.set_trace_context(None);
+    p.send(b"untraced").unwrap();
+    drop(p);
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().ack_mode(true)).expect("consumer connect");
+    let first = c.recv_ack().unwrap();
+    assert_eq!(first.trace_context(), Some(parent));
+    c.ack(first.tag.unwrap()).unwrap();
+    let second = c.recv_ack().unwrap();
+    assert_eq!(second.trace_context(), None);
+    c.ack(second.tag.unwrap()).unwrap();
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5))).expect("idle");
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let text = loop {
+        let text = std::fs::read_to_string(&log).unwrap_or_default();
+        if text.lines().count() >= 3 || Instant::now() > deadline {
+            break text;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    assert_eq!(text.lines().count(), 3, "{text}");
+    for name in ["qpipe.enqueue", "qpipe.queue_wait", "qpipe.deliver"] {
+        let span = text.lines()
+            .find(|l| l.contains(&format!(r#""name":"{name}""#)) && l.contains(&parent.trace_id()))
+            .unwrap_or_else(|| panic!("no {name} span in {text}"));
+        assert!(span.contains(&format!(r#""parentSpanId":"{}""#, parent.span_id())), "{span}");
+        assert!(span.contains(r#""status":{"code":1}"#), "{span}");
+    }
+}
+
+#[test]
+fn egress_limit_paces_deliveries() {
+    use qpipe::{Consumer, Producer};
+    use std::time::Instant;
+
+    // 200 kB/s with a 20 kB burst: the last 80 kB take at least 0.4s.
+    let orch = Orchestrator::start_with_env(&[("QPIPE_EGRESS_LIMIT", "consumer=200kB/s")]);
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..10u8 {
+        p.send(&[i; 10_000]).unwrap();
+    }
+    drop(p);
+
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    let start = Instant::now();
+    for i in 0..10u8 {
+        assert_eq!(c.recv().unwrap().payload, vec![i; 10_000]);
+    }
+    let took = start.elapsed();
+    assert!(took >= Duration::from_millis(300), "delivered 100 kB in {took:?}");
+}
+
+#[test]
+fn sustained_backlog_fires_scale_hooks() {
+    use qpipe::{Consumer, Producer};
+    use std::io::{Read, Write};
+    use std::time::Instant;
+
+    let webhook = TcpListener::bind("127.0.0.1:0").unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("scale.log");
+    let orch = Orchestrator::start_with_env(&[
+        ("QPIPE_AUTOSCALE", "depth=3,for=200ms,cooldown=300ms"),
+        ("QPIPE_SCALE_UP_HOOK", &format!("http://{}/scale", webhook.local_addr().unwrap())),
+        ("QPIPE_SCALE_DOWN_HOOK", &format!(
+            "echo \"$QPIPE_EVENT $QPIPE_DEPTH\" >> {}", log.display(),
+        )),
+    ]);
+
+    // Nobody consumes: the backlog holds above the threshold.
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..5u8 {
+        p.send(&[i]).unwrap();
+    }
+    let (mut s, _) = webhook.accept().unwrap();
+    s.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut req = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !req.ends_with(b"}") {
+        let n = s.read(&mut buf).unwrap();
+        assert!(n > 0, "webhook request cut short: {:?}", String::from_utf8_lossy(&req));
+        req.extend_from_slice(&buf[..n]);
+    }
+    s.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").unwrap();
+    let req = String::from_utf8(req).unwrap();
+    assert!(req.starts_with("POST /scale HTTP/1.1\r\n"), "{req}");
+    assert!(req.contains(r#"{"event":"scale-up","depth":5,"#), "{req}");
+
+    // Work it off; the drained queue scales back down, once.
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    for i in 0..5u8 {
+        assert_eq!(c.recv().unwrap().payload, [i]);
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !log.exists() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "scale-down 0\n");
+}
+
+#[test]
+fn stats_reach_a_statsd_collector() {
+    use qpipe::Producer;
+    use std::net::UdpSocket;
+
+    let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+    collector.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let orch = Orchestrator::start_with_env(&[
+        ("QPIPE_STATSD", &collector.local_addr().unwrap().to_string()),
+        ("QPIPE_STATSD_PREFIX", "ci.qpipe"),
+    ]);
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    for i in 0..3u8 {
+        p.send(&[i]).unwrap();
+    }
+    drop(p);
+
+    // Counters are per-report deltas, so add them up until all three show.
+    let mut posted = 0;
+    let mut buf = [0u8; 2048];
+    while posted < 3 {
+        let n = collector.recv(&mut buf).expect("no statsd packet");
+        let packet = std::str::from_utf8(&buf[..n]).unwrap();
+        assert!(packet.lines().all(|l| l.starts_with("ci.qpipe.")), "{packet}");
+        for line in packet.lines() {
+            if let Some(v) = line.strip_prefix("ci.qpipe.posted.frames:") {
+                posted += v.strip_suffix("|c").unwrap().parse::<u32>().unwrap();
+            }
+            if posted == 3 {
+                assert!(packet.contains("ci.qpipe.queue.depth:3|g"), "{packet}");
+            }
+        }
+    }
+}
+
+/// A running `netsim` proxy in front of an orchestrator; killed on drop.
+struct NetSim {
+    addr:  String,
+    child: Child,
+}
+
+impl NetSim {
+    fn start(upstream: &str, spec: &str) -> Self {
+        let addr = format!("127.0.0.1:{}", free_port());
+        let child = StdCommand::new(cargo_bin("netsim"))
+            .args([&addr, upstream, spec])
+            .env("RUST_LOG", "warn")
+            .spawn()
+            .expect("failed to spawn netsim binary");
+        let sim = Self { addr, child }; // killed on drop if we panic below
+        // Not a healthcheck: with resets configured it might never pass.
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while std::net::TcpStream::connect(&sim.addr).is_err() {
+            assert!(std::time::Instant::now() < deadline, "netsim never came up");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        sim
+    }
+}
+
+impl Drop for NetSim {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn netsim_adds_latency_to_every_round_trip() {
+    use qpipe::{Consumer, Producer};
+    use std::time::Instant;
+
+    let orch = Orchestrator::start();
+    let sim = NetSim::start(&orch.addr, "latency=40ms,jitter=5ms,seed=1");
+    let mut p = Producer::connect(&sim.addr).expect("producer via netsim");
+    let mut c = Consumer::connect(&sim.addr).expect("consumer via netsim");
+
+    // Each send waits for its ACK: at least one round trip of 2 x 40ms.
+    let start = Instant::now();
+    for i in 0..3u32 {
+        p.send(&i.to_be_bytes()).unwrap();
+    }
+    let took = start.elapsed();
+    assert!(took >= Duration::from_millis(240), "3 round trips took {took:?}");
+    for i in 0..3u32 {
+        assert_eq!(c.recv().unwrap().payload, i.to_be_bytes());
+    }
+}
+
+#[test]
+fn netsim_resets_surface_as_connection_errors() {
+    use qpipe::Producer;
+
+    let orch = Orchestrator::start();
+    let sim = NetSim::start(&orch.addr, "reset=1");
+    // The handshake's first bytes already trip the reset.
+    assert!(Producer::connect(&sim.addr).is_err());
+}
+
+#[test]
+fn conformance_suite_passes_against_the_orchestrator() {
+    let orch = Orchestrator::start();
+    Command::new(cargo_bin("qpipe-conformance"))
+        .arg(&orch.addr)
+        .timeout(Duration::from_secs(60))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("PASS  oversized-length-prefix"))
+        .stdout(predicate::str::contains("PASS  slow-loris-handshakes"))
+        .stdout(predicate::str::contains("FAIL").not());
+}
+
+#[test]
+fn conformance_suite_refuses_an_unreachable_target() {
+    Command::new(cargo_bin("qpipe-conformance"))
+        .arg(format!("127.0.0.1:{}", free_port()))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not healthy"));
+}
+
+#[test]
+fn soak_harness_checks_conservation_under_churn() {
+    let orch = Orchestrator::start();
+    let pid = orch.child.as_ref().unwrap().id().to_string();
+    Command::new(cargo_bin("qpipe-soak"))
+        .args([&orch.addr, "--duration", "2s", "--report", "1s"])
+        .args(["--producers", "3", "--consumers", "2", "--session", "50"])
+        .args(["--pid", &pid])
+        .timeout(Duration::from_secs(60))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("conservation OK"))
+        .stdout(predicate::str::contains("orchestrator fds"));
+}
+
+#[test]
+fn bench_reports_throughput_latency_and_drops() {
+    let orch = Orchestrator::start();
+    Command::new(cargo_bin("qpipe-bench"))
+        .args([&orch.addr, "--duration", "1s", "--report", "500ms"])
+        .args(["--producers", "2", "--consumers", "2", "--size", "100", "--rate", "400"])
+        .timeout(Duration::from_secs(30))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(", dropped 0 (0.00%)"))
+        .stdout(predicate::str::contains("throughput "))
+        .stdout(predicate::str::is_match(r"latency p50 [0-9.]+ ms, p99 [0-9.]+ ms").unwrap());
+}
+
+#[test]
+fn load_publishes_a_recording_and_forwards_its_eos() {
+    use qpipe::{put_frame, Consumer, Delivery, Frame, Meta};
+
+    let orch = Orchestrator::start();
+    let dir = tempfile::tempdir().unwrap();
+    let recording = dir.path().join("backlog.qpipe");
+    let mut buf = Vec::new();
+    for i in 0..3u8 {
+        put_frame(&mut buf, &Frame::Msg(vec![b'm', i]), &Meta::default()).unwrap();
+    }
+    for idx in 0..2 {
+        let chunk = Frame::Chunk { id: 9, idx, count: 2, payload: vec![b'c', idx as u8] };
+        put_frame(&mut buf, &chunk, &Meta::default()).unwrap();
+    }
+    put_frame(&mut buf, &Frame::Eos(b"batch".to_vec()), &Meta::default()).unwrap();
+    std::fs::write(&recording, buf).unwrap();
+
+    Command::new(cargo_bin("qpipe-load"))
+        .args([&orch.addr, recording.to_str().unwrap(), "--concurrency", "3"])
+        .timeout(Duration::from_secs(30))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("loaded 5 messages"));
+
+    let mut c = Consumer::connect(&orch.addr).unwrap();
+    let mut got = Vec::new();
+    for _ in 0..4 {
+        match c.recv_ext().unwrap() {
+            Delivery::Message(m) => got.push(m.payload),
+            Delivery::Eos(g) => panic!("EOS {g:?} overtook a message"),
+        }
+    }
+    got.sort();
+    assert_eq!(got, [b"c\0c\x01".to_vec(), b"m\0".to_vec(), b"m\x01".to_vec(), b"m\x02".to_vec()]);
+    assert_eq!(c.recv_ext().unwrap(), Delivery::Eos(b"batch".to_vec()));
+}
+
+#[test]
+fn load_resumes_from_its_checkpoint() {
+    use qpipe::Consumer;
+
+    let orch = Orchestrator::start();
+    let dir = tempfile::tempdir().unwrap();
+    let files = dir.path().join("files");
+    std::fs::create_dir(&files).unwrap();
+    for i in 0..5 {
+        std::fs::write(files.join(format!("{i:03}")), format!("file {i}")).unwrap();
+    }
+    let checkpoint = dir.path().join("load.ckpt");
+    let source = files.to_str().unwrap();
+    std::fs::write(&checkpoint, format!("{source}\n3\n")).unwrap();
+
+    Command::new(cargo_bin("qpipe-load"))
+        .args([&orch.addr, source, "--checkpoint", checkpoint.to_str().unwrap()])
+        .timeout(Duration::from_secs(30))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("loaded 2 messages"));
+    assert_eq!(std::fs::read_to_string(&checkpoint).unwrap(), format!("{source}\n5\n"));
+
+    let mut c = Consumer::connect(&orch.addr).unwrap();
+    let mut got = vec![c.recv().unwrap().payload, c.recv().unwrap().payload];
+    got.sort();
+    assert_eq!(got, [b"file 3".to_vec(), b"file 4".to_vec()]);
+
+    // A checkpoint for another source is refused rather than misapplied.
+    Command::new(cargo_bin("qpipe-load"))
+        .args([&orch.addr, "-", "--checkpoint", checkpoint.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("belongs to"));
+}
+
+#[test]
+fn dump_copies_or_drains_a_backlog_that_load_can_replay() {
+    use qpipe::{get_frame, Consumer, Frame, Producer};
+
+    let orch = Orchestrator::start();
+    let mut p = Producer::connect(&orch.addr).unwrap();
+    p.send(b"first").unwrap();
+    p.send_with_priority(b"urgent", 7).unwrap();
+    drop(p);
+
+    let dir = tempfile::tempdir().unwrap();
+    let copy = dir.path().join("copy.qpipe");
+    Command::new(cargo_bin("qpipe-dump"))
+        .args([&orch.addr, copy.to_str().unwrap(), "--copy"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("copied 2 frames"));
+    let buf = std::fs::read(&copy).unwrap();
+    let mut rd = &buf[..];
+    let (f, meta) = get_frame(&mut rd).unwrap().unwrap();
+    assert_eq!((f, meta.priority), (Frame::Msg(b"urgent".to_vec()), Some(7)));
+    assert_eq!(get_frame(&mut rd).unwrap().unwrap().0, Frame::Msg(b"first".to_vec()));
+
+    let drained = dir.path().join("drained.qpipe");
+    Command::new(cargo_bin("qpipe-dump"))
+        .args([&orch.addr, drained.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("drained 2 frames"));
+    assert_eq!(std::fs::read(&drained).unwrap(), buf);
+    qpipe::wait_for_idle(&orch.addr, None, Some(Duration::from_secs(5)))
+        .expect("a drained queue is idle");
+
+    // Move the backlog to another orchestrator.
+    let other = Orchestrator::start();
+    Command::new(cargo_bin("qpipe-load"))
+        .args([&other.addr, drained.to_str().unwrap(), "--concurrency", "1"])
+        .assert()
+        .success();
+    let mut c = Consumer::connect(&other.addr).unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"urgent");
+    assert_eq!(c.recv().unwrap().payload, b"first");
+}
+
+#[test]
+fn snapshots_are_imported_in_order_at_startup_or_into_a_running_orchestrator() {
+    use qpipe::{put_frame, Consumer, Frame, Meta, Producer};
+
+    let dir = tempfile::tempdir().unwrap();
+    let snapshot = dir.path().join("snapshot.qpipe");
+    let mut buf = Vec::new();
+    for m in ["one", "two", "three", "four"] {
+        put_frame(&mut buf, &Frame::Msg(m.as_bytes().to_vec()), &Meta::default()).unwrap();
+    }
+    std::fs::write(&snapshot, &buf).unwrap();
+
+    // More than the capacity of 2, with nobody to take any yet.
+    let orch = Orchestrator::start_with(&["2", "--import", snapshot.to_str().unwrap()], &[]);
+    let mut c = Consumer::connect(&orch.addr).unwrap();
+    for want in ["one", "two", "three", "four"] {
+        assert_eq!(c.recv().unwrap().payload, want.as_bytes());
+    }
+
+    // Behind what is already queued, in a running one.
+    let mut p = Producer::connect(&orch.addr).unwrap();
+    p.send(b"zero").unwrap();
+    drop((p, c));
+    Command::new(cargo_bin("qpipe-dump"))
+        .args([&orch.addr, "--import", snapshot.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("imported 4 frames"));
+    let mut c = Consumer::connect(&orch.addr).unwrap();
+    for want in ["zero", "one", "two", "three", "four"] {
+        assert_eq!(c.recv().unwrap().payload, want.as_bytes());
+    }
+
+    // A snapshot that can't be read keeps the orchestrator from starting.
+    let out = StdCommand::new(cargo_bin("orchestrator"))
+        .args([&format!("127.0.0.1:{}", free_port()), "--import", "/nonexistent/snapshot.qpipe"])
+        .env("RUST_LOG", "error")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("snapshot.qpipe"), "names the file");
+}
+
+#[test]
+fn admin_snapshots_cover_every_queue_and_restore_each_to_its_own() {
+    use qpipe::{get_frame, Consumer, Producer};
+
+    let orch = Orchestrator::start();
+    let mut p = Producer::connect(&orch.addr).unwrap();
+    p.send(b"default").unwrap();
+    let mut r = Producer::connect_to(&orch.addr, "render").unwrap();
+    r.send(b"render-1").unwrap();
+    r.send(b"render-2").unwrap();
+    drop((p, r));
+
+    let dir = tempfile::tempdir().unwrap();
+    let snapshot = dir.path().join("all.qpipe");
+    Command::new(cargo_bin("qpipe-admin"))
+        .args([&orch.addr, "snapshot", snapshot.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("copied 3 frames from 2 queue(s)"));
+    let buf = std::fs::read(&snapshot).unwrap();
+    let mut rd = &buf[..];
+    let mut queues = Vec::new();
+    while let Some((_, meta)) = get_frame(&mut rd).unwrap() {
+        queues.push(meta.queue);
+    }
+    assert_eq!(queues, [None, Some("render".into()), Some("render".into())]);
+    // A snapshot leaves the queues as they were.
+    let mut c = Consumer::connect_to(&orch.addr, "render").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"render-1");
+    drop(c);
+
+    // Into a running orchestrator, and into one starting up.
+    let other = Orchestrator::start();
+    Command::new(cargo_bin("qpipe-admin"))
+        .args([&other.addr, "restore", snapshot.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("restored 3 frames"));
+    let third = Orchestrator::start_with(&["--import", snapshot.to_str().unwrap()], &[]);
+    for addr in [&other.addr, &third.addr] {
+        let mut c = Consumer::connect_to(addr, "render").unwrap();
+        assert_eq!(c.recv().unwrap().payload, b"render-1");
+        assert_eq!(c.recv().unwrap().payload, b"render-2");
+        drop(c);
+        let mut c = Consumer::connect(addr).unwrap();
+        assert_eq!(c.recv().unwrap().payload, b"default");
+    }
+}
+
+#[test]
+fn a_protocol_floor_turns_away_clients_too_old_to_say_hello() {
+    use qpipe::{Consumer, Producer, PROTOCOL_VERSION};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let orch = Orchestrator::start_with_env(&[("QPIPE_MIN_PROTOCOL", "2")]);
+    let mut p = Producer::connect(&orch.addr).expect("producer connect");
+    let mut c = Consumer::connect(&orch.addr).expect("consumer connect");
+    assert_eq!((p.protocol_version(), c.protocol_version()), (PROTOCOL_VERSION, PROTOCOL_VERSION));
+    p.send(b"hello").unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"hello");
+
+    // A client from before the hello opens with its role byte and is hung
+    // up on; one asking only for version 1 is told there is no agreement.
+    let mut legacy = TcpStream::connect(&orch.addr).unwrap();
+    legacy.write_all(&[qpipe::ROLE_PRODUCER]).unwrap();
+    legacy.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut rest = Vec::new();
+    legacy.read_to_end(&mut rest).expect("the orchestrator hangs up");
+    assert!(rest.is_empty(), "{rest:?}");
+
+    let mut old = TcpStream::connect(&orch.addr).unwrap();
+    old.write_all(&[&qpipe::HELLO_MAGIC[..], &[1, 1]].concat()).unwrap();
+    old.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut answer = Vec::new();
+    old.read_to_end(&mut answer).unwrap();
+    assert_eq!(answer, [&qpipe::HELLO_MAGIC[..], &[0]].concat());
+
+    // Admin and control sessions carry on without a hello.
+    qpipe::wait_until_healthy(&orch.addr, Some(Duration::from_secs(5))).unwrap();
+
+    Command::new(cargo_bin("orchestrator"))
+        .arg(format!("127.0.0.1:{}", free_port()))
+        .env("QPIPE_MIN_PROTOCOL", "9")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("QPIPE_MIN_PROTOCOL"));
+}
+
+#[test]
+fn journaled_queues_survive_an_orchestrator_crash() {
+    use qpipe::{ConnectOptions, Consumer, Producer, ProducerOptions};
+
+    let dir = tempfile::tempdir().unwrap();
+    let wal = dir.path().to_str().unwrap();
+    let mut orch = Orchestrator::start_with(&["--wal-dir", wal], &[]);
+    let mut p = Producer::connect(&orch.addr).unwrap();
+    let mut jobs = Producer::connect_with(&orch.addr, &ProducerOptions::new().queue("jobs/gpu")).unwrap();
+    for i in 0..5 {
+        p.send(format!("default {i}").as_bytes()).unwrap();
+    }
+    jobs.send(b"job").unwrap();
+    jobs.send_eos(b"run-1").unwrap();
+    p.flush().unwrap();
+    jobs.flush().unwrap();
+    let mut c = Consumer::connect(&orch.addr).unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"default 0");
+    drop((p, jobs, c));
+
+    // No drain, no shutdown: the process just dies.
+    let mut child = orch.child.take().unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let orch = Orchestrator::start_on(&orch.addr, &["--wal-dir", wal], &[]);
+
+    let mut c = Consumer::connect(&orch.addr).unwrap();
+    let mut got = vec![c.recv().unwrap().payload];
+    // "default 0" was delivered, unless the crash beat its ACK.
+    if got[0] == b"default 0" {
+        got = vec![c.recv().unwrap().payload];
+    }
+    for _ in 1..4 {
+        got.push(c.recv().unwrap().payload);
+    }
+    let want: Vec<Vec<u8>> = (1..5).map(|i| format!("default {i}").into_bytes()).collect();
+    assert_eq!(got, want);
+    let mut c = Consumer::connect_with(&orch.addr, &ConnectOptions::new().queue("jobs/gpu")).unwrap();
+    assert_eq!(c.recv().unwrap().payload, b"job");
+    assert_eq!(c.recv_ext().unwrap(), qpipe::Delivery::Eos(b"run-1".to_vec()));
+}